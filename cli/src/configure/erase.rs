@@ -0,0 +1,93 @@
+use super::*;
+use distinst::auto::{erase_disk, EraseOptions};
+use errors::DistinstError;
+use std::mem;
+
+/// Erases the disk given to `--erase-and-install` as `disk[:encrypt]`, and stages the default
+/// layout of an install on it.
+pub(crate) fn erase_and_install(value: &str, matches: &ArgMatches) -> Result<Disks, DistinstError> {
+    let mut fields = value.splitn(2, ':');
+    let block = fields.next().unwrap_or_default();
+    let encrypt = match fields.next() {
+        Some("encrypt") => true,
+        Some(field) => return Err(DistinstError::InvalidField { field: field.into() }),
+        None => false,
+    };
+
+    eprintln!("distinst: erasing {} to install to it", block);
+    let disk = Disk::from_name(block)?;
+
+    let mut options = EraseOptions::new(FileSystem::Ext4);
+    if matches.is_present("force-bios") {
+        options.bootloader = Bootloader::Bios;
+    } else if matches.is_present("force-efi") {
+        options.bootloader = Bootloader::Efi;
+    }
+
+    options.recovery = options.bootloader == Bootloader::Efi;
+
+    if encrypt {
+        let password = read_passphrase("passphrase of the encrypted install")
+            .map_err(|why| DistinstError::Passphrase { why })?;
+        if password.is_empty() {
+            return Err(DistinstError::EmptyPassword);
+        }
+
+        options.encrypt = Some(LvmEncryption::new("cryptdata".into(), Some(password), None));
+    }
+
+    let mut disks = Disks::default();
+    erase_disk(&mut disks, disk, &options).map_err(|why| DistinstError::Erase { why })?;
+    passphrases(&mut disks, matches.is_present("allow-weak-passphrase"))?;
+
+    if let Some(disk) = matches.value_of("bootloader-disk") {
+        disks.set_bootloader_disk(disk)?;
+    }
+
+    eprintln!("distinst: disks configured");
+    Ok(disks)
+}
+
+/// Reads the passphrase from the terminal without echoing it, and asks for it twice. Otherwise,
+/// it is read from the first line of the standard input.
+pub(crate) fn read_passphrase(message: &str) -> io::Result<String> {
+    if unsafe { libc::isatty(0) } == 0 {
+        return read_line();
+    }
+
+    let mut term: libc::termios = unsafe { mem::zeroed() };
+    if unsafe { libc::tcgetattr(0, &mut term) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let original = term;
+    term.c_lflag &= !libc::ECHO;
+    term.c_lflag |= libc::ECHONL;
+    if unsafe { libc::tcsetattr(0, libc::TCSANOW, &term) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let result = (|| {
+        eprint!("distinst: {}: ", message);
+        let password = read_line()?;
+        eprint!("distinst: confirm the passphrase: ");
+        if read_line()? != password {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "passphrases do not match"));
+        }
+
+        Ok(password)
+    })();
+
+    unsafe { libc::tcsetattr(0, libc::TCSANOW, &original) };
+    result
+}
+
+fn read_line() -> io::Result<String> {
+    let mut line = String::new();
+    io::stdin().read_line(&mut line)?;
+    if line.ends_with('\n') {
+        line.pop();
+    }
+
+    Ok(line)
+}
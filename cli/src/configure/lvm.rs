@@ -65,7 +65,7 @@ pub(crate) fn lvm(
                 let fs = match fs {
                     PartType::Fs(fs) => fs,
                     PartType::Lvm(volume_group, encryption) => {
-                        partition.set_volume_group(volume_group, encryption);
+                        partition.set_volume_group(volume_group, encryption)?;
                         Some(FileSystem::Lvm)
                     }
                 };
@@ -84,10 +84,12 @@ pub(crate) fn lvm(
     if let Some(logical) = logical {
         parse_logical(logical, |args| match disks.get_logical_device_mut(&args.group) {
             Some(lvm_device) => {
-                let start = lvm_device.get_last_sector();
-                let end = start + lvm_device.get_sector(args.size);
-                let mut builder =
-                    PartitionBuilder::new(start, end, args.fs).name(args.name.clone());
+                // The placement of the volume is calculated when it is added.
+                let mut builder = match args.size {
+                    None => PartitionBuilder::new(0, 1, args.fs).remainder(),
+                    Some(size) => PartitionBuilder::new(0, lvm_device.get_sector(size), args.fs),
+                }
+                .name(args.name.clone());
 
                 if let Some(mount) = args.mount.as_ref() {
                     builder = builder.mount(mount.clone());
@@ -97,6 +99,10 @@ pub(crate) fn lvm(
                     builder = builder.flags(flags.clone());
                 }
 
+                if let Some(encryption) = args.encryption.clone() {
+                    builder = builder.encryption(encryption);
+                }
+
                 lvm_device
                     .add_partition(builder)
                     .map_err(|why| DistinstError::LvmPartitionAdd { why })
@@ -111,17 +117,19 @@ pub(crate) fn lvm(
 // Defines a new partition to assign to a volume group
 struct LogicalArgs {
     // The group to create a partition on
-    group: String,
+    group:      String,
     // The name of the partition
-    name:  String,
-    // The length of the partition
-    size:  Sector,
+    name:       String,
+    // The length of the partition, or `None` if it should consume the remaining space
+    size:       Option<Sector>,
     // The filesystem to assign to this partition
-    fs:    Option<FileSystem>,
+    fs:         Option<FileSystem>,
     // Where to mount this partition
-    mount: Option<PathBuf>,
+    mount:      Option<PathBuf>,
     // The partition flags to assign
-    flags: Option<Vec<PartitionFlag>>,
+    flags:      Option<Vec<PartitionFlag>>,
+    // Encrypts the file system on this partition
+    encryption: Option<LvmEncryption>,
 }
 
 fn parse_logical<F: FnMut(LogicalArgs) -> Result<(), DistinstError>>(
@@ -156,18 +164,29 @@ fn parse_logical<F: FnMut(LogicalArgs) -> Result<(), DistinstError>>(
             }
         }
 
+        // Encrypted volumes are defined as `enc=<pv>,<fs>,pass=<pass>|keyfile=<id>`.
+        let (fs, encryption) = match parse_fs(values[3])? {
+            PartType::Fs(fs) => (fs, None),
+            PartType::Lvm(fs, Some(encryption)) => {
+                let fs = fs
+                    .parse::<FileSystem>()
+                    .map_err(|_| DistinstError::InvalidField { field: fs.clone() })?;
+                (Some(fs), Some(encryption))
+            }
+            PartType::Lvm(_, None) => return Err(DistinstError::LvmOnLogical),
+        };
+
         action(LogicalArgs {
             group: values[0].into(),
             name: values[1].into(),
-            size: parse_sector(values[2])?,
-            fs: match parse_fs(values[3])? {
-                PartType::Fs(fs) => fs,
-                PartType::Lvm(..) => {
-                    unimplemented!("LUKS on LVM is unsupported");
-                }
+            size: match values[2] {
+                "rest" | "size=rest" | "100%" => None,
+                size => Some(parse_sector(size)?),
             },
+            fs,
             mount,
             flags,
+            encryption,
         })?;
     }
 
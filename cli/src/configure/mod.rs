@@ -1,12 +1,16 @@
 mod decrypt;
+mod erase;
 mod lvm;
 mod moved;
 mod new;
+mod passphrase;
 mod removed;
 mod reuse;
 mod table;
 
-use self::{decrypt::*, lvm::*, moved::*, new::*, removed::*, reuse::*, table::*};
+use self::{decrypt::*, lvm::*, moved::*, new::*, passphrase::*, removed::*, reuse::*, table::*};
+
+pub(crate) use self::erase::{erase_and_install, read_passphrase};
 
 use super::*;
 use errors::DistinstError;
@@ -29,6 +33,7 @@ pub(crate) fn configure_disks(matches: &ArgMatches) -> Result<Disks, DistinstErr
             .and_then(|_| new(disks, matches.values_of("new")))
             .and_then(|_| initialize_logical(disks))
             .and_then(|_| decrypt(disks, matches.values_of("decrypt")))
+            .and_then(|_| moved_logical(disks, matches.values_of("move")))
             .and_then(|_| {
                 lvm(
                     disks,
@@ -37,7 +42,12 @@ pub(crate) fn configure_disks(matches: &ArgMatches) -> Result<Disks, DistinstErr
                     matches.values_of("logical-remove"),
                     matches.is_present("logical-remove-all"),
                 )
-            })?;
+            })
+            .and_then(|_| passphrases(disks, matches.is_present("allow-weak-passphrase")))?;
+
+        if let Some(disk) = matches.value_of("bootloader-disk") {
+            disks.set_bootloader_disk(disk)?;
+        }
 
         eprintln!("distinst: disks configured");
     }
@@ -45,7 +55,35 @@ pub(crate) fn configure_disks(matches: &ArgMatches) -> Result<Disks, DistinstErr
     Ok(disks)
 }
 
+/// Partitions the disks as the recipe declares, unless they were given as arguments.
+pub(crate) fn configure_recipe_disks(
+    recipe: &RecipeFile,
+    matches: &ArgMatches,
+) -> Result<Disks, DistinstError> {
+    eprintln!("distinst: configuring disks from the recipe");
+    let mut disks = recipe.disks().map_err(|why| DistinstError::Recipe { why })?;
+    passphrases(&mut disks, matches.is_present("allow-weak-passphrase"))?;
+
+    if let Some(disk) = matches.value_of("bootloader-disk") {
+        disks.set_bootloader_disk(disk)?;
+    }
+
+    eprintln!("distinst: disks configured");
+    Ok(disks)
+}
+
 fn initialize_logical(disks: &mut Disks) -> Result<(), DistinstError> {
     eprintln!("distinst: initializing LVM groups");
-    disks.initialize_volume_groups().map_err(|why| DistinstError::InitializeVolumes { why })
+    disks.initialize_volume_groups().map_err(|why| DistinstError::InitializeVolumes { why })?;
+
+    for device in disks.get_logical_devices() {
+        if let Some(ref rename) = device.conflict_rename {
+            eprintln!(
+                "distinst: existing volume group '{}' will be renamed to '{}'",
+                device.volume_group, rename
+            );
+        }
+    }
+
+    Ok(())
 }
@@ -4,7 +4,8 @@ use errors::DistinstError;
 pub(crate) fn moved(disks: &mut Disks, parts: Option<Values>) -> Result<(), DistinstError> {
     eprintln!("distinst: configuring moved partitions");
     if let Some(parts) = parts {
-        for part in parts {
+        // Logical volumes are resized after the volume groups have been initialized.
+        for part in parts.filter(|part| !is_logical_target(part)) {
             let values: Vec<&str> = part.split(':').collect();
             if values.len() != 4 {
                 return Err(DistinstError::MoveArgs);
@@ -42,3 +43,35 @@ pub(crate) fn moved(disks: &mut Disks, parts: Option<Values>) -> Result<(), Dist
 
     Ok(())
 }
+
+/// Resizes existing logical volumes, which are specified as `vg/lv:none:size`.
+pub(crate) fn moved_logical(disks: &mut Disks, parts: Option<Values>) -> Result<(), DistinstError> {
+    if let Some(parts) = parts {
+        for part in parts.filter(|part| is_logical_target(part)) {
+            let values: Vec<&str> = part.split(':').collect();
+            if values.len() != 3 || values[1] != "none" {
+                return Err(DistinstError::MoveArgs);
+            }
+
+            let (group, volume) = {
+                let mut target = values[0].splitn(2, '/');
+                (target.next().unwrap(), target.next().unwrap())
+            };
+
+            let size = parse_sector(values[2])?;
+
+            let device = disks
+                .get_logical_device_mut(group)
+                .ok_or(DistinstError::LogicalDeviceNotFound { group: group.into() })?;
+
+            let size = device.get_sector(size);
+            device.resize_lv(volume, size)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn is_logical_target(part: &str) -> bool {
+    part.split(':').next().map_or(false, |target| target.contains('/') && !target.starts_with('/'))
+}
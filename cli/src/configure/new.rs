@@ -7,7 +7,7 @@ pub(crate) fn new(disks: &mut Disks, parts: Option<Values>) -> Result<(), Distin
     if let Some(parts) = parts {
         for part in parts {
             let values: Vec<&str> = part.split(':').collect();
-            if values.len() < 5 || values.len() > 7 {
+            if values.len() < 5 || values.len() > 8 {
                 return Err(DistinstError::NewArgs);
             }
 
@@ -19,7 +19,7 @@ pub(crate) fn new(disks: &mut Disks, parts: Option<Values>) -> Result<(), Distin
                 parse_fs(values[4])?,
             );
 
-            let (mut key, mut mount, mut flags) = (None, None, None);
+            let (mut key, mut mount, mut flags, mut swap) = (None, None, None, None);
 
             for value in values.iter().skip(5) {
                 if value.starts_with("mount=") {
@@ -28,6 +28,8 @@ pub(crate) fn new(disks: &mut Disks, parts: Option<Values>) -> Result<(), Distin
                     flags = Some(parse_flags(&value[6..]));
                 } else if value.starts_with("keyid=") {
                     key = Some(String::from(&value[6..]));
+                } else if *value == "swap=random" {
+                    swap = Some(SwapEncryption::Random);
                 } else {
                     return Err(DistinstError::InvalidField { field: (*value).into() });
                 }
@@ -50,6 +52,10 @@ pub(crate) fn new(disks: &mut Disks, parts: Option<Values>) -> Result<(), Distin
                 builder = builder.flags(flags);
             }
 
+            if let Some(swap) = swap {
+                builder = builder.swap_encryption(swap);
+            }
+
             if let Some(keyid) = key {
                 match mount {
                     Some(mount) => {
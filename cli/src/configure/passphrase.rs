@@ -0,0 +1,49 @@
+use super::*;
+use errors::DistinstError;
+
+/// Passphrases which are weaker than this require the `--allow-weak-passphrase` flag.
+const MINIMUM_STRENGTH: StrengthScore = StrengthScore::Fair;
+
+pub(crate) fn passphrases(disks: &mut Disks, allow_weak: bool) -> Result<(), DistinstError> {
+    eprintln!("distinst: checking passphrase strength");
+
+    let encryptions = disks
+        .get_logical_devices()
+        .iter()
+        .filter(|d| !d.is_source)
+        .filter_map(|d| d.encryption.as_ref())
+        .chain(
+            disks
+                .get_logical_partitions()
+                .filter(|p| p.will_format())
+                .filter_map(|p| p.encryption.as_ref()),
+        );
+
+    for encryption in encryptions {
+        let password = match encryption.password {
+            Some(ref password) => password,
+            None => continue,
+        };
+
+        let strength = check_password_strength(password);
+        if strength.score >= MINIMUM_STRENGTH {
+            continue;
+        }
+
+        eprintln!(
+            "distinst: passphrase of {} is {}, and could be guessed in {}",
+            encryption.physical_volume, strength.score, strength.crack_time
+        );
+
+        for hint in &strength.feedback {
+            eprintln!("    {}", hint);
+        }
+    }
+
+    if !allow_weak {
+        disks.set_minimum_passphrase_strength(MINIMUM_STRENGTH);
+        disks.verify_passphrases()?;
+    }
+
+    Ok(())
+}
@@ -57,7 +57,7 @@ pub(crate) fn reused(disks: &mut Disks, parts: Option<Values>) -> Result<(), Dis
                 let fs = match fs {
                     PartType::Fs(fs) => fs,
                     PartType::Lvm(volume_group, encryption) => {
-                        partition.set_volume_group(volume_group, encryption);
+                        partition.set_volume_group(volume_group, encryption)?;
                         Some(FileSystem::Lvm)
                     }
                 };
@@ -0,0 +1,569 @@
+//! A DBus system service, which lets frontends that run as the desktop user partition and
+//! install through a daemon that runs as root.
+//!
+//! Each method is authorized by polkit with the `com.system76.distinst.install` action, once
+//! for each caller. Disks are described by `ProbeDisks` with the versioned JSON of
+//! `Disks::to_json`, and staged as the serialized model of `Disks`, or by the recipe in JSON
+//! which the config is set with.
+//!
+//! Passphrases are never serialized with the model of the disks, so they are given with
+//! `SetPassphrase` once the disks are staged, or as the answer to the `Prompt` signal of an
+//! install which asks for them.
+
+use dbus::{
+    arg::{RefArg, Variant},
+    blocking::LocalConnection,
+    channel::Sender as _,
+    tree::{Factory, MTFn, MethodErr, MethodInfo, MethodResult},
+    Message,
+};
+use distinst::{
+    recipe::UserRecipe,
+    timezones::{Region, Timezones},
+    *,
+};
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    io,
+    rc::Rc,
+    sync::mpsc::{self, Receiver, Sender},
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+pub const BUS_NAME: &str = "com.system76.Distinst";
+pub const OBJECT_PATH: &str = "/com/system76/Distinst";
+pub const INTERFACE: &str = "com.system76.Distinst";
+pub const POLKIT_ACTION: &str = "com.system76.distinst.install";
+
+/// How long the daemon waits for messages before it checks the install for events.
+const POLL: Duration = Duration::from_millis(100);
+
+/// Authentication may wait on the user, so polkit is given a generous amount of time.
+const AUTHORIZATION_TIMEOUT: Duration = Duration::from_secs(300);
+
+type Install = (JoinHandle<Option<InstallLog>>, Receiver<InstallerEvent>);
+
+type Handler = fn(&mut Daemon, &Message) -> MethodResult;
+
+/// A prompt of the install, which waits for a frontend to answer it with `Answer`.
+enum PendingPrompt {
+    Timezone(Responder<Region>),
+    User(Responder<io::Result<UserAccountCreate>>),
+    Question(QuestionKind, Responder<Answer>),
+}
+
+impl PendingPrompt {
+    /// Responds with the answer, or gives the prompt back if the answer is invalid, so that
+    /// it may be answered again.
+    fn respond(self, answer: &str) -> Result<(), (Self, String)> {
+        match self {
+            PendingPrompt::Timezone(responder) => match find_timezone(answer) {
+                Ok(zone) => {
+                    responder.respond(zone);
+                    Ok(())
+                }
+                Err(why) => Err((PendingPrompt::Timezone(responder), why)),
+            },
+            PendingPrompt::User(responder) => match serde_json::from_str::<UserRecipe>(answer) {
+                Ok(user) => {
+                    responder.respond(Ok(user.account()));
+                    Ok(())
+                }
+                Err(why) => Err((PendingPrompt::User(responder), why.to_string())),
+            },
+            PendingPrompt::Question(kind, responder) => match parse_answer(&kind, answer) {
+                Ok(answer) => {
+                    responder.respond(answer);
+                    Ok(())
+                }
+                Err(why) => Err((PendingPrompt::Question(kind, responder), why)),
+            },
+        }
+    }
+}
+
+struct Daemon {
+    installer:   Installer,
+    disks:       Option<Disks>,
+    config:      Option<Config>,
+    install:     Option<Install>,
+    /// The prompts which were signalled, by the ID that they are answered with.
+    prompts:     HashMap<u32, PendingPrompt>,
+    next_prompt: u32,
+    authorizer:  Authorizer<(Message, Handler)>,
+}
+
+impl Daemon {
+    fn new() -> Self {
+        Daemon {
+            installer:   Installer::default(),
+            disks:       None,
+            config:      None,
+            install:     None,
+            prompts:     HashMap::new(),
+            next_prompt: 0,
+            authorizer:  Authorizer::spawn(polkit),
+        }
+    }
+
+    fn install(&mut self) -> Result<(), MethodErr> {
+        if self.install.is_some() {
+            return Err(MethodErr::failed(&"an install is already running"));
+        }
+
+        let disks = self.disks.take().ok_or_else(|| MethodErr::failed(&"no disks were staged"))?;
+        let config = match self.config.clone() {
+            Some(config) => config,
+            None => {
+                self.disks = Some(disks);
+                return Err(MethodErr::failed(&"no config was set"));
+            }
+        };
+
+        self.installer.cancel_handle().reset();
+        self.install = Some(self.installer.install_async(disks, config));
+        Ok(())
+    }
+
+    /// Takes the events that the install has sent, and joins its thread once it has finished.
+    fn events(&mut self) -> Vec<InstallerEvent> {
+        let events = match self.install {
+            Some((_, ref events)) => events.try_iter().collect::<Vec<_>>(),
+            None => return Vec::new(),
+        };
+
+        if events.iter().any(|event| matches!(event, InstallerEvent::Finished(_))) {
+            // Prompts which were not answered can't be answered anymore.
+            self.prompts.clear();
+            if let Some((handle, _)) = self.install.take() {
+                match handle.join() {
+                    Ok(Some(log)) => println!("distinst: the install log was kept at {:?}", log),
+                    Ok(None) => (),
+                    Err(_) => eprintln!("distinst: the install thread panicked"),
+                }
+            }
+        }
+
+        events
+    }
+
+    /// The signal that an event of the install is emitted as. Prompts are kept until they
+    /// are answered with `Answer`, or the install has finished.
+    fn signal(&mut self, event: InstallerEvent) -> Message {
+        let signal = |name: &'static str| {
+            Message::signal(&OBJECT_PATH.into(), &INTERFACE.into(), &name.into())
+        };
+
+        match event {
+            InstallerEvent::Status(status) => {
+                signal("Status").append2(format!("{:?}", status.step), status.percent)
+            }
+            InstallerEvent::Error(error) => {
+                signal("Error").append2(format!("{:?}", error.step()), error.to_string())
+            }
+            InstallerEvent::Log { level, message, .. } => {
+                signal("Log").append2(level.to_string(), message)
+            }
+            InstallerEvent::Prompt(prompt) => {
+                let id = self.next_prompt;
+                self.next_prompt = self.next_prompt.wrapping_add(1);
+                let (name, kind, message, options, pending) = match prompt {
+                    Prompt::Timezone(responder) => {
+                        let pending = PendingPrompt::Timezone(responder);
+                        ("Timezone".into(), "timezone", String::new(), Vec::new(), pending)
+                    }
+                    Prompt::User(responder) => {
+                        let pending = PendingPrompt::User(responder);
+                        ("User".into(), "user", String::new(), Vec::new(), pending)
+                    }
+                    Prompt::Question(question, responder) => {
+                        let (name, kind, options) = describe_question(&question);
+                        let pending = PendingPrompt::Question(question.kind, responder);
+                        (name, kind, question.message, options, pending)
+                    }
+                };
+
+                self.prompts.insert(id, pending);
+
+                signal("Prompt").append3(id, name, kind).append2(message, options)
+            }
+            InstallerEvent::Finished(result) => {
+                let message = result.as_ref().err().map_or_else(String::new, |why| why.to_string());
+                signal("Finished").append2(result.is_ok(), message)
+            }
+        }
+    }
+}
+
+/// Serves the installer on the system bus until the daemon is stopped.
+pub fn run() -> io::Result<()> {
+    let connection = LocalConnection::new_system().map_err(dbus_error)?;
+    connection.request_name(BUS_NAME, false, true, true).map_err(dbus_error)?;
+
+    let daemon = Rc::new(RefCell::new(Daemon::new()));
+    let factory = Factory::new_fn::<()>();
+
+    let method = |name: &'static str, handler: Handler| {
+        let daemon = daemon.clone();
+        factory.method(name, (), move |m: &MethodInfo<MTFn<()>, ()>| {
+            let sender =
+                m.msg.sender().ok_or_else(|| MethodErr::failed(&"message has no sender"))?;
+            let mut daemon = daemon.borrow_mut();
+            if daemon.authorizer.is_authorized(&sender) {
+                return handler(&mut daemon, m.msg);
+            }
+
+            // The call is answered once polkit has answered, without blocking other callers.
+            let msg = m.msg.duplicate().map_err(|why| MethodErr::failed(&why))?;
+            daemon.authorizer.wait(&sender, (msg, handler));
+            Ok(Vec::new())
+        })
+    };
+
+    let interface = factory
+        .interface(INTERFACE, ())
+        .add_m(method("ProbeDisks", probe_disks).outarg::<&str, _>("disks"))
+        .add_m(method("StageOperations", stage_operations).inarg::<&str, _>("disks"))
+        .add_m(
+            method("SetPassphrase", set_passphrase)
+                .inarg::<&str, _>("volume")
+                .inarg::<&str, _>("passphrase"),
+        )
+        .add_m(method("SetConfig", set_config).inarg::<&str, _>("recipe"))
+        .add_m(method("Install", |daemon, msg| {
+            daemon.install()?;
+            Ok(vec![msg.method_return()])
+        }))
+        .add_m(method("Answer", answer).inarg::<u32, _>("id").inarg::<&str, _>("answer"))
+        .add_m(method("Cancel", |daemon, msg| {
+            daemon.installer.cancel_handle().cancel();
+            // Dropping the responders of the prompts stops the install from waiting on them.
+            daemon.prompts.clear();
+            Ok(vec![msg.method_return()])
+        }))
+        .add_s(factory.signal("Status", ()).sarg::<&str, _>("step").sarg::<i32, _>("percent"))
+        .add_s(factory.signal("Error", ()).sarg::<&str, _>("step").sarg::<&str, _>("message"))
+        .add_s(factory.signal("Log", ()).sarg::<&str, _>("level").sarg::<&str, _>("message"))
+        .add_s(
+            factory
+                .signal("Prompt", ())
+                .sarg::<u32, _>("id")
+                .sarg::<&str, _>("name")
+                .sarg::<&str, _>("kind")
+                .sarg::<&str, _>("message")
+                .sarg::<Vec<&str>, _>("options"),
+        )
+        .add_s(
+            factory.signal("Finished", ()).sarg::<bool, _>("success").sarg::<&str, _>("message"),
+        );
+
+    let tree =
+        factory.tree(()).add(factory.object_path(OBJECT_PATH, ()).introspectable().add(interface));
+    tree.start_receive(&connection);
+
+    println!("distinst: serving {} on the system bus", BUS_NAME);
+    loop {
+        connection.process(POLL).map_err(dbus_error)?;
+        let mut daemon = daemon.borrow_mut();
+
+        for ((msg, handler), authorized) in daemon.authorizer.answered() {
+            let replies = authorized.and_then(|()| handler(&mut daemon, &msg));
+            match replies {
+                Ok(replies) => replies.into_iter().for_each(|reply| {
+                    let _ = connection.send(reply);
+                }),
+                Err(why) => {
+                    let _ = connection.send(why.to_message(&msg));
+                }
+            }
+        }
+
+        for event in daemon.events() {
+            let _ = connection.send(daemon.signal(event));
+        }
+    }
+}
+
+fn probe_disks(_daemon: &mut Daemon, msg: &Message) -> MethodResult {
+    let disks = Disks::probe_devices().map_err(|why| MethodErr::failed(&why))?;
+    let json = disks.to_json().map_err(|why| MethodErr::failed(&why))?;
+    Ok(vec![msg.method_return().append1(json)])
+}
+
+fn stage_operations(daemon: &mut Daemon, msg: &Message) -> MethodResult {
+    let json: &str = msg.read1()?;
+    let disks = serde_json::from_str::<Disks>(json).map_err(|why| MethodErr::invalid_arg(&why))?;
+    daemon.disks = Some(disks);
+    Ok(vec![msg.method_return()])
+}
+
+/// Gives the passphrase to the staged encrypted volume which has neither a passphrase nor a
+/// keyfile, by the name of its physical volume.
+fn set_passphrase(daemon: &mut Daemon, msg: &Message) -> MethodResult {
+    let (volume, passphrase): (&str, &str) = msg.read2()?;
+    let disks = daemon.disks.as_mut().ok_or_else(|| MethodErr::failed(&"no disks were staged"))?;
+    let encryption = disks
+        .get_keyless_encryptions_mut()
+        .into_iter()
+        .find(|encryption| encryption.physical_volume == volume)
+        .ok_or_else(|| {
+            MethodErr::invalid_arg(&format!("{} is not an encrypted volume without a key", volume))
+        })?;
+
+    encryption.password = Some(zeroize::Zeroizing::new(passphrase.to_owned()));
+    Ok(vec![msg.method_return()])
+}
+
+/// Sets the config of the install from a recipe, and stages the disks that the recipe
+/// partitions, if it declares any.
+fn set_config(daemon: &mut Daemon, msg: &Message) -> MethodResult {
+    let json: &str = msg.read1()?;
+    let recipe = RecipeFile::from_json(json).map_err(|why| MethodErr::invalid_arg(&why))?;
+    let config = recipe.config().map_err(|why| MethodErr::invalid_arg(&why))?;
+    if !recipe.disks.is_empty() {
+        daemon.disks = Some(recipe.disks().map_err(|why| MethodErr::invalid_arg(&why))?);
+    }
+
+    daemon.config = Some(config);
+    Ok(vec![msg.method_return()])
+}
+
+/// Answers the prompt which was signalled with the ID.
+///
+/// Time zones are answered with their Olson name, and users with the JSON of a user of a
+/// recipe. Questions are answered as `parse_answer` describes.
+fn answer(daemon: &mut Daemon, msg: &Message) -> MethodResult {
+    let (id, answer): (u32, &str) = msg.read2()?;
+    let prompt = daemon
+        .prompts
+        .remove(&id)
+        .ok_or_else(|| MethodErr::invalid_arg(&format!("no prompt has the ID {}", id)))?;
+
+    if let Err((prompt, why)) = prompt.respond(answer) {
+        daemon.prompts.insert(id, prompt);
+        return Err(MethodErr::invalid_arg(&why));
+    }
+
+    Ok(vec![msg.method_return()])
+}
+
+/// The name, kind, and options of the question, as the `Prompt` signal gives them.
+fn describe_question(question: &Question) -> (String, &'static str, Vec<String>) {
+    let (kind, options) = match question.kind {
+        QuestionKind::Secret => ("secret", Vec::new()),
+        QuestionKind::YesNo => ("yes-no", Vec::new()),
+        QuestionKind::Choice(ref options) => ("choice", options.clone()),
+    };
+
+    (format!("{:?}", question.id), kind, options)
+}
+
+/// Parses the answer to a question of the kind: the secret itself, `yes` or `no`, or the
+/// index of the option which was chosen.
+fn parse_answer(kind: &QuestionKind, answer: &str) -> Result<Answer, String> {
+    match *kind {
+        QuestionKind::Secret => Ok(Answer::Secret(answer.to_owned())),
+        QuestionKind::YesNo => match answer {
+            "yes" => Ok(Answer::YesNo(true)),
+            "no" => Ok(Answer::YesNo(false)),
+            _ => Err(format!("'{}' is neither 'yes' nor 'no'", answer)),
+        },
+        QuestionKind::Choice(ref options) => match answer.parse::<usize>() {
+            Ok(index) if index < options.len() => Ok(Answer::Choice(index)),
+            _ => Err(format!("'{}' is not the index of one of {} options", answer, options.len())),
+        },
+    }
+}
+
+fn find_timezone(name: &str) -> Result<Region, String> {
+    let zones = Timezones::new().map_err(|why| format!("failed to read time zones: {}", why))?;
+    zones
+        .zones()
+        .iter()
+        .flat_map(|zone| zone.regions())
+        .find(|region| region.olson_name() == Some(name))
+        .cloned()
+        .ok_or_else(|| format!("'{}' is not a time zone", name))
+}
+
+/// Authorizes callers on its own thread, so that the daemon continues to serve authorized
+/// callers, and to emit the signals of the install, while a user authenticates.
+///
+/// Callers are authorized once, by their unique bus name, which is never reused. The calls
+/// of a caller wait for it to be authorized, and are answered in the order that they were
+/// made.
+struct Authorizer<C> {
+    authorized: HashSet<String>,
+    waiting:    HashMap<String, Vec<C>>,
+    requests:   Sender<String>,
+    results:    Receiver<(String, Result<bool, String>)>,
+}
+
+impl<C> Authorizer<C> {
+    /// Spawns the thread which authorizes callers with the check that `init` creates on it.
+    fn spawn<I, F>(init: I) -> Self
+    where
+        I: FnOnce() -> F + Send + 'static,
+        F: FnMut(&str) -> Result<bool, String>,
+    {
+        let (requests, received) = mpsc::channel::<String>();
+        let (answered, results) = mpsc::channel();
+        thread::spawn(move || {
+            let mut check = init();
+            for sender in received {
+                let result = check(&sender);
+                if answered.send((sender, result)).is_err() {
+                    break;
+                }
+            }
+        });
+
+        Authorizer { authorized: HashSet::new(), waiting: HashMap::new(), requests, results }
+    }
+
+    fn is_authorized(&self, sender: &str) -> bool { self.authorized.contains(sender) }
+
+    /// Holds the call until the caller has been authorized, asking for it if it was not.
+    fn wait(&mut self, sender: &str, call: C) {
+        let calls = self.waiting.entry(sender.to_owned()).or_insert_with(Vec::new);
+        if calls.is_empty() {
+            let _ = self.requests.send(sender.to_owned());
+        }
+
+        calls.push(call);
+    }
+
+    /// The calls of the callers which polkit has answered for, with whether they may be
+    /// made.
+    fn answered(&mut self) -> Vec<(C, Result<(), MethodErr>)> {
+        let mut answered = Vec::new();
+        for (sender, result) in self.results.try_iter() {
+            let denied = match result {
+                Ok(true) => {
+                    self.authorized.insert(sender.clone());
+                    None
+                }
+                Ok(false) => Some((
+                    "org.freedesktop.DBus.Error.AccessDenied",
+                    "not authorized to install".into(),
+                )),
+                Err(why) => Some(("org.freedesktop.DBus.Error.Failed", why)),
+            };
+
+            for call in self.waiting.remove(&sender).unwrap_or_default() {
+                let result = match denied {
+                    Some((name, ref why)) => Err(MethodErr::from((name, why.as_str()))),
+                    None => Ok(()),
+                };
+
+                answered.push((call, result));
+            }
+        }
+
+        answered
+    }
+}
+
+/// Creates the check of the authorizer, which asks polkit whether the caller may install
+/// over its own connection to the system bus. The user may be asked to authenticate.
+fn polkit() -> impl FnMut(&str) -> Result<bool, String> {
+    let connection = LocalConnection::new_system().map_err(|why| why.to_string());
+    move |sender: &str| {
+        let connection = connection.as_ref().map_err(Clone::clone)?;
+
+        let mut name = HashMap::new();
+        name.insert("name", Variant(Box::new(sender.to_owned()) as Box<dyn RefArg>));
+        let subject = ("system-bus-name", name);
+        let details = HashMap::<&str, &str>::new();
+        // Allows the user to be asked to authenticate.
+        let flags = 1u32;
+
+        let polkit = connection.with_proxy(
+            "org.freedesktop.PolicyKit1",
+            "/org/freedesktop/PolicyKit1/Authority",
+            AUTHORIZATION_TIMEOUT,
+        );
+
+        let ((authorized, ..),): ((bool, bool, HashMap<String, String>),) = polkit
+            .method_call(
+                "org.freedesktop.PolicyKit1.Authority",
+                "CheckAuthorization",
+                (subject, POLKIT_ACTION, details, flags, ""),
+            )
+            .map_err(|why| why.to_string())?;
+
+        Ok(authorized)
+    }
+}
+
+fn dbus_error(why: dbus::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, format!("dbus error: {}", why))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    #[test]
+    fn answers() {
+        let secret = parse_answer(&QuestionKind::Secret, "hunter2").unwrap();
+        assert_eq!(secret, Answer::Secret("hunter2".into()));
+        assert_eq!(parse_answer(&QuestionKind::YesNo, "no"), Ok(Answer::YesNo(false)));
+        assert!(parse_answer(&QuestionKind::YesNo, "maybe").is_err());
+
+        let choice = QuestionKind::Choice(vec!["sda".into(), "sdb".into()]);
+        assert_eq!(parse_answer(&choice, "1"), Ok(Answer::Choice(1)));
+        assert!(parse_answer(&choice, "2").is_err());
+        assert!(parse_answer(&choice, "sdb").is_err());
+    }
+
+    #[test]
+    fn questions() {
+        let question = |kind| Question {
+            id: QuestionId::FormatEsp,
+            message: "format the existing EFI partition /dev/sda1?".into(),
+            kind,
+        };
+
+        let (name, kind, options) = describe_question(&question(QuestionKind::YesNo));
+        assert_eq!((name.as_str(), kind), ("FormatEsp", "yes-no"));
+        assert!(options.is_empty());
+
+        let choice = QuestionKind::Choice(vec!["sda".into(), "sdb".into()]);
+        let (_, kind, options) = describe_question(&question(choice));
+        assert_eq!((kind, options), ("choice", vec!["sda".to_owned(), "sdb".to_owned()]));
+    }
+
+    #[test]
+    fn authorizations() {
+        let mut authorizer = Authorizer::spawn(|| {
+            let mut checked = Vec::new();
+            move |sender: &str| {
+                // Each caller is checked once, however many calls it makes.
+                assert!(!checked.contains(&sender.to_owned()));
+                checked.push(sender.to_owned());
+                Ok(sender == ":1.1")
+            }
+        });
+
+        authorizer.wait(":1.1", "probe");
+        authorizer.wait(":1.1", "stage");
+        authorizer.wait(":1.2", "install");
+
+        let mut answered = Vec::new();
+        let started = Instant::now();
+        while answered.len() < 3 && started.elapsed() < Duration::from_secs(10) {
+            answered.extend(authorizer.answered());
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let calls =
+            answered.iter().map(|(call, result)| (*call, result.is_ok())).collect::<Vec<_>>();
+        assert_eq!(calls, vec![("probe", true), ("stage", true), ("install", false)]);
+        assert!(authorizer.is_authorized(":1.1"));
+        assert!(!authorizer.is_authorized(":1.2"));
+    }
+}
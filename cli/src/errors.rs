@@ -22,7 +22,10 @@ pub(crate) enum DistinstError {
     ArgNaN { arg: String },
     #[fail(display = "partition '{}' was not found", partition)]
     PartitionNotFound { partition: i32 },
-    #[fail(display = "four arguments must be supplied to the move operation")]
+    #[fail(
+        display = "four arguments must be supplied to the move operation, or three for a \
+                   logical volume"
+    )]
     MoveArgs,
     #[fail(display = "provided sector value, '{}', was invalid", value)]
     InvalidSectorValue { value: String },
@@ -56,10 +59,18 @@ pub(crate) enum DistinstError {
     NoMountPath,
     #[fail(display = "mount value is empty")]
     EmptyMount,
+    #[fail(display = "logical volumes may not be used as LVM physical volumes")]
+    LvmOnLogical,
     #[fail(display = "unable to add partition to lvm device: {}", why)]
     LvmPartitionAdd { why: DiskError },
     #[fail(display = "unable to initialize volume groups: {}", why)]
     InitializeVolumes { why: DiskError },
+    #[fail(display = "{}", why)]
+    Recipe { why: io::Error },
+    #[fail(display = "unable to read the passphrase: {}", why)]
+    Passphrase { why: io::Error },
+    #[fail(display = "unable to erase the disk to install to it: {}", why)]
+    Erase { why: distinst::auto::InstallOptionError },
 }
 
 impl From<DiskError> for DistinstError {
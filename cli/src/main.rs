@@ -1,24 +1,27 @@
 extern crate clap;
+extern crate dbus;
 extern crate distinst;
 extern crate failure;
 #[macro_use]
 extern crate failure_derive;
 extern crate libc;
 extern crate pbr;
+extern crate serde_json;
 
 mod configure;
+mod daemon;
 mod errors;
 
 use clap::{App, Arg, ArgMatches, Values};
 use configure::*;
-use distinst::{timezones::Timezones, *};
+use distinst::*;
 use errors::DistinstError;
 
 use pbr::ProgressBar;
 
 use std::{
     cell::RefCell,
-    io,
+    fs, io,
     path::{Path, PathBuf},
     process::exit,
     rc::Rc,
@@ -54,21 +57,357 @@ fn main() {
                 .help("path to icon for user profile")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("hosts-append")
+                .long("hosts-append")
+                .help("an entry to append to /etc/hosts, such as \"10.0.0.5 ldap.corp\"")
+                .takes_value(true)
+                .multiple(true),
+        )
+        .arg(
+            Arg::with_name("local-packages")
+                .long("local-packages")
+                .help("a directory of .deb packages to install, such as a pool on the ISO")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("language-package")
+                .long("language-package")
+                .help(
+                    "a language pack, font, or input method to install instead of those chosen \
+                     for the locale, which may be repeated",
+                )
+                .takes_value(true)
+                .multiple(true),
+        )
+        .arg(
+            Arg::with_name("allow-network")
+                .long("allow-network")
+                .help("allow language packages which are not on the ISO to be downloaded"),
+        )
+        .arg(
+            Arg::with_name("proxy")
+                .long("proxy")
+                .help("the HTTP proxy which packages are fetched through while configuring")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("apt-mirror")
+                .long("apt-mirror")
+                .help("a mirror of the archive which packages are fetched from while configuring")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("dns")
+                .long("dns")
+                .help("a name server which resolves host names while configuring")
+                .takes_value(true)
+                .multiple(true),
+        )
+        .arg(
+            Arg::with_name("persist-network")
+                .long("persist-network")
+                .help("keep the proxy, apt mirror, and name servers in the install"),
+        )
+        .arg(
+            Arg::with_name("overlay")
+                .long("overlay")
+                .help("a directory whose files are copied onto the install once it is extracted")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("firstboot-script")
+                .long("firstboot-script")
+                .help("a script which is run once, on the first boot of the install")
+                .takes_value(true)
+                .multiple(true),
+        )
+        .arg(
+            Arg::with_name("root-password-hash")
+                .long("root-password-hash")
+                .help("the crypt(3) hash of a password which root may log in with")
+                .takes_value(true)
+                .conflicts_with("root-authorized-keys"),
+        )
+        .arg(
+            Arg::with_name("root-authorized-keys")
+                .long("root-authorized-keys")
+                .help("a file of public keys, which are the only way that root may log in")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("ssh-server")
+                .long("ssh-server")
+                .help("enable the SSH server of the install"),
+        )
+        .arg(
+            Arg::with_name("authorized-keys")
+                .long("authorized-keys")
+                .help("a file of public keys which may log in to the user, or to root over SSH")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("permit-root-login")
+                .long("permit-root-login")
+                .help("permit root to log in over SSH with a key"),
+        )
+        .arg(
+            Arg::with_name("disable-password-auth")
+                .long("disable-password-auth")
+                .help("permit only keys to log in over SSH, rather than passwords"),
+        )
+        .arg(
+            Arg::with_name("install-drivers")
+                .long("install-drivers")
+                .help("install the drivers of devices which need them, such as NVIDIA GPUs"),
+        )
+        .arg(
+            Arg::with_name("fstab-id")
+                .long("fstab-id")
+                .help("how file systems are identified in /etc/fstab")
+                .takes_value(true)
+                .possible_values(&["uuid", "partuuid", "label", "path"])
+                .default_value("uuid"),
+        )
+        .arg(
+            Arg::with_name("initramfs")
+                .long("initramfs")
+                .help("the tool which generates the initramfs, instead of the one in the image")
+                .takes_value(true)
+                .possible_values(&["initramfs-tools", "dracut", "mkinitcpio"]),
+        )
+        .arg(
+            Arg::with_name("hibernation")
+                .long("hibernation")
+                .help("resume from hibernation, which requires swap as large as the memory"),
+        )
+        .arg(
+            Arg::with_name("swapfile")
+                .long("swapfile")
+                .help("create a swap file of the given size, in MiB, on the root file system")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("bootloader")
+                .long("bootloader")
+                .help("the bootloader to install, instead of the one for the firmware and OS")
+                .takes_value(true)
+                .possible_values(&[
+                    "auto",
+                    "systemd-boot",
+                    "grub-efi",
+                    "grub-bios",
+                    "extlinux",
+                    "raspberry-pi",
+                    "grub-ieee1275",
+                ])
+                .default_value("auto"),
+        )
+        .arg(
+            Arg::with_name("bootloader-disk")
+                .long("bootloader-disk")
+                .help("the disk to install the BIOS bootloader to, instead of the disk of /boot")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("efi-label")
+                .long("efi-label")
+                .help("the label of the EFI boot entry, which replaces entries of the same label")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("boot-order")
+                .long("boot-order")
+                .help("where the EFI boot entry is placed in the boot order, when modifying it")
+                .takes_value(true)
+                .possible_values(&["first", "last", "preserve"])
+                .default_value("first"),
+        )
+        .arg(
+            Arg::with_name("no-os-prober")
+                .long("no-os-prober")
+                .help("do not add the other systems that os-prober finds to the menu of GRUB"),
+        )
+        .arg(
+            Arg::with_name("systemd-boot-timeout")
+                .long("systemd-boot-timeout")
+                .help("the seconds for which the menu of systemd-boot is shown")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("systemd-boot-default")
+                .long("systemd-boot-default")
+                .help("the default entry of systemd-boot: current, oldkern, recovery, or an ID")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("systemd-boot-console-mode")
+                .long("systemd-boot-console-mode")
+                .help("the console mode of the menu of systemd-boot, such as auto or max")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("systemd-boot-editor")
+                .long("systemd-boot-editor")
+                .help("whether the kernel command line may be edited from systemd-boot")
+                .takes_value(true)
+                .possible_values(&["yes", "no"]),
+        )
+        .arg(
+            Arg::with_name("grub-distributor")
+                .long("grub-distributor")
+                .help("the GRUB_DISTRIBUTOR, which the entries of GRUB are named after")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("grub-theme")
+                .long("grub-theme")
+                .help("a directory with a theme.txt, to install as the theme of GRUB")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("grub-background")
+                .long("grub-background")
+                .help("an image to show behind the menu of GRUB")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("grub-hidden-timeout")
+                .long("grub-hidden-timeout")
+                .help("hide the menu of GRUB for this many seconds, in which a key shows it")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("grub-terminal")
+                .long("grub-terminal")
+                .help("the GRUB_TERMINAL, such as console or serial")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("kernel-cmdline")
+                .long("kernel-cmdline")
+                .help("arguments to add to the kernel command line, such as \"quiet splash\"")
+                .takes_value(true)
+                .multiple(true),
+        )
+        .arg(
+            Arg::with_name("zram")
+                .long("zram")
+                .help("swap to a compressed device in memory, of this fraction of the memory")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("zram-algorithm")
+                .long("zram-algorithm")
+                .help("the compression algorithm of zram, such as zstd or lz4")
+                .requires("zram")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("recovery")
+                .long("recovery")
+                .help("copy the live system to this FAT32 partition, to reinstall from")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("recovery-source")
+                .long("recovery-source")
+                .help("the casper directory of the live system, or an ISO of it")
+                .takes_value(true)
+                .default_value("/cdrom/casper"),
+        )
+        .arg(
+            Arg::with_name("refresh")
+                .long("refresh")
+                .help("reinstall the system at the root with this UUID, retaining its users")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("preserve")
+                .long("preserve")
+                .help(
+                    "a path of the old root to retain when refreshing, instead of the defaults, \
+                     which may be repeated",
+                )
+                .takes_value(true)
+                .multiple(true)
+                .requires("refresh"),
+        )
+        .arg(
+            Arg::with_name("oem")
+                .long("oem")
+                .help("prepare the install for an OEM, deferring the user's setup to first boot")
+                .conflicts_with("username"),
+        )
+        .arg(
+            Arg::with_name("autologin")
+                .long("autologin")
+                .help("log the user in automatically when the new install boots")
+                .requires("username"),
+        )
+        .arg(
+            Arg::with_name("shell")
+                .long("shell")
+                .help("the login shell of the user, rather than /bin/bash")
+                .requires("username")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("timezone")
                 .long("tz")
-                .help("the timezone to set for the new install")
-                .value_delimiter("/")
-                .min_values(2)
-                .max_values(2),
+                .help("the timezone to set for the new install, such as Europe/Prague")
+                .takes_value(true),
         )
         .arg(
             Arg::with_name("squashfs")
                 .short("s")
                 .long("squashfs")
-                .help("define the squashfs image which will be installed")
+                .help(
+                    "define the squashfs image, tar archive, or directory which will be \
+                     installed, which may be an HTTP or HTTPS URL to download it from",
+                )
                 .takes_value(true)
-                .required(true),
+                .required_unless_one(&["config", "probe", "daemon"]),
+        )
+        .arg(
+            Arg::with_name("layer")
+                .long("layer")
+                .help("a squashfs image to extract over the base image, which may be repeated")
+                .takes_value(true)
+                .multiple(true),
+        )
+        .arg(
+            Arg::with_name("squashfs-checksum")
+                .long("squashfs-checksum")
+                .help("the SHA-256 checksum of the image, which is verified before installing")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("layer-checksum")
+                .long("layer-checksum")
+                .help("the SHA-256 checksum of each layer, in the order of the layers")
+                .takes_value(true)
+                .multiple(true),
+        )
+        .arg(
+            Arg::with_name("extract-threads")
+                .long("extract-threads")
+                .help("the number of threads which decompress the image")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("space-margin")
+                .long("space-margin")
+                .help(
+                    "the space required beyond the size of the image, as a percent of it, \
+                     before the disks are modified",
+                )
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("no-trim")
+                .long("no-trim")
+                .help("do not trim the file systems of the install once it is configured"),
         )
         .arg(
             Arg::with_name("hostname")
@@ -76,18 +415,27 @@ fn main() {
                 .long("hostname")
                 .help("define the hostname that the new system will have")
                 .takes_value(true)
-                .required(true),
+                .required_unless_one(&["config", "probe", "daemon"]),
         )
         .arg(
             Arg::with_name("keyboard")
                 .short("k")
                 .long("keyboard")
-                .help("define the keyboard configuration to use")
+                .help(
+                    "define the keyboard configuration to use, as layout[:variant[:model]], or as \
+                     separate layout, model, and variant values",
+                )
                 .takes_value(true)
                 .min_values(1)
                 .max_values(3)
                 .default_value("us"),
         )
+        .arg(
+            Arg::with_name("keyboard-options")
+                .long("keyboard-options")
+                .help("XKB options to use, separated by commas, such as \"ctrl:nocaps\"")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("lang")
                 .short("l")
@@ -96,13 +444,20 @@ fn main() {
                 .takes_value(true)
                 .default_value("en_US.UTF-8"),
         )
+        .arg(
+            Arg::with_name("additional-locale")
+                .long("additional-locale")
+                .help("another locale to generate for the new system, which may be repeated")
+                .takes_value(true)
+                .multiple(true),
+        )
         .arg(
             Arg::with_name("remove")
                 .short("r")
                 .long("remove")
                 .help("defines the manifest file that contains the packages to remove post-install")
                 .takes_value(true)
-                .required(true),
+                .required_unless_one(&["config", "probe", "daemon"]),
         )
         .arg(
             Arg::with_name("disk")
@@ -111,7 +466,17 @@ fn main() {
                 .help("defines a disk that will be manipulated in the installation process")
                 .takes_value(true)
                 .multiple(true)
-                .required(true),
+                .required_unless_one(&["to-dir", "config", "erase-and-install", "probe", "daemon"]),
+        )
+        .arg(
+            Arg::with_name("erase-and-install")
+                .long("erase-and-install")
+                .help(
+                    "erases the disk and installs to it with the default layout, given as \
+                     disk[:encrypt], where the passphrase is read from the terminal or stdin",
+                )
+                .takes_value(true)
+                .conflicts_with_all(&["disk", "to-dir"]),
         )
         .arg(
             Arg::with_name("table")
@@ -140,11 +505,49 @@ fn main() {
                 .takes_value(true)
                 .multiple(true),
         )
+        .arg(
+            Arg::with_name("probe")
+                .long("probe")
+                .help("print the disks of the system as JSON, and exit without installing")
+                .conflicts_with_all(&["config", "disk", "erase-and-install", "to-dir", "test"]),
+        )
+        .arg(
+            Arg::with_name("daemon")
+                .long("daemon")
+                .help("serve the installer on the system bus, for frontends which run as a user")
+                .conflicts_with_all(&["config", "disk", "erase-and-install", "to-dir", "probe"]),
+        )
         .arg(
             Arg::with_name("test")
                 .long("test")
                 .help("simply test whether the provided arguments pass the partitioning stage"),
         )
+        .arg(
+            Arg::with_name("yes")
+                .short("y")
+                .long("yes")
+                .help("answer yes when the install asks to confirm, such as to format an old ESP"),
+        )
+        .arg(
+            Arg::with_name("to-dir")
+                .long("to-dir")
+                .help(
+                    "extract and configure the install in this empty directory, without \
+                     partitioning or installing a bootloader",
+                )
+                .takes_value(true)
+                .conflicts_with_all(&["disk", "resume", "test"]),
+        )
+        .arg(
+            Arg::with_name("to-dir-commands")
+                .long("to-dir-commands")
+                .help("run commands in the directory of --to-dir, within a user namespace")
+                .requires("to-dir"),
+        )
+        .arg(Arg::with_name("resume").long("resume").help(
+            "resume an install which was interrupted after partitioning, where the partitions of \
+             that install are mounted at the same targets without being formatted",
+        ))
         .arg(
             Arg::with_name("hardware-support")
                 .long("hardware-support")
@@ -182,7 +585,10 @@ fn main() {
             Arg::with_name("move")
                 .short("m")
                 .long("move")
-                .help("defines to move and/or resize an existing partition")
+                .help(
+                    "defines to move and/or resize an existing partition, or to resize an \
+                     existing logical volume with `vg/lv:none:size`",
+                )
                 .takes_value(true)
                 .multiple(true),
         )
@@ -213,6 +619,23 @@ fn main() {
                 .help("TODO")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("snapshot-previous-root")
+                .long("snapshot-previous-root")
+                .help("snapshots the logical volume of the previous root before reusing it"),
+        )
+        .arg(
+            Arg::with_name("snapshot-percent")
+                .long("snapshot-percent")
+                .help("the size of the snapshot, as a percentage of the previous root")
+                .takes_value(true)
+                .default_value("20"),
+        )
+        .arg(
+            Arg::with_name("allow-weak-passphrase")
+                .long("allow-weak-passphrase")
+                .help("permits encryption passphrases that are estimated to be easily guessed"),
+        )
         .arg(
             Arg::with_name("decrypt")
                 .long("decrypt")
@@ -225,37 +648,93 @@ fn main() {
                 .long("run-ubuntu-drivers")
                 .help("use ubuntu-drivers to find drivers then install in the chroot, some may have proprietary licenses")
         )
+        .arg(
+            Arg::with_name("delete-extraneous")
+                .long("delete-extraneous")
+                .help("when installing from a directory, delete files which are not in it"),
+        )
+        .arg(
+            Arg::with_name("keep-identity")
+                .long("keep-identity")
+                .help("keep the machine ID, SSH host keys, and random seed of the image"),
+        )
+        .arg(
+            Arg::with_name("force-fallback")
+                .long("force-fallback")
+                .help("install the fallback EFI loader even if another OS installed one"),
+        )
+        .arg(
+            Arg::with_name("windows-second")
+                .long("windows-second")
+                .help("boot the Windows Boot Manager second when modifying the boot order"),
+        )
+        .arg(
+            Arg::with_name("config")
+                .long("config")
+                .help(
+                    "an unattended install, declared in a TOML or JSON file, whose settings are \
+                     overridden by those which are given as arguments",
+                )
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("log-file")
+                .long("log-file")
+                .help("append the debug log, with timestamps, to this file")
+                .takes_value(true),
+        )
         .get_matches();
 
     if let Err(err) = distinst::log(|_level, _message| {}) {
         eprintln!("Failed to initialize logging: {}", err);
     }
 
-    let squashfs = matches.value_of("squashfs").unwrap();
-    let hostname = matches.value_of("hostname").unwrap();
-    let mut keyboard = matches.values_of("keyboard").unwrap();
-    let lang = matches.value_of("lang").unwrap();
-    let remove = matches.value_of("remove").unwrap();
-
-    let tzs_;
-    let timezone = match matches.values_of("timezone") {
-        Some(mut tz) => {
-            let (zone, region) = (tz.next().unwrap(), tz.next().unwrap());
-            tzs_ = Timezones::new().expect("failed to get timzones");
-            let zone = tzs_
-                .zones()
-                .into_iter()
-                .find(|z| z.name() == zone)
-                .expect(&format!("failed to find zone: {}", zone));
-            let region = zone
-                .regions()
-                .into_iter()
-                .find(|r| r.name() == region)
-                .expect(&format!("failed to find region: {}", region));
-            Some(region.clone())
+    if let Some(path) = matches.value_of("log-file") {
+        if let Err(err) = distinst::log_to_file(path, LevelFilter::Debug) {
+            eprintln!("Failed to log to {}: {}", path, err);
         }
-        None => None,
-    };
+    }
+
+    if matches.is_present("daemon") {
+        if let Err(why) = daemon::run() {
+            eprintln!("distinst: {}", why);
+            exit(1);
+        }
+
+        return;
+    }
+
+    if matches.is_present("probe") {
+        let json = Disks::probe_devices()
+            .map_err(|why| format!("failed to probe disks: {}", why))
+            .and_then(|disks| {
+                disks.to_json().map_err(|why| format!("failed to describe disks: {}", why))
+            });
+
+        match json {
+            Ok(json) => println!("{}", json),
+            Err(why) => {
+                eprintln!("distinst: {}", why);
+                exit(1);
+            }
+        }
+
+        return;
+    }
+
+    let recipe = matches.value_of("config").map(|path| {
+        RecipeFile::open(path).unwrap_or_else(|why| {
+            eprintln!("distinst: {}", why);
+            exit(1);
+        })
+    });
+
+    // Those which are required are declared by the recipe if they are not given.
+    let squashfs = matches.value_of("squashfs").unwrap_or_default();
+    let hostname = matches.value_of("hostname").unwrap_or_default();
+    let keyboard = matches.values_of("keyboard").unwrap().collect::<Vec<&str>>();
+    let lang = matches.value_of("lang").unwrap();
+    let remove = matches.value_of("remove").unwrap_or_default();
 
     let user_account = matches.value_of("username").map(|username| {
         let username = username.to_owned();
@@ -273,12 +752,19 @@ fn main() {
             }
         });
 
-        UserAccountCreate { realname, username, password, profile_icon }
+        let mut user = UserAccountCreate::default();
+        user.realname = realname;
+        user.username = username;
+        user.password = password.map(zeroize::Zeroizing::new);
+        user.profile_icon = profile_icon;
+        user.shell = matches.value_of("shell").map(String::from);
+        user.autologin = matches.is_present("autologin");
+        user
     });
 
     let pb_opt: Rc<RefCell<Option<ProgressBar<io::Stdout>>>> = Rc::new(RefCell::new(None));
 
-    let res = {
+    let (res, install_log) = {
         let mut installer = Installer::default();
 
         {
@@ -288,14 +774,30 @@ fn main() {
                     pb.finish_println("");
                 }
 
-                eprintln!("Error: {:?}", error);
+                eprintln!("Error: {}", error);
+                if let Some(context) = error.context() {
+                    if let Some(ref command) = context.command {
+                        eprintln!("    command: {}", command);
+                    }
+                    if let Some(ref device) = context.device {
+                        eprintln!("    device: {}", device.display());
+                    }
+                }
             });
         }
 
         {
             let pb_opt = pb_opt.clone();
             let mut step_opt = None;
+            let mut label = String::new();
             installer.on_status(move |status| {
+                if status.cancelled {
+                    if let Some(mut pb) = pb_opt.borrow_mut().take() {
+                        pb.finish_println("");
+                    }
+                    return;
+                }
+
                 if step_opt != Some(status.step) {
                     if let Some(mut pb) = pb_opt.borrow_mut().take() {
                         pb.finish_println("");
@@ -306,57 +808,417 @@ fn main() {
                     let mut pb = ProgressBar::new(100);
                     pb.show_speed = false;
                     pb.show_counter = false;
-                    pb.message(match status.step {
+                    label = match status.step {
                         Step::Backup => "Backing up files",
                         Step::Init => "Initializing",
+                        Step::Verify => "Verifying image ",
                         Step::Partition => "Partitioning disk ",
                         Step::Extract => "Extracting filesystem ",
+                        Step::Recovery => "Copying to the recovery partition ",
                         Step::Configure => "Configuring installation",
                         Step::Bootloader => "Installing bootloader ",
-                    });
+                    }
+                    .into();
+                    pb.message(&label);
                     *pb_opt.borrow_mut() = Some(pb);
                 }
 
                 if let Some(ref mut pb) = *pb_opt.borrow_mut() {
+                    // The paths of extracted files change too quickly to be read.
+                    match status.message {
+                        Some(ref message) if status.step != Step::Extract => {
+                            label = [message, " "].concat();
+                        }
+                        _ => (),
+                    }
+
+                    if let Some(substep) = status.substep {
+                        label = [substep, " "].concat();
+                    }
+
+                    match status.eta_seconds() {
+                        Some(eta) => pb.message(&format!("{}({} left) ", label, duration(eta))),
+                        None => pb.message(&label),
+                    }
                     pb.set(status.percent as u64);
                 }
             });
         }
 
-        if let Some(timezone) = timezone {
-            installer.set_timezone_callback(move || timezone.clone());
+        {
+            let pb_opt = pb_opt.clone();
+            let yes = matches.is_present("yes");
+            installer.set_prompt_callback(move |question| {
+                if let Some(mut pb) = pb_opt.borrow_mut().take() {
+                    pb.finish_println("");
+                }
+
+                match question.kind {
+                    QuestionKind::Secret => match read_passphrase(&question.message) {
+                        Ok(secret) => Some(Answer::Secret(secret)),
+                        Err(why) => {
+                            eprintln!("distinst: {}", why);
+                            None
+                        }
+                    },
+                    QuestionKind::YesNo => {
+                        let answer = if yes { "yes" } else { "no, as --yes was not given" };
+                        eprintln!("distinst: {} {}", question.message, answer);
+                        Some(Answer::YesNo(yes))
+                    }
+                    // The options are not chosen for the user.
+                    QuestionKind::Choice(_) => None,
+                }
+            });
+        }
+
+        let snapshot_percent = matches.value_of("snapshot-percent").unwrap();
+        let snapshot_percent = match snapshot_percent.parse::<u8>() {
+            Ok(percent) if percent > 0 && percent <= 100 => percent,
+            _ => {
+                eprintln!("distinst: snapshot percent must be a number from 1 to 100");
+                exit(1);
+            }
+        };
+
+        if let Some(threads) = matches.value_of("extract-threads") {
+            match threads.parse::<usize>() {
+                Ok(threads) if threads > 0 => installer.set_extract_threads(threads),
+                _ => {
+                    eprintln!("distinst: extract threads must be a positive number");
+                    exit(1);
+                }
+            }
+        }
+
+        if let Some(margin) = matches.value_of("space-margin") {
+            match margin.parse::<u8>() {
+                Ok(margin) => installer.set_space_margin(margin),
+                Err(_) => {
+                    eprintln!("distinst: space margin must be a percent from 0 to 255");
+                    exit(1);
+                }
+            }
         }
 
-        if let Some(user_account) = user_account {
-            installer.set_user_callback(move || user_account.clone());
+        if matches.is_present("no-trim") {
+            installer.set_trim(false);
         }
 
-        let disks = match configure_disks(&matches) {
-            Ok(disks) => disks,
+        if matches.is_present("test") {
+            installer.set_simulate(true);
+        }
+
+        let layers =
+            matches.values_of("layer").map_or_else(Vec::new, |l| l.map(String::from).collect());
+        let layer_checksums = matches
+            .values_of("layer-checksum")
+            .map_or_else(Vec::new, |l| l.map(String::from).collect());
+        let keyboard = match configure_keyboard(&keyboard, &matches) {
+            Ok(keyboard) => keyboard,
             Err(why) => {
                 eprintln!("distinst: {}", why);
                 exit(1);
             }
         };
 
-        configure_signal_handling();
+        let additional_locales = matches
+            .values_of("additional-locale")
+            .map_or_else(Vec::new, |l| l.map(String::from).collect());
+        let language_packages =
+            matches.values_of("language-package").map(|p| p.map(String::from).collect());
+        let hosts_append = matches
+            .values_of("hosts-append")
+            .map_or_else(Vec::new, |h| h.map(String::from).collect());
+        let kernel_cmdline = matches.values_of("kernel-cmdline").map_or_else(Vec::new, |args| {
+            args.flat_map(str::split_whitespace).map(String::from).collect()
+        });
+        let dns = matches.values_of("dns").map_or_else(Vec::new, |d| d.map(String::from).collect());
+        let network = NetworkConfig {
+            proxy:      matches.value_of("proxy").map(String::from),
+            apt_mirror: matches.value_of("apt-mirror").map(String::from),
+            dns,
+            persist:    matches.is_present("persist-network"),
+        };
+        let network = if network == NetworkConfig::default() { None } else { Some(network) };
+        let authorized_keys = match matches.value_of("authorized-keys") {
+            Some(path) => match fs::read_to_string(path) {
+                Ok(keys) => vec![keys],
+                Err(why) => {
+                    eprintln!("distinst: failed to read authorized keys from {}: {}", path, why);
+                    exit(1);
+                }
+            },
+            None => Vec::new(),
+        };
+        let root = if let Some(hash) = matches.value_of("root-password-hash") {
+            RootPolicy::Password(hash.into())
+        } else if let Some(path) = matches.value_of("root-authorized-keys") {
+            match fs::read_to_string(path) {
+                Ok(keys) => RootPolicy::Keys(vec![keys]),
+                Err(why) => {
+                    eprintln!("distinst: failed to read authorized keys from {}: {}", path, why);
+                    exit(1);
+                }
+            }
+        } else {
+            RootPolicy::Locked
+        };
+        let ssh = SshConfig {
+            enable_server:         matches.is_present("ssh-server"),
+            authorized_keys,
+            permit_root_login:     matches.is_present("permit-root-login"),
+            disable_password_auth: matches.is_present("disable-password-auth"),
+        };
+        let ssh = if ssh == SshConfig::default() { None } else { Some(ssh) };
+        // Each script is run by a unit that is named after it.
+        let firstboot_units = matches.values_of("firstboot-script").map_or_else(Vec::new, |s| {
+            s.map(|script| {
+                let path = Path::new(script);
+                let name = path.file_stem().map_or(script.into(), |stem| stem.to_string_lossy());
+                FirstBootUnit::from_script(&name, path, true)
+            })
+            .collect()
+        });
+        let swapfile = match matches.value_of("swapfile") {
+            Some(size) => match size.parse::<u64>() {
+                Ok(size) if size > 0 => Some(size),
+                _ => {
+                    eprintln!("distinst: swap file size must be a number of MiB");
+                    exit(1);
+                }
+            },
+            None => None,
+        };
+        let zram = matches.value_of("zram").map(|fraction| match fraction.parse::<f32>() {
+            Ok(fraction_of_ram) => ZramConfig {
+                fraction_of_ram,
+                algorithm: matches.value_of("zram-algorithm").map(String::from),
+            },
+            Err(_) => {
+                eprintln!("distinst: zram size must be a fraction of the memory, such as 0.5");
+                exit(1);
+            }
+        });
+        let fstab_id = match matches.value_of("fstab-id") {
+            Some("partuuid") => FstabId::PartUuid,
+            Some("label") => FstabId::Label,
+            Some("path") => FstabId::Path,
+            _ => FstabId::Uuid,
+        };
+        let initramfs = matches.value_of("initramfs").map(|tool| match tool {
+            "dracut" => InitramfsTool::Dracut,
+            "mkinitcpio" => InitramfsTool::Mkinitcpio,
+            _ => InitramfsTool::InitramfsTools,
+        });
+        let bootloader = match matches.value_of("bootloader") {
+            Some("systemd-boot") => BootloaderChoice::SystemdBoot,
+            Some("grub-efi") => BootloaderChoice::GrubEfi,
+            Some("grub-bios") => BootloaderChoice::GrubBios,
+            Some("extlinux") => BootloaderChoice::Extlinux,
+            Some("raspberry-pi") => BootloaderChoice::RaspberryPi,
+            Some("grub-ieee1275") => BootloaderChoice::GrubIeee1275,
+            _ => BootloaderChoice::Auto,
+        };
+        let boot_order = match matches.value_of("boot-order") {
+            Some("last") => BootOrderPolicy::Last,
+            Some("preserve") => BootOrderPolicy::Preserve,
+            _ => BootOrderPolicy::First,
+        };
+        let systemd_boot = SystemdBootOptions {
+            timeout:      matches.value_of("systemd-boot-timeout").map(|timeout| {
+                timeout.parse::<u32>().unwrap_or_else(|_| {
+                    eprintln!("distinst: the systemd-boot timeout must be a number of seconds");
+                    exit(1);
+                })
+            }),
+            default:      matches.value_of("systemd-boot-default").map(String::from),
+            console_mode: matches.value_of("systemd-boot-console-mode").map(String::from),
+            editor:       matches.value_of("systemd-boot-editor").map(|editor| editor == "yes"),
+        };
+        let grub = GrubOptions {
+            distributor:    matches.value_of("grub-distributor").map(String::from),
+            theme_dir:      matches.value_of("grub-theme").map(PathBuf::from),
+            background:     matches.value_of("grub-background").map(PathBuf::from),
+            hidden_timeout: matches.value_of("grub-hidden-timeout").map(|timeout| {
+                timeout.parse::<u32>().unwrap_or_else(|_| {
+                    eprintln!("distinst: the GRUB hidden timeout must be a number of seconds");
+                    exit(1);
+                })
+            }),
+            terminal:       matches.value_of("grub-terminal").map(String::from),
+        };
+        let recovery = matches.value_of("recovery").map(|partition| RecoveryConfig {
+            partition: PathBuf::from(partition),
+            source:    RecoverySource::from_path(
+                matches.value_of("recovery-source").unwrap_or("/cdrom/casper").into(),
+            ),
+        });
+        let mode =
+            if matches.is_present("refresh") { InstallMode::Refresh } else { InstallMode::Install };
+        let preserve = matches.values_of("preserve").map(|p| p.map(PathBuf::from).collect());
 
-        if matches.is_present("test") {
-            PARTITIONING_TEST.store(true, Ordering::Relaxed);
+        // The arguments which were given override the configuration of the recipe.
+        let mut config = match recipe.as_ref().map(RecipeFile::config) {
+            Some(Ok(config)) => config,
+            Some(Err(why)) => {
+                eprintln!("distinst: {}", why);
+                exit(1);
+            }
+            None => Config::default(),
+        };
+
+        let given = |names: &[&str]| names.iter().any(|name| matches.occurrences_of(name) != 0);
+        macro_rules! override_given {
+            ($($field:ident: $($name:expr),+ => $value:expr;)+) => {
+                $(if given(&[$($name),+]) { config.$field = $value; })+
+            };
+        }
+
+        config.flags |= install_flags(&matches);
+
+        // The options may be given without the layout of the keyboard.
+        if given(&["keyboard"]) {
+            config.keyboard = keyboard;
+        } else if given(&["keyboard-options"]) {
+            config.keyboard.options = keyboard.options;
+        }
+
+        override_given! {
+            hostname: "hostname" => hostname.into();
+            old_root: "refresh" => matches.value_of("refresh").map(String::from);
+            lang: "lang" => lang.into();
+            additional_locales: "additional-locale" => additional_locales;
+            remove: "remove" => remove.into();
+            squashfs: "squashfs" => squashfs.into();
+            layers: "layer" => layers;
+            squashfs_checksum:
+                "squashfs-checksum" => matches.value_of("squashfs-checksum").map(String::from);
+            layer_checksums: "layer-checksum" => layer_checksums;
+            timezone: "timezone" => matches.value_of("timezone").map(String::from);
+            users: "username" => user_account.into_iter().collect();
+            hosts_append: "hosts-append" => hosts_append;
+            local_packages:
+                "local-packages" => matches.value_of("local-packages").map(PathBuf::from);
+            language_packages: "language-package" => language_packages;
+            allow_network: "allow-network" => matches.is_present("allow-network");
+            fstab_id: "fstab-id" => fstab_id;
+            hibernation: "hibernation" => matches.is_present("hibernation");
+            swapfile: "swapfile" => swapfile;
+            zram: "zram" => zram;
+            bootloader: "bootloader" => bootloader;
+            efi_label: "efi-label" => matches.value_of("efi-label").map(String::from);
+            boot_order: "boot-order" => boot_order;
+            os_prober: "no-os-prober" => !matches.is_present("no-os-prober");
+            systemd_boot:
+                "systemd-boot-timeout",
+                "systemd-boot-default",
+                "systemd-boot-console-mode",
+                "systemd-boot-editor" => systemd_boot;
+            grub:
+                "grub-distributor",
+                "grub-theme",
+                "grub-background",
+                "grub-hidden-timeout",
+                "grub-terminal" => grub;
+            kernel_cmdline: "kernel-cmdline" => kernel_cmdline;
+            oem_mode: "oem" => matches.is_present("oem");
+            recovery: "recovery" => recovery;
+            mode: "refresh" => mode;
+            preserve: "preserve" => preserve;
+            initramfs: "initramfs" => initramfs;
+            install_drivers: "install-drivers" => matches.is_present("install-drivers");
+            network: "proxy", "apt-mirror", "dns", "persist-network" => network;
+            ssh:
+                "ssh-server",
+                "authorized-keys",
+                "permit-root-login",
+                "disable-password-auth" => ssh;
+            overlay: "overlay" => matches.value_of("overlay").map(PathBuf::from);
+            firstboot_units: "firstboot-script" => firstboot_units;
+            root: "root-password-hash", "root-authorized-keys" => root;
+            snapshot_previous_root:
+                "snapshot-previous-root" => matches.is_present("snapshot-previous-root");
+            snapshot_percent: "snapshot-percent" => snapshot_percent;
+        }
+
+        for &(arg, value) in &[
+            ("squashfs", &config.squashfs),
+            ("hostname", &config.hostname),
+            ("remove", &config.remove),
+        ] {
+            if value.is_empty() {
+                eprintln!("distinst: --{} is required, as the recipe does not set it", arg);
+                exit(1);
+            }
+        }
+
+        // The kind of source is detected by the installer, but errors are caught early here.
+        // Remote sources are detected once they have been downloaded.
+        if !squashfs::is_url(&config.squashfs) {
+            if let Err(why) = squashfs::Source::detect(&config.squashfs) {
+                eprintln!("distinst: invalid source at {}: {}", config.squashfs, why);
+                exit(1);
+            }
+        }
+
+        // Probing the disks requires root as well, so the host is checked before they are
+        // probed, and again for the commands that the disks need once they are configured.
+        // Installing to a directory touches no disks, and need not be run as root.
+        let installing = matches.value_of("to-dir").is_none();
+        if installing {
+            exit_on_problems(preflight(&Disks::default(), &config));
         }
 
+        // Nothing is partitioned when installing to a directory.
+        let disks = match (matches.value_of("to-dir"), recipe.as_ref()) {
+            (Some(_), _) => Ok(Disks::default()),
+            (None, _) if matches.is_present("erase-and-install") => {
+                erase_and_install(matches.value_of("erase-and-install").unwrap(), &matches)
+            }
+            (None, Some(recipe)) if !matches.is_present("disk") => {
+                configure_recipe_disks(recipe, &matches)
+            }
+            (None, _) => configure_disks(&matches),
+        };
+
+        let disks = match disks {
+            Ok(disks) => disks,
+            Err(why) => {
+                eprintln!("distinst: {}", why);
+                exit(1);
+            }
+        };
+
+        if installing {
+            exit_on_problems(preflight(&disks, &config));
+        }
+
+        configure_signal_handling();
+
         if matches.is_present("force-bios") {
             FORCE_BOOTLOADER.store(1, Ordering::Relaxed);
         } else if matches.is_present("force-efi") {
             FORCE_BOOTLOADER.store(2, Ordering::Relaxed);
         }
 
-        if matches.is_present("no-efi-vars") {
-            NO_EFI_VARIABLES.store(true, Ordering::Relaxed);
+        if matches.is_present("test") {
+            println!("distinst: detected {:?} boot mode", distinst::bootloader::detect());
+
+            // The source was detected above, unless it is remote.
+            let source = if squashfs::is_url(&config.squashfs) {
+                Ok(squashfs::Source::Remote(config.squashfs.clone()))
+            } else {
+                squashfs::Source::detect(&config.squashfs)
+            };
+
+            if let Ok(source) = source {
+                print!("{}", check_requirements(&source, &disks));
+            }
         }
 
-        fn take_optional_string(argument: Option<&str>) -> Option<String> {
-            argument.map(String::from).and_then(|x| if x.is_empty() { None } else { Some(x) })
+        if matches.is_present("no-efi-vars") {
+            NO_EFI_VARIABLES.store(true, Ordering::Relaxed);
         }
 
         // The lock is an `OwnedFd`, which on drop will close / unlock the inhibitor.
@@ -377,40 +1239,81 @@ fn main() {
             }
         };
 
-        installer.install(
-            disks,
-            &Config {
-                flags:            install_flags(&matches),
-                hostname:         hostname.into(),
-                keyboard_layout:  keyboard.next().map(String::from).unwrap(),
-                keyboard_model:   take_optional_string(keyboard.next()),
-                keyboard_variant: take_optional_string(keyboard.next()),
-                old_root:         None,
-                lang:             lang.into(),
-                remove:           remove.into(),
-                squashfs:         squashfs.into(),
-            },
-        )
+        let res = if let Some(dir) = matches.value_of("to-dir") {
+            let options = DryRunOptions {
+                verify:   true,
+                commands: matches.is_present("to-dir-commands"),
+            };
+            installer.install_to_dir(dir, &config, options)
+        } else if matches.is_present("resume") {
+            installer.resume(disks, &config)
+        } else {
+            installer.install(disks, &config)
+        };
+
+        (res, installer.install_log().cloned())
     };
 
     if let Some(mut pb) = pb_opt.borrow_mut().take() {
         pb.finish_println("");
     }
 
+    match install_log {
+        Some(InstallLog::Target(path)) => {
+            println!("the install log was kept at {} on the installed system", path.display())
+        }
+        Some(InstallLog::Live(path)) => println!("the install log was kept at {}", path.display()),
+        None => (),
+    }
+
     let status = match res {
-        Ok(()) => {
+        Ok(report) => {
+            print!("{}", report);
             println!("install was successful");
             0
         }
-        Err(err) => {
-            println!("install failed: {}", err);
-            1
-        }
+        // Each step which may fail has its own exit status, which is 10 more than its code.
+        Err(err) => match Error::from_io(&err) {
+            Some(Error::Cancelled { step }) => {
+                println!("install was cancelled during the {:?} step", step);
+                130
+            }
+            Some(error) => {
+                println!("install failed: {}", error);
+                10 + error.code() as i32
+            }
+            None => {
+                println!("install failed: {}", err);
+                1
+            }
+        },
     };
 
     exit(status);
 }
 
+/// Parses `layout[:variant[:model]]`, or the separate layout, model, and variant values which
+/// were accepted before.
+fn configure_keyboard(values: &[&str], matches: &ArgMatches) -> io::Result<KeyboardConfig> {
+    fn take_optional_string(argument: Option<&&str>) -> Option<String> {
+        argument.filter(|x| !x.is_empty()).map(|x| String::from(*x))
+    }
+
+    let mut keyboard = match values {
+        [spec] => spec.parse::<KeyboardConfig>()?,
+        _ => KeyboardConfig {
+            model: take_optional_string(values.get(1)),
+            variant: take_optional_string(values.get(2)),
+            ..KeyboardConfig::new(values[0])
+        },
+    };
+
+    keyboard.options =
+        matches.value_of("keyboard-options").filter(|x| !x.is_empty()).map(String::from);
+    keyboard.validate()?;
+    Ok(keyboard)
+}
+
 fn install_flags(matches: &ArgMatches) -> u8 {
     let mut flags = 0;
 
@@ -429,20 +1332,63 @@ fn install_flags(matches: &ArgMatches) -> u8 {
         0
     };
 
+    flags += if matches.occurrences_of("delete-extraneous") != 0 {
+        distinst::DELETE_EXTRANEOUS
+    } else {
+        0
+    };
+
+    flags +=
+        if matches.occurrences_of("keep-identity") != 0 { distinst::KEEP_IDENTITY } else { 0 };
+
+    flags +=
+        if matches.occurrences_of("force-fallback") != 0 { distinst::FORCE_FALLBACK } else { 0 };
+
+    flags +=
+        if matches.occurrences_of("windows-second") != 0 { distinst::WINDOWS_SECOND } else { 0 };
+
     flags
 }
 
+/// Formats the time remaining of a step as `m:ss`, or `h:mm:ss`.
+fn duration(seconds: u64) -> String {
+    let (hours, minutes, seconds) = (seconds / 3600, seconds / 60 % 60, seconds % 60);
+    if hours == 0 {
+        format!("{}:{:02}", minutes, seconds)
+    } else {
+        format!("{}:{:02}:{:02}", hours, minutes, seconds)
+    }
+}
+
+/// Prints each problem which stops the install on its own line, and exits if there are any.
+fn exit_on_problems(problems: Vec<PreflightProblem>) {
+    if problems.is_empty() {
+        return;
+    }
+
+    for problem in problems {
+        eprintln!("distinst: {}", problem);
+    }
+
+    exit(1);
+}
+
 fn configure_signal_handling() {
+    // A signal handler only has statics to reach, so the CLI stops its single install with
+    // the switch that every install follows.
+    #[allow(deprecated)]
     extern "C" fn handler(signal: i32) {
         match signal {
-            libc::SIGINT => KILL_SWITCH.store(true, Ordering::SeqCst),
+            libc::SIGINT | libc::SIGTERM => KILL_SWITCH.store(true, Ordering::SeqCst),
             _ => unreachable!(),
         }
     }
 
-    if unsafe { libc::signal(libc::SIGINT, handler as libc::sighandler_t) == libc::SIG_ERR } {
-        eprintln!("distinst: signal handling error: {}", io::Error::last_os_error());
-        exit(1);
+    for &signal in &[libc::SIGINT, libc::SIGTERM] {
+        if unsafe { libc::signal(signal, handler as libc::sighandler_t) == libc::SIG_ERR } {
+            eprintln!("distinst: signal handling error: {}", io::Error::last_os_error());
+            exit(1);
+        }
     }
 }
 
@@ -479,34 +1425,84 @@ fn parse_key(
     Ok(())
 }
 
+/// Parses an option for the crypttab entry, returning `false` if the field is not an option.
+fn parse_crypttab_option(
+    field: &str,
+    options: &mut CrypttabOptions,
+) -> Result<bool, DistinstError> {
+    if field == "discard" {
+        options.discard = Some(true);
+    } else if field == "nodiscard" {
+        options.discard = Some(false);
+    } else if field == "tpm2" {
+        options.tpm2 = true;
+    } else if field.starts_with("tries=") {
+        let tries = &field[6..];
+        options.tries =
+            Some(tries.parse::<u32>().map_err(|_| DistinstError::ArgNaN { arg: tries.into() })?);
+    } else if field.starts_with("keyscript=") {
+        let keyscript = &field[10..];
+        if keyscript.is_empty() {
+            return Err(DistinstError::EmptyKeyValue);
+        }
+
+        options.keyscript = Some(PathBuf::from(keyscript));
+    } else {
+        return Ok(false);
+    }
+
+    Ok(true)
+}
+
 fn parse_fs(fs: &str) -> Result<PartType, DistinstError> {
     if fs.starts_with("enc=") {
-        let (mut pass, mut keydata) = (None, None);
+        let (mut pass, mut keydata, mut header) = (None, None, None);
+        let mut crypttab = CrypttabOptions::default();
 
         let mut fields = fs[4..].split(',');
         let physical_volume =
             fields.next().map(|pv| pv.into()).ok_or(DistinstError::NoPhysicalVolume)?;
 
-        let volume_group = fields.next().map(|vg| vg.into()).ok_or(DistinstError::NoVolumeGroup)?;
+        let volume_group: String =
+            fields.next().map(|vg| vg.into()).ok_or(DistinstError::NoVolumeGroup)?;
 
         for field in fields {
-            parse_key(field, &mut pass, &mut keydata)?;
+            if field.starts_with("header=") {
+                let path = &field[7..];
+                if path.is_empty() {
+                    return Err(DistinstError::EmptyKeyValue);
+                }
+
+                header = Some(PathBuf::from(path));
+            } else if !parse_crypttab_option(field, &mut crypttab)? {
+                parse_key(field, &mut pass, &mut keydata)?;
+            }
         }
 
-        Ok(PartType::Lvm(
-            volume_group,
-            if pass.is_none() && keydata.is_none() {
-                None
-            } else {
-                Some(LvmEncryption::new(physical_volume, pass, keydata))
-            },
-        ))
+        if crypttab.tpm2 && pass.is_none() && keydata.is_none() {
+            return Err(DistinstError::EmptyPassword);
+        }
+
+        let encryption = if pass.is_none() && keydata.is_none() {
+            None
+        } else {
+            let mut encryption = LvmEncryption::new(physical_volume, pass, keydata);
+            encryption.crypttab = crypttab;
+            if let Some(header) = header {
+                encryption = encryption.detached_header(header);
+            }
+            Some(encryption)
+        };
+
+        validate_volume_group(&volume_group, encryption.as_ref())?;
+        Ok(PartType::Lvm(volume_group, encryption))
     } else if fs.starts_with("lvm=") {
         let mut fields = fs[4..].split(',');
-        Ok(PartType::Lvm(
-            fields.next().map(|vg| vg.into()).ok_or(DistinstError::NoVolumeGroup)?,
-            None,
-        ))
+        let volume_group: String =
+            fields.next().map(|vg| vg.into()).ok_or(DistinstError::NoVolumeGroup)?;
+
+        validate_volume_group(&volume_group, None)?;
+        Ok(PartType::Lvm(volume_group, None))
     } else {
         Ok(PartType::Fs(fs.parse::<FileSystem>().ok()))
     }
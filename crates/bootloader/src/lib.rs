@@ -10,6 +10,7 @@
 //! ```
 
 use std::{
+    fs,
     path::Path,
     sync::atomic::{AtomicUsize, Ordering},
 };
@@ -26,21 +27,83 @@ pub enum Bootloader {
 
 impl Bootloader {
     /// Detects whether the system is running from EFI.
-    pub fn detect() -> Bootloader {
-        match FORCE_BOOTLOADER.load(Ordering::SeqCst) {
-            1 => {
-                return Bootloader::Bios;
-            }
-            2 => {
-                return Bootloader::Efi;
-            }
-            _ => (),
+    pub fn detect() -> Bootloader { detect().bootloader() }
+}
+
+/// The firmware which the live system was booted from.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum BootMode {
+    Bios,
+    /// EFI firmware of the same width as the CPU.
+    Efi,
+    /// 32-bit EFI firmware on a 64-bit CPU, which can only boot 32-bit EFI loaders.
+    Ia32Efi,
+}
+
+impl BootMode {
+    /// The bootloader which is installed for this firmware.
+    pub fn bootloader(self) -> Bootloader {
+        match self {
+            BootMode::Bios => Bootloader::Bios,
+            BootMode::Efi | BootMode::Ia32Efi => Bootloader::Efi,
         }
+    }
+
+    fn from_firmware(efi: bool, platform_size: Option<&str>, cpu_64bit: bool) -> BootMode {
+        match (efi, platform_size.map(str::trim)) {
+            (false, _) => BootMode::Bios,
+            (true, Some("32")) if cpu_64bit => BootMode::Ia32Efi,
+            (true, _) => BootMode::Efi,
+        }
+    }
+}
 
-        if Path::new("/sys/firmware/efi").is_dir() {
-            Bootloader::Efi
-        } else {
-            Bootloader::Bios
+/// Detects the firmware which the system was booted from, unless it was forced with
+/// `FORCE_BOOTLOADER`.
+pub fn detect() -> BootMode {
+    match FORCE_BOOTLOADER.load(Ordering::SeqCst) {
+        1 => {
+            return BootMode::Bios;
+        }
+        2 => {
+            return BootMode::Efi;
         }
+        _ => (),
+    }
+
+    let platform_size = fs::read_to_string("/sys/firmware/efi/fw_platform_size").ok();
+    BootMode::from_firmware(
+        Path::new("/sys/firmware/efi").is_dir(),
+        platform_size.as_deref(),
+        cpu_is_64bit(),
+    )
+}
+
+/// x86 CPUs which support long mode list the `lm` flag.
+fn cpu_is_64bit() -> bool {
+    if cfg!(target_pointer_width = "64") {
+        return true;
+    }
+
+    fs::read_to_string("/proc/cpuinfo").ok().map_or(false, |cpuinfo| {
+        cpuinfo
+            .lines()
+            .filter(|line| line.starts_with("flags"))
+            .any(|line| line.split_whitespace().any(|flag| flag == "lm"))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn boot_modes() {
+        assert_eq!(BootMode::from_firmware(false, None, true), BootMode::Bios);
+        assert_eq!(BootMode::from_firmware(true, Some("64\n"), true), BootMode::Efi);
+        assert_eq!(BootMode::from_firmware(true, Some("32\n"), true), BootMode::Ia32Efi);
+        assert_eq!(BootMode::from_firmware(true, Some("32\n"), false), BootMode::Efi);
+        // Kernels before 4.0 don't list the size of the platform.
+        assert_eq!(BootMode::from_firmware(true, None, true), BootMode::Efi);
     }
 }
@@ -1,45 +1,146 @@
 use std::{
     ffi::OsStr,
+    fs::{self, Permissions},
     io::Result,
+    os::unix::fs::PermissionsExt,
     path::{Path, PathBuf},
     process::Stdio,
+    sync::atomic::AtomicBool,
 };
 use sys_mount::*;
 use crate::command::Command;
 
+/// The paths which are bind mounted into a chroot, in the order that they are mounted.
+const BINDS: &[(&str, &str)] = &[
+    ("/dev", "dev"),
+    ("/dev/pts", "dev/pts"),
+    ("/proc", "proc"),
+    ("/run", "run"),
+    ("/sys", "sys"),
+];
+
+/// Denies every request of a package to start or restart a service within the chroot.
+const POLICY_RC_D: &str = "#!/bin/sh\nexit 101\n";
+
+/// A file within the chroot which was temporarily replaced, and the backup of the original.
+#[derive(Debug)]
+struct Replaced {
+    path:   PathBuf,
+    backup: Option<PathBuf>,
+}
+
+impl Replaced {
+    /// Moves the original file aside, if there is one, and writes the replacement.
+    ///
+    /// The original is moved back if the replacement can't be written.
+    fn new(path: PathBuf, contents: &[u8], mode: u32) -> Result<Self> {
+        let mut backup = path.clone().into_os_string();
+        backup.push(".distinst-bak");
+        let backup = PathBuf::from(backup);
+
+        // A backup which an install that was killed left behind holds the original file.
+        if backup.symlink_metadata().is_ok() {
+            warn!("restoring {} from a previous install", path.display());
+            fs::rename(&backup, &path)?;
+        }
+
+        let backup = if path.symlink_metadata().is_ok() {
+            fs::rename(&path, &backup)?;
+            Some(backup)
+        } else {
+            None
+        };
+
+        let replaced = Replaced { path, backup };
+        let written = fs::write(&replaced.path, contents)
+            .and_then(|_| fs::set_permissions(&replaced.path, Permissions::from_mode(mode)));
+
+        if let Err(why) = written {
+            if let Err(why) = replaced.restore() {
+                warn!("failed to restore {}: {}", replaced.path.display(), why);
+            }
+
+            return Err(why);
+        }
+
+        Ok(replaced)
+    }
+
+    /// Removes the replacement, and moves the original file back.
+    fn restore(&self) -> Result<()> {
+        if self.path.symlink_metadata().is_ok() {
+            fs::remove_file(&self.path)?;
+        }
+
+        match self.backup {
+            Some(ref backup) => fs::rename(backup, &self.path),
+            None => Ok(()),
+        }
+    }
+}
+
 /// Defines the location where a `chroot` will be performed, as well as storing
 /// handles to all of the binding mounts that the chroot requires.
+///
+/// Files which were replaced for the duration of the chroot are restored when it is dropped.
 pub struct Chroot<'a> {
     pub path:   PathBuf,
-    dev_mount:  Mount,
-    pts_mount:  Mount,
-    proc_mount: Mount,
-    run_mount:  Mount,
-    sys_mount:  Mount,
+    /// The binding mounts, in the order that they were mounted.
+    mounts:     Vec<Mount>,
+    /// The replaced files, in the order that they were replaced.
+    replaced:   Vec<Replaced>,
+    /// Commands are run as root within a user namespace, rather than by root.
+    userns:     bool,
     clear_envs: bool,
     envs:       Vec<(&'a str, &'a str)>,
+    cancel:     Option<&'a AtomicBool>,
 }
 
 impl<'a> Chroot<'a> {
     /// Performs binding mounts of all required paths to ensure that a chroot
     /// is successful.
+    ///
+    /// If any of the mounts fail, those which were already mounted are unmounted.
     pub fn new<P: AsRef<Path>>(path: P) -> Result<Self> {
         let path = path.as_ref().canonicalize()?;
-        let dev_mount = Mount::new("/dev", &path.join("dev"), "none", MountFlags::BIND, None)?;
-        let pts_mount =
-            Mount::new("/dev/pts", &path.join("dev").join("pts"), "none", MountFlags::BIND, None)?;
-        let proc_mount = Mount::new("/proc", &path.join("proc"), "none", MountFlags::BIND, None)?;
-        let run_mount = Mount::new("/run", &path.join("run"), "none", MountFlags::BIND, None)?;
-        let sys_mount = Mount::new("/sys", &path.join("sys"), "none", MountFlags::BIND, None)?;
+        let mut mounts = Vec::with_capacity(BINDS.len());
+        for &(source, target) in BINDS {
+            match Mount::new(source, &path.join(target), "none", MountFlags::BIND, None) {
+                Ok(mount) => mounts.push(mount),
+                Err(why) => {
+                    for mount in mounts.iter().rev() {
+                        let _ = mount.unmount(UnmountFlags::DETACH);
+                    }
+
+                    return Err(why);
+                }
+            }
+        }
+
         Ok(Chroot {
             path,
-            dev_mount,
-            pts_mount,
-            proc_mount,
-            run_mount,
-            sys_mount,
+            mounts,
+            replaced: Vec::new(),
+            userns: false,
             clear_envs: false,
             envs: Vec::new(),
+            cancel: None,
+        })
+    }
+
+    /// A chroot which may be used without root, as nothing is mounted within it.
+    ///
+    /// Commands are run as the root of a new user namespace, which maps to the user that
+    /// runs them, and so may only modify the files that user may modify.
+    pub fn unprivileged<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Ok(Chroot {
+            path:       path.as_ref().canonicalize()?,
+            mounts:     Vec::new(),
+            replaced:   Vec::new(),
+            userns:     true,
+            clear_envs: false,
+            envs:       Vec::new(),
+            cancel:     None,
         })
     }
 
@@ -49,21 +150,74 @@ impl<'a> Chroot<'a> {
     /// Clear all environment variables for this chroot.
     pub fn clear_envs(&mut self, clear: bool) { self.clear_envs = clear; }
 
+    /// Commands will no longer be run in this chroot once `cancel` is set.
+    pub fn cancel_on(&mut self, cancel: &'a AtomicBool) { self.cancel = Some(cancel); }
+
+    /// Replaces a file within the chroot until it is restored, or the chroot is dropped.
+    ///
+    /// The `path` is relative to the root of the chroot.
+    pub fn replace_file<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+        contents: &[u8],
+        mode: u32,
+    ) -> Result<()> {
+        let path = self.path.join(path.as_ref().strip_prefix("/").unwrap_or(path.as_ref()));
+        if let Some(pos) = self.replaced.iter().position(|replaced| replaced.path == path) {
+            self.replaced.remove(pos).restore()?;
+        }
+
+        info!("temporarily replacing {}", path.display());
+        self.replaced.push(Replaced::new(path, contents, mode)?);
+        Ok(())
+    }
+
+    /// Restores a file within the chroot which was replaced by `replace_file`.
+    pub fn restore_file<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        let path = self.path.join(path.as_ref().strip_prefix("/").unwrap_or(path.as_ref()));
+        match self.replaced.iter().position(|replaced| replaced.path == path) {
+            Some(pos) => {
+                info!("restoring {}", path.display());
+                self.replaced.remove(pos).restore()
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Prevents packages from starting services within the chroot, with a `policy-rc.d`.
+    pub fn block_services(&mut self) -> Result<()> {
+        self.replace_file("usr/sbin/policy-rc.d", POLICY_RC_D.as_bytes(), 0o755)
+    }
+
+    /// Resolves host names within the chroot with the `resolv.conf` of the host, for
+    /// commands which need network access before the target has its own resolver.
+    pub fn host_resolv_conf(&mut self) -> Result<()> {
+        let resolv_conf = fs::read("/etc/resolv.conf")?;
+        self.replace_file("etc/resolv.conf", &resolv_conf, 0o644)
+    }
+
     /// Executes an external command with `chroot`.
     pub fn command<S: AsRef<OsStr>, T: AsRef<OsStr>, I: IntoIterator<Item = T>>(
         &self,
         cmd: S,
         args: I,
     ) -> Command {
-        let mut command = cascade! {
-            Command::new("chroot");
-            ..arg(&self.path);
-            ..arg(cmd.as_ref());
-            ..args(args);
-            ..stderr(Stdio::piped());
-            ..stdout(Stdio::piped());
+        let mut command = if self.userns {
+            cascade! {
+                Command::new("unshare");
+                ..args(&["--user", "--map-root-user", "chroot"]);
+            }
+        } else {
+            Command::new("chroot")
         };
 
+        command
+            .arg(&self.path)
+            .arg(cmd.as_ref())
+            .args(args)
+            .stderr(Stdio::piped())
+            .stdout(Stdio::piped());
+
         if self.clear_envs {
             command.env_clear();
         }
@@ -72,6 +226,10 @@ impl<'a> Chroot<'a> {
             command.env(key, value);
         }
 
+        if let Some(cancel) = self.cancel {
+            command.cancel_on(cancel);
+        }
+
         command
     }
 
@@ -79,22 +237,63 @@ impl<'a> Chroot<'a> {
     /// unmounted
     pub fn unmount(&mut self, lazy: bool) -> Result<()> {
         let flags = if lazy { UnmountFlags::DETACH } else { UnmountFlags::empty() };
-        self.sys_mount.unmount(flags)?;
-        self.run_mount.unmount(flags)?;
-        self.proc_mount.unmount(flags)?;
-        self.pts_mount.unmount(flags)?;
-        self.dev_mount.unmount(flags)?;
+        for mount in self.mounts.iter().rev() {
+            mount.unmount(flags)?;
+        }
         Ok(())
     }
 }
 
 impl<'a> Drop for Chroot<'a> {
     fn drop(&mut self) {
-        // Ensure unmounting
-        let _ = self.sys_mount.unmount(UnmountFlags::DETACH);
-        let _ = self.run_mount.unmount(UnmountFlags::DETACH);
-        let _ = self.proc_mount.unmount(UnmountFlags::DETACH);
-        let _ = self.pts_mount.unmount(UnmountFlags::DETACH);
-        let _ = self.dev_mount.unmount(UnmountFlags::DETACH);
+        for replaced in self.replaced.drain(..).rev() {
+            if let Err(why) = replaced.restore() {
+                warn!("failed to restore {}: {}", replaced.path.display(), why);
+            }
+        }
+
+        // Ensure unmounting, in the reverse order of mounting.
+        for mount in self.mounts.iter().rev() {
+            let _ = mount.unmount(UnmountFlags::DETACH);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, process};
+
+    #[test]
+    fn replaced_files() {
+        let dir = env::temp_dir().join(format!("distinst-chroot-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let existing = dir.join("policy-rc.d");
+        fs::write(&existing, "original").unwrap();
+
+        let replaced = Replaced::new(existing.clone(), POLICY_RC_D.as_bytes(), 0o755).unwrap();
+        assert_eq!(fs::read_to_string(&existing).unwrap(), POLICY_RC_D);
+        assert_eq!(fs::metadata(&existing).unwrap().permissions().mode() & 0o777, 0o755);
+        replaced.restore().unwrap();
+        assert_eq!(fs::read_to_string(&existing).unwrap(), "original");
+
+        let missing = dir.join("resolv.conf");
+        let replaced = Replaced::new(missing.clone(), b"nameserver 127.0.0.53\n", 0o644).unwrap();
+        assert!(missing.exists());
+        replaced.restore().unwrap();
+        assert!(!missing.exists());
+
+        // The original is restored from the backup of an install that was killed, rather
+        // than the backup being replaced by what it was replaced with.
+        let replaced = Replaced::new(existing.clone(), POLICY_RC_D.as_bytes(), 0o755).unwrap();
+        let stale = replaced.backup.clone().unwrap();
+        let replaced = Replaced::new(existing.clone(), POLICY_RC_D.as_bytes(), 0o755).unwrap();
+        assert_eq!(fs::read_to_string(&stale).unwrap(), "original");
+        replaced.restore().unwrap();
+        assert_eq!(fs::read_to_string(&existing).unwrap(), "original");
+        assert!(!stale.exists());
+
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        fs::remove_dir_all(&dir).unwrap();
     }
 }
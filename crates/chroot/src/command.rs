@@ -1,18 +1,31 @@
 use std::{
     ffi::OsStr,
-    io::{self, BufRead, BufReader, Error, ErrorKind, Write},
-    process::{self, Child, ExitStatus, Stdio},
+    io::{self, BufRead, BufReader, Error, ErrorKind, Read, Write},
+    process::{self, Child, ExitStatus, Output, Stdio},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::RecvTimeoutError,
+    },
+    thread,
+    time::{Duration, Instant},
 };
 
 /// Convenient wrapper around `process::Command` to make it easier to work with.
 pub struct Command<'a> {
-    cmd:   process::Command,
-    stdin: Option<&'a str>,
+    cmd:     process::Command,
+    stdin:   Option<&'a str>,
+    cancel:  Option<&'a AtomicBool>,
+    timeout: Option<Duration>,
 }
 
 impl<'a> Command<'a> {
     pub fn new<S: AsRef<OsStr>>(program: S) -> Self {
-        Command { cmd: process::Command::new(program), stdin: None }
+        Command {
+            cmd:     process::Command::new(program),
+            stdin:   None,
+            cancel:  None,
+            timeout: None,
+        }
     }
 
     pub fn arg<S: AsRef<OsStr>>(&mut self, arg: S) -> &mut Command<'a> {
@@ -28,7 +41,10 @@ impl<'a> Command<'a> {
         self
     }
 
-    pub fn env(&mut self, key: &str, value: &str) { self.cmd.env(key, value); }
+    pub fn env<K: AsRef<OsStr>, V: AsRef<OsStr>>(&mut self, key: K, value: V) -> &mut Self {
+        self.cmd.env(key, value);
+        self
+    }
 
     pub fn env_clear(&mut self) { self.cmd.env_clear(); }
 
@@ -54,6 +70,20 @@ impl<'a> Command<'a> {
         self
     }
 
+    /// The program will not be run once `cancel` is set, and an `Interrupted` error is
+    /// returned instead.
+    pub fn cancel_on(&mut self, cancel: &'a AtomicBool) -> &mut Self {
+        self.cancel = Some(cancel);
+        self
+    }
+
+    /// The program is killed if it is still running once `timeout` has passed, and a
+    /// `TimedOut` error is returned instead.
+    pub fn timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
     pub fn stdin_redirect(&mut self, child: &mut Child) -> io::Result<()> {
         match self.stdin {
             Some(input) => child.stdin.as_mut().unwrap().write_all(input.as_bytes()),
@@ -63,12 +93,17 @@ impl<'a> Command<'a> {
 
     pub fn run_with_stdout(&mut self) -> io::Result<String> {
         let cmd = format!("{:?}", self.cmd);
-        info!("running {}", cmd);
+        self.check_cancelled(&cmd)?;
+        debug!("running {}", cmd);
 
         self.cmd.stdout(Stdio::piped());
 
         let mut child = self.cmd.spawn().map_err(|why| {
-            Error::new(why.kind(), format!("failed to spawn process {}: {}", cmd, why))
+            misc::command_error(
+                why.kind(),
+                &cmd,
+                format!("failed to spawn process {}: {}", cmd, why),
+            )
         })?;
 
         self.stdin_redirect(&mut child)?;
@@ -79,6 +114,7 @@ impl<'a> Command<'a> {
                 Error::new(why.kind(), format!("failed to get output of {}: {}", cmd, why))
             })
             .and_then(|output| {
+                debug!("{} exited with {}", cmd, output.status);
                 String::from_utf8(output.stdout).map_err(|why| {
                     Error::new(
                         ErrorKind::Other,
@@ -100,10 +136,15 @@ impl<'a> Command<'a> {
         E: Fn(&str),
     {
         let cmd = format!("{:?}", self.cmd);
-        info!("running {}", cmd);
+        self.check_cancelled(&cmd)?;
+        debug!("running {}", cmd);
 
         let mut child = self.cmd.spawn().map_err(|why| {
-            Error::new(why.kind(), format!("failed to spawn process {}: {}", cmd, why))
+            misc::command_error(
+                why.kind(),
+                &cmd,
+                format!("failed to spawn process {}: {}", cmd, why),
+            )
         })?;
 
         self.stdin_redirect(&mut child)?;
@@ -126,6 +167,7 @@ impl<'a> Command<'a> {
         }
 
         if let Some(stderr) = child.stderr.take() {
+            let tx = tx.clone();
             std::thread::spawn(move || {
                 let stderr = BufReader::new(stderr);
                 for line in stderr.lines().filter_map(Result::ok) {
@@ -134,14 +176,129 @@ impl<'a> Command<'a> {
             });
         }
 
-        for message in rx {
+        // The messages end once the pipes of the program are closed.
+        drop(tx);
+
+        let deadline = self.timeout.map(|timeout| Instant::now() + timeout);
+        loop {
+            let message = match deadline {
+                Some(deadline) => {
+                    match rx.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                        Ok(message) => message,
+                        Err(RecvTimeoutError::Disconnected) => break,
+                        Err(RecvTimeoutError::Timeout) => {
+                            return Err(self.timed_out(&mut child, &cmd))
+                        }
+                    }
+                }
+                None => match rx.recv() {
+                    Ok(message) => message,
+                    Err(_) => break,
+                },
+            };
+
             match message {
                 Message::Stdout(line) => info(&line),
                 Message::Stderr(line) => error(&line),
             }
         }
 
-        child.wait().and_then(|status| status_as_result(status, &cmd))
+        self.wait(&mut child, &cmd).and_then(|status| {
+            debug!("{} exited with {}", cmd, status);
+            status_as_result(status, &cmd)
+        })
+    }
+
+    /// Run the program, and capture its status and the output of `stdout` and `stderr`.
+    ///
+    /// Unlike the other methods, the status is not checked, and is left to the caller.
+    pub fn output(&mut self) -> io::Result<Output> {
+        let cmd = format!("{:?}", self.cmd);
+        self.check_cancelled(&cmd)?;
+        debug!("running {}", cmd);
+
+        self.cmd.stdout(Stdio::piped());
+        self.cmd.stderr(Stdio::piped());
+
+        let mut child = self.cmd.spawn().map_err(|why| {
+            misc::command_error(
+                why.kind(),
+                &cmd,
+                format!("failed to spawn process {}: {}", cmd, why),
+            )
+        })?;
+
+        self.stdin_redirect(&mut child)?;
+
+        fn read_all<R: Read + Send + 'static>(
+            pipe: Option<R>,
+        ) -> thread::JoinHandle<io::Result<Vec<u8>>> {
+            thread::spawn(move || {
+                let mut output = Vec::new();
+                if let Some(mut pipe) = pipe {
+                    pipe.read_to_end(&mut output)?;
+                }
+                Ok(output)
+            })
+        }
+
+        let stdout = read_all(child.stdout.take());
+        let stderr = read_all(child.stderr.take());
+        let status = self.wait(&mut child, &cmd)?;
+        debug!("{} exited with {}", cmd, status);
+
+        let join = |handle: thread::JoinHandle<io::Result<Vec<u8>>>| {
+            handle.join().unwrap_or_else(|_| {
+                Err(Error::new(ErrorKind::Other, format!("failed to get output of {}", cmd)))
+            })
+        };
+
+        Ok(Output { status, stdout: join(stdout)?, stderr: join(stderr)? })
+    }
+
+    /// Waits for the program to exit, killing it if it runs past its timeout.
+    fn wait(&self, child: &mut Child, cmd: &str) -> io::Result<ExitStatus> {
+        let deadline = match self.timeout {
+            Some(timeout) => Instant::now() + timeout,
+            None => return child.wait(),
+        };
+
+        // The program may be waiting for the rest of its input.
+        drop(child.stdin.take());
+
+        loop {
+            if let Some(status) = child.try_wait()? {
+                return Ok(status);
+            }
+
+            if Instant::now() >= deadline {
+                return Err(self.timed_out(child, cmd));
+            }
+
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    fn timed_out(&self, child: &mut Child, cmd: &str) -> io::Error {
+        let _ = child.kill();
+        let _ = child.wait();
+        let timeout = self.timeout.unwrap_or_default();
+        warn!("{} was killed, as it did not exit within {:?}", cmd, timeout);
+        misc::command_error(
+            ErrorKind::TimedOut,
+            cmd,
+            format!("{} did not exit within {:?}", cmd, timeout),
+        )
+    }
+
+    fn check_cancelled(&self, cmd: &str) -> io::Result<()> {
+        match self.cancel {
+            Some(cancel) if cancel.load(Ordering::SeqCst) => Err(Error::new(
+                ErrorKind::Interrupted,
+                format!("{} was not run, as the install was cancelled", cmd),
+            )),
+            _ => Ok(()),
+        }
     }
 }
 
@@ -149,10 +306,15 @@ fn status_as_result(status: ExitStatus, cmd: &str) -> io::Result<()> {
     if status.success() {
         Ok(())
     } else if let Some(127) = status.code() {
-        Err(io::Error::new(io::ErrorKind::NotFound, format!("command {} was not found", cmd)))
+        Err(misc::command_error(
+            io::ErrorKind::NotFound,
+            cmd,
+            format!("command {} was not found", cmd),
+        ))
     } else {
-        Err(io::Error::new(
+        Err(misc::command_error(
             io::ErrorKind::Other,
+            cmd,
             format!("command failed with exit status: {}", status),
         ))
     }
@@ -167,6 +329,38 @@ mod tests {
         assert!(Command::new("asdfasdf").run().unwrap_err().kind() == io::ErrorKind::NotFound);
     }
 
+    #[test]
+    fn command_cancelled() {
+        let cancel = AtomicBool::new(true);
+        let result = Command::new("true").cancel_on(&cancel).run();
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::Interrupted);
+    }
+
+    #[test]
+    fn command_timeout() {
+        let result = Command::new("sleep").arg("5").timeout(Duration::from_millis(50)).run();
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::TimedOut);
+
+        let result = Command::new("sleep").arg("5").timeout(Duration::from_millis(50)).output();
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::TimedOut);
+
+        assert!(Command::new("true").timeout(Duration::from_secs(5)).run().is_ok());
+    }
+
+    #[test]
+    fn command_captured_output() {
+        let output = Command::new("sh")
+            .args(&["-c", "echo $GREETING; echo error >&2; exit 3"])
+            .env("GREETING", "Hello, Command!")
+            .timeout(Duration::from_secs(5))
+            .output()
+            .unwrap();
+
+        assert_eq!(output.status.code(), Some(3));
+        assert_eq!(output.stdout, b"Hello, Command!\n");
+        assert_eq!(output.stderr, b"error\n");
+    }
+
     #[test]
     fn command_with_output() {
         assert_eq!(
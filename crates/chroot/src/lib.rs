@@ -5,6 +5,7 @@ extern crate cascade;
 #[macro_use]
 extern crate log;
 
+extern crate distinst_utils as misc;
 extern crate libc;
 extern crate sys_mount;
 
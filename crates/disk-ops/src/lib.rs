@@ -5,6 +5,7 @@ extern crate derive_new;
 extern crate disk_types;
 extern crate distinst_bootloader as bootloader;
 extern crate distinst_external_commands as external;
+extern crate distinst_utils as misc;
 extern crate libparted;
 #[macro_use]
 extern crate log;
@@ -1,10 +1,23 @@
-use super::OffsetCoordinates;
+use super::{OffsetCoordinates, MEBIBYTE};
 use std::{
     fs::OpenOptions,
     io::{self, Read, Seek, SeekFrom, Write},
     path::Path,
+    sync::{mpsc::Sender, Mutex},
 };
 
+/// Receives the bytes moved, and the bytes to move, of each partition as it is moved.
+static PROGRESS: Mutex<Option<Sender<(u64, u64)>>> = Mutex::new(None);
+
+/// Sends the progress of partition moves to the given channel, until it is unset.
+///
+/// Progress is sent after each mebibyte that is moved.
+pub fn report_move_progress(sender: Option<Sender<(u64, u64)>>) {
+    if let Ok(mut progress) = PROGRESS.lock() {
+        *progress = sender;
+    }
+}
+
 /// Performs direct reads & writes on the disk to shift a partition either to the left or right,
 /// using the supplied offset coordinates to determine where the partition is, and where it
 /// should be.
@@ -23,6 +36,9 @@ pub fn move_partition<P: AsRef<Path>>(
     );
 
     let mut disk = OpenOptions::new().read(true).write(true).open(&path)?;
+    let progress = PROGRESS.lock().ok().and_then(|progress| progress.clone());
+    let total = coords.length * bs;
+    let mut moved = 0;
 
     let source_skip = coords.skip;
     let offset_skip = (source_skip as i64 + coords.offset) as u64;
@@ -48,6 +64,13 @@ pub fn move_partition<P: AsRef<Path>>(
         let offset = offset_skip + sector;
         disk.seek(SeekFrom::Start(offset * bs))?;
         disk.write_all(&buffer[..bs as usize])?;
+
+        moved += bs;
+        if let Some(ref progress) = progress {
+            if moved % MEBIBYTE == 0 || moved == total {
+                let _ = progress.send((moved, total));
+            }
+        }
     }
 
     disk.sync_all()
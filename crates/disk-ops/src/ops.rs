@@ -2,7 +2,7 @@
 
 use super::*;
 use disk_types::{FileSystem, PartitionTable, PartitionType};
-use external::{blockdev, mkfs};
+use external::{blockdev_settled, mkfs, wipefs};
 use libparted::{Device, Disk as PedDisk, Partition as PedPartition};
 use mkpart::PartitionCreate;
 use parted::*;
@@ -229,6 +229,13 @@ impl<'a> CreatePartitions<'a> {
             if partition.kind != PartitionType::Extended {
                 // Open a second instance of the disk which we need to get the new partition ID.
                 let path = get_partition_id(self.device_path, partition.start_sector as i64)?;
+
+                // Partitions which are formatted at boot must not carry stale signatures.
+                if !partition.format {
+                    wipefs(&path)?;
+                    continue;
+                }
+
                 self.format_partitions.push((
                     path,
                     partition
@@ -238,18 +245,9 @@ impl<'a> CreatePartitions<'a> {
             }
         }
 
-        // Attempt to sync three times before returning an error.
-        for attempt in 0..3 {
-            ::std::thread::sleep(::std::time::Duration::from_secs(1));
-            let result = blockdev(self.device_path, &["--flushbufs", "--rereadpt"]);
-            if result.is_err() && attempt == 2 {
-                result.map_err(|why| {
-                    io::Error::new(why.kind(), format!("failed to synchronize disk: {}", why))
-                })?
-            } else {
-                break;
-            }
-        }
+        blockdev_settled(self.device_path, &["--flushbufs", "--rereadpt"]).map_err(|why| {
+            io::Error::new(why.kind(), format!("failed to synchronize disk: {}", why))
+        })?;
 
         Ok(FormatPartitions(self.format_partitions))
     }
@@ -295,10 +293,9 @@ impl FormatPartitions {
             .map(|&(ref part, fs)| {
                 info!("formatting {} with {:?}", part.display(), fs);
                 mkfs(part, fs).map_err(|why| {
-                    io::Error::new(
-                        why.kind(),
-                        format!("failed to format {} with {}: {}", part.display(), fs, why),
-                    )
+                    let message =
+                        format!("failed to format {} with {}: {}", part.display(), fs, why);
+                    misc::device_error(why, part, message)
                 })
             })
             .collect::<io::Result<()>>()
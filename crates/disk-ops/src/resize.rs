@@ -1,7 +1,7 @@
 use self::FileSystem::*;
 use super::{move_partition, BlockCoordinates, OffsetCoordinates, MEBIBYTE, MEGABYTE};
 use disk_types::{FileSystem, PartitionType};
-use external::{blockdev, fsck};
+use external::{blockdev_settled, fsck};
 use libparted::PartitionFlag;
 use std::{
     io::{self, Write},
@@ -113,16 +113,7 @@ pub fn resize_partition<P: AsRef<Path>>(
         resize_cmd.args(args);
     }
 
-    // Attempt to sync three times before returning an error.
-    for attempt in 0..3 {
-        ::std::thread::sleep(::std::time::Duration::from_secs(1));
-        let result = blockdev(&path, &["--flushbufs"]);
-        if result.is_err() && attempt == 2 {
-            result?;
-        } else {
-            break;
-        }
-    }
+    blockdev_settled(&path, &["--flushbufs"])?;
 
     let fsck_options = if options & BTRFS != 0 {
         Some(("btrfsck", "--repair"))
@@ -239,58 +230,9 @@ where
     );
 
     // Create the command and its arguments based on the file system to apply.
-    // TODO: Handle the unimplemented file systems.
-    let (cmd, args, unit, opts): (&str, &[&'static str], ResizeUnit, u8) = match change.filesystem {
-        Some(Btrfs) => (
-            "btrfs",
-            &["filesystem", "resize"],
-            ResizeUnit::AbsoluteMebibyte,
-            BTRFS | SIZE_BEFORE_PATH,
-        ),
-        Some(Ext2) | Some(Ext3) | Some(Ext4) => {
-            ("resize2fs", &[], ResizeUnit::AbsoluteSectorsWithUnit, 0)
-        }
-        // Some(Exfat) => (),
-        // Some(F2fs) => ("resize.f2fs"),
-        Some(Fat16) | Some(Fat32) => {
-            ("fatresize", &["-s"], ResizeUnit::AbsoluteKibis, SIZE_BEFORE_PATH)
-        }
-        Some(Ntfs) => (
-            "ntfsresize",
-            &["--force", "--force", "-s"],
-            ResizeUnit::AbsoluteBytes,
-            SIZE_BEFORE_PATH | NTFS,
-        ),
-        Some(Swap) => unreachable!("Disk::diff() handles this"),
-        Some(Xfs) => {
-            if shrinking {
-                return Err(io::Error::new(
-                    io::ErrorKind::Other,
-                    "XFS partitions do not support shrinking",
-                ));
-            }
-
-            ("xfs_growfs", &["-d"], ResizeUnit::AbsoluteMegabyte, NO_SIZE | XFS)
-        }
-        fs => unimplemented!("{:?} handling", fs),
-    };
-
-    let fs = match change.filesystem {
-        Some(Fat16) | Some(Fat32) => "vfat",
-        Some(fs) => fs.into(),
-        None => "none",
-    };
-
-    // Each file system uses different units for specifying the size, and these
-    // units are sometimes written in non-standard and conflicting ways.
-    let size = match unit {
-        ResizeUnit::AbsoluteBytes => format!("{}", resize.absolute_sectors() * 512),
-        ResizeUnit::AbsoluteKibis => format!("{}ki", resize.absolute_sectors() / 2),
-        ResizeUnit::AbsoluteSectorsWithUnit => format!("{}s", resize.absolute_sectors()),
-        ResizeUnit::AbsoluteMebibyte => format!("{}M", resize.as_absolute_mebibyte()),
-        ResizeUnit::AbsoluteMegabyte => format!("{}M", resize.as_absolute_megabyte()),
-        ResizeUnit::AbsoluteSectors => format!("{}", resize.absolute_sectors()),
-    };
+    let (cmd, args, unit, opts) = resize_command(change.filesystem, shrinking)?;
+    let fs = mount_fs_name(change.filesystem);
+    let size = resize_size_arg(unit, &resize);
 
     // If the partition is shrinking, we will want to shrink before we move.
     // If the partition is growing and moving, we will want to move first, then
@@ -386,6 +328,88 @@ where
     Ok(())
 }
 
+/// Resizes the file system on the given device, without modifying the partition or volume
+/// that contains it.
+///
+/// The caller is responsible for ensuring that the underlying block device is large enough
+/// to contain the file system before growing, and for shrinking the block device after the
+/// file system has been shrunk.
+pub fn resize_filesystem<P: AsRef<Path>>(
+    path: P,
+    filesystem: Option<FileSystem>,
+    resize: &ResizeOperation,
+) -> io::Result<()> {
+    let (cmd, args, unit, opts) = resize_command(filesystem, resize.is_shrinking())?;
+    let size = resize_size_arg(unit, resize);
+    resize_partition(cmd, args, &size, path, mount_fs_name(filesystem), opts)
+}
+
+/// Obtains the command, arguments, size unit, and options required to resize a file system.
+// TODO: Handle the unimplemented file systems.
+fn resize_command(
+    filesystem: Option<FileSystem>,
+    shrinking: bool,
+) -> io::Result<(&'static str, &'static [&'static str], ResizeUnit, u8)> {
+    let command: (&str, &[&'static str], ResizeUnit, u8) = match filesystem {
+        Some(Btrfs) => (
+            "btrfs",
+            &["filesystem", "resize"],
+            ResizeUnit::AbsoluteMebibyte,
+            BTRFS | SIZE_BEFORE_PATH,
+        ),
+        Some(Ext2) | Some(Ext3) | Some(Ext4) => {
+            ("resize2fs", &[], ResizeUnit::AbsoluteSectorsWithUnit, 0)
+        }
+        // Some(Exfat) => (),
+        // Some(F2fs) => ("resize.f2fs"),
+        Some(Fat16) | Some(Fat32) => {
+            ("fatresize", &["-s"], ResizeUnit::AbsoluteKibis, SIZE_BEFORE_PATH)
+        }
+        Some(Ntfs) => (
+            "ntfsresize",
+            &["--force", "--force", "-s"],
+            ResizeUnit::AbsoluteBytes,
+            SIZE_BEFORE_PATH | NTFS,
+        ),
+        Some(Swap) => unreachable!("Disk::diff() handles this"),
+        Some(Xfs) => {
+            if shrinking {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "XFS partitions do not support shrinking",
+                ));
+            }
+
+            ("xfs_growfs", &["-d"], ResizeUnit::AbsoluteMegabyte, NO_SIZE | XFS)
+        }
+        fs => unimplemented!("{:?} handling", fs),
+    };
+
+    Ok(command)
+}
+
+/// The file system type to use when temporarily mounting a file system to resize it.
+fn mount_fs_name(filesystem: Option<FileSystem>) -> &'static str {
+    match filesystem {
+        Some(Fat16) | Some(Fat32) => "vfat",
+        Some(fs) => fs.into(),
+        None => "none",
+    }
+}
+
+/// Each file system uses different units for specifying the size, and these
+/// units are sometimes written in non-standard and conflicting ways.
+fn resize_size_arg(unit: ResizeUnit, resize: &ResizeOperation) -> String {
+    match unit {
+        ResizeUnit::AbsoluteBytes => format!("{}", resize.absolute_sectors() * 512),
+        ResizeUnit::AbsoluteKibis => format!("{}ki", resize.absolute_sectors() / 2),
+        ResizeUnit::AbsoluteSectorsWithUnit => format!("{}s", resize.absolute_sectors()),
+        ResizeUnit::AbsoluteMebibyte => format!("{}M", resize.as_absolute_mebibyte()),
+        ResizeUnit::AbsoluteMegabyte => format!("{}M", resize.as_absolute_megabyte()),
+        ResizeUnit::AbsoluteSectors => format!("{}", resize.absolute_sectors()),
+    }
+}
+
 fn ntfs_dry_run(path: &Path, size: &str) -> io::Result<()> {
     let mut consistency_check = Command::new("ntfsresize");
     consistency_check.args(&["-f", "-f", "--no-action", "-s"]).arg(size).arg(path);
@@ -3,6 +3,7 @@ use sys_mount::FilesystemType as MountFS;
 
 /// Describes a file system format, such as ext4 or fat32.
 #[derive(Debug, PartialEq, Copy, Clone, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum FileSystem {
     Btrfs,
     Exfat,
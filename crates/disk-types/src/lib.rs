@@ -2,6 +2,11 @@
 extern crate err_derive;
 #[macro_use]
 extern crate log;
+#[cfg(feature = "serde")]
+extern crate serde;
+#[cfg(feature = "serde")]
+#[macro_use]
+extern crate serde_derive;
 
 mod device;
 mod fs;
@@ -125,6 +125,7 @@ pub trait PartitionExt: BlockDeviceExt + SectorExt {
 ///
 /// This only applies for MBR partition tables.
 #[derive(Debug, PartialEq, Clone, Copy, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum PartitionType {
     Primary,
     Logical,
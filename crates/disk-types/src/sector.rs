@@ -68,6 +68,7 @@ pub trait SectorExt: BlockDeviceExt {
 /// Used with the `Disk::get_sector` method for converting a more human-readable unit
 /// into the corresponding sector for the given disk.
 #[derive(Debug, PartialEq, Clone, Copy, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum Sector {
     /// The first sector in the disk where partitions should be created.
     Start,
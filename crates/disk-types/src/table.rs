@@ -2,6 +2,7 @@ use crate::{device::BlockDeviceExt, partition::PartitionType};
 
 /// Specifies whether the partition table on the disk is **MSDOS** or **GPT**.
 #[derive(Debug, PartialEq, Clone, Copy, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum PartitionTable {
     Msdos,
     Gpt,
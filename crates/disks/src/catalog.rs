@@ -0,0 +1,356 @@
+//! Stable codes and message keys of errors, so that frontends may show translated messages
+//! instead of the English rendering of `Display`.
+//!
+//! Codes are never reused: a variant which is removed leaves its code unassigned, and new
+//! variants are given the next code that has not yet been assigned.
+
+use crate::DiskError;
+use misc::ErrorCause;
+
+/// An entry of a message catalog.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CatalogEntry {
+    /// The name of the variant of the error, such as `DiskGet`.
+    pub variant: &'static str,
+    /// The stable code of the error.
+    pub code:    u32,
+    /// The key that translations of the message are looked up by.
+    pub key:     &'static str,
+    /// The English message, whose `{name}` placeholders are replaced by the parameters of
+    /// the error of the same names.
+    pub english: &'static str,
+}
+
+impl CatalogEntry {
+    /// Finds the entry of the given code in a catalog.
+    pub fn find(catalog: &'static [CatalogEntry], code: u32) -> Option<&'static CatalogEntry> {
+        catalog.iter().find(|entry| entry.code == code)
+    }
+}
+
+/// Defines the catalog of `DiskError` messages and the codes of the variants from one table,
+/// so that a variant can't be added without its entry.
+macro_rules! catalog {
+    ($($variant:ident => $code:expr, $key:expr, $english:expr;)+) => {
+        /// The catalog of `DiskError` messages.
+        pub const DISK_ERRORS: &[CatalogEntry] = &[$(CatalogEntry {
+            variant: stringify!($variant),
+            code:    $code,
+            key:     $key,
+            english: $english,
+        },)+];
+
+        impl DiskError {
+            /// The stable code of the error, which is not shared with any other error.
+            pub fn code(&self) -> u32 {
+                match *self {
+                    $(DiskError::$variant { .. } => $code,)+
+                }
+            }
+        }
+    };
+}
+
+catalog! {
+    CacheDevices => 101, "disk.cache_devices", "cached volume group '{vg}' must span rotational and solid-state devices";
+    CacheTooSmall => 102, "disk.cache_too_small", "cache pool for logical volume '{volume}' is too small";
+    Decryption => 103, "disk.decryption", "decryption error: {why}";
+    BootloaderDiskNotFound => 104, "disk.bootloader_disk_not_found", "the bootloader disk {device} is not one of the configured disks";
+    DeviceGet => 105, "disk.device_get", "unable to get device at {device}: {why}";
+    DeviceProbe => 106, "disk.device_probe", "unable to probe for devices";
+    DiskCommit => 107, "disk.disk_commit", "unable to commit changes to disk ({device}): {why}";
+    DiskFresh => 108, "disk.disk_fresh", "unable to format partition table on {device}: {why}";
+    DiskGet => 109, "disk.disk_get", "unable to find disk at {device}";
+    DiskNew => 110, "disk.disk_new", "unable to open disk at {device}: {why}";
+    DiskSync => 111, "disk.disk_sync", "unable to sync disk changes with OS: {why}";
+    Encryption => 112, "disk.encryption", "unable to encrypt volume '{volume}': {why}";
+    EncryptionOpen => 113, "disk.encryption_open", "unable to open encrypted volume '{volume}': {why}";
+    EncryptedVolumeLacksFs => 114, "disk.encrypted_volume_lacks_fs", "encrypted logical volume on {device} lacks a file system";
+    ExternalCommand => 115, "disk.external_command", "problem executing external command: {why}";
+    InvalidLogicalVolumeName => 116, "disk.invalid_logical_volume_name", "invalid logical volume name '{name}': {why}";
+    InvalidMapperName => 117, "disk.invalid_mapper_name", "invalid LUKS device map name '{name}': {why}";
+    InvalidSerial => 118, "disk.invalid_serial", "serial model does not match";
+    InvalidVolumeGroupName => 119, "disk.invalid_volume_group_name", "invalid volume group name '{name}': {why}";
+    IO => 120, "disk.io", "{why}";
+    GeometryCreate => 121, "disk.geometry_create", "failed to create partition geometry: {why}";
+    GeometryDuplicate => 122, "disk.geometry_duplicate", "failed to duplicate partition geometry";
+    GeometrySet => 123, "disk.geometry_set", "failed to set values on partition geometry";
+    HeaderOnEncryptedVolume => 124, "disk.header_on_encrypted_volume", "LUKS header of {volume} may not be stored on the volume it encrypts";
+    HeaderWithoutPartition => 125, "disk.header_without_partition", "LUKS header at {path} is not on a mounted physical partition";
+    HibernationSwapTooSmall => 126, "disk.hibernation_swap_too_small", "{swap} bytes of swap is too small to hibernate {memory} bytes of memory";
+    KeyContainsRoot => 127, "disk.key_contains_root", "the root partition may not be contained on a key-encrypted volume";
+    KeyPathAlreadySet => 128, "disk.key_path_already_set", "LUKS key path was already set for {id}";
+    KeyWithoutPath => 129, "disk.key_without_path", "LUKS keyfile designation lacks key path";
+    KeyFileWithoutPath => 130, "disk.key_file_without_path", "LUKS keyfile partition does not have a mount target";
+    LayoutChanged => 131, "disk.layout_changed", "partition layout on disk has changed";
+    LogicalVolumeCreate => 132, "disk.logical_volume_create", "unable to create logical volume: {why}";
+    LogicalPartitionNotFound => 133, "disk.logical_partition_not_found", "logical partition '{group}-{volume}' does not exist";
+    MountsObtain => 134, "disk.mounts_obtain", "unable to get mount points: {why}";
+    NewPartNotFound => 135, "disk.new_part_not_found", "new partition could not be found";
+    PartitionError => 136, "disk.partition_error", "partition error ({partition}): {why}";
+    PartitionNotFound => 137, "disk.partition_not_found", "partition {partition} not be found on disk";
+    PartitionOOB => 138, "disk.partition_oob", "partition exceeds size of disk";
+    PartitionRemove => 139, "disk.partition_remove", "unable to remove partition {partition}: {why}";
+    PartitionRemoveBySector => 140, "disk.partition_remove_by_sector", "unable to remove partition at sector {sector}: {why}";
+    PartitionTable => 141, "disk.partition_table", "{why}";
+    PhysicalVolumeCreate => 142, "disk.physical_volume_create", "unable to create physical volume from '{volume}': {why}";
+    RandomSwapEncrypted => 143, "disk.random_swap_encrypted", "swap on {device} is already encrypted, and can't use a random key";
+    RandomSwapHibernation => 144, "disk.random_swap_hibernation", "swap on {device} uses a random key, and can't be resumed from";
+    RecoveryFileSystem => 145, "disk.recovery_file_system", "recovery partition on {device} must be formatted as FAT32";
+    RecoveryNotFound => 146, "disk.recovery_not_found", "recovery partition on {device} was not found";
+    RecoveryTarget => 147, "disk.recovery_target", "recovery partition on {device} is mounted elsewhere than /recovery";
+    RecoveryTooSmall => 148, "disk.recovery_too_small", "recovery partition of {size} bytes is too small for {required} bytes";
+    RemainderClaimed => 149, "disk.remainder_claimed", "more than one logical volume on {device} claims the remaining space";
+    SameGroup => 150, "disk.same_group", "multiple devices had the same volume group: currently unsupported";
+    SectorOverlaps => 151, "disk.sector_overlaps", "sector overlaps partition {id}";
+    SerialGet => 152, "disk.serial_get", "unable to get serial model of device: {why}";
+    Unmount => 153, "disk.unmount", "unable to unmount partition(s) on {device}: {why}";
+    VolumeGroupCreate => 154, "disk.volume_group_create", "unable to create volume group '{vg}' on {device}: {why}";
+    VgNameConflict => 155, "disk.vg_name_conflict", "volume group '{vg}' already exists on a device that will be kept; try '{suggestion}'";
+    VolumePartitionLacksLabel => 156, "disk.volume_partition_lacks_label", "logical partition on {device} lacks a label";
+    WeakPassphrase => 157, "disk.weak_passphrase", "passphrase of {volume} is {strength}, but must be at least {minimum}";
+    RecoverySourceErased => 158, "disk.recovery_source_erased", "recovery source {path} is on {device}, which is erased";
+}
+
+/// Replaces the `{name}` placeholders of a message, or of a translation of it, with the
+/// parameters of an error.
+pub fn format_message(message: &str, params: &[(&'static str, String)]) -> String {
+    params.iter().fold(message.to_owned(), |message, (name, value)| {
+        message.replace(&format!("{{{}}}", name), value)
+    })
+}
+
+impl DiskError {
+    /// The key that translations of the message of the error are looked up by.
+    pub fn key(&self) -> &'static str { self.entry().key }
+
+    /// The entry of the error in `DISK_ERRORS`.
+    pub fn entry(&self) -> &'static CatalogEntry {
+        CatalogEntry::find(DISK_ERRORS, self.code()).expect("disk error without a catalog entry")
+    }
+
+    /// The code, key, and parameters of the error, which are kept by the I/O errors that it
+    /// is converted to.
+    pub fn error_cause(&self) -> ErrorCause {
+        ErrorCause { code: self.code(), key: self.key(), params: self.params() }
+    }
+
+    /// The dynamic values of the message, such as device paths, by the names of the
+    /// placeholders that they replace. Causes are given in their English rendering.
+    pub fn params(&self) -> Vec<(&'static str, String)> {
+        use self::DiskError::*;
+
+        let why = |why: &dyn std::fmt::Display| ("why", why.to_string());
+        let device = |device: &std::path::Path| ("device", device.display().to_string());
+
+        match *self {
+            CacheDevices { ref vg } => vec![("vg", vg.clone())],
+            CacheTooSmall { ref volume } => vec![("volume", volume.clone())],
+            Decryption { why: ref err } => vec![why(err)],
+            BootloaderDiskNotFound { device: ref path } => vec![device(path)],
+            DeviceGet { device: ref path, why: ref err } => vec![device(path), why(err)],
+            DeviceProbe => Vec::new(),
+            DiskCommit { device: ref path, why: ref err } => vec![device(path), why(err)],
+            DiskFresh { device: ref path, why: ref err } => vec![device(path), why(err)],
+            DiskGet { device: ref path } => vec![device(path)],
+            DiskNew { device: ref path, why: ref err } => vec![device(path), why(err)],
+            DiskSync { why: ref err } => vec![why(err)],
+            Encryption { ref volume, why: ref err } => {
+                vec![("volume", volume.display().to_string()), why(err)]
+            }
+            EncryptionOpen { ref volume, why: ref err } => {
+                vec![("volume", volume.display().to_string()), why(err)]
+            }
+            EncryptedVolumeLacksFs { device: ref path } => vec![device(path)],
+            ExternalCommand { why: ref err } => vec![why(err)],
+            InvalidLogicalVolumeName { ref name, why: ref err } => {
+                vec![("name", name.clone()), why(err)]
+            }
+            InvalidMapperName { ref name, why: ref err } => vec![("name", name.clone()), why(err)],
+            InvalidSerial => Vec::new(),
+            InvalidVolumeGroupName { ref name, why: ref err } => {
+                vec![("name", name.clone()), why(err)]
+            }
+            IO { why: ref err } => vec![why(err)],
+            GeometryCreate { why: ref err } => vec![why(err)],
+            GeometryDuplicate => Vec::new(),
+            GeometrySet => Vec::new(),
+            HeaderOnEncryptedVolume { ref volume } => vec![("volume", volume.clone())],
+            HeaderWithoutPartition { ref path } => vec![("path", path.display().to_string())],
+            HibernationSwapTooSmall { memory, swap } => {
+                vec![("memory", memory.to_string()), ("swap", swap.to_string())]
+            }
+            KeyContainsRoot => Vec::new(),
+            KeyPathAlreadySet { ref id } => vec![("id", id.clone())],
+            KeyWithoutPath => Vec::new(),
+            KeyFileWithoutPath => Vec::new(),
+            LayoutChanged => Vec::new(),
+            LogicalVolumeCreate { why: ref err } => vec![why(err)],
+            LogicalPartitionNotFound { ref group, ref volume } => {
+                vec![("group", group.clone()), ("volume", volume.clone())]
+            }
+            MountsObtain { why: ref err } => vec![why(err)],
+            NewPartNotFound => Vec::new(),
+            PartitionError { ref partition, why: ref err } => {
+                vec![("partition", partition.display().to_string()), why(err)]
+            }
+            PartitionNotFound { partition } => vec![("partition", partition.to_string())],
+            PartitionOOB => Vec::new(),
+            PartitionRemove { partition, why: ref err } => {
+                vec![("partition", partition.to_string()), why(err)]
+            }
+            PartitionRemoveBySector { sector, why: ref err } => {
+                vec![("sector", sector.to_string()), why(err)]
+            }
+            PartitionTable { why: ref err } => vec![why(err)],
+            PhysicalVolumeCreate { ref volume, why: ref err } => {
+                vec![("volume", volume.clone()), why(err)]
+            }
+            RandomSwapEncrypted { device: ref path } => vec![device(path)],
+            RandomSwapHibernation { device: ref path } => vec![device(path)],
+            RecoveryFileSystem { device: ref path } => vec![device(path)],
+            RecoveryNotFound { device: ref path } => vec![device(path)],
+            RecoverySourceErased { ref path, device: ref disk } => {
+                vec![("path", path.display().to_string()), device(disk)]
+            }
+            RecoveryTarget { device: ref path } => vec![device(path)],
+            RecoveryTooSmall { size, required } => {
+                vec![("size", size.to_string()), ("required", required.to_string())]
+            }
+            RemainderClaimed { device: ref path } => vec![device(path)],
+            SameGroup => Vec::new(),
+            SectorOverlaps { id } => vec![("id", id.to_string())],
+            SerialGet { why: ref err } => vec![why(err)],
+            Unmount { device: ref path, why: ref err } => vec![device(path), why(err)],
+            VolumeGroupCreate { device: ref path, ref vg, why: ref err } => {
+                vec![device(path), ("vg", vg.clone()), why(err)]
+            }
+            VgNameConflict { ref vg, ref suggestion } => {
+                vec![("vg", vg.clone()), ("suggestion", suggestion.clone())]
+            }
+            VolumePartitionLacksLabel { device: ref path } => vec![device(path)],
+            WeakPassphrase { ref volume, strength, minimum } => vec![
+                ("volume", volume.clone()),
+                ("strength", strength.to_string()),
+                ("minimum", minimum.to_string()),
+            ],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::StrengthScore;
+    use std::{collections::BTreeSet, io, path::PathBuf};
+
+    /// Every code that was ever assigned, with its variant and key. Entries may be appended,
+    /// but must never be changed or removed, as frontends translate messages by them.
+    const SNAPSHOT: &str = "\
+101 CacheDevices disk.cache_devices
+102 CacheTooSmall disk.cache_too_small
+103 Decryption disk.decryption
+104 BootloaderDiskNotFound disk.bootloader_disk_not_found
+105 DeviceGet disk.device_get
+106 DeviceProbe disk.device_probe
+107 DiskCommit disk.disk_commit
+108 DiskFresh disk.disk_fresh
+109 DiskGet disk.disk_get
+110 DiskNew disk.disk_new
+111 DiskSync disk.disk_sync
+112 Encryption disk.encryption
+113 EncryptionOpen disk.encryption_open
+114 EncryptedVolumeLacksFs disk.encrypted_volume_lacks_fs
+115 ExternalCommand disk.external_command
+116 InvalidLogicalVolumeName disk.invalid_logical_volume_name
+117 InvalidMapperName disk.invalid_mapper_name
+118 InvalidSerial disk.invalid_serial
+119 InvalidVolumeGroupName disk.invalid_volume_group_name
+120 IO disk.io
+121 GeometryCreate disk.geometry_create
+122 GeometryDuplicate disk.geometry_duplicate
+123 GeometrySet disk.geometry_set
+124 HeaderOnEncryptedVolume disk.header_on_encrypted_volume
+125 HeaderWithoutPartition disk.header_without_partition
+126 HibernationSwapTooSmall disk.hibernation_swap_too_small
+127 KeyContainsRoot disk.key_contains_root
+128 KeyPathAlreadySet disk.key_path_already_set
+129 KeyWithoutPath disk.key_without_path
+130 KeyFileWithoutPath disk.key_file_without_path
+131 LayoutChanged disk.layout_changed
+132 LogicalVolumeCreate disk.logical_volume_create
+133 LogicalPartitionNotFound disk.logical_partition_not_found
+134 MountsObtain disk.mounts_obtain
+135 NewPartNotFound disk.new_part_not_found
+136 PartitionError disk.partition_error
+137 PartitionNotFound disk.partition_not_found
+138 PartitionOOB disk.partition_oob
+139 PartitionRemove disk.partition_remove
+140 PartitionRemoveBySector disk.partition_remove_by_sector
+141 PartitionTable disk.partition_table
+142 PhysicalVolumeCreate disk.physical_volume_create
+143 RandomSwapEncrypted disk.random_swap_encrypted
+144 RandomSwapHibernation disk.random_swap_hibernation
+145 RecoveryFileSystem disk.recovery_file_system
+146 RecoveryNotFound disk.recovery_not_found
+147 RecoveryTarget disk.recovery_target
+148 RecoveryTooSmall disk.recovery_too_small
+149 RemainderClaimed disk.remainder_claimed
+150 SameGroup disk.same_group
+151 SectorOverlaps disk.sector_overlaps
+152 SerialGet disk.serial_get
+153 Unmount disk.unmount
+154 VolumeGroupCreate disk.volume_group_create
+155 VgNameConflict disk.vg_name_conflict
+156 VolumePartitionLacksLabel disk.volume_partition_lacks_label
+157 WeakPassphrase disk.weak_passphrase
+158 RecoverySourceErased disk.recovery_source_erased
+";
+
+    #[test]
+    fn codes_are_never_reused() {
+        let table = DISK_ERRORS
+            .iter()
+            .map(|entry| format!("{} {} {}\n", entry.code, entry.variant, entry.key))
+            .collect::<String>();
+        assert_eq!(table, SNAPSHOT);
+
+        let codes = DISK_ERRORS.iter().map(|entry| entry.code).collect::<BTreeSet<_>>();
+        let variants = DISK_ERRORS.iter().map(|entry| entry.variant).collect::<BTreeSet<_>>();
+        let keys = DISK_ERRORS.iter().map(|entry| entry.key).collect::<BTreeSet<_>>();
+        assert_eq!(codes.len(), DISK_ERRORS.len());
+        assert_eq!(variants.len(), DISK_ERRORS.len());
+        assert_eq!(keys.len(), DISK_ERRORS.len());
+    }
+
+    #[test]
+    fn params() {
+        let errors = vec![
+            DiskError::DeviceProbe,
+            DiskError::RecoveryTooSmall { size: 1024, required: 2048 },
+            DiskError::VgNameConflict { vg: "data".into(), suggestion: "data-1".into() },
+            DiskError::WeakPassphrase {
+                volume:   "cryptdata".into(),
+                strength: StrengthScore::Weak,
+                minimum:  StrengthScore::Strong,
+            },
+            DiskError::DiskSync { why: io::Error::new(io::ErrorKind::Other, "busy") },
+        ];
+
+        // Messages without paths, which are quoted by `Display`, render as they display.
+        for error in errors {
+            let entry = error.entry();
+            assert_eq!(entry.code, error.code());
+            assert!(format!("{:?}", error).starts_with(entry.variant));
+            assert_eq!(format_message(entry.english, &error.params()), error.to_string());
+        }
+
+        let error = DiskError::DiskGet { device: PathBuf::from("/dev/sda") };
+        assert_eq!(error.key(), "disk.disk_get");
+        assert_eq!(error.params(), vec![("device", "/dev/sda".to_owned())]);
+        assert_eq!(
+            format_message(error.entry().english, &error.params()),
+            "unable to find disk at /dev/sda"
+        );
+    }
+}
@@ -0,0 +1,153 @@
+use std::{fmt, fs::File, io::Read, path::Path};
+
+/// The length of the boot code of an MBR, which is followed by the disk signature and the
+/// partition table.
+pub const BOOT_CODE_LEN: usize = 440;
+
+/// The bootloader whose boot code is in the MBR of a disk, which BIOS firmware runs.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum MbrBootloader {
+    /// The `boot.img` of GRUB 2.
+    Grub2,
+    /// The `stage1` of GRUB 0.97 and older.
+    GrubLegacy,
+    Lilo,
+    /// The `mbr.bin` of Syslinux, which boots the active partition.
+    Syslinux,
+    /// The MBR of Windows Vista and later, which boots the active partition.
+    Windows,
+    /// The MBR of Windows 2000 and XP.
+    WindowsXp,
+    /// Boot code which is not recognized.
+    Unknown,
+}
+
+impl MbrBootloader {
+    /// Classifies the `code` of an MBR, which is `None` if the code is empty.
+    pub fn detect(code: &[u8]) -> Option<Self> {
+        let code = &code[..code.len().min(BOOT_CODE_LEN)];
+        if code.iter().all(|&byte| byte == 0) {
+            return None;
+        }
+
+        let contains = |needle: &[u8]| code.windows(needle.len()).any(|window| window == needle);
+        let bootloader = if contains(b"GRUB \0") {
+            // GRUB 2 jumps over a larger parameter block than GRUB Legacy.
+            match code[..2] {
+                [0xeb, 0x63] => MbrBootloader::Grub2,
+                [0xeb, 0x48] => MbrBootloader::GrubLegacy,
+                _ => MbrBootloader::Unknown,
+            }
+        } else if code.get(6..10) == Some(b"LILO") {
+            MbrBootloader::Lilo
+        } else if contains(b"Operating system load error") {
+            MbrBootloader::Syslinux
+        } else if contains(b"Invalid partition table") {
+            // The MBR of Vista and later measures the boot with the TPM.
+            if contains(b"TCPA") {
+                MbrBootloader::Windows
+            } else {
+                MbrBootloader::WindowsXp
+            }
+        } else {
+            MbrBootloader::Unknown
+        };
+
+        Some(bootloader)
+    }
+
+    /// Reads the boot code of the disk at `path`.
+    pub fn read(path: &Path) -> Option<Self> {
+        let mut code = [0; BOOT_CODE_LEN];
+        File::open(path).and_then(|mut file| file.read_exact(&mut code)).ok()?;
+        Self::detect(&code)
+    }
+}
+
+impl fmt::Display for MbrBootloader {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            MbrBootloader::Grub2 => "GRUB 2",
+            MbrBootloader::GrubLegacy => "GRUB Legacy",
+            MbrBootloader::Lilo => "LILO",
+            MbrBootloader::Syslinux => "Syslinux",
+            MbrBootloader::Windows => "the Windows boot code",
+            MbrBootloader::WindowsXp => "the Windows XP boot code",
+            MbrBootloader::Unknown => "unrecognized boot code",
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The boot code of an MBR, with `start` at its start, and `strings` at their offsets.
+    fn sample(start: &[u8], strings: &[(usize, &[u8])]) -> Vec<u8> {
+        let mut code = vec![0x90; BOOT_CODE_LEN];
+        code[..start.len()].copy_from_slice(start);
+        for &(offset, string) in strings {
+            code[offset..offset + string.len()].copy_from_slice(string);
+        }
+
+        code
+    }
+
+    #[test]
+    fn signatures() {
+        assert_eq!(MbrBootloader::detect(&[0; BOOT_CODE_LEN]), None);
+
+        let grub = b"GRUB \0Geom\0Hard Disk\0Read\0 Error\r\n";
+        let grub2 = sample(&[0xeb, 0x63, 0x90], &[(0x180, grub)]);
+        assert_eq!(MbrBootloader::detect(&grub2), Some(MbrBootloader::Grub2));
+        let legacy = sample(&[0xeb, 0x48, 0x90], &[(0x17e, grub)]);
+        assert_eq!(MbrBootloader::detect(&legacy), Some(MbrBootloader::GrubLegacy));
+
+        let lilo = sample(&[0xfa, 0xeb, 0x21, 0x01, 0xb4, 0x01], &[(6, b"LILO")]);
+        assert_eq!(MbrBootloader::detect(&lilo), Some(MbrBootloader::Lilo));
+
+        let syslinux = sample(
+            &[0x33, 0xc0, 0xfa, 0x8e, 0xd8],
+            &[
+                (0x150, b"Missing operating system.\r\n"),
+                (0x16b, b"Operating system load error.\r\n"),
+            ],
+        );
+        assert_eq!(MbrBootloader::detect(&syslinux), Some(MbrBootloader::Syslinux));
+
+        let start = [0x33, 0xc0, 0x8e, 0xd0, 0xbc, 0x00, 0x7c];
+        let windows = sample(
+            &start,
+            &[
+                (0x0ee, b"TCPA"),
+                (0x163, b"Invalid partition table\0Error loading operating system\0"),
+            ],
+        );
+        assert_eq!(MbrBootloader::detect(&windows), Some(MbrBootloader::Windows));
+        let xp = sample(&start, &[(0x12b, b"Invalid partition table\0")]);
+        assert_eq!(MbrBootloader::detect(&xp), Some(MbrBootloader::WindowsXp));
+
+        assert_eq!(MbrBootloader::detect(&sample(&[0xfa], &[])), Some(MbrBootloader::Unknown));
+        assert_eq!(MbrBootloader::Grub2.to_string(), "GRUB 2");
+    }
+
+    /// The boot code which GRUB and Syslinux install to the MBR, as the packages of the host
+    /// ship it, in the paths of Debian and of Arch Linux. Run with `--ignored` on a host which
+    /// has `grub-pc-bin` and `syslinux-common`, or `grub` and `syslinux`, installed.
+    #[test]
+    #[ignore]
+    fn packaged_boot_code() {
+        const GRUB: &[&str] = &["/usr/lib/grub/i386-pc/boot.img"];
+        const SYSLINUX: &[&str] =
+            &["/usr/lib/syslinux/mbr/mbr.bin", "/usr/lib/syslinux/bios/mbr.bin"];
+
+        for &(paths, expected) in
+            &[(GRUB, MbrBootloader::Grub2), (SYSLINUX, MbrBootloader::Syslinux)]
+        {
+            let path = paths.iter().map(Path::new).find(|path| path.exists());
+            let path = path.unwrap_or_else(|| panic!("none of {:?} are installed", paths));
+            assert_eq!(MbrBootloader::read(path), Some(expected), "{}", path.display());
+        }
+    }
+}
@@ -1,8 +1,9 @@
 use super::{
     super::{
         serial::get_serial, BlockDeviceExt, DiskError, DiskExt, Disks, FileSystem, PartitionError,
-        PartitionFlag, PartitionInfo, PartitionTable, PartitionType,
+        PartitionFlag, PartitionInfo, PartitionTable, PartitionType, Sector,
     },
+    boot_code::MbrBootloader,
     partitions::{FORMAT, REMOVE, SOURCE, SWAPPED},
     PVS,
 };
@@ -89,6 +90,7 @@ pub fn detect_fs_on_device(path: &Path) -> Option<PartitionInfo> {
 ///
 /// The `device_path` field may be used for identification of the device in the system.
 #[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Disk {
     /// The model name of the device, assigned by the manufacturer.
     pub model_name:  String,
@@ -229,6 +231,23 @@ impl Disk {
         })
     }
 
+    /// The bootloader in the boot code of the MBR, which installing GRUB for BIOS overwrites.
+    /// It is read from the disk, so it must be asked for before the disk is partitioned.
+    pub fn detected_bootloader(&self) -> Option<MbrBootloader> {
+        MbrBootloader::read(&self.device_path)
+    }
+
+    /// Describes the boot code which is overwritten by installing GRUB for BIOS to the disk.
+    pub fn boot_code_notice(&self) -> Option<String> {
+        self.detected_bootloader().map(|bootloader| {
+            format!(
+                "MBR of {} currently contains {} and will be overwritten",
+                self.device_path.display(),
+                bootloader
+            )
+        })
+    }
+
     /// Obtains the disk that corresponds to a given device path.
     ///
     /// The `name` of the device should be a path, such as `/dev/sda`. If the device could
@@ -387,6 +406,40 @@ impl Disk {
     /// Returns the device type information as a string.
     pub fn get_device_type(&self) -> &str { &self.device_type }
 
+    /// Regions of the disk which are not allocated to a partition, as the start and end
+    /// sectors of each, within the bounds that partitions may be added to.
+    ///
+    /// Partitions which will be removed are not counted, and gaps smaller than a MiB, which
+    /// are left by alignment, are ignored.
+    pub fn get_unused_regions(&self) -> Vec<(u64, u64)> {
+        let minimum = 1024 * 1024 / self.get_logical_block_size();
+        let last = self.get_sector(Sector::End);
+
+        let mut partitions = self
+            .partitions
+            .iter()
+            .filter(|part| !part.flag_is_enabled(REMOVE))
+            .map(|part| (part.start_sector, part.end_sector))
+            .collect::<Vec<_>>();
+        partitions.sort_unstable();
+
+        let mut regions = Vec::new();
+        let mut cursor = self.get_sector(Sector::Start);
+        for (start, end) in partitions {
+            if start >= cursor + minimum {
+                regions.push((cursor, start - 1));
+            }
+
+            cursor = cursor.max(end + 1);
+        }
+
+        if last >= cursor + minimum {
+            regions.push((cursor, last - 1));
+        }
+
+        regions
+    }
+
     pub fn get_esp_partitions_mut(&mut self) -> Vec<&mut PartitionInfo> {
         self.partitions.iter_mut().filter(|p| p.is_esp_partition()).collect()
     }
@@ -715,7 +768,7 @@ impl Disk {
                                         path:         self.device_path.clone(),
                                         start_sector: new.start_sector,
                                         end_sector:   new.end_sector,
-                                        format:       true,
+                                        format:       !new.is_random_swap(),
                                         file_system:  Some(new.filesystem.expect(
                                             "no file system in partition that requires changes",
                                         )),
@@ -764,7 +817,7 @@ impl Disk {
                 path:         self.device_path.clone(),
                 start_sector: partition.start_sector,
                 end_sector:   partition.end_sector,
-                format:       true,
+                format:       !partition.is_random_swap(),
                 file_system:  partition.filesystem,
                 kind:         partition.part_type,
                 flags:        partition.flags.clone(),
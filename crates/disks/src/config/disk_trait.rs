@@ -1,6 +1,9 @@
 use super::{
-    super::{DiskError, Disks, PartitionBuilder, PartitionInfo, PartitionType, Sector},
-    partitions::REMOVE,
+    super::{
+        validate_volume_group, DiskError, Disks, PartitionBuilder, PartitionInfo, PartitionType,
+        Sector,
+    },
+    partitions::{REMAINDER, REMOVE},
 };
 use disk_types::{BlockDeviceExt, PartitionExt, PartitionTableError, PartitionTableExt, SectorExt};
 use proc_mounts::MOUNTS;
@@ -113,6 +116,10 @@ pub trait DiskExt: BlockDeviceExt + SectorExt + PartitionTableExt {
     ///
     /// An error can occur if the partition will not fit onto the disk.
     fn add_partition(&mut self, mut builder: PartitionBuilder) -> Result<(), DiskError> {
+        if let Some((ref group, ref encryption)) = builder.volume_group {
+            validate_volume_group(group, encryption.as_ref())?;
+        }
+
         // Ensure that the values aren't already contained within an existing partition.
         if !Self::LOGICAL && builder.part_type != PartitionType::Extended {
             info!("checking if {}:{} overlaps", builder.start_sector, builder.end_sector);
@@ -124,10 +131,43 @@ pub trait DiskExt: BlockDeviceExt + SectorExt + PartitionTableExt {
 
         // And that the end can fit onto the disk.
         if Self::LOGICAL {
+            let remainder = self
+                .get_partitions()
+                .iter()
+                .position(|p| !p.flag_is_enabled(REMOVE) && p.flag_is_enabled(REMAINDER));
+
             let sectors = self.get_sectors();
-            let estimated_size = self.get_used() + (builder.end_sector - builder.start_sector);
-            if sectors < estimated_size {
-                return Err(DiskError::PartitionOOB);
+            let used = self.get_used();
+
+            if builder.remainder {
+                if remainder.is_some() {
+                    return Err(DiskError::RemainderClaimed {
+                        device: self.get_device_path().to_path_buf(),
+                    });
+                }
+
+                // Leaves room for the metadata of the volume group.
+                let end = self.get_sector(Sector::End);
+                if end <= used {
+                    return Err(DiskError::PartitionOOB);
+                }
+
+                builder.end_sector = builder.start_sector + (end - used);
+            } else {
+                let size = builder.end_sector - builder.start_sector;
+                match remainder {
+                    // The volume which claims the remainder gives up space to the new volume.
+                    Some(id) => {
+                        let part = &mut self.get_partitions_mut()[id];
+                        if part.get_sectors() <= size {
+                            return Err(DiskError::PartitionOOB);
+                        }
+
+                        part.end_sector -= size;
+                    }
+                    None if sectors < used + size => return Err(DiskError::PartitionOOB),
+                    None => (),
+                }
             }
         } else if self.get_sectors() < builder.end_sector {
             return Err(DiskError::PartitionOOB);
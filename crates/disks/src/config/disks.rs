@@ -3,14 +3,15 @@ use super::{
         Bootloader, DecryptionError, DiskError, DiskExt, FileSystem, LogicalDevice, PartitionFlag,
         PartitionInfo,
     },
-    detect_fs_on_device, find_partition, find_partition_mut,
+    find_partition, find_partition_mut, is_rotational,
+    lvm::find_mapper_collision,
     partitions::{FORMAT, REMOVE, SOURCE},
-    Disk, LvmEncryption, PartitionTable, PVS,
+    Disk, EncryptionProgress, LvmEncryption, NameError, PartitionTable, StrengthScore, PVS,
 };
 use disk_types::{BlockDeviceExt, PartitionExt, PartitionTableExt, SectorExt};
 use crate::external::{
-    cryptsetup_close, cryptsetup_open, lvs, physical_volumes_to_deactivate, pvs, vgdeactivate,
-    CloseBy,
+    cryptsetup_close, generate_unique_id, lv_report, lvs, physical_volumes_to_deactivate, pvs,
+    vgdeactivate, vgrename, vgs, CloseBy,
 };
 use itertools::Itertools;
 use libparted::{Device, DeviceType};
@@ -19,28 +20,58 @@ use partition_identity::{PartitionID, PartitionSource};
 use proc_mounts::{MountIter, MOUNTS, SWAPS};
 use rayon::{iter::IntoParallelRefIterator, prelude::*};
 use std::{
-    collections::{BTreeMap, HashSet},
+    collections::{BTreeMap, BTreeSet, HashSet},
     ffi::OsString,
     fs, io,
     iter::{self, FromIterator},
+    mem,
     os::unix::ffi::{OsStrExt, OsStringExt},
     path::{Path, PathBuf},
-    str, thread,
-    time::Duration,
+    str,
 };
 use sys_mount::{swapoff, unmount, Mount, MountFlags, Mounts, Unmount, UnmountFlags};
 
+/// The size of the sectors that LVM measures volumes in, which is used for a volume group
+/// whose physical volumes are not on any of the probed disks.
+const LVM_SECTOR_SIZE: u64 = 512;
+
 /// A configuration of disks, both physical and logical.
 #[derive(Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Disks {
-    pub physical: Vec<Disk>,
-    pub logical:  Vec<LogicalDevice>,
+    pub physical:    Vec<Disk>,
+    pub logical:     Vec<LogicalDevice>,
+    /// The disk which the BIOS bootloader is installed to, instead of the disk of `/boot`,
+    /// or of `/` where there is none.
+    bootloader_disk: Option<PathBuf>,
 }
 
 impl Disks {
     /// Adds a disk to the disks configuration.
     pub fn add(&mut self, disk: Disk) { self.physical.push(disk); }
 
+    /// Pins the disk which the BIOS bootloader is installed to, which must be one of the
+    /// disks of the configuration.
+    pub fn set_bootloader_disk<P: AsRef<Path>>(&mut self, path: P) -> Result<(), DiskError> {
+        let path = path.as_ref();
+        let canonical = path.canonicalize().ok();
+        let disk = self
+            .physical
+            .iter()
+            .find(|disk| {
+                disk.device_path == path
+                    || (canonical.is_some() && disk.device_path.canonicalize().ok() == canonical)
+            })
+            .ok_or_else(|| DiskError::BootloaderDiskNotFound { device: path.to_path_buf() })?;
+
+        info!("the BIOS bootloader will be installed to {}", disk.device_path.display());
+        self.bootloader_disk = Some(disk.device_path.clone());
+        Ok(())
+    }
+
+    /// The disk which the BIOS bootloader was pinned to, if it was.
+    pub fn get_bootloader_disk(&self) -> Option<&Path> { self.bootloader_disk.as_deref() }
+
     /// Fill in any missing IDs.
     pub fn rescan_partition_ids(&mut self) {
         for part in self.get_partitions_mut() {
@@ -58,12 +89,13 @@ impl Disks {
         }
     }
 
-    /// Remove disks that aren't relevant to the install.
+    /// Remove disks that aren't relevant to the install. The bootloader disk is kept.
     pub fn remove_untouched_disks(&mut self) {
         let mut remove = Vec::with_capacity(self.physical.len() - 1);
 
         for (id, disk) in self.physical.iter().enumerate() {
-            if !disk.is_being_modified() {
+            let is_bootloader_disk = self.bootloader_disk.as_ref() == Some(&disk.device_path);
+            if !disk.is_being_modified() && !is_bootloader_disk {
                 debug!(
                     "removing {:?} from consideration: no action to apply",
                     disk.get_device_path()
@@ -410,6 +442,15 @@ impl Disks {
             Ok(())
         };
 
+        // Volume groups in the live environment which conflict with new volume groups must
+        // be renamed while their physical volumes are still accessible.
+        for device in &self.logical {
+            if let Some(ref rename) = device.conflict_rename {
+                vgrename(&device.volume_group, rename)
+                    .map_err(|why| DiskError::ExternalCommand { why })?;
+            }
+        }
+
         let devices_to_modify = self.get_device_paths_to_modify();
         info!("devices to modify: {:?}", devices_to_modify);
         let volume_map = pvs().map_err(|why| DiskError::ExternalCommand { why })?;
@@ -468,73 +509,27 @@ impl Disks {
 
         fn decrypt(
             partition: &mut PartitionInfo,
-            path: &Path,
             enc: &LvmEncryption,
+            sector_size: u64,
         ) -> Result<LogicalDevice, DecryptionError> {
-            // Attempt to decrypt the device.
-            cryptsetup_open(path, &enc)
-                .map_err(|why| DecryptionError::Open { device: path.to_path_buf(), why })?;
-
-            // Determine which VG the newly-decrypted device belongs to.
-            let pv = &PathBuf::from(["/dev/mapper/", &enc.physical_volume].concat());
-            info!("which belongs to PV {:?}", pv);
-            let mut attempt = 0;
-            while !pv.exists() && attempt < 10 {
-                info!("waiting 1 second for {:?} to activate", pv);
-                attempt += 1;
-                thread::sleep(Duration::from_millis(1000));
-            }
-
-            match pvs().expect("pvs() failed in decrypt_partition").remove(pv) {
-                Some(Some(vg)) => {
-                    // Set values in the device's partition.
-                    partition.volume_group = Some((vg.clone(), Some(enc.clone())));
-                    let mut luks = LogicalDevice::new(
-                        vg,
-                        Some(enc.clone()),
-                        partition.get_sectors(),
-                        512,
-                        true,
-                    );
-                    info!("settings luks_parent to {:?}", path);
-                    luks.set_luks_parent(path.to_path_buf());
-
-                    Ok(luks)
-                }
-                _ => {
-                    // Detect a file system on the device
-                    if let Some(fs) = detect_fs_on_device(&pv) {
-                        let pv = enc.physical_volume.clone();
-                        let mut luks = LogicalDevice::new(
-                            pv,
-                            Some(enc.clone()),
-                            partition.get_sectors(),
-                            512,
-                            true,
-                        );
-
-                        luks.set_file_system(fs);
-                        info!("settings luks_parent to {:?}", path);
-                        luks.set_luks_parent(path.to_path_buf());
-
-                        return Ok(luks);
-                    }
-
-                    // Attempt to close the device as we've failed to find a VG.
-                    let _ = cryptsetup_close(CloseBy::Path(&pv));
+            let unlocked = partition.open_luks(enc)?;
 
-                    // NOTE: Should we handle this in some way?
-                    Err(DecryptionError::DecryptedLacksVG { device: path.to_path_buf() })
-                }
+            // Set values in the device's partition.
+            if let Some(ref vg) = unlocked.volume_group {
+                partition.volume_group = Some((vg.clone(), Some(enc.clone())));
             }
+
+            Ok(unlocked.to_logical_device(partition.get_sectors(), sector_size))
         }
 
         // Attempt to find the device in the configuration.
         for device in &mut self.physical {
+            let sector_size = device.get_logical_block_size();
+
             // TODO: NLL
             if let Some(partition) = device.get_file_system_mut() {
                 if partition.get_device_path() == path {
-                    decrypt(partition, path, &enc)?;
+                    decrypt(partition, &enc, sector_size)?;
                 }
             }
 
@@ -542,7 +537,7 @@ impl Disks {
                 device.file_system.as_mut().into_iter().chain(device.partitions.iter_mut())
             {
                 if partition.get_device_path() == path {
-                    new_device = Some(decrypt(partition, path, &enc)?);
+                    new_device = Some(decrypt(partition, &enc, sector_size)?);
                     break;
                 }
             }
@@ -555,6 +550,8 @@ impl Disks {
                     device.add_partitions();
                 }
 
+                // The volume group may have already been probed while it was inactive.
+                self.logical.retain(|d| d.volume_group != device.volume_group);
                 self.logical.push(device);
                 Ok(())
             }
@@ -562,6 +559,80 @@ impl Disks {
         }
     }
 
+    /// Designates that a snapshot of the existing logical volume at `path` will be taken,
+    /// which is `percent` of its size. See `LogicalDevice::create_snapshot`.
+    pub fn snapshot_logical_volume(
+        &mut self,
+        path: &Path,
+        name: &str,
+        percent: u8,
+    ) -> Result<bool, DiskError> {
+        for device in &mut self.logical {
+            let volume = device.partitions.iter().find(|p| p.get_device_path() == path);
+            if let Some(origin) = volume.and_then(|p| p.name.as_ref()) {
+                return device.create_snapshot(origin, name, percent);
+            }
+        }
+
+        warn!("skipping snapshot of {:?}, as it is not a logical volume", path);
+        Ok(false)
+    }
+
+    /// Returns an iterator of LUKS partitions which have not been unlocked yet.
+    pub fn get_locked_partitions<'a>(&'a self) -> impl Iterator<Item = &'a PartitionInfo> + 'a {
+        self.get_physical_partitions().filter(move |p| {
+            let path = p.get_device_path();
+            p.filesystem == Some(FileSystem::Luks)
+                && !p.flag_is_enabled(REMOVE)
+                && p.volume_group.is_none()
+                && !self.logical.iter().any(|d| d.luks_parent.as_deref() == Some(path))
+                && physical_volumes_to_deactivate(&[path]).is_empty()
+        })
+    }
+
+    /// Attempts to unlock each locked LUKS partition, with secrets supplied by `provider`.
+    ///
+    /// The provider is called with the path of each locked partition, and may return `None`
+    /// to leave that partition locked. A partition that fails to unlock will not prevent
+    /// the remaining partitions from being unlocked; the failures are returned instead.
+    ///
+    /// Each container is opened to a device map named after its UUID, as `luks-<UUID>`.
+    pub fn unlock_partitions<F>(&mut self, mut provider: F) -> Vec<DecryptionError>
+    where
+        F: FnMut(&Path) -> Option<String>,
+    {
+        let locked: Vec<(PathBuf, Option<String>)> = self
+            .get_locked_partitions()
+            .map(|p| (p.get_device_path().to_path_buf(), p.luks_mapper_name()))
+            .collect();
+
+        let mut failures = Vec::new();
+        for (device, name) in locked {
+            let secret = match provider(&device) {
+                Some(secret) => secret,
+                None => continue,
+            };
+
+            let name = match name {
+                Some(name) => Ok(name),
+                None => generate_unique_id("cryptdata", &[]),
+            };
+
+            let result = name
+                .map_err(|why| DecryptionError::Open { device: device.clone(), why })
+                .and_then(|pv| {
+                    self.decrypt_partition(&device, &LvmEncryption::new(pv, Some(secret), None))
+                });
+
+            if let Err(why) = result {
+                warn!("unable to unlock {:?}: {}", device, why);
+                failures.push(why);
+            }
+        }
+
+        failures
+    }
+
     /// Sometimes, physical devices themselves may be mounted directly.
     pub fn unmount_devices(&self) -> Result<(), DiskError> {
         info!("unmounting devices");
@@ -622,9 +693,75 @@ impl Disks {
             },
         );
 
+        if let Err(why) = disks.probe_logical_devices() {
+            warn!("unable to probe logical devices: {}", why);
+        }
+
         Ok(disks)
     }
 
+    /// Probes for every volume group in the system, and adds each as a logical device.
+    ///
+    /// Inactive volume groups are not activated, but their logical volumes will be listed.
+    /// They may be activated on demand with `LogicalDevice::activate()`.
+    pub fn probe_logical_devices(&mut self) -> Result<(), DiskError> {
+        let volume_map = match unsafe { PVS.as_ref() } {
+            Some(map) => map.clone(),
+            None => pvs().map_err(|why| DiskError::ExternalCommand { why })?,
+        };
+
+        for group in vgs().map_err(|why| DiskError::ExternalCommand { why })? {
+            if self.logical.iter().any(|d| d.volume_group == group.name) {
+                continue;
+            }
+
+            info!("probed volume group {}", group.name);
+
+            let pvs: Vec<&Path> = volume_map
+                .iter()
+                .filter(|&(_, vg)| vg.as_ref() == Some(&group.name))
+                .map(|(pv, _)| pv.as_path())
+                .collect();
+
+            // A volume group on an unlocked LUKS device is associated with its LUKS partition.
+            let luks_parent = pvs.iter().filter_map(|pv| luks_parent_of(pv)).next();
+
+            let sector_size = pvs
+                .iter()
+                .cloned()
+                .chain(luks_parent.as_deref())
+                .filter_map(|pv| self.get_physical_device_with_partition(pv))
+                .map(|disk| disk.get_logical_block_size())
+                .max()
+                .unwrap_or(LVM_SECTOR_SIZE);
+
+            let mut device = LogicalDevice::new(
+                group.name.clone(),
+                None,
+                group.size / sector_size,
+                sector_size,
+                true,
+            );
+            device.vg_data.pe_size = group.extent_size / sector_size;
+            device.vg_data.total_pe = group.size / group.extent_size;
+            device.luks_parent = luks_parent;
+
+            let active = lv_report(&group.name)
+                .map(|volumes| volumes.iter().any(|lv| lv.active))
+                .unwrap_or(false);
+
+            if active {
+                device.add_partitions();
+            } else if let Err(why) = device.add_inactive_partitions() {
+                warn!("unable to list volumes of {}: {}", group.name, why);
+            }
+
+            self.logical.push(device);
+        }
+
+        Ok(())
+    }
+
     /// Locate a partition which contains the given file.
     ///
     /// ```rust
@@ -700,6 +837,18 @@ impl Disks {
         Err(io::Error::new(io::ErrorKind::NotFound, "partition was not found"))
     }
 
+    /// Returns an immutable reference to the logical device specified by its path, if it
+    /// exists.
+    pub fn find_logical_disk<P: AsRef<Path>>(&self, path: P) -> Option<&LogicalDevice> {
+        self.logical.iter().find(|disk| disk.device_path == path.as_ref())
+    }
+
+    /// Returns a mutable reference to the logical device specified by its path, if it
+    /// exists.
+    pub fn find_logical_disk_mut<P: AsRef<Path>>(&mut self, path: P) -> Option<&mut LogicalDevice> {
+        self.logical.iter_mut().find(|disk| disk.device_path == path.as_ref())
+    }
+
     /// Returns an immutable reference to the disk specified by its path, if it
     /// exists.
     pub fn find_disk<P: AsRef<Path>>(&self, path: P) -> Option<&Disk> {
@@ -730,6 +879,87 @@ impl Disks {
         }
     }
 
+    /// Finds the ESP which the bootloader is installed to, and the disk that it is on. This is
+    /// the partition which is mounted at `/boot/efi`, rather than the first ESP of the disks, as
+    /// dual-boot systems may have several. An error is returned if no partition or more than one
+    /// is mounted there, or if it lacks the ESP flag.
+    pub fn get_esp(&self) -> io::Result<(&Path, &PartitionInfo)> {
+        fn find<'a, T: DiskExt>(disks: &'a [T], claims: &mut Vec<(&'a Path, &'a PartitionInfo)>) {
+            for disk in disks {
+                let partitions = disk.get_file_system().into_iter().chain(disk.get_partitions());
+                for partition in partitions {
+                    if partition.target.as_ref().map_or(false, |t| t == Path::new("/boot/efi")) {
+                        claims.push((disk.get_device_path(), partition));
+                    }
+                }
+            }
+        }
+
+        let mut found = Vec::new();
+        find(&self.physical, &mut found);
+        find(&self.logical, &mut found);
+        let mut claims = found.into_iter();
+
+        let (device, esp) = claims.next().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "EFI partition was not defined")
+        })?;
+
+        let others = claims.map(|(_, part)| part.get_device_path().display()).join(", ");
+        if !others.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "more than one partition is mounted at /boot/efi: {}, {}",
+                    esp.get_device_path().display(),
+                    others
+                ),
+            ));
+        }
+
+        if !esp.flags.contains(&PartitionFlag::PED_PARTITION_ESP) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "the EFI partition {} does not have the ESP flag set",
+                    esp.get_device_path().display()
+                ),
+            ));
+        }
+
+        Ok((device, esp))
+    }
+
+    /// Finds the PReP boot partition which GRUB for Open Firmware is installed to, and the disk
+    /// that it is on. It must be from 8 to 16 MiB, as Open Firmware loads all of it.
+    pub fn get_prep(&self) -> io::Result<(&Path, &PartitionInfo)> {
+        // The sizes of the partitions are counted in sectors of 512 bytes.
+        const MIN_SECTORS: u64 = 16_384;
+        const MAX_SECTORS: u64 = 32_768;
+
+        let (device, prep) = self
+            .physical
+            .iter()
+            .flat_map(|disk| disk.partitions.iter().map(move |part| (disk, part)))
+            .filter(|(_, part)| !part.flag_is_enabled(REMOVE))
+            .find(|(_, part)| part.flags.contains(&PartitionFlag::PED_PARTITION_PREP))
+            .map(|(disk, part)| (disk.get_device_path(), part))
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "GRUB for Open Firmware requires a PReP boot partition",
+                )
+            })?;
+
+        if prep.get_sectors() < MIN_SECTORS || prep.get_sectors() > MAX_SECTORS {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the PReP boot partition must be from 8 to 16 MiB in size",
+            ));
+        }
+
+        Ok((device, prep))
+    }
+
     /// Returns a list of disk & partition paths that match a volume group.
     pub fn find_volume_paths<'a>(&'a self, volume_group: &str) -> Vec<(&'a Path, &'a Path)> {
         let mut volumes = Vec::new();
@@ -807,7 +1037,7 @@ impl Disks {
                 (root, boot)
             }
             Bootloader::Efi => {
-                let efi = self.find_partition(Path::new("/boot/efi")).expect(
+                let efi = self.get_esp().expect(
                     "verify_partitions() should have ensured that an EFI partition was created",
                 );
 
@@ -889,6 +1119,32 @@ impl Disks {
             }
         }
 
+        // Encrypted logical volumes may also reference keyfiles.
+        let volume_keys: Vec<String> = self
+            .logical
+            .iter()
+            .flat_map(|d| d.get_partitions().iter())
+            .filter_map(|p| p.encryption.as_ref().and_then(|e| e.keydata.as_ref()))
+            .map(|&(ref key_id, _)| key_id.clone())
+            .collect();
+
+        for key_id in volume_keys {
+            if temp.iter().any(|&(ref id, _)| *id == key_id) {
+                continue;
+            }
+
+            let partition = self
+                .physical
+                .iter()
+                .flat_map(|p| p.file_system.as_ref().into_iter().chain(p.partitions.iter()))
+                .find(|p| p.key_id.as_ref() == Some(&key_id))
+                .ok_or(DiskError::KeyWithoutPath)?;
+
+            let target = partition.target.as_ref().ok_or(DiskError::KeyFileWithoutPath)?;
+            let paths = Some((partition.get_device_path().into(), target.into()));
+            temp.push((key_id, paths));
+        }
+
         for (key, paths) in temp {
             let partitions = self
                 .physical
@@ -897,7 +1153,12 @@ impl Disks {
                 .chain(self.logical.iter_mut().flat_map(|x| x.get_partitions_mut().iter_mut()));
 
             for partition in partitions {
-                if let Some(&mut (_, Some(ref mut enc))) = partition.volume_group.as_mut() {
+                let encryption = match partition.volume_group.as_mut() {
+                    Some(&mut (_, Some(ref mut enc))) => Some(enc),
+                    _ => partition.encryption.as_mut(),
+                };
+
+                if let Some(enc) = encryption {
                     if let Some((ref id, ref mut ppath)) = enc.keydata {
                         if *id == *key {
                             *ppath = paths.clone();
@@ -911,10 +1172,387 @@ impl Disks {
         Ok(())
     }
 
+    /// Maps detached LUKS headers to the physical partitions that will store them.
+    ///
+    /// A header is stored on the partition whose mount target is the longest prefix of
+    /// the header's path. That partition may not be contained within the encrypted volume.
+    fn resolve_header_paths(&mut self) -> Result<(), DiskError> {
+        fn depth(partition: &PartitionInfo, path: &Path) -> Option<usize> {
+            partition
+                .target
+                .as_ref()
+                .filter(|target| path.starts_with(target))
+                .map(|target| target.components().count())
+        }
+
+        let headers: Vec<(String, PathBuf)> = self
+            .physical
+            .iter()
+            .flat_map(|d| d.get_partitions().iter())
+            .filter_map(|p| match p.volume_group {
+                Some((_, Some(ref enc))) => Some(enc),
+                _ => None,
+            })
+            .chain(self.logical.iter().filter_map(|d| d.encryption.as_ref()))
+            .chain(
+                self.logical
+                    .iter()
+                    .flat_map(|d| d.get_partitions().iter())
+                    .filter_map(|p| p.encryption.as_ref()),
+            )
+            .filter_map(|enc| {
+                enc.header.as_ref().map(|h| (enc.physical_volume.clone(), h.path.clone()))
+            })
+            .unique()
+            .collect();
+
+        let mut resolved = Vec::with_capacity(headers.len());
+
+        for (volume, path) in headers {
+            let physical = self
+                .physical
+                .iter()
+                .flat_map(|p| p.file_system.as_ref().into_iter().chain(p.partitions.iter()))
+                .filter(|p| !p.flag_is_enabled(REMOVE))
+                .filter_map(|p| depth(p, &path).map(|depth| (depth, p)))
+                .max_by_key(|&(depth, _)| depth);
+
+            let logical = self
+                .logical
+                .iter()
+                .flat_map(|d| {
+                    let partitions = d.file_system.as_ref().into_iter().chain(d.partitions.iter());
+                    partitions.map(move |p| (d, p))
+                })
+                .filter(|&(_, p)| !p.flag_is_enabled(REMOVE))
+                .filter_map(|(d, p)| depth(p, &path).map(|depth| (depth, d, p)))
+                .max_by_key(|&(depth, ..)| depth);
+
+            match (physical, logical) {
+                (Some((pdepth, partition)), ldepth)
+                    if ldepth.map_or(true, |(ldepth, ..)| pdepth > ldepth) =>
+                {
+                    let target = partition.target.clone().expect("target was matched");
+                    resolved.push((volume, (partition.get_device_path().to_path_buf(), target)));
+                }
+                (_, Some((_, device, partition))) => {
+                    let encrypts = |enc: Option<&LvmEncryption>| {
+                        enc.map_or(false, |enc| enc.physical_volume == volume)
+                    };
+
+                    let encryption = partition.encryption.as_ref();
+                    if encrypts(device.encryption.as_ref()) || encrypts(encryption) {
+                        return Err(DiskError::HeaderOnEncryptedVolume { volume });
+                    }
+
+                    return Err(DiskError::HeaderWithoutPartition { path });
+                }
+                _ => return Err(DiskError::HeaderWithoutPartition { path }),
+            }
+        }
+
+        for (volume, paths) in resolved {
+            let partitions = self
+                .physical
+                .iter_mut()
+                .flat_map(|x| x.get_partitions_mut().iter_mut())
+                .chain(self.logical.iter_mut().flat_map(|x| x.get_partitions_mut().iter_mut()));
+
+            for partition in partitions {
+                let encryption = match partition.volume_group.as_mut() {
+                    Some(&mut (_, Some(ref mut enc))) => Some(enc),
+                    _ => partition.encryption.as_mut(),
+                };
+
+                if let Some(enc) = encryption {
+                    enc.set_header_partition(&volume, &paths);
+                }
+            }
+
+            for device in &mut self.logical {
+                if let Some(ref mut enc) = device.encryption {
+                    enc.set_header_partition(&volume, &paths);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Rejects passphrases of new encrypted volumes which are weaker than `score`.
+    pub fn set_minimum_passphrase_strength(&mut self, score: StrengthScore) {
+        let partitions = self
+            .physical
+            .iter_mut()
+            .flat_map(|x| x.get_partitions_mut().iter_mut())
+            .chain(self.logical.iter_mut().flat_map(|x| x.get_partitions_mut().iter_mut()));
+
+        for partition in partitions {
+            if let Some(&mut (_, Some(ref mut enc))) = partition.volume_group.as_mut() {
+                enc.min_strength = Some(score);
+            }
+
+            if let Some(ref mut enc) = partition.encryption {
+                enc.min_strength = Some(score);
+            }
+        }
+
+        for device in &mut self.logical {
+            if let Some(ref mut enc) = device.encryption {
+                enc.min_strength = Some(score);
+            }
+        }
+    }
+
+    /// Ensures that the passphrases of new encrypted volumes meet their minimum strength.
+    ///
+    /// This should be called before any changes are made to the disks.
+    pub fn verify_passphrases(&self) -> Result<(), DiskError> {
+        let physical = self.get_physical_partitions().filter(|p| p.will_format()).filter_map(
+            |p| match p.volume_group {
+                Some((_, Some(ref enc))) => Some(enc),
+                _ => None,
+            },
+        );
+
+        let logical = self
+            .logical
+            .iter()
+            .filter(|d| !d.is_source)
+            .filter_map(|d| d.encryption.as_ref())
+            .chain(
+                self.get_logical_partitions()
+                    .filter(|p| p.will_format())
+                    .filter_map(|p| p.encryption.as_ref()),
+            );
+
+        physical.chain(logical).map(LvmEncryption::verify_password_strength).collect()
+    }
+
+    /// The encrypted volumes which will be created, but which were given neither a
+    /// passphrase nor a keyfile to unlock them with.
+    pub fn get_keyless_encryptions_mut(&mut self) -> Vec<&mut LvmEncryption> {
+        let mut encryptions = Vec::new();
+
+        for partition in self.physical.iter_mut().flat_map(|d| d.get_partitions_mut().iter_mut()) {
+            if !partition.will_format() {
+                continue;
+            }
+
+            if let Some((_, Some(ref mut enc))) = partition.volume_group {
+                encryptions.push(enc);
+            }
+        }
+
+        for device in &mut self.logical {
+            if !device.is_source {
+                encryptions.extend(device.encryption.as_mut());
+            }
+
+            for partition in device.partitions.iter_mut().filter(|p| p.will_format()) {
+                encryptions.extend(partition.encryption.as_mut());
+            }
+        }
+
+        encryptions.retain(|enc| enc.password.is_none() && enc.keydata.is_none());
+        encryptions
+    }
+
+    /// Validates the swap partitions which will be encrypted with a random key.
+    ///
+    /// Such swap can't be resumed from, so it will be refused if `hibernation` is requested.
+    /// It also may not be placed within a device that is already encrypted.
+    pub fn verify_swap_encryption(&self, hibernation: bool) -> Result<(), DiskError> {
+        let physical = self.get_physical_partitions().map(|p| (false, p));
+        let logical = self.logical.iter().flat_map(|device| {
+            let encrypted = device.encryption.is_some();
+            device
+                .get_partitions()
+                .iter()
+                .map(move |p| (encrypted || p.encryption.is_some(), p))
+        });
+
+        for (encrypted, partition) in physical.chain(logical) {
+            if partition.flag_is_enabled(REMOVE) || !partition.is_random_swap() {
+                continue;
+            }
+
+            let device = partition.get_device_path().to_path_buf();
+            if encrypted {
+                return Err(DiskError::RandomSwapEncrypted { device });
+            } else if hibernation {
+                return Err(DiskError::RandomSwapHibernation { device });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The size of the largest swap partition, in bytes, which hibernation resumes from.
+    pub fn get_resume_size(&self) -> u64 {
+        let physical =
+            self.physical.iter().map(|d| (d.get_logical_block_size(), d.get_partitions()));
+        let logical = self.logical.iter().map(|d| (d.get_logical_block_size(), d.get_partitions()));
+
+        physical
+            .chain(logical)
+            .flat_map(|(sector_size, partitions)| partitions.iter().map(move |p| (sector_size, p)))
+            .filter(|&(_, p)| p.is_swap() && !p.flag_is_enabled(REMOVE))
+            .map(|(sector_size, p)| p.get_sectors() * sector_size)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Validates that the install can be resumed from hibernation, which requires swap that
+    /// is at least as large as the `memory` of the system, in bytes.
+    ///
+    /// The install resumes from a swap file of `swapfile` bytes, which is created while
+    /// configuring, if there is one, and otherwise from the largest swap partition.
+    pub fn verify_hibernation(&self, memory: u64, swapfile: u64) -> Result<(), DiskError> {
+        self.verify_swap_encryption(true)?;
+
+        let swap = if swapfile == 0 { self.get_resume_size() } else { swapfile };
+        if swap < memory {
+            return Err(DiskError::HibernationSwapTooSmall { memory, swap });
+        }
+
+        Ok(())
+    }
+
+    /// Validates that the FAT32 partition at `device` can hold `size` bytes of recovery
+    /// files, and mounts it at `/recovery` if it has no other target.
+    pub fn verify_recovery(&mut self, device: &Path, size: u64) -> Result<(), DiskError> {
+        let (sector_size, partition) = self
+            .physical
+            .iter()
+            .flat_map(|d| {
+                let sector_size = d.get_logical_block_size();
+                d.get_partitions().iter().map(move |p| (sector_size, p))
+            })
+            .find(|&(_, p)| p.get_device_path() == device && !p.flag_is_enabled(REMOVE))
+            .ok_or_else(|| DiskError::RecoveryNotFound { device: device.to_path_buf() })?;
+
+        if partition.filesystem != Some(FileSystem::Fat32) {
+            return Err(DiskError::RecoveryFileSystem { device: device.to_path_buf() });
+        }
+
+        match partition.target {
+            Some(ref target) if target != Path::new("/recovery") => {
+                return Err(DiskError::RecoveryTarget { device: device.to_path_buf() });
+            }
+            _ => (),
+        }
+
+        let capacity = partition.get_sectors() * sector_size;
+        if capacity < size {
+            return Err(DiskError::RecoveryTooSmall { size: capacity, required: size });
+        }
+
+        if let Some(partition) = self.get_partition_by_path_mut(device) {
+            partition.set_mount("/recovery".into());
+        }
+
+        Ok(())
+    }
+
+    /// Validates that the source of the recovery partition at `path` is not erased before it
+    /// is copied, where the file system of the source is on the first of the `devices`, and
+    /// those which follow hold it, as the disk of a partition does.
+    pub fn verify_recovery_source(
+        &self,
+        path: &Path,
+        devices: &[PathBuf],
+    ) -> Result<(), DiskError> {
+        let erased = |device: &Path| DiskError::RecoverySourceErased {
+            path:   path.to_path_buf(),
+            device: device.to_path_buf(),
+        };
+
+        for partition in self.get_partitions() {
+            let device = partition.get_device_path();
+            if devices.iter().any(|source| source == device)
+                && (partition.flag_is_enabled(REMOVE) || partition.flag_is_enabled(FORMAT))
+            {
+                return Err(erased(device));
+            }
+        }
+
+        // A new partition table erases every partition of the disk.
+        for disk in &self.physical {
+            let device = disk.get_device_path();
+            if disk.mklabel && devices.iter().any(|source| source == device) {
+                return Err(erased(device));
+            }
+        }
+
+        Ok(())
+    }
+
     fn device_is_logical(&self, device: &Path) -> bool {
         self.get_logical_devices().iter().any(|d| d.get_device_path() == device)
     }
 
+    /// Validates the layout for boards whose firmware loads the kernel, initrd, and device
+    /// trees from `/boot` itself, as U-Boot and the firmware of the Raspberry Pi do. It must
+    /// be a FAT partition, which the firmware can read, on a physical disk.
+    pub fn verify_board_partitions(&self) -> io::Result<()> {
+        self.verify_root()?;
+
+        let (device, boot) = self.find_partition(Path::new("/boot")).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the firmware of the board loads the kernel from a /boot partition, which was not \
+                 defined",
+            )
+        })?;
+
+        match boot.filesystem {
+            Some(FileSystem::Fat16) | Some(FileSystem::Fat32) => (),
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "the /boot partition must be FAT, which the firmware of the board can read",
+                ));
+            }
+        }
+
+        if self.device_is_logical(device) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "boot partition cannot be on logical device",
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Validates the layout for GRUB on the Open Firmware of POWER systems, which requires a
+    /// PReP boot partition rather than an ESP.
+    pub fn verify_prep_partitions(&self) -> io::Result<()> {
+        self.verify_root()?;
+        self.get_prep().map(|_| ())
+    }
+
+    /// The root partition, which must have a file system that Linux can be installed on.
+    fn verify_root(&self) -> io::Result<(&Path, &PartitionInfo)> {
+        let (root_device, root) = self.find_partition(Path::new("/")).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "root partition was not defined")
+        })?;
+
+        use FileSystem::*;
+        match root.filesystem {
+            Some(Fat16) | Some(Fat32) | Some(Ntfs) => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "root partition has invalid file system",
+            )),
+            Some(_) => Ok((root_device, root)),
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "root partition does not have a file system",
+            )),
+        }
+    }
+
     /// Validates that partitions are configured correctly.
     ///
     /// - EFI installs must contain a `/boot/efi` partition as Fat16 / Fat32
@@ -922,27 +1560,9 @@ impl Disks {
     /// - Boot partitions must not be on a logical volume
     /// - EFI boot partitions must have the ESP flag set
     pub fn verify_partitions(&self, bootloader: Bootloader) -> io::Result<()> {
-        let (root_device, root) = self.find_partition(Path::new("/")).ok_or_else(|| {
-            io::Error::new(io::ErrorKind::InvalidInput, "root partition was not defined")
-        })?;
+        let (root_device, root) = self.verify_root()?;
 
         use FileSystem::*;
-        match root.filesystem {
-            Some(Fat16) | Some(Fat32) | Some(Ntfs) => {
-                return Err(io::Error::new(
-                    io::ErrorKind::InvalidInput,
-                    "root partition has invalid file system",
-                ));
-            }
-            Some(_) => (),
-            None => {
-                return Err(io::Error::new(
-                    io::ErrorKind::InvalidInput,
-                    "root partition does not have a file system",
-                ));
-            }
-        }
-
         let boot_partition = if bootloader == Bootloader::Efi {
             Some(("/boot/efi", "EFI", true))
         } else if self.device_is_logical(root_device) {
@@ -953,13 +1573,16 @@ impl Disks {
 
         if let Some((partition, kind, is_efi)) = boot_partition {
             let device = {
-                let (device, boot) =
+                let (device, boot) = if is_efi {
+                    self.get_esp()?
+                } else {
                     self.find_partition(Path::new(partition)).ok_or_else(|| {
                         io::Error::new(
                             io::ErrorKind::InvalidInput,
                             format!("{} partition was not defined", kind),
                         )
-                    })?;
+                    })?
+                };
 
                 let device = match self.find_disk(device) {
                     Some(device) => device,
@@ -980,13 +1603,6 @@ impl Disks {
                         ));
                     }
 
-                    if !boot.flags.contains(&PartitionFlag::PED_PARTITION_ESP) {
-                        return Err(io::Error::new(
-                            io::ErrorKind::InvalidInput,
-                            format!("{} partition did not have ESP flag set", kind),
-                        ));
-                    }
-
                     match boot.filesystem {
                         Some(Fat16) | Some(Fat32) => (),
                         Some(_) => {
@@ -1031,12 +1647,49 @@ impl Disks {
             }
         }
 
+        // GRUB is embedded between the MBR and the first partition, which a GPT disk only
+        // has room for in a BIOS boot partition.
+        if bootloader == Bootloader::Bios {
+            let device = match self.bootloader_disk {
+                Some(ref device) => {
+                    if self.find_disk(device).is_none() {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            format!(
+                                "the bootloader disk {} is not one of the configured disks",
+                                device.display()
+                            ),
+                        ));
+                    }
+
+                    device.as_path()
+                }
+                None => self.find_partition(Path::new("/boot")).unwrap_or((root_device, root)).0,
+            };
+
+            if let Some(disk) = self.find_disk(device) {
+                let bios_grub = disk
+                    .partitions
+                    .iter()
+                    .filter(|p| !p.flag_is_enabled(REMOVE))
+                    .any(|p| p.flags.contains(&PartitionFlag::PED_PARTITION_BIOS_GRUB));
+                if disk.get_partition_table() == Some(PartitionTable::Gpt) && !bios_grub {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "BIOS installs on disks with a GPT partition layout require a partition \
+                         with the bios_grub flag set",
+                    ));
+                }
+            }
+        }
+
         Ok(())
     }
 
     /// Loads existing logical volume data into memory, excluding encrypted volumes.
     pub fn initialize_volume_groups(&mut self) -> Result<(), DiskError> {
         let mut existing_devices: Vec<LogicalDevice> = Vec::new();
+        let conflicts = self.find_volume_group_conflicts()?;
 
         for disk in &self.physical {
             let sector_size = disk.get_logical_block_size();
@@ -1060,7 +1713,9 @@ impl Disks {
                             false,
                         ));
                     }
-                } else if let Some(ref vg) = partition.original_vg {
+                } else if let Some(vg) =
+                    partition.original_vg.as_ref().filter(|vg| !conflicts.contains_key(*vg))
+                {
                     info!("found existing LVM device on {:?}", partition.get_device_path());
                     // TODO: NLL
                     let mut found = false;
@@ -1087,15 +1742,127 @@ impl Disks {
 
         for device in &mut existing_devices {
             if !device.is_source {
+                device.conflict_rename = conflicts.get(&device.volume_group).cloned();
                 continue;
             }
 
             device.add_partitions();
         }
 
-        self.logical = existing_devices;
+        // Keep probed volume groups that don't reside directly on a physical partition,
+        // such as those within a LUKS device which was already unlocked.
+        let probed = mem::replace(&mut self.logical, existing_devices);
+        for device in probed {
+            if device.is_source
+                && !conflicts.contains_key(&device.volume_group)
+                && self.find_volume_paths(&device.volume_group).is_empty()
+                && !self.logical.iter().any(|d| d.volume_group == device.volume_group)
+            {
+                self.logical.push(device);
+            }
+        }
 
-        Ok(())
+        self.verify_mapper_names()
+    }
+
+    /// Verifies that each LUKS container which will be created is opened to a device map
+    /// that no other device of the configuration uses, whether it exists already or will be
+    /// created. Device maps of the live environment which the configuration removes are free.
+    fn verify_mapper_names(&self) -> Result<(), DiskError> {
+        let (mut used, mut new) = (Vec::new(), Vec::new());
+        for device in self.logical.iter().filter(|device| !device.remove) {
+            if let Some(ref encryption) = device.encryption {
+                if device.is_source {
+                    used.push(encryption.physical_volume.clone());
+                } else {
+                    new.push(encryption.physical_volume.as_str());
+                }
+            }
+
+            let partitions = device.file_system.iter().chain(device.partitions.iter());
+            for partition in partitions.filter(|part| !part.flag_is_enabled(REMOVE)) {
+                if let Some(ref name) = partition.name {
+                    let path = device.get_volume_path(name);
+                    used.extend(path.file_name().map(|name| name.to_string_lossy().into_owned()));
+                }
+
+                if let Some(ref encryption) = partition.encryption {
+                    if partition.flag_is_enabled(SOURCE) {
+                        used.push(encryption.physical_volume.clone());
+                    } else {
+                        new.push(encryption.physical_volume.as_str());
+                    }
+                }
+            }
+        }
+
+        match find_mapper_collision(used, new) {
+            Some(name) => Err(DiskError::InvalidMapperName {
+                name: name.to_owned(),
+                why:  NameError::MapperInUse { name: name.to_owned() },
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// Finds volume groups in the live environment which share a name with a new volume
+    /// group in this configuration.
+    ///
+    /// If the existing volume group resides entirely on partitions that will be modified,
+    /// it will be scheduled to be renamed, and the new name will be returned in the map.
+    /// Otherwise, a `DiskError::VgNameConflict` will be returned.
+    fn find_volume_group_conflicts(&self) -> Result<BTreeMap<String, String>, DiskError> {
+        let mut conflicts = BTreeMap::new();
+
+        let new_groups: BTreeSet<&str> = self
+            .get_physical_partitions()
+            .filter_map(|p| p.volume_group.as_ref())
+            .map(|&(ref vg, _)| vg.as_str())
+            .collect();
+
+        if new_groups.is_empty() {
+            return Ok(conflicts);
+        }
+
+        // Neither a rename nor a suggestion may take the name of another new volume group.
+        let reserved: Vec<u64> = new_groups.iter().map(misc::hasher).collect();
+
+        let volume_map = match unsafe { PVS.as_ref() } {
+            Some(map) => map.clone(),
+            None => pvs().map_err(|why| DiskError::ExternalCommand { why })?,
+        };
+
+        let modified = self.get_device_paths_to_modify();
+        let mut wiped = physical_volumes_to_deactivate(&modified);
+        wiped.extend_from_slice(&modified);
+
+        for vg in new_groups {
+            let mut members = volume_map
+                .iter()
+                .filter(|&(_, group)| group.as_ref().map_or(false, |group| group == vg))
+                .map(|(pv, _)| pv)
+                .peekable();
+
+            if members.peek().is_none() {
+                continue;
+            }
+
+            if members.all(|pv| wiped.contains(pv)) {
+                let rename = generate_unique_id(&[vg, "_old"].concat(), &reserved)
+                    .map_err(|why| DiskError::ExternalCommand { why })?;
+                info!(
+                    "volume group '{}' in the live environment will be renamed to '{}'",
+                    vg, rename
+                );
+                conflicts.insert(vg.to_owned(), rename);
+            } else {
+                let suggestion = generate_unique_id(vg, &reserved)
+                    .map_err(|why| DiskError::ExternalCommand { why })?;
+                return Err(DiskError::VgNameConflict { vg: vg.to_owned(), suggestion });
+            }
+        }
+
+        Ok(conflicts)
     }
 
     pub fn remove_logical_device(&mut self, volume: &str) {
@@ -1119,23 +1886,60 @@ impl Disks {
     /// Applies all logical device operations, which are to be performed after all physical disk
     /// operations have completed.
     ///
+    /// The progress of encrypting each volume is reported to `progress`.
+    ///
     /// TODO: We need to generate a diff of logical volume operations.
-    pub fn commit_logical_partitions(&mut self) -> Result<(), DiskError> {
+    pub fn commit_logical_partitions<F: FnMut(EncryptionProgress)>(
+        &mut self,
+        mut progress: F,
+    ) -> Result<(), DiskError> {
         // First we verify that we have a valid logical layout.
         for device in &self.logical {
             let volumes = self.find_volume_paths(&device.volume_group);
-            debug_assert!(!volumes.is_empty());
+            debug_assert!(device.is_source || !volumes.is_empty());
             if device.encryption.is_some() && volumes.len() > 1 {
                 return Err(DiskError::SameGroup);
             }
             device.validate()?;
         }
 
+        // Cache pools must be allocated from a non-rotational device, to cache volumes which
+        // are allocated from the rotational devices.
+        for id in 0..self.logical.len() {
+            if self.logical[id].caches.is_empty() {
+                continue;
+            }
+
+            let vg = self.logical[id].volume_group.clone();
+            let (solid, rotational): (Vec<PathBuf>, Vec<PathBuf>) = self
+                .find_volume_paths(&vg)
+                .into_iter()
+                .map(|(_, pv)| pv.to_path_buf())
+                .partition(|pv| !is_rotational(pv));
+
+            let cache_pv = match solid.into_iter().next() {
+                Some(ref pv) if !rotational.is_empty() => pv.clone(),
+                _ => return Err(DiskError::CacheDevices { vg }),
+            };
+
+            for cache in &mut self.logical[id].caches {
+                cache.cache_pv = Some(cache_pv.clone());
+                cache.origin_pvs = rotational.clone();
+            }
+        }
+
         // By default, the `device_path` field is not populated, so let's fix that.
         for device in &mut self.logical {
             for partition in
                 device.file_system.as_mut().into_iter().chain(device.partitions.iter_mut())
             {
+                // Encrypted volumes are accessed through their LUKS device.
+                if let Some(ref encryption) = partition.encryption {
+                    partition.device_path =
+                        PathBuf::from(["/dev/mapper/", &encryption.physical_volume].concat());
+                    continue;
+                }
+
                 // ... unless it is populated, due to existing beforehand.
                 if partition.flag_is_enabled(SOURCE) {
                     continue;
@@ -1148,6 +1952,7 @@ impl Disks {
 
         // Ensure that the keyfile paths are mapped to their mount targets.
         self.resolve_keyfile_paths()?;
+        self.resolve_header_paths()?;
 
         // LUKS associations with LVM devices.
         let mut associations = Vec::new();
@@ -1160,8 +1965,7 @@ impl Disks {
                 let mut device_path = None;
 
                 if let Some(encryption) = device.encryption.as_ref() {
-                    encryption.encrypt(volumes[0].1)?;
-                    encryption.open(volumes[0].1)?;
+                    encryption.encrypt_and_open(volumes[0].1, &mut progress)?;
                     encryption.create_physical_volume()?;
                     device_path =
                         Some(PathBuf::from(["/dev/mapper/", &encryption.physical_volume].concat()));
@@ -1180,7 +1984,14 @@ impl Disks {
                 device.create_volume_group(volumes)?;
             }
 
-            device.modify_partitions()?;
+            device.modify_partitions(&mut progress)?;
+        }
+
+        // The sizes of the created volumes may differ slightly from what was requested.
+        for device in &mut self.logical {
+            if let Err(why) = device.read_volume_sizes() {
+                warn!("unable to read volume sizes of {}: {}", device.volume_group, why);
+            }
         }
 
         for (luks_parent, id) in associations {
@@ -1203,10 +2014,18 @@ impl IntoIterator for Disks {
 impl FromIterator<Disk> for Disks {
     fn from_iter<I: IntoIterator<Item = Disk>>(iter: I) -> Self {
         // TODO: Also collect LVM Devices
-        Disks { physical: iter.into_iter().collect(), logical: Vec::new() }
+        Disks { physical: iter.into_iter().collect(), ..Disks::default() }
     }
 }
 
+/// Obtains the LUKS partition which backs the given device map, if it is one.
+fn luks_parent_of(pv: &Path) -> Option<PathBuf> {
+    let dm = fs::read_link(pv).ok()?;
+    let slaves = Path::new("/sys/block/").join(dm.file_name()?).join("slaves");
+    let slave = fs::read_dir(slaves).ok()?.filter_map(Result::ok).next()?;
+    Some(Path::new("/dev").join(slave.file_name()))
+}
+
 fn find_device_path_of_mount<P: AsRef<Path>>(path: P) -> io::Result<PathBuf> {
     let path = path.as_ref();
     for mount in MountIter::new()? {
@@ -0,0 +1,173 @@
+//! A machine-readable report of the disks in the system, which scripts may build
+//! configurations from.
+
+use super::{Disk, Disks, LogicalDevice, PartitionInfo};
+use disk_types::{BlockDeviceExt, FileSystem, PartitionExt, PartitionTable, SectorExt};
+use libparted::PartitionFlag;
+use os_detect::OS;
+use std::path::Path;
+
+/// The version of the schema of `Disks::to_json`, which is incremented whenever a field is
+/// changed or removed.
+pub const JSON_VERSION: u32 = 1;
+
+#[derive(Serialize)]
+struct Report<'a> {
+    version:  u32,
+    physical: Vec<DiskReport<'a>>,
+    logical:  Vec<LogicalReport<'a>>,
+}
+
+#[derive(Serialize)]
+struct DiskReport<'a> {
+    path:        &'a Path,
+    model:       &'a str,
+    serial:      &'a str,
+    device_type: &'a str,
+    table:       Option<PartitionTable>,
+    read_only:   bool,
+    removable:   bool,
+    rotational:  bool,
+    sector_size: u64,
+    size:        u64,
+    partitions:  Vec<PartitionReport<'a>>,
+    free:        Vec<RegionReport>,
+}
+
+#[derive(Serialize)]
+struct LogicalReport<'a> {
+    volume_group: &'a str,
+    path:         &'a Path,
+    luks_parent:  Option<&'a Path>,
+    encrypted:    bool,
+    sector_size:  u64,
+    size:         u64,
+    partitions:   Vec<PartitionReport<'a>>,
+}
+
+#[derive(Serialize)]
+struct PartitionReport<'a> {
+    number:      i32,
+    path:        &'a Path,
+    start:       u64,
+    end:         u64,
+    size:        u64,
+    filesystem:  Option<FileSystem>,
+    label:       Option<&'a str>,
+    uuid:        Option<&'a str>,
+    part_uuid:   Option<&'a str>,
+    #[serde(with = "crate::serialize::partition_flags")]
+    flags:       &'a [PartitionFlag],
+    mount_point: Option<&'a Path>,
+    /// Bytes used by the file system, where that can be measured.
+    used:        Option<u64>,
+    /// The name of an OS which is installed to the partition.
+    os:          Option<String>,
+}
+
+#[derive(Serialize)]
+struct RegionReport {
+    start: u64,
+    end:   u64,
+    size:  u64,
+}
+
+impl Disks {
+    /// Describes every disk in the configuration as JSON, with their partitions, the OSes
+    /// installed to them, and their unallocated regions.
+    ///
+    /// Sectors are measured in the `sector_size` of each disk, and sizes in bytes. The schema
+    /// is identified by its top-level `version` field, which is `JSON_VERSION`.
+    ///
+    /// # Note
+    ///
+    /// File systems are mounted to measure their usage and detect their OS, which requires
+    /// root.
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        let report = Report {
+            version:  JSON_VERSION,
+            physical: self.physical.iter().map(disk_report).collect(),
+            logical:  self.logical.iter().map(logical_report).collect(),
+        };
+
+        serde_json::to_string_pretty(&report)
+    }
+}
+
+fn disk_report(disk: &Disk) -> DiskReport {
+    let sector_size = disk.get_logical_block_size();
+    DiskReport {
+        path: disk.get_device_path(),
+        model: &disk.model_name,
+        serial: &disk.serial,
+        device_type: &disk.device_type,
+        table: disk.table_type,
+        read_only: disk.read_only,
+        removable: disk.is_removable(),
+        rotational: disk.is_rotational(),
+        sector_size,
+        size: disk.get_sectors() * sector_size,
+        partitions: disk
+            .partitions
+            .iter()
+            .map(|part| partition_report(part, sector_size))
+            .collect(),
+        free: disk
+            .get_unused_regions()
+            .into_iter()
+            .map(|(start, end)| RegionReport { start, end, size: (end - start + 1) * sector_size })
+            .collect(),
+    }
+}
+
+fn logical_report(device: &LogicalDevice) -> LogicalReport {
+    LogicalReport {
+        volume_group: &device.volume_group,
+        path:         &device.device_path,
+        luks_parent:  device.luks_parent.as_deref(),
+        encrypted:    device.encryption.is_some() || device.luks_parent.is_some(),
+        sector_size:  device.sector_size,
+        size:         device.sectors * device.sector_size,
+        partitions:   device
+            .partitions
+            .iter()
+            .map(|part| partition_report(part, device.sector_size))
+            .collect(),
+    }
+}
+
+fn partition_report(part: &PartitionInfo, sector_size: u64) -> PartitionReport {
+    // Only file systems which may hold an OS are mounted to inspect them.
+    let (used, os) = match part.filesystem {
+        Some(FileSystem::Swap) | Some(FileSystem::Lvm) | Some(FileSystem::Luks) | None => {
+            (None, None)
+        }
+        Some(_) => (
+            part.sectors_used().ok().map(|used| used * sector_size),
+            part.probe_os().map(|os| os_name(&os)),
+        ),
+    };
+
+    PartitionReport {
+        number: part.number,
+        path: &part.device_path,
+        start: part.start_sector,
+        end: part.end_sector,
+        size: (part.end_sector - part.start_sector + 1) * sector_size,
+        filesystem: part.filesystem,
+        label: part.name.as_deref(),
+        uuid: part.identifiers.uuid.as_deref(),
+        part_uuid: part.identifiers.part_uuid.as_deref(),
+        flags: &part.flags,
+        mount_point: part.mount_point.as_deref(),
+        used,
+        os,
+    }
+}
+
+fn os_name(os: &OS) -> String {
+    match *os {
+        OS::Windows(ref name) | OS::MacOs(ref name) => name.clone(),
+        OS::Linux { ref info, .. } => info.pretty_name.clone(),
+    }
+}
@@ -0,0 +1,63 @@
+use std::path::PathBuf;
+
+/// The write policy of a cached logical volume.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum CacheMode {
+    /// Writes are committed to both the cache and the origin before completing.
+    ///
+    /// The loss of the cache device will not result in the loss of data.
+    Writethrough,
+    /// Writes are committed to the cache, and written back to the origin later.
+    ///
+    /// This is faster, but the loss of the cache device may result in the loss of data.
+    Writeback,
+}
+
+impl Default for CacheMode {
+    fn default() -> Self { CacheMode::Writethrough }
+}
+
+impl CacheMode {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            CacheMode::Writethrough => "writethrough",
+            CacheMode::Writeback => "writeback",
+        }
+    }
+}
+
+/// A cache pool which will be attached to a logical volume in the same volume group.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct LogicalCache {
+    /// The name of the logical volume which will be cached.
+    pub origin:     String,
+    /// The size of the cache pool, including its metadata, in sectors.
+    pub sectors:    u64,
+    pub mode:       CacheMode,
+    /// The non-rotational physical volume that the cache pool will be allocated from.
+    ///
+    /// This is assigned when the volume group is validated before it is created.
+    pub cache_pv:   Option<PathBuf>,
+    /// The rotational physical volumes that the origin will be allocated from.
+    pub origin_pvs: Vec<PathBuf>,
+}
+
+impl LogicalCache {
+    pub fn new(origin: String, sectors: u64, mode: CacheMode) -> Self {
+        LogicalCache { origin, sectors, mode, cache_pv: None, origin_pvs: Vec::new() }
+    }
+
+    /// The name of the cache pool volume.
+    pub fn pool_name(&self) -> String { [&self.origin, "_cache"].concat() }
+
+    /// The amount of sectors in the pool which will be reserved for metadata.
+    ///
+    /// LVM recommends at least 8 MiB, or a thousandth of the cache.
+    pub fn metadata_sectors(&self, sector_size: u64) -> u64 {
+        let mebibyte = 1024 * 1024 / sector_size;
+        let metadata = (self.sectors / 1000).max(8 * mebibyte);
+        (metadata + mebibyte - 1) / mebibyte * mebibyte
+    }
+}
@@ -1,24 +1,181 @@
+use super::strength::{check_password_strength, StrengthScore};
 use crate::external::{cryptsetup_encrypt, cryptsetup_open, pvcreate};
+use partition_identity::PartitionID;
+use misc;
 use std::{
-    fmt,
+    borrow::Cow,
+    ffi::{OsStr, OsString},
+    fmt, io,
     path::{Path, PathBuf},
 };
 use crate::DiskError;
+use zeroize::Zeroizing;
+
+/// Options which will be applied to the crypttab entry of an encrypted volume.
+#[derive(Debug, Default, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct CrypttabOptions {
+    /// Whether to allow discards on the volume. If this is not set, discards will be enabled
+    /// when the backing device is not rotational.
+    pub discard:   Option<bool>,
+    /// The number of attempts to unlock the volume before giving up.
+    pub tries:     Option<u32>,
+    /// A script which will output the key for the volume.
+    pub keyscript: Option<PathBuf>,
+    /// The volume will be unlocked by a TPM2 device that a key was enrolled to.
+    pub tpm2:      bool,
+}
+
+impl CrypttabOptions {
+    /// The options field of the crypttab entry, excluding the `luks` option.
+    fn options(&self, rotational: bool) -> String {
+        let mut options = String::new();
+
+        if self.discard.unwrap_or(!rotational) {
+            options.push_str(",discard");
+        }
+
+        if let Some(tries) = self.tries {
+            options.push_str(&format!(",tries={}", tries));
+        }
+
+        if let Some(ref keyscript) = self.keyscript {
+            options.push_str(&format!(",keyscript={}", keyscript.display()));
+        }
+
+        if self.tpm2 {
+            options.push_str(",tpm2-device=auto");
+        }
+
+        options
+    }
+}
+
+/// Machines with less memory than this, in KiB, will have the memory cost of the KDF capped.
+const KDF_LOW_MEMORY: u64 = 4 * 1024 * 1024;
+
+/// The least amount of memory, in KiB, that the KDF will be permitted to use.
+const KDF_MIN_MEMORY: u64 = 64 * 1024;
+
+/// The key derivation parameters which will be used to format LUKS2 volumes.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct KdfParams {
+    /// The memory cost of argon2id, in KiB. Cryptsetup will benchmark the system to
+    /// choose the cost if this is not set.
+    pub memory: Option<u64>,
+}
+
+impl KdfParams {
+    /// Chooses the parameters for a machine with `total` KiB of memory.
+    ///
+    /// The default cost may consume up to a GiB of memory, which can exhaust the memory of
+    /// a live environment with little to spare, so it is capped to an eighth of the total.
+    pub fn for_memory(total: u64) -> KdfParams {
+        KdfParams {
+            memory: if total < KDF_LOW_MEMORY {
+                Some((total / 8).max(KDF_MIN_MEMORY))
+            } else {
+                None
+            },
+        }
+    }
+
+    /// Chooses the parameters from the memory reported by `/proc/meminfo`.
+    pub fn detect() -> KdfParams {
+        match memory_total() {
+            Ok(total) => KdfParams::for_memory(total),
+            Err(why) => {
+                warn!("unable to read the total memory: {}", why);
+                KdfParams::default()
+            }
+        }
+    }
+
+    /// The arguments that will be passed to `cryptsetup luksFormat`.
+    pub fn args(&self) -> Vec<OsString> {
+        match self.memory {
+            Some(memory) => vec![
+                "--pbkdf".into(),
+                "argon2id".into(),
+                "--pbkdf-memory".into(),
+                memory.to_string().into(),
+            ],
+            None => Vec::new(),
+        }
+    }
+}
+
+impl fmt::Display for KdfParams {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.memory {
+            Some(memory) => write!(f, "argon2id with {} KiB of memory", memory),
+            None => f.write_str("argon2id with benchmarked defaults"),
+        }
+    }
+}
+
+/// Reads the total memory of the system, in KiB.
+pub fn memory_total() -> io::Result<u64> {
+    let meminfo = misc::read("/proc/meminfo")?;
+    String::from_utf8_lossy(&meminfo)
+        .lines()
+        .find(|line| line.starts_with("MemTotal:"))
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|total| total.parse::<u64>().ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "MemTotal was not found"))
+}
+
+/// The progress of a volume being encrypted, identified by the name of its LUKS device.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EncryptionProgress<'a> {
+    Encrypting(&'a str),
+    Encrypted(&'a str),
+}
+
+/// A LUKS header which is stored apart from the encrypted device.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct DetachedHeader {
+    /// Where the header will be found in the installed system.
+    pub path:      PathBuf,
+    /// The device of the partition that the header will be written to, and its mount target.
+    ///
+    /// This is assigned when the disk configuration is committed.
+    pub partition: Option<(PathBuf, PathBuf)>,
+}
+
+impl DetachedHeader {
+    /// The path of the header relative to the root of the partition that stores it.
+    pub fn relative_path(&self) -> Option<&Path> {
+        let (_, ref target) = *self.partition.as_ref()?;
+        self.path.strip_prefix(target).ok()
+    }
+}
 
 /// A structure which contains the encryption settings for a physical volume.
+///
+/// The passphrase is never serialized, so it must be set again after the configuration is
+/// deserialized. It is overwritten with zeroes once dropped.
 #[derive(Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct LvmEncryption {
     pub physical_volume: String,
-    pub password:        Option<String>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub password:        Option<Zeroizing<String>>,
     pub keydata:         Option<(String, Option<(PathBuf, PathBuf)>)>,
+    pub crypttab:        CrypttabOptions,
+    pub header:          Option<DetachedHeader>,
+    /// The passphrase will be rejected if it is estimated to be weaker than this.
+    pub min_strength:    Option<StrengthScore>,
 }
 
 impl fmt::Debug for LvmEncryption {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
             f,
-            "LvmEncryption {{ physical_volume: {}, password: hidden, keydata: {:?} }}",
-            self.physical_volume, self.keydata
+            "LvmEncryption {{ physical_volume: {}, password: hidden, keydata: {:?}, crypttab: \
+             {:?}, header: {:?}, min_strength: {:?} }}",
+            self.physical_volume, self.keydata, self.crypttab, self.header, self.min_strength
         )
     }
 }
@@ -31,8 +188,52 @@ impl LvmEncryption {
     ) -> LvmEncryption {
         LvmEncryption {
             physical_volume,
-            password: password.into(),
+            password: password.into().map(Zeroizing::new),
             keydata: keydata.into().map(|key| (key, None)),
+            crypttab: CrypttabOptions::default(),
+            header: None,
+            min_strength: None,
+        }
+    }
+
+    /// Rejects passphrases which are estimated to be weaker than `score`.
+    pub fn min_strength(mut self, score: StrengthScore) -> LvmEncryption {
+        self.min_strength = Some(score);
+        self
+    }
+
+    /// Ensures that the passphrase meets the minimum strength, if one was set.
+    pub fn verify_password_strength(&self) -> Result<(), DiskError> {
+        if let (Some(minimum), Some(password)) = (self.min_strength, self.password.as_ref()) {
+            let strength = check_password_strength(password);
+            if strength.score < minimum {
+                return Err(DiskError::WeakPassphrase {
+                    volume: self.physical_volume.clone(),
+                    strength: strength.score,
+                    minimum,
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Stores the LUKS header in a file at `path` in the installed system, rather than on
+    /// the encrypted device, so that the device is indistinguishable from random data.
+    ///
+    /// The path must reside on a partition with a mount target, which is not contained
+    /// within the encrypted device. That partition must also be available at boot.
+    pub fn detached_header(mut self, path: PathBuf) -> LvmEncryption {
+        self.header = Some(DetachedHeader { path, partition: None });
+        self
+    }
+
+    /// Assigns the partition that stores the detached header, if this is the given volume.
+    pub fn set_header_partition(&mut self, volume: &str, paths: &(PathBuf, PathBuf)) {
+        if self.physical_volume == volume {
+            if let Some(ref mut header) = self.header {
+                header.partition = Some(paths.clone());
+            }
         }
     }
 
@@ -42,6 +243,21 @@ impl LvmEncryption {
             .map_err(|why| DiskError::Encryption { volume: device.into(), why })
     }
 
+    /// Encrypts and opens a new partition, reporting the progress to `progress`.
+    ///
+    /// Formatting may take some time, as the key derivation function is intentionally slow.
+    pub fn encrypt_and_open<F: FnMut(EncryptionProgress)>(
+        &self,
+        device: &Path,
+        mut progress: F,
+    ) -> Result<(), DiskError> {
+        progress(EncryptionProgress::Encrypting(&self.physical_volume));
+        self.encrypt(device)?;
+        self.open(device)?;
+        progress(EncryptionProgress::Encrypted(&self.physical_volume));
+        Ok(())
+    }
+
     /// Opens the previously-encrypted partition with the same settings used to
     /// encrypt it.
     pub fn open(&self, device: &Path) -> Result<(), DiskError> {
@@ -57,4 +273,123 @@ impl LvmEncryption {
             why,
         })
     }
+
+    /// The key field of the crypttab entry for this volume.
+    pub fn crypttab_key(&self) -> Cow<'static, OsStr> {
+        if self.crypttab.tpm2 {
+            return Cow::Borrowed(OsStr::new("none"));
+        }
+
+        match (self.password.is_some(), self.keydata.as_ref()) {
+            (true, None) => Cow::Borrowed(OsStr::new("none")),
+            (false, None) => Cow::Borrowed(OsStr::new("/dev/urandom")),
+            (true, Some(_key)) => unimplemented!(),
+            (false, Some(&(_, ref key))) => {
+                let path =
+                    key.clone().expect("should have been populated").1.join(&self.physical_volume);
+                Cow::Owned(path.into_os_string())
+            }
+        }
+    }
+
+    /// The source field of the crypttab entry for the LUKS device at `device`.
+    ///
+    /// A device with a detached header has no LUKS UUID, so it will be referred to by its
+    /// PartUUID, or by its path if it is a logical volume.
+    pub fn crypttab_source(&self, device: &Path) -> Option<String> {
+        if self.header.is_none() {
+            return PartitionID::get_uuid(device).map(|id| ["UUID=", &id.id].concat());
+        }
+
+        if device.starts_with("/dev/mapper") {
+            return Some(device.to_string_lossy().into_owned());
+        }
+
+        PartitionID::get_partuuid(device).map(|id| ["PARTUUID=", &id.id].concat())
+    }
+
+    /// Generates the crypttab entry for this volume, which refers to the LUKS device by the
+    /// given source, such as `UUID=...`. Discards will be enabled by default if the device
+    /// is not `rotational`.
+    pub fn crypttab_entry(&self, source: &str, rotational: bool) -> OsString {
+        let mut entry = OsString::with_capacity(128);
+        entry.push(&self.physical_volume);
+        entry.push(" ");
+        entry.push(source);
+        entry.push(" ");
+        entry.push(&self.crypttab_key());
+        entry.push(" luks");
+        entry.push(&self.crypttab.options(rotational));
+        if let Some(ref header) = self.header {
+            entry.push(",header=");
+            entry.push(&header.path);
+        }
+        entry.push("\n");
+        entry
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UUID: &str = "UUID=8b2a8c0e-5b52-4c1c-9d2a-1f0d3c9e6a44";
+
+    #[test]
+    fn crypttab_passphrase() {
+        let enc = LvmEncryption::new("cryptdata".into(), Some("password".into()), None);
+        assert_eq!(
+            enc.crypttab_entry(UUID, false),
+            OsString::from(format!("cryptdata {} none luks,discard\n", UUID))
+        );
+        assert_eq!(
+            enc.crypttab_entry(UUID, true),
+            OsString::from(format!("cryptdata {} none luks\n", UUID))
+        );
+    }
+
+    #[test]
+    fn crypttab_keyfile() {
+        let mut enc = LvmEncryption::new("cryptdata".into(), None, Some("key".into()));
+        enc.keydata = Some(("key".into(), Some(("/dev/sda3".into(), "/etc/keys".into()))));
+        enc.crypttab.discard = Some(false);
+        enc.crypttab.tries = Some(3);
+        assert_eq!(
+            enc.crypttab_entry(UUID, false),
+            OsString::from(format!(
+                "cryptdata {} /etc/keys/cryptdata luks,tries=3\n",
+                UUID
+            ))
+        );
+    }
+
+    #[test]
+    fn crypttab_tpm2() {
+        let mut enc = LvmEncryption::new("cryptdata".into(), Some("password".into()), None);
+        enc.crypttab.tpm2 = true;
+        assert_eq!(
+            enc.crypttab_entry(UUID, true),
+            OsString::from(format!("cryptdata {} none luks,tpm2-device=auto\n", UUID))
+        );
+    }
+
+    #[test]
+    fn crypttab_detached_header() {
+        let enc = LvmEncryption::new("cryptdata".into(), Some("password".into()), None)
+            .detached_header("/mnt/key/cryptdata.header".into());
+        assert_eq!(
+            enc.crypttab_entry("PARTUUID=0f3b1c2e-01", true),
+            OsString::from(
+                "cryptdata PARTUUID=0f3b1c2e-01 none luks,header=/mnt/key/cryptdata.header\n"
+            )
+        );
+    }
+
+    #[test]
+    fn kdf_memory_cap() {
+        assert_eq!(KdfParams::for_memory(16 * 1024 * 1024), KdfParams { memory: None });
+        assert_eq!(KdfParams::for_memory(2 * 1024 * 1024), KdfParams { memory: Some(262_144) });
+        assert_eq!(KdfParams::for_memory(256 * 1024), KdfParams { memory: Some(65_536) });
+        assert!(KdfParams::for_memory(16 * 1024 * 1024).args().is_empty());
+    }
 }
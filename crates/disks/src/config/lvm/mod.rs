@@ -1,26 +1,55 @@
+mod cache;
 mod encryption;
-
-pub use self::encryption::LvmEncryption;
+mod names;
+mod snapshot;
+mod strength;
+
+pub(crate) use self::names::find_mapper_collision;
+pub use self::{
+    cache::{CacheMode, LogicalCache},
+    encryption::{
+        memory_total, CrypttabOptions, DetachedHeader, EncryptionProgress, KdfParams, LvmEncryption,
+    },
+    names::{
+        validate_logical_volume_name, validate_mapper_name, validate_volume_group,
+        validate_volume_group_name, NameError,
+    },
+    snapshot::LogicalSnapshot,
+    strength::{check_password_strength, CrackTime, PasswordStrength, StrengthScore},
+};
 use super::{
     super::{
-        DiskError, DiskExt, PartitionError, PartitionInfo, PartitionTable, PartitionType, FORMAT,
-        REMOVE, SOURCE,
+        DiskError, DiskExt, PartitionBuilder, PartitionError, PartitionInfo, PartitionTable,
+        PartitionType, FORMAT, REMAINDER, REMOVE, SOURCE,
     },
     get_size,
 };
-use disk_types::{BlockDeviceExt, PartitionTableExt, SectorExt};
+use disk_types::{BlockDeviceExt, FileSystem, PartitionExt, PartitionTableExt, SectorExt};
 pub use crate::external::deactivate_devices;
-use crate::external::{blkid_partition, lvcreate, lvremove, lvs, mkfs, vgactivate, vgcreate};
+use crate::external::{
+    blkid_partition, lv_report, lv_sizes, lvconvert_cache, lvcreate_cache_pool, lvcreate_on,
+    lvcreate_snapshot, lvremove, lvresize, lvs, mkfs, settle_nodes, vg_extent_size, vgactivate,
+    vgcreate, wipefs,
+};
+use operations::{resize_filesystem, BlockCoordinates, ResizeOperation};
 use partition_identity::PartitionIdentifiers;
 use proc_mounts::MOUNTS;
 use std::{
+    collections::BTreeMap,
     ffi::OsStr,
+    io,
     path::{Path, PathBuf},
-    thread,
     time::Duration,
 };
 
+/// The size of a physical extent when a volume group is created with `vgcreate`, in bytes.
+const DEFAULT_PE_SIZE: u64 = 4 * 1024 * 1024;
+
+/// How long the logical volumes of an activated volume group are waited on.
+const DEVICE_TIMEOUT: Duration = Duration::from_secs(5);
+
 #[derive(Debug, Default, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct VgData {
     /// Size of a PE, measured in sectors.
     pe_size:  u64,
@@ -35,20 +64,32 @@ pub struct VgData {
 /// An LVM device acts similar to a Disk, but consists of one more block devices
 /// that comprise a volume group, and may optionally be encrypted.
 #[derive(Debug, Default, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct LogicalDevice {
-    pub model_name:   String,
-    pub volume_group: String,
-    pub device_path:  PathBuf,
-    pub luks_parent:  Option<PathBuf>,
-    pub mount_point:  Option<PathBuf>,
-    pub file_system:  Option<PartitionInfo>,
-    pub sectors:      u64,
-    pub sector_size:  u64,
-    pub partitions:   Vec<PartitionInfo>,
-    pub encryption:   Option<LvmEncryption>,
-    pub is_source:    bool,
-    pub remove:       bool,
-    pub vg_data:      VgData,
+    pub model_name:      String,
+    pub volume_group:    String,
+    pub device_path:     PathBuf,
+    pub luks_parent:     Option<PathBuf>,
+    pub mount_point:     Option<PathBuf>,
+    pub file_system:     Option<PartitionInfo>,
+    pub sectors:         u64,
+    pub sector_size:     u64,
+    pub partitions:      Vec<PartitionInfo>,
+    pub encryption:      Option<LvmEncryption>,
+    pub is_source:       bool,
+    pub remove:          bool,
+    /// Whether the volume group is active, and its logical volumes have device maps.
+    pub active:          bool,
+    pub vg_data:         VgData,
+    /// The name that a conflicting volume group in the live environment will be renamed to,
+    /// before this volume group is created.
+    pub conflict_rename: Option<String>,
+    /// Existing logical volumes which will be resized, and their original length in sectors.
+    pub resized:         BTreeMap<String, u64>,
+    /// Cache pools which will be attached to logical volumes in this group.
+    pub caches:          Vec<LogicalCache>,
+    /// Snapshots which will be taken of existing logical volumes in this group.
+    pub snapshots:       Vec<LogicalSnapshot>,
 }
 
 impl BlockDeviceExt for LogicalDevice {
@@ -67,6 +108,8 @@ impl SectorExt for LogicalDevice {
     fn get_sectors(&self) -> u64 {
         self.sectors
     }
+
+    fn get_logical_block_size(&self) -> u64 { self.sector_size }
 }
 
 impl DiskExt for LogicalDevice {
@@ -91,6 +134,19 @@ impl DiskExt for LogicalDevice {
     fn get_partitions(&self) -> &[PartitionInfo] { &self.partitions }
 
     fn push_partition(&mut self, partition: PartitionInfo) { self.partitions.push(partition); }
+
+    fn get_used(&self) -> u64 {
+        let volumes: u64 = self
+            .get_partitions()
+            .iter()
+            .filter(|p| !p.flag_is_enabled(REMOVE))
+            .map(|p| p.get_sectors())
+            .sum();
+
+        volumes
+            + self.caches.iter().map(|c| c.sectors).sum::<u64>()
+            + self.snapshots.iter().map(|s| s.sectors).sum::<u64>()
+    }
 }
 
 impl LogicalDevice {
@@ -124,11 +180,44 @@ impl LogicalDevice {
 
     pub fn add_sectors(&mut self, sectors: u64) { self.sectors += sectors; }
 
-    #[rustfmt::skip]
     pub fn validate(&self) -> Result<(), DiskError> {
         if self.get_partitions().iter().any(|p| p.name.is_none()) {
             return Err(DiskError::VolumePartitionLacksLabel {
-                device: self.get_device_path().to_path_buf()
+                device: self.get_device_path().to_path_buf(),
+            });
+        }
+
+        for partition in self.get_partitions() {
+            if partition.encryption.is_some() {
+                match partition.filesystem {
+                    None | Some(FileSystem::Lvm) | Some(FileSystem::Luks) => {
+                        return Err(DiskError::EncryptedVolumeLacksFs {
+                            device: self.get_device_path().to_path_buf(),
+                        });
+                    }
+                    _ => (),
+                }
+            }
+        }
+
+        for cache in &self.caches {
+            if self.get_partition(&cache.origin).map_or(true, |p| p.flag_is_enabled(REMOVE)) {
+                return Err(DiskError::LogicalPartitionNotFound {
+                    group:  self.volume_group.clone(),
+                    volume: cache.origin.clone(),
+                });
+            }
+        }
+
+        let remainders = self
+            .get_partitions()
+            .iter()
+            .filter(|p| !p.flag_is_enabled(REMOVE) && p.flag_is_enabled(REMAINDER))
+            .count();
+
+        if remainders > 1 {
+            return Err(DiskError::RemainderClaimed {
+                device: self.get_device_path().to_path_buf(),
             });
         }
 
@@ -151,7 +240,102 @@ impl LogicalDevice {
 
     pub fn get_pe_free(&self) -> u64 { self.vg_data.free_pe }
 
-    pub fn get_pe_size_in_sectors(&self) -> u64 { self.vg_data.pe_size }
+    pub fn get_pe_size_in_sectors(&self) -> u64 {
+        if self.vg_data.pe_size == 0 {
+            DEFAULT_PE_SIZE / self.sector_size
+        } else {
+            self.vg_data.pe_size
+        }
+    }
+
+    /// Rounds the given number of sectors up to a whole number of physical extents.
+    pub fn round_to_extents(&self, sectors: u64) -> u64 {
+        self.extents_of(sectors) * self.get_pe_size_in_sectors()
+    }
+
+    /// The number of physical extents that are needed to hold the given number of sectors.
+    pub fn extents_of(&self, sectors: u64) -> u64 {
+        let extent = self.get_pe_size_in_sectors();
+        (sectors + extent - 1) / extent
+    }
+
+    /// The number of physical extents in the volume group.
+    pub fn get_pe_total(&self) -> u64 {
+        if self.vg_data.total_pe == 0 {
+            self.get_sectors() / self.get_pe_size_in_sectors()
+        } else {
+            self.vg_data.total_pe
+        }
+    }
+
+    /// The number of physical extents which will be free once the staged logical volumes,
+    /// caches, and snapshots have been created, resized, and removed.
+    pub fn get_pe_unallocated(&self) -> u64 {
+        let volumes = self
+            .partitions
+            .iter()
+            .filter(|p| !p.flag_is_enabled(REMOVE))
+            .map(|p| self.extents_of(p.get_sectors()));
+
+        let caches = self.caches.iter().map(|c| self.extents_of(c.sectors));
+        let snapshots = self.snapshots.iter().map(|s| self.extents_of(s.sectors));
+        self.get_pe_total().saturating_sub(volumes.chain(caches).chain(snapshots).sum())
+    }
+
+    /// Adds a logical volume to the volume group.
+    ///
+    /// The volume will be placed on the first extent after the last volume, and its length
+    /// will be rounded up to a whole number of extents, as `lvcreate` would do. The start
+    /// sector of the builder is ignored.
+    pub fn add_partition(&mut self, mut builder: PartitionBuilder) -> Result<(), DiskError> {
+        if let Some(ref name) = builder.name {
+            validate_logical_volume_name(name)
+                .map_err(|why| DiskError::InvalidLogicalVolumeName { name: name.clone(), why })?;
+        }
+
+        let length = (builder.end_sector + 1).saturating_sub(builder.start_sector);
+
+        builder.start_sector = self
+            .get_partitions()
+            .iter()
+            .rev()
+            .find(|p| !p.flag_is_enabled(REMOVE))
+            .map_or(0, |p| self.round_to_extents(p.end_sector + 1));
+
+        if !builder.remainder {
+            builder.end_sector = builder.start_sector + self.round_to_extents(length) - 1;
+        }
+
+        DiskExt::add_partition(self, builder)
+    }
+
+    /// Updates the placement of each logical volume with the sizes reported by LVM.
+    pub fn read_volume_sizes(&mut self) -> Result<(), DiskError> {
+        let sizes =
+            lv_sizes(&self.volume_group).map_err(|why| DiskError::ExternalCommand { why })?;
+
+        if let Ok(extent) = vg_extent_size(&self.volume_group) {
+            self.vg_data.pe_size = extent / self.sector_size;
+        }
+
+        let sector_size = self.sector_size;
+        let mut start = 0;
+        for partition in self.partitions.iter_mut().filter(|p| !p.flag_is_enabled(REMOVE)) {
+            let name = partition.name.as_ref().expect("logical partitions should have names");
+            if let Some(&(_, bytes)) = sizes.iter().find(|&&(ref lv, _)| lv == name) {
+                let sectors = bytes / sector_size;
+                if sectors == 0 {
+                    continue;
+                }
+
+                partition.start_sector = start;
+                partition.end_sector = start + sectors - 1;
+                start += sectors;
+            }
+        }
+
+        Ok(())
+    }
 
     pub fn shrink_vg(&mut self, _pes: u64) -> Result<(), DiskError> { Ok(()) }
 
@@ -165,6 +349,19 @@ impl LogicalDevice {
             .map_or(0, |p| p.end_sector)
     }
 
+    /// The device mapper path of the given logical volume on this volume group.
+    pub fn get_volume_path(&self, volume: &str) -> PathBuf {
+        PathBuf::from(
+            [
+                "/dev/mapper/",
+                &self.volume_group.replace("-", "--"),
+                "-",
+                &volume.replace("-", "--"),
+            ]
+            .concat(),
+        )
+    }
+
     /// Obtains a partition by it's volume, with shared access.
     pub fn get_partition(&self, volume: &str) -> Option<&PartitionInfo> {
         self.partitions.iter().find(|p| {
@@ -182,20 +379,14 @@ impl LogicalDevice {
     pub fn add_partitions(&mut self) {
         info!("adding partitions to LVM device");
         let mut start_sector = 0;
-        let _ = vgactivate(&self.volume_group);
+        self.active = vgactivate(&self.volume_group).is_ok();
         if let Ok(logical_paths) = lvs(&self.volume_group) {
-            for path in logical_paths {
-                // Wait for the device to be initialized, with a 5 second timeout.
-                let mut nth = 0;
-                while !path.exists() {
-                    info!("waiting 1 second because {:?} does not exist yet", path);
-                    if nth == 5 {
-                        break;
-                    }
-                    nth += 1;
-                    thread::sleep(Duration::from_millis(1000));
-                }
+            // Wait for the devices to be initialized.
+            if let Err(why) = settle_nodes(&logical_paths, DEVICE_TIMEOUT) {
+                warn!("{}: {}", self.volume_group, why);
+            }
 
+            for path in logical_paths {
                 let length = match get_size(&path) {
                     Ok(length) => length,
                     Err(why) => {
@@ -240,6 +431,8 @@ impl LogicalDevice {
                     original_vg: None,
                     volume_group: None,
                     key_id: None,
+                    encryption: None,
+                    swap_encryption: None,
                     identifiers,
                 };
 
@@ -249,6 +442,245 @@ impl LogicalDevice {
         }
     }
 
+    /// Adds the logical volumes of an inactive volume group, as reported by LVM, without
+    /// activating the volume group.
+    ///
+    /// File systems can't be detected on these volumes until the group is activated.
+    pub fn add_inactive_partitions(&mut self) -> io::Result<()> {
+        info!("adding partitions of inactive LVM device");
+        let mut start_sector = 0;
+        for volume in lv_report(&self.volume_group)? {
+            let length = volume.size / self.sector_size;
+            let device_path = self.get_volume_path(&volume.name);
+
+            self.partitions.push(PartitionInfo {
+                bitflags: SOURCE,
+                number: -1,
+                ordering: -1,
+                start_sector,
+                end_sector: start_sector + length,
+                part_type: PartitionType::Primary,
+                flags: vec![],
+                filesystem: None,
+                name: Some(volume.name),
+                device_path,
+                mount_point: None,
+                target: None,
+                original_vg: None,
+                volume_group: None,
+                key_id: None,
+                encryption: None,
+                swap_encryption: None,
+                identifiers: PartitionIdentifiers::default(),
+            });
+
+            start_sector += length + 1;
+        }
+
+        Ok(())
+    }
+
+    /// Activates the volume group, if it is inactive, and reloads its logical volumes.
+    ///
+    /// This should be done before modifying the logical volumes of an inactive group.
+    pub fn activate(&mut self) -> Result<(), DiskError> {
+        if self.active {
+            return Ok(());
+        }
+
+        vgactivate(&self.volume_group).map_err(|why| DiskError::ExternalCommand { why })?;
+        self.partitions.clear();
+        self.add_partitions();
+        Ok(())
+    }
+
+    /// Designates that the existing logical volume, `volume`, should be resized to be
+    /// `sectors` in length, and checks whether or not this will be possible to do.
+    ///
+    /// The length will be aligned down to a whole number of physical extents, and the
+    /// aligned length will be returned. A volume may only grow into the extents which are
+    /// free once the staged volumes are removed, and may not be shrunk below the space used
+    /// by its file system.
+    pub fn resize_lv(&mut self, volume: &str, sectors: u64) -> Result<u64, DiskError> {
+        let extent = self.get_pe_size_in_sectors();
+        let sectors = sectors - sectors % extent;
+        let free = self.get_pe_unallocated();
+        let current_extents = self.get_partition(volume).map(|p| self.extents_of(p.get_sectors()));
+        let group = &self.volume_group;
+
+        let partition = self
+            .partitions
+            .iter_mut()
+            .filter(|p| !p.flag_is_enabled(REMOVE))
+            .find(|p| {
+                p.name.as_ref().expect("logical partitions should have names").as_str() == volume
+            })
+            .ok_or_else(|| DiskError::LogicalPartitionNotFound {
+                group:  group.clone(),
+                volume: volume.into(),
+            })?;
+
+        let current = partition.get_sectors();
+
+        info!("specifying to resize {}-{} from {} to {} sectors", group, volume, current, sectors);
+
+        if sectors <= (10 * 1024 * 1024) / 512 {
+            return Err(DiskError::new_partition_error(
+                partition.device_path.clone(),
+                PartitionError::ResizeTooSmall,
+            ));
+        }
+
+        let extents = sectors / extent;
+        if current_extents.map_or(false, |current| extents > current + free) {
+            return Err(DiskError::PartitionOOB);
+        }
+
+        // The file system must be able to fit within the shrunken volume.
+        if sectors < current && !partition.flag_is_enabled(FORMAT) {
+            if let Some(fs) = partition.filesystem {
+                if fs == FileSystem::Xfs {
+                    return Err(DiskError::new_partition_error(
+                        partition.device_path.clone(),
+                        PartitionError::UnsupportedShrinking { fs },
+                    ));
+                }
+
+                if fs != FileSystem::Swap {
+                    let min = partition.sectors_used().map_err(|why| {
+                        DiskError::new_partition_error(
+                            partition.device_path.clone(),
+                            PartitionError::PartitionResize { why },
+                        )
+                    })?;
+
+                    if sectors < min {
+                        return Err(DiskError::new_partition_error(
+                            partition.device_path.clone(),
+                            PartitionError::ShrinkBelowUsage { size: sectors, min },
+                        ));
+                    }
+                }
+            }
+        }
+
+        if partition.flag_is_enabled(SOURCE) {
+            self.resized.entry(volume.into()).or_insert(current);
+
+            // Swap has no file system to resize, so it will be recreated instead.
+            if partition.filesystem == Some(FileSystem::Swap) {
+                partition.format_and_keep_name(FileSystem::Swap);
+            }
+        }
+
+        partition.end_sector = partition.start_sector + sectors;
+        Ok(sectors)
+    }
+
+    /// Designates that the logical volume, `origin`, will be cached by a cache pool which is
+    /// `sectors` in length, including its metadata.
+    ///
+    /// The volume group must span both a rotational and a non-rotational device. The cache
+    /// pool will be allocated from the non-rotational device, and a new origin volume will
+    /// be allocated from the rotational devices.
+    pub fn create_cache(
+        &mut self,
+        origin: &str,
+        sectors: u64,
+        mode: CacheMode,
+    ) -> Result<(), DiskError> {
+        if self.get_partition(origin).map_or(true, |p| p.flag_is_enabled(REMOVE)) {
+            return Err(DiskError::LogicalPartitionNotFound {
+                group:  self.volume_group.clone(),
+                volume: origin.into(),
+            });
+        }
+
+        // A cache which was previously declared for this volume will be replaced.
+        self.caches.retain(|c| c.origin != origin);
+
+        let cache = LogicalCache::new(origin.into(), self.round_to_extents(sectors), mode);
+        if cache.sectors <= 2 * cache.metadata_sectors(self.sector_size) {
+            return Err(DiskError::CacheTooSmall { volume: origin.into() });
+        }
+
+        let remainder = self
+            .partitions
+            .iter()
+            .position(|p| !p.flag_is_enabled(REMOVE) && p.flag_is_enabled(REMAINDER));
+
+        match remainder {
+            // The volume which claims the remainder gives up space to the cache.
+            Some(id) => {
+                let part = &mut self.partitions[id];
+                if part.get_sectors() <= cache.sectors {
+                    return Err(DiskError::PartitionOOB);
+                }
+
+                part.end_sector -= cache.sectors;
+            }
+            None if self.get_sectors() < self.get_used() + cache.sectors => {
+                return Err(DiskError::PartitionOOB);
+            }
+            None => (),
+        }
+
+        info!("{}-{} will be cached in {} mode", self.volume_group, origin, mode.as_str());
+        self.caches.push(cache);
+        Ok(())
+    }
+
+    /// Designates that a snapshot of the existing logical volume, `origin`, will be taken
+    /// before it is modified, so that its contents may be recovered after it is reformatted.
+    /// The snapshot reserves `percent` of the origin's size for copy-on-write data.
+    ///
+    /// The snapshot is only staged if the volume group has enough free extents to hold it,
+    /// after the volumes of the new layout are accounted for. Otherwise, it is skipped with
+    /// a warning, and `false` is returned.
+    pub fn create_snapshot(
+        &mut self,
+        origin: &str,
+        name: &str,
+        percent: u8,
+    ) -> Result<bool, DiskError> {
+        validate_logical_volume_name(name)
+            .map_err(|why| DiskError::InvalidLogicalVolumeName { name: name.into(), why })?;
+
+        let volume = self
+            .partitions
+            .iter()
+            .filter(|p| p.flag_is_enabled(SOURCE) && !p.flag_is_enabled(REMOVE))
+            .find(|p| p.name.as_ref().map_or(false, |n| n == origin))
+            .ok_or_else(|| DiskError::LogicalPartitionNotFound {
+                group:  self.volume_group.clone(),
+                volume: origin.into(),
+            })?;
+
+        // A snapshot which was previously declared with this name will be replaced.
+        let length = volume.get_sectors();
+        self.snapshots.retain(|s| s.name != name);
+
+        let extents = self.extents_of(length * u64::from(percent) / 100).max(1);
+        let free = self.get_pe_unallocated();
+        if extents > free {
+            warn!(
+                "skipping snapshot of {}-{}: {} extents are required, but only {} are free",
+                self.volume_group, origin, extents, free
+            );
+            return Ok(false);
+        }
+
+        let sectors = extents * self.get_pe_size_in_sectors();
+        info!("{}-{} will be snapshotted to {}", self.volume_group, origin, name);
+        self.snapshots.push(LogicalSnapshot::new(origin.into(), name.into(), sectors));
+        Ok(true)
+    }
+
+    /// Obtains the cache that will be attached to the given logical volume, if any.
+    pub fn get_cache(&self, origin: &str) -> Option<&LogicalCache> {
+        self.caches.iter().find(|c| c.origin == origin)
+    }
+
     pub fn set_luks_parent(&mut self, device: PathBuf) { self.luks_parent = Some(device); }
 
     pub fn clear_partitions(&mut self) {
@@ -276,47 +708,112 @@ impl LogicalDevice {
     }
 
     /// Create & modify all logical volumes on the volume group, and format them.
-    pub fn modify_partitions(&self) -> Result<(), DiskError> {
-        let nparts = if self.partitions.is_empty() {
-            if self.file_system.is_some() {
-                0
-            } else {
-                return Ok(());
+    pub fn modify_partitions<F: FnMut(EncryptionProgress)>(
+        &self,
+        mut progress: F,
+    ) -> Result<(), DiskError> {
+        if self.partitions.is_empty() && self.file_system.is_none() {
+            return Ok(());
+        }
+
+        // Volumes are removed first, to free the extents that other volumes may grow into.
+        for partition in self.partitions.iter().filter(|p| p.flag_is_enabled(SOURCE)) {
+            if partition.flag_is_enabled(REMOVE) {
+                let label = partition.name.as_ref().expect("logical partitions should have names");
+                lvremove(&self.volume_group, label)
+                    .map_err(|why| DiskError::PartitionRemove { partition: -1, why })?;
             }
-        } else {
-            self.partitions.len() - 1
-        };
+        }
+
+        // Then existing volumes are shrunk to make room for new volumes, and grown.
+        for &shrinking in &[true, false] {
+            for (label, &original) in &self.resized {
+                let partition = match self.get_partition(label) {
+                    Some(partition) if !partition.flag_is_enabled(REMOVE) => partition,
+                    _ => continue,
+                };
+
+                if (partition.get_sectors() < original) == shrinking {
+                    self.resize_volume(label, partition, original)?;
+                }
+            }
+        }
 
-        let partitions = self
+        // Snapshots are taken before their origins are reformatted, and like cache pools, are
+        // allocated before the last volume can claim their space.
+        for snapshot in &self.snapshots {
+            lvcreate_snapshot(
+                &self.volume_group,
+                &snapshot.origin,
+                &snapshot.name,
+                snapshot.sectors * self.sector_size,
+            )
+            .map_err(|why| DiskError::LogicalVolumeCreate { why })?;
+        }
+
+        // Cache pools are allocated first, so that the last volume will not claim their space.
+        for cache in &self.caches {
+            let pv = cache.cache_pv.as_ref().expect("cache pool without a physical volume");
+            let metadata = cache.metadata_sectors(self.sector_size);
+            lvcreate_cache_pool(
+                &self.volume_group,
+                &cache.pool_name(),
+                (cache.sectors - metadata) * self.sector_size,
+                metadata * self.sector_size,
+                pv,
+            )
+            .map_err(|why| DiskError::LogicalVolumeCreate { why })?;
+        }
+
+        let mut partitions: Vec<&PartitionInfo> = self
             .file_system
-            .as_ref()
-            .into_iter()
-            .map(|part| (0, part))
-            .chain(self.partitions.iter().enumerate());
+            .iter()
+            .chain(self.partitions.iter())
+            .filter(|p| !p.flag_is_enabled(REMOVE))
+            .collect();
+
+        // The volume which claims the remaining space must be created last. If no volume
+        // claims the remainder, the last volume will be given whatever space remains.
+        partitions.sort_by_key(|p| p.flag_is_enabled(REMAINDER));
+        let nparts = partitions.len().saturating_sub(1);
 
-        for (id, partition) in partitions {
+        for (id, partition) in partitions.into_iter().enumerate() {
             let label =
                 partition.name.as_ref().expect("logical partitions should have names").as_str();
 
             // Don't create a partition if it already exists.
             if !partition.flag_is_enabled(SOURCE) {
-                lvcreate(
+                // The origin of a cache must not be allocated from the cache's device.
+                let pvs = self.get_cache(label).map_or(&[][..], |c| &c.origin_pvs);
+                lvcreate_on(
                     &self.volume_group,
                     label,
                     if id == nparts {
                         None
                     } else {
-                        Some(partition.get_sectors() * self.sector_size)
+                        Some(self.round_to_extents(partition.get_sectors()) * self.sector_size)
                     },
+                    pvs,
                 )
                 .map_err(|why| DiskError::LogicalVolumeCreate { why })?;
             }
 
-            if partition.flag_is_enabled(REMOVE) {
-                lvremove(&self.volume_group, label)
-                    .map_err(|why| DiskError::PartitionRemove { partition: -1, why })?;
-            } else if partition.flag_is_enabled(FORMAT) {
-                if let Some(fs) = partition.filesystem {
+            if partition.flag_is_enabled(FORMAT) {
+                // The file system of an encrypted volume is created within its LUKS device.
+                if let Some(encryption) = partition.encryption.as_ref() {
+                    let volume = self.get_volume_path(label);
+                    encryption.encrypt_and_open(&volume, &mut progress)?;
+                }
+
+                // Swap with a random key is created by cryptsetup at boot.
+                if partition.is_random_swap() {
+                    wipefs(&partition.device_path).map_err(|why| {
+                        DiskError::new_partition_error(
+                            partition.device_path.clone(),
+                            PartitionError::PartitionFormat { why },
+                        )
+                    })?;
+                } else if let Some(fs) = partition.filesystem {
                     mkfs(&partition.device_path, fs).map_err(|why| {
                         DiskError::new_partition_error(
                             partition.device_path.clone(),
@@ -327,6 +824,59 @@ impl LogicalDevice {
             }
         }
 
+        for cache in &self.caches {
+            lvconvert_cache(
+                &self.volume_group,
+                &cache.origin,
+                &cache.pool_name(),
+                cache.mode.as_str(),
+            )
+            .map_err(|why| DiskError::LogicalVolumeCreate { why })?;
+        }
+
         Ok(())
     }
+
+    /// Resizes an existing logical volume, and the file system that it contains.
+    fn resize_volume(
+        &self,
+        label: &str,
+        partition: &PartitionInfo,
+        original: u64,
+    ) -> Result<(), DiskError> {
+        let sectors = partition.get_sectors();
+        if sectors == original {
+            return Ok(());
+        }
+
+        let resize = ResizeOperation::new(
+            self.sector_size,
+            BlockCoordinates::new(0, original),
+            BlockCoordinates::new(0, sectors),
+        );
+
+        let extents = self.extents_of(sectors);
+        let path = &partition.device_path;
+
+        // Volumes that will be formatted don't need their file system to be resized.
+        let fs = match partition.filesystem {
+            Some(FileSystem::Swap) | Some(FileSystem::Lvm) | Some(FileSystem::Luks) => None,
+            _ if partition.flag_is_enabled(FORMAT) => None,
+            fs => fs,
+        };
+
+        info!("resizing {}-{} from {} to {} sectors", self.volume_group, label, original, sectors);
+
+        let result = match fs {
+            None => lvresize(&self.volume_group, label, extents),
+            Some(_) if resize.is_shrinking() => resize_filesystem(path, fs, &resize)
+                .and_then(|_| lvresize(&self.volume_group, label, extents)),
+            Some(_) => lvresize(&self.volume_group, label, extents)
+                .and_then(|_| resize_filesystem(path, fs, &resize)),
+        };
+
+        result.map_err(|why| {
+            DiskError::new_partition_error(path.clone(), PartitionError::PartitionResize { why })
+        })
+    }
 }
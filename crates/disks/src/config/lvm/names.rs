@@ -0,0 +1,194 @@
+use super::LvmEncryption;
+use crate::DiskError;
+
+/// The maximum length of a volume group, logical volume, or device map name.
+const NAME_MAX: usize = 127;
+
+/// Substrings which LVM reserves for the names of its internal sub-volumes.
+const RESERVED_LV_SUBSTRINGS: &[&str] = &[
+    "_cdata", "_cmeta", "_corig", "_mlog", "_mimage", "_pmspare", "_rimage", "_rmeta", "_tdata",
+    "_tmeta", "_vdata", "_vorigin",
+];
+
+/// Prefixes which LVM reserves for the names of its internal logical volumes.
+const RESERVED_LV_PREFIXES: &[&str] = &["pvmove", "snapshot"];
+
+/// Describes why a name was rejected by LVM or the device mapper.
+#[derive(Debug, Clone, PartialEq, Fail)]
+pub enum NameError {
+    #[fail(display = "name is empty")]
+    Empty,
+    #[fail(display = "name is longer than {} characters", max)]
+    TooLong { max: usize },
+    #[fail(display = "'.' and '..' are reserved")]
+    Reserved,
+    #[fail(display = "name may not begin with a hyphen")]
+    LeadingHyphen,
+    #[fail(display = "character {:?} is not allowed", character)]
+    InvalidCharacter { character: char },
+    #[fail(display = "name may not contain '{}', which is reserved by LVM", reserved)]
+    ReservedSubstring { reserved: &'static str },
+    #[fail(display = "name may not begin with '{}', which is reserved by LVM", reserved)]
+    ReservedPrefix { reserved: &'static str },
+    #[fail(display = "/dev/mapper/{} is used by another device of the configuration", name)]
+    MapperInUse { name: String },
+}
+
+/// Checks the rules which are shared by the names of volume groups and logical volumes.
+fn validate_lvm_name(name: &str) -> Result<(), NameError> {
+    if name.is_empty() {
+        return Err(NameError::Empty);
+    }
+
+    if name.len() > NAME_MAX {
+        return Err(NameError::TooLong { max: NAME_MAX });
+    }
+
+    if name == "." || name == ".." {
+        return Err(NameError::Reserved);
+    }
+
+    if name.starts_with('-') {
+        return Err(NameError::LeadingHyphen);
+    }
+
+    match name.chars().find(|&c| !(c.is_ascii_alphanumeric() || "+_.-".contains(c))) {
+        Some(character) => Err(NameError::InvalidCharacter { character }),
+        None => Ok(()),
+    }
+}
+
+/// Validates the name of a volume group against the rules of lvm2.
+pub fn validate_volume_group_name(name: &str) -> Result<(), NameError> { validate_lvm_name(name) }
+
+/// Validates the name of a logical volume against the rules of lvm2.
+///
+/// In addition to the rules for volume groups, names which collide with the sub-volumes
+/// that LVM creates internally are rejected.
+pub fn validate_logical_volume_name(name: &str) -> Result<(), NameError> {
+    validate_lvm_name(name)?;
+
+    if let Some(&reserved) = RESERVED_LV_PREFIXES.iter().find(|&&p| name.starts_with(p)) {
+        return Err(NameError::ReservedPrefix { reserved });
+    }
+
+    match RESERVED_LV_SUBSTRINGS.iter().find(|&&s| name.contains(s)) {
+        Some(&reserved) => Err(NameError::ReservedSubstring { reserved }),
+        None => Ok(()),
+    }
+}
+
+/// Validates the name of a device map that a new LUKS container will be opened to.
+///
+/// The name must also be usable in `/etc/crypttab`. Whether it is already in use depends
+/// upon the rest of the configuration, and is checked once volume groups are initialized.
+pub fn validate_mapper_name(name: &str) -> Result<(), NameError> {
+    if name.is_empty() {
+        return Err(NameError::Empty);
+    }
+
+    if name.len() > NAME_MAX {
+        return Err(NameError::TooLong { max: NAME_MAX });
+    }
+
+    if name == "." || name == ".." {
+        return Err(NameError::Reserved);
+    }
+
+    match name.chars().find(|&c| c == '/' || c.is_whitespace() || c.is_control()) {
+        Some(character) => Err(NameError::InvalidCharacter { character }),
+        None => Ok(()),
+    }
+}
+
+/// Finds the first of the `new` device map names which is already used: either by one of
+/// the `used` device maps, or by a new device map before it.
+pub(crate) fn find_mapper_collision<'a, I: IntoIterator<Item = &'a str>>(
+    mut used: Vec<String>,
+    new: I,
+) -> Option<&'a str> {
+    for name in new {
+        if used.iter().any(|used| used == name) {
+            return Some(name);
+        }
+
+        used.push(name.to_owned());
+    }
+
+    None
+}
+
+/// Validates the name of a volume group, and the device map of its LUKS container, if the
+/// volume group will be encrypted.
+pub fn validate_volume_group(
+    group: &str,
+    encryption: Option<&LvmEncryption>,
+) -> Result<(), DiskError> {
+    validate_volume_group_name(group)
+        .map_err(|why| DiskError::InvalidVolumeGroupName { name: group.to_owned(), why })?;
+
+    if let Some(encryption) = encryption {
+        let name = &encryption.physical_volume;
+        validate_mapper_name(name)
+            .map_err(|why| DiskError::InvalidMapperName { name: name.clone(), why })?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lvm_names() {
+        assert_eq!(validate_volume_group_name("data"), Ok(()));
+        assert_eq!(validate_volume_group_name("pop-os_2+.vg"), Ok(()));
+        assert_eq!(validate_volume_group_name(""), Err(NameError::Empty));
+        assert_eq!(validate_volume_group_name(".."), Err(NameError::Reserved));
+        assert_eq!(validate_volume_group_name("-vg"), Err(NameError::LeadingHyphen));
+        assert_eq!(
+            validate_volume_group_name("my vg!"),
+            Err(NameError::InvalidCharacter { character: ' ' })
+        );
+        assert_eq!(
+            validate_volume_group_name(&"a".repeat(128)),
+            Err(NameError::TooLong { max: NAME_MAX })
+        );
+
+        assert_eq!(validate_logical_volume_name("root"), Ok(()));
+        assert_eq!(validate_logical_volume_name("root_cache"), Ok(()));
+        assert_eq!(validate_logical_volume_name("-root"), Err(NameError::LeadingHyphen));
+        assert_eq!(
+            validate_logical_volume_name("root_tmeta"),
+            Err(NameError::ReservedSubstring { reserved: "_tmeta" })
+        );
+        assert_eq!(
+            validate_logical_volume_name("snapshot1"),
+            Err(NameError::ReservedPrefix { reserved: "snapshot" })
+        );
+    }
+
+    #[test]
+    fn mapper_names() {
+        assert_eq!(validate_mapper_name("cryptdata"), Ok(()));
+        assert_eq!(
+            validate_mapper_name("a/b"),
+            Err(NameError::InvalidCharacter { character: '/' })
+        );
+        assert_eq!(
+            validate_mapper_name("a b"),
+            Err(NameError::InvalidCharacter { character: ' ' })
+        );
+        assert_eq!(validate_mapper_name(".."), Err(NameError::Reserved));
+    }
+
+    #[test]
+    fn mapper_collisions() {
+        let used = vec!["data-root".to_owned(), "cryptdata".to_owned()];
+        assert_eq!(find_mapper_collision(used.clone(), vec!["cryptdata2", "cryptswap"]), None);
+        let collision = find_mapper_collision(used.clone(), vec!["cryptswap", "cryptdata"]);
+        assert_eq!(collision, Some("cryptdata"));
+        assert_eq!(find_mapper_collision(used, vec!["cryptswap", "cryptswap"]), Some("cryptswap"));
+    }
+}
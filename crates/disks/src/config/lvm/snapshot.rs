@@ -0,0 +1,17 @@
+/// A copy-on-write snapshot of an existing logical volume in the same volume group.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct LogicalSnapshot {
+    /// The name of the logical volume which will be snapshotted.
+    pub origin:  String,
+    /// The name of the snapshot volume.
+    pub name:    String,
+    /// The size of the copy-on-write space of the snapshot, in sectors.
+    pub sectors: u64,
+}
+
+impl LogicalSnapshot {
+    pub fn new(origin: String, name: String, sectors: u64) -> Self {
+        LogicalSnapshot { origin, name, sectors }
+    }
+}
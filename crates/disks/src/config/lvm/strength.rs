@@ -0,0 +1,325 @@
+use std::fmt;
+
+/// Guesses per second of an offline attack against a slow hash, such as a LUKS keyslot.
+const GUESSES_PER_SECOND: f64 = 10_000.0;
+
+/// Passwords which are among the first to be guessed, ordered by how common they are.
+const COMMON_PASSWORDS: &[&str] = &[
+    "123456", "password", "12345678", "qwerty", "123456789", "12345", "1234", "111111",
+    "1234567", "dragon", "123123", "baseball", "abc123", "football", "monkey", "letmein",
+    "696969", "shadow", "master", "666666", "qwertyuiop", "123321", "mustang", "1234567890",
+    "michael", "654321", "superman", "1qaz2wsx", "7777777", "121212", "000000", "qazwsx",
+    "123qwe", "killer", "trustno1", "jordan", "jennifer", "zxcvbnm", "asdfgh", "hunter",
+    "buster", "soccer", "harley", "batman", "andrew", "tigger", "sunshine", "iloveyou",
+    "charlie", "robert", "thomas", "hockey", "ranger", "daniel", "starwars", "klaster",
+    "112233", "george", "computer", "michelle", "jessica", "pepper", "1111", "zxcvbn",
+    "555555", "11111111", "131313", "freedom", "777777", "pass", "maggie", "159753",
+    "aaaaaa", "ginger", "princess", "joshua", "cheese", "amanda", "summer", "love", "ashley",
+    "nicole", "chelsea", "biteme", "matthew", "access", "yankees", "987654321", "dallas",
+    "austin", "thunder", "taylor", "matrix", "welcome", "admin", "secret", "linux", "ubuntu",
+    "root", "toor", "changeme", "passw0rd", "default", "login", "guest", "system",
+];
+
+/// Rows of the keyboard, and the alphabet, whose runs are easily guessed.
+const SEQUENCES: &[&str] = &[
+    "abcdefghijklmnopqrstuvwxyz",
+    "01234567890",
+    "qwertyuiop",
+    "asdfghjkl",
+    "zxcvbnm",
+];
+
+/// A coarse rating of how resistant a passphrase is to being guessed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum StrengthScore {
+    /// Guessed within a thousand attempts.
+    VeryWeak,
+    /// Guessed within a million attempts.
+    Weak,
+    /// Guessed within a hundred million attempts.
+    Fair,
+    /// Guessed within ten billion attempts.
+    Strong,
+    /// Requires more than ten billion attempts.
+    VeryStrong,
+}
+
+impl StrengthScore {
+    fn from_guesses(guesses: f64) -> StrengthScore {
+        if guesses < 1e3 {
+            StrengthScore::VeryWeak
+        } else if guesses < 1e6 {
+            StrengthScore::Weak
+        } else if guesses < 1e8 {
+            StrengthScore::Fair
+        } else if guesses < 1e10 {
+            StrengthScore::Strong
+        } else {
+            StrengthScore::VeryStrong
+        }
+    }
+}
+
+impl fmt::Display for StrengthScore {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            StrengthScore::VeryWeak => "very weak",
+            StrengthScore::Weak => "weak",
+            StrengthScore::Fair => "fair",
+            StrengthScore::Strong => "strong",
+            StrengthScore::VeryStrong => "very strong",
+        })
+    }
+}
+
+/// How long an offline attack would take to guess the passphrase.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum CrackTime {
+    Instant,
+    Minutes,
+    Hours,
+    Days,
+    Months,
+    Years,
+    Centuries,
+}
+
+impl CrackTime {
+    fn from_seconds(seconds: f64) -> CrackTime {
+        const MINUTE: f64 = 60.0;
+        const HOUR: f64 = MINUTE * 60.0;
+        const DAY: f64 = HOUR * 24.0;
+        const MONTH: f64 = DAY * 31.0;
+        const YEAR: f64 = MONTH * 12.0;
+
+        if seconds < MINUTE {
+            CrackTime::Instant
+        } else if seconds < HOUR {
+            CrackTime::Minutes
+        } else if seconds < DAY {
+            CrackTime::Hours
+        } else if seconds < MONTH {
+            CrackTime::Days
+        } else if seconds < YEAR {
+            CrackTime::Months
+        } else if seconds < YEAR * 100.0 {
+            CrackTime::Years
+        } else {
+            CrackTime::Centuries
+        }
+    }
+}
+
+impl fmt::Display for CrackTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            CrackTime::Instant => "less than a minute",
+            CrackTime::Minutes => "minutes",
+            CrackTime::Hours => "hours",
+            CrackTime::Days => "days",
+            CrackTime::Months => "months",
+            CrackTime::Years => "years",
+            CrackTime::Centuries => "centuries",
+        })
+    }
+}
+
+/// An estimate of the strength of a passphrase.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PasswordStrength {
+    pub score:      StrengthScore,
+    /// The estimated entropy of the passphrase, in bits.
+    pub entropy:    f64,
+    pub crack_time: CrackTime,
+    /// Suggestions for making the passphrase harder to guess.
+    pub feedback:   Vec<&'static str>,
+}
+
+/// Estimates how resistant a passphrase is to being guessed.
+///
+/// Similar to zxcvbn, the passphrase is penalized for containing common passwords,
+/// keyboard and alphabetical sequences, repeated characters, and years. The remaining
+/// characters are assumed to be drawn at random from the classes of characters used.
+pub fn check_password_strength(password: &str) -> PasswordStrength {
+    let chars: Vec<char> = password.chars().collect();
+    let lower: Vec<char> = chars.iter().map(|&c| c.to_lowercase().next().unwrap_or(c)).collect();
+    let unleeted: Vec<char> = lower.iter().cloned().map(unleet).collect();
+    let mut feedback = Vec::new();
+
+    let (mut lowers, mut uppers, mut digits, mut symbols, mut others) =
+        (false, false, false, false, false);
+    for &c in &chars {
+        match c {
+            'a'..='z' => lowers = true,
+            'A'..='Z' => uppers = true,
+            '0'..='9' => digits = true,
+            _ if c.is_ascii() => symbols = true,
+            _ => others = true,
+        }
+    }
+
+    let charset = [(lowers, 26), (uppers, 26), (digits, 10), (symbols, 33), (others, 100)]
+        .iter()
+        .filter(|&&(used, _)| used)
+        .map(|&(_, size)| size)
+        .sum::<u32>()
+        .max(1);
+    let bits_per_char = f64::from(charset).log2();
+
+    // Characters that belong to a pattern contribute little beyond the pattern itself.
+    let mut patterned = vec![false; lower.len()];
+    let mut entropy = 0.0;
+
+    if let Some((rank, start, len)) = find_common(&unleeted) {
+        entropy += ((rank + 1) as f64).log2() + 1.0;
+        patterned[start..start + len].iter_mut().for_each(|p| *p = true);
+        feedback.push("Avoid common passwords and words");
+    }
+
+    let mut found_sequence = false;
+    let mut found_repeat = false;
+    let mut found_year = false;
+    let mut i = 0;
+    while i < lower.len() {
+        if patterned[i] {
+            i += 1;
+            continue;
+        }
+
+        let sequence = sequence_len(&lower[i..]);
+        let repeat = lower[i..].iter().take_while(|&&c| c == lower[i]).count();
+        let year = is_year(&lower[i..]);
+
+        let (len, bits) = if year {
+            found_year = true;
+            (4, 7.0)
+        } else if sequence >= 3 && sequence >= repeat {
+            found_sequence = true;
+            (sequence, bits_per_char + (sequence as f64).log2())
+        } else if repeat >= 3 {
+            found_repeat = true;
+            (repeat, bits_per_char + (repeat as f64).log2())
+        } else {
+            (1, bits_per_char)
+        };
+
+        entropy += bits;
+        patterned[i..i + len].iter_mut().for_each(|p| *p = true);
+        i += len;
+    }
+
+    if found_sequence {
+        feedback.push("Avoid sequences, such as abc, 1234, or qwerty");
+    }
+
+    if found_repeat {
+        feedback.push("Avoid repeated characters, such as aaa");
+    }
+
+    if found_year {
+        feedback.push("Avoid years and dates that are associated with you");
+    }
+
+    if chars.len() < 12 {
+        feedback.push("Use a longer passphrase: a few uncommon words together are hard to guess");
+    }
+
+    if [lowers, uppers, digits, symbols, others].iter().filter(|&&used| used).count() < 2 {
+        feedback.push("Mix in uppercase letters, digits, or symbols");
+    }
+
+    let guesses = 2f64.powf(entropy);
+    PasswordStrength {
+        score: StrengthScore::from_guesses(guesses),
+        entropy,
+        crack_time: CrackTime::from_seconds(guesses / 2.0 / GUESSES_PER_SECOND),
+        feedback,
+    }
+}
+
+/// Reverses common substitutions of letters with digits and symbols.
+fn unleet(c: char) -> char {
+    match c {
+        '0' => 'o',
+        '1' | '!' => 'i',
+        '3' => 'e',
+        '4' | '@' => 'a',
+        '5' | '$' => 's',
+        '7' => 't',
+        _ => c,
+    }
+}
+
+/// Finds the longest common password within the passphrase, returning its rank, position,
+/// and length.
+fn find_common(password: &[char]) -> Option<(usize, usize, usize)> {
+    let password: String = password.iter().collect();
+    COMMON_PASSWORDS
+        .iter()
+        .enumerate()
+        .filter(|&(_, common)| common.len() >= 4)
+        .filter_map(|(rank, common)| {
+            let unleeted: String = common.chars().map(unleet).collect();
+            password.find(unleeted.as_str()).map(|pos| {
+                let start = password[..pos].chars().count();
+                (rank, start, unleeted.chars().count())
+            })
+        })
+        .max_by_key(|&(rank, _, len)| (len, usize::max_value() - rank))
+}
+
+/// The length of the run of sequential characters at the start of the input.
+fn sequence_len(input: &[char]) -> usize {
+    let forward = |input: &[char]| -> usize {
+        SEQUENCES
+            .iter()
+            .map(|sequence| {
+                let sequence: Vec<char> = sequence.chars().collect();
+                sequence
+                    .iter()
+                    .position(|&c| Some(&c) == input.first())
+                    .map_or(0, |start| {
+                        input.iter().zip(&sequence[start..]).take_while(|&(a, b)| a == b).count()
+                    })
+            })
+            .max()
+            .unwrap_or(0)
+    };
+
+    // Descending runs, such as 4321, are just as easily guessed.
+    let descending = input.iter().take_while(|c| c.is_ascii_alphanumeric()).count();
+    let reversed: Vec<char> = input[..descending].iter().rev().cloned().collect();
+    let backward = (1..=reversed.len())
+        .rev()
+        .find(|&len| forward(&reversed[reversed.len() - len..]) >= len)
+        .unwrap_or(0);
+
+    forward(input).max(backward)
+}
+
+/// Whether the input begins with a year between 1900 and 2099.
+fn is_year(input: &[char]) -> bool {
+    input.len() >= 4
+        && input[..4].iter().all(|c| c.is_ascii_digit())
+        && (input[..2] == ['1', '9'] || input[..2] == ['2', '0'])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn password_strength() {
+        assert_eq!(check_password_strength("password").score, StrengthScore::VeryWeak);
+        assert_eq!(check_password_strength("P4ssw0rd").score, StrengthScore::VeryWeak);
+        assert_eq!(check_password_strength("abcdef123456").score, StrengthScore::VeryWeak);
+        assert_eq!(check_password_strength("Summer1987!").score, StrengthScore::Fair);
+        assert_eq!(check_password_strength("zzzzzzzzzzzzzzzz").score, StrengthScore::VeryWeak);
+
+        let strong = check_password_strength("correct horse battery staple");
+        assert_eq!(strong.score, StrengthScore::VeryStrong);
+        assert_eq!(strong.crack_time, CrackTime::Centuries);
+        assert!(strong.feedback.is_empty() || strong.feedback.len() == 1);
+    }
+}
@@ -1,13 +1,19 @@
 //! Contains source code related to the configuration of disks & partitions in
 //! the system.
 
+mod boot_code;
 mod disk;
 mod disk_trait;
 mod disks;
+#[cfg(feature = "serde")]
+mod json;
 mod lvm;
 mod partitions;
 
+#[cfg(feature = "serde")]
+pub use self::json::JSON_VERSION;
 pub use self::{
+    boot_code::*,
     disk::*,
     disk_trait::{find_partition, find_partition_mut, DiskExt},
     disks::*,
@@ -40,11 +46,29 @@ pub fn get_size(path: &Path) -> io::Result<u64> {
     Block::new(&name).and_then(|ref block| block.size())
 }
 
+/// Checks if the block device at the given path, or the disk that it resides on, is rotational.
+pub fn is_rotational(path: &Path) -> bool {
+    struct Device<'a>(&'a Path);
+
+    impl<'a> BlockDeviceExt for Device<'a> {
+        fn get_device_path(&self) -> &Path { self.0 }
+    }
+
+    let device = Device(path);
+    if device.is_partition() {
+        device.get_parent_device().map_or(false, |parent| parent.queue_rotational().ok() == Some(1))
+    } else {
+        device.is_rotational()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use libparted::PartitionFlag;
     use operations::*;
     use partition_identity::PartitionIdentifiers;
+    use zeroize::Zeroizing;
 
     fn get_default() -> Disks {
         Disks {
@@ -61,80 +85,89 @@ mod tests {
                 read_only:   false,
                 partitions:  vec![
                     PartitionInfo {
-                        bitflags:     ACTIVE | BUSY | SOURCE,
-                        device_path:  Path::new("/dev/sdz1").to_path_buf(),
-                        flags:        vec![],
-                        mount_point:  Some(Path::new("/boot/efi").to_path_buf()),
-                        target:       Some(Path::new("/boot/efi").to_path_buf()),
-                        start_sector: 2048,
-                        end_sector:   1026047,
-                        filesystem:   Some(FileSystem::Fat16),
-                        name:         None,
-                        number:       1,
-                        ordering:     1,
-                        part_type:    PartitionType::Primary,
-                        key_id:       None,
-                        original_vg:  None,
-                        volume_group: None,
-                        identifiers:  PartitionIdentifiers::default(),
+                        bitflags:        ACTIVE | BUSY | SOURCE,
+                        device_path:     Path::new("/dev/sdz1").to_path_buf(),
+                        flags:           vec![],
+                        mount_point:     Some(Path::new("/boot/efi").to_path_buf()),
+                        target:          Some(Path::new("/boot/efi").to_path_buf()),
+                        start_sector:    2048,
+                        end_sector:      1026047,
+                        filesystem:      Some(FileSystem::Fat16),
+                        name:            None,
+                        number:          1,
+                        ordering:        1,
+                        part_type:       PartitionType::Primary,
+                        key_id:          None,
+                        encryption:      None,
+                        swap_encryption: None,
+                        original_vg:     None,
+                        volume_group:    None,
+                        identifiers:     PartitionIdentifiers::default(),
                     },
                     PartitionInfo {
-                        bitflags:     ACTIVE | BUSY | SOURCE,
-                        device_path:  Path::new("/dev/sdz2").to_path_buf(),
-                        flags:        vec![],
-                        mount_point:  Some(Path::new("/").to_path_buf()),
-                        target:       Some(Path::new("/").to_path_buf()),
-                        start_sector: 1026048,
-                        end_sector:   420456447,
-                        filesystem:   Some(FileSystem::Btrfs),
-                        name:         Some("Pop!_OS".into()),
-                        number:       2,
-                        ordering:     2,
-                        part_type:    PartitionType::Primary,
-                        key_id:       None,
-                        original_vg:  None,
-                        volume_group: None,
-                        identifiers:  PartitionIdentifiers::default(),
+                        bitflags:        ACTIVE | BUSY | SOURCE,
+                        device_path:     Path::new("/dev/sdz2").to_path_buf(),
+                        flags:           vec![],
+                        mount_point:     Some(Path::new("/").to_path_buf()),
+                        target:          Some(Path::new("/").to_path_buf()),
+                        start_sector:    1026048,
+                        end_sector:      420456447,
+                        filesystem:      Some(FileSystem::Btrfs),
+                        name:            Some("Pop!_OS".into()),
+                        number:          2,
+                        ordering:        2,
+                        part_type:       PartitionType::Primary,
+                        key_id:          None,
+                        encryption:      None,
+                        swap_encryption: None,
+                        original_vg:     None,
+                        volume_group:    None,
+                        identifiers:     PartitionIdentifiers::default(),
                     },
                     PartitionInfo {
-                        bitflags:     SOURCE,
-                        device_path:  Path::new("/dev/sdz3").to_path_buf(),
-                        flags:        vec![],
-                        mount_point:  None,
-                        target:       None,
-                        start_sector: 420456448,
-                        end_sector:   1936738303,
-                        filesystem:   Some(FileSystem::Ext4),
-                        name:         Some("Solus OS".into()),
-                        number:       3,
-                        ordering:     3,
-                        part_type:    PartitionType::Primary,
-                        key_id:       None,
-                        original_vg:  None,
-                        volume_group: None,
-                        identifiers:  PartitionIdentifiers::default(),
+                        bitflags:        SOURCE,
+                        device_path:     Path::new("/dev/sdz3").to_path_buf(),
+                        flags:           vec![],
+                        mount_point:     None,
+                        target:          None,
+                        start_sector:    420456448,
+                        end_sector:      1936738303,
+                        filesystem:      Some(FileSystem::Ext4),
+                        name:            Some("Solus OS".into()),
+                        number:          3,
+                        ordering:        3,
+                        part_type:       PartitionType::Primary,
+                        key_id:          None,
+                        encryption:      None,
+                        swap_encryption: None,
+                        original_vg:     None,
+                        volume_group:    None,
+                        identifiers:     PartitionIdentifiers::default(),
                     },
                     PartitionInfo {
-                        bitflags:     ACTIVE | SOURCE,
-                        device_path:  Path::new("/dev/sdz4").to_path_buf(),
-                        flags:        vec![],
-                        mount_point:  None,
-                        target:       None,
-                        start_sector: 1936738304,
-                        end_sector:   1953523711,
-                        filesystem:   Some(FileSystem::Swap),
-                        name:         None,
-                        number:       4,
-                        ordering:     4,
-                        part_type:    PartitionType::Primary,
-                        key_id:       None,
-                        original_vg:  None,
-                        volume_group: None,
-                        identifiers:  PartitionIdentifiers::default(),
+                        bitflags:        ACTIVE | SOURCE,
+                        device_path:     Path::new("/dev/sdz4").to_path_buf(),
+                        flags:           vec![],
+                        mount_point:     None,
+                        target:          None,
+                        start_sector:    1936738304,
+                        end_sector:      1953523711,
+                        filesystem:      Some(FileSystem::Swap),
+                        name:            None,
+                        number:          4,
+                        ordering:        4,
+                        part_type:       PartitionType::Primary,
+                        key_id:          None,
+                        encryption:      None,
+                        swap_encryption: None,
+                        original_vg:     None,
+                        volume_group:    None,
+                        identifiers:     PartitionIdentifiers::default(),
                     },
                 ],
             }],
-            logical:  Vec::new(),
+            logical: Vec::new(),
+            ..Disks::default()
         }
     }
 
@@ -153,7 +186,8 @@ mod tests {
                 read_only:   false,
                 partitions:  Vec::new(),
             }],
-            logical:  Vec::new(),
+            logical: Vec::new(),
+            ..Disks::default()
         }
     }
 
@@ -169,6 +203,141 @@ mod tests {
         PartitionBuilder::new(start, GIB20 + start, FileSystem::Ext4)
     }
 
+    #[test]
+    fn hibernation() {
+        let disks = get_default();
+        let swap = (1953523711 - 1936738304) * 512;
+        assert_eq!(disks.get_resume_size(), swap);
+        assert!(disks.verify_hibernation(swap, 0).is_ok());
+        assert!(disks.verify_hibernation(swap + 1, 0).is_err());
+        assert!(disks.verify_hibernation(swap + 1, swap + 1).is_ok());
+        assert!(disks.verify_hibernation(swap, swap - 1).is_err());
+    }
+
+    #[test]
+    fn recovery() {
+        let mut disks = get_default();
+        let sdz3 = Path::new("/dev/sdz3");
+        assert!(disks.verify_recovery(sdz3, 1).is_err());
+        assert!(disks.verify_recovery(Path::new("/dev/sdz1"), 1).is_err());
+
+        disks.physical[0].format_partition(3, FileSystem::Fat32).unwrap();
+        let size = (1936738303 - 420456448) * 512;
+        assert!(disks.verify_recovery(sdz3, size + 1).is_err());
+        assert!(disks.verify_recovery(sdz3, size).is_ok());
+
+        // The live system can't be copied from a partition that is formatted or erased.
+        let iso = Path::new("/media/pop.iso");
+        let sdz1 = PathBuf::from("/dev/sdz1");
+        let sources = [sdz3.to_path_buf(), PathBuf::from("/dev/sdz")];
+        assert!(disks.verify_recovery_source(iso, &[sdz1.clone()]).is_ok());
+        assert!(disks.verify_recovery_source(iso, &sources).is_err());
+        disks.physical[0].mklabel = true;
+        assert!(disks.verify_recovery_source(iso, &[sdz1, PathBuf::from("/dev/sdz")]).is_err());
+        assert_eq!(
+            disks.get_partition_by_path(sdz3).unwrap().target,
+            Some(PathBuf::from("/recovery"))
+        );
+    }
+
+    #[test]
+    fn unused_regions() {
+        let disks = get_empty();
+        assert_eq!(disks.physical[0].get_unused_regions(), vec![(4096, 1953521071)]);
+
+        let mut disks = get_default();
+        assert!(disks.physical[0].get_unused_regions().is_empty());
+        disks.physical[0].remove_partition(3).unwrap();
+        assert_eq!(disks.physical[0].get_unused_regions(), vec![(420456448, 1936738303)]);
+    }
+
+    #[test]
+    fn bootloader_disk() {
+        let mut disks = get_default();
+        assert_eq!(disks.get_bootloader_disk(), None);
+        assert!(disks.set_bootloader_disk("/dev/sdy").is_err());
+        assert!(disks.set_bootloader_disk("/dev/sdz").is_ok());
+        assert_eq!(disks.get_bootloader_disk(), Some(Path::new("/dev/sdz")));
+
+        // The disk is kept, though nothing on it is modified.
+        disks.remove_untouched_disks();
+        assert_eq!(disks.get_physical_devices().len(), 1);
+    }
+
+    #[test]
+    fn esp() {
+        let mut disks = get_default();
+        let why = disks.get_esp().unwrap_err().to_string();
+        assert_eq!(why, "the EFI partition /dev/sdz1 does not have the ESP flag set");
+
+        disks.physical[0].partitions[0].flags.push(PartitionFlag::PED_PARTITION_ESP);
+        let (device, esp) = disks.get_esp().unwrap();
+        assert_eq!((device, esp.number), (Path::new("/dev/sdz"), 1));
+
+        disks.physical[0].partitions[1].target = Some(PathBuf::from("/boot/efi"));
+        let why = disks.get_esp().unwrap_err().to_string();
+        assert_eq!(why, "more than one partition is mounted at /boot/efi: /dev/sdz1, /dev/sdz2");
+    }
+
+    #[test]
+    fn keyless_encryptions() {
+        let mut disks = get_default();
+        let encryption = LvmEncryption::new("cryptdata".into(), None, None);
+        let root = &mut disks.physical[0].partitions[1];
+        root.set_volume_group("data".into(), Some(encryption)).unwrap();
+
+        // The volume is not created unless the partition is formatted.
+        assert!(disks.get_keyless_encryptions_mut().is_empty());
+
+        disks.physical[0].partitions[1].format_with(FileSystem::Lvm);
+        let keyless = disks.get_keyless_encryptions_mut();
+        assert_eq!(keyless.len(), 1);
+        assert_eq!(keyless[0].physical_volume, "cryptdata");
+
+        let passphrase = || Some(Zeroizing::new("passphrase".into()));
+        keyless.into_iter().for_each(|enc| enc.password = passphrase());
+        assert!(disks.get_keyless_encryptions_mut().is_empty());
+    }
+
+    #[test]
+    fn board_partitions() {
+        let mut disks = get_default();
+        assert!(disks.verify_board_partitions().is_err());
+
+        // The FAT partition is mounted at /boot rather than /boot/efi.
+        disks.physical[0].partitions[0].target = Some(PathBuf::from("/boot"));
+        assert!(disks.verify_board_partitions().is_ok());
+
+        disks.physical[0].partitions[0].filesystem = Some(FileSystem::Ext4);
+        let why = disks.verify_board_partitions().unwrap_err().to_string();
+        assert_eq!(
+            why,
+            "the /boot partition must be FAT, which the firmware of the board can read"
+        );
+    }
+
+    #[test]
+    fn prep() {
+        let layout = |sectors: u64| {
+            let mut disks = get_empty();
+            let prep = PartitionBuilder::new(2048, 2048 + sectors, FileSystem::Ext4)
+                .role(PartitionRole::Prep);
+            assert_eq!(prep.filesystem, None);
+            disks.physical[0].add_partition(prep).unwrap();
+            let root = root_part(2048 + sectors).mount(PathBuf::from("/"));
+            disks.physical[0].add_partition(root).unwrap();
+            disks
+        };
+
+        let disks = layout(16_384);
+        assert!(disks.verify_prep_partitions().is_ok());
+        assert_eq!(disks.get_prep().unwrap().1.start_sector, 2048);
+
+        // Open Firmware loads all of the partition, which must be from 8 to 16 MiB.
+        assert!(layout(65_536).verify_prep_partitions().is_err());
+        assert!(get_default().verify_prep_partitions().is_err());
+    }
+
     #[test]
     fn layout_diff() {
         let source = get_default().physical.into_iter().next().unwrap();
@@ -272,4 +441,156 @@ mod tests {
             .unwrap();
         assert!(source.validate_layout(&duplicate).is_ok());
     }
+
+    #[test]
+    fn logical_remainder() {
+        let mut device = LogicalDevice::new("data".into(), None, 2 * GIB20, 512, false);
+        let lv = |start: u64, end: u64, name: &str| {
+            PartitionBuilder::new(start, end, FileSystem::Ext4).name(name.into())
+        };
+
+        // The remainder is computed when the volume is added.
+        device.add_partition(lv(0, 1, "root").remainder()).unwrap();
+        let end = device.get_sector(Sector::End);
+        assert_eq!(end, device.get_partition("root").unwrap().get_sectors());
+
+        // Only one volume may claim the remaining space.
+        assert!(device.add_partition(lv(end, end + 1, "home").remainder()).is_err());
+
+        // Volumes added afterwards take their space from the remainder.
+        let start = device.get_last_sector();
+        device.add_partition(lv(start, start + GIB20, "home")).unwrap();
+        assert_eq!(end - (GIB20 - 1), device.get_partition("root").unwrap().get_sectors());
+        assert!(device.validate().is_ok());
+
+        // Which can't be given away entirely.
+        let start = device.get_last_sector();
+        assert!(device.add_partition(lv(start, start + GIB20, "var")).is_err());
+    }
+
+    #[test]
+    fn logical_extent_alignment() {
+        let mut device = LogicalDevice::new("data".into(), None, 2 * GIB20, 512, false);
+        let extent = device.get_pe_size_in_sectors();
+        let requests = [("root", 1_953_125), ("home", GIB20 + 1), ("var", 10_000)];
+
+        for &(name, length) in &requests {
+            device
+                .add_partition(PartitionBuilder::new(0, length, FileSystem::Ext4).name(name.into()))
+                .unwrap();
+        }
+
+        let mut previous_end = None;
+        for &(name, length) in &requests {
+            let partition = device.get_partition(name).unwrap();
+            let size = partition.get_sectors() + 1;
+
+            // Each volume is placed on an extent, after the previous volume.
+            assert_eq!(0, partition.start_sector % extent);
+            if let Some(end) = previous_end {
+                assert!(partition.start_sector > end);
+            }
+
+            // And its size matches the request within one extent.
+            assert_eq!(0, size % extent);
+            assert!(size >= length && size - length < extent);
+            previous_end = Some(partition.end_sector);
+        }
+    }
+
+    #[test]
+    fn logical_cache() {
+        let mut device = LogicalDevice::new("data".into(), None, 2 * GIB20, 512, false);
+        let root = PartitionBuilder::new(0, 1, FileSystem::Ext4).name("root".into()).remainder();
+        device.add_partition(root).unwrap();
+        let root = device.get_partition("root").unwrap().get_sectors();
+        let cache = GIB20 / 4;
+
+        // Caches may only be attached to volumes that exist, and must have room for metadata.
+        assert!(device.create_cache("home", cache, CacheMode::default()).is_err());
+        assert!(device.create_cache("root", 2048, CacheMode::default()).is_err());
+
+        // The cache pool takes its space from the volume which claims the remainder.
+        device.create_cache("root", cache, CacheMode::default()).unwrap();
+        assert_eq!(root - cache, device.get_partition("root").unwrap().get_sectors());
+        assert_eq!(CacheMode::Writethrough, device.get_cache("root").unwrap().mode);
+        assert!(device.validate().is_ok());
+    }
+
+    #[test]
+    fn logical_snapshot() {
+        let mut device = LogicalDevice::new("data".into(), None, 2 * GIB20, 512, true);
+        let extent = device.get_pe_size_in_sectors();
+
+        // An existing root of 30 GiB, which leaves 10 GiB of the volume group free.
+        let root = 3 * GIB20 / 2;
+        let lv = PartitionBuilder::new(0, root - 1, FileSystem::Ext4).name("root".into());
+        device.add_partition(lv).unwrap();
+        let partition = device.get_partition_mut("root").unwrap();
+        partition.bitflags = SOURCE;
+        partition.filesystem = None;
+        assert_eq!(2560, device.get_pe_unallocated());
+
+        // Snapshots may only be taken of existing volumes.
+        assert!(device.create_snapshot("home", "root-snap", 20).is_err());
+
+        // A snapshot which doesn't fit within the free extents is skipped.
+        assert!(!device.create_snapshot("root", "root-snap", 50).unwrap());
+        assert!(device.snapshots.is_empty());
+        assert_eq!(2560, device.get_pe_unallocated());
+
+        // Otherwise, it claims its share of the free extents.
+        assert!(device.create_snapshot("root", "root-snap", 20).unwrap());
+        assert_eq!(root / 5, device.snapshots[0].sectors);
+        assert_eq!(1024, device.get_pe_unallocated());
+        assert_eq!(root + root / 5, device.get_used());
+
+        // Which volumes may not grow into.
+        assert!(device.resize_lv("root", root + 1025 * extent).is_err());
+        device.resize_lv("root", root + 1024 * extent).unwrap();
+    }
+
+    #[test]
+    fn logical_resize() {
+        let mut device = LogicalDevice::new("data".into(), None, 2 * GIB20, 512, true);
+        let extent = device.get_pe_size_in_sectors();
+
+        // Two existing volumes, which leave four extents of the volume group free.
+        for &(name, length) in &[("root", GIB20), ("home", GIB20 - 4 * extent)] {
+            let lv = PartitionBuilder::new(0, length - 1, FileSystem::Ext4).name(name.into());
+            device.add_partition(lv).unwrap();
+            let partition = device.get_partition_mut(name).unwrap();
+            partition.bitflags = SOURCE;
+            partition.filesystem = None;
+        }
+
+        let root = device.get_partition("root").unwrap().get_sectors();
+        let home = device.get_partition("home").unwrap().get_sectors();
+        assert_eq!(4, device.get_pe_unallocated());
+
+        // Volumes may only grow into the free extents, and are aligned down to extents.
+        assert!(device.resize_lv("root", GIB20 + 5 * extent).is_err());
+        assert_eq!(GIB20 + 4 * extent, device.resize_lv("root", GIB20 + 4 * extent + 100).unwrap());
+        assert_eq!(0, device.get_pe_unallocated());
+
+        // Shrinking a volume frees extents for the others to grow into.
+        let shrunk = GIB20 - 1028 * extent;
+        assert_eq!(shrunk, device.resize_lv("home", shrunk + extent - 1).unwrap());
+        assert_eq!(1024, device.get_pe_unallocated());
+        assert!(device.resize_lv("root", GIB20 + 1029 * extent).is_err());
+        device.resize_lv("root", GIB20 + 1028 * extent).unwrap();
+        assert!(device.resize_lv("home", 2048).is_err());
+
+        // As does removing a volume.
+        device.remove_partition("home").unwrap();
+        assert!(device.resize_lv("home", shrunk).is_err());
+        let total = device.get_pe_total() * extent;
+        assert!(device.resize_lv("root", total + extent).is_err());
+        assert_eq!(total, device.resize_lv("root", total).unwrap());
+        assert_eq!(0, device.get_pe_unallocated());
+
+        // The original lengths are kept, to resize the volumes from.
+        assert_eq!(Some(&root), device.resized.get("root"));
+        assert_eq!(Some(&home), device.resized.get("home"));
+    }
 }
@@ -1,35 +1,64 @@
 use super::{
     FileSystem, LvmEncryption, PartitionFlag, PartitionIdentifiers, PartitionInfo, PartitionType,
-    FORMAT,
+    SwapEncryption, FORMAT, REMAINDER,
 };
 use std::path::PathBuf;
 
+/// The part which a new partition plays in booting the install.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PartitionRole {
+    /// The EFI System Partition, which holds the EFI loaders.
+    Esp,
+    /// The partition of a GPT disk which GRUB for BIOS embeds its core image in.
+    BiosGrub,
+    /// The PReP boot partition of POWER systems, which Open Firmware loads the stage1 of
+    /// GRUB from. It has no file system.
+    Prep,
+}
+
+impl PartitionRole {
+    /// The flag which marks the partition as having the role.
+    pub fn flag(self) -> PartitionFlag {
+        match self {
+            PartitionRole::Esp => PartitionFlag::PED_PARTITION_ESP,
+            PartitionRole::BiosGrub => PartitionFlag::PED_PARTITION_BIOS_GRUB,
+            PartitionRole::Prep => PartitionFlag::PED_PARTITION_PREP,
+        }
+    }
+}
+
 /// Partition builders are supplied as inputs to `Disk::add_partition`.
 pub struct PartitionBuilder {
-    pub start_sector: u64,
-    pub end_sector:   u64,
-    pub filesystem:   Option<FileSystem>,
-    pub part_type:    PartitionType,
-    pub name:         Option<String>,
-    pub flags:        Vec<PartitionFlag>,
-    pub mount:        Option<PathBuf>,
-    pub volume_group: Option<(String, Option<LvmEncryption>)>,
-    pub key_id:       Option<String>,
+    pub start_sector:    u64,
+    pub end_sector:      u64,
+    pub filesystem:      Option<FileSystem>,
+    pub part_type:       PartitionType,
+    pub name:            Option<String>,
+    pub flags:           Vec<PartitionFlag>,
+    pub mount:           Option<PathBuf>,
+    pub volume_group:    Option<(String, Option<LvmEncryption>)>,
+    pub key_id:          Option<String>,
+    pub encryption:      Option<LvmEncryption>,
+    pub swap_encryption: Option<SwapEncryption>,
+    pub remainder:       bool,
 }
 
 impl PartitionBuilder {
     /// Creates a new partition builder.
     pub fn new<O: Into<Option<FileSystem>>>(start: u64, end: u64, fs: O) -> PartitionBuilder {
         PartitionBuilder {
-            start_sector: start,
-            end_sector:   end - 1,
-            filesystem:   fs.into(),
-            part_type:    PartitionType::Primary,
-            name:         None,
-            flags:        Vec::new(),
-            mount:        None,
-            volume_group: None,
-            key_id:       None,
+            start_sector:    start,
+            end_sector:      end - 1,
+            filesystem:      fs.into(),
+            part_type:       PartitionType::Primary,
+            name:            None,
+            flags:           Vec::new(),
+            mount:           None,
+            volume_group:    None,
+            key_id:          None,
+            encryption:      None,
+            swap_encryption: None,
+            remainder:       false,
         }
     }
 
@@ -57,6 +86,16 @@ impl PartitionBuilder {
         self
     }
 
+    /// Marks the new partition with the flag of its role in booting the install. A PReP
+    /// partition is left without a file system, as GRUB writes to it directly.
+    pub fn role(mut self, role: PartitionRole) -> PartitionBuilder {
+        if role == PartitionRole::Prep {
+            self.filesystem = None;
+        }
+
+        self.flag(role.flag())
+    }
+
     /// Specifies where the new partition should be mounted.
     pub fn mount(mut self, mount: PathBuf) -> PartitionBuilder {
         self.mount = Some(mount);
@@ -74,6 +113,18 @@ impl PartitionBuilder {
         self
     }
 
+    /// Encrypts the file system of a new logical volume with LUKS.
+    pub fn encryption(mut self, encryption: LvmEncryption) -> PartitionBuilder {
+        self.encryption = Some(encryption);
+        self
+    }
+
+    /// Encrypts a new swap partition with the given mode, instead of the default.
+    pub fn swap_encryption(mut self, mode: SwapEncryption) -> PartitionBuilder {
+        self.swap_encryption = Some(mode);
+        self
+    }
+
     /// Defines that this partition will store the keyfile of the given ID(s),
     /// at the target mount point.
     pub fn associate_keyfile(mut self, id: String) -> PartitionBuilder {
@@ -81,15 +132,24 @@ impl PartitionBuilder {
         self
     }
 
+    /// Defines that this logical volume will consume all of the space that remains in its
+    /// volume group, after all other logical volumes have been allocated.
+    ///
+    /// The end sector will be calculated when the partition is added to the volume group.
+    pub fn remainder(mut self) -> PartitionBuilder {
+        self.remainder = true;
+        self
+    }
+
     /// Builds a brand new Partition from the current state of the builder.
     pub fn build(self) -> PartitionInfo {
         PartitionInfo {
-            bitflags:     FORMAT,
-            number:       -1,
-            start_sector: self.start_sector,
-            end_sector:   self.end_sector,
-            part_type:    self.part_type,
-            filesystem:   if self.volume_group.is_some() {
+            bitflags:        if self.remainder { FORMAT | REMAINDER } else { FORMAT },
+            number:          -1,
+            start_sector:    self.start_sector,
+            end_sector:      self.end_sector,
+            part_type:       self.part_type,
+            filesystem:      if self.volume_group.is_some() {
                 if self.volume_group.as_ref().unwrap().1.is_some() {
                     Some(FileSystem::Luks)
                 } else {
@@ -98,16 +158,18 @@ impl PartitionBuilder {
             } else {
                 self.filesystem
             },
-            flags:        self.flags,
-            name:         self.name,
-            device_path:  PathBuf::new(),
-            mount_point:  None,
-            ordering:     -1,
-            target:       self.mount,
-            original_vg:  None,
-            volume_group: self.volume_group.clone(),
-            key_id:       self.key_id,
-            identifiers:  PartitionIdentifiers::default(),
+            flags:           self.flags,
+            name:            self.name,
+            device_path:     PathBuf::new(),
+            mount_point:     None,
+            ordering:        -1,
+            target:          self.mount,
+            original_vg:     None,
+            volume_group:    self.volume_group.clone(),
+            key_id:          self.key_id,
+            encryption:      self.encryption,
+            swap_encryption: self.swap_encryption,
+            identifiers:     PartitionIdentifiers::default(),
         }
     }
 }
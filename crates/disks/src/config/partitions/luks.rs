@@ -0,0 +1,125 @@
+use super::PartitionInfo;
+use crate::{
+    detect_fs_on_device,
+    external::{cryptsetup_close, cryptsetup_open, pvs, settle_nodes, CloseBy},
+    DecryptionError, DiskExt, FileSystem, LogicalDevice, LvmEncryption,
+};
+use disk_types::BlockDeviceExt;
+use partition_identity::PartitionID;
+use std::{path::PathBuf, time::Duration};
+
+/// How long the device map of an opened container is waited on.
+const DEVICE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A LUKS partition which has been opened, so that its contents may be probed and reused.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UnlockedDevice {
+    /// The path of the LUKS partition.
+    pub parent:       PathBuf,
+    /// The path of the device map that the container was opened to.
+    pub path:         PathBuf,
+    /// The settings that the container was opened with.
+    pub encryption:   LvmEncryption,
+    /// The volume group of the physical volume within the container, if it contains one.
+    pub volume_group: Option<String>,
+    /// The file system within the container, if it does not contain a physical volume.
+    pub file_system:  Option<PartitionInfo>,
+}
+
+impl UnlockedDevice {
+    /// Creates the logical device which represents the contents of the container, which is
+    /// `sectors` in length, measured in sectors of `sector_size` bytes.
+    pub fn to_logical_device(&self, sectors: u64, sector_size: u64) -> LogicalDevice {
+        let name = match self.volume_group {
+            Some(ref vg) => vg.clone(),
+            None => self.encryption.physical_volume.clone(),
+        };
+
+        let mut device =
+            LogicalDevice::new(name, Some(self.encryption.clone()), sectors, sector_size, true);
+
+        if let Some(ref fs) = self.file_system {
+            // The device map of the container is not named after a volume group.
+            device.device_path = self.path.clone();
+            device.set_file_system(fs.clone());
+        }
+
+        info!("settings luks_parent to {:?}", self.parent);
+        device.set_luks_parent(self.parent.clone());
+        device
+    }
+}
+
+impl PartitionInfo {
+    /// The name of the device map that this LUKS partition will be opened to when unlocked.
+    ///
+    /// As with `systemd-cryptsetup`, the name is derived from the UUID of the container, so
+    /// that the container will always be mapped to the same name.
+    pub fn luks_mapper_name(&self) -> Option<String> {
+        self.identifiers
+            .uuid
+            .clone()
+            .or_else(|| PartitionID::get_uuid(self.get_device_path()).map(|id| id.id))
+            .map(|uuid| ["luks-", &uuid].concat())
+    }
+
+    /// Unlocks this LUKS partition with the given secret, so that its contents may be reused.
+    ///
+    /// The container is opened to a device map named by `luks_mapper_name()`.
+    pub fn unlock_luks(&self, secret: &str) -> Result<UnlockedDevice, DecryptionError> {
+        let device = self.get_device_path();
+        let name = match self.luks_mapper_name() {
+            Some(ref name) if self.filesystem == Some(FileSystem::Luks) => name.clone(),
+            _ => return Err(DecryptionError::LuksNotFound { device: device.to_path_buf() }),
+        };
+
+        self.open_luks(&LvmEncryption::new(name, Some(secret.to_owned()), None))
+    }
+
+    /// Opens this LUKS partition with the given settings, and probes its contents.
+    ///
+    /// At the moment, only LVM on LUKS, or a file system on LUKS, are supported.
+    pub fn open_luks(&self, enc: &LvmEncryption) -> Result<UnlockedDevice, DecryptionError> {
+        let device = self.get_device_path();
+
+        // Attempt to decrypt the device.
+        cryptsetup_open(device, &enc)
+            .map_err(|why| DecryptionError::Open { device: device.to_path_buf(), why })?;
+
+        // Determine which VG the newly-decrypted device belongs to.
+        let pv = PathBuf::from(["/dev/mapper/", &enc.physical_volume].concat());
+        info!("which belongs to PV {:?}", pv);
+        settle_nodes(&[&pv], DEVICE_TIMEOUT)
+            .map_err(|why| DecryptionError::Open { device: device.to_path_buf(), why })?;
+
+        let mut unlocked = UnlockedDevice {
+            parent:       device.to_path_buf(),
+            path:         pv.clone(),
+            encryption:   enc.clone(),
+            volume_group: None,
+            file_system:  None,
+        };
+
+        let volume_group = pvs()
+            .map_err(|why| DecryptionError::Open { device: device.to_path_buf(), why })?
+            .remove(&pv)
+            .and_then(|vg| vg);
+
+        if let Some(vg) = volume_group {
+            unlocked.volume_group = Some(vg);
+            return Ok(unlocked);
+        }
+
+        // Detect a file system on the device
+        if let Some(fs) = detect_fs_on_device(&pv) {
+            unlocked.file_system = Some(fs);
+            return Ok(unlocked);
+        }
+
+        // Attempt to close the device as we've failed to find a VG.
+        let _ = cryptsetup_close(CloseBy::Path(&pv));
+
+        // NOTE: Should we handle this in some way?
+        Err(DecryptionError::DecryptedLacksVG { device: device.to_path_buf() })
+    }
+}
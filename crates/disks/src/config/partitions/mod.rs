@@ -1,13 +1,17 @@
 mod builder;
+mod luks;
 
-pub use self::builder::PartitionBuilder;
+pub use self::{
+    builder::{PartitionBuilder, PartitionRole},
+    luks::UnlockedDevice,
+};
 use super::{
-    super::{LvmEncryption, PartitionError},
+    super::{validate_volume_group, DiskError, LvmEncryption, PartitionError},
     PVS,
 };
 pub use disk_types::{BlockDeviceExt, FileSystem, PartitionExt, PartitionType, SectorExt};
 use crate::external::{get_label, is_encrypted};
-use fstab_generate::BlockInfo;
+use fstab_generate::{BlockInfo, FstabId};
 use libparted::{Partition, PartitionFlag};
 pub use os_detect::OS;
 use partition_identity::PartitionIdentifiers;
@@ -41,49 +45,71 @@ pub const ACTIVE: u8 = 0b00_1000;
 pub const BUSY: u8 = 0b01_0000;
 // Defines that this partition is currently swapped.
 pub const SWAPPED: u8 = 0b10_0000;
+// Defines that this logical volume will consume the remaining space in its volume group.
+pub const REMAINDER: u8 = 0b100_0000;
+
+/// Modes of encryption that may be applied to a swap partition.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum SwapEncryption {
+    /// Swap is encrypted with a throwaway key from `/dev/urandom`, and recreated on every boot.
+    ///
+    /// The swap signature will not be written at install time, and hibernation is not possible.
+    Random,
+}
 
 /// Contains relevant information about a certain partition.
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct PartitionInfo {
-    pub bitflags:     u8,
+    pub bitflags:        u8,
     /// The partition number is the numeric value that follows the disk's device path.
     /// IE: _/dev/sda1_
-    pub number:       i32,
+    pub number:          i32,
     /// The physical order of the partition on the disk, as partition numbers may not be in order.
-    pub ordering:     i32,
+    pub ordering:        i32,
     /// The initial sector where the partition currently, or will, reside.
-    pub start_sector: u64,
+    pub start_sector:    u64,
     /// The final sector where the partition currently, or will, reside.
     /// # Note
     /// The length of the partion can be calculated by substracting the `end_sector`
     /// from the `start_sector`, and multiplying that by the value of the disk's
     /// sector size.
-    pub end_sector:   u64,
+    pub end_sector:      u64,
     /// Whether this partition is a primary or logical partition.
-    pub part_type:    PartitionType,
+    pub part_type:       PartitionType,
     /// Whether there is a file system currently, or will be, on this partition.
-    pub filesystem:   Option<FileSystem>,
+    pub filesystem:      Option<FileSystem>,
     /// Specifies optional flags that should be applied to the partition, if
     /// not already set.
-    pub flags:        Vec<PartitionFlag>,
+    #[cfg_attr(feature = "serde", serde(with = "crate::serialize::partition_flags"))]
+    pub flags:           Vec<PartitionFlag>,
     /// Specifies the name of the partition.
-    pub name:         Option<String>,
+    pub name:            Option<String>,
     /// Contains the device path of the partition, which is the disk's device path plus
     /// the partition number.
-    pub device_path:  PathBuf,
+    pub device_path:     PathBuf,
     /// Where this partition is mounted in the file system, if at all.
-    pub mount_point:  Option<PathBuf>,
+    pub mount_point:     Option<PathBuf>,
     /// Where this partition will be mounted in the future
-    pub target:       Option<PathBuf>,
+    pub target:          Option<PathBuf>,
     /// The pre-existing volume group assigned to this partition.
-    pub original_vg:  Option<String>,
+    pub original_vg:     Option<String>,
     /// The volume group & LUKS configuration to associate with this device.
     // TODO: Separate the tuple?
-    pub volume_group: Option<(String, Option<LvmEncryption>)>,
+    pub volume_group:    Option<(String, Option<LvmEncryption>)>,
     /// If the partition is associated with a keyfile, this will name the key.
-    pub key_id:       Option<String>,
+    pub key_id:          Option<String>,
+    /// The LUKS configuration of a logical volume which contains an encrypted file system.
+    pub encryption:      Option<LvmEncryption>,
+    /// How a swap partition will be encrypted, if it differs from the default.
+    ///
+    /// By default, swap which is not within an encrypted volume group is encrypted with
+    /// a random key on every boot.
+    pub swap_encryption: Option<SwapEncryption>,
     /// Possible identifiers for this partition.
-    pub identifiers:  PartitionIdentifiers,
+    #[cfg_attr(feature = "serde", serde(with = "crate::serialize::PartitionIdentifiersDef"))]
+    pub identifiers:     PartitionIdentifiers,
 }
 
 impl BlockDeviceExt for PartitionInfo {
@@ -144,6 +170,8 @@ impl PartitionInfo {
             original_vg: None,
             volume_group: None,
             key_id: None,
+            encryption: None,
+            swap_encryption: None,
             identifiers,
         }))
     }
@@ -222,8 +250,34 @@ impl PartitionInfo {
     /// Optionally, this partition may be encrypted, in which you will also need to
     /// specify a new physical volume name as well. In the event of encryption, an LVM
     /// device will be assigned to the encrypted partition.
-    pub fn set_volume_group(&mut self, group: String, encryption: Option<LvmEncryption>) {
+    ///
+    /// The names of the volume group and physical volume are validated beforehand.
+    pub fn set_volume_group(
+        &mut self,
+        group: String,
+        encryption: Option<LvmEncryption>,
+    ) -> Result<(), DiskError> {
+        validate_volume_group(&group, encryption.as_ref())?;
         self.volume_group = Some((group, encryption));
+        Ok(())
+    }
+
+    /// Defines that the file system on this logical volume will be contained within a
+    /// LUKS device, which will be opened as the physical volume named by the encryption.
+    pub fn set_encryption(&mut self, encryption: LvmEncryption) {
+        self.encryption = Some(encryption);
+    }
+
+    /// Defines how this swap partition will be encrypted.
+    pub fn set_swap_encryption(&mut self, mode: SwapEncryption) {
+        self.swap_encryption = Some(mode);
+    }
+
+    /// True if this is a swap partition which will be created at boot with a random key.
+    ///
+    /// Such partitions must not be formatted with `mkswap` at install time.
+    pub fn is_random_swap(&self) -> bool {
+        self.is_swap() && self.swap_encryption == Some(SwapEncryption::Random)
     }
 
     /// Shrinks the partition, if possible.
@@ -264,14 +318,17 @@ impl PartitionInfo {
 
     /// Obtains bock information for the partition, if possible, for use with
     /// generating entries in "/etc/fstab".
-    pub fn get_block_info(&self) -> Option<BlockInfo> {
+    pub fn get_block_info(&self) -> Option<BlockInfo> { self.get_block_info_with(FstabId::Uuid) }
+
+    /// Obtains block information for the partition, identifying it with the given strategy.
+    pub fn get_block_info_with(&self, id: FstabId) -> Option<BlockInfo> {
         let fs = self.get_file_system()?;
         if fs != FileSystem::Swap && self.target.is_none() {
             return None;
         }
 
         Some(BlockInfo::new(
-            BlockInfo::get_partition_id(&self.device_path, fs)?,
+            id.partition_id(&self.device_path, fs)?,
             fs,
             self.target.as_deref(),
             get_preferred_options(fs),
@@ -311,117 +368,124 @@ fn get_flags(partition: &Partition) -> Vec<PartitionFlag> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::DecryptionError;
     use std::path::Path;
 
     fn efi_partition() -> PartitionInfo {
         PartitionInfo {
-            bitflags:     ACTIVE | BUSY | SOURCE,
-            device_path:  Path::new("/dev/sdz1").to_path_buf(),
-            flags:        vec![PartitionFlag::PED_PARTITION_ESP],
-            mount_point:  Some(Path::new("/boot/efi").to_path_buf()),
-            target:       Some(Path::new("/boot/efi").to_path_buf()),
-            start_sector: 2048,
-            end_sector:   1026047,
-            filesystem:   Some(FileSystem::Fat16),
-            name:         None,
-            number:       1,
-            ordering:     1,
-            part_type:    PartitionType::Primary,
-            key_id:       None,
-            original_vg:  None,
-            volume_group: None,
-            identifiers:  PartitionIdentifiers::default(),
+            bitflags:        ACTIVE | BUSY | SOURCE,
+            device_path:     Path::new("/dev/sdz1").to_path_buf(),
+            flags:           vec![PartitionFlag::PED_PARTITION_ESP],
+            mount_point:     Some(Path::new("/boot/efi").to_path_buf()),
+            target:          Some(Path::new("/boot/efi").to_path_buf()),
+            start_sector:    2048,
+            end_sector:      1026047,
+            filesystem:      Some(FileSystem::Fat16),
+            name:            None,
+            number:          1,
+            ordering:        1,
+            part_type:       PartitionType::Primary,
+            key_id:          None,
+            encryption:      None,
+            swap_encryption: None,
+            original_vg:     None,
+            volume_group:    None,
+            identifiers:     PartitionIdentifiers::default(),
         }
     }
 
     fn root_partition() -> PartitionInfo {
         PartitionInfo {
-            bitflags:     ACTIVE | BUSY | SOURCE,
-            device_path:  Path::new("/dev/sdz2").to_path_buf(),
-            flags:        vec![],
-            mount_point:  Some(Path::new("/").to_path_buf()),
-            target:       Some(Path::new("/").to_path_buf()),
-            start_sector: 1026048,
-            end_sector:   420456447,
-            filesystem:   Some(FileSystem::Btrfs),
-            name:         Some("Pop!_OS".into()),
-            number:       2,
-            ordering:     2,
-            part_type:    PartitionType::Primary,
-            key_id:       None,
-            original_vg:  None,
-            volume_group: None,
-            identifiers:  PartitionIdentifiers::default(),
+            bitflags:        ACTIVE | BUSY | SOURCE,
+            device_path:     Path::new("/dev/sdz2").to_path_buf(),
+            flags:           vec![],
+            mount_point:     Some(Path::new("/").to_path_buf()),
+            target:          Some(Path::new("/").to_path_buf()),
+            start_sector:    1026048,
+            end_sector:      420456447,
+            filesystem:      Some(FileSystem::Btrfs),
+            name:            Some("Pop!_OS".into()),
+            number:          2,
+            ordering:        2,
+            part_type:       PartitionType::Primary,
+            key_id:          None,
+            encryption:      None,
+            swap_encryption: None,
+            original_vg:     None,
+            volume_group:    None,
+            identifiers:     PartitionIdentifiers::default(),
         }
     }
 
     fn luks_on_lvm_partition() -> PartitionInfo {
         PartitionInfo {
-            bitflags:     ACTIVE | SOURCE,
-            device_path:  Path::new("/dev/sdz3").to_path_buf(),
-            flags:        vec![],
-            mount_point:  None,
-            target:       None,
-            start_sector: 420456448,
-            end_sector:   1936738303,
-            filesystem:   Some(FileSystem::Luks),
-            name:         None,
-            number:       4,
-            ordering:     4,
-            part_type:    PartitionType::Primary,
-            key_id:       None,
-            original_vg:  None,
-            identifiers:  PartitionIdentifiers::default(),
-            volume_group: Some((
+            bitflags:        ACTIVE | SOURCE,
+            device_path:     Path::new("/dev/sdz3").to_path_buf(),
+            flags:           vec![],
+            mount_point:     None,
+            target:          None,
+            start_sector:    420456448,
+            end_sector:      1936738303,
+            filesystem:      Some(FileSystem::Luks),
+            name:            None,
+            number:          4,
+            ordering:        4,
+            part_type:       PartitionType::Primary,
+            key_id:          None,
+            encryption:      None,
+            swap_encryption: None,
+            original_vg:     None,
+            identifiers:     PartitionIdentifiers::default(),
+            volume_group:    Some((
                 "LVM_GROUP".into(),
-                Some(LvmEncryption {
-                    physical_volume: "LUKS_PV".into(),
-                    password:        Some("password".into()),
-                    keydata:         None,
-                }),
+                Some(LvmEncryption::new("LUKS_PV".into(), Some("password".into()), None)),
             )),
         }
     }
 
     fn lvm_partition() -> PartitionInfo {
         PartitionInfo {
-            bitflags:     ACTIVE | SOURCE,
-            device_path:  Path::new("/dev/sdz3").to_path_buf(),
-            flags:        vec![],
-            mount_point:  None,
-            target:       None,
-            start_sector: 420456448,
-            end_sector:   1936738303,
-            filesystem:   Some(FileSystem::Lvm),
-            name:         None,
-            number:       4,
-            ordering:     4,
-            part_type:    PartitionType::Primary,
-            key_id:       None,
-            original_vg:  None,
-            volume_group: Some(("LVM_GROUP".into(), None)),
-            identifiers:  PartitionIdentifiers::default(),
+            bitflags:        ACTIVE | SOURCE,
+            device_path:     Path::new("/dev/sdz3").to_path_buf(),
+            flags:           vec![],
+            mount_point:     None,
+            target:          None,
+            start_sector:    420456448,
+            end_sector:      1936738303,
+            filesystem:      Some(FileSystem::Lvm),
+            name:            None,
+            number:          4,
+            ordering:        4,
+            part_type:       PartitionType::Primary,
+            key_id:          None,
+            encryption:      None,
+            swap_encryption: None,
+            original_vg:     None,
+            volume_group:    Some(("LVM_GROUP".into(), None)),
+            identifiers:     PartitionIdentifiers::default(),
         }
     }
 
     fn swap_partition() -> PartitionInfo {
         PartitionInfo {
-            bitflags:     ACTIVE | SOURCE,
-            device_path:  Path::new("/dev/sdz4").to_path_buf(),
-            flags:        vec![],
-            mount_point:  None,
-            target:       None,
-            start_sector: 1936738304,
-            end_sector:   1953523711,
-            filesystem:   Some(FileSystem::Swap),
-            name:         None,
-            number:       4,
-            ordering:     4,
-            part_type:    PartitionType::Primary,
-            key_id:       None,
-            original_vg:  None,
-            volume_group: None,
-            identifiers:  PartitionIdentifiers::default(),
+            bitflags:        ACTIVE | SOURCE,
+            device_path:     Path::new("/dev/sdz4").to_path_buf(),
+            flags:           vec![],
+            mount_point:     None,
+            target:          None,
+            start_sector:    1936738304,
+            end_sector:      1953523711,
+            filesystem:      Some(FileSystem::Swap),
+            name:            None,
+            number:          4,
+            ordering:        4,
+            part_type:       PartitionType::Primary,
+            key_id:          None,
+            encryption:      None,
+            swap_encryption: None,
+            original_vg:     None,
+            volume_group:    None,
+            identifiers:     PartitionIdentifiers::default(),
         }
     }
 
@@ -480,4 +544,46 @@ mod tests {
         assert!(root.is_same_partition_as(&root_dup));
         assert!(!root.is_same_partition_as(&efi));
     }
+
+    #[test]
+    fn partition_luks_mapper_name() {
+        let mut luks = luks_on_lvm_partition();
+        luks.identifiers.uuid = Some("0b7e4f2c".into());
+        assert_eq!(Some("luks-0b7e4f2c".into()), luks.luks_mapper_name());
+
+        // Only LUKS partitions may be unlocked.
+        let mut root = root_partition();
+        root.identifiers.uuid = Some("5c1d9a30".into());
+        match root.unlock_luks("password") {
+            Err(DecryptionError::LuksNotFound { device }) => assert_eq!(root.device_path, device),
+            other => panic!("unexpected unlock result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn unlocked_device_to_logical_device() {
+        let mut unlocked = UnlockedDevice {
+            parent:       Path::new("/dev/sdz3").to_path_buf(),
+            path:         Path::new("/dev/mapper/luks-0b7e4f2c").to_path_buf(),
+            encryption:   LvmEncryption::new("luks-0b7e4f2c".into(), Some("password".into()), None),
+            volume_group: Some("data".into()),
+            file_system:  None,
+        };
+
+        // A container of a physical volume is represented by its volume group.
+        let device = unlocked.to_logical_device(1024, 4096);
+        assert_eq!("data", device.volume_group);
+        assert_eq!(4096, device.get_logical_block_size());
+        assert_eq!(Some(Path::new("/dev/sdz3")), device.luks_parent.as_deref());
+        assert!(device.file_system.is_none());
+
+        // And a container of a file system by its device map.
+        unlocked.volume_group = None;
+        unlocked.file_system = Some(root_partition());
+        let device = unlocked.to_logical_device(1024, 512);
+        assert_eq!("luks-0b7e4f2c", device.volume_group);
+        assert_eq!(unlocked.path, device.device_path);
+        assert_eq!(Some(Path::new("/dev/sdz3")), device.luks_parent.as_deref());
+        assert!(device.file_system.is_some());
+    }
 }
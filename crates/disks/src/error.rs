@@ -1,13 +1,23 @@
 pub use disk_types::PartitionSizeError;
 use disk_types::{FileSystem, PartitionTableError};
 use std::{io, path::PathBuf};
+use crate::{NameError, StrengthScore};
 
 /// Defines a variety of errors that may arise from configuring and committing changes to disks.
+///
+/// Each variant has a stable code, and a key that translations of its message are looked up
+/// by, in `DISK_ERRORS`.
 #[rustfmt::skip]
 #[derive(Debug, Fail)]
 pub enum DiskError {
+    #[fail(display = "cached volume group '{}' must span rotational and solid-state devices", vg)]
+    CacheDevices { vg: String },
+    #[fail(display = "cache pool for logical volume '{}' is too small", volume)]
+    CacheTooSmall { volume: String },
     #[fail(display = "decryption error: {}", why)]
     Decryption { why: DecryptionError },
+    #[fail(display = "the bootloader disk {:?} is not one of the configured disks", device)]
+    BootloaderDiskNotFound { device: PathBuf },
     #[fail(display = "unable to get device at {:?}: {}", device, why)]
     DeviceGet { device: PathBuf, why: io::Error },
     #[fail(display = "unable to probe for devices")]
@@ -26,10 +36,18 @@ pub enum DiskError {
     Encryption { volume: PathBuf, why: io::Error },
     #[fail(display = "unable to open encrypted volume '{:?}': {}", volume, why)]
     EncryptionOpen { volume: PathBuf, why: io::Error },
+    #[fail(display = "encrypted logical volume on {:?} lacks a file system", device)]
+    EncryptedVolumeLacksFs { device: PathBuf },
     #[fail(display = "problem executing external command: {}", why)]
     ExternalCommand { why: io::Error },
+    #[fail(display = "invalid logical volume name '{}': {}", name, why)]
+    InvalidLogicalVolumeName { name: String, why: NameError },
+    #[fail(display = "invalid LUKS device map name '{}': {}", name, why)]
+    InvalidMapperName { name: String, why: NameError },
     #[fail(display = "serial model does not match")]
     InvalidSerial,
+    #[fail(display = "invalid volume group name '{}': {}", name, why)]
+    InvalidVolumeGroupName { name: String, why: NameError },
     #[fail(display = "{}", why)]
     IO { why: io::Error },
     #[fail(display = "failed to create partition geometry: {}", why)]
@@ -38,6 +56,12 @@ pub enum DiskError {
     GeometryDuplicate,
     #[fail(display = "failed to set values on partition geometry")]
     GeometrySet,
+    #[fail(display = "LUKS header of {} may not be stored on the volume it encrypts", volume)]
+    HeaderOnEncryptedVolume { volume: String },
+    #[fail(display = "LUKS header at {:?} is not on a mounted physical partition", path)]
+    HeaderWithoutPartition { path: PathBuf },
+    #[fail(display = "{} bytes of swap is too small to hibernate {} bytes of memory", swap, memory)]
+    HibernationSwapTooSmall { memory: u64, swap: u64 },
     #[fail(display = "the root partition may not be contained on a key-encrypted volume")]
     KeyContainsRoot,
     #[fail(display = "LUKS key path was already set for {}", id)]
@@ -70,6 +94,22 @@ pub enum DiskError {
     PartitionTable { why: PartitionTableError },
     #[fail(display = "unable to create physical volume from '{}': {}", volume, why)]
     PhysicalVolumeCreate { volume: String, why: io::Error },
+    #[fail(display = "swap on {:?} is already encrypted, and can't use a random key", device)]
+    RandomSwapEncrypted { device: PathBuf },
+    #[fail(display = "swap on {:?} uses a random key, and can't be resumed from", device)]
+    RandomSwapHibernation { device: PathBuf },
+    #[fail(display = "recovery partition on {:?} must be formatted as FAT32", device)]
+    RecoveryFileSystem { device: PathBuf },
+    #[fail(display = "recovery partition on {:?} was not found", device)]
+    RecoveryNotFound { device: PathBuf },
+    #[fail(display = "recovery source {:?} is on {:?}, which is erased", path, device)]
+    RecoverySourceErased { path: PathBuf, device: PathBuf },
+    #[fail(display = "recovery partition on {:?} is mounted elsewhere than /recovery", device)]
+    RecoveryTarget { device: PathBuf },
+    #[fail(display = "recovery partition of {} bytes is too small for {} bytes", size, required)]
+    RecoveryTooSmall { size: u64, required: u64 },
+    #[fail(display = "more than one logical volume on {:?} claims the remaining space", device)]
+    RemainderClaimed { device: PathBuf },
     #[fail(display = "multiple devices had the same volume group: currently unsupported")]
     SameGroup,
     #[fail(display = "sector overlaps partition {}", id)]
@@ -80,8 +120,15 @@ pub enum DiskError {
     Unmount { device: PathBuf, why: io::Error },
     #[fail(display = "unable to create volume group '{}' on {:?}: {}", vg, device, why)]
     VolumeGroupCreate { device: PathBuf, vg: String, why: io::Error },
+    #[fail(
+        display = "volume group '{}' already exists on a device that will be kept; try '{}'",
+        vg, suggestion
+    )]
+    VgNameConflict { vg: String, suggestion: String },
     #[fail(display = "logical partition on {:?} lacks a label", device)]
     VolumePartitionLacksLabel { device: PathBuf },
+    #[fail(display = "passphrase of {} is {}, but must be at least {}", volume, strength, minimum)]
+    WeakPassphrase { volume: String, strength: StrengthScore, minimum: StrengthScore },
 }
 
 #[derive(Debug, Fail)]
@@ -107,6 +154,11 @@ pub enum PartitionError {
     ResizeTooSmall,
     #[fail(display = "shrink value too high")]
     ShrinkValueTooHigh,
+    #[fail(
+        display = "cannot shrink below file system usage (size: {} sectors, min: {} sectors)",
+        size, min
+    )]
+    ShrinkBelowUsage { size: u64, min: u64 },
     #[fail(display = "shrinking not supported for {:?}", fs)]
     UnsupportedShrinking { fs: FileSystem },
 }
@@ -137,7 +189,8 @@ impl From<io::Error> for DiskError {
 
 impl From<DiskError> for io::Error {
     fn from(err: DiskError) -> io::Error {
-        io::Error::new(io::ErrorKind::Other, format!("an I/O error occurred: {}", err))
+        let message = format!("an I/O error occurred: {}", err);
+        misc::cause_error(io::ErrorKind::Other, err.error_cause(), message)
     }
 }
 
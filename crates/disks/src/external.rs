@@ -5,14 +5,15 @@ pub use external_::*;
 use misc;
 use proc_mounts::{MountList, SwapList};
 use std::{
-    fs::Permissions,
+    ffi::OsString,
+    fs::{self, Permissions},
     io::{self, Read, Write},
     os::unix::fs::PermissionsExt,
-    path::Path,
+    path::{Path, PathBuf},
 };
 use sys_mount::*;
 use tempdir::TempDir;
-use crate::LvmEncryption;
+use crate::{KdfParams, LvmEncryption};
 
 fn remove_encrypted_device(device: &Path) -> io::Result<()> {
     let mounts = MountList::new().expect("failed to get mounts in deactivate_device_maps");
@@ -58,6 +59,41 @@ fn remove_encrypted_device(device: &Path) -> io::Result<()> {
     Ok(())
 }
 
+/// Mounts the partition that stores the detached LUKS header of the volume, if it has one.
+///
+/// Returns the mount, the directory it was mounted to, and the path of the header within.
+/// The mount must be dropped before the directory.
+fn mount_header(
+    enc: &LvmEncryption,
+) -> io::Result<Option<(UnmountDrop<Mount>, TempDir, PathBuf)>> {
+    let header = match enc.header {
+        Some(ref header) => header,
+        None => return Ok(None),
+    };
+
+    let (device, relative) = match (header.partition.as_ref(), header.relative_path()) {
+        (Some(&(ref device, _)), Some(relative)) => (device, relative),
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("partition of the LUKS header of {} is unresolved", enc.physical_volume),
+            ))
+        }
+    };
+
+    let tmpfs = TempDir::new("distinst")?;
+    let supported = SupportedFilesystems::new()?;
+    let mount = Mount::new(device, tmpfs.path(), &supported, MountFlags::empty(), None)?
+        .into_unmount_drop(UnmountFlags::DETACH);
+    let path = tmpfs.path().join(relative);
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    Ok(Some((mount, tmpfs, path)))
+}
+
 /// Creates a LUKS partition from a physical partition. This could be either a LUKS on LVM
 /// configuration, or a LVM on LUKS configurations.
 pub fn cryptsetup_encrypt(device: &Path, enc: &LvmEncryption) -> io::Result<()> {
@@ -65,21 +101,26 @@ pub fn cryptsetup_encrypt(device: &Path, enc: &LvmEncryption) -> io::Result<()>
 
     info!("cryptsetup is encrypting {} with {:?}", device.display(), enc);
 
-    match (enc.password.as_ref(), enc.keydata.as_ref()) {
+    let kdf = KdfParams::detect();
+    info!("cryptsetup KDF parameters for {}: {}", device.display(), kdf);
+
+    let header = mount_header(enc)?;
+    let mut args: Vec<OsString> =
+        vec!["-s".into(), "512".into(), "luksFormat".into(), "--type".into(), "luks2".into()];
+    args.extend(kdf.args());
+
+    if let Some((_, _, ref path)) = header {
+        args.push("--header".into());
+        args.push(path.into());
+    }
+
+    args.push(device.into());
+
+    let result = match (enc.password.as_ref(), enc.keydata.as_ref()) {
         (Some(_password), Some(_keydata)) => unimplemented!(),
-        (Some(password), None) => exec(
-            "cryptsetup",
-            Some(&append_newline(password.as_bytes())),
-            None,
-            &[
-                "-s".into(),
-                "512".into(),
-                "luksFormat".into(),
-                "--type".into(),
-                "luks2".into(),
-                device.into(),
-            ],
-        ),
+        (Some(password), None) => {
+            exec("cryptsetup", Some(&append_newline(password.as_bytes())), None, &args)
+        }
         (None, Some(&(_, ref keydata))) => {
             let keydata = keydata.as_ref().expect("field should have been populated");
             let tmpfs = TempDir::new("distinst")?;
@@ -91,23 +132,28 @@ pub fn cryptsetup_encrypt(device: &Path, enc: &LvmEncryption) -> io::Result<()>
             generate_keyfile(&keypath)?;
             info!("keypath exists: {}", keypath.is_file());
 
-            exec(
-                "cryptsetup",
-                None,
-                None,
-                &[
-                    "-s".into(),
-                    "512".into(),
-                    "luksFormat".into(),
-                    "--type".into(),
-                    "luks2".into(),
-                    device.into(),
-                    tmpfs.path().join(&enc.physical_volume).into(),
-                ],
-            )
+            args.push(keypath.into());
+            exec("cryptsetup", None, None, &args)
         }
-        (None, None) => unimplemented!(),
+        (None, None) => Err(keyless(&enc.physical_volume)),
+    };
+
+    if let (true, Some(ref header)) = (result.is_ok(), enc.header.as_ref()) {
+        info!(
+            "LUKS header of {} was written to {}, which the initramfs must be able to read \
+             before {} can be unlocked at boot",
+            device.display(),
+            header.path.display(),
+            enc.physical_volume
+        );
+        warn!(
+            "the partition mounted at {} must be available to the initramfs, and the header \
+             must be backed up: the volume cannot be recovered without it",
+            header.partition.as_ref().map_or(Path::new("/"), |p| p.1.as_path()).display()
+        );
     }
+
+    result
 }
 
 /// Opens an encrypted partition and maps it to the pv name.
@@ -115,14 +161,20 @@ pub fn cryptsetup_open(device: &Path, enc: &LvmEncryption) -> io::Result<()> {
     deactivate_devices(&[device])?;
     let pv = &enc.physical_volume;
     info!("cryptsetup is opening {} with pv {} and {:?}", device.display(), pv, enc);
+
+    let header = mount_header(enc)?;
+    let mut args: Vec<OsString> = vec!["open".into(), device.into(), pv.into()];
+
+    if let Some((_, _, ref path)) = header {
+        args.push("--header".into());
+        args.push(path.into());
+    }
+
     match (enc.password.as_ref(), enc.keydata.as_ref()) {
         (Some(_password), Some(_keydata)) => unimplemented!(),
-        (Some(password), None) => exec(
-            "cryptsetup",
-            Some(&append_newline(password.as_bytes())),
-            None,
-            &["open".into(), device.into(), pv.into()],
-        ),
+        (Some(password), None) => {
+            exec("cryptsetup", Some(&append_newline(password.as_bytes())), None, &args)
+        }
         (None, Some(&(_, ref keydata))) => {
             let keydata = keydata.as_ref().expect("field should have been populated");
             let tmpfs = TempDir::new("distinst")?;
@@ -132,17 +184,22 @@ pub fn cryptsetup_open(device: &Path, enc: &LvmEncryption) -> io::Result<()> {
             let keypath = tmpfs.path().join(&enc.physical_volume);
             info!("keypath exists: {}", keypath.is_file());
 
-            exec(
-                "cryptsetup",
-                None,
-                None,
-                &["open".into(), device.into(), pv.into(), "--key-file".into(), keypath.into()],
-            )
+            args.push("--key-file".into());
+            args.push(keypath.into());
+            exec("cryptsetup", None, None, &args)
         }
-        (None, None) => unimplemented!(),
+        (None, None) => Err(keyless(&enc.physical_volume)),
     }
 }
 
+/// The error of a volume which was given neither a passphrase nor a keyfile.
+fn keyless(volume: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("the encrypted volume {} has neither a passphrase nor a keyfile", volume),
+    )
+}
+
 /// Append a newline to the input (used for the password)
 fn append_newline(input: &[u8]) -> Vec<u8> {
     let mut input = input.to_owned();
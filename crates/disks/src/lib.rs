@@ -20,16 +20,28 @@ extern crate partition_identity;
 extern crate proc_mounts;
 extern crate rand;
 extern crate rayon;
+#[cfg(feature = "serde")]
+extern crate serde;
+#[cfg(feature = "serde")]
+#[macro_use]
+extern crate serde_derive;
+#[cfg(feature = "serde")]
+extern crate serde_json;
 extern crate sys_mount;
 extern crate sysfs_class;
 extern crate tempdir;
+extern crate zeroize;
 
+mod catalog;
 mod config;
 mod error;
 pub mod external;
 mod serial;
+#[cfg(feature = "serde")]
+mod serialize;
 
 pub use self::{
+    catalog::{format_message, CatalogEntry, DISK_ERRORS},
     config::*,
     error::{DecryptionError, DiskError, PartitionError, PartitionSizeError},
 };
@@ -0,0 +1,160 @@
+//! Serialization of the disk configuration, for the types of other crates which do not
+//! implement serde's traits.
+//!
+//! Every staged change to the configuration is serialized with it, with the exception of the
+//! passphrases of encrypted volumes, which are never written out.
+
+use libparted::PartitionFlag;
+use partition_identity::PartitionIdentifiers;
+
+#[derive(Serialize, Deserialize)]
+#[serde(remote = "PartitionIdentifiers")]
+pub(crate) struct PartitionIdentifiersDef {
+    pub id:         Option<String>,
+    pub label:      Option<String>,
+    pub part_label: Option<String>,
+    pub part_uuid:  Option<String>,
+    pub path:       Option<String>,
+    pub uuid:       Option<String>,
+}
+
+/// Partition flags are serialized by the names that parted gives them.
+pub(crate) mod partition_flags {
+    use super::PartitionFlag;
+    use serde::{
+        de::{self, Deserialize, Deserializer},
+        ser::{self, SerializeSeq, Serializer},
+    };
+
+    const NAMES: &[(PartitionFlag, &str)] = &[
+        (PartitionFlag::PED_PARTITION_BOOT, "boot"),
+        (PartitionFlag::PED_PARTITION_ROOT, "root"),
+        (PartitionFlag::PED_PARTITION_SWAP, "swap"),
+        (PartitionFlag::PED_PARTITION_HIDDEN, "hidden"),
+        (PartitionFlag::PED_PARTITION_RAID, "raid"),
+        (PartitionFlag::PED_PARTITION_LVM, "lvm"),
+        (PartitionFlag::PED_PARTITION_LBA, "lba"),
+        (PartitionFlag::PED_PARTITION_HPSERVICE, "hp-service"),
+        (PartitionFlag::PED_PARTITION_PALO, "palo"),
+        (PartitionFlag::PED_PARTITION_PREP, "prep"),
+        (PartitionFlag::PED_PARTITION_MSFT_RESERVED, "msftres"),
+        (PartitionFlag::PED_PARTITION_BIOS_GRUB, "bios_grub"),
+        (PartitionFlag::PED_PARTITION_APPLE_TV_RECOVERY, "atvrecv"),
+        (PartitionFlag::PED_PARTITION_DIAG, "diag"),
+        (PartitionFlag::PED_PARTITION_LEGACY_BOOT, "legacy_boot"),
+        (PartitionFlag::PED_PARTITION_MSFT_DATA, "msftdata"),
+        (PartitionFlag::PED_PARTITION_IRST, "irst"),
+        (PartitionFlag::PED_PARTITION_ESP, "esp"),
+    ];
+
+    pub fn serialize<S: Serializer>(
+        flags: &[PartitionFlag],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let mut seq = serializer.serialize_seq(Some(flags.len()))?;
+        for &flag in flags {
+            match NAMES.iter().find(|&&(known, _)| known == flag) {
+                Some(&(_, name)) => seq.serialize_element(name)?,
+                None => {
+                    return Err(ser::Error::custom(format!("unknown partition flag: {:?}", flag)))
+                }
+            }
+        }
+
+        seq.end()
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<PartitionFlag>, D::Error> {
+        Vec::<String>::deserialize(deserializer)?
+            .into_iter()
+            .map(|name| {
+                NAMES
+                    .iter()
+                    .find(|&&(_, known)| known == name)
+                    .map(|&(flag, _)| flag)
+                    .ok_or_else(|| de::Error::custom(format!("unknown partition flag: {}", name)))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{DiskExt, Disks, LvmEncryption, PartitionBuilder};
+    use disk_types::FileSystem;
+    use std::{
+        fs::File,
+        path::{Path, PathBuf},
+        process::Command,
+    };
+    use tempdir::TempDir;
+
+    struct LoopDevice(PathBuf);
+
+    impl Drop for LoopDevice {
+        fn drop(&mut self) { let _ = Command::new("losetup").arg("-d").arg(&self.0).status(); }
+    }
+
+    fn run(command: &mut Command) {
+        assert!(command.status().unwrap().success(), "{:?} failed", command);
+    }
+
+    fn partition(device: &Path, number: u8) -> PathBuf {
+        PathBuf::from(format!("{}p{}", device.display(), number))
+    }
+
+    /// This requires root, for loop devices, so it is run with `cargo test -- --ignored`.
+    #[test]
+    #[ignore]
+    fn loop_device_round_trip() {
+        let dir = TempDir::new("distinst").unwrap();
+        let image = dir.path().join("disk.img");
+        File::create(&image).unwrap().set_len(64 * 1024 * 1024).unwrap();
+
+        let output = Command::new("losetup")
+            .args(&["--find", "--show", "--partscan"])
+            .arg(&image)
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        let device = LoopDevice(PathBuf::from(String::from_utf8(output.stdout).unwrap().trim()));
+
+        run(Command::new("parted").arg("-s").arg(&device.0).args(&[
+            "mklabel", "gpt", "mkpart", "esp", "fat32", "1MiB", "17MiB", "set", "1", "esp", "on",
+            "mkpart", "root", "ext4", "17MiB", "41MiB",
+        ]));
+        run(Command::new("udevadm").arg("settle"));
+        run(Command::new("mkfs.fat").arg("-F32").arg(partition(&device.0, 1)));
+        run(Command::new("mkfs.ext4").arg("-q").arg(partition(&device.0, 2)));
+
+        let mut disks = Disks::default();
+        disks.add(crate::Disk::from_name(&device.0).unwrap());
+
+        // Stage changes, so that they are serialized along with the probed state.
+        {
+            let disk = &mut disks.physical[0];
+            disk.format_partition(2, FileSystem::Btrfs).unwrap();
+            disk.get_partition_mut(2).unwrap().set_mount("/".into());
+            let encryption =
+                LvmEncryption::new("cryptdata".into(), Some("passphrase".into()), None);
+            disk.add_partition(
+                PartitionBuilder::new(83_968, 129_024, FileSystem::Lvm)
+                    .logical_volume("data".into(), Some(encryption)),
+            )
+            .unwrap();
+        }
+
+        let json = serde_json::to_string(&disks).unwrap();
+        let restored: Disks = serde_json::from_str(&json).unwrap();
+
+        // The passphrase is never serialized.
+        assert!(!json.contains("passphrase"));
+        let staged = disks.physical[0].partitions.iter_mut().find(|p| p.volume_group.is_some());
+        let (_, ref mut encryption) = *staged.unwrap().volume_group.as_mut().unwrap();
+        encryption.as_mut().unwrap().password = None;
+
+        assert_eq!(disks, restored);
+    }
+}
@@ -7,8 +7,16 @@ use std::{
     io,
     path::Path,
     process::{Command, Stdio},
+    thread,
+    time::{Duration, Instant},
 };
 
+/// How long udev is given to release a device before `blockdev` is run on it.
+const BLOCKDEV_SETTLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often device nodes are checked for while waiting for them to be created.
+const NODE_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
 /// Erase all signatures on a disk
 pub fn wipefs<P: AsRef<Path>>(device: P) -> io::Result<()> {
     info!("using wipefs to wipe signatures from {:?}", device.as_ref());
@@ -28,6 +36,110 @@ pub fn blockdev<P: AsRef<Path>, S: AsRef<OsStr>, I: IntoIterator<Item = S>>(
     })
 }
 
+/// Runs `blockdev` on the device once udev has settled, for up to three attempts, as udev
+/// holds devices open while it probes them.
+pub fn blockdev_settled<P: AsRef<Path>, S: AsRef<OsStr>, I: IntoIterator<Item = S>>(
+    disk: P,
+    args: I,
+) -> io::Result<()> {
+    let args = args.into_iter().map(|x| x.as_ref().into()).collect::<Vec<OsString>>();
+    let mut attempt = 0;
+    loop {
+        if let Err(why) = udev_settle(BLOCKDEV_SETTLE_TIMEOUT) {
+            warn!("udev did not settle: {}", why);
+        }
+
+        match blockdev(&disk, &args) {
+            Err(_) if attempt < 2 => attempt += 1,
+            result => return result,
+        }
+    }
+}
+
+/// Asks the kernel to reread the partition table of a disk.
+///
+/// The `BLKRRPART` ioctl is refused while any partition of the disk is in use, so
+/// `partprobe` is used then, which updates each partition individually.
+pub fn reread_partition_table<P: AsRef<Path>>(disk: P) -> io::Result<()> {
+    let disk = disk.as_ref();
+    blockdev(disk, &["--flushbufs", "--rereadpt"]).or_else(|why| {
+        info!("{}: {}: rereading partitions with partprobe", disk.display(), why);
+        exec("partprobe", None, None, &[disk.into()])
+    })
+}
+
+/// Waits until udev has handled every event in its queue, such as those which create the
+/// device nodes of new partitions, for at most `timeout`.
+pub fn udev_settle(timeout: Duration) -> io::Result<()> {
+    let timeout = format!("--timeout={}", timeout.as_secs());
+    exec("udevadm", None, None, &["settle".into(), timeout.into()])
+}
+
+/// Waits for udev to settle, and then for each of the given device nodes to exist, for at
+/// most `timeout` in total. An error which lists the missing nodes is returned otherwise.
+pub fn settle_nodes<P: AsRef<Path>, I: IntoIterator<Item = P>>(
+    nodes: I,
+    timeout: Duration,
+) -> io::Result<()> {
+    let deadline = Instant::now() + timeout;
+    if let Err(why) = udev_settle(timeout) {
+        warn!("udev did not settle: {}", why);
+    }
+
+    wait_for_nodes(nodes, deadline)
+}
+
+/// Polls for each of the device nodes to exist, until the deadline.
+fn wait_for_nodes<P: AsRef<Path>, I: IntoIterator<Item = P>>(
+    nodes: I,
+    deadline: Instant,
+) -> io::Result<()> {
+    let mut missing = nodes.into_iter().collect::<Vec<P>>();
+    loop {
+        missing.retain(|node| !node.as_ref().exists());
+        if missing.is_empty() {
+            return Ok(());
+        }
+
+        if Instant::now() >= deadline {
+            let missing =
+                missing.iter().map(|node| node.as_ref().display().to_string()).collect::<Vec<_>>();
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("device nodes were not created: {}", missing.join(", ")),
+            ));
+        }
+
+        thread::sleep(NODE_POLL_INTERVAL);
+    }
+}
+
+/// Discards the unused blocks of the file system mounted at `mount`, and returns the number
+/// of bytes which were trimmed.
+pub fn fstrim<P: AsRef<Path>>(mount: P) -> io::Result<u64> {
+    let output = Command::new("fstrim").arg("--verbose").arg(mount.as_ref()).output()?;
+
+    if !output.status.success() {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!(
+                "fstrim failed with status: {}: {}",
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ),
+        ));
+    }
+
+    Ok(parse_trimmed(&String::from_utf8_lossy(&output.stdout)).unwrap_or(0))
+}
+
+/// fstrim reports `/: 1.2 GiB (1288490188 bytes) trimmed`.
+fn parse_trimmed(output: &str) -> Option<u64> {
+    let start = output.find('(')? + 1;
+    let end = start + output[start..].find(" bytes)")?;
+    output[start..end].parse().ok()
+}
+
 /// Obtains the file system on a partition via blkid
 pub fn blkid_partition<P: AsRef<Path>>(part: P) -> Option<FileSystem> {
     let output = Command::new("blkid")
@@ -52,6 +164,22 @@ pub fn blkid_partition<P: AsRef<Path>>(part: P) -> Option<FileSystem> {
     return None
 }
 
+/// True if the NTFS file system on the partition was hibernated, which the fast startup of
+/// Windows also does on shut down. Such file systems must not be modified.
+pub fn ntfs_hibernated<P: AsRef<Path>>(part: P) -> io::Result<bool> {
+    /// The exit status of `ntfs-3g.probe` for a hibernated volume.
+    const NTFS_VOLUME_HIBERNATED: i32 = 14;
+
+    let status = Command::new("ntfs-3g.probe")
+        .arg("--readwrite")
+        .arg(part.as_ref())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()?;
+
+    Ok(status.code() == Some(NTFS_VOLUME_HIBERNATED))
+}
+
 /// Checks & corrects errors with partitions that have been moved / resized.
 pub fn fsck<P: AsRef<Path>>(part: P, cmd: Option<(&str, &str)>) -> io::Result<()> {
     let (cmd, arg) = cmd.unwrap_or(("fsck", "-fy"));
@@ -93,6 +221,25 @@ pub fn mkfs<P: AsRef<Path>>(part: P, kind: FileSystem) -> io::Result<()> {
     })
 }
 
+/// The command which `mkfs` formats the file system with, which LUKS and LVM have none of.
+pub fn mkfs_command(kind: FileSystem) -> Option<&'static str> {
+    let command = match kind {
+        Btrfs => "mkfs.btrfs",
+        Exfat => "mkfs.exfat",
+        Ext2 => "mkfs.ext2",
+        Ext3 => "mkfs.ext3",
+        Ext4 => "mkfs.ext4",
+        F2fs => "mkfs.f2fs",
+        Fat16 | Fat32 => "mkfs.fat",
+        Ntfs => "mkfs.ntfs",
+        Swap => "mkswap",
+        Xfs => "mkfs.xfs",
+        Luks | Lvm => return None,
+    };
+
+    Some(command)
+}
+
 /// Get the label from the given partition, if it exists.
 pub fn get_label<P: AsRef<Path>>(part: P, kind: FileSystem) -> Option<String> {
     let (cmd, args) = get_label_cmd(kind)?;
@@ -146,3 +293,32 @@ pub fn remount_rw<P: AsRef<Path>>(path: P) -> io::Result<()> {
 fn swap_exists(path: &Path) -> bool {
     Command::new("swaplabel").arg(path).status().ok().map_or(false, |stat| stat.success())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempdir::TempDir;
+
+    #[test]
+    fn missing_nodes() {
+        let dir = TempDir::new("distinst").unwrap();
+        let (sda1, sda2) = (dir.path().join("sda1"), dir.path().join("sda2"));
+        fs::write(&sda1, "").unwrap();
+
+        let nodes = [sda1.as_path(), sda2.as_path()];
+        let err = wait_for_nodes(&nodes, Instant::now()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert_eq!(err.to_string(), format!("device nodes were not created: {}", sda2.display()));
+
+        fs::write(&sda2, "").unwrap();
+        wait_for_nodes(&nodes, Instant::now()).unwrap();
+    }
+
+    #[test]
+    fn trimmed() {
+        assert_eq!(parse_trimmed("/: 1.2 GiB (1288490188 bytes) trimmed\n"), Some(1288490188));
+        assert_eq!(parse_trimmed("/boot/efi: 0 B (0 bytes) trimmed\n"), Some(0));
+        assert_eq!(parse_trimmed(""), None);
+    }
+}
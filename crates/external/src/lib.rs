@@ -31,7 +31,7 @@ pub fn exec(
     valid_codes: Option<&'static [i32]>,
     args: &[OsString],
 ) -> io::Result<()> {
-    info!("executing {} with {:?}", cmd, args);
+    debug!("executing {} with {:?}", cmd, args);
 
     let mut child = Command::new(cmd)
         .args(args)
@@ -44,6 +44,7 @@ pub fn exec(
     }
 
     let status = child.wait()?;
+    debug!("{} exited with {}", cmd, status);
     let success = status.success()
         || valid_codes
             .map_or(false, |codes| status.code().map_or(false, |code| codes.contains(&code)));
@@ -51,8 +52,9 @@ pub fn exec(
     if success {
         Ok(())
     } else {
-        Err(io::Error::new(
+        Err(misc::command_error(
             io::ErrorKind::Other,
+            &format!("{} {:?}", cmd, args),
             format!(
                 "{} failed with status: {}",
                 cmd,
@@ -4,7 +4,7 @@ use proc_mounts::{MOUNTS, SWAPS};
 use rand::{self, distributions::Alphanumeric, Rng};
 use std::{
     collections::BTreeMap,
-    ffi::OsStr,
+    ffi::{OsStr, OsString},
     fs::read_link,
     io::{self, BufRead, BufReader},
     path::{Path, PathBuf},
@@ -106,9 +106,15 @@ pub fn dmlist() -> io::Result<Vec<String>> {
     Ok(output)
 }
 
-/// Generate a unique device map ID, to ensure no collisions between dm blocks.
+/// Generate a unique device map ID, to ensure no collisions between dm blocks, volume groups,
+/// or the device maps that logical volumes will have once activated.
 pub fn generate_unique_id(prefix: &str, exclude_hashes: &[u64]) -> io::Result<String> {
-    let dmlist = dmlist()?;
+    let mut dmlist = dmlist()?;
+    for vg in vgs()? {
+        dmlist.extend(lv_report(&vg.name)?.iter().map(|lv| lv_device_map(&vg.name, &lv.name)));
+        dmlist.push(vg.name);
+    }
+
     let check_uniqueness = |id: &str, exclude: &[u64]| -> bool {
         !dmlist.iter().any(|x| x.as_str() == id) && !exclude.contains(&::misc::hasher(&id))
     };
@@ -127,25 +133,103 @@ pub fn generate_unique_id(prefix: &str, exclude_hashes: &[u64]) -> io::Result<St
     }
 }
 
+/// The name of the device map of a logical volume, which escapes hyphens by doubling them.
+fn lv_device_map(group: &str, name: &str) -> String {
+    [&group.replace('-', "--"), "-", &name.replace('-', "--")].concat()
+}
+
 /// Used to create a logical volume on a volume group.
 pub fn lvcreate(group: &str, name: &str, size: Option<u64>) -> io::Result<()> {
+    lvcreate_on::<&Path>(group, name, size, &[])
+}
+
+/// Used to create a logical volume on a volume group, which may only be allocated from the
+/// given physical volumes. If no physical volumes are given, any may be used.
+pub fn lvcreate_on<P: AsRef<Path>>(
+    group: &str,
+    name: &str,
+    size: Option<u64>,
+    physical_volumes: &[P],
+) -> io::Result<()> {
+    let mut args: Vec<OsString> = match size {
+        Some(size) => vec!["-y".into(), "-L".into(), mebibytes(size).into()],
+        None => vec!["-y".into(), "-l".into(), "100%FREE".into()],
+    };
+
+    args.extend_from_slice(&[group.into(), "-n".into(), name.into()]);
+    args.extend(physical_volumes.iter().map(|pv| pv.as_ref().into()));
+    exec("lvcreate", None, None, &args)
+}
+
+/// Creates a copy-on-write snapshot of the logical volume, `origin`, named `name`, which
+/// may hold up to `size` bytes of changes to the origin.
+pub fn lvcreate_snapshot(group: &str, origin: &str, name: &str, size: u64) -> io::Result<()> {
+    info!("creating snapshot {}/{} of {}", group, name, origin);
+    exec(
+        "lvcreate",
+        None,
+        None,
+        &[
+            "-y".into(),
+            "-s".into(),
+            "-L".into(),
+            mebibytes(size).into(),
+            "-n".into(),
+            name.into(),
+            [group, "/", origin].concat().into(),
+        ],
+    )
+}
+
+/// Creates a cache pool on the physical volume, `pv`, which is `size` bytes in length, with
+/// an additional `metadata` bytes for its metadata.
+pub fn lvcreate_cache_pool(
+    group: &str,
+    name: &str,
+    size: u64,
+    metadata: u64,
+    pv: &Path,
+) -> io::Result<()> {
+    info!("creating cache pool {}/{} on {:?}", group, name, pv);
     exec(
         "lvcreate",
         None,
         None,
-        &size.map_or(
-            ["-y".into(), "-l".into(), "100%FREE".into(), group.into(), "-n".into(), name.into()],
-            |size| {
-                [
-                    "-y".into(),
-                    "-L".into(),
-                    mebibytes(size).into(),
-                    group.into(),
-                    "-n".into(),
-                    name.into(),
-                ]
-            },
-        ),
+        &[
+            "-y".into(),
+            "--type".into(),
+            "cache-pool".into(),
+            "-L".into(),
+            mebibytes(size).into(),
+            "--poolmetadatasize".into(),
+            mebibytes(metadata).into(),
+            "--poolmetadataspare".into(),
+            "n".into(),
+            "-n".into(),
+            name.into(),
+            group.into(),
+            pv.into(),
+        ],
+    )
+}
+
+/// Attaches the cache pool, `pool`, to the logical volume, `origin`, with the given cache mode.
+pub fn lvconvert_cache(group: &str, origin: &str, pool: &str, mode: &str) -> io::Result<()> {
+    info!("caching {}/{} with {}/{} in {} mode", group, origin, group, pool, mode);
+    exec(
+        "lvconvert",
+        None,
+        None,
+        &[
+            "-y".into(),
+            "--type".into(),
+            "cache".into(),
+            "--cachepool".into(),
+            [group, "/", pool].concat().into(),
+            "--cachemode".into(),
+            mode.into(),
+            [group, "/", origin].concat().into(),
+        ],
     )
 }
 
@@ -154,6 +238,127 @@ pub fn lvremove(group: &str, name: &str) -> io::Result<()> {
     exec("lvremove", None, None, &["-y".into(), ["/dev/mapper/", group, "-", name].concat().into()])
 }
 
+/// Resize the logical volume, `name`, on the volume group, `group`, to `extents` physical
+/// extents.
+///
+/// The file system on the volume will not be resized by this command.
+pub fn lvresize(group: &str, name: &str, extents: u64) -> io::Result<()> {
+    exec(
+        "lvresize",
+        None,
+        None,
+        &[
+            "-y".into(),
+            "-f".into(),
+            "-l".into(),
+            extents.to_string().into(),
+            [group, "/", name].concat().into(),
+        ],
+    )
+}
+
+/// Obtains the name and size, in bytes, of each logical volume in the given volume group.
+pub fn lv_sizes(vg: &str) -> io::Result<Vec<(String, u64)>> {
+    info!("obtaining logical volume sizes on {}", vg);
+    let output = Command::new("lvs")
+        .args(&["--noheadings", "--units", "b", "--nosuffix", "-o", "lv_name,lv_size"])
+        .arg(vg)
+        .stderr(Stdio::null())
+        .output()?;
+
+    let output = String::from_utf8_lossy(&output.stdout);
+    Ok(output
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let name = fields.next()?;
+            let size = fields.next()?.parse::<u64>().ok()?;
+            Some((name.to_owned(), size))
+        })
+        .collect())
+}
+
+/// Obtains the size of a physical extent, in bytes, on the given volume group.
+pub fn vg_extent_size(vg: &str) -> io::Result<u64> {
+    let output = Command::new("vgs")
+        .args(&["--noheadings", "--units", "b", "--nosuffix", "-o", "vg_extent_size"])
+        .arg(vg)
+        .stderr(Stdio::null())
+        .output()?;
+
+    String::from_utf8_lossy(&output.stdout).trim().parse::<u64>().map_err(|why| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("invalid extent size for {}: {}", vg, why),
+        )
+    })
+}
+
+/// A volume group, as reported by `vgs`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VolumeGroupReport {
+    pub name:        String,
+    /// The size of the volume group, in bytes.
+    pub size:        u64,
+    /// The size of a physical extent, in bytes.
+    pub extent_size: u64,
+}
+
+/// A logical volume, as reported by `lvs`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogicalVolumeReport {
+    pub name:   String,
+    /// The size of the logical volume, in bytes.
+    pub size:   u64,
+    /// Whether the logical volume is active, and therefore has a device map.
+    pub active: bool,
+}
+
+/// Obtains every volume group in the system, whether it is active or not.
+pub fn vgs() -> io::Result<Vec<VolumeGroupReport>> {
+    info!("obtaining list of volume groups");
+    let output = Command::new("vgs")
+        .args(&["--noheadings", "--units", "b", "--nosuffix"])
+        .args(&["-o", "vg_name,vg_size,vg_extent_size"])
+        .stderr(Stdio::null())
+        .output()?;
+
+    let output = String::from_utf8_lossy(&output.stdout);
+    Ok(output
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let name = fields.next()?;
+            let size = fields.next()?.parse::<u64>().ok()?;
+            let extent_size = fields.next()?.parse::<u64>().ok()?;
+            Some(VolumeGroupReport { name: name.to_owned(), size, extent_size })
+        })
+        .collect())
+}
+
+/// Obtains every logical volume in the given volume group, whether it is active or not.
+pub fn lv_report(vg: &str) -> io::Result<Vec<LogicalVolumeReport>> {
+    info!("obtaining logical volume report on {}", vg);
+    let output = Command::new("lvs")
+        .args(&["--noheadings", "--units", "b", "--nosuffix", "-o", "lv_name,lv_size,lv_attr"])
+        .arg(vg)
+        .stderr(Stdio::null())
+        .output()?;
+
+    let output = String::from_utf8_lossy(&output.stdout);
+    Ok(output
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let name = fields.next()?;
+            let size = fields.next()?.parse::<u64>().ok()?;
+            // The fifth character of the attributes describes the state of the volume.
+            let active = fields.next()?.chars().nth(4) == Some('a');
+            Some(LogicalVolumeReport { name: name.to_owned(), size, active })
+        })
+        .collect())
+}
+
 /// Obtains a list of logical volumes associated with the given volume group.
 pub fn lvs(vg: &str) -> io::Result<Vec<PathBuf>> {
     info!("obtaining logical volumes on {}", vg);
@@ -261,6 +466,12 @@ pub fn vgcreate<I: Iterator<Item = S>, S: AsRef<OsStr>>(group: &str, devices: I)
     })
 }
 
+/// Renames the volume group, `old`, to `new`.
+pub fn vgrename(old: &str, new: &str) -> io::Result<()> {
+    info!("renaming volume group '{}' to '{}'", old, new);
+    exec("vgrename", None, None, &[old.into(), new.into()])
+}
+
 /// Deactivates all logical volumes in the supplied volume group
 pub fn vgdeactivate(volume_group: &str) -> io::Result<()> {
     info!("deactivating '{}'", volume_group);
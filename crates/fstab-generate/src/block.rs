@@ -4,6 +4,65 @@ use std::ffi::{OsStr, OsString};
 use std::path::{Path, PathBuf};
 use disk_types::FileSystem;
 
+/// How the file systems in the fstab are identified.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum FstabId {
+    /// The UUID of the file system.
+    Uuid,
+    /// The UUID of the partition, or of the file system where the device is not a partition.
+    PartUuid,
+    /// The label of the file system, or its UUID where it has no label.
+    Label,
+    /// The path of device mapper devices, such as logical volumes, and the UUID of others.
+    Path,
+}
+
+impl Default for FstabId {
+    fn default() -> Self { FstabId::Uuid }
+}
+
+impl FstabId {
+    /// Obtains the ID of the partition, as identified by this strategy.
+    pub fn partition_id(self, path: &Path, fs: FileSystem) -> Option<PartitionID> {
+        self.partition_id_from(path, fs, |variant| PartitionID::get_source(variant, path))
+    }
+
+    /// Obtains the ID of the partition as `partition_id` does, but with the IDs that the
+    /// device is known by given by `lookup`, rather than found in `/dev/disk`.
+    pub fn partition_id_from<F>(self, path: &Path, fs: FileSystem, lookup: F) -> Option<PartitionID>
+    where
+        F: Fn(PartitionSource) -> Option<PartitionID>,
+    {
+        // FAT partitions are prone to UUID collisions, so PartUUID will be used instead.
+        let fallback = || {
+            if fs == FileSystem::Fat16 || fs == FileSystem::Fat32 {
+                lookup(PartitionSource::PartUUID)
+            } else {
+                lookup(PartitionSource::UUID)
+            }
+        };
+
+        match self {
+            FstabId::Uuid => fallback(),
+            FstabId::PartUuid => lookup(PartitionSource::PartUUID).or_else(fallback),
+            FstabId::Label => lookup(PartitionSource::Label).or_else(fallback),
+            FstabId::Path if is_device_mapper(path) => Some(PartitionID {
+                variant: PartitionSource::Path,
+                id:      path.to_string_lossy().into_owned(),
+            }),
+            FstabId::Path => fallback(),
+        }
+    }
+}
+
+/// Whether the device is a device mapper device, which is only judged by its path: a
+/// `/dev/mapper` path or a `dm-*` node. A device mapper device at another path, such as
+/// `/dev/<vg>/<lv>`, is not recognized, and is identified by its UUID instead.
+fn is_device_mapper(path: &Path) -> bool {
+    path.starts_with("/dev/mapper")
+        || path.file_name().map_or(false, |name| name.to_string_lossy().starts_with("dm-"))
+}
+
 /// Information that will be used to generate a fstab entry for the given
 /// partition.
 #[derive(Debug, PartialEq)]
@@ -80,11 +139,7 @@ impl<'a> BlockInfo<'a> {
     /// # Notes
     /// FAT partitions are prone to UUID collisions, so PartUUID will be used instead.
     pub fn get_partition_id(path: &Path, fs: FileSystem) -> Option<PartitionID> {
-        if fs == FileSystem::Fat16 || fs == FileSystem::Fat32 {
-            PartitionID::get_partuuid(path)
-        } else {
-            PartitionID::get_uuid(path)
-        }
+        FstabId::Uuid.partition_id(path, fs)
     }
 }
 
@@ -116,6 +171,208 @@ UUID=ROOT  /  ext4  defaults  0  1
         );
     }
 
+    /// A device of a layout, with the IDs that it would be found by.
+    struct Device {
+        path:     &'static str,
+        fs:       FileSystem,
+        target:   Option<&'static str>,
+        options:  &'static str,
+        uuid:     &'static str,
+        partuuid: Option<&'static str>,
+        label:    Option<&'static str>,
+    }
+
+    /// The fstab of the layout, through the same public functions that `generate_fstabs`
+    /// of the installer writes each entry with.
+    fn generate(layout: &[Device], strategy: FstabId) -> OsString {
+        let mut fstab = OsString::new();
+        for device in layout {
+            let path = Path::new(device.path);
+            let id = strategy.partition_id_from(path, device.fs, |variant| {
+                let id = match variant {
+                    PartitionSource::UUID => Some(device.uuid),
+                    PartitionSource::PartUUID => device.partuuid,
+                    PartitionSource::Label => device.label,
+                    _ => None,
+                };
+                id.map(|id| PartitionID { variant, id: id.into() })
+            });
+
+            let target = device.target.map(Path::new);
+            BlockInfo::new(id.unwrap(), device.fs, target, device.options).write_entry(&mut fstab);
+        }
+
+        fstab
+    }
+
+    const EFI: Device = Device {
+        path:     "/dev/sda1",
+        fs:       FileSystem::Fat32,
+        target:   Some("/boot/efi"),
+        options:  "umask=0077",
+        uuid:     "A1B2-C3D4",
+        partuuid: Some("e0f9a1c2-01"),
+        label:    None,
+    };
+
+    #[test]
+    fn fstab_ext4() {
+        let layout = [
+            EFI,
+            Device {
+                path:     "/dev/sda2",
+                fs:       FileSystem::Ext4,
+                target:   Some("/"),
+                options:  "noatime,errors=remount-ro",
+                uuid:     "7f3c9d2e-5b1a-4c8e-9f60-2d4b8a1e7c35",
+                partuuid: Some("e0f9a1c2-02"),
+                label:    Some("Pop_OS"),
+            },
+            Device {
+                path:     "/dev/sda3",
+                fs:       FileSystem::Swap,
+                target:   None,
+                options:  "sw",
+                uuid:     "0b6e4f1d-8a2c-4d3b-b7e9-5c1f2a8d6e40",
+                partuuid: Some("e0f9a1c2-03"),
+                label:    None,
+            },
+        ];
+
+        assert_eq!(
+            generate(&layout, FstabId::Uuid),
+            OsString::from(r#"PARTUUID=e0f9a1c2-01  /boot/efi  vfat  umask=0077  0  0
+UUID=7f3c9d2e-5b1a-4c8e-9f60-2d4b8a1e7c35  /  ext4  noatime,errors=remount-ro  0  1
+UUID=0b6e4f1d-8a2c-4d3b-b7e9-5c1f2a8d6e40  none  swap  sw  0  0
+"#)
+        );
+
+        assert_eq!(
+            generate(&layout, FstabId::PartUuid),
+            OsString::from(r#"PARTUUID=e0f9a1c2-01  /boot/efi  vfat  umask=0077  0  0
+PARTUUID=e0f9a1c2-02  /  ext4  noatime,errors=remount-ro  0  1
+PARTUUID=e0f9a1c2-03  none  swap  sw  0  0
+"#)
+        );
+
+        assert_eq!(
+            generate(&layout, FstabId::Label),
+            OsString::from(r#"PARTUUID=e0f9a1c2-01  /boot/efi  vfat  umask=0077  0  0
+LABEL=Pop_OS  /  ext4  noatime,errors=remount-ro  0  1
+UUID=0b6e4f1d-8a2c-4d3b-b7e9-5c1f2a8d6e40  none  swap  sw  0  0
+"#)
+        );
+
+        assert_eq!(generate(&layout, FstabId::Path), generate(&layout, FstabId::Uuid));
+    }
+
+    #[test]
+    fn fstab_encrypted_lvm() {
+        let layout = [
+            EFI,
+            Device {
+                path:     "/dev/mapper/data-root",
+                fs:       FileSystem::Ext4,
+                target:   Some("/"),
+                options:  "noatime,errors=remount-ro",
+                uuid:     "3d8a5c1f-2e7b-4f90-a6d4-9b1c0e5f7a28",
+                partuuid: None,
+                label:    None,
+            },
+            Device {
+                path:     "/dev/mapper/data-home",
+                fs:       FileSystem::Xfs,
+                target:   Some("/home"),
+                options:  "defaults",
+                uuid:     "c5e2b7a9-1f4d-4a6e-8c3b-7d0f9e2a1b54",
+                partuuid: None,
+                label:    Some("home"),
+            },
+        ];
+
+        assert_eq!(
+            generate(&layout, FstabId::Uuid),
+            OsString::from(r#"PARTUUID=e0f9a1c2-01  /boot/efi  vfat  umask=0077  0  0
+UUID=3d8a5c1f-2e7b-4f90-a6d4-9b1c0e5f7a28  /  ext4  noatime,errors=remount-ro  0  1
+UUID=c5e2b7a9-1f4d-4a6e-8c3b-7d0f9e2a1b54  /home  xfs  defaults  0  0
+"#)
+        );
+
+        assert_eq!(generate(&layout, FstabId::PartUuid), generate(&layout, FstabId::Uuid));
+
+        assert_eq!(
+            generate(&layout, FstabId::Label),
+            OsString::from(r#"PARTUUID=e0f9a1c2-01  /boot/efi  vfat  umask=0077  0  0
+UUID=3d8a5c1f-2e7b-4f90-a6d4-9b1c0e5f7a28  /  ext4  noatime,errors=remount-ro  0  1
+LABEL=home  /home  xfs  defaults  0  0
+"#)
+        );
+
+        assert_eq!(
+            generate(&layout, FstabId::Path),
+            OsString::from(r#"PARTUUID=e0f9a1c2-01  /boot/efi  vfat  umask=0077  0  0
+/dev/mapper/data-root  /  ext4  noatime,errors=remount-ro  0  1
+/dev/mapper/data-home  /home  xfs  defaults  0  0
+"#)
+        );
+
+        // The path of a device mapper device needs no lookup in `/dev/disk`.
+        let root = Path::new("/dev/mapper/data-root");
+        let id = FstabId::Path.partition_id(root, FileSystem::Ext4).unwrap();
+        assert_eq!((id.variant, id.id.as_str()), (PartitionSource::Path, "/dev/mapper/data-root"));
+
+        // Only the paths of device mapper devices are recognized as them.
+        let uuid = |variant| Some(PartitionID { variant, id: "UUID".into() });
+        let lv = Path::new("/dev/data/root");
+        let lv = FstabId::Path.partition_id_from(lv, FileSystem::Ext4, uuid);
+        assert_eq!(lv.map(|id| id.variant), Some(PartitionSource::UUID));
+    }
+
+    #[test]
+    fn fstab_btrfs_subvolumes() {
+        let subvolume = |target, options| Device {
+            path: "/dev/sda2",
+            fs: FileSystem::Btrfs,
+            target: Some(target),
+            options,
+            uuid: "9a4f2c7e-6d1b-4e3a-b8c5-0f7d2e9a4b16",
+            partuuid: Some("e0f9a1c2-02"),
+            label: Some("Pop_OS"),
+        };
+
+        let layout = [
+            EFI,
+            subvolume("/", "subvol=@,compress=zstd"),
+            subvolume("/home", "subvol=@home,compress=zstd"),
+        ];
+
+        assert_eq!(
+            generate(&layout, FstabId::Uuid),
+            OsString::from(r#"PARTUUID=e0f9a1c2-01  /boot/efi  vfat  umask=0077  0  0
+UUID=9a4f2c7e-6d1b-4e3a-b8c5-0f7d2e9a4b16  /  btrfs  subvol=@,compress=zstd  0  1
+UUID=9a4f2c7e-6d1b-4e3a-b8c5-0f7d2e9a4b16  /home  btrfs  subvol=@home,compress=zstd  0  0
+"#)
+        );
+
+        assert_eq!(
+            generate(&layout, FstabId::PartUuid),
+            OsString::from(r#"PARTUUID=e0f9a1c2-01  /boot/efi  vfat  umask=0077  0  0
+PARTUUID=e0f9a1c2-02  /  btrfs  subvol=@,compress=zstd  0  1
+PARTUUID=e0f9a1c2-02  /home  btrfs  subvol=@home,compress=zstd  0  0
+"#)
+        );
+
+        assert_eq!(
+            generate(&layout, FstabId::Label),
+            OsString::from(r#"PARTUUID=e0f9a1c2-01  /boot/efi  vfat  umask=0077  0  0
+LABEL=Pop_OS  /  btrfs  subvol=@,compress=zstd  0  1
+LABEL=Pop_OS  /home  btrfs  subvol=@home,compress=zstd  0  0
+"#)
+        );
+
+        assert_eq!(generate(&layout, FstabId::Path), generate(&layout, FstabId::Uuid));
+    }
+
     #[test]
     fn block_info_swap() {
         let id = PartitionID {
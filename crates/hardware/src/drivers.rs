@@ -0,0 +1,253 @@
+//! Finds the devices which need drivers that are not in the image, by the PCI and USB IDs
+//! that the kernel lists in sysfs.
+
+use std::{fs, path::Path};
+
+/// The bus which a device is attached to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Bus {
+    Pci,
+    Usb,
+}
+
+/// A device which was found in sysfs.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Device {
+    pub bus:    Bus,
+    pub vendor: u16,
+    pub device: u16,
+    /// The class, subclass, and programming interface of a PCI device.
+    pub class:  Option<u32>,
+}
+
+/// A device which was found, with the packages of its driver. A package which ends in `*`
+/// is a family of versioned packages, such as `nvidia-driver-*`, which is resolved to its
+/// newest version in the package metadata of the image by `newest_version`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DriverMatch {
+    pub device:      Device,
+    pub description: &'static str,
+    pub packages:    &'static [&'static str],
+}
+
+/// The devices of a driver, by their vendor, and by their IDs or class.
+struct Driver {
+    bus:         Bus,
+    vendor:      u16,
+    /// Any device of the vendor is matched if there are none.
+    devices:     &'static [u16],
+    /// The class of PCI device that is matched, without its programming interface.
+    class:       Option<u16>,
+    description: &'static str,
+    packages:    &'static [&'static str],
+}
+
+// NOTE: Distributions and vendors should add their devices and packages here.
+const DRIVERS: &[Driver] = &[
+    Driver {
+        bus:         Bus::Pci,
+        vendor:      0x10de,
+        devices:     &[],
+        class:       Some(0x0300),
+        description: "NVIDIA graphics",
+        packages:    &["nvidia-driver-*"],
+    },
+    Driver {
+        bus:         Bus::Pci,
+        vendor:      0x10de,
+        devices:     &[],
+        class:       Some(0x0302),
+        description: "NVIDIA 3D controller",
+        packages:    &["nvidia-driver-*"],
+    },
+    Driver {
+        bus:         Bus::Pci,
+        vendor:      0x14e4,
+        devices:     &[
+            0x4311, 0x4312, 0x4313, 0x4315, 0x4328, 0x4329, 0x432a, 0x432b, 0x432c, 0x432d, 0x4331,
+            0x4353, 0x4357, 0x4358, 0x4359, 0x4365, 0x43a0, 0x43b1,
+        ],
+        class:       None,
+        description: "Broadcom BCM43xx wireless",
+        packages:    &["bcmwl-kernel-source"],
+    },
+    Driver {
+        bus:         Bus::Usb,
+        vendor:      0x0bda,
+        devices:     &[0x8812, 0x881a, 0x881b, 0x881c],
+        class:       None,
+        description: "Realtek RTL8812AU wireless",
+        packages:    &["rtl8812au-dkms"],
+    },
+];
+
+/// Finds the devices of the system which need drivers that are not in the image.
+pub fn detect() -> Vec<DriverMatch> { match_drivers(&devices(Path::new("/sys/bus"))) }
+
+/// Reads the PCI and USB devices which the kernel lists in the sysfs `bus` directory.
+pub fn devices(bus: &Path) -> Vec<Device> {
+    let read = |path: &Path| fs::read_to_string(path).ok();
+    let mut devices = Vec::new();
+
+    for entry in fs::read_dir(bus.join("pci/devices")).into_iter().flatten().flatten() {
+        let path = entry.path();
+        let ids = (read(&path.join("vendor")), read(&path.join("device")));
+        if let (Some(vendor), Some(device)) = ids {
+            if let (Some(vendor), Some(device)) = (parse_id(&vendor), parse_id(&device)) {
+                let class = read(&path.join("class")).and_then(|class| parse_class(&class));
+                devices.push(Device { bus: Bus::Pci, vendor, device, class });
+            }
+        }
+    }
+
+    // Interfaces of USB devices are listed alongside them, without IDs of their own.
+    for entry in fs::read_dir(bus.join("usb/devices")).into_iter().flatten().flatten() {
+        let path = entry.path();
+        let ids = (read(&path.join("idVendor")), read(&path.join("idProduct")));
+        if let (Some(vendor), Some(device)) = ids {
+            if let (Some(vendor), Some(device)) = (parse_id(&vendor), parse_id(&device)) {
+                devices.push(Device { bus: Bus::Usb, vendor, device, class: None });
+            }
+        }
+    }
+
+    devices
+}
+
+/// Matches the devices against the table of drivers. Each device is matched at most once.
+pub fn match_drivers(devices: &[Device]) -> Vec<DriverMatch> {
+    devices
+        .iter()
+        .filter_map(|device| {
+            DRIVERS.iter().find(|driver| driver.matches(device)).map(|driver| DriverMatch {
+                device:      device.clone(),
+                description: driver.description,
+                packages:    driver.packages,
+            })
+        })
+        .collect()
+}
+
+impl Driver {
+    fn matches(&self, device: &Device) -> bool {
+        self.bus == device.bus
+            && self.vendor == device.vendor
+            && (self.devices.is_empty() || self.devices.contains(&device.device))
+            && self.class.map_or(true, |class| device.class.map(|c| (c >> 8) as u16) == Some(class))
+    }
+}
+
+/// Whether the `package` is the `pattern` of a driver, or a version of its family.
+pub fn is_version_of(pattern: &str, package: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => package.strip_prefix(prefix).and_then(parse_version).is_some(),
+        None => pattern == package,
+    }
+}
+
+/// The newest version of the family of the `pattern` among the `available` packages, such
+/// as `nvidia-driver-550` of `nvidia-driver-*`. Variants of a version, such as
+/// `nvidia-driver-550-open`, are not versions of the family. A `pattern` which is not a
+/// family is returned if it is available.
+pub fn newest_version<'a, I: IntoIterator<Item = &'a str>>(
+    pattern: &str,
+    available: I,
+) -> Option<&'a str> {
+    let prefix = match pattern.strip_suffix('*') {
+        Some(prefix) => prefix,
+        None => return available.into_iter().find(|&package| package == pattern),
+    };
+
+    available
+        .into_iter()
+        .filter_map(|package| {
+            package.strip_prefix(prefix).and_then(parse_version).map(|version| (version, package))
+        })
+        .max()
+        .map(|(_, package)| package)
+}
+
+fn parse_version(version: &str) -> Option<u32> {
+    if version.is_empty() || !version.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+
+    version.parse().ok()
+}
+
+/// PCI IDs are listed as `0x10de`, and USB IDs as `10de`.
+fn parse_id(id: &str) -> Option<u16> {
+    let id = id.trim();
+    u16::from_str_radix(id.strip_prefix("0x").unwrap_or(id), 16).ok()
+}
+
+fn parse_class(class: &str) -> Option<u32> {
+    let class = class.trim();
+    u32::from_str_radix(class.strip_prefix("0x").unwrap_or(class), 16).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pci(vendor: u16, device: u16, class: u32) -> Device {
+        Device { bus: Bus::Pci, vendor, device, class: Some(class) }
+    }
+
+    #[test]
+    fn ids() {
+        assert_eq!(parse_id("0x10de\n"), Some(0x10de));
+        assert_eq!(parse_id("0bda\n"), Some(0x0bda));
+        assert_eq!(parse_id("nvidia"), None);
+        assert_eq!(parse_class("0x030000\n"), Some(0x030000));
+    }
+
+    #[test]
+    fn matches() {
+        let devices = [
+            // The NVIDIA GPU, and its audio controller, which needs no driver.
+            pci(0x10de, 0x2191, 0x030000),
+            pci(0x10de, 0x1aeb, 0x040300),
+            pci(0x14e4, 0x43b1, 0x028000),
+            pci(0x14e4, 0x1687, 0x020000),
+            Device { bus: Bus::Usb, vendor: 0x0bda, device: 0x8812, class: None },
+            // A Realtek PCI device has the same IDs as the USB adapter.
+            pci(0x0bda, 0x8812, 0x028000),
+        ];
+
+        let found = match_drivers(&devices);
+        let found = found.iter().map(|m| (m.device.vendor, m.device.device)).collect::<Vec<_>>();
+        assert_eq!(found, vec![(0x10de, 0x2191), (0x14e4, 0x43b1), (0x0bda, 0x8812)]);
+        assert_eq!(match_drivers(&devices)[2].device.bus, Bus::Usb);
+    }
+
+    #[test]
+    fn versions() {
+        let available = [
+            "nvidia-driver-470",
+            "nvidia-driver-535-open",
+            "nvidia-driver-535",
+            "nvidia-driver-550-server",
+            "nvidia-driver-1000",
+            "nvidia-driver-",
+            "nvidia-driver-full",
+        ];
+
+        let newest = |pattern, available: &[&'static str]| {
+            newest_version(pattern, available.iter().cloned())
+        };
+
+        // Versions are compared as numbers, and variants of a version are not versions.
+        assert_eq!(newest("nvidia-driver-*", &available), Some("nvidia-driver-1000"));
+        assert_eq!(newest("nvidia-driver-*", &available[..4]), Some("nvidia-driver-535"));
+        assert_eq!(newest("nvidia-driver-*", &available[5..]), None);
+        assert_eq!(newest("nvidia-driver-470", &available), Some("nvidia-driver-470"));
+        assert_eq!(newest("bcmwl-kernel-source", &available), None);
+
+        assert!(is_version_of("nvidia-driver-*", "nvidia-driver-535"));
+        assert!(!is_version_of("nvidia-driver-*", "nvidia-driver-535-open"));
+        assert!(!is_version_of("nvidia-driver-*", "nvidia-driver-"));
+        assert!(is_version_of("bcmwl-kernel-source", "bcmwl-kernel-source"));
+        assert!(!is_version_of("bcmwl-kernel-source", "bcmwl-kernel-source-dkms"));
+    }
+}
@@ -11,6 +11,7 @@ use os_release::OsRelease;
 use raw_cpuid::CpuId;
 use std::{fs, io::Read};
 
+pub mod drivers;
 pub mod switchable_graphics;
 #[macro_use]
 mod macros;
@@ -79,13 +80,16 @@ pub fn append_packages(install_pkgs: &mut Vec<&'static str>, os_release: &OsRele
 }
 
 fn graphics_support(os_release: &OsRelease) -> Option<&'static str> {
-    Module::all().ok().and_then(|modules| {
-        if modules.iter().any(|x| &x.module == "nvidia") {
-            return nvidia_driver(os_release);
-        }
+    if nvidia_loaded() {
+        return nvidia_driver(os_release);
+    }
 
-        None
-    })
+    None
+}
+
+/// Whether the NVIDIA driver is loaded on the live system.
+pub fn nvidia_loaded() -> bool {
+    Module::all().ok().map_or(false, |modules| modules.iter().any(|x| &x.module == "nvidia"))
 }
 
 /// Microcode packages for specific processor vendors.
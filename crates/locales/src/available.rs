@@ -0,0 +1,261 @@
+//! Lists the locales, keyboard layouts, and time zones which a system supports, so that
+//! frontends offer only those which the image that is installed supports.
+//!
+//! Each list is read from the image when a source is given, before it is extracted, and from
+//! the live system otherwise. Tar archives and remote images can't be read without being
+//! extracted, so the lists of the live system are given for them as well.
+
+use crate::{get_country_name, get_language_name, keyboard_layout::*};
+use serde_xml_rs as xml;
+use squashfs::Source;
+use std::{
+    fs,
+    io::{self, BufReader},
+    path::Path,
+};
+
+const SUPPORTED: &str = "usr/share/i18n/SUPPORTED";
+const XKB_BASE: &str = "usr/share/X11/xkb/rules/base.xml";
+const XKB_EVDEV: &str = "usr/share/X11/xkb/rules/evdev.lst";
+const ZONE_TAB: &str = "usr/share/zoneinfo/zone.tab";
+
+/// A locale which the system can generate.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SupportedLocale {
+    /// The name which `Config::lang` is given, such as `en_US.UTF-8`.
+    pub name:        String,
+    /// The character set of the locale, such as `UTF-8`.
+    pub charset:     String,
+    /// The ISO 639 code of the language, such as `en`.
+    pub language:    String,
+    /// The ISO 3166 code of the country, such as `US`.
+    pub country:     Option<String>,
+    /// The names of the language and country, such as `English (United States)`.
+    pub description: String,
+}
+
+/// A time zone which the system has in its database.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Timezone {
+    /// The Olson name which `Config::timezone` is given, such as `America/New_York`.
+    pub name:        String,
+    /// The ISO 3166 code of the country of the time zone, which `UTC` has none of.
+    pub country:     Option<String>,
+    /// The city and country of the time zone, such as `New York, United States`.
+    pub description: String,
+}
+
+/// The locales which the system can generate, from `/usr/share/i18n/SUPPORTED`.
+pub fn get_locales(source: Option<&Source>) -> io::Result<Vec<SupportedLocale>> {
+    read_list(source, SUPPORTED).map(|list| parse_supported(&list))
+}
+
+/// The keyboard layouts of the live system, and their variants.
+pub fn get_keyboard_layouts() -> io::Result<KeyboardLayouts> { get_keyboard_layouts_of(None) }
+
+/// The keyboard layouts of the system, and their variants.
+///
+/// These are read from XKB's `base.xml`, or from its `evdev.lst` if the system lacks it.
+pub fn get_keyboard_layouts_of(source: Option<&Source>) -> io::Result<KeyboardLayouts> {
+    match read(source, XKB_BASE)? {
+        Some(base) => xml::from_reader(BufReader::new(base.as_slice()))
+            .map_err(|why| io::Error::new(io::ErrorKind::InvalidData, format!("{}", why))),
+        None => read_list(source, XKB_EVDEV).map(|list| parse_evdev(&list)),
+    }
+}
+
+/// The time zones of the system, from `/usr/share/zoneinfo/zone.tab`, and `UTC`.
+pub fn get_timezones(source: Option<&Source>) -> io::Result<Vec<Timezone>> {
+    read_list(source, ZONE_TAB).map(|list| parse_zone_tab(&list))
+}
+
+/// Reads a list which the system must have.
+fn read_list(source: Option<&Source>, path: &str) -> io::Result<String> {
+    let contents = read(source, path)?.ok_or_else(|| {
+        io::Error::new(io::ErrorKind::NotFound, format!("/{} was not found", path))
+    })?;
+
+    Ok(String::from_utf8_lossy(&contents).into_owned())
+}
+
+/// Reads a file of the image, or of the live system if the image can't be read.
+fn read(source: Option<&Source>, path: &str) -> io::Result<Option<Vec<u8>>> {
+    match source {
+        Some(source) if !matches!(*source, Source::Tar(..) | Source::Remote(_)) => {
+            source.read_file(path)
+        }
+        _ => match fs::read(Path::new("/").join(path)) {
+            Ok(contents) => Ok(Some(contents)),
+            Err(ref why) if why.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(why) => Err(why),
+        },
+    }
+}
+
+/// Parses lines such as `en_US.UTF-8 UTF-8` and `sr_RS@latin UTF-8`.
+fn parse_supported(list: &str) -> Vec<SupportedLocale> {
+    list.lines()
+        .filter(|line| !line.starts_with('#'))
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let (name, charset) = (fields.next()?, fields.next()?);
+
+            let (code, modifier) = match name.find('@') {
+                Some(at) => (&name[..at], Some(&name[at + 1..])),
+                None => (name, None),
+            };
+            let code = code.split('.').next().unwrap_or(code);
+            let mut code = code.splitn(2, '_');
+            let language = code.next()?.to_owned();
+            let country = code.next().map(String::from);
+
+            let mut details = Vec::new();
+            details.extend(country.as_ref().map(|c| get_country_name(c).unwrap_or(c.as_str())));
+            details.extend(modifier);
+
+            let mut description =
+                get_language_name(&language).unwrap_or(language.as_str()).to_owned();
+            if !details.is_empty() {
+                description = format!("{} ({})", description, details.join(", "));
+            }
+
+            Some(SupportedLocale {
+                name: name.to_owned(),
+                charset: charset.to_owned(),
+                language,
+                country,
+                description,
+            })
+        })
+        .collect()
+}
+
+/// Parses the layouts and variants of `evdev.lst`, whose sections are headed by lines
+/// such as `! layout`, and whose variants name their layouts, as in `  chr  us: Cherokee`.
+fn parse_evdev(list: &str) -> KeyboardLayouts {
+    let item = |name: &str, description: &str| ConfigItem {
+        name:              name.to_owned(),
+        short_description: None,
+        description:       description.to_owned(),
+    };
+
+    let mut layouts: Vec<KeyboardLayout> = Vec::new();
+    let mut section = "";
+    for line in list.lines() {
+        if line.starts_with('!') {
+            section = line[1..].trim();
+            continue;
+        }
+
+        let line = line.trim();
+        let (name, description) = match line.find(char::is_whitespace) {
+            Some(end) => (&line[..end], line[end..].trim()),
+            None => continue,
+        };
+
+        match section {
+            "layout" => {
+                let config_item = item(name, description);
+                layouts.push(KeyboardLayout { config_item, variant_list: None });
+            }
+            "variant" => {
+                let (layout, description) = match description.find(": ") {
+                    Some(colon) => (&description[..colon], &description[colon + 2..]),
+                    None => continue,
+                };
+
+                if let Some(layout) = layouts.iter_mut().find(|l| l.get_name() == layout) {
+                    let list = layout.variant_list.get_or_insert(VariantList { variant: None });
+                    let variant = KeyboardVariant { config_item: item(name, description) };
+                    list.variant.get_or_insert_with(Vec::new).push(variant);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    KeyboardLayouts { layout_list: LayoutList { layout: layouts } }
+}
+
+/// Parses lines of tab-separated fields, such as `US +404251-0740023 America/New_York`.
+fn parse_zone_tab(list: &str) -> Vec<Timezone> {
+    let mut zones = list
+        .lines()
+        .filter(|line| !line.starts_with('#'))
+        .filter_map(|line| {
+            let mut fields = line.split('\t');
+            let (country, _coordinates, name) = (fields.next()?, fields.next()?, fields.next()?);
+            let city = name.rsplit('/').next().unwrap_or(name).replace('_', " ");
+            let description = format!("{}, {}", city, get_country_name(country).unwrap_or(country));
+
+            Some(Timezone { name: name.to_owned(), country: Some(country.to_owned()), description })
+        })
+        .collect::<Vec<_>>();
+
+    zones.sort_by(|a, b| a.name.cmp(&b.name));
+    zones.push(Timezone {
+        name:        "UTC".into(),
+        country:     None,
+        description: "Coordinated Universal Time".into(),
+    });
+
+    zones
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EVDEV: &str = r#"! model
+  pc105           Generic 105-key PC
+
+! layout
+  us              English (US)
+  cz              Czech
+
+! variant
+  chr             us: Cherokee
+  qwerty          cz: Czech (QWERTY)
+
+! option
+  grp             Switching to another layout
+"#;
+
+    #[test]
+    fn supported() {
+        let locales = parse_supported("en_US.UTF-8 UTF-8\nen_US ISO-8859-1\nsr_RS@latin UTF-8\n");
+        let names = locales.iter().map(|l| l.name.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, vec!["en_US.UTF-8", "en_US", "sr_RS@latin"]);
+        assert_eq!(locales[1].charset, "ISO-8859-1");
+        assert_eq!(locales[2].language, "sr");
+        assert_eq!(locales[2].country.as_deref(), Some("RS"));
+        assert!(locales[2].description.ends_with(", latin)"));
+    }
+
+    #[test]
+    fn evdev() {
+        let layouts = parse_evdev(EVDEV);
+        let layouts = layouts.get_layouts();
+        assert_eq!(layouts.len(), 2);
+        assert_eq!((layouts[0].get_name(), layouts[0].get_description()), ("us", "English (US)"));
+        let variants = layouts[1].get_variants().unwrap();
+        assert_eq!(variants.len(), 1);
+        assert_eq!(variants[0].get_name(), "qwerty");
+        assert_eq!(variants[0].get_description(), "Czech (QWERTY)");
+    }
+
+    #[test]
+    fn zone_tab() {
+        let zones = parse_zone_tab(concat!(
+            "# country\tcoordinates\tTZ\tcomments\n",
+            "US\t+404251-0740023\tAmerica/New_York\tEastern (most areas)\n",
+            "CZ\t+5005+01426\tEurope/Prague\n",
+        ));
+
+        let names = zones.iter().map(|z| z.name.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, vec!["America/New_York", "Europe/Prague", "UTC"]);
+        assert!(zones[0].description.starts_with("New York, "));
+        assert_eq!(zones[1].country.as_deref(), Some("CZ"));
+        assert_eq!(zones[2].country, None);
+    }
+}
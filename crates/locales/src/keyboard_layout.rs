@@ -1,7 +1,3 @@
-use misc;
-use serde_xml_rs as xml;
-use std::io::{self, BufReader};
-
 /// A list of keyboard layouts parsed from `/usr/share/X11/xkb/rules/base.xml`.
 #[derive(Debug, Deserialize)]
 pub struct KeyboardLayouts {
@@ -74,11 +70,3 @@ impl KeyboardVariant {
     /// A description of this variant of a keyboard layout.
     pub fn get_description(&self) -> &str { &self.config_item.description }
 }
-
-const X11_BASE_RULES: &str = "/usr/share/X11/xkb/rules/base.xml";
-
-/// Fetches a list of keyboard layouts from `/usr/share/X11/xkb/rules/base.xml`.
-pub fn get_keyboard_layouts() -> io::Result<KeyboardLayouts> {
-    xml::from_reader(BufReader::new(misc::open(X11_BASE_RULES)?))
-        .map_err(|why| io::Error::new(io::ErrorKind::InvalidData, format!("{}", why)))
-}
@@ -0,0 +1,208 @@
+use super::{cancelled, Progress, Throttle, PROGRESS_INTERVAL};
+use std::{
+    env, fs,
+    io::{Error, ErrorKind, Result},
+    path::Path,
+    process::{Command, Stdio},
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+    time::Duration,
+};
+
+/// How many times a download is attempted, resuming from where the previous attempt stopped.
+const ATTEMPTS: u64 = 5;
+
+/// Whether the source is a URL to download the image from, rather than a local path.
+pub fn is_url(source: &str) -> bool {
+    source.starts_with("http://") || source.starts_with("https://")
+}
+
+/// Downloads the image at the URL to the given path with curl, reporting the bytes
+/// downloaded.
+///
+/// A partial download at the path is resumed with a range request, and interrupted
+/// downloads are retried from where they stopped. Proxies are taken from the `http_proxy`,
+/// `https_proxy`, and `no_proxy` environment variables, in either case.
+pub fn download<F: FnMut(&Progress)>(
+    url: &str,
+    path: &Path,
+    cancel: &AtomicBool,
+    mut callback: F,
+) -> Result<()> {
+    let total = content_length(url);
+    let mut throttle = Throttle::default();
+
+    for attempt in 1..=ATTEMPTS {
+        match fetch(url, path, total, cancel, &mut throttle, &mut callback) {
+            Ok(()) => break,
+            Err(why) if attempt < ATTEMPTS && is_transient(&why) => {
+                warn!("download of {} was interrupted, resuming: {}", url, why);
+                thread::sleep(Duration::from_secs(attempt * 2));
+            }
+            Err(why) => return Err(why),
+        }
+    }
+
+    let bytes = path.metadata()?.len();
+    callback(&Progress { bytes, total: total.unwrap_or(bytes), path: None });
+    Ok(())
+}
+
+fn fetch(
+    url: &str,
+    path: &Path,
+    total: Option<u64>,
+    cancel: &AtomicBool,
+    throttle: &mut Throttle,
+    callback: &mut dyn FnMut(&Progress),
+) -> Result<()> {
+    let mut command = curl();
+    command
+        .args(&["--continue-at", "-", "--output"])
+        .arg(path)
+        .arg(url)
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped());
+
+    debug!("{:?}", command);
+
+    let mut child = command.spawn()?;
+    let status = loop {
+        if cancel.load(Ordering::SeqCst) {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(cancelled());
+        }
+
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+
+        if throttle.ready() {
+            let bytes = path.metadata().map(|m| m.len()).unwrap_or(0);
+            callback(&Progress { bytes, total: total.unwrap_or(0), path: None });
+        }
+
+        thread::sleep(PROGRESS_INTERVAL);
+    };
+
+    if status.success() {
+        return Ok(());
+    }
+
+    let output = child.wait_with_output()?;
+    let message = String::from_utf8_lossy(&output.stderr);
+    let message = message.trim().trim_start_matches("curl: ");
+
+    match status.code() {
+        // The server does not support range requests, so the download must start over.
+        Some(33) => {
+            fs::remove_file(path)?;
+            Err(Error::new(ErrorKind::UnexpectedEof, message))
+        }
+        Some(code) => Err(curl_error(code, url, message)),
+        None => Err(Error::new(ErrorKind::Other, format!("curl was killed: {}", status))),
+    }
+}
+
+/// Requests the headers of the URL for the size of the image, if the server gives it.
+fn content_length(url: &str) -> Option<u64> {
+    let output = match curl().arg("--head").arg(url).stderr(Stdio::null()).output() {
+        Ok(ref output) if output.status.success() => output.stdout.clone(),
+        _ => return None,
+    };
+
+    // Redirects list the headers of every response, and the last is of the image.
+    String::from_utf8_lossy(&output)
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.splitn(2, ':');
+            let name = fields.next()?.trim();
+            if name.eq_ignore_ascii_case("content-length") {
+                fields.next()?.trim().parse::<u64>().ok()
+            } else {
+                None
+            }
+        })
+        .last()
+}
+
+fn curl() -> Command {
+    let mut command = Command::new("curl");
+    command.args(&[
+        "--fail",
+        "--location",
+        "--silent",
+        "--show-error",
+        "--connect-timeout",
+        "30",
+        // Abort stalled transfers, so that they may be resumed.
+        "--speed-limit",
+        "1024",
+        "--speed-time",
+        "60",
+    ]);
+
+    // curl only reads the lowercase name of the HTTP proxy variable.
+    if env::var_os("http_proxy").is_none() {
+        if let Some(proxy) = env::var_os("HTTP_PROXY") {
+            command.env("http_proxy", proxy);
+        }
+    }
+
+    command
+}
+
+/// Describes the failure of curl by its exit code.
+fn curl_error(code: i32, url: &str, message: &str) -> Error {
+    let (kind, description) = match code {
+        5 | 6 => (ErrorKind::NotFound, "could not resolve host"),
+        7 => (ErrorKind::ConnectionRefused, "could not connect"),
+        18 => (ErrorKind::UnexpectedEof, "transfer was interrupted"),
+        22 => (ErrorKind::Other, "server responded with an error"),
+        23 => (ErrorKind::Other, "could not write the download"),
+        28 => (ErrorKind::TimedOut, "transfer timed out"),
+        16 | 52 | 55 | 56 | 92 => (ErrorKind::ConnectionReset, "connection was lost"),
+        35 | 51 | 53 | 54 | 58 | 59 | 60 | 64 | 66 | 77 | 80 | 82 | 83 | 90 | 91 => {
+            (ErrorKind::Other, "TLS error")
+        }
+        _ => (ErrorKind::Other, "download failed"),
+    };
+
+    Error::new(kind, format!("{} while downloading {}: {}", description, url, message))
+}
+
+/// Errors after which the download may be resumed.
+fn is_transient(why: &Error) -> bool {
+    match why.kind() {
+        ErrorKind::UnexpectedEof
+        | ErrorKind::TimedOut
+        | ErrorKind::ConnectionReset
+        | ErrorKind::ConnectionRefused => true,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn urls() {
+        assert!(is_url("https://cdn.example.com/rootfs.squashfs"));
+        assert!(is_url("http://10.0.0.1/rootfs.tar.zst"));
+        assert!(!is_url("/cdrom/casper/filesystem.squashfs"));
+        assert!(!is_url("ftp://example.com/rootfs.squashfs"));
+    }
+
+    #[test]
+    fn errors() {
+        let tls = curl_error(60, "https://a/b", "SSL certificate problem");
+        assert_eq!(
+            tls.to_string(),
+            "TLS error while downloading https://a/b: SSL certificate problem"
+        );
+        assert!(!is_transient(&tls));
+        assert!(is_transient(&curl_error(18, "https://a/b", "")));
+    }
+}
@@ -0,0 +1,132 @@
+//! Whiteouts, which delete the files of lower layers, as defined by overlayfs and aufs.
+//!
+//! In overlayfs, a whiteout is a character device with the device number 0/0, and a directory
+//! which hides the contents of lower layers is marked as opaque with an extended attribute.
+//! In aufs, a whiteout is an empty file whose name is prefixed with `.wh.`, and an opaque
+//! directory contains a `.wh..wh..opq` file.
+
+use super::unsquashfs::ListEntry;
+use std::{
+    collections::HashSet,
+    ffi::{CString, OsStr},
+    fs,
+    io::{Error, ErrorKind, Result},
+    os::unix::ffi::OsStrExt,
+    path::Path,
+};
+
+const AUFS_WHITEOUT: &str = ".wh.";
+const AUFS_OPAQUE: &str = ".wh..wh..opq";
+const OVERLAY_OPAQUE: &str = "trusted.overlay.opaque";
+
+fn is_overlay_whiteout(entry: &ListEntry) -> bool {
+    entry.kind == 'c' && entry.device == Some((0, 0))
+}
+
+fn aufs_whiteout(path: &Path) -> Option<&str> {
+    path.file_name().and_then(OsStr::to_str).filter(|name| name.starts_with(AUFS_WHITEOUT))
+}
+
+/// Removes the files of lower layers which are whited out by the layer, before it is
+/// extracted.
+pub fn remove_whited_out(listing: &[ListEntry]) -> Result<()> {
+    for entry in listing {
+        let path = Path::new(&entry.path);
+        if is_overlay_whiteout(entry) {
+            remove(path)?;
+        } else if let Some(name) = aufs_whiteout(path) {
+            let parent = path.parent().unwrap_or(path);
+            if name == AUFS_OPAQUE {
+                for child in fs::read_dir(parent).into_iter().flatten() {
+                    remove(&child?.path())?;
+                }
+            } else {
+                remove(&parent.join(&name[AUFS_WHITEOUT.len()..]))?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Removes the whiteouts of the layer once it has been extracted, and the files of lower
+/// layers from directories that the layer marks as opaque, along with the opaque markers.
+pub fn finish_layer(listing: &[ListEntry]) -> Result<()> {
+    let paths: HashSet<&Path> = listing.iter().map(|entry| Path::new(&entry.path)).collect();
+
+    for entry in listing {
+        let path = Path::new(&entry.path);
+        if is_overlay_whiteout(entry) || aufs_whiteout(path).is_some() {
+            remove(path)?;
+        } else if entry.kind == 'd' && is_overlay_opaque(path) {
+            for child in fs::read_dir(path)? {
+                let child = child?.path();
+                if !paths.contains(child.as_path()) {
+                    remove(&child)?;
+                }
+            }
+
+            remove_overlay_opaque(path)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn is_overlay_opaque(path: &Path) -> bool {
+    let path = match CString::new(path.as_os_str().as_bytes()) {
+        Ok(path) => path,
+        Err(_) => return false,
+    };
+
+    let name = CString::new(OVERLAY_OPAQUE).expect("attribute name contains a nul byte");
+
+    let mut value = [0u8; 1];
+    let read = unsafe {
+        libc::lgetxattr(path.as_ptr(), name.as_ptr(), value.as_mut_ptr() as *mut libc::c_void, 1)
+    };
+
+    read == 1 && value[0] == b'y'
+}
+
+/// Removes the attribute which marks a directory as opaque, as it has no use in the install.
+fn remove_overlay_opaque(path: &Path) -> Result<()> {
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    let name = CString::new(OVERLAY_OPAQUE).expect("attribute name contains a nul byte");
+
+    match unsafe { libc::lremovexattr(path.as_ptr(), name.as_ptr()) } {
+        -1 => Err(Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+/// Removes a file or directory, if it exists.
+fn remove(path: &Path) -> Result<()> {
+    let result = match path.symlink_metadata() {
+        Ok(ref metadata) if metadata.is_dir() => fs::remove_dir_all(path),
+        Ok(_) => fs::remove_file(path),
+        Err(why) => Err(why),
+    };
+
+    match result {
+        Err(ref why) if why.kind() == ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn whiteouts() {
+        let entry = |kind, device| ListEntry { path: "root/etc/a".into(), size: 0, kind, device };
+        assert!(is_overlay_whiteout(&entry('c', Some((0, 0)))));
+        assert!(!is_overlay_whiteout(&entry('c', Some((5, 1)))));
+        assert!(!is_overlay_whiteout(&entry('-', None)));
+
+        assert_eq!(aufs_whiteout(Path::new("root/etc/.wh.hosts")), Some(".wh.hosts"));
+        assert_eq!(aufs_whiteout(Path::new("root/etc/.wh..wh..opq")), Some(AUFS_OPAQUE));
+        assert_eq!(aufs_whiteout(Path::new("root/etc/hosts")), None);
+    }
+}
@@ -2,18 +2,127 @@ extern crate libc;
 #[macro_use]
 extern crate log;
 
+mod download;
+mod layers;
+mod size;
+mod source;
+mod unsquashfs;
+mod verify;
+
+pub use self::{
+    download::{download, is_url},
+    size::{file_sizes, FileSize},
+    source::{Compression, Source},
+    verify::{copy_with_checksum, verify_checksum, verify_source, verify_superblock},
+};
+
 use std::{
+    ffi::OsString,
     fs::File,
-    io::{Error, ErrorKind, Read, Result},
-    os::unix::{
-        io::{AsRawFd, FromRawFd, RawFd},
-        process::CommandExt,
-    },
+    io::{BufRead, BufReader, Error, ErrorKind, Read, Result, Write},
+    os::unix::io::{AsRawFd, FromRawFd, RawFd},
     path::Path,
-    process::{Command, Stdio},
-    str,
+    process::{Command, ExitStatus, Stdio},
+    slice,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc,
+    },
+    thread,
+    time::{Duration, Instant},
 };
 
+/// The minimum amount of time between progress updates.
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How often the extracted files are written back to the disk, while extracting.
+const WRITEBACK_INTERVAL: Duration = Duration::from_secs(2);
+
+/// The most threads which are used to decompress an image by default, as the disk is the
+/// bottleneck beyond that.
+const MAX_THREADS: usize = 8;
+
+/// The progress of an extraction, measured in bytes where the source allows it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Progress<'a> {
+    /// Bytes of file data which have been extracted, or of the archive which have been read.
+    pub bytes: u64,
+    /// What `bytes` will be once the extraction has completed.
+    pub total: u64,
+    /// The file which was most recently extracted.
+    pub path:  Option<&'a str>,
+}
+
+impl<'a> Progress<'a> {
+    pub fn percent(&self) -> i32 {
+        if self.total == 0 {
+            0
+        } else {
+            (self.bytes.min(self.total) * 100 / self.total) as i32
+        }
+    }
+}
+
+/// Limits progress updates to one every `PROGRESS_INTERVAL`.
+#[derive(Default)]
+struct Throttle {
+    last_update: Option<Instant>,
+}
+
+impl Throttle {
+    fn ready(&mut self) -> bool {
+        if self.last_update.map_or(false, |last| last.elapsed() < PROGRESS_INTERVAL) {
+            return false;
+        }
+
+        self.last_update = Some(Instant::now());
+        true
+    }
+}
+
+/// The number of threads which decompress an image, if it was not given.
+///
+/// One processor is left to the live desktop, so that it stays responsive.
+pub fn default_threads() -> usize {
+    let processors = thread::available_parallelism().map_or(1, |processors| processors.get());
+    processors.saturating_sub(1).max(1).min(MAX_THREADS)
+}
+
+/// Writes the extracted files back to the disk while the image is decompressed, so that the
+/// disk is kept busy, rather than flushing everything once the extraction has finished.
+struct Writeback {
+    stop:   mpsc::Sender<()>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Writeback {
+    fn start(directory: &Path) -> Result<Self> {
+        let target = File::open(directory)?;
+        let (stop, stopped) = mpsc::channel();
+        let thread = thread::spawn(move || {
+            while let Err(mpsc::RecvTimeoutError::Timeout) =
+                stopped.recv_timeout(WRITEBACK_INTERVAL)
+            {
+                if unsafe { libc::syncfs(target.as_raw_fd()) } < 0 {
+                    warn!("failed to write back extracted files: {}", Error::last_os_error());
+                    break;
+                }
+            }
+        });
+
+        Ok(Writeback { stop, thread: Some(thread) })
+    }
+}
+
+impl Drop for Writeback {
+    fn drop(&mut self) {
+        let _ = self.stop.send(());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
 fn getpty(columns: u32, lines: u32) -> (RawFd, String) {
     use std::{
         ffi::CStr,
@@ -96,104 +205,317 @@ fn before_exec() -> Result<()> {
     Ok(())
 }
 
-fn handle<F: FnMut(i32)>(mut master: File, mut callback: F) -> Result<()> {
-    let mut last_progress = 0;
-    loop {
-        let mut data = [0; 0x1000];
-        let count = master.read(&mut data)?;
-        if count == 0 {
-            return Ok(());
-        }
-        if let Ok(string) = str::from_utf8(&data[..count]) {
-            for line in string.split(|c| c == '\r' || c == '\n') {
-                let len = line.len();
-                if line.starts_with('[') && line.ends_with('%') && len >= 4 {
-                    if let Ok(progress) = line[len - 4..len - 1].trim().parse::<i32>() {
-                        if last_progress != progress {
-                            callback(progress);
-                            last_progress = progress;
-                        }
-                    }
-                }
-            }
+/// Extracts an image using either unsquashfs or tar.
+pub fn extract<P: AsRef<Path>, Q: AsRef<Path>, F: FnMut(i32)>(
+    archive: P,
+    directory: Q,
+    mut callback: F,
+) -> Result<()> {
+    let mut last_percent = 0;
+    extract_with_progress(archive, directory, |progress| {
+        let percent = progress.percent();
+        if percent != last_percent {
+            callback(percent);
+            last_percent = percent;
         }
-    }
-}
-
-enum ExtractFormat {
-    Tar,
-    Squashfs,
+    })
 }
 
-/// Extracts an image using either unsquashfs or tar.
-pub fn extract<P: AsRef<Path>, Q: AsRef<Path>, F: FnMut(i32)>(
+/// Extracts an image, whose kind is detected, reporting the bytes extracted.
+pub fn extract_with_progress<P: AsRef<Path>, Q: AsRef<Path>, F: FnMut(&Progress)>(
     archive: P,
     directory: Q,
     callback: F,
 ) -> Result<()> {
-    let archive = archive.as_ref().canonicalize()?;
-    let directory = directory.as_ref().canonicalize()?;
+    let source = Source::detect(archive)?;
+    extract_source(&source, directory.as_ref(), 0, &AtomicBool::new(false), callback)
+}
 
-    let directory = directory
-        .to_str()
-        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Invalid directory path"))?
-        .replace("'", "'\"'\"'");
+/// Extracts or copies the source into the directory, reporting the bytes extracted.
+///
+/// The progress of a squashfs image is measured against the sizes of its files, which are
+/// listed before it is extracted, and the progress of a tar archive by how much of the
+/// archive has been read. The progress of a directory is measured against the sizes of its
+/// files, which are summed before it is copied. Layered images are extracted in order, with
+/// their progress measured against the files of every layer.
+///
+/// Squashfs images are decompressed by `threads` processors, or by `default_threads` if it
+/// is zero. The extracted files are written back to the disk as the extraction proceeds.
+///
+/// Once `cancel` is set, the extraction is stopped with an `Interrupted` error.
+pub fn extract_source<F: FnMut(&Progress)>(
+    source: &Source,
+    directory: &Path,
+    threads: usize,
+    cancel: &AtomicBool,
+    mut callback: F,
+) -> Result<()> {
+    let directory = directory.canonicalize()?;
+    let threads = if threads == 0 { default_threads() } else { threads };
+    info!("extracting {:?} to {} with {} threads", source, directory.display(), threads);
 
-    let format = if archive.extension().map_or(false, |ext| ext == "squashfs") {
-        ExtractFormat::Squashfs
-    } else {
-        ExtractFormat::Tar
+    let writeback = Writeback::start(&directory)?;
+    let start = Instant::now();
+    let mut extracted = 0;
+    let callback = |progress: &Progress| {
+        extracted = progress.bytes;
+        callback(progress);
     };
 
-    let archive = archive
-        .to_str()
-        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Invalid archive path"))?
-        .replace("'", "'\"'\"'");
+    let result = match *source {
+        Source::Squashfs(ref archive) => {
+            unsquashfs::extract(slice::from_ref(archive), &directory, threads, cancel, callback)
+        }
+        Source::Layered(ref layers) => {
+            unsquashfs::extract(layers, &directory, threads, cancel, callback)
+        }
+        Source::Tar(ref archive, compression) => {
+            extract_tar(archive, compression, &directory, cancel, callback)
+        }
+        Source::Directory { ref path, delete } => {
+            copy_directory(path, &directory, delete, cancel, callback)
+        }
+        Source::Remote(ref url) => Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("{} must be downloaded before it is extracted", url),
+        )),
+    };
+
+    drop(writeback);
+    result?;
+
+    let elapsed = start.elapsed().as_secs_f64();
+    info!(
+        "extracted {} MB in {:.1} seconds, at {:.1} MB/s",
+        extracted / 1_000_000,
+        elapsed,
+        throughput(extracted, elapsed)
+    );
+
+    Ok(())
+}
+
+/// The rate of extraction, in megabytes per second.
+fn throughput(bytes: u64, seconds: f64) -> f64 {
+    if seconds > 0.0 {
+        bytes as f64 / 1_000_000.0 / seconds
+    } else {
+        0.0
+    }
+}
+
+/// Streams the archive into tar, so that the progress may be measured by the bytes read.
+fn extract_tar<F: FnMut(&Progress)>(
+    archive: &Path,
+    compression: Compression,
+    directory: &Path,
+    cancel: &AtomicBool,
+    mut callback: F,
+) -> Result<()> {
+    let total = archive.metadata()?.len();
+    let mut file = File::open(archive)?;
+
+    let mut command = Command::new("tar");
+    command
+        .args(&[
+            "--extract",
+            "--verbose",
+            "--overwrite",
+            "--numeric-owner",
+            "--same-owner",
+            "--preserve-permissions",
+            "--acls",
+            "--xattrs",
+            "--xattrs-include=*",
+        ])
+        .args(compression.tar_arg())
+        .arg("--directory")
+        .arg(directory)
+        .arg("--file=-")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit());
+
+    debug!("{:?}", command);
+
+    let mut child = command.spawn()?;
+    let mut stdin = child.stdin.take().expect("stdin of tar is piped");
+    let stdout = child.stdout.take().expect("stdout of tar is piped");
+
+    // The verbose output of tar names each file as it is extracted. It must be read until
+    // tar exits, so that tar never blocks on writing it.
+    let (paths_tx, paths_rx) = mpsc::channel();
+    let lister = thread::spawn(move || {
+        let mut stdout = BufReader::new(stdout);
+        let mut line = Vec::new();
+        while let Ok(read) = stdout.read_until(b'\n', &mut line) {
+            if read == 0 {
+                break;
+            }
 
-    let mut command = match format {
-        ExtractFormat::Squashfs => {
-            let mut command = Command::new("unsquashfs");
-            command.arg("-f").arg("-d").arg(directory).arg(archive);
-            command
+            let _ = paths_tx.send(String::from_utf8_lossy(&line).trim_end().to_owned());
+            line.clear();
         }
-        ExtractFormat::Tar => {
-            let mut command = Command::new("tar");
-            command.arg("--overwrite").arg("-xf").arg(archive).arg("-C").arg(directory);
-            command
+    });
+
+    let mut buffer = vec![0; 64 * 1024];
+    let mut bytes = 0;
+    let mut path = None;
+    let mut throttle = Throttle::default();
+
+    let result = loop {
+        if cancel.load(Ordering::SeqCst) {
+            break Err(cancelled());
+        }
+
+        let read = match file.read(&mut buffer) {
+            Ok(0) => break Ok(()),
+            Ok(read) => read,
+            Err(why) => break Err(why),
+        };
+
+        // If tar exits early, the reason is given by its exit status.
+        if stdin.write_all(&buffer[..read]).is_err() {
+            break Ok(());
+        }
+
+        bytes += read as u64;
+        if let Some(latest) = paths_rx.try_iter().last() {
+            path = Some(latest);
+        }
+
+        if throttle.ready() {
+            callback(&Progress { bytes, total, path: path.as_ref().map(String::as_str) });
         }
     };
 
+    drop(stdin);
+    if result.is_err() {
+        let _ = child.kill();
+    }
+
+    let status = child.wait()?;
+    let _ = lister.join();
+    result?;
+
+    callback(&Progress { bytes, total, path: None });
+    exit_status(status)
+}
+
+/// Copies an unpacked root file system with rsync, preserving hard links, sparse files,
+/// ownership, ACLs, and extended attributes, including file capabilities.
+///
+/// If the target is within the source, it is excluded from the copy.
+fn copy_directory<F: FnMut(&Progress)>(
+    source: &Path,
+    directory: &Path,
+    delete: bool,
+    cancel: &AtomicBool,
+    mut callback: F,
+) -> Result<()> {
+    let source = source.canonicalize()?;
+    if directory == source {
+        return Err(Error::new(ErrorKind::InvalidInput, "source and target are the same"));
+    }
+
+    let excluded = directory.strip_prefix(&source).ok();
+
+    let total = directory_size(&source, directory, cancel)?;
+
+    let mut command = Command::new("rsync");
+    command.args(&[
+        "--archive",
+        "--hard-links",
+        "--acls",
+        "--xattrs",
+        "--sparse",
+        "--numeric-ids",
+        "--no-inc-recursive",
+        "--info=progress2",
+    ]);
+
+    if delete {
+        command.arg("--delete");
+    }
+
+    if let Some(excluded) = excluded {
+        // Anchored to the root of the transfer.
+        let mut exclude = OsString::from("--exclude=/");
+        exclude.push(excluded);
+        command.arg(exclude);
+    }
+
+    command
+        // The trailing slashes copy the contents of the source, rather than the source.
+        .arg(source.join(""))
+        .arg(directory.join(""))
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit());
+
     debug!("{:?}", command);
 
-    let (master_fd, tty_path) = getpty(80, 30);
-    let mut child = {
-        let (slave_stdin, slave_stdout, slave_stderr) = slave_stdio(&tty_path)?;
-
-        unsafe {
-            command
-                .stdin(Stdio::from_raw_fd(slave_stdin.as_raw_fd()))
-                .stdout(Stdio::from_raw_fd(slave_stdout.as_raw_fd()))
-                .stderr(Stdio::from_raw_fd(slave_stderr.as_raw_fd()))
-                .env("COLUMNS", "")
-                .env("LINES", "")
-                .env("TERM", "xterm-256color")
-                .pre_exec(before_exec)
-                .spawn()?
+    let mut child = command.spawn()?;
+    let mut stdout = BufReader::new(child.stdout.take().expect("stdout of rsync is piped"));
+    let mut line = Vec::new();
+    let mut bytes = 0;
+    let mut throttle = Throttle::default();
+
+    let result = loop {
+        if cancel.load(Ordering::SeqCst) {
+            break Err(cancelled());
+        }
+
+        line.clear();
+        match stdout.read_until(b'\r', &mut line) {
+            Ok(0) => break Ok(()),
+            Ok(_) => (),
+            Err(why) => break Err(why),
+        }
+
+        if let Some(copied) = parse_rsync_progress(&String::from_utf8_lossy(&line)) {
+            bytes = copied;
+            if throttle.ready() {
+                callback(&Progress { bytes, total, path: None });
+            }
         }
     };
 
-    let master = unsafe { File::from_raw_fd(master_fd) };
-    match handle(master, callback) {
-        Ok(()) => (),
-        Err(err) => match err.raw_os_error() {
-            // EIO happens when slave end is closed
-            Some(libc::EIO) => (),
-            // Log other errors, use status code below to return
-            _ => error!("handle error: {}", err),
-        },
+    if result.is_err() {
+        let _ = child.kill();
     }
 
     let status = child.wait()?;
+    result?;
+
+    callback(&Progress { bytes: total.max(bytes), total, path: None });
+    exit_status(status)
+}
+
+/// Sums the sizes of the files within a directory, counting hard-linked files once, and
+/// skipping the excluded directory.
+fn directory_size(path: &Path, excluded: &Path, cancel: &AtomicBool) -> Result<u64> {
+    let mut size = 0;
+    size::walk(path, excluded, cancel, |_, metadata| {
+        if metadata.is_file() {
+            size += metadata.len();
+        }
+    })?;
+
+    Ok(size)
+}
+
+/// Parses the bytes copied from the progress of `rsync --info=progress2`, such as
+/// `  1,238,099,968  42%   95.37MB/s    0:00:12 (xfr#1024, to-chk=96/2048)`.
+fn parse_rsync_progress(line: &str) -> Option<u64> {
+    let mut fields = line.split_whitespace();
+    let bytes = fields.next()?.replace(',', "");
+    fields.next()?.strip_suffix('%')?;
+    bytes.parse().ok()
+}
+
+fn cancelled() -> Error { Error::new(ErrorKind::Interrupted, "extraction was cancelled") }
+
+fn exit_status(status: ExitStatus) -> Result<()> {
     if status.success() {
         Ok(())
     } else {
@@ -203,3 +525,25 @@ pub fn extract<P: AsRef<Path>, Q: AsRef<Path>, F: FnMut(i32)>(
         ))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn threads() {
+        let threads = default_threads();
+        assert!(threads >= 1 && threads <= MAX_THREADS);
+        assert_eq!(throughput(250_000_000, 2.0), 125.0);
+        assert_eq!(throughput(250_000_000, 0.0), 0.0);
+    }
+
+    #[test]
+    fn rsync_progress() {
+        assert_eq!(
+            parse_rsync_progress("  1,238,099,968  42%   95.37MB/s    0:00:12 (xfr#1024)"),
+            Some(1_238_099_968)
+        );
+        assert_eq!(parse_rsync_progress("sending incremental file list"), None);
+    }
+}
@@ -0,0 +1,177 @@
+//! The space which the files of a source will take once they are extracted.
+
+use super::{
+    cancelled,
+    unsquashfs::{self, ListEntry},
+    Source,
+};
+use std::{
+    collections::HashSet,
+    fs::{self, Metadata},
+    io::{Error, ErrorKind, Result},
+    os::unix::fs::MetadataExt,
+    path::{Component, Path, PathBuf},
+    process::{Command, Stdio},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+/// The block size of the file systems which are installed to.
+const BLOCK_SIZE: u64 = 4096;
+
+/// A file of a source, and the space which it will take once it is extracted.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileSize {
+    /// The path of the file, relative to the root of the source.
+    pub path: PathBuf,
+    /// The size of the file, in whole blocks.
+    pub size: u64,
+}
+
+/// Lists the space which each file of a source will take once it is extracted.
+///
+/// Tar archives must be decompressed to be listed. The files of every layer of a layered
+/// image are counted, so the space is overestimated when a layer replaces a file.
+pub fn file_sizes(source: &Source, cancel: &AtomicBool) -> Result<Vec<FileSize>> {
+    match *source {
+        Source::Squashfs(ref archive) => squashfs_sizes(archive),
+        Source::Layered(ref layers) => {
+            let mut sizes = Vec::new();
+            for layer in layers {
+                if cancel.load(Ordering::SeqCst) {
+                    return Err(cancelled());
+                }
+
+                sizes.extend(squashfs_sizes(layer)?);
+            }
+
+            Ok(sizes)
+        }
+        Source::Tar(ref archive, compression) => {
+            let output = Command::new("tar")
+                .args(&["--list", "--verbose", "--numeric-owner"])
+                .args(compression.tar_arg())
+                .arg("--file")
+                .arg(archive)
+                .stderr(Stdio::inherit())
+                .output()?;
+
+            if !output.status.success() {
+                return Err(Error::new(
+                    ErrorKind::Other,
+                    format!("listing archive contents failed with status: {}", output.status),
+                ));
+            }
+
+            Ok(String::from_utf8_lossy(&output.stdout)
+                .lines()
+                .filter_map(unsquashfs::parse_listing)
+                .map(|entry| FileSize {
+                    size: listed_size(&entry),
+                    path: relative(entry.path.as_ref()),
+                })
+                .collect())
+        }
+        Source::Directory { ref path, .. } => {
+            let mut sizes = Vec::new();
+            walk(path, Path::new(""), cancel, |file, metadata| {
+                let path = file.strip_prefix(path).unwrap_or(file).to_path_buf();
+                let size = if metadata.is_dir() { BLOCK_SIZE } else { blocks(metadata.len()) };
+                sizes.push(FileSize { path, size });
+            })?;
+
+            Ok(sizes)
+        }
+        Source::Remote(ref url) => Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("the size of {} is not known until it is downloaded", url),
+        )),
+    }
+}
+
+/// Walks the files and directories within a directory, skipping the excluded directory.
+///
+/// Files with more than one hard link are only given once.
+pub(crate) fn walk<F: FnMut(&Path, &Metadata)>(
+    path: &Path,
+    excluded: &Path,
+    cancel: &AtomicBool,
+    mut func: F,
+) -> Result<()> {
+    let mut linked = HashSet::new();
+    let mut directories: Vec<PathBuf> = vec![path.to_path_buf()];
+
+    while let Some(directory) = directories.pop() {
+        if cancel.load(Ordering::SeqCst) {
+            return Err(cancelled());
+        }
+
+        for entry in fs::read_dir(&directory)? {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            let path = entry.path();
+
+            if metadata.is_dir() {
+                if path != excluded {
+                    func(&path, &metadata);
+                    directories.push(path);
+                }
+            } else if metadata.is_file()
+                && (metadata.nlink() == 1 || linked.insert((metadata.dev(), metadata.ino())))
+            {
+                func(&path, &metadata);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn squashfs_sizes(archive: &Path) -> Result<Vec<FileSize>> {
+    let archive = archive
+        .to_str()
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Invalid archive path"))?;
+
+    Ok(unsquashfs::listing(archive, "squashfs-root")?
+        .iter()
+        .map(|entry| {
+            let path = Path::new(&entry.path);
+            let path = path.strip_prefix("squashfs-root").unwrap_or(path);
+            FileSize { size: listed_size(entry), path: relative(path) }
+        })
+        .collect())
+}
+
+/// Directories take at least a block, and symlinks and devices are stored in their inodes.
+fn listed_size(entry: &ListEntry) -> u64 {
+    match entry.kind {
+        '-' => blocks(entry.size),
+        'd' => BLOCK_SIZE,
+        _ => 0,
+    }
+}
+
+fn blocks(size: u64) -> u64 { (size + BLOCK_SIZE - 1) / BLOCK_SIZE * BLOCK_SIZE }
+
+/// Archives may list their files as `./etc/hosts`, or as `/etc/hosts`.
+fn relative(path: &Path) -> PathBuf {
+    path.components().filter(|c| matches!(c, Component::Normal(_))).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn listed_sizes() {
+        let entry = |kind, size| ListEntry { path: "./etc".into(), size, kind, device: None };
+        assert_eq!(listed_size(&entry('-', 0)), 0);
+        assert_eq!(listed_size(&entry('-', 1)), 4096);
+        assert_eq!(listed_size(&entry('-', 4097)), 8192);
+        assert_eq!(listed_size(&entry('d', 64)), 4096);
+        assert_eq!(listed_size(&entry('l', 7)), 0);
+
+        assert_eq!(relative(Path::new("./etc/hosts")), Path::new("etc/hosts"));
+        assert_eq!(relative(Path::new("/etc/hosts")), Path::new("etc/hosts"));
+        assert_eq!(relative(Path::new(".")), Path::new(""));
+    }
+}
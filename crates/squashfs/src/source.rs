@@ -0,0 +1,284 @@
+use super::unsquashfs;
+use std::{
+    fs::{self, File},
+    io::{Error, ErrorKind, Read, Result},
+    path::{Path, PathBuf},
+};
+
+/// The compression of a tar archive, which must be given to tar when it reads from a pipe.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Bzip2,
+    Xz,
+    Zstd,
+}
+
+impl Compression {
+    /// The argument that tells tar how to decompress the archive.
+    pub fn tar_arg(self) -> Option<&'static str> {
+        match self {
+            Compression::None => None,
+            Compression::Gzip => Some("--gzip"),
+            Compression::Bzip2 => Some("--bzip2"),
+            Compression::Xz => Some("--xz"),
+            Compression::Zstd => Some("--zstd"),
+        }
+    }
+}
+
+/// Where the base system is extracted or copied from.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Source {
+    /// A squashfs image, which is extracted with `unsquashfs`.
+    Squashfs(PathBuf),
+    /// A base squashfs image, followed by the squashfs images which are extracted over it
+    /// in order.
+    Layered(Vec<PathBuf>),
+    /// A tar archive, which is streamed into `tar`.
+    Tar(PathBuf, Compression),
+    /// An image or archive at an HTTP or HTTPS URL, which must be downloaded with
+    /// `download` before it can be extracted.
+    Remote(String),
+    /// An unpacked root file system, which is copied with `rsync`.
+    Directory {
+        path:   PathBuf,
+        /// Delete files in the target which are not in the source, as when reinstalling
+        /// over an existing root.
+        delete: bool,
+    },
+}
+
+impl Source {
+    /// Determines the kind of source at the given path, by the magic bytes of the file, or
+    /// by its extension if the magic is not recognized.
+    pub fn detect<P: AsRef<Path>>(path: P) -> Result<Source> {
+        let path = path.as_ref().canonicalize()?;
+        if path.is_dir() {
+            return Ok(Source::Directory { path, delete: false });
+        }
+
+        let mut header = [0; 512];
+        let mut read = 0;
+        {
+            let mut file = File::open(&path)?;
+            while read < header.len() {
+                match file.read(&mut header[read..])? {
+                    0 => break,
+                    count => read += count,
+                }
+            }
+        }
+
+        match from_magic(&header[..read]).or_else(|| from_extension(&path)) {
+            Some(SourceKind::Squashfs) => Ok(Source::Squashfs(path)),
+            Some(SourceKind::Tar(compression)) => Ok(Source::Tar(path, compression)),
+            None => Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("{} is not a squashfs image or tar archive", path.display()),
+            )),
+        }
+    }
+
+    /// Extracts the given squashfs images over a squashfs source, in order.
+    pub fn with_layers(self, layers: Vec<PathBuf>) -> Result<Source> {
+        if layers.is_empty() {
+            return Ok(self);
+        }
+
+        let base = match self {
+            Source::Squashfs(base) => base,
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "layers may only be applied over a local squashfs image",
+                ))
+            }
+        };
+
+        let mut images = vec![base];
+        for layer in layers {
+            match Source::detect(&layer)? {
+                Source::Squashfs(layer) => images.push(layer),
+                _ => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidInput,
+                        format!("layer {} is not a squashfs image", layer.display()),
+                    ))
+                }
+            }
+        }
+
+        Ok(Source::Layered(images))
+    }
+
+    /// Reads a file of the base system, such as `etc/passwd`, before it is extracted.
+    ///
+    /// The file of the last layer which has it is read. Files of tar archives and remote
+    /// sources are `None`, as the archive would have to be read in full.
+    pub fn read_file(&self, path: &str) -> Result<Option<Vec<u8>>> {
+        match *self {
+            Source::Squashfs(ref archive) => unsquashfs::cat(archive, path),
+            Source::Layered(ref layers) => {
+                for layer in layers.iter().rev() {
+                    if let Some(contents) = unsquashfs::cat(layer, path)? {
+                        return Ok(Some(contents));
+                    }
+                }
+
+                Ok(None)
+            }
+            Source::Directory { path: ref root, .. } => match fs::read(root.join(path)) {
+                Ok(contents) => Ok(Some(contents)),
+                Err(ref why) if why.kind() == ErrorKind::NotFound => Ok(None),
+                Err(why) => Err(why),
+            },
+            Source::Tar(..) | Source::Remote(_) => Ok(None),
+        }
+    }
+
+    /// Lists the names in a directory of the base system, such as `boot`, before it is
+    /// extracted.
+    ///
+    /// The names of every layer are listed, except those which a later layer whites out.
+    /// Tar archives and remote sources are `None`, as with `read_file`.
+    pub fn list_dir(&self, path: &str) -> Result<Option<Vec<String>>> {
+        match *self {
+            Source::Squashfs(ref archive) => unsquashfs::list_dir(archive, path).map(Some),
+            Source::Layered(ref layers) => {
+                let mut names: Vec<String> = Vec::new();
+                for layer in layers {
+                    for name in unsquashfs::list_dir(layer, path)? {
+                        match name.strip_prefix(".wh.") {
+                            Some(hidden) => names.retain(|name| name != hidden),
+                            None if !names.contains(&name) => names.push(name),
+                            None => (),
+                        }
+                    }
+                }
+
+                Ok(Some(names))
+            }
+            Source::Directory { path: ref root, .. } => {
+                let entries = match fs::read_dir(root.join(path)) {
+                    Ok(entries) => entries,
+                    Err(ref why) if why.kind() == ErrorKind::NotFound => {
+                        return Ok(Some(Vec::new()))
+                    }
+                    Err(why) => return Err(why),
+                };
+
+                let mut names = Vec::new();
+                for entry in entries {
+                    if let Ok(name) = entry?.file_name().into_string() {
+                        names.push(name);
+                    }
+                }
+
+                Ok(Some(names))
+            }
+            Source::Tar(..) | Source::Remote(_) => Ok(None),
+        }
+    }
+
+    /// Whether the base system has a file, such as `usr/bin/dracut`, before it is extracted.
+    /// Tar archives and remote sources are `None`, as with `read_file`.
+    pub fn contains(&self, path: &str) -> Result<Option<bool>> {
+        let (parent, name) = match path.rfind('/') {
+            Some(pos) => (&path[..pos], &path[pos + 1..]),
+            None => ("", path),
+        };
+
+        Ok(self.list_dir(parent)?.map(|names| names.iter().any(|entry| entry == name)))
+    }
+
+    /// The path of the image, archive, or directory, the base image of a layered source, or
+    /// the URL of a remote source.
+    pub fn path(&self) -> &Path {
+        match *self {
+            Source::Squashfs(ref path)
+            | Source::Tar(ref path, _)
+            | Source::Directory { ref path, .. } => path,
+            Source::Layered(ref layers) => &layers[0],
+            Source::Remote(ref url) => Path::new(url),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum SourceKind {
+    Squashfs,
+    Tar(Compression),
+}
+
+fn from_magic(header: &[u8]) -> Option<SourceKind> {
+    let kind = if header.starts_with(b"hsqs") {
+        SourceKind::Squashfs
+    } else if header.starts_with(&[0x1F, 0x8B]) {
+        SourceKind::Tar(Compression::Gzip)
+    } else if header.starts_with(b"BZh") {
+        SourceKind::Tar(Compression::Bzip2)
+    } else if header.starts_with(&[0xFD, b'7', b'z', b'X', b'Z', 0x00]) {
+        SourceKind::Tar(Compression::Xz)
+    } else if header.starts_with(&[0x28, 0xB5, 0x2F, 0xFD]) {
+        SourceKind::Tar(Compression::Zstd)
+    } else if header.len() >= 262 && &header[257..262] == b"ustar" {
+        SourceKind::Tar(Compression::None)
+    } else {
+        return None;
+    };
+
+    Some(kind)
+}
+
+fn from_extension(path: &Path) -> Option<SourceKind> {
+    let name = path.file_name()?.to_str()?;
+    let kind = if name.ends_with(".squashfs") || name.ends_with(".sfs") {
+        SourceKind::Squashfs
+    } else if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+        SourceKind::Tar(Compression::Gzip)
+    } else if name.ends_with(".tar.bz2") || name.ends_with(".tbz2") {
+        SourceKind::Tar(Compression::Bzip2)
+    } else if name.ends_with(".tar.xz") || name.ends_with(".txz") {
+        SourceKind::Tar(Compression::Xz)
+    } else if name.ends_with(".tar.zst") || name.ends_with(".tzst") {
+        SourceKind::Tar(Compression::Zstd)
+    } else if name.ends_with(".tar") {
+        SourceKind::Tar(Compression::None)
+    } else {
+        return None;
+    };
+
+    Some(kind)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn magic() {
+        assert_eq!(from_magic(b"hsqs\x00\x00"), Some(SourceKind::Squashfs));
+        assert_eq!(from_magic(&[0x1F, 0x8B, 0x08]), Some(SourceKind::Tar(Compression::Gzip)));
+        assert_eq!(
+            from_magic(&[0x28, 0xB5, 0x2F, 0xFD, 0x00]),
+            Some(SourceKind::Tar(Compression::Zstd))
+        );
+        assert_eq!(from_magic(b"\xFD7zXZ\x00\x00"), Some(SourceKind::Tar(Compression::Xz)));
+
+        let mut tar = [0; 512];
+        tar[257..262].copy_from_slice(b"ustar");
+        assert_eq!(from_magic(&tar), Some(SourceKind::Tar(Compression::None)));
+        assert_eq!(from_magic(b"\x7fELF"), None);
+    }
+
+    #[test]
+    fn extension() {
+        let kind = |name: &str| from_extension(Path::new(name));
+        assert_eq!(kind("/cdrom/casper/filesystem.squashfs"), Some(SourceKind::Squashfs));
+        assert_eq!(kind("rootfs.tar.zst"), Some(SourceKind::Tar(Compression::Zstd)));
+        assert_eq!(kind("rootfs.tar.xz"), Some(SourceKind::Tar(Compression::Xz)));
+        assert_eq!(kind("rootfs.img"), None);
+    }
+}
@@ -0,0 +1,369 @@
+use super::{before_exec, cancelled, exit_status, getpty, layers, slave_stdio, Progress, Throttle};
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufRead, BufReader, Error, ErrorKind, Result},
+    os::unix::{
+        io::{AsRawFd, FromRawFd},
+        process::CommandExt,
+    },
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+/// A file, as listed by `unsquashfs -lls`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ListEntry {
+    /// The path that the file will be extracted to.
+    pub path:   String,
+    /// The size of the file, which is zero for devices.
+    pub size:   u64,
+    /// The type of the file, as given by the first character of its mode.
+    pub kind:   char,
+    /// The major and minor numbers of a device.
+    pub device: Option<(u32, u32)>,
+}
+
+/// Extracts each squashfs image onto the directory in order, so that the files of later
+/// layers replace those of earlier layers.
+///
+/// Every layer after the first may delete the files of the layers beneath it, with the
+/// whiteouts of overlayfs or aufs. The progress is measured against the sizes of the files
+/// of every layer.
+pub fn extract<F: FnMut(&Progress)>(
+    layers: &[PathBuf],
+    directory: &Path,
+    threads: usize,
+    cancel: &AtomicBool,
+    mut callback: F,
+) -> Result<()> {
+    let directory = directory
+        .to_str()
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Invalid directory path"))?
+        .replace("'", "'\"'\"'");
+
+    let mut archives = Vec::with_capacity(layers.len());
+    let mut listings = Vec::with_capacity(layers.len());
+    for (id, layer) in layers.iter().enumerate() {
+        let archive = layer
+            .to_str()
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Invalid archive path"))?
+            .replace("'", "'\"'\"'");
+
+        let listing = listing(&archive, &directory).map_err(|why| layer_error(id, layers, why))?;
+
+        archives.push(archive);
+        listings.push(listing);
+    }
+
+    let total = listings.iter().flat_map(|listing| listing.iter()).map(|e| e.size).sum();
+    let mut bytes = 0;
+    let mut throttle = Throttle::default();
+
+    for (id, (archive, listing)) in archives.iter().zip(&listings).enumerate() {
+        if layers.len() > 1 {
+            info!("extracting layer {} of {}: {}", id + 1, layers.len(), archive);
+        }
+
+        let mut extracted = |path: &str, size| {
+            bytes += size;
+            if throttle.ready() {
+                callback(&Progress { bytes, total, path: Some(path) });
+            }
+        };
+
+        let overlay = id != 0;
+        let result =
+            extract_layer(archive, &directory, listing, overlay, threads, cancel, &mut extracted);
+
+        match result {
+            Ok(()) => (),
+            Err(why) if cancel.load(Ordering::SeqCst) => return Err(why),
+            Err(why) => return Err(layer_error(id, layers, why)),
+        }
+    }
+
+    callback(&Progress { bytes, total, path: None });
+    Ok(())
+}
+
+/// Names the layer that an error occurred in, if there is more than one layer.
+fn layer_error(id: usize, layers: &[PathBuf], why: Error) -> Error {
+    if layers.len() == 1 {
+        return why;
+    }
+
+    Error::new(
+        why.kind(),
+        format!(
+            "failed to extract layer {} of {} ({}): {}",
+            id + 1,
+            layers.len(),
+            layers[id].display(),
+            why
+        ),
+    )
+}
+
+fn extract_layer(
+    archive: &str,
+    directory: &str,
+    listing: &[ListEntry],
+    overlay: bool,
+    threads: usize,
+    cancel: &AtomicBool,
+    extracted: &mut dyn FnMut(&str, u64),
+) -> Result<()> {
+    if overlay {
+        layers::remove_whited_out(listing)?;
+    }
+
+    let sizes: HashMap<&str, u64> =
+        listing.iter().map(|entry| (entry.path.as_str(), entry.size)).collect();
+
+    let mut command = Command::new("unsquashfs");
+    command
+        .arg("-f")
+        .arg("-i")
+        .arg("-processors")
+        .arg(threads.to_string())
+        .arg("-d")
+        .arg(directory)
+        .arg(archive);
+
+    debug!("{:?}", command);
+
+    let (master_fd, tty_path) = getpty(80, 30);
+    let mut child = {
+        let (slave_stdin, slave_stdout, slave_stderr) = slave_stdio(&tty_path)?;
+
+        unsafe {
+            command
+                .stdin(Stdio::from_raw_fd(slave_stdin.as_raw_fd()))
+                .stdout(Stdio::from_raw_fd(slave_stdout.as_raw_fd()))
+                .stderr(Stdio::from_raw_fd(slave_stderr.as_raw_fd()))
+                .env("COLUMNS", "")
+                .env("LINES", "")
+                .env("TERM", "xterm-256color")
+                .pre_exec(before_exec)
+                .spawn()?
+        }
+    };
+
+    let master = unsafe { File::from_raw_fd(master_fd) };
+    match handle(master, &sizes, cancel, extracted) {
+        Ok(()) => (),
+        Err(_) if cancel.load(Ordering::SeqCst) => {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(cancelled());
+        }
+        Err(err) => match err.raw_os_error() {
+            // EIO happens when slave end is closed
+            Some(libc::EIO) => (),
+            // Log other errors, use status code below to return
+            _ => error!("handle error: {}", err),
+        },
+    }
+
+    exit_status(child.wait()?)?;
+
+    if overlay {
+        layers::finish_layer(listing)?;
+    }
+
+    Ok(())
+}
+
+/// Reads the path of each file as it is extracted by `unsquashfs -i`, and passes it on with
+/// the size of that file.
+fn handle(
+    master: File,
+    sizes: &HashMap<&str, u64>,
+    cancel: &AtomicBool,
+    extracted: &mut dyn FnMut(&str, u64),
+) -> Result<()> {
+    let mut master = BufReader::new(master);
+    let mut line = Vec::new();
+    loop {
+        if cancel.load(Ordering::SeqCst) {
+            return Err(cancelled());
+        }
+
+        line.clear();
+        if master.read_until(b'\n', &mut line)? == 0 {
+            return Ok(());
+        }
+
+        for path in line.split(|&c| c == b'\r' || c == b'\n') {
+            let path = String::from_utf8_lossy(path);
+            if let Some(&size) = sizes.get(path.as_ref()) {
+                extracted(&path, size);
+            }
+        }
+    }
+}
+
+/// Reads a file from a squashfs image, without extracting the image. A file which is not
+/// in the image, or which can't be read by this version of `unsquashfs`, is `None`.
+pub(crate) fn cat(archive: &Path, path: &str) -> Result<Option<Vec<u8>>> {
+    let output = Command::new("unsquashfs")
+        .arg("-cat")
+        .arg(archive)
+        .arg(path)
+        .stderr(Stdio::null())
+        .output()?;
+
+    if output.status.success() {
+        Ok(Some(output.stdout))
+    } else {
+        debug!("unable to read {} from {}: {}", path, archive.display(), output.status);
+        Ok(None)
+    }
+}
+
+/// Lists the names in a directory of a squashfs image, without extracting the image. A
+/// directory which is not in the image has no names.
+pub(crate) fn list_dir(archive: &Path, path: &str) -> Result<Vec<String>> {
+    let output = Command::new("unsquashfs")
+        .args(&["-lls", "-d", "squashfs-root"])
+        .arg(archive)
+        .arg(path)
+        .stderr(Stdio::null())
+        .output()?;
+
+    if !output.status.success() {
+        return Err(Error::new(
+            ErrorKind::Other,
+            format!(
+                "listing {} of {} failed with status: {}",
+                path,
+                archive.display(),
+                output.status
+            ),
+        ));
+    }
+
+    let parent = match path.trim_matches('/') {
+        "" => String::from("squashfs-root/"),
+        path => ["squashfs-root/", path, "/"].concat(),
+    };
+    Ok(children(&String::from_utf8_lossy(&output.stdout), &parent))
+}
+
+/// The names of the files of the listing which are directly within the `parent`.
+fn children(listing: &str, parent: &str) -> Vec<String> {
+    listing
+        .lines()
+        .filter_map(parse_listing)
+        .filter_map(|entry| {
+            let name = entry.path.strip_prefix(parent)?;
+            if name.is_empty() || name.contains('/') {
+                None
+            } else {
+                Some(name.to_owned())
+            }
+        })
+        .collect()
+}
+
+/// Lists the files in a squashfs image, as they will be named by `unsquashfs -i` when
+/// extracted to the given directory.
+pub(crate) fn listing(archive: &str, directory: &str) -> Result<Vec<ListEntry>> {
+    let output = Command::new("unsquashfs")
+        .args(&["-lls", "-d", directory, archive])
+        .stderr(Stdio::inherit())
+        .output()?;
+
+    if !output.status.success() {
+        return Err(Error::new(
+            ErrorKind::Other,
+            format!("listing squashfs contents failed with status: {}", output.status),
+        ));
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).lines().filter_map(parse_listing).collect())
+}
+
+/// Parses a line of `unsquashfs -lls`, such as
+/// `-rw-r--r-- root/root 220 2020-04-14 13:47 squashfs-root/etc/hosts`.
+pub(crate) fn parse_listing(line: &str) -> Option<ListEntry> {
+    fn field<'a>(rest: &mut &'a str) -> Option<&'a str> {
+        let trimmed = rest.trim_start();
+        let end = trimmed.find(char::is_whitespace)?;
+        *rest = &trimmed[end..];
+        Some(&trimmed[..end])
+    }
+
+    let mut rest = line;
+    let mode = field(&mut rest)?;
+    if mode.len() != 10 || !"-dlcbps".contains(&mode[..1]) {
+        return None;
+    }
+
+    let _owner = field(&mut rest)?;
+    let size = field(&mut rest)?;
+
+    // Devices list their major and minor numbers, rather than a size.
+    let (size, device) = if size.ends_with(',') {
+        let major = size[..size.len() - 1].parse().ok()?;
+        let minor = field(&mut rest)?.parse().ok()?;
+        (0, Some((major, minor)))
+    } else {
+        (size.parse().ok()?, None)
+    };
+
+    let _date = field(&mut rest)?;
+    let _time = field(&mut rest)?;
+
+    let path = rest.trim_start();
+    let path = if mode.starts_with('l') { path.split(" -> ").next()? } else { path };
+
+    Some(ListEntry { path: path.to_owned(), size, kind: mode.as_bytes()[0] as char, device })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(path: &str, size: u64, kind: char, device: Option<(u32, u32)>) -> ListEntry {
+        ListEntry { path: path.into(), size, kind, device }
+    }
+
+    #[test]
+    fn children_of() {
+        let listing = "\
+drwxr-xr-x root/root 4096 2020-04-14 13:47 squashfs-root/boot
+lrwxrwxrwx root/root 24 2020-04-14 13:47 squashfs-root/boot/vmlinuz -> vmlinuz-5.4.0-26-generic
+-rw------- root/root 11657976 2020-04-14 13:47 squashfs-root/boot/vmlinuz-5.4.0-26-generic
+drwxr-xr-x root/root 4096 2020-04-14 13:47 squashfs-root/boot/grub
+-rw-r--r-- root/root 1024 2020-04-14 13:47 squashfs-root/boot/grub/grubenv";
+
+        assert_eq!(
+            children(listing, "squashfs-root/boot/"),
+            vec!["vmlinuz", "vmlinuz-5.4.0-26-generic", "grub"]
+        );
+    }
+
+    #[test]
+    fn listing() {
+        assert_eq!(
+            parse_listing("-rw-r--r-- root/root               220 2020-04-14 13:47 root/etc/hosts"),
+            Some(entry("root/etc/hosts", 220, '-', None))
+        );
+        assert_eq!(
+            parse_listing("lrwxrwxrwx root/root 7 2020-04-14 13:47 root/bin -> usr/bin"),
+            Some(entry("root/bin", 7, 'l', None))
+        );
+        assert_eq!(
+            parse_listing("crw-r--r-- root/root 5,  1 2020-04-14 13:47 root/dev/console"),
+            Some(entry("root/dev/console", 0, 'c', Some((5, 1))))
+        );
+        assert_eq!(
+            parse_listing("-rw-r--r-- root/root 1 2020-04-14 13:47 root/a file"),
+            Some(entry("root/a file", 1, '-', None))
+        );
+        assert_eq!(parse_listing("Parallel unsquashfs: Using 8 processors"), None);
+    }
+}
@@ -0,0 +1,246 @@
+use super::{cancelled, Progress, Source, Throttle};
+use std::{
+    fs::File,
+    io::{Error, ErrorKind, Read, Result, Write},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+/// The size of the superblock of a squashfs image.
+const SUPERBLOCK_SIZE: usize = 96;
+
+/// The offset of `bytes_used` in the superblock, which is the size of the image.
+const BYTES_USED_OFFSET: usize = 40;
+
+/// Verifies that the source is intact, before anything is extracted from it.
+///
+/// If SHA-256 checksums are given, the image or archive is read in full to compare against
+/// them, with the progress measured by the bytes read. A layered source needs a checksum for
+/// each of its images, in order, starting with the base image. Otherwise, the superblock of
+/// each squashfs image is checked to be valid, and to not advertise more data than the file
+/// has.
+pub fn verify_source<F: FnMut(&Progress)>(
+    source: &Source,
+    checksums: &[String],
+    cancel: &AtomicBool,
+    mut callback: F,
+) -> Result<()> {
+    let images: Vec<&Path> = match *source {
+        // A remote source is verified once it has been downloaded.
+        Source::Remote(_) => return Ok(()),
+        Source::Directory { .. } if checksums.is_empty() => return Ok(()),
+        Source::Directory { .. } => {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "a checksum cannot be verified for a directory",
+            ))
+        }
+        Source::Layered(ref layers) => layers.iter().map(PathBuf::as_path).collect(),
+        _ => vec![source.path()],
+    };
+
+    if checksums.is_empty() {
+        return match *source {
+            Source::Tar(..) => Ok(()),
+            _ => images.into_iter().map(verify_superblock).collect(),
+        };
+    }
+
+    if checksums.len() != images.len() {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("{} checksums were given for {} images", checksums.len(), images.len()),
+        ));
+    }
+
+    // The progress is measured across every image.
+    let total: u64 =
+        images.iter().map(|image| image.metadata().map(|m| m.len())).sum::<Result<_>>()?;
+    let mut verified = 0;
+    for (image, checksum) in images.into_iter().zip(checksums) {
+        verify_checksum(image, checksum, cancel, |progress| {
+            callback(&Progress { bytes: verified + progress.bytes, total, path: None })
+        })?;
+
+        verified += image.metadata()?.len();
+    }
+
+    Ok(())
+}
+
+/// Streams the file into `sha256sum`, and compares the digest against the expected checksum.
+pub fn verify_checksum<F: FnMut(&Progress)>(
+    path: &Path,
+    expected: &str,
+    cancel: &AtomicBool,
+    callback: F,
+) -> Result<()> {
+    let expected = expected.trim().to_ascii_lowercase();
+    if expected.len() != 64 || !expected.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("'{}' is not a SHA-256 checksum", expected),
+        ));
+    }
+
+    let found = digest(path, None, cancel, callback)?;
+    if found != expected {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "checksum mismatch for {}: expected {}, but found {}",
+                path.display(),
+                expected,
+                found
+            ),
+        ));
+    }
+
+    info!("checksum of {} verified", path.display());
+    Ok(())
+}
+
+/// Copies the file to `dest`, returning the SHA-256 checksum of its contents, with the
+/// progress measured by the bytes copied.
+pub fn copy_with_checksum<F: FnMut(&Progress)>(
+    path: &Path,
+    dest: &Path,
+    cancel: &AtomicBool,
+    callback: F,
+) -> Result<String> {
+    let mut dest_file = File::create(dest)?;
+    let digest = digest(path, Some(&mut dest_file), cancel, callback)?;
+    dest_file.sync_all()?;
+    Ok(digest)
+}
+
+/// Streams the file into `sha256sum`, and into `copy` if given, returning the digest.
+fn digest<F: FnMut(&Progress)>(
+    path: &Path,
+    mut copy: Option<&mut File>,
+    cancel: &AtomicBool,
+    mut callback: F,
+) -> Result<String> {
+    let total = path.metadata()?.len();
+    let mut file = File::open(path)?;
+
+    let mut command = Command::new("sha256sum");
+    command.stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::inherit());
+
+    debug!("{:?}", command);
+
+    let mut child = command.spawn()?;
+    let mut stdin = child.stdin.take().expect("stdin of sha256sum is piped");
+
+    let mut buffer = vec![0; 1024 * 1024];
+    let mut bytes = 0;
+    let mut throttle = Throttle::default();
+
+    let result = loop {
+        if cancel.load(Ordering::SeqCst) {
+            break Err(cancelled());
+        }
+
+        let read = match file.read(&mut buffer) {
+            Ok(0) => break Ok(()),
+            Ok(read) => read,
+            Err(why) => break Err(why),
+        };
+
+        if let Err(why) = stdin.write_all(&buffer[..read]) {
+            break Err(why);
+        }
+
+        if let Some(Err(why)) = copy.as_mut().map(|file| file.write_all(&buffer[..read])) {
+            break Err(why);
+        }
+
+        bytes += read as u64;
+        if throttle.ready() {
+            callback(&Progress { bytes, total, path: None });
+        }
+    };
+
+    drop(stdin);
+    if result.is_err() {
+        let _ = child.kill();
+    }
+
+    let output = child.wait_with_output()?;
+    result?;
+
+    if !output.status.success() {
+        return Err(Error::new(
+            ErrorKind::Other,
+            format!("sha256sum failed with status: {}", output.status),
+        ));
+    }
+
+    callback(&Progress { bytes, total, path: None });
+
+    let output = String::from_utf8_lossy(&output.stdout);
+    Ok(output.split_whitespace().next().unwrap_or("").to_owned())
+}
+
+/// Checks the magic of a squashfs superblock, and that the file is not shorter than the
+/// size that the superblock advertises, as happens when an image was copied incompletely.
+pub fn verify_superblock(path: &Path) -> Result<()> {
+    let mut superblock = [0; SUPERBLOCK_SIZE];
+    File::open(path)?.read_exact(&mut superblock).map_err(|why| {
+        Error::new(why.kind(), format!("failed to read superblock of {}: {}", path.display(), why))
+    })?;
+
+    let file_size = path.metadata()?.len();
+    check_superblock(&superblock, file_size)
+        .map_err(|why| Error::new(ErrorKind::InvalidData, format!("{}: {}", path.display(), why)))
+}
+
+fn check_superblock(
+    superblock: &[u8; SUPERBLOCK_SIZE],
+    file_size: u64,
+) -> ::std::result::Result<(), String> {
+    if &superblock[..4] != b"hsqs" {
+        return Err("not a squashfs image, as the superblock magic is missing".into());
+    }
+
+    let mut bytes_used = [0; 8];
+    bytes_used.copy_from_slice(&superblock[BYTES_USED_OFFSET..BYTES_USED_OFFSET + 8]);
+    let bytes_used = u64::from_le_bytes(bytes_used);
+
+    if bytes_used > file_size {
+        return Err(format!(
+            "image is truncated: the superblock advertises {} bytes, but the file has {}",
+            bytes_used, file_size
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn superblock() {
+        let mut superblock = [0; SUPERBLOCK_SIZE];
+        assert!(check_superblock(&superblock, 4096).is_err());
+
+        superblock[..4].copy_from_slice(b"hsqs");
+        superblock[BYTES_USED_OFFSET..BYTES_USED_OFFSET + 8]
+            .copy_from_slice(&4000u64.to_le_bytes());
+        assert_eq!(check_superblock(&superblock, 4096), Ok(()));
+        assert!(check_superblock(&superblock, 3999).is_err());
+    }
+
+    #[test]
+    fn checksum_of_each_layer() {
+        let source = Source::Layered(vec!["base.squashfs".into(), "nvidia.squashfs".into()]);
+        let checksums = vec!["0".repeat(64)];
+        let cancel = AtomicBool::new(false);
+
+        let why = verify_source(&source, &checksums, &cancel, |_| ()).unwrap_err();
+        assert_eq!(ErrorKind::InvalidInput, why.kind());
+    }
+}
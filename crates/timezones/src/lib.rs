@@ -4,6 +4,67 @@ use std::{
     path::{Path, PathBuf},
 };
 
+/// Where the time zone database is found, relative to the root of a system.
+const ZONEINFO: &str = "usr/share/zoneinfo";
+
+/// Validates that the Olson name, such as `Europe/Prague`, names a time zone in the database
+/// of the system at `root`.
+pub fn validate(root: &Path, name: &str) -> io::Result<()> {
+    validate_with(name, |path| match fs::read(root.join(path)) {
+        Ok(contents) => Ok(Some(contents)),
+        Err(ref why) if why.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(why) => Err(why),
+    })
+}
+
+/// Validates the Olson name against a database which is read by `read`, such as that of an
+/// image which has not been extracted. It is given the path of the zone relative to the root
+/// of the system, and gives the file, or `None` if the system lacks it.
+pub fn validate_with<F>(name: &str, read: F) -> io::Result<()>
+where
+    F: FnOnce(&str) -> io::Result<Option<Vec<u8>>>,
+{
+    let invalid = |why: &str| {
+        io::Error::new(io::ErrorKind::InvalidInput, format!("invalid time zone {}: {}", name, why))
+    };
+
+    if !is_valid_name(name) {
+        return Err(invalid("not an Olson name, such as Europe/Prague"));
+    }
+
+    // Files in the database which are not zones, such as `leapseconds`, lack the magic.
+    match read(&[ZONEINFO, "/", name].concat()) {
+        Ok(Some(ref zone)) if zone.starts_with(b"TZif") => Ok(()),
+        Ok(Some(_)) => Err(invalid("not a time zone file")),
+        Ok(None) => Err(invalid(&format!("not found in /{}", ZONEINFO))),
+        Err(why) => Err(invalid(&why.to_string())),
+    }
+}
+
+/// Configures the system at `root` to use the time zone, as `timedatectl` would: the
+/// `/etc/localtime` link points to the zone in the database, and `/etc/timezone` has its name.
+pub fn configure(root: &Path, name: &str) -> io::Result<()> {
+    validate(root, name)?;
+
+    let localtime = root.join("etc/localtime");
+    match fs::remove_file(&localtime) {
+        Err(ref why) if why.kind() == io::ErrorKind::NotFound => (),
+        result => result?,
+    }
+
+    symlink(Path::new("..").join(ZONEINFO).join(name), &localtime)?;
+    fs::write(root.join("etc/timezone"), [name, "\n"].concat())
+}
+
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.split('/').all(|part| {
+            !part.is_empty()
+                && !part.starts_with('.')
+                && part.chars().all(|c| c.is_ascii_alphanumeric() || "_-+".contains(c))
+        })
+}
+
 #[derive(Clone, Debug, Default)]
 pub struct Timezones {
     zones: Vec<Zone>,
@@ -61,9 +122,50 @@ impl Region {
 
     pub fn path(&self) -> &Path { &self.path }
 
+    /// The Olson name of the time zone, such as `Europe/Prague`.
+    pub fn olson_name(&self) -> Option<&str> {
+        self.path.strip_prefix(Path::new("/").join(ZONEINFO)).ok()?.to_str()
+    }
+
     pub fn install(&self, dest: &Path) -> io::Result<()> {
         let timezone = dest.join("etc/timezone");
         fs::remove_file(&timezone)?;
         symlink(&self.path, &timezone)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names() {
+        assert!(is_valid_name("Europe/Prague"));
+        assert!(is_valid_name("America/Argentina/Buenos_Aires"));
+        assert!(is_valid_name("Etc/GMT+5"));
+        assert!(is_valid_name("UTC"));
+        assert!(!is_valid_name(""));
+        assert!(!is_valid_name("/etc/passwd"));
+        assert!(!is_valid_name("Europe/../../etc/passwd"));
+        assert!(!is_valid_name("Europe//Prague"));
+    }
+
+    #[test]
+    fn zones() {
+        let read = |path: &str| -> io::Result<Option<Vec<u8>>> {
+            Ok(match path {
+                "usr/share/zoneinfo/Europe/Prague" => Some(b"TZif2\0\0".to_vec()),
+                "usr/share/zoneinfo/leapseconds" => Some(b"# Allowance for leap seconds".to_vec()),
+                _ => None,
+            })
+        };
+
+        assert!(validate_with("Europe/Prague", read).is_ok());
+        let why = validate_with("leapseconds", read).unwrap_err();
+        assert!(why.to_string().ends_with("not a time zone file"));
+        let why = validate_with("Mars/Olympus_Mons", read).unwrap_err();
+        assert!(why.to_string().ends_with("not found in /usr/share/zoneinfo"));
+        let why = validate_with("../passwd", read).unwrap_err();
+        assert_eq!(why.kind(), io::ErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,80 @@
+//! The command or device which an I/O error occurred with, which is kept as the error is
+//! given more context on its way to the installer.
+
+use std::{
+    error::Error,
+    fmt, io,
+    path::{Path, PathBuf},
+};
+
+/// What an operation which failed was acting upon.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ErrorContext {
+    /// The command line of the command which failed.
+    pub command: Option<String>,
+    /// The device which the operation failed on.
+    pub device:  Option<PathBuf>,
+    /// The catalog entry of the error which caused the failure, if it has one.
+    pub cause:   Option<ErrorCause>,
+}
+
+/// The code and key of an error in its message catalog, such as that of a `DiskError`, with
+/// the parameters of its message, so that frontends may translate it.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ErrorCause {
+    pub code:   u32,
+    pub key:    &'static str,
+    pub params: Vec<(&'static str, String)>,
+}
+
+#[derive(Debug)]
+struct ContextError {
+    context: ErrorContext,
+    message: String,
+}
+
+impl fmt::Display for ContextError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { f.write_str(&self.message) }
+}
+
+impl Error for ContextError {}
+
+/// The command or device that an error occurred with, if it was recorded.
+pub fn error_context(error: &io::Error) -> Option<&ErrorContext> {
+    error.get_ref()?.downcast_ref::<ContextError>().map(|error| &error.context)
+}
+
+/// Creates an error for a command which failed.
+pub fn command_error<M: Into<String>>(kind: io::ErrorKind, command: &str, message: M) -> io::Error {
+    let context = ErrorContext { command: Some(command.into()), ..ErrorContext::default() };
+    io::Error::new(kind, ContextError { context, message: message.into() })
+}
+
+/// Creates an error which was caused by an error of a message catalog.
+pub fn cause_error<M: Into<String>>(
+    kind: io::ErrorKind,
+    cause: ErrorCause,
+    message: M,
+) -> io::Error {
+    let context = ErrorContext { cause: Some(cause), ..ErrorContext::default() };
+    io::Error::new(kind, ContextError { context, message: message.into() })
+}
+
+/// Records the device that an error occurred with, along with a new message.
+pub fn device_error<M: Into<String>>(error: io::Error, device: &Path, message: M) -> io::Error {
+    let mut context = error_context(&error).cloned().unwrap_or_default();
+    context.device = Some(device.to_path_buf());
+    io::Error::new(error.kind(), ContextError { context, message: message.into() })
+}
+
+/// Replaces the message of an error, keeping the command or device that it occurred with.
+pub fn rewrap_error<M: Into<String>>(
+    kind: io::ErrorKind,
+    context: Option<ErrorContext>,
+    message: M,
+) -> io::Error {
+    match context {
+        Some(context) => io::Error::new(kind, ContextError { context, message: message.into() }),
+        None => io::Error::new(kind, message.into()),
+    }
+}
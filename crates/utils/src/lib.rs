@@ -2,6 +2,10 @@
 
 extern crate sedregex;
 
+mod context;
+
+pub use self::context::*;
+
 use std::{
     fs::File,
     io::{self, Read, Write},
@@ -57,6 +61,7 @@ use std::{
     fs::{self, DirEntry},
     hash::{Hash, Hasher},
     path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
 };
 
 mod layout {
@@ -106,6 +111,45 @@ pub fn canonicalize(path: &Path) -> Cow<Path> {
     }
 }
 
+/// The current date in UTC, formatted as `YYYYMMDD`.
+pub fn date_stamp() -> String {
+    let days = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs() / 86_400);
+    let (year, month, day) = civil_date(days);
+    format!("{:04}{:02}{:02}", year, month, day)
+}
+
+/// The current time in UTC, formatted as `YYYY-MM-DD HH:MM:SS.mmm`.
+pub fn timestamp() -> String {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = now.as_secs();
+    let (year, month, day) = civil_date(secs / 86_400);
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}.{:03}",
+        year,
+        month,
+        day,
+        secs / 3600 % 24,
+        secs / 60 % 60,
+        secs % 60,
+        now.subsec_millis()
+    )
+}
+
+/// Converts days since the epoch to a civil date, from Howard Hinnant's algorithm.
+fn civil_date(days: u64) -> (i64, i64, i64) {
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    (year, month, day)
+}
+
 /// Concatenates an array of `&OsStr` into a new `OsString`.
 pub fn concat_osstr(input: &[&OsStr]) -> OsString {
     let mut output = OsString::with_capacity(input.iter().fold(0, |acc, c| acc + c.len()));
@@ -25,17 +25,11 @@ fn main() {
 
     let options = InstallOptions::new(&disks, required, 0);
 
-    let mut config = Config {
-        flags:            distinst::MODIFY_BOOT_ORDER | distinst::INSTALL_HARDWARE_SUPPORT,
-        hostname:         "pop-testing".into(),
-        keyboard_layout:  "us".into(),
-        keyboard_model:   None,
-        keyboard_variant: None,
-        old_root:         None,
-        lang:             "en_US.UTF-8".into(),
-        remove:           "/cdrom/casper/filesystem.manifest-remove".into(),
-        squashfs:         "/cdrom/casper/filesystem.squashfs".into(),
-    };
+    let mut config = Config::default();
+    config.flags = distinst::MODIFY_BOOT_ORDER | distinst::INSTALL_HARDWARE_SUPPORT;
+    config.hostname = "pop-testing".into();
+    config.remove = "/cdrom/casper/filesystem.manifest-remove".into();
+    config.squashfs = "/cdrom/casper/filesystem.squashfs".into();
 
     eprintln!("Options: {:#?}", options);
 
@@ -199,6 +193,7 @@ fn main() {
                 pb.message(match status.step {
                     Step::Backup => "Backing up files",
                     Step::Init => "Initializing",
+                    Step::Verify => "Verifying image ",
                     Step::Partition => "Partitioning disk ",
                     Step::Extract => "Extracting filesystem ",
                     Step::Configure => "Configuring installation",
@@ -216,7 +211,7 @@ fn main() {
     let _ = log(|level, msg| eprintln!("{:?}: {}", level, msg));
 
     match installer.install(disks, &config) {
-        Ok(()) => (),
+        Ok(report) => print!("{}", report),
         Err(why) => {
             eprintln!("install failed: {}", why);
         }
@@ -45,8 +45,16 @@ pub unsafe extern "C" fn distinst_alongside_option_get_os_release(
     } else {
         let option = &*(option as *const AlongsideOption);
         if let Some(OS::Linux { ref info, .. }) = option.alongside {
-            *os_release = DistinstOsRelease::from_os_release(info);
-            0
+            match DistinstOsRelease::from_os_release(info) {
+                Ok(release) => {
+                    *os_release = release;
+                    0
+                }
+                Err(why) => {
+                    error!("distinst_alongside_option_get_os_release: {}", why);
+                    3
+                }
+            }
         } else {
             2
         }
@@ -161,8 +169,16 @@ pub unsafe extern "C" fn distinst_refresh_option_get_os_release(
         1
     } else {
         let option = &*(option as *const RefreshOption);
-        *os_release = DistinstOsRelease::from_os_release(&option.os_release);
-        0
+        match DistinstOsRelease::from_os_release(&option.os_release) {
+            Ok(release) => {
+                *os_release = release;
+                0
+            }
+            Err(why) => {
+                error!("distinst_refresh_option_get_os_release: {}", why);
+                2
+            }
+        }
     }
 }
 
@@ -1,9 +1,17 @@
-use distinst::{Config, UserAccountCreate};
+use distinst::{
+    zeroize::Zeroizing, BootOrderPolicy, BootloaderChoice, Config, FirstBootUnit, FstabId,
+    GrubOptions, InitramfsTool, InstallMode, KeyboardConfig, NetworkConfig, RecoveryConfig,
+    RecoverySource, RootPolicy, SshConfig, SystemdBootOptions, UserAccountCreate, ZramConfig,
+};
 use crate::get_str;
 use libc;
-use std::io;
+use std::{io, path::PathBuf, slice};
 
 /// Installer configuration
+///
+/// The layout of this structure is kept as it was first released, for the programs which
+/// were built against it. The settings which were added since are given with
+/// `DistinstConfigV2`.
 #[repr(C)]
 #[derive(Debug)]
 pub struct DistinstConfig {
@@ -20,35 +28,510 @@ pub struct DistinstConfig {
 
 impl DistinstConfig {
     pub unsafe fn as_config(&self) -> io::Result<Config> {
-        Ok(Config {
-            squashfs:         get_str(self.squashfs)?.to_string(),
-            hostname:         get_str(self.hostname)?.to_string(),
-            lang:             get_str(self.lang)?.to_string(),
-            keyboard_layout:  get_str(self.keyboard_layout)?.to_string(),
-            keyboard_model:   get_str(self.keyboard_model).ok().map(String::from),
-            keyboard_variant: get_str(self.keyboard_variant).ok().map(String::from),
-            old_root:         get_str(self.old_root).ok().map(String::from),
-            remove:           get_str(self.remove)?.to_string(),
-            flags:            self.flags,
+        let mut config = Config::default();
+        config.squashfs = get_str(self.squashfs)?.to_string();
+        config.hostname = get_str(self.hostname)?.to_string();
+        config.lang = get_str(self.lang)?.to_string();
+        config.keyboard = KeyboardConfig {
+            layout:  get_str(self.keyboard_layout)?.to_string(),
+            variant: get_str(self.keyboard_variant).ok().map(String::from),
+            model:   get_str(self.keyboard_model).ok().map(String::from),
+            options: None,
+        };
+        config.old_root = get_str(self.old_root).ok().map(String::from);
+        config.remove = get_str(self.remove)?.to_string();
+        config.flags = self.flags;
+
+        // Setting the old root has always refreshed the install at it.
+        if !self.old_root.is_null() {
+            config.mode = InstallMode::Refresh;
+        }
+
+        Ok(config)
+    }
+}
+
+/// Installer configuration, with the settings which were added after `DistinstConfig`.
+///
+/// This layout is fixed as well, so settings which are added later are given with a new
+/// version of the structure, and of the functions which take it.
+#[repr(C)]
+#[derive(Debug)]
+pub struct DistinstConfigV2 {
+    /// The settings of the first version of the configuration.
+    base:                   DistinstConfig,
+    layers:                 *const *const libc::c_char,
+    layers_len:             libc::size_t,
+    squashfs_checksum:      *const libc::c_char,
+    layer_checksums:        *const *const libc::c_char,
+    layer_checksums_len:    libc::size_t,
+    snapshot_previous_root: bool,
+    snapshot_percent:       u8,
+    timezone:               *const libc::c_char,
+    users:                  *const DistinstUserAccountCreateV2,
+    users_len:              libc::size_t,
+    hosts_append:           *const *const libc::c_char,
+    hosts_append_len:       libc::size_t,
+    additional_locales:     *const *const libc::c_char,
+    additional_locales_len: libc::size_t,
+    keyboard_options:       *const libc::c_char,
+    local_packages:         *const libc::c_char,
+    language_packages:      *const *const libc::c_char,
+    language_packages_len:  libc::size_t,
+    allow_network:          bool,
+    fstab_id:               DISTINST_FSTAB_ID,
+    hibernation:            bool,
+    /// The size of the swap file in MiB, or zero to create none.
+    swapfile:               u64,
+    oem_mode:               bool,
+    /// The FAT32 partition to copy the live system to, or null to create no recovery.
+    recovery_partition:     *const libc::c_char,
+    /// The casper directory or ISO which is copied to the recovery partition.
+    recovery_source:        *const libc::c_char,
+    /// Paths of the old root to retain when refreshing, or null for the defaults.
+    preserve:               *const *const libc::c_char,
+    preserve_len:           libc::size_t,
+    /// The tool which generates the initramfs, or `DETECT` to use the one in the image.
+    initramfs:              DISTINST_INITRAMFS_TOOL,
+    install_drivers:        bool,
+    /// The proxy, apt mirror, and name servers of the network, or null to use the defaults.
+    network:                *const DistinstNetworkConfig,
+    /// The SSH server and authorized keys, or null to leave SSH as the image has it.
+    ssh:                    *const DistinstSshConfig,
+    /// A directory which is copied onto the install once it is extracted, or null.
+    overlay:                *const libc::c_char,
+    firstboot_units:        *const DistinstFirstBootUnit,
+    firstboot_units_len:    libc::size_t,
+    root_policy:            DISTINST_ROOT_POLICY,
+    /// The crypt(3) hash of the password of root, for the `PASSWORD` policy.
+    root_password_hash:     *const libc::c_char,
+    /// Lines of the `authorized_keys` of root, for the `KEYS` policy.
+    root_keys:              *const *const libc::c_char,
+    root_keys_len:          libc::size_t,
+    /// Swap on a compressed device in memory, or null for none.
+    zram:                   *const DistinstZramConfig,
+    /// Arguments which are added to the kernel command line, such as `nomodeset`.
+    kernel_cmdline:         *const *const libc::c_char,
+    kernel_cmdline_len:     libc::size_t,
+    bootloader:             DISTINST_BOOTLOADER_CHOICE,
+    /// The label of the EFI boot entry, or null for the pretty name of the image.
+    efi_label:              *const libc::c_char,
+    /// Leave other systems out of the menu of GRUB, rather than finding them with os-prober.
+    disable_os_prober:      bool,
+    /// The settings of the menu of systemd-boot, or null for the defaults.
+    systemd_boot:           *const DistinstSystemdBootOptions,
+    boot_order:             DISTINST_BOOT_ORDER_POLICY,
+    /// The branding and menu of GRUB, or null to keep those of the image.
+    grub:                   *const DistinstGrubOptions,
+}
+
+impl DistinstConfigV2 {
+    pub unsafe fn as_config(&self) -> io::Result<Config> {
+        let mut config = self.base.as_config()?;
+        config.layers = strings(self.layers, self.layers_len)?;
+        config.squashfs_checksum = get_str(self.squashfs_checksum).ok().map(String::from);
+        config.layer_checksums = strings(self.layer_checksums, self.layer_checksums_len)?;
+        config.additional_locales = strings(self.additional_locales, self.additional_locales_len)?;
+        config.keyboard.options = get_str(self.keyboard_options).ok().map(String::from);
+        config.snapshot_previous_root = self.snapshot_previous_root;
+        config.snapshot_percent = self.snapshot_percent;
+        config.timezone = get_str(self.timezone).ok().map(String::from);
+        config.users = self.users()?;
+        config.hosts_append = strings(self.hosts_append, self.hosts_append_len)?;
+        config.local_packages = get_str(self.local_packages).ok().map(PathBuf::from);
+        config.language_packages = Some(self.language_packages)
+            .filter(|packages| !packages.is_null())
+            .map(|packages| strings(packages, self.language_packages_len))
+            .transpose()?;
+        config.allow_network = self.allow_network;
+        config.fstab_id = self.fstab_id.into();
+        config.hibernation = self.hibernation;
+        config.swapfile = if self.swapfile == 0 { None } else { Some(self.swapfile) };
+        config.oem_mode = self.oem_mode;
+        config.recovery = get_str(self.recovery_partition).ok().map(|partition| RecoveryConfig {
+            partition: PathBuf::from(partition),
+            source:    RecoverySource::from_path(PathBuf::from(
+                get_str(self.recovery_source).unwrap_or("/cdrom/casper"),
+            )),
+        });
+        config.preserve = Some(self.preserve)
+            .filter(|preserve| !preserve.is_null())
+            .map(|preserve| strings(preserve, self.preserve_len))
+            .transpose()?
+            .map(|paths| paths.into_iter().map(PathBuf::from).collect());
+        config.initramfs = self.initramfs.into();
+        config.install_drivers = self.install_drivers;
+        config.network = self.network.as_ref().map(|network| network.as_config()).transpose()?;
+        config.ssh = self.ssh.as_ref().map(|ssh| ssh.as_config()).transpose()?;
+        config.overlay = get_str(self.overlay).ok().map(PathBuf::from);
+        config.firstboot_units = self.firstboot_units()?;
+        config.root = self.root()?;
+        config.zram = self.zram.as_ref().map(|zram| zram.as_config());
+        config.kernel_cmdline = strings(self.kernel_cmdline, self.kernel_cmdline_len)?;
+        config.bootloader = self.bootloader.into();
+        config.efi_label = get_str(self.efi_label).ok().map(String::from);
+        config.boot_order = self.boot_order.into();
+        config.os_prober = !self.disable_os_prober;
+        config.systemd_boot = self
+            .systemd_boot
+            .as_ref()
+            .map_or_else(SystemdBootOptions::default, |options| options.as_config());
+        config.grub =
+            self.grub.as_ref().map_or_else(GrubOptions::default, |options| options.as_config());
+        Ok(config)
+    }
+
+    unsafe fn users(&self) -> io::Result<Vec<UserAccountCreate>> {
+        if self.users.is_null() {
+            return Ok(Vec::new());
+        }
+
+        slice::from_raw_parts(self.users, self.users_len)
+            .iter()
+            .map(|user| user.as_config())
+            .collect()
+    }
+
+    unsafe fn firstboot_units(&self) -> io::Result<Vec<FirstBootUnit>> {
+        if self.firstboot_units.is_null() {
+            return Ok(Vec::new());
+        }
+
+        slice::from_raw_parts(self.firstboot_units, self.firstboot_units_len)
+            .iter()
+            .map(|unit| unit.as_config())
+            .collect()
+    }
+
+    unsafe fn root(&self) -> io::Result<RootPolicy> {
+        Ok(match self.root_policy {
+            DISTINST_ROOT_POLICY::LOCKED => RootPolicy::Locked,
+            DISTINST_ROOT_POLICY::PASSWORD => {
+                RootPolicy::Password(get_str(self.root_password_hash)?.to_owned())
+            }
+            DISTINST_ROOT_POLICY::KEYS => {
+                RootPolicy::Keys(strings(self.root_keys, self.root_keys_len)?)
+            }
         })
     }
 }
 
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DISTINST_FSTAB_ID {
+    UUID = 0,
+    PARTUUID = 1,
+    LABEL = 2,
+    PATH = 3,
+}
+
+impl From<DISTINST_FSTAB_ID> for FstabId {
+    fn from(id: DISTINST_FSTAB_ID) -> FstabId {
+        match id {
+            DISTINST_FSTAB_ID::UUID => FstabId::Uuid,
+            DISTINST_FSTAB_ID::PARTUUID => FstabId::PartUuid,
+            DISTINST_FSTAB_ID::LABEL => FstabId::Label,
+            DISTINST_FSTAB_ID::PATH => FstabId::Path,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DISTINST_INITRAMFS_TOOL {
+    DETECT = 0,
+    INITRAMFS_TOOLS = 1,
+    DRACUT = 2,
+    MKINITCPIO = 3,
+}
+
+impl From<DISTINST_INITRAMFS_TOOL> for Option<InitramfsTool> {
+    fn from(tool: DISTINST_INITRAMFS_TOOL) -> Option<InitramfsTool> {
+        match tool {
+            DISTINST_INITRAMFS_TOOL::DETECT => None,
+            DISTINST_INITRAMFS_TOOL::INITRAMFS_TOOLS => Some(InitramfsTool::InitramfsTools),
+            DISTINST_INITRAMFS_TOOL::DRACUT => Some(InitramfsTool::Dracut),
+            DISTINST_INITRAMFS_TOOL::MKINITCPIO => Some(InitramfsTool::Mkinitcpio),
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DISTINST_BOOTLOADER_CHOICE {
+    AUTO = 0,
+    SYSTEMD_BOOT = 1,
+    GRUB_EFI = 2,
+    GRUB_BIOS = 3,
+    EXTLINUX = 4,
+    RASPBERRY_PI = 5,
+    GRUB_IEEE1275 = 6,
+}
+
+impl From<DISTINST_BOOTLOADER_CHOICE> for BootloaderChoice {
+    fn from(choice: DISTINST_BOOTLOADER_CHOICE) -> BootloaderChoice {
+        match choice {
+            DISTINST_BOOTLOADER_CHOICE::AUTO => BootloaderChoice::Auto,
+            DISTINST_BOOTLOADER_CHOICE::SYSTEMD_BOOT => BootloaderChoice::SystemdBoot,
+            DISTINST_BOOTLOADER_CHOICE::GRUB_EFI => BootloaderChoice::GrubEfi,
+            DISTINST_BOOTLOADER_CHOICE::GRUB_BIOS => BootloaderChoice::GrubBios,
+            DISTINST_BOOTLOADER_CHOICE::EXTLINUX => BootloaderChoice::Extlinux,
+            DISTINST_BOOTLOADER_CHOICE::RASPBERRY_PI => BootloaderChoice::RaspberryPi,
+            DISTINST_BOOTLOADER_CHOICE::GRUB_IEEE1275 => BootloaderChoice::GrubIeee1275,
+        }
+    }
+}
+
+/// Where the EFI boot entry of the install is placed in the boot order.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DISTINST_BOOT_ORDER_POLICY {
+    FIRST = 0,
+    LAST = 1,
+    PRESERVE = 2,
+}
+
+impl From<DISTINST_BOOT_ORDER_POLICY> for BootOrderPolicy {
+    fn from(policy: DISTINST_BOOT_ORDER_POLICY) -> BootOrderPolicy {
+        match policy {
+            DISTINST_BOOT_ORDER_POLICY::FIRST => BootOrderPolicy::First,
+            DISTINST_BOOT_ORDER_POLICY::LAST => BootOrderPolicy::Last,
+            DISTINST_BOOT_ORDER_POLICY::PRESERVE => BootOrderPolicy::Preserve,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DISTINST_ROOT_POLICY {
+    LOCKED = 0,
+    PASSWORD = 1,
+    KEYS = 2,
+}
+
+/// Collects an array of C strings, which may be null if it is empty.
+unsafe fn strings(array: *const *const libc::c_char, len: libc::size_t) -> io::Result<Vec<String>> {
+    if array.is_null() {
+        return Ok(Vec::new());
+    }
+
+    slice::from_raw_parts(array, len)
+        .iter()
+        .map(|&string| get_str(string).map(String::from))
+        .collect()
+}
+
+/// A user account to create, whose layout is kept as it was first released. The settings
+/// which were added since are given with `DistinstUserAccountCreateV2`.
 #[repr(C)]
 pub struct DistinstUserAccountCreate {
-    pub username: *const libc::c_char,
-    pub realname: *const libc::c_char,
-    pub password: *const libc::c_char,
+    pub username:     *const libc::c_char,
+    pub realname:     *const libc::c_char,
+    pub password:     *const libc::c_char,
     pub profile_icon: *const libc::c_char,
 }
 
 impl DistinstUserAccountCreate {
     pub unsafe fn as_config(&self) -> io::Result<UserAccountCreate> {
-        Ok(UserAccountCreate {
-            username: get_str(self.username)?.to_owned(),
-            realname: get_str(self.realname).ok().map(String::from),
-            password: get_str(self.password).ok().map(String::from),
-            profile_icon: get_str(self.profile_icon).ok().map(String::from),
+        let mut user = UserAccountCreate::default();
+        user.username = get_str(self.username)?.to_owned();
+        user.realname = get_str(self.realname).ok().map(String::from);
+        user.password = get_str(self.password).ok().map(|password| Zeroizing::new(password.into()));
+        user.profile_icon = get_str(self.profile_icon).ok().map(String::from);
+        Ok(user)
+    }
+}
+
+/// A user account to create, with the settings which were added after
+/// `DistinstUserAccountCreate`.
+#[repr(C)]
+pub struct DistinstUserAccountCreateV2 {
+    /// The settings of the first version of the account.
+    pub base:          DistinstUserAccountCreate,
+    pub password_hash: *const libc::c_char,
+    /// The login shell, or null for `/bin/bash`.
+    pub shell:         *const libc::c_char,
+    /// Replaces the default groups when not null.
+    pub groups:        *const *const libc::c_char,
+    pub groups_len:    libc::size_t,
+    /// Assigns the next free user ID when zero.
+    pub uid:           u32,
+    /// Creates a primary group of this ID, named after the user, when not zero.
+    pub gid:           u32,
+    pub no_password:   bool,
+    pub autologin:     bool,
+}
+
+impl DistinstUserAccountCreateV2 {
+    pub unsafe fn as_config(&self) -> io::Result<UserAccountCreate> {
+        let mut user = self.base.as_config()?;
+        user.password_hash = get_str(self.password_hash).ok().map(String::from);
+        user.groups = self.groups()?;
+        user.uid = if self.uid == 0 { None } else { Some(self.uid) };
+        user.gid = if self.gid == 0 { None } else { Some(self.gid) };
+        user.shell = get_str(self.shell).ok().map(String::from);
+        user.no_password = self.no_password;
+        user.autologin = self.autologin;
+        Ok(user)
+    }
+
+    unsafe fn groups(&self) -> io::Result<Option<Vec<String>>> {
+        if self.groups.is_null() {
+            return Ok(None);
+        }
+
+        strings(self.groups, self.groups_len).map(Some)
+    }
+}
+
+/// The network which packages are fetched through while configuring.
+#[repr(C)]
+#[derive(Debug)]
+pub struct DistinstNetworkConfig {
+    pub proxy:      *const libc::c_char,
+    pub apt_mirror: *const libc::c_char,
+    pub dns:        *const *const libc::c_char,
+    pub dns_len:    libc::size_t,
+    pub persist:    bool,
+}
+
+impl DistinstNetworkConfig {
+    pub unsafe fn as_config(&self) -> io::Result<NetworkConfig> {
+        Ok(NetworkConfig {
+            proxy:      get_str(self.proxy).ok().map(String::from),
+            apt_mirror: get_str(self.apt_mirror).ok().map(String::from),
+            dns:        strings(self.dns, self.dns_len)?,
+            persist:    self.persist,
+        })
+    }
+}
+
+/// Access to the install over SSH.
+#[repr(C)]
+#[derive(Debug)]
+pub struct DistinstSshConfig {
+    pub enable_server:         bool,
+    /// Lines of an `authorized_keys` file.
+    pub authorized_keys:       *const *const libc::c_char,
+    pub authorized_keys_len:   libc::size_t,
+    pub permit_root_login:     bool,
+    pub disable_password_auth: bool,
+}
+
+impl DistinstSshConfig {
+    pub unsafe fn as_config(&self) -> io::Result<SshConfig> {
+        Ok(SshConfig {
+            enable_server:         self.enable_server,
+            authorized_keys:       strings(self.authorized_keys, self.authorized_keys_len)?,
+            permit_root_login:     self.permit_root_login,
+            disable_password_auth: self.disable_password_auth,
+        })
+    }
+}
+
+/// Swap on a compressed device in memory.
+#[repr(C)]
+#[derive(Debug)]
+pub struct DistinstZramConfig {
+    /// The size of the device, as a fraction of the memory of the machine.
+    pub fraction_of_ram: f32,
+    /// The compression algorithm, or null for the default.
+    pub algorithm:       *const libc::c_char,
+}
+
+impl DistinstZramConfig {
+    pub unsafe fn as_config(&self) -> ZramConfig {
+        ZramConfig {
+            fraction_of_ram: self.fraction_of_ram,
+            algorithm:       get_str(self.algorithm).ok().map(String::from),
+        }
+    }
+}
+
+/// The settings of the `loader.conf` of systemd-boot.
+#[repr(C)]
+#[derive(Debug)]
+pub struct DistinstSystemdBootOptions {
+    /// The seconds for which the menu is shown, or a negative number for the default.
+    pub timeout:       i32,
+    /// `current`, `oldkern`, `recovery`, or the ID of an entry, or null for `current`.
+    pub default_entry: *const libc::c_char,
+    /// The `console-mode` of the menu, or null for the default.
+    pub console_mode:  *const libc::c_char,
+    /// 1 to allow the command line to be edited from the menu, 0 to deny it, or a
+    /// negative number for the default.
+    pub editor:        i8,
+}
+
+impl DistinstSystemdBootOptions {
+    pub unsafe fn as_config(&self) -> SystemdBootOptions {
+        SystemdBootOptions {
+            timeout:      if self.timeout < 0 { None } else { Some(self.timeout as u32) },
+            default:      get_str(self.default_entry).ok().map(String::from),
+            console_mode: get_str(self.console_mode).ok().map(String::from),
+            editor:       if self.editor < 0 { None } else { Some(self.editor != 0) },
+        }
+    }
+}
+
+/// The branding and the menu of GRUB, in `/etc/default/grub`.
+#[repr(C)]
+#[derive(Debug)]
+pub struct DistinstGrubOptions {
+    /// The `GRUB_DISTRIBUTOR`, or null for that of the image.
+    pub distributor:    *const libc::c_char,
+    /// A directory with a `theme.txt`, or null for no theme.
+    pub theme_dir:      *const libc::c_char,
+    /// An image to show behind the menu, or null for none.
+    pub background:     *const libc::c_char,
+    /// The seconds for which the menu is hidden, or a negative number to show it.
+    pub hidden_timeout: i32,
+    /// The `GRUB_TERMINAL`, or null for that of the image.
+    pub terminal:       *const libc::c_char,
+}
+
+impl DistinstGrubOptions {
+    pub unsafe fn as_config(&self) -> GrubOptions {
+        GrubOptions {
+            distributor:    get_str(self.distributor).ok().map(String::from),
+            theme_dir:      get_str(self.theme_dir).ok().map(PathBuf::from),
+            background:     get_str(self.background).ok().map(PathBuf::from),
+            hidden_timeout: if self.hidden_timeout < 0 {
+                None
+            } else {
+                Some(self.hidden_timeout as u32)
+            },
+            terminal:       get_str(self.terminal).ok().map(String::from),
+        }
+    }
+}
+
+/// A service which is run on the first boot of the install.
+#[repr(C)]
+#[derive(Debug)]
+pub struct DistinstFirstBootUnit {
+    pub name:     *const libc::c_char,
+    /// The command line of the service, or null to run the script.
+    pub exec:     *const libc::c_char,
+    /// A script of the host which is copied into the install and run, or null. A unit may
+    /// not have both a command and a script.
+    pub script:   *const libc::c_char,
+    pub run_once: bool,
+}
+
+impl DistinstFirstBootUnit {
+    pub unsafe fn as_config(&self) -> io::Result<FirstBootUnit> {
+        let name = get_str(self.name)?;
+        if self.exec.is_null() {
+            let script = get_str(self.script)?;
+            return Ok(FirstBootUnit::from_script(name, script, self.run_once));
+        }
+
+        Ok(FirstBootUnit {
+            name:     name.to_owned(),
+            exec:     get_str(self.exec)?.to_owned(),
+            script:   get_str(self.script).ok().map(PathBuf::from),
+            run_once: self.run_once,
         })
     }
 }
@@ -8,9 +8,9 @@ use std::{
 };
 
 use distinst::{
-    BlockDeviceExt, DecryptionError, Disk, DiskExt, Disks, FileSystem, LogicalDevice,
-    LvmEncryption, PartitionBuilder, PartitionInfo, PartitionTable, PartitionTableExt, Sector,
-    SectorExt,
+    BlockDeviceExt, BootloaderChoice, DecryptionError, Disk, DiskExt, Disks, FileSystem, Installer,
+    LogicalDevice, LvmEncryption, MbrBootloader, PartitionBuilder, PartitionInfo, PartitionTable,
+    PartitionTableExt, Sector, SectorExt,
 };
 
 use super::{get_str, null_check};
@@ -263,6 +263,48 @@ pub unsafe extern "C" fn distinst_disk_get_partition_table(
     disk.get_partition_table().into()
 }
 
+/// The bootloader in the boot code of the MBR of a disk.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DISTINST_MBR_BOOTLOADER {
+    NONE = 0,
+    GRUB2 = 1,
+    GRUB_LEGACY = 2,
+    LILO = 3,
+    SYSLINUX = 4,
+    WINDOWS = 5,
+    WINDOWS_XP = 6,
+    UNKNOWN = 7,
+}
+
+impl From<Option<MbrBootloader>> for DISTINST_MBR_BOOTLOADER {
+    fn from(bootloader: Option<MbrBootloader>) -> Self {
+        match bootloader {
+            Some(MbrBootloader::Grub2) => DISTINST_MBR_BOOTLOADER::GRUB2,
+            Some(MbrBootloader::GrubLegacy) => DISTINST_MBR_BOOTLOADER::GRUB_LEGACY,
+            Some(MbrBootloader::Lilo) => DISTINST_MBR_BOOTLOADER::LILO,
+            Some(MbrBootloader::Syslinux) => DISTINST_MBR_BOOTLOADER::SYSLINUX,
+            Some(MbrBootloader::Windows) => DISTINST_MBR_BOOTLOADER::WINDOWS,
+            Some(MbrBootloader::WindowsXp) => DISTINST_MBR_BOOTLOADER::WINDOWS_XP,
+            Some(MbrBootloader::Unknown) => DISTINST_MBR_BOOTLOADER::UNKNOWN,
+            None => DISTINST_MBR_BOOTLOADER::NONE,
+        }
+    }
+}
+
+/// The bootloader which was in the MBR of the disk when it was probed.
+#[no_mangle]
+pub unsafe extern "C" fn distinst_disk_detected_bootloader(
+    disk: *const DistinstDisk,
+) -> DISTINST_MBR_BOOTLOADER {
+    if null_check(disk).is_err() {
+        return DISTINST_MBR_BOOTLOADER::NONE;
+    }
+
+    let disk = &*(disk as *const Disk);
+    disk.detected_bootloader().into()
+}
+
 #[no_mangle]
 pub unsafe extern "C" fn distinst_disk_mklabel(
     disk: *mut DistinstDisk,
@@ -647,6 +689,49 @@ pub unsafe extern "C" fn distinst_disks_find_partition(
         .unwrap_or(ptr::null_mut())
 }
 
+/// Verifies that the layout of the disks can be booted by the firmware of the system.
+/// Returns 0 if it can, or -1 if it can't, with the reason logged.
+#[no_mangle]
+pub unsafe extern "C" fn distinst_disks_verify_installable(
+    disks: *const DistinstDisks,
+) -> libc::c_int {
+    if null_check(disks).is_err() {
+        return -1;
+    }
+
+    let disks = &*(disks as *const Disks);
+    let mode = distinst::bootloader::detect();
+    match Installer::verify_installable(disks, mode, BootloaderChoice::Auto) {
+        Ok(()) => 0,
+        Err(why) => {
+            error!("layout is not installable: {}", why);
+            -1
+        }
+    }
+}
+
+/// Pins the disk which the BIOS bootloader is installed to. Returns 0 on success, or -1
+/// if the disk is not one of the disks of the configuration.
+#[no_mangle]
+pub unsafe extern "C" fn distinst_disks_set_bootloader_disk(
+    disks: *mut DistinstDisks,
+    path: *const libc::c_char,
+) -> libc::c_int {
+    if null_check(disks).or_else(|_| null_check(path)).is_err() {
+        return -1;
+    }
+
+    let disks = &mut *(disks as *mut Disks);
+    match get_str(path).map(|path| disks.set_bootloader_disk(path)) {
+        Ok(Ok(())) => 0,
+        Ok(Err(why)) => {
+            error!("unable to set the bootloader disk: {}", why);
+            -1
+        }
+        Err(_) => -1,
+    }
+}
+
 #[no_mangle]
 pub unsafe extern "C" fn distinst_disks_decrypt_partition(
     disks: *mut DistinstDisks,
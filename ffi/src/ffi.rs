@@ -1,6 +1,22 @@
 //! Contains some convenience functions and traits for handling the C API.
 
-use std::ptr;
+use std::{any::Any, panic, ptr};
+
+/// Runs a function which is called across the C API, so that a panic within it is logged
+/// and replaced by `default`, rather than unwinding into the C caller.
+pub fn catch_panic<T, F: FnOnce() -> T>(name: &str, default: T, func: F) -> T {
+    panic::catch_unwind(panic::AssertUnwindSafe(func)).unwrap_or_else(|why| {
+        error!("{}: panicked: {}", name, panic_message(&*why));
+        default
+    })
+}
+
+fn panic_message(why: &(dyn Any + Send)) -> &str {
+    why.downcast_ref::<&str>()
+        .copied()
+        .or_else(|| why.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("unknown panic")
+}
 
 /// https://stackoverflow.com/a/35888360
 pub trait AsMutPtr<T> {
@@ -1,13 +1,15 @@
 use libc;
 
-use std::{io, mem};
+use std::{ffi::CString, io, mem, os::unix::ffi::OsStrExt, path::PathBuf, ptr};
 
-use crate::config::DistinstConfig;
+use crate::config::{DistinstConfig, DistinstConfigV2};
 use crate::disk::DistinstDisks;
-use distinst::{timezones::Region, Disks, Error, Installer, Status, Step};
-use crate::gen_object_ptr;
+use distinst::{
+    timezones::Region, Config, Disks, Error, ErrorCode, Installer, Status, Step, Transfer,
+};
+use crate::{ffi::catch_panic, gen_object_ptr, get_str, null_check};
 use crate::DistinstRegion;
-use crate::DistinstUserAccountCreate;
+use crate::{DistinstUserAccountCreate, DistinstUserAccountCreateV2};
 
 /// Bootloader steps
 #[repr(C)]
@@ -19,6 +21,8 @@ pub enum DISTINST_STEP {
     EXTRACT,
     CONFIGURE,
     BOOTLOADER,
+    VERIFY,
+    RECOVERY,
 }
 
 impl From<DISTINST_STEP> for Step {
@@ -31,6 +35,8 @@ impl From<DISTINST_STEP> for Step {
             EXTRACT => Step::Extract,
             CONFIGURE => Step::Configure,
             BOOTLOADER => Step::Bootloader,
+            VERIFY => Step::Verify,
+            RECOVERY => Step::Recovery,
         }
     }
 }
@@ -45,11 +51,46 @@ impl From<Step> for DISTINST_STEP {
             Step::Extract => EXTRACT,
             Step::Configure => CONFIGURE,
             Step::Bootloader => BOOTLOADER,
+            Step::Verify => VERIFY,
+            Step::Recovery => RECOVERY,
         }
     }
 }
 
-/// Installer error message
+/// The category of an installer error, whose values are stable between releases.
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub enum DISTINST_ERROR_CODE {
+    BACKUP = 1,
+    INIT = 2,
+    VERIFY = 3,
+    PARTITION = 4,
+    EXTRACT = 5,
+    RECOVERY = 6,
+    CONFIGURE = 7,
+    BOOTLOADER = 8,
+    CANCELLED = 9,
+}
+
+impl From<ErrorCode> for DISTINST_ERROR_CODE {
+    fn from(code: ErrorCode) -> Self {
+        use DISTINST_ERROR_CODE::*;
+        match code {
+            ErrorCode::Backup => BACKUP,
+            ErrorCode::Init => INIT,
+            ErrorCode::Verify => VERIFY,
+            ErrorCode::Partition => PARTITION,
+            ErrorCode::Extract => EXTRACT,
+            ErrorCode::Recovery => RECOVERY,
+            ErrorCode::Configure => CONFIGURE,
+            ErrorCode::Bootloader => BOOTLOADER,
+            ErrorCode::Cancelled => CANCELLED,
+        }
+    }
+}
+
+/// Installer error message, whose layout is kept as it was first released. The details which
+/// were added since are given with `DistinstErrorV2`.
 #[repr(C)]
 #[derive(Copy, Clone, Debug)]
 pub struct DistinstError {
@@ -57,11 +98,31 @@ pub struct DistinstError {
     err:  libc::c_int,
 }
 
+/// Installer error message, with the details which were added after `DistinstError`.
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct DistinstErrorV2 {
+    base:    DistinstError,
+    /// Ignored when the error is emitted, as it is derived from the step and `err`.
+    code:    DISTINST_ERROR_CODE,
+    /// The command which failed, if it is known.
+    command: *const libc::c_char,
+    /// The device which the step failed on, if it is known.
+    device:  *const libc::c_char,
+    /// The error message, which is ignored when the error is emitted.
+    message: *const libc::c_char,
+}
+
 /// Installer error callback
 pub type DistinstErrorCallback =
     extern "C" fn(status: *const DistinstError, user_data: *mut libc::c_void);
 
-/// Installer status message
+/// Installer error callback, which is given the details of the error.
+pub type DistinstErrorCallbackV2 =
+    extern "C" fn(status: *const DistinstErrorV2, user_data: *mut libc::c_void);
+
+/// Installer status message, whose layout is kept as it was first released. The details
+/// which were added since are given with `DistinstStatusV2`.
 #[repr(C)]
 #[derive(Copy, Clone, Debug)]
 pub struct DistinstStatus {
@@ -69,10 +130,32 @@ pub struct DistinstStatus {
     percent: libc::c_int,
 }
 
+/// Installer status message, with the details which were added after `DistinstStatus`.
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct DistinstStatusV2 {
+    base:        DistinstStatus,
+    message:     *const libc::c_char,
+    cancelled:   bool,
+    /// Bytes copied by the step, which is 0 with `bytes_total` when nothing is copied.
+    bytes_done:  u64,
+    bytes_total: u64,
+    /// Bytes per second, or -1 if the rate is not yet known.
+    rate:        libc::c_double,
+    /// Seconds until the copy completes, or -1 if it is not yet known.
+    eta_seconds: i64,
+    /// The sub-task which is being run within the step, if the step has several.
+    substep:     *const libc::c_char,
+}
+
 /// Installer status callback
 pub type DistinstStatusCallback =
     extern "C" fn(status: *const DistinstStatus, user_data: *mut libc::c_void);
 
+/// Installer status callback, which is given the details of the status.
+pub type DistinstStatusCallbackV2 =
+    extern "C" fn(status: *const DistinstStatusV2, user_data: *mut libc::c_void);
+
 /// Installer timezone callback
 pub type DistinstTimezoneCallback =
     extern "C" fn(user_data: *mut libc::c_void) -> *const DistinstRegion;
@@ -81,6 +164,18 @@ pub type DistinstTimezoneCallback =
 pub type DistinstUserAccountCallback =
     extern "C" fn(user_account_create: *mut DistinstUserAccountCreate, user_data: *mut libc::c_void);
 
+/// Installer user account creation callback, which may give the settings of the account
+/// which were added after `DistinstUserAccountCreate`.
+pub type DistinstUserAccountCallbackV2 = extern "C" fn(
+    user_account_create: *mut DistinstUserAccountCreateV2,
+    user_data: *mut libc::c_void,
+);
+
+/// Installer secret callback, which is given the path of a locked LUKS partition, and returns
+/// its passphrase, or null to leave the partition locked.
+pub type DistinstSecretCallback =
+    extern "C" fn(path: *const libc::c_char, user_data: *mut libc::c_void) -> *const libc::c_char;
+
 /// An installer object
 #[repr(C)]
 pub struct DistinstInstaller;
@@ -91,16 +186,42 @@ pub unsafe extern "C" fn distinst_installer_new() -> *mut DistinstInstaller {
     gen_object_ptr(Installer::default()) as *mut DistinstInstaller
 }
 
+impl DistinstError {
+    fn as_error(&self) -> Error {
+        let step = self.step.into();
+        match self.err {
+            libc::ECANCELED => Error::Cancelled { step },
+            err => Error::new(step, io::Error::from_raw_os_error(err)),
+        }
+    }
+
+    fn new(error: &Error) -> Self {
+        DistinstError { step: error.step().into(), err: error.raw_os_error() }
+    }
+}
+
 /// Send an installer status message
 #[no_mangle]
 pub unsafe extern "C" fn distinst_installer_emit_error(
     installer: *mut DistinstInstaller,
-    error: *const DistinstError,
+    error_ptr: *const DistinstError,
 ) {
-    (*(installer as *mut Installer)).emit_error(&Error {
-        step: (*error).step.into(),
-        err:  io::Error::from_raw_os_error((*error).err),
-    });
+    (*(installer as *mut Installer)).emit_error(&(*error_ptr).as_error());
+}
+
+/// Send an installer error message, with the command and device which it failed on.
+#[no_mangle]
+pub unsafe extern "C" fn distinst_installer_emit_error_v2(
+    installer: *mut DistinstInstaller,
+    error_ptr: *const DistinstErrorV2,
+) {
+    let mut error = (*error_ptr).base.as_error();
+    if let Some(context) = error.context_mut() {
+        context.command = get_str((*error_ptr).command).ok().map(String::from);
+        context.device = get_str((*error_ptr).device).ok().map(PathBuf::from);
+    }
+
+    (*(installer as *mut Installer)).emit_error(&error);
 }
 
 /// Set the installer status callback
@@ -111,11 +232,35 @@ pub unsafe extern "C" fn distinst_installer_on_error(
     user_data: *mut libc::c_void,
 ) {
     (*(installer as *mut Installer)).on_error(move |error| {
+        callback(&DistinstError::new(error) as *const DistinstError, user_data)
+    });
+}
+
+/// Set the installer error callback, which is given the details of each error.
+#[no_mangle]
+pub unsafe extern "C" fn distinst_installer_on_error_v2(
+    installer: *mut DistinstInstaller,
+    callback: DistinstErrorCallbackV2,
+    user_data: *mut libc::c_void,
+) {
+    (*(installer as *mut Installer)).on_error(move |error| {
+        let context = error.context();
+        let command = context
+            .and_then(|context| context.command.as_ref())
+            .and_then(|command| CString::new(command.as_str()).ok());
+        let device = context
+            .and_then(|context| context.device.as_ref())
+            .and_then(|device| CString::new(device.as_os_str().as_bytes()).ok());
+        let message = CString::new(error.to_string()).ok();
+
         callback(
-            &DistinstError {
-                step: error.step.into(),
-                err:  error.err.raw_os_error().unwrap_or(libc::EIO),
-            } as *const DistinstError,
+            &DistinstErrorV2 {
+                base:    DistinstError::new(error),
+                code:    error.code().into(),
+                command: command.as_ref().map_or(ptr::null(), |c| c.as_ptr()),
+                device:  device.as_ref().map_or(ptr::null(), |d| d.as_ptr()),
+                message: message.as_ref().map_or(ptr::null(), |m| m.as_ptr()),
+            } as *const DistinstErrorV2,
             user_data,
         )
     });
@@ -127,8 +272,34 @@ pub unsafe extern "C" fn distinst_installer_emit_status(
     installer: *mut DistinstInstaller,
     status: *const DistinstStatus,
 ) {
-    (*(installer as *mut Installer))
-        .emit_status(Status { step: (*status).step.into(), percent: (*status).percent });
+    let status = &*status;
+    (*(installer as *mut Installer)).emit_status(Status::new(status.step.into(), status.percent));
+}
+
+/// Send an installer status message, with the details of the status.
+#[no_mangle]
+pub unsafe extern "C" fn distinst_installer_emit_status_v2(
+    installer: *mut DistinstInstaller,
+    status: *const DistinstStatusV2,
+) {
+    let status = &*status;
+    let transfer = if status.bytes_total == 0 {
+        None
+    } else {
+        Some(Transfer {
+            bytes_done:  status.bytes_done,
+            bytes_total: status.bytes_total,
+            rate:        Some(status.rate).filter(|&rate| rate >= 0.0),
+            eta_seconds: Some(status.eta_seconds).filter(|&eta| eta >= 0).map(|eta| eta as u64),
+        })
+    };
+
+    // Sub-tasks are only reported by the steps of the installer.
+    let mut emitted = Status::new(status.base.step.into(), status.base.percent);
+    emitted.message = get_str(status.message).ok().map(String::from);
+    emitted.cancelled = status.cancelled;
+    emitted.transfer = transfer;
+    (*(installer as *mut Installer)).emit_status(emitted);
 }
 
 /// Set the installer status callback
@@ -147,6 +318,32 @@ pub unsafe extern "C" fn distinst_installer_on_status(
     });
 }
 
+/// Set the installer status callback, which is given the details of each status.
+#[no_mangle]
+pub unsafe extern "C" fn distinst_installer_on_status_v2(
+    installer: *mut DistinstInstaller,
+    callback: DistinstStatusCallbackV2,
+    user_data: *mut libc::c_void,
+) {
+    (*(installer as *mut Installer)).on_status(move |status| {
+        let message = status.message.as_ref().and_then(|m| CString::new(m.as_str()).ok());
+        let substep = status.substep.and_then(|s| CString::new(s).ok());
+        callback(
+            &DistinstStatusV2 {
+                base:        DistinstStatus { step: status.step.into(), percent: status.percent },
+                message:     message.as_ref().map_or(ptr::null(), |m| m.as_ptr()),
+                cancelled:   status.cancelled,
+                bytes_done:  status.bytes_done().unwrap_or(0),
+                bytes_total: status.bytes_total().unwrap_or(0),
+                rate:        status.rate().unwrap_or(-1.0),
+                eta_seconds: status.eta_seconds().map_or(-1, |eta| eta as i64),
+                substep:     substep.as_ref().map_or(ptr::null(), |s| s.as_ptr()),
+            } as *const DistinstStatusV2,
+            user_data,
+        )
+    });
+}
+
 #[no_mangle]
 pub unsafe extern "C" fn distinst_installer_set_timezone_callback(
     installer: *mut DistinstInstaller,
@@ -163,13 +360,106 @@ pub unsafe extern "C" fn distinst_installer_set_user_callback(
     callback: DistinstUserAccountCallback,
     user_data: *mut libc::c_void,
 ) {
-    (*(installer as *mut Installer)).set_user_callback(move || {
+    (*(installer as *mut Installer)).set_user_callback_checked(move || {
+        let mut user_account_create = mem::zeroed();
+        callback(&mut user_account_create, user_data);
+        user_account_create.as_config()
+    });
+}
+
+/// Set the user callback, which may give the settings of the account that were added after
+/// `DistinstUserAccountCreate`.
+#[no_mangle]
+pub unsafe extern "C" fn distinst_installer_set_user_callback_v2(
+    installer: *mut DistinstInstaller,
+    callback: DistinstUserAccountCallbackV2,
+    user_data: *mut libc::c_void,
+) {
+    (*(installer as *mut Installer)).set_user_callback_checked(move || {
         let mut user_account_create = mem::zeroed();
         callback(&mut user_account_create, user_data);
-        user_account_create.as_config().expect("user callback invalid")
+        user_account_create.as_config()
+    });
+}
+
+/// Set the callback which supplies the passphrases of locked LUKS partitions, as the disks
+/// are probed by `distinst_installer_disks`.
+#[no_mangle]
+pub unsafe extern "C" fn distinst_installer_set_secret_provider(
+    installer: *mut DistinstInstaller,
+    callback: DistinstSecretCallback,
+    user_data: *mut libc::c_void,
+) {
+    (*(installer as *mut Installer)).set_secret_provider(move |path| {
+        let path = CString::new(path.as_os_str().as_bytes()).ok()?;
+        let secret = callback(path.as_ptr(), user_data);
+        if secret.is_null() {
+            None
+        } else {
+            get_str(secret).ok().map(String::from)
+        }
     });
 }
 
+/// Probes the disks of the system, and unlocks the locked LUKS partitions with the passphrases
+/// of the secret provider. Partitions which fail to unlock will remain locked.
+///
+/// Returns null if the disks could not be probed.
+#[no_mangle]
+pub unsafe extern "C" fn distinst_installer_disks(
+    installer: *const DistinstInstaller,
+) -> *mut DistinstDisks {
+    if null_check(installer).is_err() {
+        return ptr::null_mut();
+    }
+
+    match (*(installer as *const Installer)).disks() {
+        Ok(disks) => gen_object_ptr(disks) as *mut DistinstDisks,
+        Err(why) => {
+            info!("unable to probe devices: {}", why);
+            ptr::null_mut()
+        }
+    }
+}
+
+/// Set the number of threads which decompress the image, where zero uses every processor
+/// but one, up to a limit.
+#[no_mangle]
+pub unsafe extern "C" fn distinst_installer_set_extract_threads(
+    installer: *mut DistinstInstaller,
+    threads: libc::size_t,
+) {
+    (*(installer as *mut Installer)).set_extract_threads(threads);
+}
+
+/// Set the space required beyond the size of the source, as a percent of it, before the
+/// disks are partitioned.
+#[no_mangle]
+pub unsafe extern "C" fn distinst_installer_set_space_margin(
+    installer: *mut DistinstInstaller,
+    percent: u8,
+) {
+    (*(installer as *mut Installer)).set_space_margin(percent);
+}
+
+/// Set whether the file systems of the install are trimmed once it is configured.
+#[no_mangle]
+pub unsafe extern "C" fn distinst_installer_set_trim(
+    installer: *mut DistinstInstaller,
+    trim: bool,
+) {
+    (*(installer as *mut Installer)).set_trim(trim);
+}
+
+/// Set whether installs stop once the disks are partitioned, before the image is extracted.
+#[no_mangle]
+pub unsafe extern "C" fn distinst_installer_set_simulate(
+    installer: *mut DistinstInstaller,
+    simulate: bool,
+) {
+    (*(installer as *mut Installer)).set_simulate(simulate);
+}
+
 /// Install using this installer, whilst retaining home & user accounts.
 #[no_mangle]
 pub unsafe extern "C" fn distinst_installer_install(
@@ -177,27 +467,95 @@ pub unsafe extern "C" fn distinst_installer_install(
     disks: *mut DistinstDisks,
     config: *const DistinstConfig,
 ) -> libc::c_int {
-    let disks: Box<Disks> = if disks.is_null() || installer.is_null() || config.is_null() {
+    if config.is_null() {
+        return libc::EIO;
+    }
+
+    install("distinst_installer_install", installer, disks, false, || (*config).as_config())
+}
+
+/// Install using this installer, with the settings that were added after `DistinstConfig`.
+#[no_mangle]
+pub unsafe extern "C" fn distinst_installer_install_v2(
+    installer: *mut DistinstInstaller,
+    disks: *mut DistinstDisks,
+    config: *const DistinstConfigV2,
+) -> libc::c_int {
+    if config.is_null() {
+        return libc::EIO;
+    }
+
+    install("distinst_installer_install_v2", installer, disks, false, || (*config).as_config())
+}
+
+/// Resume an install which was interrupted after its disks were partitioned.
+#[no_mangle]
+pub unsafe extern "C" fn distinst_installer_resume(
+    installer: *mut DistinstInstaller,
+    disks: *mut DistinstDisks,
+    config: *const DistinstConfigV2,
+) -> libc::c_int {
+    if config.is_null() {
+        return libc::EIO;
+    }
+
+    install("distinst_installer_resume", installer, disks, true, || (*config).as_config())
+}
+
+/// Installs, or resumes an install, with the configuration that `config` reads.
+unsafe fn install<F: FnOnce() -> io::Result<Config>>(
+    name: &str,
+    installer: *mut DistinstInstaller,
+    disks: *mut DistinstDisks,
+    resume: bool,
+    config: F,
+) -> libc::c_int {
+    let disks: Box<Disks> = if disks.is_null() || installer.is_null() {
         return libc::EIO;
     } else {
         Box::from_raw(disks as *mut Disks)
     };
 
-    match (*config).as_config() {
-        Ok(config) => match (*(installer as *mut Installer)).install(*disks, &config) {
-            Ok(()) => 0,
-            Err(err) => {
-                info!("Install error: {}", err);
-                err.raw_os_error().unwrap_or(libc::EIO)
+    let installer = &mut *(installer as *mut Installer);
+    catch_panic(name, libc::EIO, || match config() {
+        Ok(config) => {
+            let result = if resume {
+                installer.resume(*disks, &config)
+            } else {
+                installer.install(*disks, &config)
+            };
+
+            match result {
+                Ok(_) => 0,
+                Err(err) => {
+                    info!("Install error: {}", err);
+                    Error::from_io(&err)
+                        .map(Error::raw_os_error)
+                        .or_else(|| err.raw_os_error())
+                        .unwrap_or(libc::EIO)
+                }
             }
-        },
+        }
         Err(err) => {
             info!("Config error: {}", err);
             let errno = err.raw_os_error().unwrap_or(libc::EIO);
-            (*(installer as *mut Installer)).emit_error(&Error { step: Step::Init, err });
+            installer.emit_error(&Error::new(Step::Init, err));
             errno
         }
-    }
+    })
+}
+
+/// Unmount everything left behind by an installer that was killed, and deactivate any
+/// logical volumes and encrypted partitions which remain open.
+#[no_mangle]
+pub extern "C" fn distinst_installer_cleanup() -> libc::c_int {
+    catch_panic("distinst_installer_cleanup", libc::EIO, || match Installer::cleanup() {
+        Ok(()) => 0,
+        Err(err) => {
+            info!("Cleanup error: {}", err);
+            err.raw_os_error().unwrap_or(libc::EIO)
+        }
+    })
 }
 
 /// Destroy an installer object
@@ -1,3 +1,15 @@
+//! The C API of distinst, for which `distinst.h` is generated by cbindgen when it is built.
+//!
+//! Every string which crosses the API is nul-terminated UTF-8. Strings given to distinst are
+//! borrowed for the duration of the call, and strings given to callbacks are only valid until
+//! the callback returns. Strings which are returned are owned by the caller, unless they are
+//! documented as borrowed from an object. They are allocated by Rust, so they are released
+//! with `distinst_string_free()`, and arrays of them with `distinst_strings_free()`, rather
+//! than with `free()`. Objects are released by the `distinst_*_destroy` function of their type.
+//!
+//! Panics are not permitted to unwind into C. Functions which run the installer catch them,
+//! and return `EIO` after logging the panic.
+
 #![allow(unknown_lints)]
 #![allow(cast_ptr_alignment)]
 
@@ -10,7 +22,7 @@ extern crate partition_identity;
 
 use std::{
     ffi::{CStr, CString},
-    ptr,
+    ptr, slice,
 };
 
 pub use self::{
@@ -22,6 +34,10 @@ pub const DISTINST_MODIFY_BOOT_ORDER: u8 = 0b1;
 pub const DISTINST_INSTALL_HARDWARE_SUPPORT: u8 = 0b10;
 pub const DISTINST_KEEP_OLD_ROOT: u8 = 0b100;
 pub const DISTINST_RUN_UBUNTU_DRIVERS: u8 = 0b1000;
+pub const DISTINST_DELETE_EXTRANEOUS: u8 = 0b1_0000;
+pub const DISTINST_KEEP_IDENTITY: u8 = 0b10_0000;
+pub const DISTINST_FORCE_FALLBACK: u8 = 0b100_0000;
+pub const DISTINST_WINDOWS_SECOND: u8 = 0b1000_0000;
 
 use std::io;
 
@@ -62,10 +78,32 @@ pub fn get_str<'a>(ptr: *const libc::c_char) -> io::Result<&'a str> {
     })
 }
 
+/// Converts the string into one which is owned by the C caller, or null if it contains a nul.
 pub fn to_cstr(string: String) -> *mut libc::c_char {
     CString::new(string).map(|string| string.into_raw()).unwrap_or(ptr::null_mut())
 }
 
+/// Releases a string which was returned by distinst.
+#[no_mangle]
+pub unsafe extern "C" fn distinst_string_free(string: *mut libc::c_char) {
+    if !string.is_null() {
+        drop(CString::from_raw(string));
+    }
+}
+
+/// Releases an array of strings which was returned by distinst, along with its strings.
+#[no_mangle]
+pub unsafe extern "C" fn distinst_strings_free(strings: *mut *mut libc::c_char, len: libc::c_int) {
+    if strings.is_null() {
+        return;
+    }
+
+    let strings = Box::from_raw(slice::from_raw_parts_mut(strings, len as usize));
+    for &string in strings.iter() {
+        distinst_string_free(string);
+    }
+}
+
 #[no_mangle]
 pub extern "C" fn distinst_device_layout_hash() -> u64 { distinst::device_layout_hash() }
 
@@ -92,12 +130,27 @@ pub unsafe extern "C" fn distinst_generate_unique_id(
 
 #[no_mangle]
 pub unsafe extern "C" fn distinst_validate_hostname(hostname: *const libc::c_char) -> bool {
-    get_str(hostname).ok().map_or(false, |hostname| distinst::hostname::is_valid(hostname))
+    get_str(hostname).ok().map_or(false, |hostname| distinst::validate_hostname(hostname).is_ok())
 }
 
 #[no_mangle]
 pub extern "C" fn distinst_minimum_disk_size(size: u64) -> u64 { distinst::minimum_disk_size(size) }
 
+/// The minimum size of a disk, in bytes, which the source at the given path may be installed
+/// to, or 0 if the size of the source could not be found.
+#[no_mangle]
+pub unsafe extern "C" fn distinst_minimum_disk_size_of_source(source: *const libc::c_char) -> u64 {
+    let source = match get_str(source) {
+        Ok(source) => source,
+        Err(_) => return 0,
+    };
+
+    distinst::minimum_disk_size_of_source(source).unwrap_or_else(|why| {
+        error!("unable to find the size of {}: {}", source, why);
+        0
+    })
+}
+
 #[no_mangle]
 pub extern "C" fn distinst_unset_mode() -> bool {
     match distinst::unset_mode() {
@@ -149,10 +202,45 @@ pub unsafe extern "C" fn distinst_log(
             Level::Warn => WARN,
             Level::Error => ERROR,
         };
-        let c_message = CString::new(message).unwrap();
+        // Messages are truncated at an interior nul, rather than panicking within the logger.
+        let c_message = CString::new(message).unwrap_or_else(|why| {
+            let position = why.nul_position();
+            let mut bytes = why.into_vec();
+            bytes.truncate(position);
+            CString::new(bytes).unwrap_or_default()
+        });
         callback(c_level, c_message.as_ptr(), user_data_sync as *mut libc::c_void);
     }) {
         Ok(()) => 0,
         Err(_err) => libc::EINVAL,
     }
 }
+
+/// Appends the log of the installer to the file at `path`, with timestamps, for messages at
+/// or above the `level`.
+#[no_mangle]
+pub unsafe extern "C" fn distinst_log_to_file(
+    path: *const libc::c_char,
+    level: DISTINST_LOG_LEVEL,
+) -> libc::c_int {
+    use log::LevelFilter;
+    use DISTINST_LOG_LEVEL::*;
+
+    let path = match get_str(path) {
+        Ok(path) => path,
+        Err(why) => return why.raw_os_error().unwrap_or(-1),
+    };
+
+    let level = match level {
+        TRACE => LevelFilter::Trace,
+        DEBUG => LevelFilter::Debug,
+        INFO => LevelFilter::Info,
+        WARN => LevelFilter::Warn,
+        ERROR => LevelFilter::Error,
+    };
+
+    match distinst::log_to_file(path, level) {
+        Ok(()) => 0,
+        Err(_err) => libc::EINVAL,
+    }
+}
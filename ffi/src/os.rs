@@ -1,7 +1,11 @@
 use super::null_check;
 use distinst::os_release::{OsRelease, OS_RELEASE};
 use libc;
-use std::{ffi::CString, mem::forget, ptr};
+use std::{
+    ffi::{CString, NulError},
+    mem::forget,
+    ptr,
+};
 
 macro_rules! get_os_release {
     () => {
@@ -30,21 +34,33 @@ pub struct DistinstOsRelease {
 }
 
 impl DistinstOsRelease {
-    pub unsafe fn from_os_release(release: &OsRelease) -> DistinstOsRelease {
-        DistinstOsRelease {
-            bug_report_url:     CString::new(release.bug_report_url.clone()).unwrap().into_raw(),
-            home_url:           CString::new(release.home_url.clone()).unwrap().into_raw(),
-            id_like:            CString::new(release.id_like.clone()).unwrap().into_raw(),
-            id:                 CString::new(release.id.clone()).unwrap().into_raw(),
-            name:               CString::new(release.name.clone()).unwrap().into_raw(),
-            pretty_name:        CString::new(release.pretty_name.clone()).unwrap().into_raw(),
-            privacy_policy_url: CString::new(release.privacy_policy_url.clone())
-                .unwrap()
-                .into_raw(),
-            support_url:        CString::new(release.support_url.clone()).unwrap().into_raw(),
-            version_codename:   CString::new(release.version_codename.clone()).unwrap().into_raw(),
-            version_id:         CString::new(release.version_id.clone()).unwrap().into_raw(),
-        }
+    /// Copies the fields of the release, which fails if any of them contain a nul.
+    pub unsafe fn from_os_release(release: &OsRelease) -> Result<DistinstOsRelease, NulError> {
+        // Every field is copied before any is given to C, so that none leak on an error.
+        let field = |value: &String| CString::new(value.as_str());
+        let bug_report_url = field(&release.bug_report_url)?;
+        let home_url = field(&release.home_url)?;
+        let id_like = field(&release.id_like)?;
+        let id = field(&release.id)?;
+        let name = field(&release.name)?;
+        let pretty_name = field(&release.pretty_name)?;
+        let privacy_policy_url = field(&release.privacy_policy_url)?;
+        let support_url = field(&release.support_url)?;
+        let version_codename = field(&release.version_codename)?;
+        let version_id = field(&release.version_id)?;
+
+        Ok(DistinstOsRelease {
+            bug_report_url:     bug_report_url.into_raw(),
+            home_url:           home_url.into_raw(),
+            id_like:            id_like.into_raw(),
+            id:                 id.into_raw(),
+            name:               name.into_raw(),
+            pretty_name:        pretty_name.into_raw(),
+            privacy_policy_url: privacy_policy_url.into_raw(),
+            support_url:        support_url.into_raw(),
+            version_codename:   version_codename.into_raw(),
+            version_id:         version_id.into_raw(),
+        })
     }
 }
 
@@ -3,8 +3,8 @@ use libc;
 use std::{ffi::CString, io, os::unix::ffi::OsStrExt, path::PathBuf, ptr};
 
 use distinst::{
-    BlockDeviceExt, Bootloader, FileSystem, LvmEncryption, PartitionBuilder, PartitionExt,
-    PartitionFlag, PartitionInfo, PartitionTable, PartitionType,
+    BlockDeviceExt, BootMode, Bootloader, FileSystem, LvmEncryption, PartitionBuilder,
+    PartitionExt, PartitionFlag, PartitionInfo, PartitionTable, PartitionType, SwapEncryption,
 };
 use crate::filesystem::DISTINST_FILE_SYSTEM;
 use crate::gen_object_ptr;
@@ -38,6 +38,31 @@ pub unsafe extern "C" fn distinst_bootloader_detect() -> DISTINST_PARTITION_TABL
     }
 }
 
+/// The firmware which the live system was booted from.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DISTINST_BOOT_MODE {
+    BIOS = 1,
+    EFI = 2,
+    /// 32-bit EFI firmware on a 64-bit CPU.
+    IA32_EFI = 3,
+}
+
+impl From<BootMode> for DISTINST_BOOT_MODE {
+    fn from(mode: BootMode) -> Self {
+        match mode {
+            BootMode::Bios => DISTINST_BOOT_MODE::BIOS,
+            BootMode::Efi => DISTINST_BOOT_MODE::EFI,
+            BootMode::Ia32Efi => DISTINST_BOOT_MODE::IA32_EFI,
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn distinst_boot_mode_detect() -> DISTINST_BOOT_MODE {
+    distinst::bootloader::detect().into()
+}
+
 #[repr(C)]
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum DISTINST_PARTITION_TYPE {
@@ -247,6 +272,14 @@ pub unsafe extern "C" fn distinst_partition_builder_flag(
     builder_action(builder, |builder| builder.flag(flag.into()))
 }
 
+/// Encrypts the new swap partition with a random key on every boot, instead of formatting it.
+#[no_mangle]
+pub unsafe extern "C" fn distinst_partition_builder_random_swap(
+    builder: *mut DistinstPartitionBuilder,
+) -> *mut DistinstPartitionBuilder {
+    builder_action(builder, |builder| builder.swap_encryption(SwapEncryption::Random))
+}
+
 #[no_mangle]
 pub unsafe extern "C" fn distinst_partition_builder_logical_volume(
     builder: *mut DistinstPartitionBuilder,
@@ -436,7 +469,10 @@ pub unsafe extern "C" fn distinst_partition_set_mount(
 
     let target = match get_str(target) {
         Ok(string) => PathBuf::from(string.to_string()),
-        Err(why) => panic!("partition action: failed: {}", why),
+        Err(why) => {
+            error!("distinst_partition_set_mount: {}", why);
+            return;
+        }
     };
 
     let part = &mut *(partition as *mut PartitionInfo);
@@ -454,7 +490,10 @@ pub unsafe extern "C" fn distinst_partition_associate_keyfile(
 
     let keyid = match get_str(keyid) {
         Ok(string) => string.to_string(),
-        Err(why) => panic!("partition action: failed: {}", why),
+        Err(why) => {
+            error!("distinst_partition_associate_keyfile: {}", why);
+            return;
+        }
     };
 
     let part = &mut *(partition as *mut PartitionInfo);
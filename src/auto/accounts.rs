@@ -2,7 +2,7 @@
 
 use super::{mount_and_then, ReinstallError};
 use disk_types::FileSystem;
-use std::{collections::HashMap, ffi::OsStr, os::unix::ffi::OsStrExt, path::Path};
+use std::{collections::HashMap, ffi::OsStr, fmt, os::unix::ffi::OsStrExt, path::Path};
 
 use crate::misc::read;
 
@@ -47,6 +47,12 @@ impl AccountFiles {
         })
     }
 
+    /// Maps the user and group IDs of the old install to the IDs of the accounts of the same
+    /// names in the `passwd` and `group` files of the new install.
+    pub fn id_maps(&self, passwd: &[u8], group: &[u8]) -> (HashMap<u32, u32>, HashMap<u32, u32>) {
+        (id_map(&self.passwd, &ids(passwd)), id_map(&self.group, &ids(group)))
+    }
+
     pub fn get(&self, home: &OsStr) -> Option<UserData> {
         let mut home_path = b"/home/".to_vec();
         home_path.extend_from_slice(home.as_bytes());
@@ -104,11 +110,100 @@ impl AccountFiles {
     }
 }
 
+/// Maps the names of the entries of a passwd or group file to their IDs.
+pub(crate) fn ids(input: &[u8]) -> HashMap<Vec<u8>, u32> {
+    input.split(|&b| b == b'\n').filter_map(name_and_id).map(|(n, id)| (n.to_owned(), id)).collect()
+}
+
+fn name_and_id(entry: &[u8]) -> Option<(&[u8], u32)> {
+    let mut fields = entry.split(|&x| x == b':');
+    let name = fields.next().filter(|name| !name.is_empty())?;
+    let id = fields.nth(1).and_then(|id| std::str::from_utf8(id).ok())?.parse::<u32>().ok()?;
+    Some((name, id))
+}
+
+fn id_map(old: &HashMap<Vec<u8>, Vec<u8>>, new: &HashMap<Vec<u8>, u32>) -> HashMap<u32, u32> {
+    old.values()
+        .filter_map(|entry| name_and_id(entry))
+        .filter_map(|(name, id)| new.get(name).map(|&new_id| (id, new_id)))
+        .collect()
+}
+
+/// An account of the old install which conflicts with an account of the new install.
+#[derive(Debug, PartialEq)]
+pub struct Collision {
+    pub kind:        &'static str,
+    pub name:        String,
+    pub id:          u32,
+    pub existing:    String,
+    pub existing_id: u32,
+}
+
+impl fmt::Display for Collision {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} '{}' ({}) collides with '{}' ({}) of the new install",
+            self.kind, self.name, self.id, self.existing, self.existing_id
+        )
+    }
+}
+
+/// Accounts of the old install which could not be retained.
+#[derive(Debug)]
+pub struct Collisions(pub Vec<Collision>);
+
+impl fmt::Display for Collisions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (id, collision) in self.0.iter().enumerate() {
+            if id != 0 {
+                f.write_str(", ")?;
+            }
+
+            write!(f, "{}", collision)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Checks if an `entry` of the old passwd or group file may be merged into the `existing`
+/// file of the new install. It is already there when an account of the same name and ID
+/// exists, and collides when only one of the two matches.
+pub(crate) fn merge_check(
+    kind: &'static str,
+    existing: &[u8],
+    entry: &[u8],
+) -> Result<bool, Collision> {
+    let (name, id) = match name_and_id(entry) {
+        Some(account) => account,
+        None => return Ok(false),
+    };
+
+    for (existing_name, existing_id) in existing.split(|&b| b == b'\n').filter_map(name_and_id) {
+        match (existing_name == name, existing_id == id) {
+            (true, true) => return Ok(false),
+            (false, false) => (),
+            _ => {
+                return Err(Collision {
+                    kind,
+                    name: String::from_utf8_lossy(name).into_owned(),
+                    id,
+                    existing: String::from_utf8_lossy(existing_name).into_owned(),
+                    existing_id,
+                })
+            }
+        }
+    }
+
+    Ok(true)
+}
+
 fn group_has_id(entry: &[u8], id: &[u8]) -> bool {
     entry.split(|&x| x == b':').nth(2).map_or(false, |field| field == id)
 }
 
-fn group_has_user(entry: &[u8], user: &[u8]) -> bool {
+pub(crate) fn group_has_user(entry: &[u8], user: &[u8]) -> bool {
     entry
         .split(|&x| x == b':')
         .nth(3)
@@ -133,6 +228,11 @@ pub struct UserData<'a> {
     pub secondary_groups: Vec<&'a [u8]>,
 }
 
+impl<'a> UserData<'a> {
+    /// The user ID of the account.
+    pub fn uid(&self) -> Option<u32> { name_and_id(self.passwd).map(|(_, id)| id) }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -151,6 +251,31 @@ mod tests {
         assert!(!group_has_id(b"nogroup:x:65534:", b"1"));
     }
 
+    #[test]
+    fn collisions() {
+        let passwd = b"root:x:0:0:root:/root:/bin/bash\nbob:x:1000:1000::/home/bob:/bin/bash\n";
+        assert_eq!(
+            merge_check("user", passwd, b"alice:x:1001:1001::/home/alice:/bin/bash"),
+            Ok(true)
+        );
+        assert_eq!(merge_check("user", passwd, b"bob:x:1000:1000::/home/bob:/bin/bash"), Ok(false));
+        assert_eq!(
+            merge_check("user", passwd, b"alice:x:1000:1000::/home/alice:/bin/bash"),
+            Err(Collision {
+                kind:        "user",
+                name:        "alice".into(),
+                id:          1000,
+                existing:    "bob".into(),
+                existing_id: 1000,
+            })
+        );
+        assert!(merge_check("group", b"bob:x:1000:\n", b"bob:x:1001:").is_err());
+
+        let ids = ids(passwd);
+        assert_eq!(ids.get(&b"bob"[..]), Some(&1000));
+        assert_eq!(ids.len(), 2);
+    }
+
     #[test]
     fn secondary_groups() {
         assert!(group_has_user(b"random:x:12345:user_x,user_b,user_c", b"user_b"));
@@ -0,0 +1,391 @@
+//! Installs alongside an existing OS, in the space that is freed by shrinking its partition.
+
+use super::{
+    erase::{align, validate_root_filesystem, Layout},
+    EraseOptions, InstallOptionError,
+};
+use crate::{
+    disks::{
+        Bootloader, Disk, DiskExt, PartitionFlag, PartitionInfo, PartitionTable, PartitionType,
+        REMOVE,
+    },
+    external::ntfs_hibernated,
+};
+use disk_types::{BlockDeviceExt, FileSystem, PartitionExt, PartitionTableExt, SectorExt};
+use std::{
+    fmt, io,
+    path::{Path, PathBuf},
+};
+
+/// The space which is left free on a shrunk partition, so that the OS on it may still be used.
+pub const SHRINK_HEADROOM_BYTES: u64 = 5 * 1024 * 1024 * 1024;
+
+/// Existing ESPs which are smaller than this are not shared with the install.
+const MINIMUM_ESP_BYTES: u64 = 400 * 1024 * 1024;
+
+/// What `install_alongside` staged on the disk.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AlongsideSummary {
+    /// The partition which will be shrunk.
+    pub partition:  PathBuf,
+    pub filesystem: FileSystem,
+    /// The size of the partition before it is shrunk, in bytes.
+    pub old_size:   u64,
+    /// The size of the partition after it is shrunk, in bytes.
+    pub new_size:   u64,
+    /// The existing ESP which the install will share.
+    pub reused_esp: Option<PathBuf>,
+    /// The number of partitions which will be created.
+    pub partitions: usize,
+}
+
+impl AlongsideSummary {
+    /// The number of bytes which the partition will be shrunk by.
+    pub fn shrunk_by(&self) -> u64 { self.old_size - self.new_size }
+}
+
+impl fmt::Display for AlongsideSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        const MIB: u64 = 1024 * 1024;
+
+        write!(
+            f,
+            "{} ({:?}) will be shrunk by {} MiB, from {} MiB to {} MiB, to create {} partitions",
+            self.partition.display(),
+            self.filesystem,
+            self.shrunk_by() / MIB,
+            self.old_size / MIB,
+            self.new_size / MIB,
+            self.partitions
+        )?;
+
+        match self.reused_esp {
+            Some(ref esp) => write!(f, ", and the ESP at {} will be shared", esp.display()),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Shrinks the largest partition of the disk with a file system that can be shrunk, and stages
+/// the partitions of the install in `requested_size` bytes of the space which that frees.
+///
+/// The partitions are those of `erase_and_install`, except that an existing ESP is shared with
+/// the install, and the BIOS boot partition is only created on GPT disks which lack one. If
+/// the install is encrypted, its logical volumes may be staged with `erase_and_install_volumes`
+/// once the volume groups of the disks have been initialized.
+///
+/// The partition must keep `SHRINK_HEADROOM_BYTES` free after it is shrunk. Nothing is staged if
+/// its file system is hibernated NTFS, which Windows leaves on shut down with fast startup, or
+/// if an MBR partition table lacks the primary partitions for the install.
+pub fn install_alongside(
+    disk: &mut Disk,
+    requested_size: u64,
+    options: &EraseOptions,
+) -> Result<AlongsideSummary, InstallOptionError> {
+    stage_alongside(disk, requested_size, options, PartitionInfo::sectors_used, |path| {
+        ntfs_hibernated(path)
+    })
+}
+
+fn stage_alongside<U, H>(
+    disk: &mut Disk,
+    requested_size: u64,
+    options: &EraseOptions,
+    sectors_used: U,
+    hibernated: H,
+) -> Result<AlongsideSummary, InstallOptionError>
+where
+    U: Fn(&PartitionInfo) -> io::Result<u64>,
+    H: Fn(&Path) -> io::Result<bool>,
+{
+    validate_root_filesystem(options.filesystem)?;
+
+    let block_size = disk.get_logical_block_size();
+    let mut layout = Layout::new(options, block_size);
+    let msdos = disk.get_partition_table() == Some(PartitionTable::Msdos);
+    let existing = |flag: PartitionFlag| {
+        disk.partitions.iter().position(|p| !p.flag_is_enabled(REMOVE) && p.flags.contains(&flag))
+    };
+
+    let esp = match options.bootloader {
+        Bootloader::Efi => existing(PartitionFlag::PED_PARTITION_ESP).filter(|&id| {
+            let esp = &disk.partitions[id];
+            let fat = esp
+                .filesystem
+                .map_or(false, |fs| fs == FileSystem::Fat16 || fs == FileSystem::Fat32);
+            fat && esp.get_sectors() * block_size >= MINIMUM_ESP_BYTES
+        }),
+        Bootloader::Bios => None,
+    };
+
+    if esp.is_some() {
+        layout.esp = 0;
+    }
+
+    // GRUB is embedded after the MBR on MBR disks.
+    if msdos || existing(PartitionFlag::PED_PARTITION_BIOS_GRUB).is_some() {
+        layout.bios_grub = 0;
+    }
+
+    let requested = align(requested_size / block_size, layout.alignment);
+    if requested < layout.required() {
+        return Err(InstallOptionError::AlongsideTooSmall {
+            requested: requested_size,
+            required:  layout.required() * block_size,
+        });
+    }
+
+    if msdos {
+        let (primary, logical, extended) = disk.get_partition_type_count();
+        let available = 4usize.saturating_sub(primary + (extended || logical != 0) as usize);
+        if available < layout.partitions() {
+            return Err(InstallOptionError::PrimaryLimit {
+                device: disk.get_device_path().to_path_buf(),
+                available,
+                required: layout.partitions(),
+            });
+        }
+    }
+
+    let id = shrink_candidate(disk, esp, msdos).ok_or_else(|| {
+        InstallOptionError::NoShrinkCandidate { device: disk.get_device_path().to_path_buf() }
+    })?;
+
+    let (start, end, old_size) = {
+        let candidate = &disk.partitions[id];
+        let path = candidate.get_device_path();
+        let probe = |why| InstallOptionError::ShrinkProbe { partition: path.to_path_buf(), why };
+
+        if candidate.filesystem == Some(FileSystem::Ntfs) && hibernated(path).map_err(probe)? {
+            return Err(InstallOptionError::Hibernated { partition: path.to_path_buf() });
+        }
+
+        // The new partitions end where the candidate does, and it keeps what it uses.
+        let used = sectors_used(candidate).map_err(probe)?;
+        let headroom = SHRINK_HEADROOM_BYTES / block_size;
+        let end = layout.align_down(candidate.end_sector + 1);
+        let kept = candidate.start_sector + used + headroom + layout.alignment;
+        let reclaimable = layout.align_down(end.saturating_sub(kept));
+        if reclaimable < requested {
+            return Err(InstallOptionError::ShrinkInsufficient {
+                partition: path.to_path_buf(),
+                available: reclaimable * block_size,
+                required:  requested * block_size,
+            });
+        }
+
+        (end - requested, end, candidate.get_sectors())
+    };
+
+    // Nothing is changed on the disk unless all of the install could be staged.
+    let mut staged = disk.clone();
+    let (partition, filesystem, new_size) = {
+        let candidate = &mut staged.partitions[id];
+        candidate.shrink_to(start - 1 - candidate.start_sector)?;
+        let filesystem = candidate.filesystem.expect("shrink candidate lacks a file system");
+        (candidate.get_device_path().to_path_buf(), filesystem, candidate.get_sectors())
+    };
+
+    let reused_esp = esp.map(|id| {
+        let esp = &mut staged.partitions[id];
+        esp.set_mount("/boot/efi".into());
+        esp.get_device_path().to_path_buf()
+    });
+
+    layout.stage(&mut staged, start, end)?;
+    *disk = staged;
+
+    Ok(AlongsideSummary {
+        partition,
+        filesystem,
+        old_size: old_size * block_size,
+        new_size: new_size * block_size,
+        reused_esp,
+        partitions: layout.partitions(),
+    })
+}
+
+/// The largest partition with a file system that can be shrunk, which isn't mounted. Logical
+/// partitions of MBR disks are skipped, as the primary partitions of the install can't be
+/// created within the extended partition.
+fn shrink_candidate(disk: &Disk, esp: Option<usize>, msdos: bool) -> Option<usize> {
+    disk.partitions
+        .iter()
+        .enumerate()
+        .filter(|&(id, part)| {
+            Some(id) != esp
+                && !(msdos && part.part_type == PartitionType::Logical)
+                && !part.flag_is_enabled(REMOVE)
+                && !part.will_format()
+                && part.mount_point.is_none()
+                && !part.flags.contains(&PartitionFlag::PED_PARTITION_ESP)
+                && match part.filesystem {
+                    Some(FileSystem::Btrfs)
+                    | Some(FileSystem::Ext2)
+                    | Some(FileSystem::Ext3)
+                    | Some(FileSystem::Ext4)
+                    | Some(FileSystem::Fat16)
+                    | Some(FileSystem::Fat32)
+                    | Some(FileSystem::Ntfs) => true,
+                    _ => false,
+                }
+        })
+        .max_by_key(|&(_, part)| part.get_sectors())
+        .map(|(id, _)| id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        auto::SwapPolicy,
+        disks::{PartitionBuilder, SOURCE},
+    };
+    use std::io;
+
+    const GIB: u64 = 1024 * 1024 * 1024;
+
+    fn partition(number: i32, start: u64, end: u64, fs: FileSystem) -> PartitionInfo {
+        let mut partition = PartitionBuilder::new(start, end, fs).build();
+        partition.bitflags = SOURCE;
+        partition.number = number;
+        partition.ordering = number;
+        partition.device_path = format!("/dev/sdz{}", number).into();
+        partition.name = None;
+        partition
+    }
+
+    /// A 256 GiB disk with an ESP, and a Windows partition of 200 GiB.
+    fn windows(table: PartitionTable) -> Disk {
+        let mut esp = partition(1, 2048, 1_050_624, FileSystem::Fat32);
+        esp.flags.push(PartitionFlag::PED_PARTITION_ESP);
+
+        Disk {
+            mklabel:     false,
+            model_name:  "Test Disk".into(),
+            serial:      "Test Disk 123".into(),
+            device_path: "/dev/sdz".into(),
+            file_system: None,
+            mount_point: None,
+            size:        256 * GIB / 512,
+            device_type: "TEST".into(),
+            table_type:  Some(table),
+            read_only:   false,
+            partitions:  vec![
+                esp,
+                partition(2, 1_050_624, 1_050_624 + 200 * GIB / 512, FileSystem::Ntfs),
+            ],
+        }
+    }
+
+    fn options(bootloader: Bootloader) -> EraseOptions {
+        EraseOptions { bootloader, ..EraseOptions::new(FileSystem::Ext4) }
+    }
+
+    /// Windows uses 100 GiB of its partition, and isn't hibernated.
+    fn stage(
+        disk: &mut Disk,
+        requested: u64,
+        options: &EraseOptions,
+        hibernated: bool,
+    ) -> Result<AlongsideSummary, InstallOptionError> {
+        stage_alongside(disk, requested, options, |_| Ok(100 * GIB / 512), |_| Ok(hibernated))
+    }
+
+    #[test]
+    fn alongside() {
+        let mut disk = windows(PartitionTable::Gpt);
+        let windows_end = disk.partitions[1].end_sector;
+        let summary = stage(&mut disk, 64 * GIB, &options(Bootloader::Efi), false).unwrap();
+
+        assert_eq!(summary.partition, Path::new("/dev/sdz2"));
+        assert_eq!(summary.filesystem, FileSystem::Ntfs);
+        assert_eq!(summary.reused_esp.as_ref().map(PathBuf::as_path), Some(Path::new("/dev/sdz1")));
+        assert_eq!(summary.partitions, 2);
+        assert!(summary.shrunk_by() >= 64 * GIB && summary.shrunk_by() - 64 * GIB <= 1024 * 1024);
+
+        // The ESP is shared, and the root and swap take the end of the Windows partition.
+        let partitions = &disk.partitions;
+        assert_eq!(partitions.len(), 4);
+        assert_eq!(
+            partitions[0].target.as_ref().map(PathBuf::as_path),
+            Some(Path::new("/boot/efi"))
+        );
+        assert!(partitions[1].end_sector < partitions[2].start_sector);
+        assert_eq!(partitions[2].target.as_ref().map(PathBuf::as_path), Some(Path::new("/")));
+        assert_eq!(partitions[2].get_sectors() + 1, 60 * GIB / 512);
+        assert_eq!(partitions[3].filesystem, Some(FileSystem::Swap));
+        assert_eq!(partitions[3].end_sector, windows_end);
+        assert_eq!(partitions[2].start_sector + 64 * GIB / 512, partitions[3].end_sector + 1);
+    }
+
+    #[test]
+    fn alongside_refused() {
+        let options = options(Bootloader::Efi);
+
+        // Windows must be fully shut down.
+        let mut disk = windows(PartitionTable::Gpt);
+        match stage(&mut disk, 64 * GIB, &options, true) {
+            Err(InstallOptionError::Hibernated { partition }) => {
+                assert_eq!(partition, Path::new("/dev/sdz2"))
+            }
+            result => panic!("expected hibernation to be refused: {:?}", result),
+        }
+
+        // And keep the space that it uses, with some to spare.
+        match stage(&mut disk, 96 * GIB, &options, false) {
+            Err(InstallOptionError::ShrinkInsufficient { available, required, .. }) => {
+                assert_eq!(required, 96 * GIB);
+                assert_eq!(available, 95 * GIB - 1024 * 1024);
+            }
+            result => panic!("expected too little space to be refused: {:?}", result),
+        }
+
+        // The install can't be smaller than its partitions require.
+        match stage(&mut disk, 8 * GIB, &options, false) {
+            Err(InstallOptionError::AlongsideTooSmall { requested, .. }) => {
+                assert_eq!(requested, 8 * GIB)
+            }
+            result => panic!("expected the install to be too small: {:?}", result),
+        }
+
+        assert_eq!(disk.partitions, windows(PartitionTable::Gpt).partitions);
+
+        // An MBR partition table with three primary partitions has room for one more.
+        let mut disk = windows(PartitionTable::Msdos);
+        let recovery = partition(3, 420_481_024, 421_505_024, FileSystem::Ntfs);
+        disk.partitions.push(recovery);
+        assert_eq!(disk.partitions[2].part_type, PartitionType::Primary);
+        match stage(&mut disk, 64 * GIB, &options, false) {
+            Err(InstallOptionError::PrimaryLimit { available: 1, required: 2, .. }) => (),
+            result => panic!("expected the primary partition limit to be hit: {:?}", result),
+        }
+
+        // Which is enough for an install without swap.
+        let options = EraseOptions { swap: SwapPolicy::None, ..options };
+        assert!(stage(&mut disk, 64 * GIB, &options, false).is_ok());
+
+        // A logical partition can't be shrunk for primary partitions to take its space.
+        let mut disk = windows(PartitionTable::Msdos);
+        disk.partitions[1].part_type = PartitionType::Logical;
+        match stage(&mut disk, 64 * GIB, &options, false) {
+            Err(InstallOptionError::NoShrinkCandidate { .. }) => (),
+            result => panic!("expected the logical partition to be skipped: {:?}", result),
+        }
+
+        // Nothing can be shrunk on a disk of free space.
+        let mut disk = windows(PartitionTable::Gpt);
+        disk.partitions.truncate(1);
+        let result = stage_alongside(
+            &mut disk,
+            64 * GIB,
+            &options,
+            |_| Ok(0),
+            |_| Err(io::Error::new(io::ErrorKind::Other, "unreachable")),
+        );
+        match result {
+            Err(InstallOptionError::NoShrinkCandidate { .. }) => (),
+            result => panic!("expected no shrink candidate: {:?}", result),
+        }
+    }
+}
@@ -0,0 +1,424 @@
+//! The default layout of a disk which is erased to be installed to.
+
+use super::InstallOptionError;
+use crate::{
+    disks::{
+        Bootloader, Disk, DiskExt, Disks, LogicalDevice, LvmEncryption, PartitionBuilder,
+        PartitionRole, PartitionTable,
+    },
+    DEFAULT_ESP_SECTORS, DEFAULT_RECOVER_SECTORS, DEFAULT_SWAP_SECTORS,
+};
+use disk_types::{BlockDeviceExt, FileSystem, Sector, SectorExt};
+
+/// Partitions are aligned to, and sized in multiples of, a mebibyte.
+const ALIGNMENT: u64 = 1024 * 1024;
+
+/// The least space that the root file system may be given, in bytes.
+pub const MINIMUM_ROOT_BYTES: u64 = 8 * 1024 * 1024 * 1024;
+
+/// How much swap to create when erasing a disk to install to it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SwapPolicy {
+    /// No swap will be created.
+    None,
+    /// Swap the size of `DEFAULT_SWAP_SECTORS`.
+    Default,
+    /// Swap of the given size, in bytes.
+    Bytes(u64),
+}
+
+impl SwapPolicy {
+    /// The size of the swap, in bytes.
+    pub fn bytes(self) -> u64 {
+        match self {
+            SwapPolicy::None => 0,
+            SwapPolicy::Default => DEFAULT_SWAP_SECTORS * 512,
+            SwapPolicy::Bytes(bytes) => bytes,
+        }
+    }
+}
+
+/// Options for the layout that `erase_and_install` stages.
+#[derive(Debug, Clone)]
+pub struct EraseOptions {
+    /// Places the root and swap on logical volumes, within a LUKS container.
+    pub encrypt:      Option<LvmEncryption>,
+    /// The volume group to create, if the install is encrypted.
+    pub volume_group: String,
+    pub swap:         SwapPolicy,
+    /// The file system of the root.
+    pub filesystem:   FileSystem,
+    /// Creates a recovery partition after the ESP.
+    pub recovery:     bool,
+    /// The boot partitions are created for this bootloader.
+    pub bootloader:   Bootloader,
+}
+
+impl EraseOptions {
+    /// Options for an unencrypted install with the default swap, and no recovery partition,
+    /// for the bootloader of this system.
+    pub fn new(filesystem: FileSystem) -> EraseOptions {
+        EraseOptions {
+            encrypt: None,
+            volume_group: "data".into(),
+            swap: SwapPolicy::Default,
+            filesystem,
+            recovery: false,
+            bootloader: Bootloader::detect(),
+        }
+    }
+}
+
+/// Stages a new GPT label on the disk, with the default layout of an install.
+///
+/// The partitions are, in order:
+///
+/// - For EFI, an ESP mounted at `/boot/efi`. For BIOS, a 1 MiB BIOS boot partition for GRUB, and an
+///   ext4 `/boot` partition if the install is encrypted.
+/// - A recovery partition, if requested.
+/// - The root, or the LUKS container of the volume group if encrypted.
+/// - A swap partition at the end of the disk, if unencrypted. Encrypted installs place their swap
+///   on a logical volume instead, which `erase_and_install_volumes` stages.
+///
+/// Nothing is staged if the root would be smaller than `MINIMUM_ROOT_BYTES`.
+pub fn erase_and_install(
+    disk: &mut Disk,
+    options: &EraseOptions,
+) -> Result<(), InstallOptionError> {
+    validate_root_filesystem(options.filesystem)?;
+
+    let block_size = disk.get_logical_block_size();
+    let layout = Layout::new(options, block_size);
+    let start = disk.get_sector(Sector::Start);
+    let end = layout.align_down(disk.get_sector(Sector::End));
+    let required = start + layout.required();
+    if end < required {
+        return Err(InstallOptionError::DiskTooSmall {
+            device:   disk.get_device_path().to_path_buf(),
+            required: (required + disk.get_sectors() - end) * block_size,
+        });
+    }
+
+    disk.mklabel(PartitionTable::Gpt)?;
+    layout.stage(disk, start, end)
+}
+
+/// The sizes of the partitions of an install, in sectors of its disk. Partitions with a size
+/// of zero are not created.
+pub(super) struct Layout<'a> {
+    pub(super) options:   &'a EraseOptions,
+    pub(super) alignment: u64,
+    pub(super) esp:       u64,
+    pub(super) bios_grub: u64,
+    pub(super) boot:      u64,
+    pub(super) recovery:  u64,
+    pub(super) swap:      u64,
+    pub(super) root:      u64,
+}
+
+impl<'a> Layout<'a> {
+    pub(super) fn new(options: &'a EraseOptions, block_size: u64) -> Layout<'a> {
+        let alignment = ALIGNMENT / block_size;
+        let sectors = |bytes: u64| align(bytes / block_size, alignment);
+        let efi = options.bootloader == Bootloader::Efi;
+
+        Layout {
+            options,
+            alignment,
+            esp: if efi { sectors(DEFAULT_ESP_SECTORS * 512) } else { 0 },
+            bios_grub: if efi { 0 } else { alignment },
+            // GRUB can't read the LUKS container, so the kernels are kept outside of it.
+            boot: if !efi && options.encrypt.is_some() {
+                sectors(DEFAULT_ESP_SECTORS * 512)
+            } else {
+                0
+            },
+            recovery: if options.recovery { sectors(DEFAULT_RECOVER_SECTORS * 512) } else { 0 },
+            swap: sectors(options.swap.bytes()),
+            root: sectors(MINIMUM_ROOT_BYTES),
+        }
+    }
+
+    pub(super) fn align_down(&self, sector: u64) -> u64 { sector / self.alignment * self.alignment }
+
+    /// The sectors which the install requires, including its swap.
+    pub(super) fn required(&self) -> u64 {
+        self.esp + self.bios_grub + self.boot + self.recovery + self.swap + self.root
+    }
+
+    /// The number of partitions which will be created.
+    pub(super) fn partitions(&self) -> usize {
+        let swap = self.swap != 0 && self.options.encrypt.is_none();
+        let boot = [self.esp, self.bios_grub, self.boot, self.recovery];
+        boot.iter().filter(|&&sectors| sectors != 0).count() + 1 + swap as usize
+    }
+
+    /// Adds the partitions to the disk, from the `start` sector to the `end`.
+    pub(super) fn stage(
+        &self,
+        disk: &mut Disk,
+        mut start: u64,
+        end: u64,
+    ) -> Result<(), InstallOptionError> {
+        if self.esp != 0 {
+            disk.add_partition(
+                PartitionBuilder::new(start, start + self.esp, FileSystem::Fat32)
+                    .role(PartitionRole::Esp)
+                    .mount("/boot/efi".into()),
+            )?;
+            start += self.esp;
+        }
+
+        if self.bios_grub != 0 {
+            disk.add_partition(
+                PartitionBuilder::new(start, start + self.bios_grub, None)
+                    .role(PartitionRole::BiosGrub),
+            )?;
+            start += self.bios_grub;
+        }
+
+        if self.boot != 0 {
+            disk.add_partition(
+                PartitionBuilder::new(start, start + self.boot, FileSystem::Ext4)
+                    .mount("/boot".into()),
+            )?;
+            start += self.boot;
+        }
+
+        if self.recovery != 0 {
+            disk.add_partition(
+                PartitionBuilder::new(start, start + self.recovery, FileSystem::Fat32)
+                    .name("recovery".into())
+                    .mount("/recovery".into()),
+            )?;
+            start += self.recovery;
+        }
+
+        let options = self.options;
+        match options.encrypt {
+            Some(ref encryption) => disk.add_partition(
+                PartitionBuilder::new(start, end, FileSystem::Lvm)
+                    .logical_volume(options.volume_group.clone(), Some(encryption.clone())),
+            )?,
+            None => {
+                let root_end = end - self.swap;
+                disk.add_partition(
+                    PartitionBuilder::new(start, root_end, options.filesystem).mount("/".into()),
+                )?;
+
+                if self.swap != 0 {
+                    disk.add_partition(PartitionBuilder::new(root_end, end, FileSystem::Swap))?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Stages the logical volumes of an encrypted install on its volume group: a `swap` volume,
+/// if requested, followed by a `root` volume which claims the remaining space.
+pub fn erase_and_install_volumes(
+    device: &mut LogicalDevice,
+    options: &EraseOptions,
+) -> Result<(), InstallOptionError> {
+    validate_root_filesystem(options.filesystem)?;
+
+    let swap = options.swap.bytes() / device.get_logical_block_size();
+    if swap != 0 {
+        device
+            .add_partition(PartitionBuilder::new(0, swap, FileSystem::Swap).name("swap".into()))?;
+    }
+
+    device.add_partition(
+        PartitionBuilder::new(0, 1, options.filesystem)
+            .name("root".into())
+            .mount("/".into())
+            .remainder(),
+    )?;
+
+    Ok(())
+}
+
+/// Adds the disk to `disks` with the layout of `erase_and_install`, and stages the logical
+/// volumes of its volume group if the install is encrypted.
+pub fn erase_disk(
+    disks: &mut Disks,
+    mut disk: Disk,
+    options: &EraseOptions,
+) -> Result<(), InstallOptionError> {
+    erase_and_install(&mut disk, options)?;
+    disks.add(disk);
+    disks.initialize_volume_groups()?;
+
+    if options.encrypt.is_some() {
+        let vg = &options.volume_group;
+        let device = disks
+            .get_logical_device_mut(vg)
+            .ok_or_else(|| InstallOptionError::LogicalDeviceNotFound { vg: vg.clone() })?;
+
+        erase_and_install_volumes(device, options)?;
+    }
+
+    Ok(())
+}
+
+pub(super) fn validate_root_filesystem(fs: FileSystem) -> Result<(), InstallOptionError> {
+    match fs {
+        FileSystem::Btrfs
+        | FileSystem::Ext2
+        | FileSystem::Ext3
+        | FileSystem::Ext4
+        | FileSystem::F2fs
+        | FileSystem::Xfs => Ok(()),
+        fs => Err(InstallOptionError::RootFilesystem { fs }),
+    }
+}
+
+pub(super) fn align(sectors: u64, alignment: u64) -> u64 {
+    (sectors + alignment - 1) / alignment * alignment
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disks::PartitionFlag;
+    use disk_types::PartitionExt;
+
+    const GIB: u64 = 1024 * 1024 * 1024;
+
+    fn disk(bytes: u64) -> Disk {
+        Disk {
+            mklabel:     false,
+            model_name:  "Test Disk".into(),
+            serial:      "Test Disk 123".into(),
+            device_path: "/dev/sdz".into(),
+            file_system: None,
+            mount_point: None,
+            size:        bytes / 512,
+            device_type: "TEST".into(),
+            table_type:  Some(PartitionTable::Msdos),
+            read_only:   false,
+            partitions:  Vec::new(),
+        }
+    }
+
+    fn layout(disk: &Disk) -> Vec<(u64, u64, Option<FileSystem>, Option<&str>)> {
+        disk.partitions
+            .iter()
+            .map(|p| {
+                let target = p.target.as_ref().and_then(|t| t.to_str());
+                (p.start_sector, p.end_sector, p.filesystem, target)
+            })
+            .collect()
+    }
+
+    fn options(bootloader: Bootloader) -> EraseOptions {
+        EraseOptions { bootloader, ..EraseOptions::new(FileSystem::Ext4) }
+    }
+
+    fn encryption() -> LvmEncryption {
+        LvmEncryption::new("cryptdata".into(), Some("passphrase".into()), None)
+    }
+
+    #[test]
+    fn erase_64gib() {
+        let mut device = disk(64 * GIB);
+        erase_and_install(&mut device, &options(Bootloader::Efi)).unwrap();
+
+        assert!(device.mklabel);
+        assert_eq!(device.table_type, Some(PartitionTable::Gpt));
+        assert_eq!(
+            layout(&device),
+            vec![
+                (4096, 2_101_247, Some(FileSystem::Fat32), Some("/boot/efi")),
+                (2_101_248, 125_825_023, Some(FileSystem::Ext4), Some("/")),
+                (125_825_024, 134_213_631, Some(FileSystem::Swap), None),
+            ]
+        );
+        assert_eq!(device.partitions[0].flags, vec![PartitionFlag::PED_PARTITION_ESP]);
+
+        // BIOS installs embed GRUB in a partition of their own, and need a /boot to
+        // read the kernels from when encrypted.
+        let mut device = disk(64 * GIB);
+        let options = EraseOptions { encrypt: Some(encryption()), ..options(Bootloader::Bios) };
+        erase_and_install(&mut device, &options).unwrap();
+
+        assert_eq!(
+            layout(&device),
+            vec![
+                (4096, 6143, None, None),
+                (6144, 2_103_295, Some(FileSystem::Ext4), Some("/boot")),
+                (2_103_296, 134_213_631, Some(FileSystem::Luks), None),
+            ]
+        );
+        assert_eq!(device.partitions[0].flags, vec![PartitionFlag::PED_PARTITION_BIOS_GRUB]);
+    }
+
+    #[test]
+    fn erase_1tib() {
+        let mut device = disk(1024 * GIB);
+        let options = EraseOptions {
+            encrypt: Some(encryption()),
+            recovery: true,
+            ..options(Bootloader::Efi)
+        };
+        erase_and_install(&mut device, &options).unwrap();
+
+        assert_eq!(
+            layout(&device),
+            vec![
+                (4096, 2_101_247, Some(FileSystem::Fat32), Some("/boot/efi")),
+                (2_101_248, 10_489_855, Some(FileSystem::Fat32), Some("/recovery")),
+                (10_489_856, 2_147_479_551, Some(FileSystem::Luks), None),
+            ]
+        );
+
+        let container = &device.partitions[2];
+        assert_eq!(container.volume_group, Some(("data".into(), Some(encryption()))));
+
+        let sectors = container.get_sectors();
+        let mut volumes =
+            LogicalDevice::new("data".into(), Some(encryption()), sectors, 512, false);
+        erase_and_install_volumes(&mut volumes, &options).unwrap();
+
+        let swap = volumes.get_partition("swap").unwrap();
+        assert_eq!((swap.start_sector, swap.end_sector), (0, 8_388_607));
+        assert_eq!(swap.filesystem, Some(FileSystem::Swap));
+
+        let root = volumes.get_partition("root").unwrap();
+        assert_eq!((root.start_sector, root.end_sector), (8_388_608, 2_136_985_600));
+        assert_eq!(root.target.as_ref().and_then(|t| t.to_str()), Some("/"));
+        assert!(volumes.validate().is_ok());
+    }
+
+    #[test]
+    fn erase_invalid() {
+        let mut device = disk(16 * GIB);
+
+        // The root, ESP, and swap don't fit on 16 GiB with a recovery partition and
+        // 8 GiB of swap.
+        let options = EraseOptions {
+            recovery: true,
+            swap: SwapPolicy::Bytes(8 * GIB),
+            ..options(Bootloader::Efi)
+        };
+        match erase_and_install(&mut device, &options) {
+            Err(InstallOptionError::DiskTooSmall { required, .. }) => {
+                assert!(required > 16 * GIB)
+            }
+            result => panic!("expected the disk to be too small: {:?}", result),
+        }
+        assert!(!device.mklabel);
+
+        let options = EraseOptions { swap: SwapPolicy::None, ..options };
+        assert!(erase_and_install(&mut device, &options).is_ok());
+        assert_eq!(device.partitions.len(), 3);
+
+        let options = EraseOptions::new(FileSystem::Ntfs);
+        match erase_and_install(&mut device, &options) {
+            Err(InstallOptionError::RootFilesystem { fs: FileSystem::Ntfs }) => (),
+            result => panic!("expected NTFS to be rejected: {:?}", result),
+        }
+    }
+}
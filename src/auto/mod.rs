@@ -1,14 +1,23 @@
 //! Automatic installation options, and actions executed by them.
 
 mod accounts;
+mod alongside;
+mod erase;
 mod options;
 mod retain;
 
 pub(crate) use self::{
     accounts::{AccountFiles, UserData},
+    options::is_live_disk,
     retain::*,
 };
-pub use self::{options::*, retain::delete_old_install};
+pub use self::{
+    accounts::{Collision, Collisions},
+    alongside::*,
+    erase::*,
+    options::*,
+    retain::{delete_old_install, DEFAULT_PRESERVE},
+};
 
 use disk_types::FileSystem;
 use std::{
@@ -44,6 +53,10 @@ pub enum ReinstallError {
     InvalidPartitionConfiguration { why: io::Error },
     #[fail(display = "install media at {:?} was not found", path)]
     MissingSquashfs { path: PathBuf },
+    #[fail(display = "retained path {:?} is not within the root", path)]
+    InvalidPreserve { path: PathBuf },
+    #[fail(display = "accounts of the old install collide with the new install: {}", collisions)]
+    AccountCollisions { collisions: Collisions },
 }
 
 impl From<io::Error> for ReinstallError {
@@ -163,11 +163,7 @@ impl InstallOptions {
                     })
                 }
 
-                let skip = !Path::new("/cdrom/recovery.conf").exists()
-                    && (device.contains_mount("/", &disks)
-                        || device.contains_mount("/cdrom", &disks));
-
-                if skip {
+                if is_live_disk(device, disks) {
                     info!("install options: skipping options on {:?}", device.get_device_path());
                     continue;
                 }
@@ -210,6 +206,13 @@ impl InstallOptions {
     }
 }
 
+/// Whether the disk holds the live system, which is not offered as a target, unless the live
+/// system is the recovery partition of the install.
+pub(crate) fn is_live_disk(device: &Disk, disks: &Disks) -> bool {
+    !Path::new("/cdrom/recovery.conf").exists()
+        && (device.contains_mount("/", disks) || device.contains_mount("/cdrom", disks))
+}
+
 #[derive(Debug, Fail)]
 pub enum InstallOptionError {
     #[fail(display = "partition ID ({:?}) was not found", id)]
@@ -236,6 +239,36 @@ pub enum InstallOptionError {
     ProcMounts { why: io::Error },
     #[fail(display = "could not remount /cdrom as rewriteable: {}", _0)]
     RemountCdrom(io::Error),
+    #[fail(display = "{:?} is too small to install to: {} bytes are required", device, required)]
+    DiskTooSmall { device: PathBuf, required: u64 },
+    #[fail(display = "{:?} can't be used as the file system of the root", fs)]
+    RootFilesystem { fs: FileSystem },
+    #[fail(
+        display = "{} bytes were requested for the install, which requires {}",
+        requested, required
+    )]
+    AlongsideTooSmall { requested: u64, required: u64 },
+    #[fail(
+        display = "the MBR partition table of {:?} has room for {} more primary partitions, but \
+                   the install requires {}",
+        device, available, required
+    )]
+    PrimaryLimit { device: PathBuf, available: usize, required: usize },
+    #[fail(display = "no partition on {:?} has a file system which can be shrunk", device)]
+    NoShrinkCandidate { device: PathBuf },
+    #[fail(display = "unable to probe {:?} before shrinking it: {}", partition, why)]
+    ShrinkProbe { partition: PathBuf, why: io::Error },
+    #[fail(
+        display = "{:?} is hibernated, as Windows does on shut down with fast startup: it must be \
+                   fully shut down before it can be shrunk",
+        partition
+    )]
+    Hibernated { partition: PathBuf },
+    #[fail(
+        display = "{:?} can be shrunk by {} bytes, but the install requires {}",
+        partition, available, required
+    )]
+    ShrinkInsufficient { partition: PathBuf, available: u64, required: u64 },
 }
 
 impl From<DiskError> for InstallOptionError {
@@ -4,25 +4,33 @@ use crate::bootloader::Bootloader;
 use disk_types::FileSystem;
 use crate::disks::Disks;
 
-use super::{mount_and_then, AccountFiles, ReinstallError, UserData};
+use super::{
+    accounts::{group_has_user, merge_check, Collisions},
+    mount_and_then, AccountFiles, ReinstallError, UserData,
+};
 
 use crate::misc;
 use std::{
-    ffi::{OsStr, OsString},
+    collections::HashMap,
+    ffi::{CString, OsStr, OsString},
     fs::{self, File, OpenOptions, Permissions},
     io::{self, Read, Seek, SeekFrom, Write},
     os::unix::{
         ffi::OsStrExt,
-        fs::{symlink, PermissionsExt},
+        fs::{symlink, MetadataExt, PermissionsExt},
     },
-    path::{Path, PathBuf},
+    path::{Component, Path, PathBuf},
 };
 
+/// Where the files of the retained paths are copied to on the old root, until they are
+/// restored to the new install.
+const STASH: &str = "distinst.preserved";
+
 /// Removes all files in the chroot at `/`, except for `/home`.
 pub fn remove_root(root_path: &Path, root_fs: FileSystem) -> Result<(), ReinstallError> {
     info!("removing all files except /home. This may take a while...");
     mount_and_then(root_path, root_fs, |base| {
-        read_and_exclude(base, &[OsStr::new("home")], |entry| {
+        read_and_exclude(base, &[OsStr::new("home"), OsStr::new(STASH)], |entry| {
             if entry.is_dir() {
                 fs::remove_dir_all(entry)?;
             } else {
@@ -49,7 +57,7 @@ pub fn move_root(root_path: &Path, root_fs: FileSystem) -> Result<(), ReinstallE
         fs::create_dir(&old_root)?;
 
         // Migrate the current root system to the old root path.
-        let exclude = &[OsStr::new("home"), OsStr::new("linux.old")];
+        let exclude = &[OsStr::new("home"), OsStr::new("linux.old"), OsStr::new(STASH)];
         read_and_exclude(base, exclude, |entry| {
             let filename = entry.file_name().expect("root entry without file name");
             fs::rename(entry, base.join("linux.old").join(filename))?;
@@ -63,7 +71,8 @@ pub fn recover_root(root_path: &Path, root_fs: FileSystem) -> Result<(), Reinsta
     info!("attempting to restore the original system");
     mount_and_then(root_path, root_fs, |base| {
         // Remove files installed by the installer.
-        read_and_exclude(base, &[OsStr::new("home"), OsStr::new("linux.old")], |entry| {
+        let exclude = &[OsStr::new("home"), OsStr::new("linux.old"), OsStr::new(STASH)];
+        read_and_exclude(base, exclude, |entry| {
             if entry.is_dir() {
                 fs::remove_dir_all(entry)?;
             } else {
@@ -145,32 +154,203 @@ fn read_and_exclude<F: FnMut(&Path) -> Result<(), ReinstallError>>(
     Ok(())
 }
 
+/// Paths of the old root which are retained by a refresh, when none are configured.
+pub const DEFAULT_PRESERVE: &[&str] = &[
+    "etc/NetworkManager/system-connections",
+    "var/lib/AccountsService/icons",
+    "var/lib/AccountsService/users",
+    "var/lib/bluetooth",
+];
+
+/// A file, directory, or symlink which was copied out of the old root.
+pub struct Preserved {
+    /// The path of the entry, relative to the root.
+    pub path: PathBuf,
+    pub kind: PreservedKind,
+    pub mode: u32,
+    pub uid:  u32,
+    pub gid:  u32,
+}
+
+pub enum PreservedKind {
+    Directory,
+    /// The contents of the file are copied to the same path within the stash.
+    File,
+    Symlink(PathBuf),
+}
+
+impl Preserved {
+    /// Replaces whatever the new install has at this path with the preserved copy, which is
+    /// copied from the `stash`. Ownership is given to the accounts of the same names in the
+    /// new install, as their IDs may have changed.
+    fn restore(
+        &self,
+        base: &Path,
+        stash: &Path,
+        users: &HashMap<u32, u32>,
+        groups: &HashMap<u32, u32>,
+    ) -> io::Result<()> {
+        let path = base.join(&self.path);
+        let existing = fs::symlink_metadata(&path).ok();
+
+        match self.kind {
+            PreservedKind::Directory => {
+                // The directory is merged with the contents of the new install.
+                if existing.map_or(false, |meta| !meta.is_dir()) {
+                    fs::remove_file(&path)?;
+                }
+
+                fs::create_dir_all(&path)?;
+            }
+            PreservedKind::File => {
+                remove_existing(&path, existing)?;
+                fs::copy(stash.join(&self.path), &path)?;
+            }
+            PreservedKind::Symlink(ref target) => {
+                remove_existing(&path, existing)?;
+                symlink(target, &path)?;
+            }
+        }
+
+        let uid = users.get(&self.uid).cloned().unwrap_or(self.uid);
+        let gid = groups.get(&self.gid).cloned().unwrap_or(self.gid);
+        lchown(&path, uid, gid)?;
+
+        // Changing the owner clears the setuid and setgid bits, so permissions are set after.
+        match self.kind {
+            PreservedKind::Symlink(_) => Ok(()),
+            _ => fs::set_permissions(&path, Permissions::from_mode(self.mode)),
+        }
+    }
+}
+
+/// Copies the `paths` out of the old root at `base`, along with everything within them. The
+/// contents of files are copied into the `stash`, rather than being held in memory.
+fn preserve_paths(
+    base: &Path,
+    stash: &Path,
+    paths: &[PathBuf],
+) -> Result<Vec<Preserved>, ReinstallError> {
+    let mut preserved = Vec::new();
+
+    for path in paths {
+        let relative = path.strip_prefix("/").unwrap_or(path);
+        let within_root = relative.components().all(|c| matches!(c, Component::Normal(_)));
+        if relative.as_os_str().is_empty() || !within_root {
+            return Err(ReinstallError::InvalidPreserve { path: path.clone() });
+        }
+
+        if fs::symlink_metadata(base.join(relative)).is_err() {
+            info!("/{} is not in the old root, so it will not be retained", relative.display());
+            continue;
+        }
+
+        info!("retaining /{}", relative.display());
+        preserve_path(base, stash, relative, &mut preserved)?;
+    }
+
+    Ok(preserved)
+}
+
+fn preserve_path(
+    base: &Path,
+    stash: &Path,
+    relative: &Path,
+    preserved: &mut Vec<Preserved>,
+) -> io::Result<()> {
+    let path = base.join(relative);
+    let meta = fs::symlink_metadata(&path)?;
+    let file_type = meta.file_type();
+
+    let kind = if file_type.is_symlink() {
+        PreservedKind::Symlink(fs::read_link(&path)?)
+    } else if file_type.is_dir() {
+        PreservedKind::Directory
+    } else if file_type.is_file() {
+        let copy = stash.join(relative);
+        copy.parent().map_or(Ok(()), fs::create_dir_all)?;
+        fs::copy(&path, &copy)?;
+        PreservedKind::File
+    } else {
+        warn!("not retaining {}, as it is not a file, directory, or symlink", path.display());
+        return Ok(());
+    };
+
+    preserved.push(Preserved {
+        path: relative.to_path_buf(),
+        kind,
+        mode: meta.mode() & 0o7777,
+        uid: meta.uid(),
+        gid: meta.gid(),
+    });
+
+    if file_type.is_dir() {
+        for entry in path.read_dir()? {
+            preserve_path(base, stash, &relative.join(entry?.file_name()), preserved)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Removes a file or directory from the new install, which a preserved path replaces.
+fn remove_existing(path: &Path, existing: Option<fs::Metadata>) -> io::Result<()> {
+    match existing {
+        Some(ref meta) if meta.is_dir() => fs::remove_dir_all(path),
+        Some(_) => fs::remove_file(path),
+        None => path.parent().map_or(Ok(()), fs::create_dir_all),
+    }
+}
+
+fn lchown(path: &Path, uid: u32, gid: u32) -> io::Result<()> {
+    let cpath = CString::new(path.as_os_str().as_bytes())
+        .map_err(|why| io::Error::new(io::ErrorKind::InvalidInput, why))?;
+
+    if unsafe { libc::lchown(cpath.as_ptr(), uid, gid) } == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// Lists the home directories of the users of the old install.
+fn home_users(home: &Path) -> Result<Vec<OsString>, ReinstallError> {
+    let users = home
+        .read_dir()?
+        .filter_map(|entry| entry.ok())
+        .map(|name| name.file_name())
+        .inspect(|name| info!("found user account: {}", name.to_string_lossy()))
+        .collect();
+
+    Ok(users)
+}
+
 pub struct Backup<'a> {
     pub users:     Vec<UserData<'a>>,
     pub localtime: Option<PathBuf>,
     pub timezone:  Option<Vec<u8>>,
-    pub networks:  Option<Vec<(OsString, Vec<u8>)>>,
+    pub preserved: Vec<Preserved>,
+    accounts:      &'a AccountFiles,
+    /// The old root, which the contents of the preserved files are stashed on.
+    root:          (PathBuf, FileSystem),
 }
 
 impl<'a> Backup<'a> {
-    /// Create a backup from key data on the given device.
+    /// Create a backup of the users, time zone, and `preserve` paths of the old root. The
+    /// homes of the users are found on the `home` partition, when it is not on the root.
     pub fn new(
-        device: &Path,
-        fs: FileSystem,
-        is_root: bool,
+        root: &Path,
+        root_fs: FileSystem,
+        home: Option<(&Path, FileSystem)>,
+        preserve: &[PathBuf],
         account_files: &'a AccountFiles,
     ) -> Result<Backup<'a>, ReinstallError> {
-        mount_and_then(device, fs, |base| {
+        mount_and_then(root, root_fs, |base| {
             info!("collecting list of user accounts");
-            let dir = if is_root { base.join("home").read_dir() } else { base.read_dir() };
-
-            let users = dir?
-                .filter_map(|entry| entry.ok())
-                .map(|name| name.file_name())
-                .inspect(|name| {
-                    info!("found user account: {}", name.clone().into_string().unwrap())
-                })
-                .collect::<Vec<OsString>>();
+            let users = match home {
+                Some((device, fs)) => mount_and_then(device, fs, home_users)?,
+                None => home_users(&base.join("home"))?,
+            };
 
             info!("retaining localtime information");
             let localtime = exists_and_then(&base, "etc/localtime", |localtime| {
@@ -181,27 +361,54 @@ impl<'a> Backup<'a> {
             let timezone =
                 exists_and_then(&base, "etc/timezone", |timezone| misc::read(&timezone).ok());
 
-            info!("retaining /etc/NetworkManager/system-connections/");
-            let networks = base.join("etc/NetworkManager/system-connections/").read_dir().ok().map(
-                |directory| {
-                    directory
-                        .flat_map(|entry| entry.ok())
-                        .filter(|entry| entry.path().is_file())
-                        .filter_map(|conn| {
-                            misc::read(conn.path()).ok().map(|data| (conn.file_name(), data))
-                        })
-                        .collect::<Vec<(OsString, Vec<u8>)>>()
-                },
-            );
+            let preserved = preserve_paths(base, &base.join(STASH), preserve)?;
 
             let users = users.iter().filter_map(|user| account_files.get(user)).collect::<Vec<_>>();
 
-            Ok(Backup { users, localtime, timezone, networks })
+            Ok(Backup {
+                users,
+                localtime,
+                timezone,
+                preserved,
+                accounts: account_files,
+                root: (root.to_path_buf(), root_fs),
+            })
         })
     }
 
+    /// Checks that the retained accounts can be merged with the accounts of the new install,
+    /// given by its `passwd` and `group` files. This may be checked against the image before
+    /// the old root is removed.
+    ///
+    /// Each account is returned with whether its user and its group must be appended.
+    pub fn merge_accounts(
+        &self,
+        passwd: &[u8],
+        group: &[u8],
+    ) -> Result<Vec<(&UserData<'a>, bool, bool)>, ReinstallError> {
+        let mut collisions = Vec::new();
+        let mut retained = Vec::new();
+        for user in &self.users {
+            let checks =
+                (merge_check("user", passwd, user.passwd), merge_check("group", group, user.group));
+            match checks {
+                (Ok(passwd), Ok(group)) => retained.push((user, passwd, group)),
+                (passwd, group) => collisions.extend(passwd.err().into_iter().chain(group.err())),
+            }
+        }
+
+        if collisions.is_empty() {
+            Ok(retained)
+        } else {
+            Err(ReinstallError::AccountCollisions { collisions: Collisions(collisions) })
+        }
+    }
+
     /// Restores the backup to the given device. The device will be opened using the specified file
     /// system.
+    ///
+    /// Accounts which collide with those of the new install are reported as an error, before
+    /// any account is written to the new install.
     pub fn restore(&self, device: &Path, fs: FileSystem) -> Result<(), ReinstallError> {
         mount_and_then(device, fs, |base| {
             info!("appending user account data to new install");
@@ -212,6 +419,12 @@ impl<'a> Backup<'a> {
                 base.join("etc/gshadow"),
             );
 
+            let (existing_passwd, existing_group) = misc::read(&passwd)
+                .and_then(|p| misc::read(&group).map(|g| (p, g)))
+                .map_err(|why| ReinstallError::AccountsObtain { why, step: "merge" })?;
+
+            let retained = self.merge_accounts(&existing_passwd, &existing_group)?;
+
             let (mut passwd, mut group, mut shadow, mut gshadow) = open(&passwd, true)
                 .and_then(|p| open(&group, false).map(|g| (p, g)))
                 .and_then(|(p, g)| open(&shadow, true).map(|s| (p, g, s)))
@@ -226,11 +439,19 @@ impl<'a> Backup<'a> {
                 entry
             }
 
-            for user in &self.users {
-                let _ = passwd.write_all(&append(user.passwd));
-                let _ = group.write_all(&append(user.group));
-                let _ = shadow.write_all(&append(user.shadow));
-                let _ = gshadow.write_all(&append(user.gshadow));
+            for (user, append_user, append_group) in retained {
+                // Accounts of the same name and ID are already in the new install.
+                if append_user {
+                    let _ = passwd.write_all(&append(user.passwd));
+                    let _ = shadow.write_all(&append(user.shadow));
+                } else {
+                    info!("{} is already in the new install", String::from_utf8_lossy(user.user));
+                }
+
+                if append_group {
+                    let _ = group.write_all(&append(user.group));
+                    let _ = gshadow.write_all(&append(user.gshadow));
+                }
 
                 if !user.secondary_groups.is_empty() {
                     group.seek(SeekFrom::Start(0))?;
@@ -246,7 +467,7 @@ impl<'a> Backup<'a> {
                         super::accounts::lines::<Vec<(Vec<u8>, Vec<u8>)>>(&groups_data);
                     for &group in &user.secondary_groups {
                         for entry in &mut groups {
-                            if entry.0.as_slice() == group {
+                            if entry.0.as_slice() == group && !group_has_user(&entry.1, user.user) {
                                 if entry.1[entry.1.len() - 1] != b':' {
                                     entry.1.push(b',');
                                 }
@@ -290,13 +511,30 @@ impl<'a> Backup<'a> {
                 misc::create(base.join("etc/timezone")).and_then(|mut file| file.write_all(tz))?;
             }
 
-            if let Some(ref networks) = self.networks {
-                info!("restoring NetworkManager configuration");
-                let network_conf_dir = &base.join("etc/NetworkManager/system-connections/");
-                let _ = fs::create_dir_all(&network_conf_dir);
+            if !self.preserved.is_empty() {
+                info!("restoring the retained paths of the old root");
+                let (users, groups) = misc::read(base.join("etc/passwd"))
+                    .and_then(|p| misc::read(base.join("etc/group")).map(|g| (p, g)))
+                    .map(|(passwd, group)| self.accounts.id_maps(&passwd, &group))
+                    .map_err(|why| ReinstallError::AccountsObtain { why, step: "map" })?;
+
+                let restore = |old: &Path| -> Result<(), ReinstallError> {
+                    let stash = old.join(STASH);
+                    for entry in &self.preserved {
+                        entry.restore(base, &stash, &users, &groups)?;
+                    }
+
+                    if let Err(why) = fs::remove_dir_all(&stash) {
+                        warn!("failed to remove the retained files at {:?}: {}", stash, why);
+                    }
+
+                    Ok(())
+                };
 
-                for &(ref connection, ref data) in networks {
-                    create_network_conf(network_conf_dir, connection, data);
+                if device == self.root.0 {
+                    restore(base)?;
+                } else {
+                    mount_and_then(&self.root.0, self.root.1, restore)?;
                 }
             }
 
@@ -305,16 +543,6 @@ impl<'a> Backup<'a> {
     }
 }
 
-fn create_network_conf(base: &Path, conn: &OsStr, data: &[u8]) {
-    let result = misc::create(base.join(conn)).and_then(|mut file| {
-        file.write_all(data).and_then(|_| file.set_permissions(Permissions::from_mode(0o600)))
-    });
-
-    if let Err(why) = result {
-        warn!("failed to write network configuration file: {}", why);
-    }
-}
-
 /// Open a file with both read and write permissions, and optionally make it appendable.
 fn open(path: &Path, append: bool) -> io::Result<File> {
     OpenOptions::new().read(true).write(true).append(append).open(path).map_err(|why| {
@@ -355,6 +583,7 @@ where
 mod tests {
     use super::*;
     use std::path::PathBuf;
+    use tempdir::TempDir;
 
     #[test]
     fn localtime() {
@@ -363,4 +592,43 @@ mod tests {
             Some(PathBuf::from("../usr/share/zoneinfo/America/Denver"))
         )
     }
+
+    #[test]
+    fn preserving() {
+        let old = TempDir::new("distinst").unwrap();
+        let new = TempDir::new("distinst").unwrap();
+        let (old, new) = (old.path(), new.path());
+
+        let connections = old.join("etc/NetworkManager/system-connections");
+        fs::create_dir_all(&connections).unwrap();
+        fs::write(connections.join("wifi.nmconnection"), "[wifi]\n").unwrap();
+        fs::set_permissions(connections.join("wifi.nmconnection"), Permissions::from_mode(0o600))
+            .unwrap();
+        symlink("wifi.nmconnection", connections.join("link")).unwrap();
+
+        let stash = old.join(STASH);
+        let paths = &[PathBuf::from("/etc/NetworkManager/system-connections"), "var/lib/x".into()];
+        let preserved = preserve_paths(old, &stash, paths).unwrap();
+        assert_eq!(preserved.len(), 3);
+        assert!(preserve_paths(old, &stash, &["etc/../../x".into()]).is_err());
+
+        // The contents of files are stashed, rather than kept in memory.
+        fs::remove_dir_all(old.join("etc")).unwrap();
+
+        fs::create_dir_all(new.join("etc/NetworkManager")).unwrap();
+        fs::write(new.join("etc/NetworkManager/system-connections"), "").unwrap();
+
+        let ids = HashMap::new();
+        for entry in &preserved {
+            entry.restore(new, &stash, &ids, &ids).unwrap();
+        }
+
+        let wifi = new.join("etc/NetworkManager/system-connections/wifi.nmconnection");
+        assert_eq!(fs::read(&wifi).unwrap(), b"[wifi]\n");
+        assert_eq!(fs::metadata(&wifi).unwrap().mode() & 0o7777, 0o600);
+        assert_eq!(
+            fs::read_link(new.join("etc/NetworkManager/system-connections/link")).unwrap(),
+            Path::new("wifi.nmconnection")
+        );
+    }
 }
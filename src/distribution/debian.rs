@@ -1,6 +1,5 @@
-use crate::bootloader::Bootloader;
 use crate::chroot::Chroot;
-use crate::installer::{bitflags::FileSystemSupport, traits::InstallerDiskOps};
+use crate::installer::{bitflags::FileSystemSupport, traits::InstallerDiskOps, BootloaderChoice};
 use os_release::OsRelease;
 use std::{
     collections::HashSet,
@@ -38,6 +37,30 @@ pub fn check_language_support(lang: &str, chroot: &Chroot) -> io::Result<Option<
     Ok(output)
 }
 
+/// The architecture of the install, as named by dpkg, such as `amd64`.
+pub fn architecture(chroot: &Chroot) -> io::Result<String> {
+    chroot
+        .command("dpkg", &["--print-architecture"])
+        .run_with_stdout()
+        .map(|output| output.trim().to_owned())
+}
+
+/// The names of the packages which are installed in the install.
+pub fn installed_packages(chroot: &Chroot) -> io::Result<Vec<String>> {
+    let output = chroot
+        .command("dpkg-query", &["-W", "-f", "${db:Status-Abbrev}${Package}\\n"])
+        .run_with_stdout()?;
+
+    let packages = output
+        .lines()
+        .filter(|line| line.starts_with("ii"))
+        .filter_map(|line| line.split_whitespace().nth(1))
+        .map(String::from)
+        .collect();
+
+    Ok(packages)
+}
+
 // This is a hack to work around issues with Ubuntu's manifest-remove file.
 // This will get the immediate dependencies of the given packages.
 pub fn get_dependencies_from_list<P: AsRef<str>>(deps: &[P]) -> Option<Vec<String>> {
@@ -92,11 +115,41 @@ fn parse_dependency_line<F: FnMut(&str)>(line: &str, mut func: F) {
     }
 }
 
-pub fn get_bootloader_packages(os_release: &OsRelease) -> &'static [&'static str] {
-    match Bootloader::detect() {
-        Bootloader::Bios => &["grub-common", "grub2-common", "grub-pc"],
-        Bootloader::Efi if os_release.name == "Pop!_OS" => &["kernelstub"],
-        Bootloader::Efi if os_release.name == "Ubuntu" && os_release.version_id == "18.04" => &[
+/// The packages of the `loader`, which is resolved from the choice of the config, for the
+/// `arch` of the image, as the suffix of its EFI binaries.
+pub fn get_bootloader_packages(
+    os_release: &OsRelease,
+    loader: BootloaderChoice,
+    arch: &str,
+) -> &'static [&'static str] {
+    match loader {
+        BootloaderChoice::GrubBios => &["grub-common", "grub2-common", "grub-pc"],
+        BootloaderChoice::SystemdBoot if os_release.name == "Pop!_OS" => {
+            if splits_systemd_boot(os_release) {
+                &["kernelstub", "systemd-boot", "systemd-boot-efi"]
+            } else {
+                &["kernelstub"]
+            }
+        }
+        BootloaderChoice::SystemdBoot if splits_systemd_boot(os_release) => {
+            &["systemd-boot", "systemd-boot-efi"]
+        }
+        // bootctl is a part of systemd in older releases of Debian and Ubuntu.
+        BootloaderChoice::SystemdBoot => &[],
+        // Their config is written by distinst, from the kernels and firmware of the image.
+        BootloaderChoice::Extlinux | BootloaderChoice::RaspberryPi => &[],
+        BootloaderChoice::GrubIeee1275 => &["grub-common", "grub2-common", "grub-ieee1275"],
+        _ if arch == "aa64" => &[
+            "grub-efi-arm64",
+            "grub-efi-arm64-bin",
+            "grub-efi-arm64-signed",
+            "shim-signed",
+            "mokutil",
+        ],
+        // Shim is not signed for 32-bit firmware.
+        _ if arch == "ia32" => &["grub-efi-ia32", "grub-efi-ia32-bin"],
+        // The rest are the packages of x86-64.
+        _ if os_release.name == "Ubuntu" && os_release.version_id == "18.04" => &[
             "grub-efi",
             "grub-efi-amd64",
             "grub-efi-amd64-signed",
@@ -105,7 +158,7 @@ pub fn get_bootloader_packages(os_release: &OsRelease) -> &'static [&'static str
             "fwupdate-signed",
             "linux-signed-generic-hwe-18.04",
         ],
-        Bootloader::Efi if os_release.name == "Ubuntu" && os_release.version_id == "20.04" => &[
+        _ if os_release.name == "Ubuntu" && os_release.version_id == "20.04" => &[
             "grub-efi",
             "grub-efi-amd64",
             "grub-efi-amd64-signed",
@@ -114,7 +167,7 @@ pub fn get_bootloader_packages(os_release: &OsRelease) -> &'static [&'static str
             "fwupd-signed",
             "linux-image-generic-hwe-20.04",
         ],
-        Bootloader::Efi if os_release.name == "Ubuntu" && os_release.version_id.starts_with("22.04") => &[
+        _ if os_release.name == "Ubuntu" && os_release.version_id.starts_with("22.04") => &[
             "efibootmgr",
             "grub-common",
             "grub-efi-amd64-bin",
@@ -124,13 +177,13 @@ pub fn get_bootloader_packages(os_release: &OsRelease) -> &'static [&'static str
             "fwupd-signed",
             "linux-image-generic-hwe-22.04",
         ],
-        Bootloader::Efi if os_release.name == "elementary OS" => &[
+        _ if os_release.name == "elementary OS" => &[
             "grub-efi-amd64",
             "grub-efi-amd64-signed",
             "shim-signed",
             "mokutil",
         ],
-        Bootloader::Efi => &[
+        _ => &[
             "grub-efi",
             "grub-efi-amd64",
             "grub-efi-amd64-bin",
@@ -143,6 +196,22 @@ pub fn get_bootloader_packages(os_release: &OsRelease) -> &'static [&'static str
     }
 }
 
+/// Whether bootctl and the EFI binaries of systemd-boot are packaged apart from systemd, as
+/// they are since Debian 12 and Ubuntu 22.10.
+fn splits_systemd_boot(os_release: &OsRelease) -> bool {
+    let mut version = os_release.version_id.split('.').map(|part| part.parse::<u32>().ok());
+    let version = (version.next().flatten(), version.next().flatten().unwrap_or(0));
+    match (os_release.id.as_str(), version) {
+        // Testing and unstable have no version.
+        ("debian", (None, _)) => true,
+        ("debian", (Some(major), _)) => major >= 12,
+        ("ubuntu", (Some(major), minor)) | ("pop", (Some(major), minor)) => {
+            (major, minor) >= (22, 10)
+        }
+        _ => false,
+    }
+}
+
 pub fn get_required_packages<D: InstallerDiskOps>(
     disks: &D,
     release: &OsRelease,
@@ -189,5 +258,10 @@ pub fn get_required_packages<D: InstallerDiskOps>(
         retain.extend_from_slice(&["lvm2", "dmeventd", "dmraid", "kpartx", "kpartx-boot"]);
     }
 
+    if flags.contains(FileSystemSupport::LVM_CACHE) {
+        // Provides `cache_check`, which is required to activate cached volumes.
+        retain.push("thin-provisioning-tools");
+    }
+
     retain
 }
@@ -0,0 +1,170 @@
+/// The packages which are installed for each locale.
+const LANGUAGE_PACKAGES: &str = include_str!("language-packages");
+
+/// Where the candidate of a package would be installed from.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Candidate {
+    /// The package is already installed.
+    Installed,
+    /// The package is on the ISO, or on the local filesystem.
+    Local,
+    /// The package must be downloaded.
+    Network,
+    /// The package is not in any of the sources of apt.
+    Missing,
+}
+
+/// The language packs, fonts, and input methods to install for the locale, such as
+/// `ja_JP.UTF-8`.
+pub fn packages(lang: &str) -> Vec<String> { packages_from(LANGUAGE_PACKAGES, lang) }
+
+fn packages_from(table: &str, lang: &str) -> Vec<String> {
+    // Takes the locale, such as `zh_CN.UTF-8`, and changes it into `zh_CN` and `zh`.
+    let territory = lang.split(|c| c == '.' || c == '@').next().unwrap_or(lang);
+    let language = territory.split('_').next().unwrap_or(territory);
+
+    let entries =
+        table.lines().map(str::trim).filter(|line| !line.is_empty() && !line.starts_with('#'));
+    let entries = entries.map(|line| {
+        let mut fields = line.split_whitespace();
+        (fields.next().unwrap_or_default(), fields)
+    });
+
+    let mut found = None;
+    for (key, packages) in entries {
+        if key == territory {
+            found = Some(packages);
+            break;
+        } else if key == language && found.is_none() {
+            found = Some(packages);
+        }
+    }
+
+    found.map_or_else(Vec::new, |packages| packages.map(String::from).collect())
+}
+
+/// Where the candidate of a package would be installed from, from the output of
+/// `apt-cache policy`, which is empty for packages that apt does not know of.
+pub fn candidate(policy: &str) -> Candidate {
+    let field = |name: &str| {
+        policy
+            .lines()
+            .map(str::trim)
+            .find(|line| line.starts_with(name))
+            .map(|line| line[name.len()..].trim())
+    };
+
+    if field("Installed:").map_or(false, |version| version != "(none)") {
+        return Candidate::Installed;
+    }
+
+    let candidate = match field("Candidate:") {
+        Some(version) if version != "(none)" => version,
+        _ => return Candidate::Missing,
+    };
+
+    // Each version in the table is followed by the sources which provide it, which are
+    // indented further, and begin with their pin priority.
+    let mut is_candidate = false;
+    let table = policy.lines().skip_while(|line| line.trim() != "Version table:").skip(1);
+    for line in table {
+        if line.starts_with("        ") {
+            let uri = line.split_whitespace().nth(1).unwrap_or("");
+            if is_candidate && (uri.starts_with("cdrom:") || uri.starts_with("file:")) {
+                return Candidate::Local;
+            }
+        } else {
+            let version = line.trim_start().trim_start_matches("***").split_whitespace().next();
+            is_candidate = version == Some(candidate);
+        }
+    }
+
+    Candidate::Network
+}
+
+/// apt options which set the proxies of the live system, as the environment of the chroot
+/// is cleared.
+pub fn proxy_options<I: Iterator<Item = (String, String)>>(vars: I) -> Vec<String> {
+    let mut options = Vec::new();
+    for (key, value) in vars {
+        let protocol = match key.to_lowercase().as_str() {
+            "http_proxy" => "http",
+            "https_proxy" => "https",
+            _ => continue,
+        };
+
+        let option = format!("Acquire::{}::Proxy={}", protocol, value);
+        if !value.is_empty() && !options.contains(&option) {
+            options.push("-o".into());
+            options.push(option);
+        }
+    }
+
+    options
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[rustfmt::skip]
+    const POLICY: &str = "fonts-noto-cjk:
+  Installed: (none)
+  Candidate: 1:20220127+repack1-1
+  Version table:
+     1:20220127+repack1-1 500
+        500 cdrom://Pop_OS 22.04 _Jammy Jellyfish_ jammy/main amd64 Packages
+        500 http://apt.pop-os.org/ubuntu jammy/main amd64 Packages
+     1:20201206-cjk+repack1-1 100
+        100 http://apt.pop-os.org/ubuntu focal/main amd64 Packages
+";
+
+    #[test]
+    fn table() {
+        let table = "# comment\nzh      fonts-noto-cjk\nzh_TW   fonts-noto-cjk ibus-chewing\n";
+        assert_eq!(packages_from(table, "zh_TW.UTF-8"), vec!["fonts-noto-cjk", "ibus-chewing"]);
+        assert_eq!(packages_from(table, "zh_CN.UTF-8"), vec!["fonts-noto-cjk"]);
+        assert!(packages_from(table, "en_US.UTF-8").is_empty());
+
+        assert!(packages("ja_JP.UTF-8").contains(&"ibus-mozc".to_owned()));
+        assert!(packages("zh_CN.UTF-8").contains(&"language-pack-zh-hans".to_owned()));
+    }
+
+    #[test]
+    fn candidates() {
+        assert_eq!(candidate(POLICY), Candidate::Local);
+        assert_eq!(
+            candidate(&POLICY.replace("cdrom://Pop_OS", "http://mirror")),
+            Candidate::Network
+        );
+        assert_eq!(
+            candidate(&POLICY.replace("Installed: (none)", "Installed: 1")),
+            Candidate::Installed
+        );
+        assert_eq!(
+            candidate("ibus-mozc:\n  Installed: (none)\n  Candidate: (none)\n"),
+            Candidate::Missing
+        );
+        assert_eq!(candidate(""), Candidate::Missing);
+    }
+
+    #[test]
+    fn proxies() {
+        let vars = vec![
+            ("HTTP_PROXY".to_owned(), "http://proxy:3128".to_owned()),
+            ("http_proxy".to_owned(), "http://proxy:3128".to_owned()),
+            ("https_proxy".to_owned(), "http://proxy:3129".to_owned()),
+            ("no_proxy".to_owned(), "localhost".to_owned()),
+        ];
+
+        assert_eq!(
+            proxy_options(vars.into_iter()),
+            vec![
+                "-o",
+                "Acquire::http::Proxy=http://proxy:3128",
+                "-o",
+                "Acquire::https::Proxy=http://proxy:3129"
+            ]
+        );
+    }
+}
@@ -1 +1,3 @@
 pub mod debian;
+pub mod language;
+pub mod target;
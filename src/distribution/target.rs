@@ -0,0 +1,203 @@
+//! The distribution of the install, which decides how it is configured.
+
+use super::debian;
+use crate::chroot::Chroot;
+use os_release::OsRelease;
+use std::{io, path::Path};
+
+/// Where `/etc/resolv.conf` links to when the install resolves names with systemd-resolved.
+const RESOLVED_STUB: &str = "../run/systemd/resolve/stub-resolv.conf";
+
+/// The family of distributions which the install belongs to, from its `os-release`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TargetOs {
+    /// Debian, Ubuntu, and their derivatives, which are managed with apt.
+    Debian,
+    /// Fedora and Red Hat, which are managed with dnf.
+    Fedora,
+    /// Arch Linux and its derivatives, which are managed with pacman.
+    Arch,
+    /// A distribution whose packages are not managed.
+    Unsupported,
+}
+
+/// How the locales of the install are made available.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LocaleMechanism {
+    /// Locales are enabled in `/etc/locale.gen`, and generated with `locale-gen`.
+    LocaleGen,
+    /// Locales are installed already compiled, by the language packs of the C library.
+    Precompiled,
+}
+
+impl TargetOs {
+    /// Reads the `os-release` of the install at `root`.
+    pub fn detect(root: &Path) -> Self {
+        let release = OsRelease::new_from(root.join("etc/os-release"))
+            .or_else(|_| OsRelease::new_from(root.join("usr/lib/os-release")));
+
+        match release {
+            Ok(release) => Self::from_ids(&release.id, &release.id_like),
+            Err(why) => {
+                warn!("failed to read the os-release of {}: {}", root.display(), why);
+                TargetOs::Unsupported
+            }
+        }
+    }
+
+    /// Matches the `ID` of the distribution, or else the first of its `ID_LIKE` that is known.
+    pub fn from_ids(id: &str, id_like: &str) -> Self {
+        for id in id.split_whitespace().chain(id_like.split_whitespace()) {
+            match id {
+                "debian" | "ubuntu" => return TargetOs::Debian,
+                "fedora" | "rhel" | "centos" => return TargetOs::Fedora,
+                "arch" => return TargetOs::Arch,
+                _ => (),
+            }
+        }
+
+        TargetOs::Unsupported
+    }
+
+    /// The program which installs and removes packages, if they can be managed.
+    pub fn package_manager(self) -> Option<&'static str> {
+        match self {
+            TargetOs::Debian => Some("apt-get"),
+            TargetOs::Fedora => Some("dnf"),
+            TargetOs::Arch => Some("pacman"),
+            TargetOs::Unsupported => None,
+        }
+    }
+
+    pub fn locale_mechanism(self) -> LocaleMechanism {
+        match self {
+            TargetOs::Fedora => LocaleMechanism::Precompiled,
+            _ => LocaleMechanism::LocaleGen,
+        }
+    }
+
+    /// The target of the `/etc/resolv.conf` link, if the distribution resolves names with
+    /// systemd-resolved. Otherwise, the file of the image is kept.
+    pub fn resolv_conf(self) -> Option<&'static str> {
+        match self {
+            TargetOs::Debian | TargetOs::Fedora => Some(RESOLVED_STUB),
+            TargetOs::Arch | TargetOs::Unsupported => None,
+        }
+    }
+
+    /// The systemd unit of the OpenSSH server.
+    pub fn ssh_service(self) -> &'static str {
+        match self {
+            TargetOs::Debian => "ssh.service",
+            _ => "sshd.service",
+        }
+    }
+
+    /// The architecture of the install, as it is named by its package manager.
+    pub fn architecture(self, chroot: &Chroot) -> io::Result<String> {
+        let mut command = match self {
+            TargetOs::Debian => return debian::architecture(chroot),
+            TargetOs::Fedora => chroot.command("rpm", &["--eval", "%{_arch}"]),
+            TargetOs::Arch | TargetOs::Unsupported => chroot.command("uname", &["-m"]),
+        };
+
+        command.run_with_stdout().map(|output| output.trim().to_owned())
+    }
+
+    /// The names of the packages which are installed in the install.
+    pub fn installed_packages(self, chroot: &Chroot) -> io::Result<Vec<String>> {
+        let mut command = match self {
+            TargetOs::Debian => return debian::installed_packages(chroot),
+            TargetOs::Fedora => chroot.command("rpm", &["--query", "--all", "--qf", "%{NAME}\\n"]),
+            TargetOs::Arch => chroot.command("pacman", &["-Qq"]),
+            TargetOs::Unsupported => return Ok(Vec::new()),
+        };
+
+        let output = command.run_with_stdout()?;
+        Ok(output.lines().map(str::trim).filter(|l| !l.is_empty()).map(String::from).collect())
+    }
+
+    /// The commands which remove the packages, and purge the others along with their
+    /// configuration files, followed by any dependencies that are no longer needed.
+    pub fn remove_commands<'a>(
+        self,
+        remove: &[&'a str],
+        purge: &[&'a str],
+    ) -> Vec<(&'static str, Vec<&'a str>)> {
+        let with = |args: &[&'a str], packages: &[&'a str]| {
+            let mut args = args.to_vec();
+            args.extend_from_slice(packages);
+            args
+        };
+
+        let mut commands = Vec::new();
+        match self {
+            TargetOs::Debian => {
+                if !remove.is_empty() {
+                    commands.push(("apt-get", with(&["remove", "-y"], remove)));
+                }
+                if !purge.is_empty() {
+                    commands.push(("apt-get", with(&["purge", "-y"], purge)));
+                }
+                commands.push(("apt-get", vec!["autoremove", "-y", "--purge"]));
+            }
+            // Configuration files which were changed are kept as `.rpmsave` either way.
+            TargetOs::Fedora => {
+                if !remove.is_empty() || !purge.is_empty() {
+                    commands.push(("dnf", with(&with(&["remove", "-y"], remove), purge)));
+                }
+                commands.push(("dnf", vec!["autoremove", "-y"]));
+            }
+            TargetOs::Arch => {
+                if !remove.is_empty() {
+                    commands.push(("pacman", with(&["-Rs", "--noconfirm"], remove)));
+                }
+                if !purge.is_empty() {
+                    commands.push(("pacman", with(&["-Rns", "--noconfirm"], purge)));
+                }
+            }
+            TargetOs::Unsupported => (),
+        }
+
+        commands
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn from_ids() {
+        assert_eq!(TargetOs::from_ids("pop", "ubuntu debian"), TargetOs::Debian);
+        assert_eq!(TargetOs::from_ids("debian", ""), TargetOs::Debian);
+        assert_eq!(TargetOs::from_ids("fedora", ""), TargetOs::Fedora);
+        assert_eq!(TargetOs::from_ids("rocky", "rhel centos fedora"), TargetOs::Fedora);
+        assert_eq!(TargetOs::from_ids("manjaro", "arch"), TargetOs::Arch);
+        assert_eq!(TargetOs::from_ids("opensuse-tumbleweed", "opensuse suse"), TargetOs::Unsupported);
+    }
+
+    #[test]
+    fn remove_commands() {
+        assert_eq!(
+            TargetOs::Debian.remove_commands(&["a"], &["b", "c"]),
+            vec![
+                ("apt-get", vec!["remove", "-y", "a"]),
+                ("apt-get", vec!["purge", "-y", "b", "c"]),
+                ("apt-get", vec!["autoremove", "-y", "--purge"]),
+            ]
+        );
+
+        assert_eq!(
+            TargetOs::Fedora.remove_commands(&["a"], &["b"]),
+            vec![("dnf", vec!["remove", "-y", "a", "b"]), ("dnf", vec!["autoremove", "-y"])]
+        );
+
+        assert_eq!(
+            TargetOs::Arch.remove_commands(&[], &["b"]),
+            vec![("pacman", vec!["-Rns", "--noconfirm", "b"])]
+        );
+
+        assert!(TargetOs::Unsupported.remove_commands(&["a"], &["b"]).is_empty());
+    }
+}
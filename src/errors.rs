@@ -1,4 +1,5 @@
 use crate::disks::DiskError;
+use crate::misc;
 use std::{error::Error, fmt::Display, io};
 
 /// Extends `Option<T>` to be converted into an `io::Result<T>`.
@@ -26,14 +27,22 @@ pub trait IoContext<T> {
 
 impl<T> IoContext<T> for io::Result<T> {
     fn with_context<F: FnMut(Box<dyn Display>) -> String>(self, mut func: F) -> io::Result<T> {
-        self.map_err(|why| io::Error::new(why.kind(), func(Box::new(why))))
+        self.map_err(|why| {
+            // The command or device that the error occurred with is kept for the installer.
+            let (kind, context) = (why.kind(), misc::error_context(&why).cloned());
+            misc::rewrap_error(kind, context, func(Box::new(why)))
+        })
     }
 }
 
 // NOTE: This can be removed once RFC #1210 is implemented.
 impl<T> IoContext<T> for Result<T, DiskError> {
     fn with_context<F: FnMut(Box<dyn Display>) -> String>(self, mut func: F) -> io::Result<T> {
-        self.map_err(|why| io::Error::new(io::ErrorKind::Other, func(Box::new(why))))
+        self.map_err(|why| {
+            // The code of the error is kept, so that frontends may translate its message.
+            let cause = why.error_cause();
+            misc::cause_error(io::ErrorKind::Other, cause, func(Box::new(why)))
+        })
     }
 }
 
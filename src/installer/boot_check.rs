@@ -0,0 +1,355 @@
+use super::efivars::{HardDrive, LoadOption};
+use std::{
+    fs::{self, File},
+    io::{self, Read},
+    path::{Path, PathBuf},
+};
+
+/// A kernel which the config of a bootloader boots, with its initrds and parameters.
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct BootEntry {
+    pub kernel:  String,
+    pub initrds: Vec<String>,
+    pub options: String,
+}
+
+impl BootEntry {
+    /// The value of the `root` parameter, of which the kernel uses the last.
+    pub(crate) fn root(&self) -> Option<&str> {
+        self.options.split_whitespace().rev().find_map(|arg| arg.strip_prefix("root="))
+    }
+}
+
+/// The ways in which the root of the install may be named by a `root` parameter.
+pub(crate) struct RootIds {
+    /// `UUID=` and `PARTUUID=` identifiers of the root.
+    pub ids:    Vec<String>,
+    /// The device of the root, to which a path such as `/dev/mapper/data-root` resolves.
+    pub device: Option<PathBuf>,
+}
+
+impl RootIds {
+    fn matches(&self, root: &str) -> bool {
+        self.ids.iter().any(|id| id == root)
+            || (root.starts_with('/')
+                && self.device.is_some()
+                && Path::new(root).canonicalize().ok() == self.device)
+    }
+}
+
+/// The kernels which the `grub.cfg` boots. Those of the `10_linux` script are the install's
+/// own, whereas os-prober adds those of other OSes after them.
+pub(crate) fn grub_entries(cfg: &str) -> Vec<BootEntry> {
+    let own = cfg
+        .split("### BEGIN /etc/grub.d/10_linux ###")
+        .nth(1)
+        .and_then(|linux| linux.split("### END /etc/grub.d/10_linux ###").next())
+        .unwrap_or(cfg);
+
+    let mut entries: Vec<BootEntry> = Vec::new();
+    for line in own.lines() {
+        let mut words = line.split_whitespace();
+        match words.next() {
+            Some("linux") | Some("linuxefi") | Some("linux16") => {
+                if let Some(kernel) = words.next() {
+                    entries.push(BootEntry {
+                        kernel:  kernel.to_owned(),
+                        initrds: Vec::new(),
+                        options: words.collect::<Vec<_>>().join(" "),
+                    });
+                }
+            }
+            Some("initrd") | Some("initrdefi") | Some("initrd16") => {
+                if let Some(entry) = entries.last_mut() {
+                    entry.initrds.extend(words.map(String::from));
+                }
+            }
+            _ => (),
+        }
+    }
+
+    entries
+}
+
+/// The kernel which an entry of systemd-boot boots.
+pub(crate) fn systemd_boot_entry(conf: &str) -> BootEntry {
+    let mut entry = BootEntry::default();
+    let mut options = Vec::new();
+    for line in conf.lines() {
+        let mut words = line.splitn(2, char::is_whitespace);
+        let (key, value) = match (words.next(), words.next()) {
+            (Some(key), Some(value)) => (key, value.trim()),
+            _ => continue,
+        };
+
+        match key {
+            "linux" => entry.kernel = value.to_owned(),
+            "initrd" => entry.initrds.push(value.to_owned()),
+            "options" => options.push(value),
+            _ => (),
+        }
+    }
+
+    entry.options = options.join(" ");
+    entry
+}
+
+/// Checks that the `entries` of `config` boot kernels and initrds which `exists`, with the
+/// root of the install.
+pub(crate) fn entries<F: Fn(&str) -> bool>(
+    config: &str,
+    entries: &[BootEntry],
+    exists: F,
+    root: &RootIds,
+) -> io::Result<()> {
+    let invalid = |why: String| Err(io::Error::new(io::ErrorKind::InvalidData, why));
+    if entries.is_empty() {
+        return invalid(format!("{} has no entries which boot the install", config));
+    }
+
+    for entry in entries {
+        let kernel = &entry.kernel;
+        if kernel.is_empty() || !exists(kernel) {
+            return invalid(format!(
+                "{} boots the kernel {:?}, which does not exist",
+                config, kernel
+            ));
+        }
+
+        if entry.initrds.is_empty() {
+            return invalid(format!("{} boots {} without an initrd", config, kernel));
+        }
+
+        if let Some(initrd) = entry.initrds.iter().find(|initrd| !exists(initrd)) {
+            return invalid(format!(
+                "{} boots the initrd {}, which does not exist",
+                config, initrd
+            ));
+        }
+
+        match entry.root() {
+            Some(value) if root.matches(value) => (),
+            Some(value) => {
+                return invalid(format!(
+                    "{} boots {} with root={}, which is not the root of the install ({})",
+                    config,
+                    kernel,
+                    value,
+                    root.ids.join(", ")
+                ))
+            }
+            None => {
+                return invalid(format!("{} boots {} without a root parameter", config, kernel))
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Checks the `grub.cfg` of the install at `target`. Its paths are relative to the file
+/// system of `/boot`, which may be the root, and the kernels are found there.
+pub(crate) fn grub_config(target: &Path, cfg: &Path, root: &RootIds) -> io::Result<()> {
+    let contents = read(&target.join(cfg))?;
+    let exists = |path: &str| {
+        let name = match Path::new(path).file_name() {
+            Some(name) => name,
+            None => return false,
+        };
+
+        target.join("boot").join(name).is_file()
+    };
+
+    entries(&["/", &cfg.to_string_lossy()].concat(), &grub_entries(&contents), exists, root)
+}
+
+/// Checks the entries of systemd-boot on the ESP at `esp` which boot the OS `name`, as
+/// kernelstub and distinst name them. Their paths are relative to the ESP.
+pub(crate) fn systemd_boot_config(esp: &Path, name: &str, root: &RootIds) -> io::Result<()> {
+    let dir = esp.join("loader/entries");
+    let prefix = [name, "-"].concat();
+    let mut boot_entries = Vec::new();
+    for entry in fs::read_dir(&dir).map_err(|why| context(&dir, why))? {
+        let path = entry?.path();
+        let is_ours = path
+            .file_name()
+            .and_then(|file| file.to_str())
+            .map_or(false, |file| file.starts_with(&prefix) && file.ends_with(".conf"));
+
+        if is_ours {
+            boot_entries.push(systemd_boot_entry(&read(&path)?));
+        }
+    }
+
+    let exists = |path: &str| esp.join(path.trim_start_matches('/')).is_file();
+    entries("/boot/efi/loader/entries", &boot_entries, exists, root)
+}
+
+/// Checks that the `loader` exists on the ESP at `esp`.
+pub(crate) fn efi_loader(esp: &Path, loader: &Path) -> io::Result<()> {
+    let path = esp.join(loader);
+    match fs::metadata(&path) {
+        Ok(metadata) if metadata.len() > 0 => Ok(()),
+        _ => Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("the loader /{} is missing from the ESP", loader.display()),
+        )),
+    }
+}
+
+/// Checks that the boot entry `number` boots `loader` from the ESP at `partition`.
+pub(crate) fn efi_entry(
+    entries: &[(u16, LoadOption)],
+    number: u16,
+    partition: &HardDrive,
+    loader: &str,
+) -> io::Result<()> {
+    let invalid = |why: String| Err(io::Error::new(io::ErrorKind::InvalidData, why));
+    let option = match entries.iter().find(|&&(other, _)| other == number) {
+        Some((_, option)) => option,
+        None => return invalid(format!("the boot entry Boot{:04X} was not saved", number)),
+    };
+
+    if option.partition.map(|part| part.signature) != Some(partition.signature) {
+        return invalid(format!("the boot entry Boot{:04X} is not on the ESP", number));
+    }
+
+    match option.loader {
+        Some(ref path) if path.eq_ignore_ascii_case(loader) => Ok(()),
+        _ => invalid(format!("the boot entry Boot{:04X} does not boot {}", number, loader)),
+    }
+}
+
+/// Checks that the boot code of GRUB was embedded in the MBR of `disk`.
+pub(crate) fn mbr(disk: &Path) -> io::Result<()> {
+    if is_grub_mbr(&read_start(disk, 512)?) {
+        Ok(())
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("the MBR of {} does not boot GRUB", disk.display()),
+        ))
+    }
+}
+
+/// Checks that the stage1 of GRUB was written to the PReP boot partition at `prep`.
+pub(crate) fn prep(prep: &Path) -> io::Result<()> {
+    if read_start(prep, 4)? == b"\x7fELF" {
+        Ok(())
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("the PReP boot partition {} does not contain GRUB", prep.display()),
+        ))
+    }
+}
+
+fn is_grub_mbr(sector: &[u8]) -> bool {
+    sector.len() == 512
+        && sector[510..] == [0x55, 0xaa]
+        && sector.windows(5).any(|window| window == b"GRUB ")
+}
+
+fn read_start(path: &Path, len: usize) -> io::Result<Vec<u8>> {
+    let mut start = vec![0; len];
+    File::open(path)
+        .and_then(|mut file| file.read_exact(&mut start))
+        .map_err(|why| context(path, why))?;
+    Ok(start)
+}
+
+fn read(path: &Path) -> io::Result<String> {
+    fs::read_to_string(path).map_err(|why| context(path, why))
+}
+
+fn context(path: &Path, why: io::Error) -> io::Error {
+    io::Error::new(why.kind(), format!("failed to read {}: {}", path.display(), why))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    const GRUB_CFG: &str = r#"### BEGIN /etc/grub.d/10_linux ###
+menuentry 'Ubuntu' --class ubuntu {
+	linux	/boot/vmlinuz-6.2.0-39-generic root=UUID=1234 ro  quiet splash $vt_handoff
+	initrd	/boot/initrd.img-6.2.0-39-generic
+}
+### END /etc/grub.d/10_linux ###
+### BEGIN /etc/grub.d/30_os-prober ###
+menuentry 'Debian' {
+	linux /vmlinuz root=UUID=5678
+}
+### END /etc/grub.d/30_os-prober ###
+"#;
+
+    fn root() -> RootIds { RootIds { ids: vec!["UUID=1234".into()], device: None } }
+
+    #[test]
+    fn grub() {
+        let target = TempDir::new("distinst").unwrap();
+        let target = target.path();
+        fs::create_dir_all(target.join("boot/grub")).unwrap();
+        let cfg = Path::new("boot/grub/grub.cfg");
+
+        // An empty config boots nothing.
+        fs::write(target.join(cfg), "").unwrap();
+        let why = grub_config(target, cfg, &root()).unwrap_err().to_string();
+        assert_eq!(why, "/boot/grub/grub.cfg has no entries which boot the install");
+
+        // The entries of os-prober are not those of the install.
+        fs::write(target.join(cfg), GRUB_CFG).unwrap();
+        let entries = grub_entries(GRUB_CFG);
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].initrds, vec!["/boot/initrd.img-6.2.0-39-generic"]);
+        assert_eq!(entries[0].root(), Some("UUID=1234"));
+
+        let why = grub_config(target, cfg, &root()).unwrap_err().to_string();
+        assert!(why.ends_with("vmlinuz-6.2.0-39-generic\", which does not exist"), "{}", why);
+
+        fs::write(target.join("boot/vmlinuz-6.2.0-39-generic"), "").unwrap();
+        fs::write(target.join("boot/initrd.img-6.2.0-39-generic"), "").unwrap();
+        grub_config(target, cfg, &root()).unwrap();
+
+        let other = RootIds { ids: vec!["UUID=abcd".into()], device: None };
+        let why = grub_config(target, cfg, &other).unwrap_err().to_string();
+        assert!(why.contains("with root=UUID=1234, which is not the root"), "{}", why);
+    }
+
+    #[test]
+    fn systemd_boot() {
+        let esp = TempDir::new("distinst").unwrap();
+        let esp = esp.path();
+        fs::create_dir_all(esp.join("loader/entries")).unwrap();
+        fs::create_dir_all(esp.join("EFI/Pop_OS")).unwrap();
+        let entry = "title Pop!_OS\nlinux /EFI/Pop_OS/vmlinuz.efi\ninitrd \
+                     /EFI/Pop_OS/initrd.img\noptions root=UUID=1234 ro\n";
+        fs::write(esp.join("loader/entries/Pop_OS-current.conf"), entry).unwrap();
+        fs::write(esp.join("loader/entries/Other-current.conf"), "linux /missing").unwrap();
+
+        let why = systemd_boot_config(esp, "Pop_OS", &root()).unwrap_err().to_string();
+        assert!(why.ends_with("\"/EFI/Pop_OS/vmlinuz.efi\", which does not exist"), "{}", why);
+
+        fs::write(esp.join("EFI/Pop_OS/vmlinuz.efi"), "").unwrap();
+        fs::write(esp.join("EFI/Pop_OS/initrd.img"), "").unwrap();
+        systemd_boot_config(esp, "Pop_OS", &root()).unwrap();
+
+        // A root which is named by its path is found by its device.
+        let device = esp.join("dm-2");
+        fs::write(&device, "").unwrap();
+        std::os::unix::fs::symlink(&device, esp.join("data-root")).unwrap();
+        let root = RootIds { ids: Vec::new(), device: Some(device.canonicalize().unwrap()) };
+        assert!(root.matches(&esp.join("data-root").to_string_lossy()));
+        assert!(!root.matches("UUID=1234"));
+    }
+
+    #[test]
+    fn boot_code() {
+        let mut sector = vec![0; 512];
+        assert!(!is_grub_mbr(&sector));
+        sector[0x180..0x185].copy_from_slice(b"GRUB ");
+        sector[510..].copy_from_slice(&[0x55, 0xaa]);
+        assert!(is_grub_mbr(&sector));
+    }
+}
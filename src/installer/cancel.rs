@@ -0,0 +1,93 @@
+#![allow(deprecated)]
+
+use crate::KILL_SWITCH;
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+/// How often an install checks whether the deprecated `KILL_SWITCH` was set.
+const KILL_SWITCH_POLL: Duration = Duration::from_millis(100);
+
+/// Stops the install of the installer that it was taken from, or the operations which that
+/// install is running.
+///
+/// Clones of the token share its state, so one may be kept by a frontend or a signal
+/// handler while the install runs. Cancelling the token only sets an atomic flag, which is
+/// safe to do from a signal handler.
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self { Self::default() }
+
+    /// Cancels the install, which unmounts its target and closes its encrypted devices
+    /// before it returns an error that `Error::cancelled_at` recognizes.
+    pub fn cancel(&self) { self.0.store(true, Ordering::SeqCst); }
+
+    /// Whether the token, or the deprecated `KILL_SWITCH`, was set.
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst) || KILL_SWITCH.load(Ordering::SeqCst)
+    }
+
+    /// Clears a cancellation, which remains set until this is called, so that the installer
+    /// may run another install.
+    pub fn reset(&self) { self.0.store(false, Ordering::SeqCst); }
+
+    /// The flag which the operations of an install check.
+    pub(crate) fn flag(&self) -> &AtomicBool { &self.0 }
+
+    /// Cancels the token once `KILL_SWITCH` is set, until the returned guard is dropped.
+    ///
+    /// Operations check the flag of the token directly, rather than `is_cancelled`, so the
+    /// switch is watched for the duration of an install.
+    pub(crate) fn follow_kill_switch(&self) -> KillSwitchWatch {
+        let done = Arc::new(AtomicBool::new(false));
+        let (token, watching) = (self.clone(), done.clone());
+
+        thread::spawn(move || {
+            while !watching.load(Ordering::SeqCst) {
+                if KILL_SWITCH.load(Ordering::SeqCst) {
+                    token.cancel();
+                    break;
+                }
+
+                thread::sleep(KILL_SWITCH_POLL);
+            }
+        });
+
+        KillSwitchWatch(done)
+    }
+}
+
+/// Stops watching `KILL_SWITCH` when it is dropped.
+pub(crate) struct KillSwitchWatch(Arc<AtomicBool>);
+
+impl Drop for KillSwitchWatch {
+    fn drop(&mut self) { self.0.store(true, Ordering::SeqCst); }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clones_share_cancellation() {
+        let token = CancellationToken::new();
+        let handle = token.clone();
+        let other = CancellationToken::new();
+        assert!(!token.is_cancelled());
+
+        handle.cancel();
+        assert!(token.is_cancelled());
+        assert!(token.flag().load(Ordering::SeqCst));
+        assert!(!other.is_cancelled());
+
+        token.reset();
+        assert!(!handle.is_cancelled());
+    }
+}
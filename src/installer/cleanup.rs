@@ -0,0 +1,115 @@
+use crate::external::luks::deactivate_logical_devices;
+use proc_mounts::MountList;
+use std::{
+    env, io,
+    path::{Path, PathBuf},
+};
+use sys_mount::{unmount, UnmountFlags};
+
+/// Unmounts everything that is mounted at or beneath the target, deepest mounts first.
+///
+/// Mounts which are busy are lazily unmounted as a last resort, so that they are detached
+/// from the target even if they cannot be unmounted right away.
+pub fn unmount_within(target: &Path) -> io::Result<()> {
+    unmount_matching(|dest| dest.starts_with(target))
+}
+
+/// Unmounts everything within the temporary chroot directories of the installer, and then
+/// deactivates the logical volumes and encrypted partitions that were opened.
+pub fn cleanup() -> io::Result<()> {
+    let prefix = env::temp_dir().join(super::Installer::CHROOT_ROOT);
+    let prefix = [&*prefix.to_string_lossy(), "."].concat();
+    let unmounted = unmount_matching(|dest| dest.to_string_lossy().starts_with(&prefix));
+    let deactivated = deactivate_logical_devices();
+    unmounted.and(deactivated)
+}
+
+fn unmount_matching<F: Fn(&Path) -> bool>(matches: F) -> io::Result<()> {
+    let mut targets = MountList::new()?
+        .0
+        .into_iter()
+        .map(|mount| mount.dest)
+        .filter(|dest| matches(dest))
+        .collect::<Vec<PathBuf>>();
+
+    // A mount that was mounted later may be stacked on the same target, so the last of
+    // those in the list is unmounted first.
+    targets.reverse();
+    targets.sort_by_key(|dest| ::std::cmp::Reverse(dest.components().count()));
+
+    let mut result = Ok(());
+    for target in targets {
+        info!("unmounting {}", target.display());
+        if let Err(why) = unmount(&target, UnmountFlags::empty()) {
+            warn!("failed to unmount {}, unmounting lazily: {}", target.display(), why);
+            if let Err(why) = unmount(&target, UnmountFlags::DETACH) {
+                error!("failed to lazily unmount {}: {}", target.display(), why);
+                result = Err(why);
+            }
+        }
+    }
+
+    result
+}
+
+/// Cleans up after an install when dropped, whether the install returned an error or
+/// panicked.
+pub struct Cleanup {
+    target: Option<PathBuf>,
+}
+
+impl Cleanup {
+    /// Unmounts anything that remains mounted within the target.
+    pub fn mounts(target: &Path) -> Self { Cleanup { target: Some(target.to_path_buf()) } }
+
+    /// Deactivates the logical volumes and encrypted partitions that remain open.
+    pub fn devices() -> Self { Cleanup { target: None } }
+}
+
+impl Drop for Cleanup {
+    fn drop(&mut self) {
+        let result = match self.target {
+            Some(ref target) => unmount_within(target),
+            None => deactivate_logical_devices(),
+        };
+
+        if let Err(why) = result {
+            error!("failed to clean up after the install: {}", why);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chroot::Chroot;
+    use std::{fs, mem};
+    use sys_mount::{Mount, MountFlags};
+    use tempdir::TempDir;
+
+    #[test]
+    fn configure_failure() {
+        // Mounting requires root, so this is only tested as root.
+        if unsafe { libc::geteuid() } != 0 {
+            return;
+        }
+
+        let target = TempDir::new("distinst").unwrap();
+        let target = target.path();
+        Mount::new("tmpfs", target, "tmpfs", MountFlags::empty(), None).unwrap();
+        for dir in &["dev/pts", "proc", "run", "sys"] {
+            fs::create_dir_all(target.join(dir)).unwrap();
+        }
+
+        let result: io::Result<()> = (|| {
+            let _cleanup = Cleanup::mounts(target);
+            // The chroot is leaked, as when a step panics before it is dropped.
+            mem::forget(Chroot::new(target)?);
+            Err(io::Error::new(io::ErrorKind::Other, "configure failed"))
+        })();
+
+        assert!(result.is_err());
+        let mounts = MountList::new().unwrap();
+        assert!(mounts.0.iter().all(|mount| !mount.dest.starts_with(target)));
+    }
+}
@@ -0,0 +1,98 @@
+use std::io;
+
+/// Parameters which the kernel accepts more than once, which are only deduplicated when
+/// they are repeated exactly.
+const REPEATABLE: &[&str] = &["console"];
+
+/// Validates the arguments before any disks are modified. Each is one word, which needs no
+/// quoting in the config of GRUB or kernelstub.
+pub fn validate(args: &[String]) -> io::Result<()> {
+    for arg in args {
+        let invalid = arg.is_empty()
+            || arg.starts_with('=')
+            || arg.contains(|c: char| c.is_whitespace() || "\"'`$\\".contains(c));
+        if invalid {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid kernel argument: '{}'", arg),
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Merges the arguments into a command line, in order. An argument replaces an earlier one
+/// with the same name, such as `loglevel=3` replacing `loglevel=0`, and arguments which are
+/// repeated are given once.
+pub fn merge<'a, I: IntoIterator<Item = &'a str>>(args: I) -> Vec<&'a str> {
+    let mut merged: Vec<&str> = Vec::new();
+    for arg in args {
+        let key = name(arg);
+        let existing = if REPEATABLE.contains(&key) {
+            merged.iter().position(|&other| other == arg)
+        } else {
+            merged.iter().position(|&other| name(other) == key)
+        };
+
+        match existing {
+            Some(pos) => merged[pos] = arg,
+            None => merged.push(arg),
+        }
+    }
+
+    merged
+}
+
+/// The arguments of `defaults` which `merged` replaced with a different value.
+pub fn replaced<'a>(defaults: &'a str, merged: &[&str]) -> Vec<&'a str> {
+    defaults.split_whitespace().filter(|arg| !merged.contains(arg)).collect()
+}
+
+/// The value of `GRUB_CMDLINE_LINUX_DEFAULT` in `/etc/default/grub`.
+pub fn grub_default(grub: &str) -> &str {
+    grub.lines()
+        .filter_map(|line| line.trim().strip_prefix("GRUB_CMDLINE_LINUX_DEFAULT="))
+        .last()
+        .map_or("", |value| value.trim_matches(|c| c == '"' || c == '\''))
+}
+
+/// Sets the kernel command line which GRUB generates, in a file for `/etc/default/grub.d`.
+pub fn grub(cmdline: &str) -> String { format!("GRUB_CMDLINE_LINUX_DEFAULT=\"{}\"\n", cmdline) }
+
+/// The name of the parameter, which precedes its value.
+fn name(arg: &str) -> &str { arg.split('=').next().unwrap_or(arg) }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merging() {
+        let defaults = "quiet loglevel=0 splash";
+        let args = &["nomodeset", "quiet", "loglevel=3", "console=tty0", "console=ttyS0,115200"];
+        let merged = merge(defaults.split_whitespace().chain(args.iter().cloned()));
+        assert_eq!(
+            merged.join(" "),
+            "quiet loglevel=3 splash nomodeset console=tty0 console=ttyS0,115200"
+        );
+        assert_eq!(replaced(defaults, &merged), vec!["loglevel=0"]);
+    }
+
+    #[test]
+    fn grub_files() {
+        let defaults = "GRUB_DEFAULT=0\nGRUB_CMDLINE_LINUX_DEFAULT=\"quiet \
+                        splash\"\nGRUB_CMDLINE_LINUX=\"\"\n";
+        assert_eq!(grub_default(defaults), "quiet splash");
+        assert_eq!(grub_default("GRUB_DEFAULT=0\n"), "");
+        assert_eq!(grub("quiet nomodeset"), "GRUB_CMDLINE_LINUX_DEFAULT=\"quiet nomodeset\"\n");
+    }
+
+    #[test]
+    fn arguments() {
+        assert!(validate(&["mitigations=off".into(), "nomodeset".into()]).is_ok());
+        assert!(validate(&["quiet splash".into()]).is_err());
+        assert!(validate(&["".into()]).is_err());
+        assert!(validate(&["init=$(reboot)".into()]).is_err());
+    }
+}
@@ -0,0 +1,106 @@
+use super::{locales, state::InstallerState, steps, Config, InstallReport, Installer, Step};
+use std::{io, path::Path, slice};
+
+/// Options for an install to a directory, rather than to disks.
+#[derive(Clone, Debug, Default)]
+pub struct DryRunOptions {
+    /// Verifies the source before it is extracted.
+    pub verify:   bool,
+    /// Allows commands to be run in the target, as root within a user namespace, to
+    /// generate locales and create the user.
+    pub commands: bool,
+}
+
+impl Installer {
+    /// Installs to an existing empty directory, instead of to disks, so that the extraction
+    /// and configuration of an image may be tested without any block devices.
+    ///
+    /// The Partition and Bootloader steps are skipped, and nothing is mounted. The status and
+    /// error callbacks are called as they are for `Installer::install`, but hooks are not
+    /// run.
+    pub fn install_to_dir<P: AsRef<Path>>(
+        &mut self,
+        target: P,
+        config: &Config,
+        options: DryRunOptions,
+    ) -> io::Result<InstallReport> {
+        let target = target.as_ref();
+        self.run_sync(move |steps, _| {
+            info!("installing from {} to the directory at {}", config.squashfs, target.display());
+            Self::dry_run(target, config, &options, steps)
+        })
+    }
+
+    fn dry_run(
+        target: &Path,
+        config: &Config,
+        options: &DryRunOptions,
+        steps: &mut InstallerState,
+    ) -> io::Result<()> {
+        let cancel = steps.worker.cancel.clone();
+        let source = steps.apply(Step::Init, "initializing", |steps| {
+            Self::validate(config)?;
+
+            let mut entries = target.read_dir().map_err(|why| {
+                io::Error::new(why.kind(), format!("{}: {}", target.display(), why))
+            })?;
+
+            if entries.next().is_some() {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("{} is not empty", target.display()),
+                ));
+            }
+
+            let source = steps::find_source(config)?;
+            locales::verify(&source, config)?;
+            steps.status.percent = 100;
+            let status = steps.status.clone();
+            steps.emit_status(status);
+            Ok(source)
+        })?;
+
+        // A remote image which is not verified is downloaded into the target as it is
+        // extracted.
+        let source = if options.verify {
+            steps.apply(Step::Verify, "verifying", |steps| {
+                Installer::verify(&source, config, &cancel, |percent, message, bytes| {
+                    steps.emit_transfer(percent, message, bytes)
+                })
+            })?
+        } else {
+            source
+        };
+
+        info!("skipping partitioning, as the install is to a directory");
+
+        let threads = steps.worker.extract_threads;
+        steps.apply(Step::Extract, "extracting", |steps| {
+            Installer::extract(&source, config, target, threads, &cancel, |percent, path, bytes| {
+                steps.emit_transfer(percent, path, bytes)
+            })
+        })?;
+
+        let timezone = steps.timezone();
+        let callback_user = steps.user(config)?;
+        let users = callback_user.as_ref().map_or(&config.users[..], slice::from_ref);
+        if !config.oem_mode && config.users.is_empty() {
+            Self::validate_users(config, users)?;
+        }
+
+        steps.apply(Step::Configure, "configuring directory", |steps| {
+            steps::configure_dir(
+                target,
+                config,
+                timezone.as_ref(),
+                users,
+                options.commands,
+                &cancel,
+                |percent, substep| steps.emit_substep(percent, substep),
+            )
+        })?;
+
+        info!("skipping the bootloader, as the install is to a directory");
+        Ok(())
+    }
+}
@@ -0,0 +1,197 @@
+use crate::errors::IoContext;
+use std::{
+    fs,
+    io::{self, Read},
+    path::{Path, PathBuf},
+};
+
+/// The suffix of the EFI binaries of the architecture, as in `shimx64.efi`.
+pub(crate) fn efi_arch(arch: &str) -> Option<&'static str> {
+    match arch {
+        "x86_64" => Some("x64"),
+        "x86" | "i686" => Some("ia32"),
+        "aarch64" => Some("aa64"),
+        _ => None,
+    }
+}
+
+/// Finds the architecture of the image at `root` from the ELF header of its `/usr/bin/env`,
+/// as the suffix of its EFI binaries.
+pub(crate) fn target_arch(root: &Path) -> Option<&'static str> {
+    let mut header = [0; 20];
+    let mut file = fs::File::open(root.join("usr/bin/env")).ok()?;
+    file.read_exact(&mut header).ok()?;
+    elf_efi_arch(&header)
+}
+
+/// The suffix of the EFI binaries of the architecture of an ELF header.
+pub(crate) fn elf_efi_arch(header: &[u8]) -> Option<&'static str> {
+    match elf_machine(header)? {
+        0x03 => Some("ia32"),
+        0x3e => Some("x64"),
+        0xb7 => Some("aa64"),
+        _ => None,
+    }
+}
+
+/// The machine of an ELF header, such as `0x3e` for x86-64.
+pub(crate) fn elf_machine(header: &[u8]) -> Option<u16> {
+    if header.len() < 20 || header[..4] != *b"\x7fELF" {
+        return None;
+    }
+
+    let machine = [header[18], header[19]];
+    match header[5] {
+        1 => Some(u16::from_le_bytes(machine)),
+        2 => Some(u16::from_be_bytes(machine)),
+        _ => None,
+    }
+}
+
+/// The removable media path of the ESP, which firmware boots when it has no boot entries.
+pub(crate) fn fallback_path(arch: &str) -> String {
+    ["EFI/BOOT/BOOT", &arch.to_ascii_uppercase(), ".EFI"].concat()
+}
+
+/// The loader which boots the install, relative to the ESP: shim if it was installed
+/// beside GRUB, or else GRUB itself.
+pub(crate) fn grub_loader(esp: &Path, name: &str, arch: &str) -> PathBuf {
+    let dir = Path::new("EFI").join(name);
+    let shim = dir.join(["shim", arch, ".efi"].concat());
+    if esp.join(&shim).exists() {
+        shim
+    } else {
+        dir.join(["grub", arch, ".efi"].concat())
+    }
+}
+
+/// The loader of systemd-boot, relative to the ESP, as `bootctl` installs it.
+pub(crate) fn systemd_boot_loader(arch: &str) -> PathBuf {
+    Path::new("EFI/systemd").join(["systemd-boot", arch, ".efi"].concat())
+}
+
+/// Copies the `loader` of the ESP at `esp` to its fallback path, unless the loader there
+/// belongs to another OS, which only `force` replaces. It is ours if it is a copy of a
+/// binary beside the `loader`, as `bootctl` installs. Shim is copied with the binaries
+/// which it loads from its own directory.
+pub(crate) fn install(esp: &Path, loader: &Path, arch: &str, force: bool) -> io::Result<()> {
+    let source = esp.join(loader);
+    let fallback = fallback_path(arch);
+    let target = esp.join(&fallback);
+
+    if !force && target.exists() && !is_copy_of_sibling(&target, &source)? {
+        info!("/{} belongs to another OS, so it is not replaced", fallback);
+        return Ok(());
+    }
+
+    let dir = esp.join("EFI/BOOT");
+    fs::create_dir_all(&dir)?;
+    copy(&source, &target)?;
+
+    let is_shim =
+        loader.file_name().map_or(false, |file| file.to_string_lossy().starts_with("shim"));
+    if is_shim {
+        for binary in &["grub", "mm"] {
+            let binary = [binary, arch, ".efi"].concat();
+            let source = source.with_file_name(&binary);
+            if source.exists() {
+                copy(&source, &dir.join(&binary))?;
+            }
+        }
+    }
+
+    info!("installed /{} as the fallback loader at /{}", loader.display(), fallback);
+    Ok(())
+}
+
+fn is_copy_of_sibling(target: &Path, loader: &Path) -> io::Result<bool> {
+    let contents = fs::read(target)?;
+    let dir = match loader.parent() {
+        Some(dir) => dir,
+        None => return Ok(false),
+    };
+
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_file() && fs::read(&path)? == contents {
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
+
+fn copy(source: &Path, target: &Path) -> io::Result<()> {
+    fs::copy(source, target).map(|_| ()).with_context(|err| {
+        format!("failed to copy {} to {}: {}", source.display(), target.display(), err)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn paths() {
+        assert_eq!(efi_arch("x86_64").map(fallback_path).unwrap(), "EFI/BOOT/BOOTX64.EFI");
+        assert_eq!(efi_arch("aarch64").map(fallback_path).unwrap(), "EFI/BOOT/BOOTAA64.EFI");
+        assert_eq!(efi_arch("x86").map(fallback_path).unwrap(), "EFI/BOOT/BOOTIA32.EFI");
+        assert_eq!(efi_arch("powerpc64"), None);
+    }
+
+    #[test]
+    fn fallback() {
+        let esp = TempDir::new("distinst").unwrap();
+        let esp = esp.path();
+        fs::create_dir_all(esp.join("EFI/Pop_OS")).unwrap();
+        fs::write(esp.join("EFI/Pop_OS/grubx64.efi"), "grub").unwrap();
+        assert_eq!(grub_loader(esp, "Pop_OS", "x64"), Path::new("EFI/Pop_OS/grubx64.efi"));
+
+        fs::write(esp.join("EFI/Pop_OS/shimx64.efi"), "shim").unwrap();
+        let shim = grub_loader(esp, "Pop_OS", "x64");
+        assert_eq!(shim, Path::new("EFI/Pop_OS/shimx64.efi"));
+
+        install(esp, &shim, "x64", false).unwrap();
+        assert_eq!(fs::read_to_string(esp.join("EFI/BOOT/BOOTX64.EFI")).unwrap(), "shim");
+        assert_eq!(fs::read_to_string(esp.join("EFI/BOOT/grubx64.efi")).unwrap(), "grub");
+
+        // A copy of a binary beside the loader is ours, which bootctl installs.
+        fs::write(esp.join("EFI/BOOT/BOOTX64.EFI"), "grub").unwrap();
+        install(esp, &shim, "x64", false).unwrap();
+        assert_eq!(fs::read_to_string(esp.join("EFI/BOOT/BOOTX64.EFI")).unwrap(), "shim");
+
+        // The loader of another OS is kept, unless it is forced.
+        fs::write(esp.join("EFI/BOOT/BOOTX64.EFI"), "windows").unwrap();
+        install(esp, &shim, "x64", false).unwrap();
+        assert_eq!(fs::read_to_string(esp.join("EFI/BOOT/BOOTX64.EFI")).unwrap(), "windows");
+        install(esp, &shim, "x64", true).unwrap();
+        assert_eq!(fs::read_to_string(esp.join("EFI/BOOT/BOOTX64.EFI")).unwrap(), "shim");
+    }
+
+    #[test]
+    fn aarch64() {
+        let root = TempDir::new("distinst").unwrap();
+        let root = root.path();
+        assert_eq!(target_arch(root), None);
+
+        // The header of a 64-bit little-endian ELF executable for aarch64.
+        let mut elf = b"\x7fELF\x02\x01\x01".to_vec();
+        elf.resize(18, 0);
+        elf.extend_from_slice(&[0xb7, 0x00, 0x01, 0x00, 0x00, 0x00]);
+        fs::create_dir_all(root.join("usr/bin")).unwrap();
+        fs::write(root.join("usr/bin/env"), &elf).unwrap();
+        let arch = target_arch(root).unwrap();
+        assert_eq!(arch, "aa64");
+
+        let esp = root.join("boot/efi");
+        fs::create_dir_all(esp.join("EFI/Pop_OS")).unwrap();
+        fs::write(esp.join("EFI/Pop_OS/grubaa64.efi"), "grub").unwrap();
+        let grub = grub_loader(&esp, "Pop_OS", arch);
+        assert_eq!(grub, Path::new("EFI/Pop_OS/grubaa64.efi"));
+        assert_eq!(systemd_boot_loader(arch), Path::new("EFI/systemd/systemd-bootaa64.efi"));
+
+        install(&esp, &grub, arch, false).unwrap();
+        assert_eq!(fs::read_to_string(esp.join("EFI/BOOT/BOOTAA64.EFI")).unwrap(), "grub");
+    }
+}
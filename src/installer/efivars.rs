@@ -0,0 +1,742 @@
+use super::steps::BootOrderPolicy;
+use crate::errors::{IntoIoResult, IoContext};
+use libc;
+use partition_identity::PartitionID;
+use std::{
+    ffi::CString,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    mem,
+    os::unix::{ffi::OsStrExt, io::AsRawFd},
+    path::{Path, PathBuf},
+};
+
+/// Where the kernel mounts efivarfs.
+pub(crate) const EFIVARS: &str = "/sys/firmware/efi/efivars";
+
+/// The GUID which the variables of the boot manager are defined under.
+const GLOBAL_VARIABLE: &str = "8be4df61-93ca-11d2-aa0d-00e098032b8c";
+
+/// `f_type` of efivarfs, as given by `statfs`.
+const EFIVARFS_MAGIC: u32 = 0xde5e_81e4;
+
+/// Non-volatile, and accessible to both the boot services and the OS.
+const ATTRIBUTES: u32 = 0x7;
+
+/// The entry may be booted.
+const LOAD_OPTION_ACTIVE: u32 = 0x1;
+
+/// The loader of the Windows Boot Manager on the ESP.
+pub(crate) const WINDOWS_LOADER: &str = "\\EFI\\Microsoft\\Boot\\bootmgfw.efi";
+
+/// The kernel makes most variables immutable, so that they aren't removed by accident.
+const FS_IMMUTABLE_FL: libc::c_int = 0x10;
+
+#[cfg(target_pointer_width = "64")]
+const FS_IOC_GETFLAGS: libc::c_ulong = 0x8008_6601;
+#[cfg(target_pointer_width = "64")]
+const FS_IOC_SETFLAGS: libc::c_ulong = 0x4008_6602;
+#[cfg(target_pointer_width = "32")]
+const FS_IOC_GETFLAGS: libc::c_ulong = 0x8004_6601;
+#[cfg(target_pointer_width = "32")]
+const FS_IOC_SETFLAGS: libc::c_ulong = 0x4004_6602;
+
+/// How the firmware identifies the partition that an entry boots from.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Signature {
+    /// The signature of the MBR disk that the partition is on.
+    Mbr([u8; 4]),
+    /// The unique GUID of the GPT partition.
+    Gpt([u8; 16]),
+}
+
+impl Signature {
+    /// The signature of the partition with this PARTUUID, which is either a GUID, or the
+    /// signature of an MBR disk followed by the number of the partition.
+    pub(crate) fn from_partuuid(partuuid: &str) -> Option<Self> {
+        let hex = partuuid.replace('-', "");
+        let mut bytes = (0..hex.len())
+            .step_by(2)
+            .map(|pos| hex.get(pos..pos + 2).and_then(|byte| u8::from_str_radix(byte, 16).ok()))
+            .collect::<Option<Vec<u8>>>()?;
+
+        match (partuuid.len(), bytes.len()) {
+            // The first three fields of a GUID are stored in little endian.
+            (36, 16) => {
+                bytes[..4].reverse();
+                bytes[4..6].reverse();
+                bytes[6..8].reverse();
+                let mut guid = [0; 16];
+                guid.copy_from_slice(&bytes);
+                Some(Signature::Gpt(guid))
+            }
+            (11, 5) => Some(Signature::Mbr([bytes[3], bytes[2], bytes[1], bytes[0]])),
+            _ => None,
+        }
+    }
+}
+
+/// The hard drive node of a device path, which locates the partition on any disk.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct HardDrive {
+    pub number:    u32,
+    /// The first block of the partition.
+    pub start:     u64,
+    /// The number of blocks of the partition.
+    pub size:      u64,
+    pub signature: Signature,
+}
+
+/// The contents of a `Boot####` variable.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct LoadOption {
+    pub attributes:  u32,
+    /// The label which the boot menu shows.
+    pub description: String,
+    /// The partition which the loader is on, if the entry boots from a partition.
+    pub partition:   Option<HardDrive>,
+    /// The path of the loader on the partition, such as `\EFI\BOOT\BOOTX64.EFI`.
+    pub loader:      Option<String>,
+}
+
+impl LoadOption {
+    /// Parses an `EFI_LOAD_OPTION`, whose device path is only read for the nodes which
+    /// locate a file on a partition.
+    pub(crate) fn parse(data: &[u8]) -> Option<Self> {
+        let attributes = le_u32(data, 0)?;
+        let path_len = le_u16(data, 4)? as usize;
+
+        let (description, mut pos) = utf16(data, 6)?;
+        let end = pos.checked_add(path_len).filter(|&end| end <= data.len())?;
+
+        let (mut partition, mut loader) = (None, None);
+        while pos + 4 <= end {
+            let (kind, subtype) = (data[pos], data[pos + 1]);
+            let len = le_u16(data, pos + 2)? as usize;
+            if len < 4 || pos + len > end || kind == 0x7f {
+                break;
+            }
+
+            let node = &data[pos..pos + len];
+            match (kind, subtype) {
+                (4, 1) if len >= 42 => {
+                    let signature = match node[41] {
+                        1 => Signature::Mbr([node[24], node[25], node[26], node[27]]),
+                        2 => {
+                            let mut guid = [0; 16];
+                            guid.copy_from_slice(&node[24..40]);
+                            Signature::Gpt(guid)
+                        }
+                        _ => {
+                            pos += len;
+                            continue;
+                        }
+                    };
+
+                    partition = Some(HardDrive {
+                        number: le_u32(node, 4)?,
+                        start: le_u64(node, 8)?,
+                        size: le_u64(node, 16)?,
+                        signature,
+                    });
+                }
+                (4, 4) => loader = utf16(node, 4).map(|(path, _)| path),
+                _ => (),
+            }
+
+            pos += len;
+        }
+
+        Some(LoadOption { attributes, description, partition, loader })
+    }
+
+    /// Whether the entry boots the Windows Boot Manager, whatever its label.
+    pub(crate) fn is_windows(&self) -> bool {
+        self.loader.as_ref().map_or(false, |loader| loader.eq_ignore_ascii_case(WINDOWS_LOADER))
+    }
+
+    /// Encodes the option as an `EFI_LOAD_OPTION`, with a device path of its partition and
+    /// loader.
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut path = Vec::new();
+        if let Some(ref partition) = self.partition {
+            let (signature, mbr_type, signature_type) = match partition.signature {
+                Signature::Mbr(signature) => {
+                    let mut bytes = [0; 16];
+                    bytes[..4].copy_from_slice(&signature);
+                    (bytes, 1, 1)
+                }
+                Signature::Gpt(guid) => (guid, 2, 2),
+            };
+
+            path.extend_from_slice(&[4, 1, 42, 0]);
+            path.extend_from_slice(&partition.number.to_le_bytes());
+            path.extend_from_slice(&partition.start.to_le_bytes());
+            path.extend_from_slice(&partition.size.to_le_bytes());
+            path.extend_from_slice(&signature);
+            path.extend_from_slice(&[mbr_type, signature_type]);
+        }
+
+        if let Some(ref loader) = self.loader {
+            let loader = encode_utf16(loader);
+            path.extend_from_slice(&[4, 4]);
+            path.extend_from_slice(&(loader.len() as u16 + 4).to_le_bytes());
+            path.extend_from_slice(&loader);
+        }
+
+        // The end of the device path.
+        path.extend_from_slice(&[0x7f, 0xff, 4, 0]);
+
+        let mut data = self.attributes.to_le_bytes().to_vec();
+        data.extend_from_slice(&(path.len() as u16).to_le_bytes());
+        data.extend_from_slice(&encode_utf16(&self.description));
+        data.extend_from_slice(&path);
+        data
+    }
+}
+
+/// The variables of the firmware, in efivarfs.
+pub(crate) struct EfiVars {
+    dir:       PathBuf,
+    /// Whether efivarfs is mounted read-only, so that no variable can be written.
+    read_only: bool,
+    /// The flags that efivarfs is mounted with.
+    flags:     u64,
+}
+
+impl EfiVars {
+    /// Opens the variables at `dir`, if efivarfs is mounted there.
+    pub(crate) fn open(dir: &Path) -> Option<Self> {
+        let path = CString::new(dir.as_os_str().as_bytes()).ok()?;
+        let mut stat: libc::statfs = unsafe { mem::zeroed() };
+        if unsafe { libc::statfs(path.as_ptr(), &mut stat) } != 0
+            || stat.f_type as u32 != EFIVARFS_MAGIC
+        {
+            return None;
+        }
+
+        let flags = stat.f_flags as u64;
+        let read_only = flags & libc::ST_RDONLY as u64 != 0;
+        Some(EfiVars { dir: dir.to_owned(), read_only, flags })
+    }
+
+    /// Whether efivarfs is mounted read-only.
+    pub(crate) fn read_only(&self) -> bool { self.read_only }
+
+    /// The `ST_*` flags that efivarfs is mounted with, which match their `MS_*` flags.
+    pub(crate) fn mount_flags(&self) -> u64 { self.flags }
+
+    /// The `Boot####` entries, by their number.
+    pub(crate) fn boot_entries(&self) -> io::Result<Vec<(u16, LoadOption)>> {
+        let suffix = ["-", GLOBAL_VARIABLE].concat();
+        let mut entries = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let file_name = entry?.file_name();
+            let name = match file_name.to_str().and_then(|name| name.strip_suffix(&suffix)) {
+                Some(name) => name,
+                None => continue,
+            };
+
+            let number = match boot_number(name) {
+                Some(number) => number,
+                None => continue,
+            };
+
+            if let Some(option) = self.read(name)?.and_then(|data| LoadOption::parse(&data)) {
+                entries.push((number, option));
+            }
+        }
+
+        entries.sort_by_key(|&(number, _)| number);
+        Ok(entries)
+    }
+
+    /// The entries of the `BootOrder`, in the order which the firmware tries them.
+    pub(crate) fn boot_order(&self) -> io::Result<Vec<u16>> {
+        Ok(self.read("BootOrder")?.map_or_else(Vec::new, |order| decode_order(&order)))
+    }
+
+    /// Whether the firmware booted with Secure Boot enforced.
+    pub(crate) fn secure_boot(&self) -> io::Result<bool> {
+        Ok(self.read("SecureBoot")?.map_or(false, |data| data.first() == Some(&1)))
+    }
+
+    /// Replaces the entries named `label` which boot from one of the partitions of `disk`, or
+    /// from a GPT partition which no longer exists on the system, with an entry that boots
+    /// `loader` from `partition`, which is placed in the boot order
+    /// by `policy`. The entries of other OSes are never removed, and keep their order, except
+    /// that the Windows Boot Manager is booted second if `windows_second` and the install is
+    /// booted first. The number of the new entry is returned, or `None` if the variables are
+    /// read-only, in which case the entry is only logged.
+    pub(crate) fn replace_entry(
+        &self,
+        label: &str,
+        partition: HardDrive,
+        disk: &[Signature],
+        loader: &str,
+        windows_second: bool,
+        policy: BootOrderPolicy,
+    ) -> io::Result<Option<u16>> {
+        if self.read_only {
+            warn!(
+                "the EFI variables are read-only, so no boot entry was made for {}, which boots \
+                 {} from partition {}",
+                label, loader, partition.number
+            );
+            return Ok(None);
+        }
+
+        let entries = self.boot_entries()?;
+        let stale = stale_entries(&entries, label, disk, present_partitions().as_deref());
+
+        for &number in &stale {
+            info!("removing the stale boot entry {}", boot_name(number));
+            self.delete(&boot_name(number))?;
+        }
+
+        let number = (0..=u16::MAX)
+            .find(|number| {
+                stale.contains(number) || !entries.iter().any(|&(other, _)| other == *number)
+            })
+            .into_io_result(|| "there are no free boot entries")?;
+
+        let option = LoadOption {
+            attributes:  LOAD_OPTION_ACTIVE,
+            description: label.to_owned(),
+            partition:   Some(partition),
+            loader:      Some(loader.to_owned()),
+        };
+
+        self.write(&boot_name(number), &option.encode())?;
+
+        let windows = entries.iter().find(|(_, option)| option.is_windows()).map(|&(n, _)| n);
+        let windows = windows.filter(|_| windows_second);
+
+        // The attributes which the firmware gave the order are kept.
+        let (attributes, existing) = match self.read_variable("BootOrder")? {
+            Some((attributes, order)) => (attributes, decode_order(&order)),
+            None => (ATTRIBUTES, Vec::new()),
+        };
+
+        let order = boot_order(number, &existing, &stale, windows, policy);
+        let data = order.iter().flat_map(|entry| entry.to_le_bytes().to_vec()).collect::<Vec<u8>>();
+        match self.write_variable("BootOrder", attributes, &data) {
+            Err(ref why) if is_read_only(why) => {
+                let order = order.iter().map(|&entry| format!("{:04X}", entry)).collect::<Vec<_>>();
+                warn!(
+                    "the firmware refused to change the BootOrder, which should be {}: {}",
+                    order.join(","),
+                    why
+                );
+            }
+            result => result.with_context(|err| format!("failed to write BootOrder: {}", err))?,
+        }
+
+        Ok(Some(number))
+    }
+
+    fn path(&self, name: &str) -> PathBuf { self.dir.join([name, "-", GLOBAL_VARIABLE].concat()) }
+
+    /// Reads the data of a variable, without its attributes.
+    fn read(&self, name: &str) -> io::Result<Option<Vec<u8>>> {
+        Ok(self.read_variable(name)?.map(|(_, data)| data))
+    }
+
+    /// Reads the attributes and the data of a variable.
+    fn read_variable(&self, name: &str) -> io::Result<Option<(u32, Vec<u8>)>> {
+        match fs::read(self.path(name)) {
+            Ok(data) => Ok(le_u32(&data, 0).map(|attributes| (attributes, data[4..].to_vec()))),
+            Err(ref why) if why.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(why) => Err(why).with_context(|err| format!("failed to read {}: {}", name, err)),
+        }
+    }
+
+    /// Replaces a variable, with the attributes of the boot manager.
+    fn write(&self, name: &str, data: &[u8]) -> io::Result<()> {
+        self.write_variable(name, ATTRIBUTES, data)
+            .with_context(|err| format!("failed to write {}: {}", name, err))
+    }
+
+    /// Replaces a variable, whose attributes and data are written at once, as efivarfs
+    /// requires.
+    fn write_variable(&self, name: &str, attributes: u32, data: &[u8]) -> io::Result<()> {
+        let path = self.path(name);
+        if path.exists() {
+            clear_immutable(&path)?;
+        }
+
+        let mut contents = attributes.to_le_bytes().to_vec();
+        contents.extend_from_slice(data);
+        OpenOptions::new()
+            .write(true)
+            .create(true)
+            .open(&path)
+            .and_then(|mut file| file.write_all(&contents))
+    }
+
+    fn delete(&self, name: &str) -> io::Result<()> {
+        let path = self.path(name);
+        clear_immutable(&path)?;
+        fs::remove_file(&path).with_context(|err| format!("failed to remove {}: {}", name, err))
+    }
+}
+
+/// Warns before the disks are modified if the variables at `dir` can't be written. The
+/// install will try to remount them read-write, and otherwise its boot entry is only logged.
+pub(crate) fn warn_if_read_only(dir: &Path) {
+    match EfiVars::open(dir) {
+        Some(ref vars) if !vars.read_only => (),
+        Some(_) => warn!(
+            "the EFI variables at {} are read-only: if they can't be remounted read-write, the \
+             boot entry of the install will be logged instead of written",
+            dir.display()
+        ),
+        None => warn!(
+            "the EFI variables are not mounted at {}, so no boot entry will be written",
+            dir.display()
+        ),
+    }
+}
+
+/// The `BootOrder` with the entry `number` placed by `policy` among the `existing` entries
+/// which are not `stale`, which keep their order. If the entry is booted first, the
+/// `windows` entry is moved to be second.
+pub(crate) fn boot_order(
+    number: u16,
+    existing: &[u16],
+    stale: &[u16],
+    windows: Option<u16>,
+    policy: BootOrderPolicy,
+) -> Vec<u16> {
+    let kept = |entry: &&u16| **entry != number && !stale.contains(entry);
+    match policy {
+        BootOrderPolicy::First => {
+            let first = Some(number).into_iter().chain(windows.filter(|&w| w != number));
+            let first = first.collect::<Vec<u16>>();
+            let rest = existing.iter().filter(kept).filter(|entry| !first.contains(entry));
+            first.iter().chain(rest).cloned().collect()
+        }
+        BootOrderPolicy::Last => {
+            existing.iter().filter(kept).cloned().chain(Some(number)).collect()
+        }
+        BootOrderPolicy::Preserve => {
+            // No entry before the one which is replaced is removed.
+            let replaced = existing.iter().position(|entry| !kept(&entry));
+            let mut order = existing.iter().filter(kept).cloned().collect::<Vec<u16>>();
+            order.insert(replaced.unwrap_or_else(|| order.len()), number);
+            order
+        }
+    }
+}
+
+/// The lines of the report of the boot entries, in the style of `efibootmgr`: the
+/// `BootOrder`, and then each entry with whether it is active, its label, and its loader.
+pub(crate) fn report(entries: &[(u16, LoadOption)], order: &[u16]) -> Vec<String> {
+    let order = order.iter().map(|&number| format!("{:04X}", number)).collect::<Vec<_>>();
+    let mut lines = vec![["BootOrder: ", &order.join(",")].concat()];
+    lines.extend(entries.iter().map(|(number, option)| {
+        format!(
+            "{}{} {}\t{}",
+            boot_name(*number),
+            if option.attributes & LOAD_OPTION_ACTIVE != 0 { "*" } else { " " },
+            option.description,
+            option.loader.as_ref().map_or("-", String::as_str)
+        )
+    }));
+
+    lines
+}
+
+/// The entries named `label` which boot from one of the partitions of `disk`, or from a GPT
+/// partition which is not `present` on the system, if the partitions of the system are known.
+pub(crate) fn stale_entries(
+    entries: &[(u16, LoadOption)],
+    label: &str,
+    disk: &[Signature],
+    present: Option<&[Signature]>,
+) -> Vec<u16> {
+    let stale = |signature: &Signature| {
+        disk.contains(signature)
+            || match (signature, present) {
+                (Signature::Gpt(_), Some(present)) => !present.contains(signature),
+                _ => false,
+            }
+    };
+
+    entries
+        .iter()
+        .filter(|(_, option)| {
+            option.description == label
+                && !option.is_windows()
+                && option.partition.map_or(false, |part| stale(&part.signature))
+        })
+        .map(|&(number, _)| number)
+        .collect()
+}
+
+/// The signatures of the partitions on the system, as udev links them by their PARTUUID.
+/// Nothing is known of them if udev has not made the links.
+fn present_partitions() -> Option<Vec<Signature>> {
+    let links = fs::read_dir("/dev/disk/by-partuuid").ok()?;
+    let signatures = links
+        .filter_map(Result::ok)
+        .filter_map(|link| link.file_name().into_string().ok())
+        .filter_map(|partuuid| Signature::from_partuuid(&partuuid))
+        .collect::<Vec<_>>();
+
+    if signatures.is_empty() {
+        None
+    } else {
+        Some(signatures)
+    }
+}
+
+/// Locates the partition `number` of `disk` from sysfs, and finds the signatures of each
+/// partition of the disk.
+pub(crate) fn partition(disk: &Path, number: u32) -> io::Result<(HardDrive, Vec<Signature>)> {
+    let name = disk.file_name().into_io_result(|| format!("{} is not a disk", disk.display()))?;
+    let sys = Path::new("/sys/class/block").join(name);
+
+    // Sysfs counts 512-byte sectors, whereas EFI counts logical blocks.
+    let block_size = fs::read_to_string(sys.join("queue/logical_block_size"))
+        .ok()
+        .and_then(|size| size.trim().parse::<u64>().ok())
+        .filter(|&size| size >= 512)
+        .unwrap_or(512);
+    let read_sectors = |path: PathBuf| {
+        fs::read_to_string(&path)
+            .ok()
+            .and_then(|value| value.trim().parse::<u64>().ok())
+            .map(|sectors| sectors * 512 / block_size)
+            .into_io_result(|| format!("failed to read {}", path.display()))
+    };
+
+    let mut found = None;
+    let mut signatures = Vec::new();
+    for entry in fs::read_dir(&sys)? {
+        let entry = entry?;
+        let part_number = match fs::read_to_string(entry.path().join("partition")) {
+            Ok(part_number) => part_number.trim().parse::<u32>().ok(),
+            Err(_) => continue,
+        };
+
+        let device = Path::new("/dev").join(entry.file_name());
+        let signature = PartitionID::get_partuuid(&device)
+            .and_then(|partuuid| Signature::from_partuuid(&partuuid.id));
+        let signature = match signature {
+            Some(signature) => signature,
+            None => continue,
+        };
+
+        signatures.push(signature);
+        if part_number == Some(number) {
+            found = Some(HardDrive {
+                number,
+                start: read_sectors(entry.path().join("start"))?,
+                size: read_sectors(entry.path().join("size"))?,
+                signature,
+            });
+        }
+    }
+
+    let partition = found.into_io_result(|| {
+        format!("unable to find partition {} of {} and its PARTUUID", number, disk.display())
+    })?;
+
+    Ok((partition, signatures))
+}
+
+fn boot_name(number: u16) -> String { format!("Boot{:04X}", number) }
+
+fn decode_order(order: &[u8]) -> Vec<u16> {
+    order.chunks_exact(2).map(|entry| u16::from_le_bytes([entry[0], entry[1]])).collect()
+}
+
+/// Whether the variable could not be written because the variables are read-only, or the
+/// firmware refused it.
+fn is_read_only(why: &io::Error) -> bool {
+    why.kind() == io::ErrorKind::PermissionDenied || why.raw_os_error() == Some(libc::EROFS)
+}
+
+fn boot_number(name: &str) -> Option<u16> {
+    name.strip_prefix("Boot")
+        .filter(|hex| hex.len() == 4 && hex.chars().all(|c| c.is_ascii_hexdigit()))
+        .and_then(|hex| u16::from_str_radix(hex, 16).ok())
+}
+
+fn clear_immutable(path: &Path) -> io::Result<()> {
+    let file = File::open(path)?;
+    let mut flags: libc::c_int = 0;
+    unsafe {
+        if libc::ioctl(file.as_raw_fd(), FS_IOC_GETFLAGS as _, &mut flags) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        if flags & FS_IMMUTABLE_FL != 0 {
+            flags &= !FS_IMMUTABLE_FL;
+            if libc::ioctl(file.as_raw_fd(), FS_IOC_SETFLAGS as _, &flags) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn le_u16(data: &[u8], pos: usize) -> Option<u16> {
+    data.get(pos..pos + 2).map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]))
+}
+
+fn le_u32(data: &[u8], pos: usize) -> Option<u32> {
+    Some(u32::from(le_u16(data, pos)?) | u32::from(le_u16(data, pos + 2)?) << 16)
+}
+
+fn le_u64(data: &[u8], pos: usize) -> Option<u64> {
+    Some(u64::from(le_u32(data, pos)?) | u64::from(le_u32(data, pos + 4)?) << 32)
+}
+
+/// Reads a null-terminated UTF-16 string, and the position after it.
+fn utf16(data: &[u8], pos: usize) -> Option<(String, usize)> {
+    let mut units = Vec::new();
+    let mut pos = pos;
+    loop {
+        let unit = le_u16(data, pos)?;
+        pos += 2;
+        if unit == 0 {
+            break;
+        }
+
+        units.push(unit);
+    }
+
+    Some((String::from_utf16_lossy(&units), pos))
+}
+
+/// Encodes a null-terminated UTF-16 string.
+fn encode_utf16(string: &str) -> Vec<u8> {
+    string.encode_utf16().chain(Some(0)).flat_map(|unit| unit.to_le_bytes().to_vec()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn partuuids() {
+        assert_eq!(
+            Signature::from_partuuid("c12a7328-f81f-11d2-ba4b-00a0c93ec93b"),
+            Some(Signature::Gpt([
+                0x28, 0x73, 0x2a, 0xc1, 0x1f, 0xf8, 0xd2, 0x11, 0xba, 0x4b, 0x00, 0xa0, 0xc9, 0x3e,
+                0xc9, 0x3b
+            ]))
+        );
+        assert_eq!(
+            Signature::from_partuuid("1234abcd-01"),
+            Some(Signature::Mbr([0xcd, 0xab, 0x34, 0x12]))
+        );
+        assert_eq!(Signature::from_partuuid("not-a-partuuid"), None);
+        assert_eq!(boot_number("Boot000A"), Some(10));
+        assert_eq!(boot_number("BootOrder"), None);
+    }
+
+    #[test]
+    fn load_options() {
+        let option = LoadOption {
+            attributes:  LOAD_OPTION_ACTIVE,
+            description: "Pop!_OS".into(),
+            partition:   Some(HardDrive {
+                number:    1,
+                start:     2048,
+                size:      1_048_576,
+                signature: Signature::from_partuuid("c12a7328-f81f-11d2-ba4b-00a0c93ec93b")
+                    .unwrap(),
+            }),
+            loader:      Some("\\EFI\\systemd\\systemd-bootx64.efi".into()),
+        };
+
+        let data = option.encode();
+        // The attributes, the length of the device path, and the label.
+        assert_eq!(&data[..6], &[1, 0, 0, 0, 116, 0]);
+        assert_eq!(&data[6..10], &[b'P', 0, b'o', 0]);
+        assert_eq!(&data[data.len() - 4..], &[0x7f, 0xff, 4, 0]);
+        assert_eq!(LoadOption::parse(&data), Some(option));
+
+        // Entries which boot from other devices have no partition.
+        let mut network = LoadOption::parse(&data).unwrap();
+        network.partition = None;
+        network.loader = None;
+        assert_eq!(LoadOption::parse(&network.encode()), Some(network));
+    }
+
+    #[test]
+    fn windows_second() {
+        let windows = LoadOption {
+            attributes:  LOAD_OPTION_ACTIVE,
+            description: "Windows Boot Manager".into(),
+            partition:   None,
+            loader:      Some("\\EFI\\MICROSOFT\\BOOT\\BOOTMGFW.EFI".into()),
+        };
+        assert!(windows.is_windows());
+
+        // The stale entry 2 is dropped, and the others keep their order.
+        let first = BootOrderPolicy::First;
+        assert_eq!(boot_order(4, &[2, 0, 3, 1], &[2], None, first), vec![4, 0, 3, 1]);
+        assert_eq!(boot_order(4, &[2, 0, 3, 1], &[2], Some(3), first), vec![4, 3, 0, 1]);
+        assert_eq!(boot_order(2, &[2, 0, 3], &[2], Some(3), first), vec![2, 3, 0]);
+
+        let network = LoadOption { attributes: 0, description: "PXE".into(), ..windows.clone() };
+        let network = LoadOption { loader: None, ..network };
+        assert_eq!(
+            report(&[(0, windows), (10, network)], &[10, 0]),
+            vec![
+                "BootOrder: 000A,0000",
+                "Boot0000* Windows Boot Manager\t\\EFI\\MICROSOFT\\BOOT\\BOOTMGFW.EFI",
+                "Boot000A  PXE\t-",
+            ]
+        );
+    }
+
+    #[test]
+    fn stale() {
+        let signature = |partuuid| Signature::from_partuuid(partuuid).unwrap();
+        let (esp, gone, other) = (
+            signature("c12a7328-f81f-11d2-ba4b-00a0c93ec93b"),
+            signature("0fc63daf-8483-4772-8e79-3d69d8477de4"),
+            signature("1234abcd-01"),
+        );
+        let entry = |description: &str, signature| LoadOption {
+            attributes:  LOAD_OPTION_ACTIVE,
+            description: description.into(),
+            partition:   Some(HardDrive { number: 1, start: 2048, size: 1_048_576, signature }),
+            loader:      Some("\\EFI\\systemd\\systemd-bootx64.efi".into()),
+        };
+
+        let entries = [
+            (0, entry("Pop!_OS", esp)),
+            (1, entry("Pop!_OS", gone)),
+            (2, entry("Pop!_OS", other)),
+            (3, entry("Ubuntu", gone)),
+        ];
+
+        // The entry of a partition which is gone is only known to be stale if the partitions
+        // of the system are known.
+        assert_eq!(stale_entries(&entries, "Pop!_OS", &[esp], None), vec![0]);
+        assert_eq!(stale_entries(&entries, "Pop!_OS", &[esp], Some(&[esp, other][..])), vec![0, 1]);
+        assert_eq!(stale_entries(&entries, "Pop!_OS", &[], Some(&[esp][..])), vec![1]);
+    }
+
+    #[test]
+    fn boot_order_policy() {
+        // Network boot, a stale entry of the install, and another OS.
+        let existing = [10, 2, 0];
+        let order = |number, policy| boot_order(number, &existing, &[2], Some(0), policy);
+        assert_eq!(order(4, BootOrderPolicy::First), vec![4, 0, 10]);
+        assert_eq!(order(4, BootOrderPolicy::Last), vec![10, 0, 4]);
+        assert_eq!(order(4, BootOrderPolicy::Preserve), vec![10, 4, 0]);
+        assert_eq!(order(2, BootOrderPolicy::Preserve), vec![10, 2, 0]);
+        assert_eq!(
+            boot_order(4, &existing, &[], None, BootOrderPolicy::Preserve),
+            vec![10, 2, 0, 4]
+        );
+    }
+}
@@ -0,0 +1,481 @@
+// The derived implementations of `Error` match the deprecated `Error::Failed`.
+#![allow(deprecated)]
+
+use super::Step;
+use crate::{
+    disks::CatalogEntry,
+    misc::{self, ErrorContext},
+};
+use std::io;
+
+/// Installer error
+///
+/// Each step that may fail has its own variant, which carries the command or device that the
+/// step failed on, when it is known, and the error which caused it.
+#[derive(Debug, err_derive::Error)]
+pub enum Error {
+    #[error(display = "backing up the old install failed: {}", err)]
+    Backup {
+        context: ErrorContext,
+        #[error(source)]
+        err:     io::Error,
+    },
+    #[error(display = "initializing the install failed: {}", err)]
+    Init {
+        context: ErrorContext,
+        #[error(source)]
+        err:     io::Error,
+    },
+    #[error(display = "verifying the source failed: {}", err)]
+    Verify {
+        context: ErrorContext,
+        #[error(source)]
+        err:     io::Error,
+    },
+    #[error(display = "partitioning failed: {}", err)]
+    Partition {
+        context: ErrorContext,
+        #[error(source)]
+        err:     io::Error,
+    },
+    #[error(display = "extracting the image failed: {}", err)]
+    Extract {
+        context: ErrorContext,
+        #[error(source)]
+        err:     io::Error,
+    },
+    #[error(display = "creating the recovery partition failed: {}", err)]
+    Recovery {
+        context: ErrorContext,
+        #[error(source)]
+        err:     io::Error,
+    },
+    #[error(display = "configuring the install failed: {}", err)]
+    Configure {
+        context: ErrorContext,
+        #[error(source)]
+        err:     io::Error,
+    },
+    #[error(display = "installing the bootloader failed: {}", err)]
+    Bootloader {
+        context: ErrorContext,
+        #[error(source)]
+        err:     io::Error,
+    },
+    /// The kill switch was set during the step, and the install was torn down.
+    #[error(display = "install was cancelled during the {:?} step", step)]
+    Cancelled { step: Step },
+    /// A step failed. Errors of the installer are of the variant of their step, and this is
+    /// kept for frontends which emit their own errors.
+    #[deprecated(note = "use `Error::new`, which gives the variant of the step")]
+    #[error(display = "{:?} step failed: {}", step, err)]
+    Failed { step: Step, err: io::Error },
+}
+
+/// The context of a deprecated `Error::Failed`, which has none.
+static NO_CONTEXT: ErrorContext = ErrorContext { command: None, device: None, cause: None };
+
+/// Stable codes for each category of error, which are given to frontends over the FFI, and
+/// which will not change between releases.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ErrorCode {
+    Backup = 1,
+    Init = 2,
+    Verify = 3,
+    Partition = 4,
+    Extract = 5,
+    Recovery = 6,
+    Configure = 7,
+    Bootloader = 8,
+    Cancelled = 9,
+}
+
+/// The catalog of installer `Error` messages, whose codes are those of `ErrorCode`.
+///
+/// The command and device that a step failed on are given as the `command` and `device`
+/// parameters when they are known, which these messages do not include. So is the code and
+/// key of the cause, as `cause_code` and `cause_key`, if it is in a catalog such as
+/// `DISK_ERRORS`, along with the parameters of its message.
+pub const ERROR_CATALOG: &[CatalogEntry] = &[
+    CatalogEntry {
+        variant: "Backup",
+        code:    ErrorCode::Backup as u32,
+        key:     "installer.backup",
+        english: "backing up the old install failed: {cause}",
+    },
+    CatalogEntry {
+        variant: "Init",
+        code:    ErrorCode::Init as u32,
+        key:     "installer.init",
+        english: "initializing the install failed: {cause}",
+    },
+    CatalogEntry {
+        variant: "Verify",
+        code:    ErrorCode::Verify as u32,
+        key:     "installer.verify",
+        english: "verifying the source failed: {cause}",
+    },
+    CatalogEntry {
+        variant: "Partition",
+        code:    ErrorCode::Partition as u32,
+        key:     "installer.partition",
+        english: "partitioning failed: {cause}",
+    },
+    CatalogEntry {
+        variant: "Extract",
+        code:    ErrorCode::Extract as u32,
+        key:     "installer.extract",
+        english: "extracting the image failed: {cause}",
+    },
+    CatalogEntry {
+        variant: "Recovery",
+        code:    ErrorCode::Recovery as u32,
+        key:     "installer.recovery",
+        english: "creating the recovery partition failed: {cause}",
+    },
+    CatalogEntry {
+        variant: "Configure",
+        code:    ErrorCode::Configure as u32,
+        key:     "installer.configure",
+        english: "configuring the install failed: {cause}",
+    },
+    CatalogEntry {
+        variant: "Bootloader",
+        code:    ErrorCode::Bootloader as u32,
+        key:     "installer.bootloader",
+        english: "installing the bootloader failed: {cause}",
+    },
+    CatalogEntry {
+        variant: "Cancelled",
+        code:    ErrorCode::Cancelled as u32,
+        key:     "installer.cancelled",
+        english: "install was cancelled during the {step} step",
+    },
+];
+
+impl Error {
+    /// The error of a step that failed, with the command or device that it failed on.
+    pub fn new(step: Step, err: io::Error) -> Self {
+        let context = misc::error_context(&err).cloned().unwrap_or_default();
+        match step {
+            Step::Backup => Error::Backup { context, err },
+            Step::Init => Error::Init { context, err },
+            Step::Verify => Error::Verify { context, err },
+            Step::Partition => Error::Partition { context, err },
+            Step::Extract => Error::Extract { context, err },
+            Step::Recovery => Error::Recovery { context, err },
+            Step::Configure => Error::Configure { context, err },
+            Step::Bootloader => Error::Bootloader { context, err },
+        }
+    }
+
+    /// The step that was running when the install failed or was cancelled.
+    pub fn step(&self) -> Step {
+        match *self {
+            Error::Backup { .. } => Step::Backup,
+            Error::Init { .. } => Step::Init,
+            Error::Verify { .. } => Step::Verify,
+            Error::Partition { .. } => Step::Partition,
+            Error::Extract { .. } => Step::Extract,
+            Error::Recovery { .. } => Step::Recovery,
+            Error::Configure { .. } => Step::Configure,
+            Error::Bootloader { .. } => Step::Bootloader,
+            Error::Cancelled { step } | Error::Failed { step, .. } => step,
+        }
+    }
+
+    /// The stable code of the category of this error.
+    pub fn code(&self) -> ErrorCode {
+        match *self {
+            Error::Backup { .. } => ErrorCode::Backup,
+            Error::Init { .. } => ErrorCode::Init,
+            Error::Verify { .. } => ErrorCode::Verify,
+            Error::Partition { .. } => ErrorCode::Partition,
+            Error::Extract { .. } => ErrorCode::Extract,
+            Error::Recovery { .. } => ErrorCode::Recovery,
+            Error::Configure { .. } => ErrorCode::Configure,
+            Error::Bootloader { .. } => ErrorCode::Bootloader,
+            Error::Cancelled { .. } => ErrorCode::Cancelled,
+            Error::Failed { step, .. } => match step {
+                Step::Backup => ErrorCode::Backup,
+                Step::Init => ErrorCode::Init,
+                Step::Verify => ErrorCode::Verify,
+                Step::Partition => ErrorCode::Partition,
+                Step::Extract => ErrorCode::Extract,
+                Step::Recovery => ErrorCode::Recovery,
+                Step::Configure => ErrorCode::Configure,
+                Step::Bootloader => ErrorCode::Bootloader,
+            },
+        }
+    }
+
+    /// The key that translations of the message of the error are looked up by.
+    pub fn key(&self) -> &'static str { self.entry().key }
+
+    /// The entry of the error in `ERROR_CATALOG`.
+    pub fn entry(&self) -> &'static CatalogEntry {
+        CatalogEntry::find(ERROR_CATALOG, self.code() as u32)
+            .expect("installer error without a catalog entry")
+    }
+
+    /// The dynamic values of the message, by the names of the placeholders that they
+    /// replace: the `cause` of the failure in its English rendering, with the `command` and
+    /// `device` that it failed on if they are known, or the `step` that was cancelled.
+    ///
+    /// If the cause is in a catalog, its `cause_code` and `cause_key` are given, with the
+    /// parameters of its message which are not already given.
+    pub fn params(&self) -> Vec<(&'static str, String)> {
+        let (context, err) = match self.parts() {
+            Some(parts) => parts,
+            None => return vec![("step", format!("{:?}", self.step()))],
+        };
+
+        let mut params = vec![("cause", err.to_string())];
+        if let Some(ref command) = context.command {
+            params.push(("command", command.clone()));
+        }
+        if let Some(ref device) = context.device {
+            params.push(("device", device.display().to_string()));
+        }
+        if let Some(ref cause) = context.cause {
+            params.push(("cause_code", cause.code.to_string()));
+            params.push(("cause_key", cause.key.to_owned()));
+            for &(name, ref value) in &cause.params {
+                if params.iter().all(|&(given, _)| given != name) {
+                    params.push((name, value.clone()));
+                }
+            }
+        }
+
+        params
+    }
+
+    /// The command or device that the step failed on, and the catalog entry of its cause, if
+    /// they are known.
+    pub fn context(&self) -> Option<&ErrorContext> {
+        self.parts().map(|(context, _)| context).filter(|context| **context != Default::default())
+    }
+
+    /// The command or device that the step failed on, which may be given by frontends that
+    /// emit their own errors.
+    pub fn context_mut(&mut self) -> Option<&mut ErrorContext> {
+        match *self {
+            Error::Backup { ref mut context, .. }
+            | Error::Init { ref mut context, .. }
+            | Error::Verify { ref mut context, .. }
+            | Error::Partition { ref mut context, .. }
+            | Error::Extract { ref mut context, .. }
+            | Error::Recovery { ref mut context, .. }
+            | Error::Configure { ref mut context, .. }
+            | Error::Bootloader { ref mut context, .. } => Some(context),
+            Error::Cancelled { .. } | Error::Failed { .. } => None,
+        }
+    }
+
+    /// The error which caused the step to fail, which is `None` for a cancellation.
+    pub fn io_error(&self) -> Option<&io::Error> { self.parts().map(|(_, err)| err) }
+
+    /// The OS error code of the failure, which is `ECANCELED` for a cancellation.
+    pub fn raw_os_error(&self) -> i32 {
+        match self.io_error() {
+            Some(err) => err.raw_os_error().unwrap_or(libc::EIO),
+            None => libc::ECANCELED,
+        }
+    }
+
+    /// The installer error which an install returned, if it failed at one of the steps.
+    pub fn from_io(err: &io::Error) -> Option<&Error> { err.get_ref()?.downcast_ref::<Error>() }
+
+    /// The step at which an install was cancelled, if that is why it returned the error.
+    pub fn cancelled_at(err: &io::Error) -> Option<Step> {
+        match Error::from_io(err)? {
+            Error::Cancelled { step } => Some(*step),
+            _ => None,
+        }
+    }
+
+    /// A copy of the error, which is reported to the frontend while the error itself unwinds
+    /// the install. The cause is copied by its OS error code, or else by its kind and message.
+    pub(crate) fn duplicate(&self) -> Error {
+        match self.parts() {
+            Some((context, err)) => {
+                let err = match err.raw_os_error() {
+                    Some(code) => io::Error::from_raw_os_error(code),
+                    None => io::Error::new(err.kind(), err.to_string()),
+                };
+
+                let mut error = Error::new(self.step(), err);
+                if let Some(copy) = error.context_mut() {
+                    *copy = context.clone();
+                }
+
+                error
+            }
+            None => Error::Cancelled { step: self.step() },
+        }
+    }
+
+    fn parts(&self) -> Option<(&ErrorContext, &io::Error)> {
+        match *self {
+            Error::Backup { ref context, ref err }
+            | Error::Init { ref context, ref err }
+            | Error::Verify { ref context, ref err }
+            | Error::Partition { ref context, ref err }
+            | Error::Extract { ref context, ref err }
+            | Error::Recovery { ref context, ref err }
+            | Error::Configure { ref context, ref err }
+            | Error::Bootloader { ref context, ref err } => Some((context, err)),
+            Error::Failed { ref err, .. } => Some((&NO_CONTEXT, err)),
+            Error::Cancelled { .. } => None,
+        }
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(error: Error) -> io::Error {
+        let kind = match error.io_error() {
+            Some(err) => err.kind(),
+            None => io::ErrorKind::Interrupted,
+        };
+
+        io::Error::new(kind, error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        disks::{format_message, DiskError},
+        errors::IoContext,
+    };
+    use std::path::Path;
+
+    #[test]
+    fn structured() {
+        let err = misc::command_error(io::ErrorKind::Other, "mkfs.ext4 /dev/sda2", "failed");
+        let err = misc::device_error(err, Path::new("/dev/sda2"), "failed to format /dev/sda2");
+        let error = Error::new(Step::Partition, err);
+
+        assert_eq!(error.step(), Step::Partition);
+        assert_eq!(error.code(), ErrorCode::Partition);
+        assert_eq!(error.raw_os_error(), libc::EIO);
+        let context = error.context().unwrap();
+        assert_eq!(context.command.as_ref().unwrap(), "mkfs.ext4 /dev/sda2");
+        assert_eq!(context.device.as_ref().unwrap(), Path::new("/dev/sda2"));
+
+        let err = io::Error::from(error);
+        assert_eq!(err.kind(), io::ErrorKind::Other);
+        assert_eq!(Error::from_io(&err).map(Error::step), Some(Step::Partition));
+        assert_eq!(Error::cancelled_at(&err), None);
+        assert_eq!(format!("{}", err), "partitioning failed: failed to format /dev/sda2");
+
+        let error = Error::new(Step::Bootloader, io::Error::from_raw_os_error(libc::ENOSPC));
+        assert_eq!(error.context(), None);
+        assert_eq!(error.raw_os_error(), libc::ENOSPC);
+
+        let err = io::Error::from(Error::Cancelled { step: Step::Extract });
+        assert_eq!(err.kind(), io::ErrorKind::Interrupted);
+        assert_eq!(Error::cancelled_at(&err), Some(Step::Extract));
+        assert_eq!(Error::from_io(&err).map(Error::code), Some(ErrorCode::Cancelled));
+    }
+
+    #[test]
+    fn duplicate() {
+        let err = misc::command_error(io::ErrorKind::Other, "grub-install", "failed");
+        let error = Error::new(Step::Bootloader, err);
+        let copy = error.duplicate();
+        assert_eq!(copy.code(), ErrorCode::Bootloader);
+        assert_eq!(copy.context(), error.context());
+        assert_eq!(copy.to_string(), error.to_string());
+
+        let error = Error::new(Step::Extract, io::Error::from_raw_os_error(libc::ENOSPC));
+        assert_eq!(error.duplicate().raw_os_error(), libc::ENOSPC);
+
+        let error = Error::Cancelled { step: Step::Verify };
+        assert_eq!(error.duplicate().code(), ErrorCode::Cancelled);
+
+        let err = io::Error::from_raw_os_error(libc::EACCES);
+        let error = Error::Failed { step: Step::Configure, err };
+        assert_eq!(error.code(), ErrorCode::Configure);
+        assert_eq!(error.context(), None);
+        assert_eq!(error.duplicate().raw_os_error(), libc::EACCES);
+    }
+
+    #[test]
+    fn catalog() {
+        // Codes are given to frontends, so entries may be appended, but never changed.
+        let table = ERROR_CATALOG
+            .iter()
+            .map(|entry| format!("{} {} {}\n", entry.code, entry.variant, entry.key))
+            .collect::<String>();
+        assert_eq!(
+            table,
+            "\
+1 Backup installer.backup
+2 Init installer.init
+3 Verify installer.verify
+4 Partition installer.partition
+5 Extract installer.extract
+6 Recovery installer.recovery
+7 Configure installer.configure
+8 Bootloader installer.bootloader
+9 Cancelled installer.cancelled
+"
+        );
+
+        let steps = [
+            Step::Backup,
+            Step::Init,
+            Step::Verify,
+            Step::Partition,
+            Step::Extract,
+            Step::Recovery,
+            Step::Configure,
+            Step::Bootloader,
+        ];
+
+        let errors = steps
+            .iter()
+            .map(|&step| Error::new(step, io::Error::new(io::ErrorKind::Other, "disk is full")))
+            .chain(steps.iter().map(|&step| Error::Cancelled { step }));
+
+        for error in errors {
+            let entry = error.entry();
+            assert_eq!(entry.code, error.code() as u32);
+            assert_eq!(format_message(entry.english, &error.params()), error.to_string());
+        }
+
+        let err = misc::command_error(io::ErrorKind::Other, "grub-install", "failed");
+        let error = Error::new(Step::Bootloader, err);
+        assert_eq!(error.key(), "installer.bootloader");
+        assert_eq!(
+            error.params(),
+            vec![("cause", "failed".to_owned()), ("command", "grub-install".to_owned())]
+        );
+    }
+
+    #[test]
+    fn causes() {
+        let cause = DiskError::DiskGet { device: "/dev/sdb".into() };
+        let err = Err::<(), _>(cause).with_context(|err| format!("finding the disk: {}", err));
+        let err = misc::device_error(err.unwrap_err(), Path::new("/dev/sda"), "no disk");
+        let error = Error::new(Step::Partition, err);
+
+        assert_eq!(
+            error.params(),
+            vec![
+                ("cause", "no disk".to_owned()),
+                ("device", "/dev/sda".to_owned()),
+                ("cause_code", "109".to_owned()),
+                ("cause_key", "disk.disk_get".to_owned()),
+            ]
+        );
+
+        let error = Error::new(Step::Partition, io::Error::from(DiskError::DeviceProbe));
+        let params = error.params();
+        assert_eq!(params[1], ("cause_code", "106".to_owned()));
+        assert_eq!(params[2], ("cause_key", "disk.device_probe".to_owned()));
+    }
+}
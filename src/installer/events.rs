@@ -0,0 +1,120 @@
+use super::{Error, InstallReport, Status, UserAccountCreate};
+use crate::timezones::Region;
+use log::Level;
+use std::{fmt, io, sync::mpsc::Sender, time::Duration};
+
+/// How long an install waits for a question to be answered, unless the installer was given
+/// another timeout.
+pub const DEFAULT_PROMPT_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// An event of an install which is running on a worker thread, as it is received from
+/// `Installer::install_async`.
+#[derive(Debug)]
+pub enum InstallerEvent {
+    /// The progress of the install.
+    Status(Status),
+    /// A step of the install failed, or the install was cancelled during it.
+    Error(Error),
+    /// A message which was logged while the install was running, if the logger of the
+    /// installer was initialized with `log` or `log_subscribe`.
+    Log { level: Level, target: &'static str, message: String },
+    /// The install is waiting for an answer before it continues.
+    Prompt(Prompt),
+    /// The install has finished, and this is the last event that will be received.
+    Finished(io::Result<InstallReport>),
+}
+
+/// A question which the install waits to be answered before it continues.
+#[derive(Debug)]
+pub enum Prompt {
+    /// The time zone of the install, which is asked for when the config does not give one.
+    Timezone(Responder<Region>),
+    /// The user account to create, which is asked for when the config does not give any
+    /// accounts, and the install is not for an OEM. A frontend which can't give the account
+    /// responds with the error of why, which fails the Configure step.
+    User(Responder<io::Result<UserAccountCreate>>),
+    /// A question which the config did not answer, such as a passphrase, or whether data
+    /// may be destroyed. The install fails if it is not answered within the timeout of the
+    /// installer.
+    Question(Question, Responder<Answer>),
+}
+
+/// Identifies a question, so that frontends may answer it without parsing its message.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QuestionId {
+    /// The passphrase of a new encrypted volume, which was given neither a passphrase nor
+    /// a keyfile.
+    Passphrase,
+    /// Whether an existing EFI partition, which may hold the boot loaders of other
+    /// systems, may be formatted.
+    FormatEsp,
+    /// Whether the boot code in the MBR of a disk, which may boot other systems, may be
+    /// overwritten by GRUB for BIOS.
+    OverwriteBootCode,
+}
+
+/// The kind of answer that a question expects.
+#[derive(Clone, Debug, PartialEq)]
+pub enum QuestionKind {
+    /// Text which must not be echoed or logged, answered with `Answer::Secret`.
+    Secret,
+    /// A confirmation, answered with `Answer::YesNo`.
+    YesNo,
+    /// One of the given options, answered with `Answer::Choice` and the index of the option.
+    Choice(Vec<String>),
+}
+
+/// A question which the install asks its frontend.
+#[derive(Clone, Debug)]
+pub struct Question {
+    pub id:      QuestionId,
+    pub message: String,
+    pub kind:    QuestionKind,
+}
+
+/// The answer to a question, which should be of the kind that it asked for.
+#[derive(Clone, PartialEq)]
+pub enum Answer {
+    Secret(String),
+    YesNo(bool),
+    Choice(usize),
+}
+
+impl fmt::Debug for Answer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Answer::Secret(_) => f.write_str("Secret(hidden)"),
+            Answer::YesNo(yes) => write!(f, "YesNo({})", yes),
+            Answer::Choice(index) => write!(f, "Choice({})", index),
+        }
+    }
+}
+
+/// The install gave up waiting on a frontend to answer a question.
+///
+/// It is the inner error of the `io::ErrorKind::TimedOut` error that the install fails
+/// with, so frontends may find it with `get_ref` and `downcast_ref`.
+#[derive(Debug, err_derive::Error)]
+#[error(display = "the {:?} question was not answered within {:?}", id, timeout)]
+pub struct Unanswered {
+    pub id:      QuestionId,
+    pub timeout: Duration,
+}
+
+impl From<Unanswered> for io::Error {
+    fn from(why: Unanswered) -> Self { io::Error::new(io::ErrorKind::TimedOut, why) }
+}
+
+/// Answers a prompt of an install.
+///
+/// The install continues without an answer if the responder is dropped without responding.
+pub struct Responder<T>(pub(crate) Sender<T>);
+
+impl<T> Responder<T> {
+    pub fn respond(self, answer: T) { let _ = self.0.send(answer); }
+}
+
+// Answers may hold secrets, such as the password of a user, so they are never printed.
+impl<T> fmt::Debug for Responder<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { f.write_str("Responder") }
+}
@@ -0,0 +1,207 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+
+/// Where the units of `run_once` mark that they have run.
+pub(crate) const FIRSTBOOT_DIR: &str = "var/lib/distinst/firstboot";
+
+/// Where the scripts of first-boot units are copied to, each named after its unit.
+pub(crate) const SCRIPT_DIR: &str = "usr/lib/distinst/firstboot";
+
+/// Where the units are written to, which is also the first place that systemd looks for
+/// units.
+pub(crate) const UNIT_DIR: &str = "etc/systemd/system";
+
+/// Where else the units of the image may be, which are not overwritten.
+const IMAGE_UNIT_DIRS: &[&str] =
+    &["etc/systemd/system", "lib/systemd/system", "usr/lib/systemd/system"];
+
+/// The longest name of a unit that systemd accepts, including its suffix.
+const UNIT_NAME_MAX: usize = 255;
+
+/// A systemd service which is run on the first boot of the install, and every boot after
+/// it unless it is `run_once`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FirstBootUnit {
+    /// The name of the service, with or without its `.service` suffix.
+    pub name:     String,
+    /// The command line of the service, which begins with an absolute path.
+    pub exec:     String,
+    /// A file of the host which is copied into the install, to be run instead of a command.
+    /// The `exec` of a unit with a script must be the path that the script is copied to.
+    pub script:   Option<PathBuf>,
+    /// The service disables itself once it has succeeded.
+    pub run_once: bool,
+}
+
+impl FirstBootUnit {
+    /// Wraps a script of the host into a unit which runs it on the first boot. The script
+    /// is copied into the install when it is configured.
+    pub fn from_script<P: Into<PathBuf>>(name: &str, script: P, run_once: bool) -> Self {
+        let name = name.trim_end_matches(".service");
+        FirstBootUnit {
+            name: name.to_owned(),
+            exec: ["/", SCRIPT_DIR, "/", name].concat(),
+            script: Some(script.into()),
+            run_once,
+        }
+    }
+
+    /// Where the script of the unit is copied to within the install.
+    pub(crate) fn script_path(&self) -> PathBuf {
+        Path::new(SCRIPT_DIR).join(self.name.trim_end_matches(".service"))
+    }
+
+    /// The name of the unit file.
+    pub(crate) fn unit_name(&self) -> String {
+        [self.name.trim_end_matches(".service"), ".service"].concat()
+    }
+
+    /// Validates the unit before any disks are modified.
+    pub(crate) fn validate(&self) -> io::Result<()> {
+        let invalid = |why: String| io::Error::new(io::ErrorKind::InvalidInput, why);
+
+        let name = self.name.trim_end_matches(".service");
+        let valid_char = |c: char| c.is_ascii_alphanumeric() || "-_.:".contains(c);
+        if name.is_empty()
+            || self.unit_name().len() > UNIT_NAME_MAX
+            || name.starts_with('.')
+            || !name.chars().all(valid_char)
+        {
+            return Err(invalid(format!("invalid unit name '{}'", self.name)));
+        }
+
+        if !self.exec.starts_with('/') || self.exec.contains('\n') {
+            return Err(invalid(format!(
+                "the command of {} must be one line, which begins with an absolute path",
+                self.name
+            )));
+        }
+
+        if let Some(ref script) = self.script {
+            if Path::new(&self.exec) != Path::new("/").join(self.script_path()) {
+                return Err(invalid(format!(
+                    "{} has a script, so its command can only be the script",
+                    self.name
+                )));
+            }
+
+            if !script.is_file() {
+                return Err(invalid(format!(
+                    "the script of {} is not a file: {}",
+                    self.name,
+                    script.display()
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Fails if a unit of the same name is in the image at `root`.
+    pub(crate) fn verify_unique(&self, root: &Path) -> io::Result<()> {
+        let unit = self.unit_name();
+        for dir in IMAGE_UNIT_DIRS {
+            let path = root.join(dir).join(&unit);
+            if path.symlink_metadata().is_ok() {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("the image already has a unit at /{}/{}", dir, unit),
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The contents of the unit file.
+    pub(crate) fn unit(&self) -> String {
+        let name = self.name.trim_end_matches(".service");
+        let mut unit = fomat!(
+            "# Written by distinst\n"
+            "[Unit]\n"
+            "Description=First boot setup: " (name) "\n"
+            "Wants=network-online.target\n"
+            "After=network-online.target\n"
+        );
+
+        // The mark is kept even if the service is enabled again.
+        let done = ["/", FIRSTBOOT_DIR, "/", name, ".done"].concat();
+        if self.run_once {
+            unit.push_str(&fomat!("ConditionPathExists=!" (done) "\n"));
+        }
+
+        unit.push_str(&fomat!(
+            "\n[Service]\n"
+            "Type=oneshot\n"
+            "ExecStart=" (self.exec) "\n"
+        ));
+
+        if self.run_once {
+            unit.push_str(&fomat!(
+                "ExecStartPost=/bin/touch " (done) "\n"
+                "ExecStartPost=/bin/systemctl --no-block disable " (self.unit_name()) "\n"
+            ));
+        }
+
+        unit.push_str("\n[Install]\nWantedBy=multi-user.target\n");
+        unit
+    }
+}
+
+/// Validates the units, whose names must be unique.
+pub(crate) fn validate(units: &[FirstBootUnit]) -> io::Result<()> {
+    for (index, unit) in units.iter().enumerate() {
+        unit.validate()?;
+        if units[..index].iter().any(|other| other.unit_name() == unit.unit_name()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is given more than once", unit.unit_name()),
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unit(name: &str, exec: &str) -> FirstBootUnit {
+        FirstBootUnit { name: name.into(), exec: exec.into(), ..Default::default() }
+    }
+
+    #[test]
+    fn names() {
+        assert!(unit("enroll", "/usr/bin/enroll --now").validate().is_ok());
+        assert!(unit("expand-data.service", "/usr/bin/true").validate().is_ok());
+        assert!(unit("", "/usr/bin/true").validate().is_err());
+        assert!(unit("../ssh", "/usr/bin/true").validate().is_err());
+        assert!(unit("getty@tty1", "/usr/bin/true").validate().is_err());
+        assert!(unit("enroll", "enroll").validate().is_err());
+        assert!(validate(&[unit("a", "/bin/a"), unit("a.service", "/bin/b")]).is_err());
+
+        // A script is never copied over the program of a command.
+        let script = FirstBootUnit::from_script("a", "/bin/sh", false);
+        assert!(script.validate().is_ok());
+        assert!(FirstBootUnit { exec: "/bin/bash".into(), ..script }.validate().is_err());
+    }
+
+    #[test]
+    fn run_once() {
+        let once = FirstBootUnit::from_script("enroll.service", "/tmp/enroll.sh", true);
+        assert_eq!(once.exec, "/usr/lib/distinst/firstboot/enroll");
+        assert_eq!(once.script_path(), Path::new("usr/lib/distinst/firstboot/enroll"));
+        assert_eq!(once.unit_name(), "enroll.service");
+
+        let contents = once.unit();
+        assert!(contents.contains("ConditionPathExists=!/var/lib/distinst/firstboot/enroll.done"));
+        assert!(contents.contains("ExecStart=/usr/lib/distinst/firstboot/enroll\n"));
+        assert!(contents.contains("disable enroll.service\n"));
+
+        let always = unit("expand", "/usr/bin/expand").unit();
+        assert!(!always.contains("ConditionPathExists") && !always.contains("disable"));
+    }
+}
@@ -0,0 +1,58 @@
+use super::Step;
+use crate::chroot::Chroot;
+use crate::disks::Disks;
+use std::{fmt, io, path::Path};
+
+/// Whether a hook runs before or after its step.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum HookWhen {
+    Before,
+    After,
+}
+
+impl fmt::Display for HookWhen {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            HookWhen::Before => "before",
+            HookWhen::After => "after",
+        })
+    }
+}
+
+/// The state of the install that is given to a hook.
+pub struct HookContext<'a> {
+    /// The step that the hook is running before or after.
+    pub step:   Step,
+    pub when:   HookWhen,
+    /// Where the new install is mounted, once the targets have been mounted.
+    pub target: Option<&'a Path>,
+    /// The disk configuration that is being installed to.
+    pub disks:  &'a Disks,
+}
+
+impl<'a> HookContext<'a> {
+    /// Mounts the binds of a chroot at the target, so that commands may be run within the
+    /// new install, which may not start services.
+    ///
+    /// The binds are unmounted when the returned chroot is dropped.
+    pub fn chroot(&self) -> io::Result<Chroot<'static>> {
+        match self.target {
+            Some(target) => {
+                let mut chroot = Chroot::new(target)?;
+                chroot.block_services()?;
+                Ok(chroot)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("the target is not mounted {} the {:?} step", self.when, self.step),
+            )),
+        }
+    }
+}
+
+/// A callback that was registered with `Installer::hook`.
+pub(crate) struct Hook {
+    pub step:     Step,
+    pub when:     HookWhen,
+    pub callback: Box<dyn FnMut(&HookContext) -> io::Result<()> + Send>,
+}
@@ -0,0 +1,162 @@
+use std::{io, net::IpAddr};
+
+/// The longest hostname that the kernel accepts.
+const HOSTNAME_MAX: usize = 63;
+
+/// Used when the image does not have a hosts file of its own.
+const HOSTS_TEMPLATE: &str = "# See `man hosts` for details.
+#
+# By default, systemd-resolved or libnss-myhostname will resolve
+# localhost and the system hostname if they're not specified here.
+127.0.0.1\tlocalhost
+::1\t\tlocalhost
+";
+
+/// Validates the hostname against the rules of RFC 1123: labels of letters, digits, and
+/// dashes, which neither begin nor end with a dash, separated by dots.
+pub fn validate_hostname(hostname: &str) -> io::Result<()> {
+    let invalid = |why: &str| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid hostname '{}': {}", hostname, why),
+        )
+    };
+
+    if hostname.is_empty() {
+        return Err(invalid("the hostname is empty"));
+    }
+
+    if hostname.len() > HOSTNAME_MAX {
+        return Err(invalid("the hostname is longer than 63 characters"));
+    }
+
+    for label in hostname.split('.') {
+        if label.is_empty() {
+            return Err(invalid("the hostname has an empty label"));
+        }
+
+        if label.starts_with('-') || label.ends_with('-') {
+            return Err(invalid("labels may not begin or end with a dash"));
+        }
+
+        if !label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+            return Err(invalid("only letters, digits, dashes, and dots are allowed"));
+        }
+    }
+
+    Ok(())
+}
+
+/// Validates an entry to append to the hosts file, which is an address followed by one or
+/// more hostnames.
+pub fn validate_entry(entry: &str) -> io::Result<()> {
+    let invalid = |why: String| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid hosts entry '{}': {}", entry, why),
+        )
+    };
+
+    let mut fields = entry.split_whitespace();
+    let address = fields.next().ok_or_else(|| invalid("the entry is empty".into()))?;
+    address.parse::<IpAddr>().map_err(|_| invalid(format!("{} is not an IP address", address)))?;
+
+    let mut names = fields.peekable();
+    if names.peek().is_none() {
+        return Err(invalid("the entry has no hostnames".into()));
+    }
+
+    for name in names {
+        validate_hostname(name).map_err(|why| invalid(why.to_string()))?;
+    }
+
+    Ok(())
+}
+
+/// Generates the hosts file of the install from the one in the image, or a default.
+///
+/// The lines of the image are kept, except for the `127.0.1.1` entry of the live system,
+/// which is replaced by the hostname of the install, following the Debian convention. The
+/// appended entries are added at the end.
+pub fn generate(existing: Option<&str>, hostname: &str, append: &[String]) -> String {
+    let existing = existing.unwrap_or(HOSTS_TEMPLATE);
+
+    // A fully-qualified hostname is also resolved by its first label.
+    let names = match hostname.find('.') {
+        Some(pos) => [hostname, " ", &hostname[..pos]].concat(),
+        None => hostname.to_owned(),
+    };
+    let entry = ["127.0.1.1\t", &names].concat();
+
+    let mut lines = Vec::new();
+    let mut added = false;
+    for line in existing.lines() {
+        if line.split_whitespace().next() == Some("127.0.1.1") {
+            continue;
+        }
+
+        lines.push(line.to_owned());
+        if !added && line.split_whitespace().next() == Some("127.0.0.1") {
+            lines.push(entry.clone());
+            added = true;
+        }
+    }
+
+    if !added {
+        lines.push(entry);
+    }
+
+    lines.extend(append.iter().cloned());
+
+    let mut hosts = lines.join("\n");
+    hosts.push('\n');
+    hosts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hostnames() {
+        assert!(validate_hostname("pop-os").is_ok());
+        assert!(validate_hostname("web01.example.com").is_ok());
+        assert!(validate_hostname("").is_err());
+        assert!(validate_hostname("pop os").is_err());
+        assert!(validate_hostname("pop_os").is_err());
+        assert!(validate_hostname("-pop").is_err());
+        assert!(validate_hostname("pop..os").is_err());
+        assert!(validate_hostname(&"a".repeat(64)).is_err());
+    }
+
+    #[test]
+    fn entries() {
+        assert!(validate_entry("10.0.0.5 ldap.corp ldap").is_ok());
+        assert!(validate_entry("fd00::1\tproxy").is_ok());
+        assert!(validate_entry("ldap.corp 10.0.0.5").is_err());
+        assert!(validate_entry("10.0.0.5").is_err());
+        assert!(validate_entry("10.0.0.5 bad_name").is_err());
+    }
+
+    #[test]
+    fn hosts() {
+        let image = "127.0.0.1\tlocalhost\n127.0.1.1\tpop-os\n\n\
+                     # The following lines are desirable for IPv6 capable hosts\n\
+                     ::1     ip6-localhost ip6-loopback\n";
+
+        assert_eq!(
+            generate(Some(image), "web01.example.com", &["10.0.0.5 ldap.corp".to_owned()]),
+            "127.0.0.1\tlocalhost\n127.0.1.1\tweb01.example.com web01\n\n\
+             # The following lines are desirable for IPv6 capable hosts\n\
+             ::1     ip6-localhost ip6-loopback\n10.0.0.5 ldap.corp\n"
+        );
+
+        assert_eq!(
+            generate(None, "pop-os", &[]),
+            "# See `man hosts` for details.\n#\n\
+             # By default, systemd-resolved or libnss-myhostname will resolve\n\
+             # localhost and the system hostname if they're not specified here.\n\
+             127.0.0.1\tlocalhost\n127.0.1.1\tpop-os\n::1\t\tlocalhost\n"
+        );
+    }
+}
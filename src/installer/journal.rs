@@ -0,0 +1,348 @@
+use super::Step;
+use crate::{
+    disks::{Disks, LvmEncryption, PartitionInfo},
+    errors::{IntoIoResult, IoContext},
+    external::vgactivate,
+};
+use disk_types::{BlockDeviceExt, FileSystem};
+use partition_identity::PartitionID;
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+use sys_mount::{Mount, MountFlags, Unmount, UnmountFlags};
+use tempdir::TempDir;
+
+/// Where the journal is kept, relative to the root of the new install.
+const JOURNAL_PATH: &str = "var/lib/distinst/state";
+
+/// The copies of the journal are kept at the root of the boot partitions of the install,
+/// which are never encrypted, so that the encrypted devices of an interrupted install may be
+/// found before they are opened.
+const MIRROR_NAME: &str = "distinst-state";
+
+/// The targets of the partitions which the journal is copied to.
+const MIRROR_TARGETS: &[&str] = &["/boot/efi", "/boot"];
+
+/// Records which steps of an install have completed on the target, so that an install which
+/// was interrupted may be resumed without repeating them.
+///
+/// The journal is a file of `key=value` lines within the new install, which is removed once
+/// the install has completed.
+#[derive(Debug, PartialEq)]
+pub struct Journal {
+    path:          PathBuf,
+    /// Where the copies of the journal are written, on the boot partitions of the install.
+    mirrors:       Vec<PathBuf>,
+    /// Describes each mounted partition of the install, as they were after partitioning.
+    layout:        String,
+    /// The UUID of each LUKS container of the install, and the name of its device map.
+    luks:          Vec<(String, String)>,
+    /// The volume groups of the install, which are activated once the containers are open.
+    volume_groups: Vec<String>,
+    completed:     Vec<Step>,
+}
+
+impl Journal {
+    /// Starts a journal for an install to the disks, which have been partitioned and
+    /// mounted at the target.
+    pub fn new(target: &Path, disks: &Disks) -> Self {
+        let logical = disks.get_logical_devices();
+        let luks = logical.iter().filter_map(|device| {
+            let encryption = device.encryption.as_ref()?;
+            let uuid = PartitionID::get_uuid(device.luks_parent.as_ref()?)?;
+            Some((uuid.id, encryption.physical_volume.clone()))
+        });
+
+        Journal {
+            path:          target.join(JOURNAL_PATH),
+            mirrors:       mirrors(target, disks),
+            layout:        layout(disks),
+            luks:          luks.collect(),
+            volume_groups: logical
+                .iter()
+                .filter(|device| !device.remove)
+                .map(|device| device.volume_group.clone())
+                .collect(),
+            completed:     Vec::new(),
+        }
+    }
+
+    /// Opens the LUKS containers and activates the volume groups of an interrupted install,
+    /// as the copy of its journal on a boot partition of the `disks` records them, with the
+    /// secrets of the encryption that the `disks` were configured with. The devices were
+    /// closed if the system was restarted since the install was interrupted.
+    pub fn reopen(disks: &Disks) -> io::Result<()> {
+        let journal = match read_mirror(disks)? {
+            Some(journal) => journal,
+            None => {
+                info!("no boot partition has a copy of the journal: no devices are reopened");
+                return Ok(());
+            }
+        };
+
+        for (uuid, name) in &journal.luks {
+            if Path::new("/dev/mapper").join(name).exists() {
+                continue;
+            }
+
+            let encryption = find_encryption(disks, name).into_io_result(|| {
+                format!("the config lacks the encryption of the device map {}", name)
+            })?;
+            let device = Path::new("/dev/disk/by-uuid").join(uuid);
+            info!("reopening {} from {}", name, device.display());
+            encryption.open(&device).map_err(io::Error::from)?;
+        }
+
+        for group in &journal.volume_groups {
+            vgactivate(group)?;
+        }
+
+        Ok(())
+    }
+
+    /// Reads the journal of an interrupted install from the target, and validates that the
+    /// disks still have the layout which the install was partitioned with.
+    pub fn resume(target: &Path, disks: &Disks) -> io::Result<Self> {
+        let path = target.join(JOURNAL_PATH);
+        let data = fs::read_to_string(&path).map_err(|why| {
+            io::Error::new(
+                why.kind(),
+                format!("no interrupted install was found at {}: {}", path.display(), why),
+            )
+        })?;
+
+        let mut journal = parse(path, &data)?;
+        journal.mirrors = mirrors(target, disks);
+        if journal.layout != layout(disks) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "the disk layout has changed since the install was interrupted, so the \
+                 partitioning step cannot be skipped",
+            ));
+        }
+
+        Ok(journal)
+    }
+
+    pub fn is_complete(&self, step: Step) -> bool { self.completed.contains(&step) }
+
+    /// Records that the step has completed, and writes the journal to the target.
+    pub fn complete(&mut self, step: Step) -> io::Result<()> {
+        if !self.is_complete(step) {
+            self.completed.push(step);
+        }
+
+        self.write()
+    }
+
+    /// Removes the journal and its copies from the target, once the install has completed.
+    pub fn remove(self) -> io::Result<()> {
+        for path in self.mirrors.iter().chain(Some(&self.path)) {
+            match fs::remove_file(path) {
+                Err(ref why) if why.kind() == io::ErrorKind::NotFound => (),
+                result => result?,
+            }
+        }
+
+        Ok(())
+    }
+
+    fn write(&self) -> io::Result<()> {
+        let data = self.data();
+        for path in Some(&self.path).into_iter().chain(&self.mirrors) {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+
+            // Written to a temporary file first, so that an interruption never leaves a
+            // partially written journal.
+            let temporary = path.with_extension("tmp");
+            fs::write(&temporary, &data)
+                .and_then(|_| fs::rename(&temporary, path))
+                .with_context(|err| format!("failed to write {}: {}", path.display(), err))?;
+        }
+
+        Ok(())
+    }
+
+    fn data(&self) -> String {
+        let completed = self.completed.iter().map(|step| format!("{:?}", step)).collect::<Vec<_>>();
+        let mut data = format!("completed={}\nlayout={}\n", completed.join(","), self.layout);
+        for (uuid, name) in &self.luks {
+            data.push_str(&format!("luks={}:{}\n", uuid, name));
+        }
+
+        for group in &self.volume_groups {
+            data.push_str(&format!("lvm={}\n", group));
+        }
+
+        data
+    }
+}
+
+/// The unencrypted boot partitions of the install, and their targets.
+fn boot_partitions(disks: &Disks) -> impl Iterator<Item = (&PartitionInfo, &Path)> + '_ {
+    disks.get_physical_partitions().filter_map(|partition| {
+        let target = partition.target.as_deref()?;
+        match partition.filesystem? {
+            FileSystem::Luks | FileSystem::Lvm => None,
+            _ if MIRROR_TARGETS.iter().any(|boot| target == Path::new(boot)) => {
+                Some((partition, target))
+            }
+            _ => None,
+        }
+    })
+}
+
+/// Where the copies of the journal are kept, with the boot partitions mounted at the target.
+fn mirrors(target: &Path, disks: &Disks) -> Vec<PathBuf> {
+    boot_partitions(disks)
+        .map(|(_, boot)| target.join(boot.strip_prefix("/").unwrap_or(boot)).join(MIRROR_NAME))
+        .collect()
+}
+
+/// Reads the first copy of the journal which is found on the boot partitions of the `disks`,
+/// which are mounted read-only to find it.
+fn read_mirror(disks: &Disks) -> io::Result<Option<Journal>> {
+    for (partition, _) in boot_partitions(disks) {
+        let fs: &str = match partition.filesystem {
+            Some(fs) => fs.into(),
+            None => continue,
+        };
+
+        let dir = TempDir::new("distinst-journal")?;
+        let device = partition.get_device_path();
+        let mount = Mount::new(device, dir.path(), fs, MountFlags::RDONLY, None)
+            .with_context(|err| format!("failed to mount {}: {}", device.display(), err))?
+            .into_unmount_drop(UnmountFlags::DETACH);
+
+        let path = dir.path().join(MIRROR_NAME);
+        let data = match fs::read_to_string(&path) {
+            Ok(data) => data,
+            Err(ref why) if why.kind() == io::ErrorKind::NotFound => continue,
+            Err(why) => return Err(why),
+        };
+
+        drop(mount);
+        return parse(path, &data).map(Some);
+    }
+
+    Ok(None)
+}
+
+/// The encryption of the device map `name`, as the `disks` were configured.
+fn find_encryption<'a>(disks: &'a Disks, name: &str) -> Option<&'a LvmEncryption> {
+    let logical =
+        disks.get_logical_devices().iter().filter_map(|device| device.encryption.as_ref());
+    let physical = disks.get_physical_partitions().filter_map(|partition| {
+        partition.volume_group.as_ref().and_then(|(_, encryption)| encryption.as_ref())
+    });
+
+    logical.chain(physical).find(|encryption| encryption.physical_volume == name)
+}
+
+/// Describes the partitions which are mounted by the install by their target, UUID, file
+/// system, and size.
+fn layout(disks: &Disks) -> String {
+    let mut partitions = disks
+        .get_partitions()
+        .filter_map(|part| {
+            let target = part.target.as_ref()?;
+            let uuid = PartitionID::get_uuid(&part.device_path).map(|id| id.id);
+            Some(format!(
+                "{}:{}:{}:{}",
+                target.display(),
+                uuid.as_ref().map_or("", String::as_str),
+                part.filesystem.map_or("", |kind| kind.into()),
+                part.end_sector - part.start_sector
+            ))
+        })
+        .collect::<Vec<String>>();
+
+    partitions.sort();
+    partitions.join(";")
+}
+
+fn parse(path: PathBuf, data: &str) -> io::Result<Journal> {
+    let invalid = |why: String| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("install journal at {} is invalid: {}", path.display(), why),
+        )
+    };
+
+    let mut layout = None;
+    let mut completed = Vec::new();
+    let mut luks = Vec::new();
+    let mut volume_groups = Vec::new();
+
+    for line in data.lines() {
+        let mut fields = line.splitn(2, '=');
+        match (fields.next(), fields.next()) {
+            (Some("completed"), Some(steps)) => {
+                for step in steps.split(',').filter(|step| !step.is_empty()) {
+                    let parsed = parse_step(step);
+                    completed
+                        .push(parsed.ok_or_else(|| invalid(format!("unknown step {}", step)))?);
+                }
+            }
+            (Some("layout"), Some(value)) => layout = Some(value.to_owned()),
+            (Some("luks"), Some(value)) => {
+                let mut fields = value.splitn(2, ':');
+                match (fields.next(), fields.next()) {
+                    (Some(uuid), Some(name)) => luks.push((uuid.to_owned(), name.to_owned())),
+                    _ => return Err(invalid(format!("invalid LUKS container: {}", value))),
+                }
+            }
+            (Some("lvm"), Some(group)) => volume_groups.push(group.to_owned()),
+            _ => return Err(invalid(format!("unexpected line: {}", line))),
+        }
+    }
+
+    let layout = layout.ok_or_else(|| invalid("the disk layout is missing".into()))?;
+    Ok(Journal { path, mirrors: Vec::new(), layout, luks, volume_groups, completed })
+}
+
+fn parse_step(step: &str) -> Option<Step> {
+    let step = match step {
+        "Backup" => Step::Backup,
+        "Init" => Step::Init,
+        "Verify" => Step::Verify,
+        "Partition" => Step::Partition,
+        "Extract" => Step::Extract,
+        "Recovery" => Step::Recovery,
+        "Configure" => Step::Configure,
+        "Bootloader" => Step::Bootloader,
+        _ => return None,
+    };
+
+    Some(step)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn journal() {
+        let data = "completed=Init,Verify,Partition,Extract\nlayout=/:abcd:ext4:2048\n";
+        let journal = parse(PathBuf::from("/state"), data).unwrap();
+        assert!(journal.is_complete(Step::Extract));
+        assert!(!journal.is_complete(Step::Configure));
+        assert_eq!(journal.layout, "/:abcd:ext4:2048");
+
+        assert!(journal.luks.is_empty());
+
+        let data = "completed=Init,Verify,Partition\nlayout=/:abcd:ext4:2048\nluks=1234-abcd:\
+                    cryptdata\nlvm=data\n";
+        let journal = parse(PathBuf::from("/state"), data).unwrap();
+        assert_eq!(journal.luks, vec![("1234-abcd".to_owned(), "cryptdata".to_owned())]);
+        assert_eq!(journal.volume_groups, vec!["data".to_owned()]);
+        assert_eq!(journal.data(), data);
+        assert!(parse(PathBuf::from("/state"), "layout=\nluks=1234-abcd\n").is_err());
+
+        assert!(parse(PathBuf::from("/state"), "completed=Init\n").is_err());
+        assert!(parse(PathBuf::from("/state"), "completed=Unpack\nlayout=\n").is_err());
+    }
+}
@@ -0,0 +1,168 @@
+use std::{io, str::FromStr};
+
+/// The keyboard of the installed system, which is applied to both X11 and the console.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct KeyboardConfig {
+    /// The XKB layout, such as "us".
+    pub layout:  String,
+    /// An optional variant of the layout, such as "dvorak".
+    pub variant: Option<String>,
+    /// An optional model of the keyboard, such as "pc105".
+    pub model:   Option<String>,
+    /// Optional XKB options, separated by commas, such as "grp:alt_shift_toggle".
+    pub options: Option<String>,
+}
+
+impl KeyboardConfig {
+    pub fn new<S: Into<String>>(layout: S) -> Self {
+        KeyboardConfig { layout: layout.into(), ..Default::default() }
+    }
+
+    /// Validates the fields before any disks are modified. They are written to files which
+    /// are sourced by shells, and so are limited to the characters which XKB names use.
+    pub fn validate(&self) -> io::Result<()> {
+        let invalid = |why: String| io::Error::new(io::ErrorKind::InvalidInput, why);
+
+        if self.layout.is_empty() {
+            return Err(invalid("no keyboard layout was given".into()));
+        }
+
+        let fields = [
+            ("layout", Some(&self.layout)),
+            ("variant", self.variant.as_ref()),
+            ("model", self.model.as_ref()),
+            ("options", self.options.as_ref()),
+        ];
+
+        for &(field, value) in &fields {
+            if let Some(value) = value {
+                let valid = |c: char| c.is_ascii_alphanumeric() || "-_,:+.".contains(c);
+                if !value.chars().all(valid) {
+                    return Err(invalid(format!("invalid keyboard {} '{}'", field, value)));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The contents of `/etc/default/keyboard`, which `console-setup` applies to the console.
+    pub(crate) fn default_keyboard(&self) -> String {
+        fomat!(
+            "XKBMODEL=\"" (self.model.as_deref().unwrap_or("")) "\"\n"
+            "XKBLAYOUT=\"" (self.layout) "\"\n"
+            "XKBVARIANT=\"" (self.variant.as_deref().unwrap_or("")) "\"\n"
+            "XKBOPTIONS=\"" (self.options.as_deref().unwrap_or("")) "\"\n"
+            "\n"
+            "BACKSPACE=\"guess\"\n"
+        )
+    }
+
+    /// Sets the X11 keymap in an existing `/etc/vconsole.conf`, where systemd-localed keeps
+    /// it, keeping the console font and keymap as they are.
+    pub(crate) fn vconsole(&self, existing: &str) -> String {
+        let keys = [
+            ("XKBLAYOUT", Some(self.layout.as_str())),
+            ("XKBMODEL", self.model.as_deref()),
+            ("XKBVARIANT", self.variant.as_deref()),
+            ("XKBOPTIONS", self.options.as_deref()),
+        ];
+
+        let mut lines = existing
+            .lines()
+            .filter(|line| {
+                let key = line.splitn(2, '=').next().unwrap_or("").trim();
+                keys.iter().all(|&(name, _)| name != key)
+            })
+            .map(String::from)
+            .collect::<Vec<String>>();
+
+        for &(name, value) in &keys {
+            if let Some(value) = value {
+                lines.push([name, "=", value].concat());
+            }
+        }
+
+        let mut vconsole = lines.join("\n");
+        vconsole.push('\n');
+        vconsole
+    }
+}
+
+impl FromStr for KeyboardConfig {
+    type Err = io::Error;
+
+    /// Parses `layout[:variant[:model]]`, where empty fields are omitted, as in `us::pc105`.
+    fn from_str(string: &str) -> Result<Self, Self::Err> {
+        let mut fields = string.splitn(3, ':');
+        let mut next = || fields.next().filter(|field| !field.is_empty()).map(String::from);
+
+        let config = KeyboardConfig {
+            layout:  next().unwrap_or_default(),
+            variant: next(),
+            model:   next(),
+            options: None,
+        };
+
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        assert_eq!("us".parse::<KeyboardConfig>().unwrap(), KeyboardConfig::new("us"));
+
+        assert_eq!(
+            "us:dvorak:pc105".parse::<KeyboardConfig>().unwrap(),
+            KeyboardConfig {
+                variant: Some("dvorak".into()),
+                model: Some("pc105".into()),
+                ..KeyboardConfig::new("us")
+            }
+        );
+
+        assert_eq!(
+            "de::pc104".parse::<KeyboardConfig>().unwrap(),
+            KeyboardConfig { model: Some("pc104".into()), ..KeyboardConfig::new("de") }
+        );
+
+        assert!("".parse::<KeyboardConfig>().is_err());
+        assert!(":dvorak".parse::<KeyboardConfig>().is_err());
+        assert!("us\"; rm -rf /".parse::<KeyboardConfig>().is_err());
+    }
+
+    #[test]
+    fn files() {
+        let keyboard = KeyboardConfig {
+            variant: Some("dvorak".into()),
+            options: Some("grp:alt_shift_toggle,ctrl:nocaps".into()),
+            ..KeyboardConfig::new("us")
+        };
+
+        assert_eq!(
+            keyboard.default_keyboard(),
+            r#"XKBMODEL=""
+XKBLAYOUT="us"
+XKBVARIANT="dvorak"
+XKBOPTIONS="grp:alt_shift_toggle,ctrl:nocaps"
+
+BACKSPACE="guess"
+"#
+        );
+
+        assert_eq!(
+            keyboard.vconsole("KEYMAP=us\nFONT=eurlatgr\nXKBLAYOUT=de\nXKBMODEL=pc105\n"),
+            "KEYMAP=us
+FONT=eurlatgr
+XKBLAYOUT=us
+XKBVARIANT=dvorak
+XKBOPTIONS=grp:alt_shift_toggle,ctrl:nocaps
+"
+        );
+    }
+}
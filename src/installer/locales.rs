@@ -0,0 +1,256 @@
+//! Validates the locales, keyboard, and time zone of the config against those which the
+//! image supports, before any disks are modified.
+
+use super::{Config, KeyboardConfig};
+use crate::{
+    errors::IoContext,
+    locale::{self, KeyboardLayouts, SupportedLocale},
+    squashfs::Source,
+    timezones,
+};
+use std::{io, iter, path::Path};
+
+/// Names which are further than this from the name that was given aren't suggested.
+const SUGGESTION_DISTANCE: usize = 3;
+
+/// Checks the config against the lists of the image. Lists which the image lacks, such as
+/// those of a minimal image, are skipped.
+pub fn verify(source: &Source, config: &Config) -> io::Result<()> {
+    if let Some(locales) = available("locales", locale::get_locales(Some(source)))? {
+        let requested = iter::once(&config.lang).chain(&config.additional_locales);
+        validate_locales(&locales, requested)
+            .with_context(|err| format!("locale validation: {}", err))?;
+    }
+
+    let layouts = locale::get_keyboard_layouts_of(Some(source));
+    if let Some(layouts) = available("keyboard layouts", layouts)? {
+        validate_keyboard(&layouts, &config.keyboard_config())
+            .with_context(|err| format!("keyboard validation: {}", err))?;
+    }
+
+    // The zone must be in the database of the image, which is that of the live system when
+    // the image can't be read before it is extracted.
+    if let Some(ref timezone) = config.timezone {
+        match *source {
+            Source::Tar(..) | Source::Remote(_) => timezones::validate(Path::new("/"), timezone),
+            _ => timezones::validate_with(timezone, |path| source.read_file(path)),
+        }
+        .with_context(|err| format!("timezone validation: {}", err))?;
+    }
+
+    Ok(())
+}
+
+fn available<T>(what: &str, list: io::Result<T>) -> io::Result<Option<T>> {
+    match list {
+        Ok(list) => Ok(Some(list)),
+        Err(ref why) if why.kind() == io::ErrorKind::NotFound => {
+            info!("the {} of the image are not checked: {}", what, why);
+            Ok(None)
+        }
+        Err(why) => {
+            Err(why).with_context(|err| format!("reading the {} of the image: {}", what, err))
+        }
+    }
+}
+
+/// Validates that each locale is supported, where `en_US.utf8` names `en_US.UTF-8`, as
+/// glibc normalizes the character set. A locale without one names any of its character sets.
+fn validate_locales<'a, I: IntoIterator<Item = &'a String>>(
+    supported: &[SupportedLocale],
+    requested: I,
+) -> io::Result<()> {
+    for name in requested {
+        let wanted = normalize_locale(name);
+        let found = supported.iter().any(|locale| {
+            let locale = normalize_locale(&locale.name);
+            locale == wanted || (!name.contains('.') && strip_charset(&locale) == wanted)
+        });
+
+        if !found {
+            let names = supported.iter().map(|locale| locale.name.as_str());
+            return Err(unsupported("locale", name, names));
+        }
+    }
+
+    Ok(())
+}
+
+/// Validates the layouts and variants, which are separated by commas when several layouts
+/// are given, and where a variant may be left empty to use the layout's default.
+fn validate_keyboard(layouts: &KeyboardLayouts, keyboard: &KeyboardConfig) -> io::Result<()> {
+    let layouts = layouts.get_layouts();
+    let variants =
+        keyboard.variant.as_deref().unwrap_or("").split(',').map(Some).chain(iter::repeat(None));
+
+    for (name, variant) in keyboard.layout.split(',').zip(variants) {
+        let layout = layouts.iter().find(|layout| layout.get_name() == name).ok_or_else(|| {
+            unsupported("keyboard layout", name, layouts.iter().map(|layout| layout.get_name()))
+        })?;
+
+        let variant = match variant {
+            Some(variant) if !variant.is_empty() => variant,
+            _ => continue,
+        };
+
+        let variants = layout.get_variants().map_or(&[][..], Vec::as_slice);
+        if variants.iter().all(|v| v.get_name() != variant) {
+            let names = variants.iter().map(|v| v.get_name());
+            let what = format!("variant of the keyboard layout {}", name);
+            return Err(unsupported(&what, variant, names));
+        }
+    }
+
+    Ok(())
+}
+
+/// Lowercases the character set and removes its punctuation, as in `en_US.utf8@euro`.
+fn normalize_locale(name: &str) -> String {
+    let (name, modifier) = match name.find('@') {
+        Some(at) => name.split_at(at),
+        None => (name, ""),
+    };
+
+    match name.find('.') {
+        Some(dot) => {
+            let charset = name[dot + 1..]
+                .chars()
+                .filter(char::is_ascii_alphanumeric)
+                .map(|c| c.to_ascii_lowercase())
+                .collect::<String>();
+            [&name[..dot], ".", &charset, modifier].concat()
+        }
+        None => [name, modifier].concat(),
+    }
+}
+
+fn strip_charset(name: &str) -> String {
+    match (name.find('.'), name.find('@')) {
+        (Some(dot), Some(at)) => [&name[..dot], &name[at..]].concat(),
+        (Some(dot), None) => name[..dot].to_owned(),
+        _ => name.to_owned(),
+    }
+}
+
+/// An error which names what the image supports instead, if something is close to it.
+fn unsupported<'a, I: Iterator<Item = &'a str>>(what: &str, name: &str, names: I) -> io::Error {
+    let why = match suggest(name, names) {
+        Some(suggestion) => format!(
+            "the {} '{}' is not supported by the image: did you mean '{}'?",
+            what, name, suggestion
+        ),
+        None => format!("the {} '{}' is not supported by the image", what, name),
+    };
+
+    io::Error::new(io::ErrorKind::InvalidInput, why)
+}
+
+/// The name which differs only by case, or else the closest name within a few edits.
+fn suggest<'a, I: Iterator<Item = &'a str>>(name: &str, names: I) -> Option<&'a str> {
+    let lowercase = name.to_lowercase();
+    let mut closest = None;
+    for candidate in names {
+        let distance = edit_distance(&lowercase, &candidate.to_lowercase());
+        if distance == 0 {
+            return Some(candidate);
+        }
+
+        if distance <= SUGGESTION_DISTANCE && closest.map_or(true, |(d, _)| distance < d) {
+            closest = Some((distance, candidate));
+        }
+    }
+
+    closest.map(|(_, candidate)| candidate)
+}
+
+/// The Levenshtein distance between the strings.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut row = (0..=b.len()).collect::<Vec<_>>();
+
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, &cb) in b.iter().enumerate() {
+            let substitution = diagonal + if ca == cb { 0 } else { 1 };
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(row[j + 1] + 1);
+        }
+    }
+
+    row[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::locale::{ConfigItem, KeyboardLayout, KeyboardVariant, LayoutList, VariantList};
+
+    fn locale(name: &str) -> SupportedLocale {
+        SupportedLocale {
+            name:        name.into(),
+            charset:     String::new(),
+            language:    String::new(),
+            country:     None,
+            description: String::new(),
+        }
+    }
+
+    #[test]
+    fn locales() {
+        let supported = vec![locale("en_US.UTF-8"), locale("sr_RS@latin"), locale("de_DE.UTF-8")];
+        let requested = |names: &[&str]| names.iter().map(|&n| n.to_owned()).collect::<Vec<_>>();
+
+        let valid = requested(&["en_US.utf8", "en_US", "sr_RS@latin", "de_DE.UTF-8"]);
+        assert!(validate_locales(&supported, &valid).is_ok());
+
+        let why = validate_locales(&supported, &requested(&["en_UK.UTF-8"])).unwrap_err();
+        assert_eq!(why.kind(), io::ErrorKind::InvalidInput);
+        assert!(why.to_string().ends_with("did you mean 'en_US.UTF-8'?"));
+    }
+
+    #[test]
+    fn keyboard() {
+        let item = |name: &str| ConfigItem {
+            name:              name.into(),
+            short_description: None,
+            description:       String::new(),
+        };
+        let variants = vec![KeyboardVariant { config_item: item("dvorak") }];
+        let layouts = KeyboardLayouts {
+            layout_list: LayoutList {
+                layout: vec![
+                    KeyboardLayout {
+                        config_item:  item("us"),
+                        variant_list: Some(VariantList { variant: Some(variants) }),
+                    },
+                    KeyboardLayout { config_item: item("cz"), variant_list: None },
+                ],
+            },
+        };
+
+        let keyboard = |layout: &str, variant: Option<&str>| KeyboardConfig {
+            variant: variant.map(String::from),
+            ..KeyboardConfig::new(layout)
+        };
+
+        assert!(validate_keyboard(&layouts, &keyboard("us", Some("dvorak"))).is_ok());
+        assert!(validate_keyboard(&layouts, &keyboard("cz,us", Some(",dvorak"))).is_ok());
+        assert!(validate_keyboard(&layouts, &keyboard("us,cz", None)).is_ok());
+
+        let why = validate_keyboard(&layouts, &keyboard("cze", None)).unwrap_err();
+        assert!(why.to_string().ends_with("did you mean 'cz'?"));
+        let why = validate_keyboard(&layouts, &keyboard("cz", Some("qwerty"))).unwrap_err();
+        assert!(why.to_string().contains("variant of the keyboard layout cz 'qwerty'"));
+    }
+
+    #[test]
+    fn suggestions() {
+        assert_eq!(edit_distance("dvorak", "dvorak"), 0);
+        assert_eq!(edit_distance("qwerty", "qwertz"), 1);
+        assert_eq!(edit_distance("", "us"), 2);
+        assert_eq!(suggest("US", ["gb", "us"].iter().cloned()), Some("us"));
+        assert_eq!(suggest("dvorka", ["colemak", "dvorak"].iter().cloned()), Some("dvorak"));
+        assert_eq!(suggest("russian", ["us", "gb"].iter().cloned()), None);
+    }
+}
@@ -1,94 +1,397 @@
 pub mod bitflags;
 pub mod traits;
 
+mod boot_check;
+mod cancel;
+mod cleanup;
+mod cmdline;
 mod conf;
+mod dry_run;
+mod efivars;
+mod error;
+mod events;
+mod firstboot;
+mod hooks;
+mod hosts;
+mod journal;
+mod keyboard;
+mod locales;
+mod mounted;
+mod network;
+mod overlay;
+mod raid;
+mod report;
+mod secure_boot;
+mod ssh;
 mod state;
+mod transfer;
+mod user;
+mod zram;
 
+pub(crate) mod efi_fallback;
+pub(crate) mod space;
 pub(crate) mod steps;
 
-pub use self::{conf::RecoveryEnv, steps::Step};
-
-use self::state::InstallerState;
+pub use self::{
+    cancel::CancellationToken,
+    conf::RecoveryEnv,
+    dry_run::DryRunOptions,
+    error::{Error, ErrorCode, ERROR_CATALOG},
+    events::{
+        Answer, InstallerEvent, Prompt, Question, QuestionId, QuestionKind, Responder, Unanswered,
+        DEFAULT_PROMPT_TIMEOUT,
+    },
+    firstboot::FirstBootUnit,
+    hooks::{HookContext, HookWhen},
+    hosts::validate_hostname,
+    keyboard::KeyboardConfig,
+    network::NetworkConfig,
+    report::{DeviceReport, InstallReport, StepReport, SubstepReport, TARGET_REPORT},
+    space::DEFAULT_SPACE_MARGIN,
+    ssh::SshConfig,
+    steps::{
+        BootOrderPolicy, BootloaderChoice, GrubOptions, InitramfsTool, RecoveryConfig,
+        RecoverySource, Step, SystemdBootOptions,
+    },
+    transfer::Transfer,
+    user::RootPolicy,
+    zram::ZramConfig,
+};
+pub use fstab_generate::FstabId;
+
+use self::{
+    cleanup::Cleanup,
+    efivars::{EfiVars, EFIVARS},
+    hooks::Hook,
+    journal::Journal,
+    secure_boot::SignedChain,
+    state::{InstallerState, Worker},
+    traits::InstallerDiskOps,
+};
 
 use crate::auto::{
     delete_old_install, move_root, recover_root, remove_root, validate_backup_conditions,
-    AccountFiles, Backup, ReinstallError,
+    AccountFiles, Backup, ReinstallError, DEFAULT_PRESERVE,
 };
 use disk_types::BlockDeviceExt;
-use crate::disks::{Bootloader, Disks};
+use crate::bootloader::{self, BootMode};
+use crate::disks::{memory_total, Bootloader, Disk, Disks, PartitionFlag, SOURCE};
 use crate::errors::IoContext;
-use crate::external::luks::deactivate_logical_devices;
-use crate::hostname;
+use crate::logging::{InstallLog, LogSink};
+use crate::misc;
+use crate::preflight;
 use os_release::OsRelease;
 use partition_identity::PartitionID;
-use crate::squashfs;
+use crate::squashfs::{self, Source};
 use std::{
-    io,
+    env, fs, io, panic,
     path::{Path, PathBuf},
-    sync::atomic::Ordering,
+    slice,
+    sync::{
+        atomic::Ordering,
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex, PoisonError,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
 };
 use tempdir::TempDir;
 use crate::timezones::Region;
-use crate::PARTITIONING_TEST;
+use zeroize::Zeroizing;
 
 pub const MODIFY_BOOT_ORDER: u8 = 0b01;
 pub const INSTALL_HARDWARE_SUPPORT: u8 = 0b10;
 pub const KEEP_OLD_ROOT: u8 = 0b100;
 pub const RUN_UBUNTU_DRIVERS: u8 = 0b1000;
+/// When copying from a directory, delete files in the target which are not in the source.
+pub const DELETE_EXTRANEOUS: u8 = 0b1_0000;
+/// Keep the machine ID, SSH host keys, and random seed of the image, for images which
+/// provision the identity of the machine themselves.
+pub const KEEP_IDENTITY: u8 = 0b10_0000;
+/// Install the fallback EFI loader even if another OS installed one.
+pub const FORCE_FALLBACK: u8 = 0b100_0000;
+/// Boot the Windows Boot Manager second, after the install, when the boot order is modified.
+pub const WINDOWS_SECOND: u8 = 0b1000_0000;
 
 macro_rules! percent {
     ($steps:expr) => {
         |percent| {
             $steps.status.percent = percent;
-            let status = $steps.status;
+            let status = $steps.status.clone();
             $steps.emit_status(status);
         }
     };
 }
 
+/// Whether the system is installed anew, or in place of an existing install.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum InstallMode {
+    /// Installs a new system.
+    Install,
+    /// Reinstalls the system at `Config::old_root`, retaining its user accounts, `/home`,
+    /// time zone, and the paths of `Config::preserve`.
+    Refresh,
+}
+
+impl Default for InstallMode {
+    fn default() -> Self { InstallMode::Install }
+}
+
 /// Installer configuration
+///
+/// Settings are added to the configuration as the installer gains them, so it is created
+/// from `Config::default()`, whose settings are those of a plain install, rather than as a
+/// literal.
+#[non_exhaustive]
 pub struct Config {
     /// Hostname to assign to the installed system.
-    pub hostname:         String,
-    /// The keyboard layout to use with the installed system (such as "us").
-    pub keyboard_layout:  String,
+    pub hostname:               String,
+    /// The keyboard layout, variant, model, and options to use with the installed system.
+    pub keyboard:               KeyboardConfig,
+    /// The keyboard layout to use with the installed system (such as "us"). When it is
+    /// set, it and the model and variant below are used instead of those of `keyboard`.
+    #[deprecated(note = "use the layout of `keyboard` instead")]
+    pub keyboard_layout:        String,
     /// An optional keyboard model (such as "pc105") to define the keyboard's model.
-    pub keyboard_model:   Option<String>,
+    #[deprecated(note = "use the model of `keyboard` instead")]
+    pub keyboard_model:         Option<String>,
     /// An optional variant of the keyboard (such as "dvorak").
-    pub keyboard_variant: Option<String>,
+    #[deprecated(note = "use the variant of `keyboard` instead")]
+    pub keyboard_variant:       Option<String>,
     /// The UUID of the old root partition, for retaining user accounts.
-    pub old_root:         Option<String>,
+    pub old_root:               Option<String>,
     /// The locale to use for the installed system.
-    pub lang:             String,
+    pub lang:                   String,
+    /// Locales to generate in addition to `lang`, such as `en_US.UTF-8`.
+    pub additional_locales:     Vec<String>,
     /// The file that contains a list of packages to remove.
-    pub remove:           String,
-    /// The archive (`tar` or `squashfs`) which contains the base system.
-    pub squashfs:         String,
+    pub remove:                 String,
+    /// The image which contains the base system: a squashfs image, a tar archive, or a
+    /// directory to copy. The kind of image is detected from its contents.
+    pub squashfs:               String,
+    /// Squashfs images which are extracted over the base system, in order. Each may delete
+    /// files from the layers beneath it with overlayfs or aufs whiteouts.
+    pub layers:                 Vec<String>,
+    /// The SHA-256 checksum of the image, which is verified before the disks are modified.
+    /// Without it, only the superblock of a squashfs image is checked.
+    pub squashfs_checksum:      Option<String>,
+    /// The SHA-256 checksums of `layers`, in the same order. A layered image with a checksum
+    /// must be given a checksum for each of its layers.
+    pub layer_checksums:        Vec<String>,
     /// Some flags to control the behavior of the installation.
-    pub flags:            u8,
+    pub flags:                  u8,
+    /// Snapshot the logical volume of the previous root before it is reused, so that its
+    /// files may be recovered after installing.
+    pub snapshot_previous_root: bool,
+    /// The size of that snapshot, as a percentage of the size of the previous root.
+    pub snapshot_percent:       u8,
+    /// The Olson name of the time zone of the installed system, such as `Europe/Prague`.
+    /// This takes precedence over the timezone callback.
+    pub timezone:               Option<String>,
+    /// The user accounts to create on the installed system, which are created with fixed
+    /// IDs first, and then in order. This takes precedence over the user callback.
+    pub users:                  Vec<UserAccountCreate>,
+    /// Entries to append to `/etc/hosts`, each an address followed by its hostnames.
+    pub hosts_append:           Vec<String>,
+    /// A directory of `.deb` packages outside of the image, such as a pool on the ISO, which
+    /// are installed along with their dependencies.
+    pub local_packages:         Option<PathBuf>,
+    /// The language packs, fonts, and input methods to install, instead of those which
+    /// distinst installs for `lang`.
+    pub language_packages:      Option<Vec<String>>,
+    /// Allow packages to be downloaded, through the proxies of the live system, if they are
+    /// not on the ISO.
+    pub allow_network:          bool,
+    /// How file systems are identified in the fstab, and the root on the kernel command line.
+    pub fstab_id:               FstabId,
+    /// Configure the install to resume from hibernation, which requires swap that is at
+    /// least as large as the memory of the system.
+    pub hibernation:            bool,
+    /// The size, in MiB, of a swap file to create at `/swapfile` on the root file system.
+    pub swapfile:               Option<u64>,
+    /// Swap on a compressed device in memory, instead of or in addition to swap on disk.
+    pub zram:                   Option<ZramConfig>,
+    /// The bootloader to install, instead of the one which the firmware and image choose.
+    pub bootloader:             BootloaderChoice,
+    /// The label of the EFI boot entry of the install, instead of the pretty name of the
+    /// image. Entries of the same label on the same disk are replaced.
+    pub efi_label:              Option<String>,
+    /// Where the EFI boot entry of the install is placed in the boot order, when
+    /// `MODIFY_BOOT_ORDER` is set.
+    pub boot_order:             BootOrderPolicy,
+    /// Add the other systems which os-prober finds to the menu of GRUB. As it mounts every
+    /// file system that it finds, security-sensitive deployments may disable it.
+    pub os_prober:              bool,
+    /// The timeout, default entry, console mode, and editor of the menu of systemd-boot.
+    pub systemd_boot:           SystemdBootOptions,
+    /// The distributor, theme, background, and menu of GRUB.
+    pub grub:                   GrubOptions,
+    /// Arguments which are added to the kernel command line of the bootloader, such as
+    /// `nomodeset`, replacing the defaults of the same name.
+    pub kernel_cmdline:         Vec<String>,
+    /// Prepare the install for an OEM, with a temporary `oem` account, and have the owner of
+    /// the machine create their account on the first boot instead.
+    pub oem_mode:               bool,
+    /// A partition to copy the live system to, from which the install may be reinstalled.
+    pub recovery:               Option<RecoveryConfig>,
+    /// Whether to install a new system, or to refresh the install at `old_root`.
+    pub mode:                   InstallMode,
+    /// Paths of the old root which are retained by a refresh, instead of
+    /// `auto::DEFAULT_PRESERVE`.
+    pub preserve:               Option<Vec<PathBuf>>,
+    /// The tool which generates the initramfs, instead of the one found in the image.
+    pub initramfs:              Option<InitramfsTool>,
+    /// Install the drivers of the devices which `driver_support` finds, from the ISO, or
+    /// from the network if `allow_network` is set.
+    pub install_drivers:        bool,
+    /// The proxy, apt mirror, and name servers which packages are fetched through.
+    pub network:                Option<NetworkConfig>,
+    /// Enable the SSH server, and authorize keys to log in to the install.
+    pub ssh:                    Option<SshConfig>,
+    /// A directory whose files are copied onto the install once it is extracted, such as
+    /// wallpapers, defaults in `/etc`, and udev rules.
+    pub overlay:                Option<PathBuf>,
+    /// Services which are run on the first boot of the install, such as to enroll it in
+    /// management.
+    pub firstboot_units:        Vec<FirstBootUnit>,
+    /// Whether root is locked, or may log in with a password or with SSH keys.
+    pub root:                   RootPolicy,
+}
+
+impl Config {
+    /// The keyboard of the installed system, which is that of the deprecated `keyboard_*`
+    /// fields if a layout was set through them.
+    #[allow(deprecated)]
+    pub fn keyboard_config(&self) -> KeyboardConfig {
+        if self.keyboard_layout.is_empty() {
+            return self.keyboard.clone();
+        }
+
+        KeyboardConfig {
+            layout:  self.keyboard_layout.clone(),
+            variant: self.keyboard_variant.clone(),
+            model:   self.keyboard_model.clone(),
+            options: self.keyboard.options.clone(),
+        }
+    }
+}
+
+impl Default for Config {
+    #[allow(deprecated)]
+    fn default() -> Self {
+        Config {
+            hostname:               String::new(),
+            keyboard:               KeyboardConfig::new("us"),
+            keyboard_layout:        String::new(),
+            keyboard_model:         None,
+            keyboard_variant:       None,
+            old_root:               None,
+            lang:                   "en_US.UTF-8".into(),
+            additional_locales:     Vec::new(),
+            remove:                 String::new(),
+            squashfs:               String::new(),
+            layers:                 Vec::new(),
+            squashfs_checksum:      None,
+            layer_checksums:        Vec::new(),
+            flags:                  0,
+            snapshot_previous_root: false,
+            snapshot_percent:       20,
+            timezone:               None,
+            users:                  Vec::new(),
+            hosts_append:           Vec::new(),
+            local_packages:         None,
+            language_packages:      None,
+            allow_network:          false,
+            fstab_id:               FstabId::default(),
+            hibernation:            false,
+            swapfile:               None,
+            zram:                   None,
+            bootloader:             BootloaderChoice::default(),
+            efi_label:              None,
+            boot_order:             BootOrderPolicy::default(),
+            os_prober:              true,
+            systemd_boot:           SystemdBootOptions::default(),
+            grub:                   GrubOptions::default(),
+            kernel_cmdline:         Vec::new(),
+            oem_mode:               false,
+            recovery:               None,
+            mode:                   InstallMode::Install,
+            preserve:               None,
+            initramfs:              None,
+            install_drivers:        false,
+            network:                None,
+            ssh:                    None,
+            overlay:                None,
+            firstboot_units:        Vec::new(),
+            root:                   RootPolicy::default(),
+        }
+    }
 }
 
 /// Credentials for creating a new user account.
-#[derive(Clone)]
+///
+/// Like `Config`, it is created from `UserAccountCreate::default()`.
+#[derive(Clone, Default)]
+#[non_exhaustive]
 pub struct UserAccountCreate {
     pub username: String,
     pub realname: Option<String>,
-    pub password: Option<String>,
+    /// A plaintext password, which is hashed before it is given to the new install, and is
+    /// overwritten with zeroes once dropped.
+    pub password: Option<Zeroizing<String>>,
+    /// A password that was already hashed by crypt(3), as an alternative to `password`.
+    pub password_hash: Option<String>,
     pub profile_icon: Option<String>,
-}
-
-/// Installer error
-#[derive(Debug)]
-pub struct Error {
-    pub step: Step,
-    pub err:  io::Error,
+    /// The groups to add the user to, instead of the default administrative groups.
+    pub groups: Option<Vec<String>>,
+    /// A fixed user ID, instead of the next that is free.
+    pub uid: Option<u32>,
+    /// A fixed ID of the primary group of the user, which has the same name as the user.
+    pub gid: Option<u32>,
+    /// The login shell, instead of `/bin/bash`.
+    pub shell: Option<String>,
+    /// Creates the account without a password, which the user must set when first logging
+    /// in, such as for OEM installs.
+    pub no_password: bool,
+    /// Logs the user in automatically at boot, with the display manager of the install, or
+    /// on the console if it has none.
+    pub autologin: bool,
 }
 
 /// Installer status
-#[derive(Copy, Clone, Debug)]
+///
+/// Details are added to the status as the installer reports them, so a status is created
+/// with `Status::new`.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
 pub struct Status {
-    pub step:    Step,
-    pub percent: i32,
+    pub step:      Step,
+    pub percent:   i32,
+    /// Describes an operation within the step that may take a while, such as encryption,
+    /// or the file which is being extracted.
+    pub message:   Option<String>,
+    /// Set on the final status of an install that was cancelled.
+    pub cancelled: bool,
+    /// The bytes copied by steps which copy data, with the rate and time remaining.
+    pub transfer:  Option<Transfer>,
+    /// The sub-task which is being run by steps that have several, such as Configure.
+    pub substep:   Option<&'static str>,
+}
+
+impl Status {
+    /// The status of a step, without any details.
+    pub fn new(step: Step, percent: i32) -> Self {
+        Status { step, percent, message: None, cancelled: false, transfer: None, substep: None }
+    }
+
+    pub fn bytes_done(&self) -> Option<u64> { self.transfer.map(|t| t.bytes_done) }
+
+    pub fn bytes_total(&self) -> Option<u64> { self.transfer.map(|t| t.bytes_total) }
+
+    /// Bytes per second, averaged over the last few seconds.
+    pub fn rate(&self) -> Option<f64> { self.transfer.and_then(|t| t.rate) }
+
+    pub fn eta_seconds(&self) -> Option<u64> { self.transfer.and_then(|t| t.eta_seconds) }
 }
 
 /// An installer object
@@ -96,7 +399,17 @@ pub struct Installer {
     error_cb:         Option<Box<dyn FnMut(&Error)>>,
     status_cb:        Option<Box<dyn FnMut(&Status)>>,
     timezone_cb:      Option<Box<dyn FnMut() -> Region>>,
-    user_creation_cb: Option<Box<dyn FnMut() -> UserAccountCreate>>,
+    user_creation_cb: Option<Box<dyn FnMut() -> io::Result<UserAccountCreate>>>,
+    secret_cb:        Option<Box<dyn Fn(&Path) -> Option<String>>>,
+    prompt_cb:        Option<Box<dyn FnMut(&Question) -> Option<Answer>>>,
+    hooks:            Arc<Mutex<Vec<Hook>>>,
+    cancel:           CancellationToken,
+    log:              Option<InstallLog>,
+    extract_threads:  usize,
+    space_margin:     u8,
+    trim:             bool,
+    simulate:         bool,
+    prompt_timeout:   Duration,
 }
 
 impl Default for Installer {
@@ -112,15 +425,33 @@ impl Default for Installer {
             status_cb:        None,
             timezone_cb:      None,
             user_creation_cb: None,
+            secret_cb:        None,
+            prompt_cb:        None,
+            hooks:            Arc::default(),
+            cancel:           CancellationToken::new(),
+            log:              None,
+            extract_threads:  0,
+            space_margin:     DEFAULT_SPACE_MARGIN,
+            trim:             true,
+            simulate:         false,
+            prompt_timeout:   DEFAULT_PROMPT_TIMEOUT,
         }
     }
 }
 
 impl Installer {
     const CHROOT_ROOT: &'static str = "distinst";
+    /// Where a remote image is downloaded on the host, so that it is verified before the disks
+    /// are modified. A partial download is resumed from here if the install is retried.
+    const DOWNLOAD_DIR: &'static str = "/var/cache/distinst";
+    /// The name of a remote image which is downloaded.
+    const DOWNLOAD_NAME: &'static str = "distinst-download";
 
     /// Get a list of disks, skipping loopback devices
     ///
+    /// Locked LUKS partitions will be unlocked with secrets from the secret provider, if
+    /// one was set. Partitions which fail to unlock will remain locked.
+    ///
     /// ```ignore,rust
     /// use distinst::Installer;
     /// let installer = Installer::new();
@@ -128,7 +459,17 @@ impl Installer {
     /// ```
     pub fn disks(&self) -> io::Result<Disks> {
         info!("probing disks on system");
-        Disks::probe_devices().with_context(|err| format!("disk probing error: {}", err))
+        let mut disks =
+            Disks::probe_devices().with_context(|err| format!("disk probing error: {}", err))?;
+
+        if let Some(ref provider) = self.secret_cb {
+            let failures = disks.unlock_partitions(|path| provider(path));
+            if !failures.is_empty() {
+                warn!("{} LUKS partitions could not be unlocked", failures.len());
+            }
+        }
+
+        Ok(disks)
     }
 
     /// The user will use this method to hand off installation tasks to distinst.
@@ -137,8 +478,355 @@ impl Installer {
     /// applied before installation. The `config` field provides configuration details that
     /// will be applied when configuring the new installation.
     ///
-    /// If `config.old_root` is set, then home at that location will be retained.
-    pub fn install(&mut self, mut disks: Disks, config: &Config) -> io::Result<()> {
+    /// If `config.mode` is `InstallMode::Refresh`, then the users and home of the install at
+    /// `config.old_root` will be retained.
+    ///
+    /// The steps which complete after partitioning are recorded in a journal on the target,
+    /// so that the install may be resumed with `Installer::resume` if it is interrupted.
+    ///
+    /// Cancelling the token of `Installer::cancel_handle` stops the install, which unmounts the
+    /// target and closes its encrypted devices before returning an error that
+    /// `Error::cancelled_at` recognizes.
+    ///
+    /// A successful install returns its report, which is also written to `TARGET_REPORT` on
+    /// the target.
+    ///
+    /// Nothing is done if the host fails the checks of `preflight`, whose problems are the
+    /// error of the `Init` step. Simulated installs are not checked.
+    pub fn install(&mut self, disks: Disks, config: &Config) -> io::Result<InstallReport> {
+        self.run_sync(move |steps, log| Installer::run(disks, config, false, steps, log))
+    }
+
+    /// Installs on a worker thread, rather than blocking the caller until the install is
+    /// finished.
+    ///
+    /// The status, errors, log, and prompts of the install are received as events instead of
+    /// through the callbacks of the installer, and the last event is the result of the
+    /// install. The thread returns where the log of the install was kept.
+    ///
+    /// Hooks run on the worker thread, and the install waits for each prompt to be answered
+    /// or dropped, so events should be received until the install has finished.
+    pub fn install_async(
+        &self,
+        disks: Disks,
+        config: Config,
+    ) -> (JoinHandle<Option<InstallLog>>, Receiver<InstallerEvent>) {
+        let (sender, events) = mpsc::channel();
+        let worker = self.worker(sender);
+        let handle = thread::spawn(move || {
+            worker.run(move |steps, log| Installer::run(disks, &config, false, steps, log))
+        });
+
+        (handle, events)
+    }
+
+    /// Resumes an install which was interrupted after its disks were partitioned, continuing
+    /// from the first step which did not complete.
+    ///
+    /// The `disks` must mount the partitions of the interrupted install at the same targets,
+    /// without formatting them. The install is refused if the partitions differ from those
+    /// that were recorded in the journal of the interrupted install, and installs which
+    /// retain the home of an old root cannot be resumed.
+    ///
+    /// The LUKS containers and volume groups of the interrupted install are reopened with the
+    /// encryption of the `disks`, as the copy of the journal on its boot partition records
+    /// them, so the install may be resumed after the system was restarted.
+    pub fn resume(&mut self, disks: Disks, config: &Config) -> io::Result<InstallReport> {
+        if config.mode == InstallMode::Refresh {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "installs which refresh an old root cannot be resumed",
+            ));
+        }
+
+        self.run_sync(move |steps, log| Installer::run(disks, config, true, steps, log))
+    }
+
+    /// Unmounts everything within the temporary chroot directories of the installer, and
+    /// deactivates any logical volumes and encrypted partitions which remain open.
+    ///
+    /// An install cleans up after itself when it fails, so this is only needed to recover
+    /// from an installer process that was killed.
+    pub fn cleanup() -> io::Result<()> { cleanup::cleanup() }
+
+    /// Verifies that the layout of the disks can be booted by the firmware, as `detect` finds
+    /// it, with the bootloader that was chosen: EFI requires an ESP, and BIOS a disk which
+    /// GRUB can be embedded in.
+    ///
+    /// The image is taken to be of the architecture of the installer, which is run from it.
+    pub fn verify_installable(
+        disks: &Disks,
+        mode: BootMode,
+        choice: BootloaderChoice,
+    ) -> io::Result<()> {
+        Self::verify_installable_for(disks, mode, choice, efi_fallback::efi_arch(env::consts::ARCH))
+    }
+
+    /// Verifies the layout as `verify_installable` does, for an image of the architecture
+    /// `arch`, as the suffix of its EFI binaries.
+    fn verify_installable_for(
+        disks: &Disks,
+        mode: BootMode,
+        choice: BootloaderChoice,
+        arch: Option<&str>,
+    ) -> io::Result<()> {
+        info!("verifying the layout for {:?} firmware, with {:?}", mode, choice);
+        Self::verify_firmware(mode, choice, arch)?;
+        let verified = if choice.is_board() {
+            disks.verify_board_partitions()
+        } else if choice.is_ieee1275() {
+            disks.verify_prep_partitions()
+        } else {
+            disks.verify_partitions(choice.firmware(mode.bootloader()))
+        };
+
+        verified.with_context(|err| format!("partition validation: {}", err))
+    }
+
+    /// Fails the `Init` step with the problems that `preflight` finds on the host. A simulation
+    /// does not need all that an install requires of the host, so it is not checked.
+    fn preflight(disks: &Disks, config: &Config, steps: &mut InstallerState) -> io::Result<()> {
+        if steps.worker.simulate {
+            return Ok(());
+        }
+
+        preflight::verify(disks, config).map_err(|why| {
+            error!("{}", why);
+            let error = Error::new(Step::Init, why);
+            steps.emit_error(&error);
+            error.into()
+        })
+    }
+
+    /// Asks to confirm that the boot code in the MBR of the disk that GRUB for BIOS is
+    /// installed to is overwritten, before the disks are modified.
+    fn confirm_boot_code(disks: &Disks, steps: &InstallerState) -> io::Result<()> {
+        let devices = disks.get_boot_devices(Bootloader::Bios).unwrap_or_default();
+        for (device, _) in devices {
+            let notice = match disks.find_disk(&device).and_then(Disk::boot_code_notice) {
+                Some(notice) => notice,
+                None => {
+                    info!("MBR of {} contains no boot code", device.display());
+                    continue;
+                }
+            };
+
+            info!("{}", notice);
+            let question = Question {
+                id:      QuestionId::OverwriteBootCode,
+                message: format!("{}: continue?", notice),
+                kind:    QuestionKind::YesNo,
+            };
+
+            if let Some(Answer::YesNo(false)) = steps.ask(question)? {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("overwriting the MBR of {} was declined", device.display()),
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Asks for the passphrases of the encrypted volumes which were given neither a
+    /// passphrase nor a keyfile.
+    ///
+    /// A volume whose passphrase is not answered can't be created, so the install is stopped
+    /// here, before any disks are modified.
+    fn ask_passphrases(disks: &mut Disks, steps: &InstallerState) -> io::Result<()> {
+        for encryption in disks.get_keyless_encryptions_mut() {
+            let question = Question {
+                id:      QuestionId::Passphrase,
+                message: format!(
+                    "passphrase of the encrypted volume {}",
+                    encryption.physical_volume
+                ),
+                kind:    QuestionKind::Secret,
+            };
+
+            match steps.ask(question)? {
+                Some(Answer::Secret(passphrase)) => {
+                    encryption.password = Some(Zeroizing::new(passphrase))
+                }
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!(
+                            "the encrypted volume {} was given neither a passphrase nor a \
+                             keyfile, and its passphrase was not answered",
+                            encryption.physical_volume
+                        ),
+                    ));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Asks whether EFI partitions which already exist may be formatted, as the boot
+    /// loaders of other systems on them would be lost.
+    fn confirm_esp_format(disks: &Disks, steps: &InstallerState) -> io::Result<()> {
+        let existing = disks.get_physical_partitions().filter(|p| {
+            p.flag_is_enabled(SOURCE)
+                && p.will_format()
+                && p.flags.contains(&PartitionFlag::PED_PARTITION_ESP)
+        });
+
+        for esp in existing {
+            let device = esp.get_device_path().display();
+            let question = Question {
+                id:      QuestionId::FormatEsp,
+                message: format!("format the existing EFI partition {}?", device),
+                kind:    QuestionKind::YesNo,
+            };
+
+            if let Some(Answer::YesNo(false)) = steps.ask(question)? {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("formatting the EFI partition {} was declined", device),
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn verify_firmware(
+        mode: BootMode,
+        choice: BootloaderChoice,
+        arch: Option<&str>,
+    ) -> io::Result<()> {
+        // The firmware of a board and Open Firmware are neither EFI nor BIOS.
+        if choice.is_board() || choice.is_ieee1275() {
+            return Ok(());
+        }
+
+        let firmware = choice.firmware(mode.bootloader());
+        if firmware == Bootloader::Bios && !matches!(arch, Some("x64") | Some("ia32")) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "{} images can only be booted by EFI firmware, not by GRUB for BIOS",
+                    arch.unwrap_or(env::consts::ARCH)
+                ),
+            ));
+        }
+
+        match (mode.bootloader(), firmware) {
+            (Bootloader::Bios, Bootloader::Efi) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "{:?} requires EFI firmware, but the installer was booted in BIOS mode",
+                        choice
+                    ),
+                ));
+            }
+            (Bootloader::Efi, Bootloader::Bios) => {
+                warn!("GRUB is installed for BIOS, which EFI firmware only boots in CSM mode");
+                return Ok(());
+            }
+            _ => (),
+        }
+
+        if mode == BootMode::Ia32Efi && arch == Some("x64") {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "this machine has 32-bit EFI firmware, which can't boot the 64-bit EFI \
+                 loaders of this image: boot the installer in BIOS (CSM) mode instead",
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Runs an install on a worker thread, and passes its events to the callbacks of the
+    /// installer until it has finished.
+    fn run_sync<F>(&mut self, install: F) -> io::Result<InstallReport>
+    where
+        F: FnOnce(&mut InstallerState, &LogSink) -> io::Result<()> + Send,
+    {
+        let (sender, events) = mpsc::channel();
+        let worker = self.worker(sender);
+
+        let (log, result) = thread::scope(|scope| {
+            let handle = scope.spawn(move || worker.run(install));
+
+            let mut result = None;
+            for event in events {
+                if let Some(finished) = self.dispatch(event) {
+                    result = Some(finished);
+                }
+            }
+
+            match handle.join() {
+                Ok(log) => (log, result),
+                Err(why) => panic::resume_unwind(why),
+            }
+        });
+
+        self.log = log;
+        result.unwrap_or_else(|| {
+            Err(io::Error::new(io::ErrorKind::Other, "the install finished without a result"))
+        })
+    }
+
+    /// The state of the installer which the thread that runs an install is given.
+    #[allow(deprecated)]
+    fn worker(&self, events: Sender<InstallerEvent>) -> Worker {
+        Worker {
+            hooks: Arc::clone(&self.hooks),
+            extract_threads: self.extract_threads,
+            space_margin: self.space_margin,
+            trim: self.trim,
+            simulate: self.simulate || crate::PARTITIONING_TEST.load(Ordering::SeqCst),
+            cancel: self.cancel.clone(),
+            prompt_timeout: self.prompt_timeout,
+            events,
+        }
+    }
+
+    /// Passes an event of an install to the callbacks of the installer, and returns the
+    /// result of the install once it has finished.
+    fn dispatch(&mut self, event: InstallerEvent) -> Option<io::Result<InstallReport>> {
+        match event {
+            InstallerEvent::Status(status) => self.emit_status(status),
+            InstallerEvent::Error(error) => self.emit_error(&error),
+            // The log reaches the subscribers of the logger directly.
+            InstallerEvent::Log { .. } => (),
+            InstallerEvent::Prompt(Prompt::Timezone(responder)) => {
+                if let Some(ref mut callback) = self.timezone_cb {
+                    responder.respond(callback());
+                }
+            }
+            InstallerEvent::Prompt(Prompt::User(responder)) => {
+                if let Some(ref mut callback) = self.user_creation_cb {
+                    responder.respond(callback());
+                }
+            }
+            InstallerEvent::Prompt(Prompt::Question(question, responder)) => {
+                if let Some(answer) = self.prompt_cb.as_mut().and_then(|ask| ask(&question)) {
+                    responder.respond(answer);
+                }
+            }
+            InstallerEvent::Finished(result) => return Some(result),
+        }
+
+        None
+    }
+
+    fn run(
+        mut disks: Disks,
+        config: &Config,
+        resume: bool,
+        steps: &mut InstallerState,
+        log: &LogSink,
+    ) -> io::Result<()> {
+        info!("installing from {}, with flags {:#b}", config.squashfs, config.flags);
+        Self::preflight(&disks, config, steps)?;
+
         let mut recovery_conf = if Path::new("/cdrom/recovery.conf").exists() {
             Some(RecoveryEnv::new()?)
         } else {
@@ -146,25 +834,109 @@ impl Installer {
         };
 
         disks.remove_untouched_disks();
-        let steps = &mut InstallerState::new(self);
 
-        Self::backup(disks, config, steps, |mut disks, config, steps| {
-            if !hostname::is_valid(&config.hostname) {
-                return Err(io::Error::new(io::ErrorKind::InvalidInput, "hostname is not valid"));
+        if config.snapshot_previous_root {
+            Self::snapshot_previous_root(&mut disks, config)?;
+        }
+
+        // Device maps are deactivated last, once everything mounted from them is unmounted.
+        let devices = Cleanup::devices();
+
+        let result = Self::backup(disks, config, steps, |mut disks, config, steps| {
+            let cancel = steps.worker.cancel.clone();
+            Self::validate(config)?;
+
+            // The image of a resumed install has already been extracted.
+            let source = if resume { None } else { Some(steps::find_source(config)?) };
+
+            let mode = bootloader::detect();
+            let arch = Self::image_arch(source.as_ref())?;
+            Self::verify_installable_for(&disks, mode, config.bootloader, arch)?;
+            let bootloader = config.bootloader.firmware(mode.bootloader());
+
+            // A boot entry which can't be written is logged instead, as the install may still
+            // be booted from the fallback loader.
+            if bootloader == Bootloader::Efi
+                && config.flags & MODIFY_BOOT_ORDER != 0
+                && !crate::NO_EFI_VARIABLES.load(Ordering::Relaxed)
+            {
+                efivars::warn_if_read_only(Path::new(EFIVARS));
             }
 
-            let bootloader = Bootloader::detect();
+            Self::ask_passphrases(&mut disks, steps)?;
             disks
-                .verify_partitions(bootloader)
-                .with_context(|err| format!("partition validation: {}", err))?;
+                .verify_passphrases()
+                .with_context(|err| format!("passphrase validation: {}", err))?;
+            Self::confirm_esp_format(&disks, steps)?;
+            if bootloader == Bootloader::Bios
+                && !config.bootloader.is_board()
+                && !config.bootloader.is_ieee1275()
+            {
+                Self::confirm_boot_code(&disks, steps)?;
+            }
 
-            let (squashfs, remove_pkgs) = steps.apply(Step::Init, "initializing", |steps| {
-                Installer::initialize(&mut disks, config, percent!(steps))
-            })?;
+            // Swap which is encrypted with a random key can't be resumed from.
+            disks
+                .verify_swap_encryption(config.hibernation)
+                .with_context(|err| format!("swap validation: {}", err))?;
+
+            // The recovery partition must hold all of the live system before it is copied.
+            if let Some(ref recovery) = config.recovery {
+                let size = recovery.source.size().with_context(|err| {
+                    format!("unable to find the size of {:?}: {}", recovery.source.path(), err)
+                })?;
+                disks
+                    .verify_recovery(&recovery.partition, size)
+                    .with_context(|err| format!("recovery validation: {}", err))?;
+
+                // The live system is copied after the disks are modified, to a FAT32 partition.
+                let source = recovery.source.path();
+                let devices = recovery.source.devices().with_context(|err| {
+                    format!("unable to find the device of {:?}: {}", source, err)
+                })?;
+                disks
+                    .verify_recovery_source(source, &devices)
+                    .with_context(|err| format!("recovery validation: {}", err))?;
+                recovery
+                    .source
+                    .verify_files()
+                    .with_context(|err| format!("recovery validation: {}", err))?;
+            }
+
+            if config.hibernation {
+                let memory = memory_total()
+                    .with_context(|err| format!("unable to read the total memory: {}", err))?;
+                let swapfile = config.swapfile.map_or(0, |size| size * 1024 * 1024);
+                disks
+                    .verify_hibernation(memory * 1024, swapfile)
+                    .with_context(|err| format!("hibernation validation: {}", err))?;
+            }
+
+            // Packages are fetched through the proxy or mirror, which must be reachable before
+            // the disks are modified.
+            if let Some(ref network) = config.network {
+                network
+                    .verify_reachable()
+                    .with_context(|err| format!("network validation: {}", err))?;
+            }
+
+            // An install that would run out of space is stopped before the disks are modified.
+            if let Some(ref source) = source {
+                Self::verify_space(&disks, source, steps.worker.space_margin, &cancel)
+                    .with_context(|err| format!("space validation: {}", err))?;
+                Self::verify_accounts(source, &config.users)
+                    .with_context(|err| format!("user validation: {}", err))?;
+                locales::verify(source, config)?;
+                Self::verify_boot(&disks, source, config, bootloader)?;
+                Self::verify_initramfs(&disks, source, config)
+                    .with_context(|err| format!("initramfs validation: {}", err))?;
+            }
 
-            steps.apply(Step::Partition, "partitioning", |steps| {
-                Installer::partition(&mut disks, percent!(steps))
+            steps.run_hooks(Step::Init, HookWhen::Before, &disks, None)?;
+            let (mut squashfs, remove_pkgs) = steps.apply(Step::Init, "initializing", |steps| {
+                Installer::initialize(&mut disks, config, percent!(steps))
             })?;
+            steps.run_hooks(Step::Init, HookWhen::After, &disks, None)?;
 
             // Mount the temporary directory, and all of our mount targets.
             info!("mounting temporary chroot directory at {}", Self::CHROOT_ROOT);
@@ -172,38 +944,184 @@ impl Installer {
             let mount_dir = TempDir::new(Self::CHROOT_ROOT)
                 .with_context(|err| format!("chroot root temp mount: {}", err))?;
 
-            info!("mounting all targets to the temporary chroot");
+            // Unmounts anything left within the chroot if a step fails, before the temporary
+            // directory is removed.
+            let _cleanup = Cleanup::mounts(mount_dir.path());
+
+            let mut mounts = None;
+            let mut journal = None;
+
+            if resume {
+                let (resumed_mounts, resumed) =
+                    steps.apply(Step::Partition, "validating the interrupted install", |_| {
+                        Journal::reopen(&disks).with_context(|err| {
+                            format!("reopening the encrypted devices: {}", err)
+                        })?;
+
+                        info!("mounting all targets of the interrupted install");
+                        let mounts = disks
+                            .mount_all_targets(mount_dir.path())
+                            .with_context(|err| format!("mounting all targets: {}", err))?;
+                        Ok((mounts, Journal::resume(mount_dir.path(), &disks)?))
+                    })?;
+
+                info!("resuming interrupted install: {:?}", resumed);
+                mounts = Some(resumed_mounts);
+                journal = Some(resumed);
+            }
 
-            let mut mounts = disks
-                .mount_all_targets(mount_dir.path())
-                .with_context(|err| format!("mounting all targets: {}", err))?;
+            let extracted = journal.as_ref().map_or(false, |j| j.is_complete(Step::Extract));
+
+            if !extracted {
+                steps.run_hooks(Step::Verify, HookWhen::Before, &disks, None)?;
+                // A remote image is downloaded here, so that the disks are only modified once
+                // it has been fetched and verified.
+                squashfs = steps.apply(Step::Verify, "verifying", |steps| {
+                    Installer::verify(&squashfs, config, &cancel, |percent, message, bytes| {
+                        steps.emit_transfer(percent, message, bytes)
+                    })
+                })?;
+                steps.run_hooks(Step::Verify, HookWhen::After, &disks, None)?;
+            }
 
-            if PARTITIONING_TEST.load(Ordering::SeqCst) {
-                info!("PARTITION_TEST enabled: exiting before unsquashing");
+            let mut journal = match journal {
+                Some(journal) => journal,
+                None => {
+                    steps.run_hooks(Step::Partition, HookWhen::Before, &disks, None)?;
+                    steps.recorder.partitions(&disks);
+                    steps.apply(Step::Partition, "partitioning", |steps| {
+                        Installer::partition(&mut disks, &cancel, |percent, message, bytes| {
+                            steps.emit_transfer(percent, message, bytes)
+                        })
+                    })?;
+
+                    info!("mounting all targets to the temporary chroot");
+
+                    mounts = Some(
+                        disks
+                            .mount_all_targets(mount_dir.path())
+                            .with_context(|err| format!("mounting all targets: {}", err))?,
+                    );
+
+                    let mut journal = Journal::new(mount_dir.path(), &disks);
+                    for &step in &[Step::Init, Step::Verify, Step::Partition] {
+                        journal
+                            .complete(step)
+                            .with_context(|err| format!("install journal: {}", err))?;
+                    }
+
+                    journal
+                }
+            };
+
+            // The log is kept on the target before it is unmounted, even if the install fails.
+            let passphrases = disks.get_passphrases().into_iter().map(String::from);
+            let mut secrets = passphrases.map(Zeroizing::new).collect::<Vec<_>>();
+            secrets.extend(config.users.iter().filter_map(|user| user.password.clone()));
+            let target_log = log.target(mount_dir.path(), secrets);
+
+            let target = Some(mount_dir.path());
+            if !resume {
+                steps.run_hooks(Step::Partition, HookWhen::After, &disks, target)?;
+            }
+
+            if steps.worker.simulate {
+                info!("simulating the install: exiting before unsquashing");
                 return Ok(());
             }
 
-            let iso_os_release = steps.apply(Step::Extract, "extracting", |steps| {
-                Installer::extract(squashfs.as_path(), mount_dir.path(), percent!(steps))
-            })?;
+            let iso_os_release = if extracted {
+                info!("skipping extraction, which completed before the install was interrupted");
+                OsRelease::new_from(&mount_dir.path().join("etc/os-release")).with_context(
+                    |why| format!("failed to parse /etc/os-release from resumed install: {}", why),
+                )?
+            } else {
+                steps.run_hooks(Step::Extract, HookWhen::Before, &disks, target)?;
+                let threads = steps.worker.extract_threads;
+                let iso_os_release = steps.apply(Step::Extract, "extracting", |steps| {
+                    Installer::extract(
+                        &squashfs,
+                        config,
+                        mount_dir.path(),
+                        threads,
+                        &cancel,
+                        |percent, path, bytes| steps.emit_transfer(percent, path, bytes),
+                    )
+                })?;
+
+                journal
+                    .complete(Step::Extract)
+                    .with_context(|err| format!("install journal: {}", err))?;
+
+                steps.run_hooks(Step::Extract, HookWhen::After, &disks, target)?;
+                iso_os_release
+            };
+
+            if let Some(ref recovery) = config.recovery {
+                if journal.is_complete(Step::Recovery) {
+                    info!("skipping the recovery partition, which was already copied");
+                } else {
+                    steps.run_hooks(Step::Recovery, HookWhen::Before, &disks, target)?;
+                    steps.apply(Step::Recovery, "copying the recovery partition", |steps| {
+                        Installer::recovery(
+                            recovery,
+                            mount_dir.path(),
+                            config.bootloader.resolve(bootloader, &iso_os_release.name),
+                            &iso_os_release,
+                            &cancel,
+                            |percent, path| {
+                                steps.status.percent = percent;
+                                let mut status = steps.status.clone();
+                                status.message = path;
+                                steps.emit_status(status);
+                            },
+                        )
+                    })?;
+
+                    journal
+                        .complete(Step::Recovery)
+                        .with_context(|err| format!("install journal: {}", err))?;
+
+                    steps.run_hooks(Step::Recovery, HookWhen::After, &disks, target)?;
+                }
+            }
 
-            let timezone = steps.installer.timezone_cb.as_mut().map(|func| func());
-            let user = steps.installer.user_creation_cb.as_mut().map(|func| func());
+            let timezone = steps.timezone();
+            let callback_user = steps.user(config)?;
+            let users = callback_user.as_ref().map_or(&config.users[..], slice::from_ref);
+            if !config.oem_mode && config.users.is_empty() {
+                Self::validate_users(config, users)?;
+            }
 
-            steps.apply(Step::Configure, "configuring chroot", |steps| {
-                Installer::configure(
-                    recovery_conf.as_mut(),
-                    &disks,
-                    mount_dir.path(),
-                    &config,
-                    &iso_os_release,
-                    timezone.as_ref(),
-                    user.as_ref(),
-                    &remove_pkgs,
-                    percent!(steps),
-                )
-            })?;
+            if journal.is_complete(Step::Configure) {
+                info!("skipping configuration, which completed before the install was interrupted");
+            } else {
+                steps.run_hooks(Step::Configure, HookWhen::Before, &disks, target)?;
+                steps.apply(Step::Configure, "configuring chroot", |steps| {
+                    Installer::configure(
+                        recovery_conf.as_mut(),
+                        &disks,
+                        mount_dir.path(),
+                        &config,
+                        &iso_os_release,
+                        timezone.as_ref(),
+                        users,
+                        &remove_pkgs,
+                        &cancel,
+                        |percent, substep| steps.emit_substep(percent, substep),
+                    )
+                })?;
+                journal
+                    .complete(Step::Configure)
+                    .with_context(|err| format!("install journal: {}", err))?;
+                steps.run_hooks(Step::Configure, HookWhen::After, &disks, target)?;
+            }
+
+            if steps.worker.trim {
+                steps::trim(mount_dir.path());
+            }
 
+            steps.run_hooks(Step::Bootloader, HookWhen::Before, &disks, target)?;
             steps.apply(Step::Bootloader, "configuring bootloader", |steps| {
                 Installer::bootloader(
                     &disks,
@@ -214,12 +1132,26 @@ impl Installer {
                     percent!(steps),
                 )
             })?;
+            steps.run_hooks(Step::Bootloader, HookWhen::After, &disks, target)?;
+
+            journal.remove().with_context(|err| format!("removing install journal: {}", err))?;
+
+            steps.recorder.devices(&disks);
+            if let Err(why) = steps.recorder.write(mount_dir.path()) {
+                warn!("failed to write the install report to /{}: {}", TARGET_REPORT, why);
+            }
+
+            drop(target_log);
+
+            if let Some(mut mounts) = mounts {
+                mounts.unmount(false).with_context(|err| format!("chroot unmount: {}", err))?;
+            }
 
-            mounts.unmount(false).with_context(|err| format!("chroot unmount: {}", err))?;
             mount_dir.close().with_context(|err| format!("closing mount directory: {}", err))
-        })?;
+        });
 
-        let _ = deactivate_logical_devices();
+        drop(devices);
+        result?;
 
         if let Some(conf) = recovery_conf.as_mut() {
             conf.remove("MODE");
@@ -229,6 +1161,309 @@ impl Installer {
         Ok(())
     }
 
+    /// Validates the parts of the config which don't depend on the disks.
+    pub(crate) fn validate(config: &Config) -> io::Result<()> {
+        hosts::validate_hostname(&config.hostname)?;
+        config.keyboard_config().validate()?;
+        for entry in &config.hosts_append {
+            hosts::validate_entry(entry)?;
+        }
+
+        if let Some(ref dir) = config.local_packages {
+            if !dir.is_dir() {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("local packages: {} is not a directory", dir.display()),
+                ));
+            }
+        }
+
+        cmdline::validate(&config.kernel_cmdline)
+            .with_context(|err| format!("kernel command line validation: {}", err))?;
+
+        config.grub.validate().with_context(|err| format!("GRUB validation: {}", err))?;
+
+        firstboot::validate(&config.firstboot_units)
+            .with_context(|err| format!("first boot validation: {}", err))?;
+
+        if let Some(ref dir) = config.overlay {
+            overlay::validate(dir).with_context(|err| format!("overlay validation: {}", err))?;
+        }
+
+        user::validate_all(&config.users)
+            .with_context(|err| format!("user validation: {}", err))?;
+
+        if config.oem_mode {
+            if !config.users.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "user validation: a user can't be given in OEM mode",
+                ));
+            }
+
+            info!("installing in OEM mode: the user is created on the first boot");
+        }
+
+        user::validate_root(&config.root)
+            .with_context(|err| format!("root validation: {}", err))?;
+
+        if let RootPolicy::Keys(ref keys) = config.root {
+            ssh::validate_keys(keys).with_context(|err| format!("root validation: {}", err))?;
+            if config.ssh.as_ref().map_or(false, |ssh| !ssh.permit_root_login) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "root validation: root may only log in with keys, but ssh does not permit \
+                     root to log in",
+                ));
+            }
+        }
+
+        if let Some(ref zram) = config.zram {
+            zram.validate().with_context(|err| format!("zram validation: {}", err))?;
+            if config.hibernation {
+                warn!("zram can't be resumed from: hibernation requires swap on disk as well");
+            }
+
+            match memory_total() {
+                Ok(memory) => info!(
+                    "creating {} MiB of zram swap, for {} MiB of memory",
+                    zram.size(memory),
+                    memory / 1024
+                ),
+                Err(why) => warn!("unable to read the total memory: {}", why),
+            }
+        }
+
+        if let Some(ref ssh) = config.ssh {
+            ssh.validate().with_context(|err| format!("ssh validation: {}", err))?;
+        }
+
+        // Otherwise, the user callback gives the account once the disks are ready.
+        if config.oem_mode || !config.users.is_empty() {
+            Self::validate_users(config, &config.users)?;
+        }
+
+        Ok(())
+    }
+
+    /// Validates the parts of the config which depend on the accounts which are created,
+    /// once they are known.
+    pub(crate) fn validate_users(config: &Config, users: &[UserAccountCreate]) -> io::Result<()> {
+        user::validate_administrator(&config.root, users, config.oem_mode)
+            .with_context(|err| format!("root validation: {}", err))?;
+
+        if let Some(ref ssh) = config.ssh {
+            let has_keys = ssh.authorized_keys.iter().any(|keys| !keys.trim().is_empty());
+            if has_keys && users.is_empty() && !ssh.permit_root_login {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "ssh validation: keys are authorized for root when no user is created, but \
+                     root may not log in",
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Validates that the accounts do not conflict with those of the image, if its accounts
+    /// can be read before it is extracted. Otherwise, they are checked once it is.
+    fn verify_accounts(source: &Source, users: &[UserAccountCreate]) -> io::Result<()> {
+        if users.is_empty() {
+            return Ok(());
+        }
+
+        let read = |path| {
+            source
+                .read_file(path)
+                .map(|file| file.map(|f| String::from_utf8_lossy(&f).into_owned()))
+        };
+
+        match (read("etc/passwd")?, read("etc/group")?) {
+            (Some(passwd), Some(group)) => user::verify_image(users, &passwd, &group),
+            _ => {
+                info!("the accounts of the image are checked once it has been extracted");
+                Ok(())
+            }
+        }
+    }
+
+    /// The architecture of the image, as the suffix of its EFI binaries. Tar archives and
+    /// remote sources can't be checked for it, so they are taken to be of the installer's.
+    fn image_arch(source: Option<&Source>) -> io::Result<Option<&'static str>> {
+        let binary = match source {
+            Some(source) => source.read_file("usr/bin/env")?,
+            None => None,
+        };
+
+        Ok(binary
+            .and_then(|binary| efi_fallback::elf_efi_arch(&binary))
+            .or_else(|| efi_fallback::efi_arch(env::consts::ARCH)))
+    }
+
+    /// Validates that the default entry of systemd-boot will be generated for the install,
+    /// and that the image has the signed binaries which Secure Boot requires, which would
+    /// otherwise only be found once the image has been extracted.
+    fn verify_boot(
+        disks: &Disks,
+        source: &Source,
+        config: &Config,
+        firmware: Bootloader,
+    ) -> io::Result<()> {
+        // An automatic choice depends upon the name of the image.
+        let mut os_name = None;
+        for path in &["usr/lib/os-release", "etc/os-release"] {
+            if let Some(release) = source.read_file(path)? {
+                let release = String::from_utf8_lossy(&release);
+                os_name = Some(release.lines().map(String::from).collect::<OsRelease>().name);
+                break;
+            }
+        }
+
+        let loader = match os_name {
+            Some(ref name) => config.bootloader.resolve(firmware, name),
+            None if config.bootloader == BootloaderChoice::Auto => {
+                info!("the bootloader is checked once the image has been extracted");
+                return Ok(());
+            }
+            None => config.bootloader.resolve(firmware, ""),
+        };
+
+        let systemd_boot = loader == BootloaderChoice::SystemdBoot;
+        if let Some(default) = config.systemd_boot.default.as_ref().filter(|_| systemd_boot) {
+            let boot = source.list_dir("boot")?;
+            let recovery =
+                config.recovery.is_some() || disks.find_partition(Path::new("/recovery")).is_some();
+            steps::verify_default(default, boot.as_deref(), recovery)
+                .with_context(|err| format!("systemd-boot validation: {}", err))?;
+        }
+
+        // Secure Boot is only known from the variables of the live system.
+        let vars = match firmware {
+            Bootloader::Efi if !crate::NO_EFI_VARIABLES.load(Ordering::Relaxed) => {
+                EfiVars::open(Path::new(EFIVARS))
+            }
+            _ => None,
+        };
+
+        let secure_boot = match vars {
+            Some(ref vars) => vars.secure_boot()?,
+            None => false,
+        };
+
+        // The binaries are named for the architecture of the image, which tar archives and
+        // remote sources can't be checked for.
+        let binary = if secure_boot { source.read_file("usr/bin/env")? } else { None };
+        if let Some(binary) = binary {
+            let arch = efi_fallback::elf_efi_arch(&binary)
+                .or_else(|| efi_fallback::efi_arch(env::consts::ARCH))
+                .unwrap_or("x64");
+            SignedChain::locate(systemd_boot, arch, |path| {
+                source.contains(path).map(|found| found.unwrap_or(true))
+            })?;
+        }
+
+        Ok(())
+    }
+
+    /// Validates that the image has the initramfs hooks which the devices of the root file
+    /// system require, such as those of LVM, which would otherwise only be found missing once
+    /// the image has been configured.
+    fn verify_initramfs(disks: &Disks, source: &Source, config: &Config) -> io::Result<()> {
+        let contains = |path: &str| source.contains(path).map(|found| found.unwrap_or(true));
+        let tool = match config.initramfs {
+            Some(tool) => tool,
+            None => InitramfsTool::detect_with(contains)?.unwrap_or(InitramfsTool::InitramfsTools),
+        };
+
+        for required in tool.required_files(disks.get_support_flags()) {
+            if !contains(required)? {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("the initramfs of this disk configuration requires /{}", required),
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Validates that the files of the source will fit on the file systems which they will be
+    /// extracted to, once they're formatted.
+    fn verify_space(
+        disks: &Disks,
+        source: &Source,
+        margin: u8,
+        cancel: &CancellationToken,
+    ) -> io::Result<()> {
+        if let Source::Remote(ref url) = *source {
+            info!("skipping the space check, as the size of {} is not known", url);
+            return Ok(());
+        }
+
+        let capacities = disks.get_target_capacities();
+        let targets = capacities.iter().map(|&(target, _)| target).collect::<Vec<_>>();
+        let files = squashfs::file_sizes(source, cancel.flag())?;
+
+        let mut short = Vec::new();
+        for ((target, required), &(_, capacity)) in
+            space::required_space(&files, &targets, margin).into_iter().zip(&capacities)
+        {
+            match capacity {
+                Some(capacity) if capacity < required => short.push(format!(
+                    "{} requires {} bytes, but has {} bytes",
+                    target.display(),
+                    required,
+                    capacity
+                )),
+                Some(_) => info!("{} requires {} bytes", target.display(), required),
+                None => info!("{} requires {} bytes of its free space", target.display(), required),
+            }
+        }
+
+        if short.is_empty() {
+            Ok(())
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("not enough space for the source: {}", short.join(", ")),
+            ))
+        }
+    }
+
+    /// Stages a snapshot of the logical volume of the previous root, which will be taken
+    /// before it is modified.
+    ///
+    /// The previous root is the partition of `config.old_root`, or otherwise the existing
+    /// partition which will be the root of the new install.
+    fn snapshot_previous_root(disks: &mut Disks, config: &Config) -> io::Result<()> {
+        let root = match config.old_root {
+            Some(ref uuid) => disks.get_partition_by_id(&PartitionID::new_uuid(uuid.clone())),
+            None => disks
+                .get_partition_with_target(Path::new("/"))
+                .filter(|p| p.flag_is_enabled(SOURCE)),
+        };
+
+        let root = match root {
+            Some(root) => root.get_device_path().to_path_buf(),
+            None => {
+                warn!("skipping snapshot, as there is no previous root to snapshot");
+                return Ok(());
+            }
+        };
+
+        let name = ["root-preinstall-", &misc::date_stamp()].concat();
+        let created = disks
+            .snapshot_logical_volume(&root, &name, config.snapshot_percent)
+            .with_context(|err| format!("snapshot of previous root: {}", err))?;
+
+        if created {
+            info!("previous root at {} will be snapshotted to {}", root.display(), name);
+        }
+
+        Ok(())
+    }
+
     /// Create a backup of key data on the system, execute the given functi on, and then restore
     /// that backup. If a backup is not requested for the configuration, then it will just
     /// execute the given function.
@@ -241,8 +1476,12 @@ impl Installer {
         let account_files;
         let mut old_backup = None;
 
-        let backup = if let Some(ref old_root_uuid) = config.old_root {
-            info!("installing while retaining home");
+        let backup = if config.mode == InstallMode::Refresh {
+            info!("refreshing the install, while retaining its users and home");
+
+            let old_root_uuid = config.old_root.as_ref().ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidInput, "refresh: the old root was not given")
+            })?;
 
             let old_root = disks
                 .get_partition_by_id(&PartitionID::new_uuid(old_root_uuid.clone()))
@@ -269,13 +1508,51 @@ impl Installer {
 
             account_files = AccountFiles::new(old_root_path, old_root_fs)?;
 
+            steps.run_hooks(Step::Backup, HookWhen::Before, &disks, None)?;
             let backup = steps.apply(Step::Backup, "backing up", |steps| {
                 let mut callback = percent!(steps);
 
-                let backup = Backup::new(home_path, home_fs, home_is_root, &account_files)?;
+                let preserve = match config.preserve {
+                    Some(ref preserve) => preserve.clone(),
+                    None => DEFAULT_PRESERVE.iter().map(PathBuf::from).collect(),
+                };
+
+                let home = if home_is_root { None } else { Some((home_path, home_fs)) };
+                let backup =
+                    Backup::new(old_root_path, old_root_fs, home, &preserve, &account_files)?;
+
+                // Users given an ID by `useradd` are only found to collide once installed.
+                for user in &config.users {
+                    let collides = backup.users.iter().any(|retained| {
+                        retained.user == user.username.as_bytes()
+                            || (user.uid.is_some() && retained.uid() == user.uid)
+                    });
+
+                    if collides {
+                        return Err(io::Error::new(
+                            io::ErrorKind::AlreadyExists,
+                            format!(
+                                "refresh: {} collides with a user of the old install",
+                                user.username
+                            ),
+                        ));
+                    }
+                }
+
                 callback(25);
 
                 validate_backup_conditions(&disks, &config.squashfs)?;
+
+                // The retained accounts are checked against those of the image, if they can
+                // be read before it is extracted, so that they collide before the old root
+                // is removed.
+                let source = steps::find_source(config)?;
+                if let (Some(passwd), Some(group)) =
+                    (source.read_file("etc/passwd")?, source.read_file("etc/group")?)
+                {
+                    backup.merge_accounts(&passwd, &group)?;
+                }
+
                 callback(50);
 
                 if config.flags & KEEP_OLD_ROOT != 0 {
@@ -289,6 +1566,7 @@ impl Installer {
 
                 Ok(backup)
             })?;
+            steps.run_hooks(Step::Backup, HookWhen::After, &disks, None)?;
 
             Some((backup, root_path, root_fs))
         } else {
@@ -323,16 +1601,25 @@ impl Installer {
         Ok(())
     }
 
+    /// Where the log of the last install was kept, if it was written.
+    pub fn install_log(&self) -> Option<&InstallLog> { self.log.as_ref() }
+
+    /// A handle which cancels the installs of this installer, and only this installer.
+    ///
+    /// A cancelled handle stays cancelled until it is reset, so every install which is
+    /// started before then is cancelled as soon as it starts.
+    pub fn cancel_handle(&self) -> CancellationToken { self.cancel.clone() }
+
     /// Send an error message
     ///
     /// ```ignore,rust
     /// use distinst::{Error, Installer, Step};
     /// use std::io;
     /// let mut installer = Installer::new();
-    /// installer.emit_error(&Error {
-    ///     step: Step::Extract,
-    ///     err:  io::Error::new(io::ErrorKind::NotFound, "File not found"),
-    /// });
+    /// installer.emit_error(&Error::new(
+    ///     Step::Extract,
+    ///     io::Error::new(io::ErrorKind::NotFound, "File not found"),
+    /// ));
     /// ```
     pub fn emit_error(&mut self, error: &Error) {
         if let Some(ref mut cb) = self.error_cb {
@@ -356,10 +1643,7 @@ impl Installer {
     /// ```ignore,rust
     /// use distinst::{Installer, Status, Step};
     /// let mut installer = Installer::new();
-    /// installer.emit_status(&Status {
-    ///     step:    Step::Extract,
-    ///     percent: 50,
-    /// });
+    /// installer.emit_status(Status::new(Step::Extract, 50));
     /// ```
     pub fn emit_status(&mut self, status: Status) {
         if let Some(ref mut cb) = self.status_cb {
@@ -383,51 +1667,247 @@ impl Installer {
         self.timezone_cb = Some(Box::new(callback));
     }
 
-    pub fn set_user_callback<F: FnMut() -> UserAccountCreate + 'static>(&mut self, callback: F) {
+    pub fn set_user_callback<F>(&mut self, mut callback: F)
+    where
+        F: FnMut() -> UserAccountCreate + 'static,
+    {
+        self.user_creation_cb = Some(Box::new(move || Ok(callback())));
+    }
+
+    /// Set the user callback, whose error fails the install when it can't give the account.
+    pub fn set_user_callback_checked<F>(&mut self, callback: F)
+    where
+        F: FnMut() -> io::Result<UserAccountCreate> + 'static,
+    {
         self.user_creation_cb = Some(Box::new(callback));
     }
 
+    /// Set the number of threads which decompress the image while extracting it.
+    ///
+    /// By default, or if `threads` is zero, every processor but one is used, up to a limit.
+    pub fn set_extract_threads(&mut self, threads: usize) { self.extract_threads = threads; }
+
+    /// Set the space which is required beyond the size of the source, as a percent of it,
+    /// before an install will partition the disks.
+    ///
+    /// The default is `DEFAULT_SPACE_MARGIN`.
+    pub fn set_space_margin(&mut self, percent: u8) { self.space_margin = percent; }
+
+    /// Set whether the file systems of the install are trimmed once it is configured.
+    ///
+    /// Trimming is enabled by default, and may be disabled for drives with firmware that
+    /// mishandles discards.
+    pub fn set_trim(&mut self, trim: bool) { self.trim = trim; }
+
+    /// Set whether installs are simulated, which partitions the disks and then stops before
+    /// the image is extracted.
+    ///
+    /// Only the installs of this installer are simulated, so another installer may install
+    /// while this one simulates.
+    pub fn set_simulate(&mut self, simulate: bool) { self.simulate = simulate; }
+
+    /// Set the callback which answers the questions that an install asks when the config
+    /// does not answer them, such as the passphrase of an encrypted volume.
+    ///
+    /// The callback may return `None` to leave a question unanswered, in which case the
+    /// install continues as it would have without asking. Installs by `install_async`
+    /// receive questions as events instead.
+    pub fn set_prompt_callback<F>(&mut self, callback: F)
+    where
+        F: FnMut(&Question) -> Option<Answer> + 'static,
+    {
+        self.prompt_cb = Some(Box::new(callback));
+    }
+
+    /// Set how long an install waits for a question to be answered before it fails with an
+    /// `Unanswered` error.
+    ///
+    /// The default is `DEFAULT_PROMPT_TIMEOUT`.
+    pub fn set_prompt_timeout(&mut self, timeout: Duration) { self.prompt_timeout = timeout; }
+
+    /// Set the callback which supplies the secrets of locked LUKS partitions.
+    ///
+    /// The callback is given the path of each locked partition as disks are probed, and may
+    /// return `None` to leave the partition locked.
+    pub fn set_secret_provider<F>(&mut self, callback: F)
+    where
+        F: Fn(&Path) -> Option<String> + 'static,
+    {
+        self.secret_cb = Some(Box::new(callback));
+    }
+
+    /// Registers a hook which will run before or after the given step of the install.
+    ///
+    /// Hooks run in the order that they were registered. If a hook returns an error, the
+    /// install is aborted, and the error is reported with the position of the hook.
+    ///
+    /// ```ignore,rust
+    /// use distinst::{HookWhen, Installer, Step};
+    /// let mut installer = Installer::default();
+    /// installer.hook(Step::Configure, HookWhen::After, |context| {
+    ///     context.chroot()?.command("systemctl", &["enable", "fleet-agent"]).run()
+    /// });
+    /// ```
+    pub fn hook<F>(&mut self, step: Step, when: HookWhen, callback: F)
+    where
+        F: FnMut(&HookContext) -> io::Result<()> + Send + 'static,
+    {
+        let mut hooks = self.hooks.lock().unwrap_or_else(PoisonError::into_inner);
+        hooks.push(Hook { step, when, callback: Box::new(callback) });
+    }
+
     fn initialize<F: FnMut(i32)>(
         disks: &mut Disks,
         config: &Config,
         callback: F,
-    ) -> io::Result<(PathBuf, Vec<String>)> {
+    ) -> io::Result<(Source, Vec<steps::RemoveEntry>)> {
         steps::initialize(disks, config, callback)
     }
 
+    /// Verifies the checksum of the source if one was given, or otherwise that its squashfs
+    /// superblock is intact, so that a corrupted image is caught before the disks are wiped.
+    ///
+    /// A remote source is downloaded to `DOWNLOAD_DIR` on the host and verified there, and the
+    /// downloaded image is returned to be extracted in its place.
+    fn verify<F: FnMut(i32, Option<String>, Option<(u64, u64)>)>(
+        source: &Source,
+        config: &Config,
+        cancel: &CancellationToken,
+        mut callback: F,
+    ) -> io::Result<Source> {
+        if let Source::Remote(ref url) = *source {
+            let dir = Path::new(Self::DOWNLOAD_DIR);
+            fs::create_dir_all(dir)
+                .with_context(|err| format!("creating {}: {}", dir.display(), err))?;
+            return Source::detect(&Self::download(url, dir, config, cancel, callback)?);
+        }
+
+        let checksums: Vec<String> =
+            config.squashfs_checksum.iter().chain(&config.layer_checksums).cloned().collect();
+        let mut last_percent = 0;
+        squashfs::verify_source(source, &checksums, cancel.flag(), |progress| {
+            let percent = progress.percent();
+            if percent != last_percent {
+                callback(percent, None, transfer(&progress));
+                last_percent = percent;
+            }
+        })?;
+
+        Ok(source.clone())
+    }
+
+    /// Downloads the image at the URL into the directory, and verifies its checksum if one
+    /// was given.
+    fn download<F: FnMut(i32, Option<String>, Option<(u64, u64)>)>(
+        url: &str,
+        dir: &Path,
+        config: &Config,
+        cancel: &CancellationToken,
+        mut callback: F,
+    ) -> io::Result<PathBuf> {
+        let path = dir.join(Self::DOWNLOAD_NAME);
+        let message = format!("downloading {}", url);
+        info!("downloading {} to {}", url, path.display());
+        squashfs::download(url, &path, cancel.flag(), |progress| {
+            callback(progress.percent(), Some(message.clone()), transfer(&progress))
+        })?;
+
+        if let Some(ref checksum) = config.squashfs_checksum {
+            squashfs::verify_checksum(&path, checksum, cancel.flag(), |progress| {
+                let message = Some("verifying checksum".into());
+                callback(progress.percent(), message, transfer(&progress))
+            })?;
+        }
+
+        Ok(path)
+    }
+
     /// Apply all partitioning and formatting changes to the disks
     /// configuration specified.
-    fn partition<F: FnMut(i32)>(disks: &mut Disks, callback: F) -> io::Result<()> {
-        steps::partition(disks, callback)
+    fn partition<F: FnMut(i32, Option<String>, Option<(u64, u64)>)>(
+        disks: &mut Disks,
+        cancel: &CancellationToken,
+        callback: F,
+    ) -> io::Result<()> {
+        steps::partition(disks, cancel, callback)
     }
 
-    /// Extracts the squashfs image into the new install, and then gets the os-release data.
+    /// Extracts the source image into the new install, and then gets the os-release data.
     ///
     /// We get the os-release data here because the host that is installing the image may differ
     /// from the image that is being installed, and thus may be a completely different distro.
-    fn extract<P: AsRef<Path>, F: FnMut(i32)>(
-        squashfs: P,
-        mount_dir: P,
-        callback: F,
+    ///
+    /// A remote source which was not downloaded when it was verified is first downloaded into
+    /// the new install. A downloaded image is removed once it has been extracted, and the
+    /// overlay of the config is copied onto the install.
+    ///
+    /// The callback is given the percent extracted, and the path of the file being extracted.
+    fn extract<F: FnMut(i32, Option<String>, Option<(u64, u64)>)>(
+        source: &Source,
+        config: &Config,
+        mount_dir: &Path,
+        threads: usize,
+        cancel: &CancellationToken,
+        mut callback: F,
     ) -> io::Result<OsRelease> {
-        info!("Extracting {}", squashfs.as_ref().display());
-        let mount_dir = mount_dir.as_ref();
-        squashfs::extract(squashfs, mount_dir, callback)?;
+        let host_download = Path::new(Self::DOWNLOAD_DIR).join(Self::DOWNLOAD_NAME);
+        let downloaded = match *source {
+            Source::Remote(ref url) => {
+                Some(Self::download(url, mount_dir, config, cancel, &mut callback)?)
+            }
+            ref source if source.path() == host_download => Some(host_download),
+            _ => None,
+        };
+
+        let source = match downloaded {
+            Some(ref path) => Source::detect(path)?,
+            None => source.clone(),
+        };
+
+        info!(target: "extract", "extracting {}", source.path().display());
+        squashfs::extract_source(&source, mount_dir, threads, cancel.flag(), |progress| {
+            callback(progress.percent(), progress.path.map(String::from), transfer(&progress))
+        })?;
+
+        if let Some(path) = downloaded {
+            fs::remove_file(&path)
+                .with_context(|err| format!("removing downloaded image: {}", err))?;
+        }
+
+        if let Some(ref dir) = config.overlay {
+            overlay::apply(dir, mount_dir)
+                .with_context(|err| format!("copying the overlay: {}", err))?;
+        }
+
         OsRelease::new_from(&mount_dir.join("etc/os-release")).with_context(|why| {
             format!("failed to parse /etc/os-release from extracted image: {}", why)
         })
     }
 
+    /// Copies the live system to the recovery partition, after the install has been
+    /// extracted.
+    fn recovery<F: FnMut(i32, Option<String>)>(
+        recovery: &RecoveryConfig,
+        mount_dir: &Path,
+        loader: BootloaderChoice,
+        iso_os_release: &OsRelease,
+        cancel: &CancellationToken,
+        callback: F,
+    ) -> io::Result<()> {
+        steps::recovery(recovery, mount_dir, loader, iso_os_release, cancel, callback)
+    }
+
     /// Configures the new install after it has been extracted.
-    fn configure<P: AsRef<Path>, S: AsRef<str>, F: FnMut(i32)>(
+    fn configure<D: InstallerDiskOps, P: AsRef<Path>, F: FnMut(i32, Option<&'static str>)>(
         recovery_conf: Option<&mut RecoveryEnv>,
-        disks: &Disks,
+        disks: &D,
         mount_dir: P,
         config: &Config,
         iso_os_release: &OsRelease,
         region: Option<&Region>,
-        user: Option<&UserAccountCreate>,
-        remove_pkgs: &[S],
+        users: &[UserAccountCreate],
+        remove_pkgs: &[steps::RemoveEntry],
+        cancel: &CancellationToken,
         callback: F,
     ) -> io::Result<()> {
         steps::configure(
@@ -437,15 +1917,16 @@ impl Installer {
             config,
             iso_os_release,
             region,
-            user,
+            users,
             remove_pkgs,
+            cancel,
             callback,
         )
     }
 
     /// Installs and configures the boot loader after it has been configured.
-    fn bootloader<F: FnMut(i32)>(
-        disks: &Disks,
+    fn bootloader<D: InstallerDiskOps, F: FnMut(i32)>(
+        disks: &D,
         mount_dir: &Path,
         bootloader: Bootloader,
         config: &Config,
@@ -461,3 +1942,207 @@ impl From<ReinstallError> for io::Error {
         io::Error::new(io::ErrorKind::Other, format!("{}", why))
     }
 }
+
+/// The bytes transferred of the progress, as the status reports them. The size of a download
+/// may not be known.
+fn transfer(progress: &squashfs::Progress) -> Option<(u64, u64)> {
+    Some((progress.bytes, progress.total)).filter(|&(_, total)| total != 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::recipe::RecipeFile;
+    use disk_types::FileSystem;
+    use std::process::Command;
+
+    #[test]
+    fn installers_are_isolated() {
+        let mut simulated = Installer::default();
+        simulated.set_simulate(true);
+        let installer = Installer::default();
+
+        let (events, _) = mpsc::channel();
+        let (simulation, install) = (simulated.worker(events.clone()), installer.worker(events));
+        assert!(simulation.simulate);
+        assert!(!install.simulate);
+
+        simulated.cancel_handle().cancel();
+        assert!(simulation.cancel.is_cancelled());
+        assert!(!install.cancel.is_cancelled());
+    }
+
+    #[test]
+    fn firmware_of_image_arch() {
+        let verify = |mode, arch| Installer::verify_firmware(mode, BootloaderChoice::Auto, arch);
+        assert!(verify(BootMode::Ia32Efi, Some("x64")).is_err());
+        assert!(verify(BootMode::Ia32Efi, Some("ia32")).is_ok());
+        assert!(verify(BootMode::Efi, Some("x64")).is_ok());
+        assert!(verify(BootMode::Bios, Some("ia32")).is_ok());
+        assert!(verify(BootMode::Bios, Some("aa64")).is_err());
+        assert!(verify(BootMode::Bios, None).is_err());
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn deprecated_keyboard_fields() {
+        let mut config = Config::default();
+        config.keyboard.options = Some("grp:alt_shift_toggle".into());
+        assert_eq!(config.keyboard_config(), config.keyboard);
+
+        config.keyboard_layout = "de".into();
+        config.keyboard_variant = Some("nodeadkeys".into());
+        assert_eq!(
+            config.keyboard_config(),
+            KeyboardConfig {
+                layout:  "de".into(),
+                variant: Some("nodeadkeys".into()),
+                model:   None,
+                options: Some("grp:alt_shift_toggle".into()),
+            }
+        );
+    }
+
+    #[test]
+    fn ssh_keys_of_root() {
+        let mut config = Config::default();
+        config.ssh = Some(SshConfig {
+            authorized_keys: vec!["ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIA== jane@lab".into()],
+            ..SshConfig::default()
+        });
+
+        // The keys are given to root if the user callback gives no account.
+        let jane = UserAccountCreate { username: "jane".into(), ..UserAccountCreate::default() };
+        assert!(Installer::validate_users(&config, &[jane]).is_ok());
+        assert!(Installer::validate_users(&config, &[]).is_err());
+
+        config.ssh.as_mut().unwrap().permit_root_login = true;
+        assert!(Installer::validate_users(&config, &[]).is_ok());
+    }
+
+    #[test]
+    fn local_packages_directory() {
+        let dir = TempDir::new("distinst").unwrap();
+        let mut config = Config::default();
+        config.hostname = "pop-os".into();
+        config.local_packages = Some(dir.path().to_path_buf());
+        assert!(Installer::validate(&config).is_ok());
+
+        // A package, or a directory which does not exist, is not a directory of packages.
+        let deb = dir.path().join("hello_2.10-2_amd64.deb");
+        fs::write(&deb, "").unwrap();
+        for path in vec![deb, dir.path().join("pool")] {
+            config.local_packages = Some(path);
+            let why = Installer::validate(&config).unwrap_err();
+            assert_eq!(why.kind(), io::ErrorKind::NotFound);
+        }
+    }
+
+    struct LoopDevice(PathBuf);
+
+    impl Drop for LoopDevice {
+        fn drop(&mut self) { let _ = Command::new("losetup").arg("-d").arg(&self.0).status(); }
+    }
+
+    fn run(command: &mut Command) {
+        assert!(command.status().unwrap().success(), "{:?} failed", command);
+    }
+
+    /// Attaches a partitioned and formatted disk image, which is staged to be installed to.
+    fn loop_disk(dir: &Path, name: &str) -> (LoopDevice, Disks) {
+        let image = dir.join(name);
+        fs::File::create(&image).unwrap().set_len(8 * 1024 * 1024 * 1024).unwrap();
+
+        let output = Command::new("losetup")
+            .args(&["--find", "--show", "--partscan"])
+            .arg(&image)
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        let device = LoopDevice(PathBuf::from(String::from_utf8(output.stdout).unwrap().trim()));
+
+        run(Command::new("parted").arg("-s").arg(&device.0).args(&[
+            "mklabel", "gpt", "mkpart", "esp", "fat32", "1MiB", "513MiB", "set", "1", "esp", "on",
+            "mkpart", "root", "ext4", "513MiB", "100%",
+        ]));
+        run(Command::new("udevadm").arg("settle"));
+
+        let mut disks = Disks::default();
+        disks.add(Disk::from_name(&device.0).unwrap());
+        {
+            let disk = &mut disks.physical[0];
+            disk.format_partition(1, FileSystem::Fat32).unwrap();
+            disk.get_partition_mut(1).unwrap().set_mount("/boot/efi".into());
+            disk.format_partition(2, FileSystem::Ext4).unwrap();
+            disk.get_partition_mut(2).unwrap().set_mount("/".into());
+        }
+
+        (device, disks)
+    }
+
+    /// Drains the events of an install on another thread, and gives its result along with
+    /// the messages which it logged.
+    fn finish(
+        (handle, events): (JoinHandle<Option<InstallLog>>, Receiver<InstallerEvent>),
+    ) -> (io::Result<InstallReport>, String) {
+        let drained = thread::spawn(move || {
+            let (mut log, mut finished) = (String::new(), None);
+            for event in events {
+                match event {
+                    InstallerEvent::Log { message, .. } => log.push_str(&[&message, "\n"].concat()),
+                    InstallerEvent::Finished(result) => finished = Some(result),
+                    InstallerEvent::Prompt(prompt) => panic!("unexpected prompt: {:?}", prompt),
+                    _ => (),
+                }
+            }
+            (finished.expect("install did not finish"), log)
+        });
+
+        handle.join().unwrap();
+        drained.join().unwrap()
+    }
+
+    /// A simulated and a real install which run at the same time must each keep to their
+    /// own disks, logs, and events.
+    ///
+    /// This requires root, for loop devices, and the `tests/filesystem.squashfs` image
+    /// which `tests/loopback.sh` installs.
+    #[test]
+    #[ignore]
+    fn concurrent_installs() {
+        let squashfs =
+            Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("filesystem.squashfs");
+        let config = |hostname: &str| {
+            let recipe = format!(
+                r#"{{"squashfs": {:?}, "hostname": {:?}, "lang": "en_US.UTF-8",
+                    "timezone": "America/Denver", "oem_mode": true}}"#,
+                squashfs, hostname
+            );
+            RecipeFile::from_json(&recipe).and_then(|recipe| recipe.config()).unwrap()
+        };
+
+        let _ = crate::log(|_, _| ());
+        let dir = TempDir::new("distinst").unwrap();
+        let (simulated_device, simulated_disks) = loop_disk(dir.path(), "simulated.img");
+        let (real_device, real_disks) = loop_disk(dir.path(), "real.img");
+
+        let mut simulated = Installer::default();
+        simulated.set_simulate(true);
+        let simulation = simulated.install_async(simulated_disks, config("simulated"));
+        let install = Installer::default().install_async(real_disks, config("installed"));
+
+        let (simulation, simulation_log) = finish(simulation);
+        let (install, install_log) = finish(install);
+        simulation.expect("simulated install failed");
+        install.expect("real install failed");
+
+        let simulated_device = simulated_device.0.display().to_string();
+        let real_device = real_device.0.display().to_string();
+        assert!(simulation_log.contains(&simulated_device));
+        assert!(!simulation_log.contains(&real_device));
+        assert!(!simulation_log.contains("installed"));
+        assert!(install_log.contains(&real_device));
+        assert!(!install_log.contains(&simulated_device));
+        assert!(!install_log.contains("simulated"));
+    }
+}
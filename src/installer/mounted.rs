@@ -0,0 +1,587 @@
+use super::{
+    bitflags::FileSystemSupport, locales, raid, state::InstallerState, steps,
+    traits::InstallerDiskOps, Config, InstallReport, Installer, Step,
+};
+use crate::{
+    bootloader,
+    disks::{get_preferred_options, is_rotational, Bootloader, Disks},
+    errors::{IntoIoResult, IoContext},
+};
+use disk_types::FileSystem;
+use fstab_generate::{BlockInfo, FstabId};
+use partition_identity::{PartitionID, PartitionSource};
+use proc_mounts::{MountInfo, MountList};
+use std::{
+    ffi::OsString,
+    fs, io,
+    path::{Path, PathBuf},
+    slice,
+};
+
+/// The targets of the install which packages run programs from, so that they must not be
+/// mounted `noexec`.
+const EXEC_TARGETS: &[&str] = &["/", "/usr", "/var", "/tmp"];
+
+/// File systems which were mounted for the install by the caller, each with the path that it
+/// is mounted at within the install.
+pub struct MountedTargets {
+    root:   PathBuf,
+    mounts: Vec<MountedTarget>,
+}
+
+/// A file system which was mounted for the install.
+struct MountedTarget {
+    /// Where the file system is mounted within the install.
+    target:  PathBuf,
+    info:    MountInfo,
+    fs:      FileSystem,
+    /// The options of its entry in the fstab.
+    options: String,
+}
+
+impl MountedTargets {
+    /// Validates that the mounts form the tree of an install: a file system must be mounted
+    /// at `/`, and each of the others must be mounted beneath it at its target, writable,
+    /// and with the options that the install needs of it.
+    pub fn new(mut mounts: Vec<(PathBuf, MountInfo)>) -> io::Result<Self> {
+        mounts.sort_by_key(|(target, _)| target.components().count());
+
+        let root = mounts
+            .iter()
+            .find(|(target, _)| target == Path::new("/"))
+            .map(|(_, info)| info.dest.clone())
+            .into_io_result(|| "no file system is mounted at / of the install")?;
+
+        let live = MountList::new()?;
+        let mut targets = Vec::with_capacity(mounts.len());
+
+        for (target, info) in mounts {
+            let relative = target.strip_prefix("/").map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{} is not an absolute path", target.display()),
+                )
+            })?;
+
+            let expected = root.join(relative);
+            if info.dest != expected {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "{} is mounted at {}, rather than at {}",
+                        target.display(),
+                        info.dest.display(),
+                        expected.display()
+                    ),
+                ));
+            }
+
+            // The options of the caller may differ from those that it was mounted with.
+            let options = match live.get_mount_by_dest(&info.dest) {
+                Some(live) if live.source == info.source => &live.options,
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!(
+                            "{} is not mounted at {}",
+                            info.source.display(),
+                            info.dest.display()
+                        ),
+                    ))
+                }
+            };
+
+            verify_options(&target, options)?;
+
+            let fs = file_system(&info.fstype).into_io_result(|| {
+                format!("{}: {} file systems are not supported", target.display(), info.fstype)
+            })?;
+
+            let options = fstab_options(fs, options);
+            targets.push(MountedTarget { target, info, fs, options });
+        }
+
+        Ok(Self { root, mounts: targets })
+    }
+
+    /// Where the root of the install is mounted.
+    pub fn root(&self) -> &Path { &self.root }
+
+    /// The EFI partition must be mounted at `/boot/efi` when the system boots with EFI.
+    pub fn verify(&self, bootloader: Bootloader) -> io::Result<()> {
+        if bootloader == Bootloader::Efi && self.find(Path::new("/boot/efi")).is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "the EFI partition must be mounted at /boot/efi",
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn find(&self, target: &Path) -> Option<&MountedTarget> {
+        self.mounts.iter().find(|mount| mount.target == target)
+    }
+}
+
+impl InstallerDiskOps for MountedTargets {
+    fn generate_fstabs(&self, id: FstabId) -> (OsString, OsString) {
+        info!("generating /etc/crypttab & /etc/fstab from the mounts of the install");
+        let mut crypttab = OsString::with_capacity(1024);
+        let mut unlocked = Vec::new();
+
+        for mount in &self.mounts {
+            for (name, device) in crypt_devices(&mount.info.source) {
+                if unlocked.contains(&name) {
+                    continue;
+                }
+
+                match PartitionID::get_uuid(&device) {
+                    Some(uuid) => {
+                        crypttab.push(&name);
+                        crypttab.push(" UUID=");
+                        crypttab.push(&uuid.id);
+                        crypttab.push(if is_rotational(&device) {
+                            " none luks\n"
+                        } else {
+                            " none luks,discard\n"
+                        });
+                    }
+                    None => warn!("unable to find UUID for {} -- skipping", device.display()),
+                }
+
+                unlocked.push(name);
+            }
+        }
+
+        let mut fstab = write_fstab(&self.mounts, id, |source, variant| {
+            PartitionID::get_source(variant, source)
+        });
+
+        info!("generated the following crypttab data:\n{}", crypttab.to_string_lossy());
+        info!("generated the following fstab data:\n{}", fstab.to_string_lossy());
+
+        crypttab.shrink_to_fit();
+        fstab.shrink_to_fit();
+        (crypttab, fstab)
+    }
+
+    fn get_block_info_of(&self, path: &str, id: FstabId) -> io::Result<BlockInfo> {
+        self.find(Path::new(path))
+            .and_then(|mount| {
+                let uid = id.partition_id(&mount.info.source, mount.fs)?;
+                Some(BlockInfo::new(uid, mount.fs, Some(&mount.target), &mount.options))
+            })
+            .into_io_result(|| "root partition not found")
+    }
+
+    /// Swap is not among the mounts, so there is nothing to resume from.
+    fn get_resume_uuid(&self) -> Option<String> { None }
+
+    /// Encrypted devices were unlocked by the caller.
+    fn get_passphrases(&self) -> Vec<&str> { Vec::new() }
+
+    fn get_support_flags(&self) -> FileSystemSupport {
+        let mut flags = FileSystemSupport::empty();
+
+        for mount in &self.mounts {
+            if !crypt_devices(&mount.info.source).is_empty() {
+                flags |= FileSystemSupport::LUKS;
+            }
+
+            if is_logical_volume(&mount.info.source) {
+                flags |= FileSystemSupport::LVM;
+            }
+
+            match mount.fs {
+                FileSystem::Btrfs => flags |= FileSystemSupport::BTRFS,
+                FileSystem::Ext2 | FileSystem::Ext3 | FileSystem::Ext4 => {
+                    flags |= FileSystemSupport::EXT4
+                }
+                FileSystem::F2fs => flags |= FileSystemSupport::F2FS,
+                FileSystem::Fat16 | FileSystem::Fat32 => flags |= FileSystemSupport::FAT,
+                FileSystem::Ntfs => flags |= FileSystemSupport::NTFS,
+                FileSystem::Xfs => flags |= FileSystemSupport::XFS,
+                _ => (),
+            }
+        }
+
+        flags
+    }
+
+    /// None of the file systems are formatted by the installer.
+    fn get_target_capacities(&self) -> Vec<(&Path, Option<u64>)> {
+        self.mounts.iter().map(|mount| (mount.target.as_path(), None)).collect()
+    }
+
+    fn get_boot_device(&self, bootloader: Bootloader) -> io::Result<(PathBuf, i32)> {
+        let (target, in_partition) = match bootloader {
+            Bootloader::Bios => ("/", false),
+            Bootloader::Efi => ("/boot/efi", true),
+        };
+
+        let mount = self
+            .find(Path::new(target))
+            .into_io_result(|| format!("no file system is mounted at {} of the install", target))?;
+
+        let partition = physical_device(&mount.info.source);
+        let name = sys_name(&partition);
+        let sys = Path::new("/sys/class/block").join(&name);
+
+        // The parent of a partition within sysfs is the disk that it is on.
+        let disk = sys
+            .canonicalize()?
+            .parent()
+            .and_then(Path::file_name)
+            .map(|disk| Path::new("/dev").join(disk))
+            .into_io_result(|| format!("unable to find the disk of {}", partition.display()))?;
+
+        let number = if in_partition {
+            fs::read_to_string(sys.join("partition"))
+                .ok()
+                .and_then(|number| number.trim().parse::<i32>().ok())
+                .into_io_result(|| format!("{} is not a partition", partition.display()))?
+        } else {
+            0
+        };
+
+        Ok((disk, number))
+    }
+
+    fn get_boot_devices(&self, bootloader: Bootloader) -> io::Result<Vec<(PathBuf, i32)>> {
+        let target = match bootloader {
+            Bootloader::Bios if self.find(Path::new("/boot")).is_some() => "/boot",
+            Bootloader::Bios => "/",
+            Bootloader::Efi => "/boot/efi",
+        };
+
+        let mount = self
+            .find(Path::new(target))
+            .into_io_result(|| format!("no file system is mounted at {} of the install", target))?;
+
+        match raid::boot_devices(&mount.info.source, bootloader)? {
+            Some(devices) => Ok(devices),
+            None => Ok(vec![self.get_boot_device(bootloader)?]),
+        }
+    }
+
+    /// The PReP partition is not mounted, so it is not among the mounts.
+    fn get_prep_device(&self) -> io::Result<PathBuf> {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "GRUB for Open Firmware is not installed to existing mounts, which lack the PReP \
+             partition",
+        ))
+    }
+}
+
+impl Installer {
+    /// Installs onto file systems which were already mounted by the caller, instead of
+    /// partitioning disks, with each mount given with the path that it is mounted at
+    /// within the install.
+    ///
+    /// The Partition and Recovery steps are skipped, and the fstab and crypttab of the
+    /// install are generated from the mounts, keeping the subvolumes of btrfs mounts. Each
+    /// mount must be writable, and `/`, `/usr`, `/var`, and `/tmp` must not be `noexec`.
+    /// Encrypted devices must already be unlocked, and hooks are not run, as there are no
+    /// disks to give to them.
+    pub fn install_to_mounts(
+        &mut self,
+        mounts: Vec<(PathBuf, MountInfo)>,
+        config: &Config,
+    ) -> io::Result<InstallReport> {
+        self.run_sync(move |steps, log| {
+            Self::preflight(&Disks::default(), config, steps)?;
+            let targets = MountedTargets::new(mounts)?;
+            info!(
+                "installing from {} to the file systems mounted at {}",
+                config.squashfs,
+                targets.root().display()
+            );
+
+            let target_log = log.target(targets.root(), Vec::new());
+            let result = Self::install_mounted(&targets, config, steps);
+            drop(target_log);
+            result
+        })
+    }
+
+    fn install_mounted(
+        targets: &MountedTargets,
+        config: &Config,
+        steps: &mut InstallerState,
+    ) -> io::Result<()> {
+        let root = targets.root();
+        let cancel = steps.worker.cancel.clone();
+        let mode = bootloader::detect();
+        let bootloader = config.bootloader.firmware(mode.bootloader());
+
+        let (source, remove_pkgs) = steps.apply(Step::Init, "initializing", |steps| {
+            Self::validate(config)?;
+            Self::verify_firmware(mode, config.bootloader)?;
+            targets.verify(bootloader)?;
+
+            if config.recovery.is_some() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "the recovery partition is not copied by installs to existing mounts",
+                ));
+            }
+
+            if let Some(ref network) = config.network {
+                network
+                    .verify_reachable()
+                    .with_context(|err| format!("network validation: {}", err))?;
+            }
+
+            let source = steps::find_source(config)?;
+            locales::verify(&source, config)?;
+            let remove_pkgs = steps::read_remove_manifest(config)?;
+            steps.status.percent = 100;
+            let status = steps.status.clone();
+            steps.emit_status(status);
+            Ok((source, remove_pkgs))
+        })?;
+
+        let source = steps.apply(Step::Verify, "verifying", |steps| {
+            Installer::verify(&source, config, &cancel, |percent, message, bytes| {
+                steps.emit_transfer(percent, message, bytes)
+            })
+        })?;
+
+        info!("skipping partitioning, as the file systems are already mounted");
+
+        let threads = steps.worker.extract_threads;
+        let iso_os_release = steps.apply(Step::Extract, "extracting", |steps| {
+            Installer::extract(&source, config, root, threads, &cancel, |percent, path, bytes| {
+                steps.emit_transfer(percent, path, bytes)
+            })
+        })?;
+
+        let timezone = steps.timezone();
+        let callback_user = steps.user(config)?;
+        let users = callback_user.as_ref().map_or(&config.users[..], slice::from_ref);
+        if !config.oem_mode && config.users.is_empty() {
+            Self::validate_users(config, users)?;
+        }
+
+        steps.apply(Step::Configure, "configuring chroot", |steps| {
+            Installer::configure(
+                None,
+                targets,
+                root,
+                config,
+                &iso_os_release,
+                timezone.as_ref(),
+                users,
+                &remove_pkgs,
+                &cancel,
+                |percent, substep| steps.emit_substep(percent, substep),
+            )
+        })?;
+
+        if steps.worker.trim {
+            steps::trim(root);
+        }
+
+        steps.apply(Step::Bootloader, "configuring bootloader", |steps| {
+            Installer::bootloader(targets, root, bootloader, config, &iso_os_release, |percent| {
+                steps.status.percent = percent;
+                let status = steps.status.clone();
+                steps.emit_status(status);
+            })
+        })
+    }
+}
+
+/// The fstab of the mounts, with each identified by the ID that `lookup` finds for its
+/// source.
+fn write_fstab<F>(mounts: &[MountedTarget], id: FstabId, lookup: F) -> OsString
+where
+    F: Fn(&Path, PartitionSource) -> Option<PartitionID>,
+{
+    let mut fstab = OsString::with_capacity(1024);
+    for mount in mounts {
+        let source = &mount.info.source;
+        match id.partition_id_from(source, mount.fs, |variant| lookup(source, variant)) {
+            Some(uid) => BlockInfo::new(uid, mount.fs, Some(&mount.target), &mount.options)
+                .write_entry(&mut fstab),
+            None => warn!("unable to find an ID for {} -- skipping", source.display()),
+        }
+    }
+
+    fstab
+}
+
+/// The install is written to each mount, and packages run programs from some of them.
+fn verify_options(target: &Path, options: &[String]) -> io::Result<()> {
+    let has = |option: &str| options.iter().any(|o| o == option);
+    let invalid = if has("ro") {
+        "read-only"
+    } else if has("noexec") && EXEC_TARGETS.iter().any(|exec| target == Path::new(exec)) {
+        "noexec"
+    } else {
+        return Ok(());
+    };
+
+    Err(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("{} of the install is mounted {}", target.display(), invalid),
+    ))
+}
+
+/// The options of the fstab entry of a mount: the preferred options of its file system, and
+/// the subvolume of a btrfs mount, which is otherwise lost on the next boot.
+fn fstab_options(fs: FileSystem, options: &[String]) -> String {
+    let preferred = get_preferred_options(fs);
+    match options.iter().find(|option| option.starts_with("subvol=")) {
+        Some(subvolume) if fs == FileSystem::Btrfs => [preferred, ",", subvolume].concat(),
+        _ => preferred.to_owned(),
+    }
+}
+
+/// The kernel names vfat what blkid and parted name fat32.
+fn file_system(fstype: &str) -> Option<FileSystem> {
+    match fstype {
+        "vfat" => Some(FileSystem::Fat32),
+        fstype => fstype.parse().ok(),
+    }
+}
+
+/// The name of the device within sysfs, such as `dm-0` for `/dev/mapper/cryptdata`.
+fn sys_name(device: &Path) -> OsString {
+    let device = device.canonicalize().unwrap_or_else(|_| device.to_path_buf());
+    device.file_name().map_or_else(OsString::new, |name| name.to_os_string())
+}
+
+/// The devices which a device mapper device is made from.
+fn slaves(device: &Path) -> Vec<PathBuf> {
+    let path = Path::new("/sys/class/block").join(sys_name(device)).join("slaves");
+    fs::read_dir(path)
+        .map(|entries| {
+            entries
+                .filter_map(Result::ok)
+                .map(|entry| Path::new("/dev").join(entry.file_name()))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Device mapper devices are identified by the target which created them, such as `CRYPT-`
+/// or `LVM-`.
+fn dm_uuid(device: &Path) -> Option<String> {
+    let path = Path::new("/sys/class/block").join(sys_name(device)).join("dm/uuid");
+    fs::read_to_string(path).ok()
+}
+
+fn is_logical_volume(device: &Path) -> bool {
+    dm_uuid(device).map_or(false, |uuid| uuid.starts_with("LVM-"))
+        || slaves(device).iter().any(|slave| is_logical_volume(slave))
+}
+
+/// The encrypted devices beneath a device, with the names that they are unlocked as, and
+/// the LUKS devices that they are unlocked from.
+fn crypt_devices(device: &Path) -> Vec<(String, PathBuf)> {
+    let mut devices = Vec::new();
+    let slaves = slaves(device);
+
+    if dm_uuid(device).map_or(false, |uuid| uuid.starts_with("CRYPT-")) {
+        let path = Path::new("/sys/class/block").join(sys_name(device)).join("dm/name");
+        if let Ok(name) = fs::read_to_string(path) {
+            let name = name.trim();
+            devices.extend(slaves.iter().map(|slave| (name.to_owned(), slave.clone())));
+        }
+    }
+
+    for slave in &slaves {
+        devices.extend(crypt_devices(slave));
+    }
+
+    devices
+}
+
+/// Follows device mapper devices down to the partition which they are on.
+fn physical_device(device: &Path) -> PathBuf {
+    match slaves(device).into_iter().next() {
+        Some(slave) => physical_device(&slave),
+        None => device.to_path_buf(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mount(target: &str, source: &str, fs: FileSystem, options: &str) -> MountedTarget {
+        let fstype: &str = fs.into();
+        let info = MountInfo {
+            source:  PathBuf::from(source),
+            dest:    Path::new("/target").join(target.trim_start_matches('/')),
+            fstype:  fstype.into(),
+            options: options.split(',').map(String::from).collect(),
+            dump:    0,
+            pass:    0,
+        };
+
+        let options = fstab_options(fs, &info.options);
+        MountedTarget { target: PathBuf::from(target), info, fs, options }
+    }
+
+    #[test]
+    fn options() {
+        let rw = ["rw".to_owned(), "noexec".to_owned()];
+        assert!(verify_options(Path::new("/home"), &rw).is_ok());
+        assert!(verify_options(Path::new("/boot/efi"), &rw).is_ok());
+        assert!(verify_options(Path::new("/"), &rw).is_err());
+        assert!(verify_options(Path::new("/var"), &rw).is_err());
+        assert!(verify_options(Path::new("/home"), &["ro".to_owned()]).is_err());
+
+        let btrfs = ["rw".to_owned(), "subvolid=256".to_owned(), "subvol=/@".to_owned()];
+        let preferred = get_preferred_options(FileSystem::Btrfs);
+        assert_eq!(fstab_options(FileSystem::Btrfs, &btrfs), [preferred, ",subvol=/@"].concat());
+        let ext4 = get_preferred_options(FileSystem::Ext4);
+        assert_eq!(fstab_options(FileSystem::Ext4, &btrfs), ext4);
+    }
+
+    #[test]
+    fn fstab() {
+        let mounts = [
+            mount("/", "/dev/mapper/data-root", FileSystem::Btrfs, "rw,subvol=/@"),
+            mount("/home", "/dev/mapper/data-root", FileSystem::Btrfs, "rw,subvol=/@home"),
+            mount("/boot/efi", "/dev/sda1", FileSystem::Fat32, "rw"),
+        ];
+
+        let lookup = |source: &Path, variant| {
+            let id = match (source.to_str(), variant) {
+                (Some("/dev/mapper/data-root"), PartitionSource::UUID) => "9a4f2c7e",
+                (Some("/dev/sda1"), PartitionSource::PartUUID) => "e0f9a1c2-01",
+                _ => return None,
+            };
+
+            Some(PartitionID { variant, id: id.into() })
+        };
+
+        let btrfs = get_preferred_options(FileSystem::Btrfs);
+        let fat = get_preferred_options(FileSystem::Fat32);
+        let expected = [
+            format!("UUID=9a4f2c7e  /  btrfs  {},subvol=/@  0  1\n", btrfs),
+            format!("UUID=9a4f2c7e  /home  btrfs  {},subvol=/@home  0  0\n", btrfs),
+            format!("PARTUUID=e0f9a1c2-01  /boot/efi  vfat  {}  0  0\n", fat),
+        ]
+        .concat();
+
+        assert_eq!(write_fstab(&mounts, FstabId::Uuid, lookup), OsString::from(&expected));
+
+        // A device mapper device is identified by its path.
+        let fstab = write_fstab(&mounts, FstabId::Path, lookup);
+        assert!(fstab.to_str().unwrap().starts_with("/dev/mapper/data-root  /  btrfs"));
+    }
+
+    #[test]
+    fn file_systems() {
+        assert_eq!(file_system("vfat"), Some(FileSystem::Fat32));
+        assert_eq!(file_system("ext4"), Some(FileSystem::Ext4));
+        assert_eq!(file_system("btrfs"), Some(FileSystem::Btrfs));
+        assert_eq!(file_system("tmpfs"), None);
+    }
+}
@@ -0,0 +1,161 @@
+use crate::errors::IoContext;
+use std::{
+    fs, io,
+    path::{Component, Path, PathBuf},
+    process::Command,
+};
+
+/// Validates the overlay before any disks are modified.
+pub fn validate(overlay: &Path) -> io::Result<()> {
+    if !overlay.is_dir() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} is not a directory", overlay.display()),
+        ));
+    }
+
+    walk(overlay, Path::new(""), &mut |_, _| Ok(()))
+}
+
+/// Copies the files of the overlay onto the target, preserving their ownership, modes, ACLs,
+/// and extended attributes. Symlinks are copied as symlinks, and the symlinks of the target
+/// to directories are followed, so that `lib` remains a link to `usr/lib`.
+///
+/// Each file of the target which is replaced is recorded in the log.
+pub fn apply(overlay: &Path, target: &Path) -> io::Result<()> {
+    info!("copying the overlay at {} to the install", overlay.display());
+
+    let mut replaced = Vec::new();
+    walk(overlay, Path::new(""), &mut |relative, is_dir| {
+        let path = target.join(relative);
+        let metadata = match path.symlink_metadata() {
+            Ok(metadata) => metadata,
+            Err(_) => return Ok(()),
+        };
+
+        if !is_dir {
+            replaced.push(relative.to_path_buf());
+        } else if metadata.file_type().is_symlink() {
+            // The link is followed on the host, and so must stay within the target.
+            let link = fs::read_link(&path)?;
+            if link.is_absolute() || escapes(relative, &link) {
+                return Err(escaped(relative, &link));
+            }
+        }
+
+        Ok(())
+    })?;
+
+    for path in &replaced {
+        info!("the overlay replaces /{}", path.display());
+    }
+
+    info!("the overlay replaces {} files of the install", replaced.len());
+
+    let status = Command::new("rsync")
+        .args(&["--archive", "--hard-links", "--acls", "--xattrs", "--keep-dirlinks"])
+        .arg("--numeric-ids")
+        // The trailing slashes copy the contents of the overlay, rather than the overlay.
+        .arg(overlay.join(""))
+        .arg(target.join(""))
+        .status()
+        .with_context(|err| format!("failed to run rsync: {}", err))?;
+
+    if status.success() {
+        Ok(())
+    } else {
+        Err(io::Error::new(io::ErrorKind::Other, format!("rsync failed with {}", status)))
+    }
+}
+
+/// Calls `func` with the path of each entry of the overlay, relative to the overlay, and
+/// whether it is a directory. Symlinks which would point outside of the target are rejected.
+fn walk<F: FnMut(&Path, bool) -> io::Result<()>>(
+    overlay: &Path,
+    relative: &Path,
+    func: &mut F,
+) -> io::Result<()> {
+    let dir = overlay.join(relative);
+    let entries = fs::read_dir(&dir)
+        .with_context(|err| format!("failed to read {}: {}", dir.display(), err))?;
+
+    for entry in entries {
+        let entry = entry?;
+        let relative = relative.join(entry.file_name());
+        let file_type = entry.file_type()?;
+
+        if file_type.is_symlink() {
+            let link = fs::read_link(entry.path())?;
+            if escapes(&relative, &link) {
+                return Err(escaped(&relative, &link));
+            }
+        }
+
+        func(&relative, file_type.is_dir())?;
+        if file_type.is_dir() {
+            walk(overlay, &relative, func)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Whether the relative link at `path` resolves to a parent of the root. Absolute links
+/// are resolved within the root of the install once it is booted.
+fn escapes(path: &Path, link: &Path) -> bool {
+    if link.is_absolute() {
+        return false;
+    }
+
+    let mut depth = path.components().count() as isize - 1;
+    for component in link.components() {
+        match component {
+            Component::ParentDir => depth -= 1,
+            Component::Normal(_) => depth += 1,
+            _ => (),
+        }
+
+        if depth < 0 {
+            return true;
+        }
+    }
+
+    false
+}
+
+fn escaped(path: &Path, link: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("/{} links outside of the install, to {}", path.display(), link.display()),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+    use tempdir::TempDir;
+
+    #[test]
+    fn links() {
+        assert!(!escapes(Path::new("lib"), Path::new("usr/lib")));
+        assert!(!escapes(Path::new("etc/alternatives/x"), Path::new("../../usr/bin/x")));
+        assert!(!escapes(Path::new("etc/x"), Path::new("/usr/share/x")));
+        assert!(escapes(Path::new("etc/x"), Path::new("../../host")));
+        assert!(escapes(Path::new("x"), Path::new("a/../../y")));
+    }
+
+    #[test]
+    fn overlay() {
+        let overlay = TempDir::new("distinst").unwrap();
+        let overlay = overlay.path();
+        fs::create_dir_all(overlay.join("etc/default")).unwrap();
+        fs::write(overlay.join("etc/default/grub"), "").unwrap();
+        symlink("../usr/share/wallpaper.png", overlay.join("etc/wallpaper")).unwrap();
+        assert!(validate(overlay).is_ok());
+
+        symlink("../../../../root", overlay.join("etc/default/escape")).unwrap();
+        let why = validate(overlay).unwrap_err().to_string();
+        assert!(why.starts_with("/etc/default/escape links outside"), "{}", why);
+    }
+}
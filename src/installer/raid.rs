@@ -0,0 +1,127 @@
+use crate::{disks::Bootloader, errors::IntoIoResult};
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+/// Where the kernel lists the block devices.
+const SYS_BLOCK: &str = "/sys/class/block";
+
+/// The members of the RAID1 array which `device` is, or which it is built on, as with LUKS
+/// or a logical volume on the array.
+pub(crate) fn raid1_members(device: &Path) -> Option<Vec<PathBuf>> {
+    let device = device.canonicalize().ok()?;
+    let name = device.file_name()?.to_str()?;
+    let members = members(Path::new(SYS_BLOCK), name)?;
+    Some(members.into_iter().map(|member| Path::new("/dev").join(member)).collect())
+}
+
+/// Where the bootloader is installed to for a boot partition on the RAID1 array of `device`,
+/// which is every member of the array: their disks for BIOS, and their partitions for EFI.
+pub(crate) fn boot_devices(
+    device: &Path,
+    bootloader: Bootloader,
+) -> io::Result<Option<Vec<(PathBuf, i32)>>> {
+    let members = match raid1_members(device) {
+        Some(members) => members,
+        None => return Ok(None),
+    };
+
+    info!("{} is on a RAID1 array of {:?}", device.display(), members);
+    let disks = member_disks(&members)?;
+    Ok(Some(match bootloader {
+        Bootloader::Bios => disks.into_iter().map(|(disk, _)| (disk, 0)).collect(),
+        Bootloader::Efi => disks,
+    }))
+}
+
+/// The disk of each member of an array, and the number of the partition of the member, or
+/// 0 for a member which is a whole disk.
+pub(crate) fn member_disks(members: &[PathBuf]) -> io::Result<Vec<(PathBuf, i32)>> {
+    members
+        .iter()
+        .map(|member| {
+            let name = member.file_name().and_then(|name| name.to_str());
+            name.and_then(|name| member_disk(Path::new(SYS_BLOCK), name))
+                .map(|(disk, number)| (Path::new("/dev").join(disk), number))
+                .into_io_result(|| format!("unable to find the disk of {}", member.display()))
+        })
+        .collect()
+}
+
+fn members(sys: &Path, name: &str) -> Option<Vec<String>> {
+    let device = sys.join(name);
+    let slaves = slaves(&device);
+    match fs::read_to_string(device.join("md/level")) {
+        Ok(ref level) if level.trim() == "raid1" => Some(slaves),
+        Ok(_) => None,
+        // A device mapper device which is made from one device may be on an array.
+        Err(_) => match slaves.as_slice() {
+            [slave] => members(sys, slave),
+            _ => None,
+        },
+    }
+}
+
+fn member_disk(sys: &Path, name: &str) -> Option<(String, i32)> {
+    let device = sys.join(name);
+    match fs::read_to_string(device.join("partition")) {
+        // The parent of a partition within sysfs is the disk that it is on.
+        Ok(number) => {
+            let disk = device.canonicalize().ok()?;
+            let disk = disk.parent()?.file_name()?.to_str()?.to_owned();
+            Some((disk, number.trim().parse().ok()?))
+        }
+        Err(_) => Some((name.to_owned(), 0)),
+    }
+}
+
+fn slaves(device: &Path) -> Vec<String> {
+    let mut slaves = fs::read_dir(device.join("slaves"))
+        .map(|entries| {
+            entries
+                .filter_map(Result::ok)
+                .filter_map(|entry| entry.file_name().into_string().ok())
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+
+    slaves.sort();
+    slaves
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+    use tempdir::TempDir;
+
+    #[test]
+    fn raid1() {
+        let sys = TempDir::new("distinst").unwrap();
+        let sys = sys.path();
+        for (disk, partition) in &[("sda", "sda2"), ("sdb", "sdb2")] {
+            let dir = sys.join("devices").join(disk).join(partition);
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(dir.join("partition"), "2\n").unwrap();
+            symlink(&dir, sys.join(partition)).unwrap();
+        }
+
+        fs::create_dir_all(sys.join("md0/md")).unwrap();
+        fs::create_dir_all(sys.join("md0/slaves/sdb2")).unwrap();
+        fs::create_dir_all(sys.join("md0/slaves/sda2")).unwrap();
+        fs::write(sys.join("md0/md/level"), "raid1\n").unwrap();
+
+        // LUKS on the array.
+        fs::create_dir_all(sys.join("dm-0/slaves/md0")).unwrap();
+
+        assert_eq!(members(sys, "md0"), Some(vec!["sda2".into(), "sdb2".into()]));
+        assert_eq!(members(sys, "dm-0"), Some(vec!["sda2".into(), "sdb2".into()]));
+        assert_eq!(members(sys, "sda2"), None);
+        assert_eq!(member_disk(sys, "sdb2"), Some(("sdb".into(), 2)));
+
+        // Striped arrays have no copy on each disk to boot from.
+        fs::write(sys.join("md0/md/level"), "raid0\n").unwrap();
+        assert_eq!(members(sys, "dm-0"), None);
+    }
+}
@@ -0,0 +1,360 @@
+use super::Step;
+use crate::disks::{Disks, PartitionInfo, REMOVE, SOURCE};
+use disk_types::FileSystem;
+use partition_identity::PartitionID;
+use serde::Serializer;
+use std::{
+    fmt, fs, io,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+/// Where the report of an install is written on the target, beside the log of the install.
+pub const TARGET_REPORT: &str = "var/log/installer/distinst-report.json";
+
+/// The time that a step of an install took, and the data which it copied.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct StepReport {
+    pub step:     Step,
+    #[serde(serialize_with = "seconds")]
+    pub duration: Duration,
+    /// The sub-tasks of steps which have several, such as Configure, in the order that they
+    /// were run.
+    pub substeps: Vec<SubstepReport>,
+    /// The bytes which were copied by steps which copy data, such as Extract.
+    pub bytes:    Option<u64>,
+}
+
+impl StepReport {
+    /// The average number of bytes which the step copied per second.
+    pub fn rate(&self) -> Option<f64> {
+        let seconds = self.duration.as_secs_f64();
+        self.bytes.filter(|_| seconds > 0.0).map(|bytes| bytes as f64 / seconds)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct SubstepReport {
+    pub name:     &'static str,
+    #[serde(serialize_with = "seconds")]
+    pub duration: Duration,
+}
+
+/// A device of the install, and where it is mounted.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct DeviceReport {
+    pub device:      PathBuf,
+    pub uuid:        Option<String>,
+    /// Where the device is mounted within the install, which swap has none of.
+    pub target:      Option<PathBuf>,
+    pub file_system: Option<&'static str>,
+}
+
+/// What an install did, and how long each of its steps took.
+///
+/// This is returned by a successful install, and written to `TARGET_REPORT` as JSON.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct InstallReport {
+    #[serde(serialize_with = "seconds")]
+    pub duration:             Duration,
+    /// The steps in the order that they were run. A resumed install lacks the steps which
+    /// were completed before it was interrupted.
+    pub steps:                Vec<StepReport>,
+    pub bytes_extracted:      u64,
+    /// The partitions and logical volumes which the install created.
+    pub partitions_created:   usize,
+    pub partitions_formatted: usize,
+    /// The partitions and logical volumes which are mounted by the install, or are its swap.
+    pub devices:              Vec<DeviceReport>,
+}
+
+impl InstallReport {
+    /// The report of the step, which is the last if it was run more than once.
+    pub fn step(&self, step: Step) -> Option<&StepReport> {
+        self.steps.iter().rev().find(|report| report.step == step)
+    }
+
+    pub fn to_json(&self) -> io::Result<String> { Ok(serde_json::to_string_pretty(self)?) }
+}
+
+/// A summary of the report which fits on a screen.
+impl fmt::Display for InstallReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let steps = self
+            .steps
+            .iter()
+            .map(|report| match report.rate() {
+                Some(rate) => format!(
+                    "{:?} {} @ {} MB/s",
+                    report.step,
+                    HumanDuration(report.duration),
+                    (rate / 1_000_000.0).round()
+                ),
+                None => format!("{:?} {}", report.step, HumanDuration(report.duration)),
+            })
+            .collect::<Vec<_>>();
+
+        writeln!(f, "{}", steps.join(", "))?;
+        writeln!(
+            f,
+            "{} in total: {} partitions created, {} formatted, {} MB extracted",
+            HumanDuration(self.duration),
+            self.partitions_created,
+            self.partitions_formatted,
+            self.bytes_extracted / 1_000_000
+        )?;
+
+        for device in &self.devices {
+            writeln!(
+                f,
+                "{} {} {} {}",
+                device.device.display(),
+                device.target.as_ref().map_or("-".into(), |target| target.display().to_string()),
+                device.file_system.unwrap_or("-"),
+                device.uuid.as_deref().unwrap_or("-")
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Records the report of an install as its steps are run.
+pub(crate) struct Recorder {
+    report:  InstallReport,
+    started: Instant,
+    step:    Option<Instant>,
+    substep: Option<Instant>,
+}
+
+impl Recorder {
+    pub fn new() -> Self {
+        Recorder {
+            report:  InstallReport::default(),
+            started: Instant::now(),
+            step:    None,
+            substep: None,
+        }
+    }
+
+    /// Starts timing the step, and stops timing the step before it.
+    pub fn start_step(&mut self, step: Step) {
+        self.finish_step();
+        self.report.steps.push(StepReport {
+            step,
+            duration: Duration::default(),
+            substeps: Vec::new(),
+            bytes: None,
+        });
+        self.step = Some(Instant::now());
+    }
+
+    /// Stops timing the step which is running, whether it succeeded or not.
+    pub fn finish_step(&mut self) {
+        self.substep(None);
+        if let (Some(started), Some(report)) = (self.step.take(), self.report.steps.last_mut()) {
+            report.duration = started.elapsed();
+        }
+    }
+
+    /// Starts timing the sub-task of the step which is running, if it was not already.
+    pub fn substep(&mut self, name: Option<&'static str>) {
+        let report = match self.report.steps.last_mut() {
+            Some(report) if self.step.is_some() => report,
+            _ => return,
+        };
+
+        let timing = self.substep.is_some();
+        let current = report.substeps.last().filter(|_| timing);
+        if current.map(|substep| substep.name) == name {
+            return;
+        }
+
+        if let (Some(started), Some(substep)) = (self.substep.take(), report.substeps.last_mut()) {
+            substep.duration = started.elapsed();
+        }
+
+        if let Some(name) = name {
+            report.substeps.push(SubstepReport { name, duration: Duration::default() });
+            self.substep = Some(Instant::now());
+        }
+    }
+
+    /// Records the bytes which the step that is running has copied so far.
+    pub fn bytes(&mut self, done: u64) {
+        let running = self.step.is_some();
+        if let Some(report) = self.report.steps.last_mut().filter(|_| running) {
+            report.bytes = Some(done);
+        }
+    }
+
+    /// Counts the partitions and logical volumes which the disks will create, and the
+    /// partitions which they will format.
+    pub fn partitions(&mut self, disks: &Disks) {
+        let created =
+            |part: &&PartitionInfo| !part.flag_is_enabled(SOURCE) && !part.flag_is_enabled(REMOVE);
+        self.report.partitions_created = disks
+            .get_physical_partitions()
+            .chain(disks.get_logical_partitions())
+            .filter(created)
+            .count();
+        self.report.partitions_formatted =
+            disks.get_partitions().filter(|part| part.will_format()).count();
+    }
+
+    /// Records the devices of the disks, once they have been partitioned.
+    pub fn devices(&mut self, disks: &Disks) {
+        let mut devices = disks
+            .get_partitions()
+            .filter(|part| part.target.is_some() || part.filesystem == Some(FileSystem::Swap))
+            .map(|part| DeviceReport {
+                device:      part.device_path.clone(),
+                uuid:        PartitionID::get_uuid(&part.device_path).map(|id| id.id),
+                target:      part.target.clone(),
+                file_system: part.filesystem.map(Into::into),
+            })
+            .collect::<Vec<_>>();
+
+        devices.sort_by(|a, b| a.target.cmp(&b.target));
+        self.report.devices = devices;
+    }
+
+    /// Writes the report, as it is so far, to the target which is mounted at `root`.
+    pub fn write(&mut self, root: &Path) -> io::Result<()> {
+        self.totals();
+
+        let path = root.join(TARGET_REPORT);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        fs::write(&path, self.report.to_json()?)
+    }
+
+    pub fn finish(mut self) -> InstallReport {
+        self.totals();
+        self.report
+    }
+
+    /// Stops timing the step which is running, and totals the report as it is so far.
+    fn totals(&mut self) {
+        self.finish_step();
+        self.report.duration = self.started.elapsed();
+        self.report.bytes_extracted =
+            self.report.step(Step::Extract).and_then(|report| report.bytes).unwrap_or(0);
+    }
+}
+
+/// A duration such as `14s`, `3m02s`, or `1h05m`.
+struct HumanDuration(Duration);
+
+impl fmt::Display for HumanDuration {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let seconds = self.0.as_secs();
+        match (seconds / 3600, seconds / 60 % 60, seconds % 60) {
+            (0, 0, seconds) => write!(f, "{}s", seconds),
+            (0, minutes, seconds) => write!(f, "{}m{:02}s", minutes, seconds),
+            (hours, minutes, _) => write!(f, "{}h{:02}m", hours, minutes),
+        }
+    }
+}
+
+fn seconds<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_f64(duration.as_secs_f64())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disks::{LogicalDevice, PartitionBuilder};
+    use tempdir::TempDir;
+
+    fn step(step: Step, seconds: u64, bytes: Option<u64>) -> StepReport {
+        StepReport { step, duration: Duration::from_secs(seconds), substeps: Vec::new(), bytes }
+    }
+
+    #[test]
+    fn summary() {
+        let report = InstallReport {
+            duration:             Duration::from_secs(3 * 60 + 16),
+            steps:                vec![
+                step(Step::Partition, 14, None),
+                step(Step::Extract, 182, Some(74_984_000_000)),
+            ],
+            bytes_extracted:      74_984_000_000,
+            partitions_created:   3,
+            partitions_formatted: 2,
+            devices:              vec![DeviceReport {
+                device:      "/dev/sda1".into(),
+                uuid:        None,
+                target:      Some("/boot/efi".into()),
+                file_system: Some("fat32"),
+            }],
+        };
+
+        assert_eq!(
+            report.to_string(),
+            "Partition 14s, Extract 3m02s @ 412 MB/s\n3m16s in total: 3 partitions created, 2 \
+             formatted, 74984 MB extracted\n/dev/sda1 /boot/efi fat32 -\n"
+        );
+    }
+
+    #[test]
+    fn durations() {
+        let human = |seconds| HumanDuration(Duration::from_secs(seconds)).to_string();
+        assert_eq!(human(0), "0s");
+        assert_eq!(human(65), "1m05s");
+        assert_eq!(human(3 * 3600 + 4 * 60 + 5), "3h04m");
+    }
+
+    #[test]
+    fn recorder() {
+        let mut recorder = Recorder::new();
+        recorder.bytes(1);
+        recorder.start_step(Step::Extract);
+        recorder.bytes(1024);
+        recorder.start_step(Step::Configure);
+        recorder.substep(Some("locale"));
+        recorder.substep(Some("locale"));
+        recorder.substep(Some("users"));
+        recorder.finish_step();
+        recorder.substep(Some("ignored"));
+
+        let report = recorder.finish();
+        assert_eq!(report.bytes_extracted, 1024);
+        let steps = report.steps.iter().map(|report| report.step).collect::<Vec<_>>();
+        assert_eq!(steps, vec![Step::Extract, Step::Configure]);
+        let substeps = report.steps[1].substeps.iter().map(|s| s.name).collect::<Vec<_>>();
+        assert_eq!(substeps, vec!["locale", "users"]);
+    }
+
+    #[test]
+    fn written_totals() {
+        let mut recorder = Recorder::new();
+        recorder.start_step(Step::Extract);
+        recorder.bytes(1024);
+        recorder.start_step(Step::Configure);
+
+        let root = TempDir::new("distinst").unwrap();
+        recorder.write(root.path()).unwrap();
+        let written = fs::read_to_string(root.path().join(TARGET_REPORT)).unwrap();
+        assert!(written.contains("\"bytes_extracted\": 1024"), "{}", written);
+        assert_eq!(recorder.finish().bytes_extracted, 1024);
+    }
+
+    #[test]
+    fn created_volumes() {
+        let mut device = LogicalDevice::new("data".into(), None, 1 << 24, 512, false);
+        let lv = |name: &str| PartitionBuilder::new(0, 1 << 20, FileSystem::Ext4).name(name.into());
+        device.add_partition(lv("root")).unwrap();
+        device.add_partition(lv("home")).unwrap();
+        device.remove_partition("home").unwrap();
+
+        let mut disks = Disks::default();
+        disks.logical.push(device);
+
+        let mut recorder = Recorder::new();
+        recorder.partitions(&disks);
+        assert_eq!(recorder.finish().partitions_created, 1);
+    }
+}
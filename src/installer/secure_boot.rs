@@ -0,0 +1,193 @@
+use crate::errors::IoContext;
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+/// The binaries of the image which are signed for Secure Boot, which the firmware boots
+/// in a chain: shim, which is signed by Microsoft, and the loader which shim verifies.
+#[derive(Debug, PartialEq)]
+pub(crate) struct SignedChain {
+    pub shim:        PathBuf,
+    /// MokManager, which shim runs to enroll the keys of the machine owner.
+    pub mok_manager: PathBuf,
+    pub loader:      PathBuf,
+}
+
+impl SignedChain {
+    /// Finds the signed binaries in the image at `root`, for GRUB, or for systemd-boot if
+    /// `systemd_boot`. Each binary that is missing is named in the error.
+    pub(crate) fn find(root: &Path, systemd_boot: bool, arch: &str) -> io::Result<Self> {
+        let chain = Self::locate(systemd_boot, arch, |path| Ok(root.join(path).is_file()))?;
+        Ok(SignedChain {
+            shim:        root.join(chain.shim),
+            mok_manager: root.join(chain.mok_manager),
+            loader:      root.join(chain.loader),
+        })
+    }
+
+    /// Finds the paths of the signed binaries within the image, where `exists` tells whether
+    /// the image has a file, so that an image may be checked before it is extracted.
+    pub(crate) fn locate<F>(systemd_boot: bool, arch: &str, mut exists: F) -> io::Result<Self>
+    where
+        F: FnMut(&str) -> io::Result<bool>,
+    {
+        let shim = [
+            ["usr/lib/shim/shim", arch, ".efi.signed.latest"].concat(),
+            ["usr/lib/shim/shim", arch, ".efi.signed"].concat(),
+        ];
+        let mok_manager = [
+            ["usr/lib/shim/mm", arch, ".efi.signed"].concat(),
+            ["usr/lib/shim/mm", arch, ".efi"].concat(),
+        ];
+        let loader = if systemd_boot {
+            ["usr/lib/systemd/boot/efi/systemd-boot", arch, ".efi.signed"].concat()
+        } else {
+            ["usr/lib/grub/", grub_target(arch), "-efi-signed/grub", arch, ".efi.signed"].concat()
+        };
+
+        let mut missing = Vec::new();
+        let mut find = |paths: &[String]| -> io::Result<PathBuf> {
+            for path in paths {
+                if exists(path)? {
+                    return Ok(PathBuf::from(path));
+                }
+            }
+
+            missing.push(["/", &paths[paths.len() - 1]].concat());
+            Ok(PathBuf::new())
+        };
+
+        let chain = SignedChain {
+            shim:        find(&shim)?,
+            mok_manager: find(&mok_manager)?,
+            loader:      find(&[loader])?,
+        };
+
+        if !missing.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!(
+                    "Secure Boot is enabled, but the image lacks the signed binaries which boot \
+                     it: {}",
+                    missing.join(", ")
+                ),
+            ));
+        }
+
+        Ok(chain)
+    }
+
+    /// Installs the chain into `dir` of the ESP at `esp`, with the loader named as shim
+    /// expects, and a `BOOT{ARCH}.CSV` from which shim's fallback restores the boot entry
+    /// of `label`. The path of shim on the ESP is returned.
+    pub(crate) fn install(
+        &self,
+        esp: &Path,
+        dir: &Path,
+        label: &str,
+        arch: &str,
+    ) -> io::Result<PathBuf> {
+        let target = esp.join(dir);
+        fs::create_dir_all(&target)?;
+
+        let shim = ["shim", arch, ".efi"].concat();
+        copy(&self.shim, &target.join(&shim))?;
+        copy(&self.mok_manager, &target.join(["mm", arch, ".efi"].concat()))?;
+        copy(&self.loader, &target.join(["grub", arch, ".efi"].concat()))?;
+
+        let csv = target.join(["BOOT", &arch.to_ascii_uppercase(), ".CSV"].concat());
+        fs::write(&csv, boot_csv(&shim, label))
+            .with_context(|err| format!("failed to write {:?}: {}", csv, err))?;
+
+        info!("installed the signed boot chain to /{}", dir.display());
+        Ok(dir.join(shim))
+    }
+}
+
+/// A `BOOT{ARCH}.CSV` file, which is UTF-16 with a byte order mark.
+pub(crate) fn boot_csv(shim: &str, label: &str) -> Vec<u8> {
+    let line = [shim, ",", label, ",,This is the boot entry for ", label, "\n"].concat();
+    let units = Some(0xfeff).into_iter().chain(line.encode_utf16());
+    units.flat_map(|unit| unit.to_le_bytes().to_vec()).collect()
+}
+
+/// The name of the GRUB platform of the architecture, as in `x86_64-efi`.
+pub(crate) fn grub_target(arch: &str) -> &str {
+    match arch {
+        "ia32" => "i386",
+        "aa64" => "arm64",
+        _ => "x86_64",
+    }
+}
+
+fn copy(source: &Path, target: &Path) -> io::Result<()> {
+    fs::copy(source, target).map(|_| ()).with_context(|err| {
+        format!("failed to copy {} to {}: {}", source.display(), target.display(), err)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn chain() {
+        let root = TempDir::new("distinst").unwrap();
+        let root = root.path();
+
+        let why = SignedChain::find(root, false, "x64").unwrap_err().to_string();
+        assert!(
+            why.ends_with(
+                "/usr/lib/shim/shimx64.efi.signed, /usr/lib/shim/mmx64.efi, \
+                 /usr/lib/grub/x86_64-efi-signed/grubx64.efi.signed"
+            ),
+            "{}",
+            why
+        );
+
+        fs::create_dir_all(root.join("usr/lib/shim")).unwrap();
+        fs::create_dir_all(root.join("usr/lib/systemd/boot/efi")).unwrap();
+        fs::write(root.join("usr/lib/shim/shimx64.efi.signed"), "shim").unwrap();
+        fs::write(root.join("usr/lib/shim/mmx64.efi"), "mm").unwrap();
+        fs::write(root.join("usr/lib/systemd/boot/efi/systemd-bootx64.efi.signed"), "sd").unwrap();
+
+        let why = SignedChain::find(root, false, "x64").unwrap_err().to_string();
+        assert!(why.ends_with(": /usr/lib/grub/x86_64-efi-signed/grubx64.efi.signed"), "{}", why);
+
+        let chain = SignedChain::find(root, true, "x64").unwrap();
+        let esp = root.join("boot/efi");
+        let shim = chain.install(&esp, Path::new("EFI/systemd"), "Pop!_OS", "x64").unwrap();
+        assert_eq!(shim, Path::new("EFI/systemd/shimx64.efi"));
+        assert_eq!(fs::read_to_string(esp.join("EFI/systemd/grubx64.efi")).unwrap(), "sd");
+        assert_eq!(fs::read_to_string(esp.join("EFI/systemd/mmx64.efi")).unwrap(), "mm");
+        assert!(esp.join("EFI/systemd/BOOTX64.CSV").exists());
+    }
+
+    #[test]
+    fn located() {
+        let image = [
+            "usr/lib/shim/shimx64.efi.signed.latest",
+            "usr/lib/shim/mmx64.efi.signed",
+            "usr/lib/systemd/boot/efi/systemd-bootx64.efi.signed",
+        ];
+        let exists = |path: &str| Ok(image.contains(&path));
+
+        let chain = SignedChain::locate(true, "x64", exists).unwrap();
+        assert_eq!(chain.shim, Path::new(image[0]));
+        assert_eq!(chain.loader, Path::new(image[2]));
+        assert!(SignedChain::locate(false, "x64", exists).is_err());
+    }
+
+    #[test]
+    fn csv() {
+        let csv = boot_csv("shimx64.efi", "Pop");
+        assert_eq!(&csv[..6], &[0xff, 0xfe, b's', 0, b'h', 0]);
+        let units = csv.chunks(2).skip(1).map(|unit| u16::from_le_bytes([unit[0], unit[1]]));
+        assert_eq!(
+            String::from_utf16(&units.collect::<Vec<_>>()).unwrap(),
+            "shimx64.efi,Pop,,This is the boot entry for Pop\n"
+        );
+    }
+}
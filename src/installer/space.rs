@@ -0,0 +1,70 @@
+use crate::squashfs::FileSize;
+use std::path::Path;
+
+/// The space that is added to the size of the source by default, as a percent, for the
+/// packages which are installed while configuring, and the metadata of the file systems.
+pub const DEFAULT_SPACE_MARGIN: u8 = 10;
+
+/// Sums the space which the files of the source will take on each of the mount targets,
+/// with the margin added.
+///
+/// Each file is counted against the deepest target which contains it, so that a separate
+/// `/home` or `/usr` takes the files beneath it from the root.
+pub fn required_space<'a>(
+    files: &[FileSize],
+    targets: &[&'a Path],
+    margin: u8,
+) -> Vec<(&'a Path, u64)> {
+    let mut required = vec![0u64; targets.len()];
+    let relative =
+        targets.iter().map(|target| target.strip_prefix("/").unwrap_or(target)).collect::<Vec<_>>();
+
+    for file in files {
+        let deepest = relative
+            .iter()
+            .enumerate()
+            .filter(|&(_, target)| file.path.starts_with(target))
+            .max_by_key(|&(_, target)| target.components().count());
+
+        if let Some((id, _)) = deepest {
+            required[id] += file.size;
+        }
+    }
+
+    targets
+        .iter()
+        .zip(required)
+        .map(|(&target, size)| (target, size + size * u64::from(margin) / 100))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn required() {
+        let file = |path: &str, size| FileSize { path: path.into(), size };
+        let files = [
+            file("", 4096),
+            file("etc/hosts", 4096),
+            file("usr/bin/bash", 1_000_000),
+            file("home", 4096),
+            file("home/user/file", 8192),
+        ];
+
+        let root = [Path::new("/")];
+        assert_eq!(required_space(&files, &root, 0), vec![(Path::new("/"), 1_020_480)]);
+        assert_eq!(required_space(&files, &root, 10), vec![(Path::new("/"), 1_122_528)]);
+
+        let targets = [Path::new("/"), Path::new("/home"), Path::new("/boot/efi")];
+        assert_eq!(
+            required_space(&files, &targets, 0),
+            vec![
+                (Path::new("/"), 1_008_192),
+                (Path::new("/home"), 12_288),
+                (Path::new("/boot/efi"), 0)
+            ]
+        );
+    }
+}
@@ -0,0 +1,191 @@
+use std::io;
+
+/// The types of public keys which OpenSSH accepts in `authorized_keys`.
+const KEY_TYPES: &[&str] = &[
+    "ssh-rsa",
+    "ssh-dss",
+    "ssh-ed25519",
+    "ecdsa-sha2-nistp256",
+    "ecdsa-sha2-nistp384",
+    "ecdsa-sha2-nistp521",
+    "sk-ssh-ed25519@openssh.com",
+    "sk-ecdsa-sha2-nistp256@openssh.com",
+];
+
+/// Access to the install over SSH, for machines which are provisioned without a display.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SshConfig {
+    /// Enable the SSH server of the install, which is installed if the image lacks it.
+    pub enable_server:         bool,
+    /// Lines of an `authorized_keys` file, which are given to the first user that is
+    /// created, or to root if there is none.
+    pub authorized_keys:       Vec<String>,
+    /// Permit root to log in with a key. Root may never log in with a password.
+    pub permit_root_login:     bool,
+    /// Permit only keys to log in, rather than passwords.
+    pub disable_password_auth: bool,
+}
+
+impl SshConfig {
+    /// Validates each line of the authorized keys before any disks are modified.
+    pub(crate) fn validate(&self) -> io::Result<()> { validate_keys(&self.authorized_keys) }
+
+    /// The contents of the `authorized_keys` file.
+    pub(crate) fn authorized_keys(&self) -> String { authorized_keys(&self.authorized_keys) }
+
+    /// The contents of an `sshd_config.d` file, which takes precedence over the defaults
+    /// of the image.
+    pub(crate) fn sshd_conf(&self) -> String {
+        let root = if self.permit_root_login { "prohibit-password" } else { "no" };
+        let mut conf = ["# Written by distinst\nPermitRootLogin ", root, "\n"].concat();
+        if self.disable_password_auth {
+            conf.push_str("PasswordAuthentication no\nKbdInteractiveAuthentication no\n");
+        }
+
+        conf
+    }
+}
+
+/// Validates each line of an `authorized_keys` file, which may be given in several parts.
+pub(crate) fn validate_keys(keys: &[String]) -> io::Result<()> {
+    let lines = keys.iter().flat_map(|keys| keys.lines());
+    for (number, line) in lines.enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        validate_key(line).map_err(|why| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("authorized key on line {} is invalid: {}", number + 1, why),
+            )
+        })?;
+    }
+
+    Ok(())
+}
+
+/// The contents of an `authorized_keys` file, from its parts.
+pub(crate) fn authorized_keys(keys: &[String]) -> String {
+    let mut contents = String::new();
+    for line in keys.iter().flat_map(|keys| keys.lines()) {
+        contents.push_str(line.trim());
+        contents.push('\n');
+    }
+
+    contents
+}
+
+/// Checks that a line has the type of a key, followed by that key in base64, after any
+/// options which precede it.
+fn validate_key(line: &str) -> Result<(), String> {
+    let mut rest = line;
+    if !KEY_TYPES.iter().any(|kind| rest.starts_with(kind)) {
+        // Options are separated by commas, and may have quoted values which contain spaces.
+        let mut quoted = false;
+        let end = rest
+            .char_indices()
+            .find(|&(_, c)| {
+                quoted ^= c == '"';
+                !quoted && c.is_whitespace()
+            })
+            .map(|(pos, _)| pos)
+            .ok_or("the key is missing")?;
+        rest = rest[end..].trim_start();
+    }
+
+    let mut fields = rest.split_whitespace();
+    let kind = fields.next().ok_or("the key is missing")?;
+    if !KEY_TYPES.contains(&kind) {
+        return Err(format!("unknown key type '{}'", kind));
+    }
+
+    let blob = fields.next().ok_or("the key is missing")?;
+    let blob = decode_base64(blob).ok_or("the key is not valid base64")?;
+
+    // The blob begins with the type of the key, prefixed by its length.
+    let embedded = match blob.get(..4) {
+        Some(len) => {
+            let len = u32::from_be_bytes([len[0], len[1], len[2], len[3]]) as usize;
+            blob.get(4..4 + len)
+        }
+        None => None,
+    };
+
+    if embedded != Some(kind.as_bytes()) {
+        return Err(format!("the key is not a {} key", kind));
+    }
+
+    Ok(())
+}
+
+fn decode_base64(input: &str) -> Option<Vec<u8>> {
+    if input.is_empty() || input.len() % 4 != 0 {
+        return None;
+    }
+
+    let data = input.trim_end_matches('=');
+    if input.len() - data.len() > 2 {
+        return None;
+    }
+
+    let mut output = Vec::with_capacity(input.len() / 4 * 3);
+    let (mut buffer, mut bits) = (0u32, 0);
+    for byte in data.bytes() {
+        let value = match byte {
+            b'A'..=b'Z' => byte - b'A',
+            b'a'..=b'z' => byte - b'a' + 26,
+            b'0'..=b'9' => byte - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => return None,
+        };
+
+        buffer = buffer << 6 | u32::from(value);
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            output.push((buffer >> bits) as u8);
+        }
+    }
+
+    Some(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ED25519: &str = "ssh-ed25519 \
+                           AAAAC3NzaC1lZDI1NTE5AAAAIHvtxqR4hJkfORCbiH2T4E1gk3bx1NTZp3oyRB5vxTZw \
+                           admin@example";
+
+    #[test]
+    fn keys() {
+        assert_eq!(validate_key(ED25519), Ok(()));
+        assert_eq!(
+            validate_key(&["from=\"10.0.0.0/8,host name\",no-pty ", ED25519].concat()),
+            Ok(())
+        );
+        assert!(validate_key("ssh-ed25519").is_err());
+        assert!(validate_key("ssh-ed25519 AAAA!!==").is_err());
+        assert!(validate_key("ssh-foo AAAAC3NzaC1lZDI1NTE5").is_err());
+        // An RSA key which claims to be an Ed25519 key.
+        assert!(validate_key("ssh-ed25519 AAAAB3NzaC1yc2EAAAADAQABAAABAQ==").is_err());
+    }
+
+    #[test]
+    fn line_numbers() {
+        let ssh = SshConfig {
+            authorized_keys: vec![["# keys\n", ED25519].concat(), "ssh-rsa AAAA".into()],
+            ..Default::default()
+        };
+
+        let why = ssh.validate().unwrap_err().to_string();
+        assert!(why.starts_with("authorized key on line 3 is invalid"), "{}", why);
+
+        let ssh = SshConfig { authorized_keys: vec![ED25519.into()], ..ssh };
+        assert!(ssh.validate().is_ok());
+    }
+}
@@ -1,16 +1,134 @@
-use super::{Error, Installer, Status, Step};
+use super::{
+    events::{Answer, InstallerEvent, Prompt, Question, Responder, Unanswered},
+    hooks::Hook,
+    report::Recorder,
+    transfer::RateEstimator,
+    CancellationToken, Config, Error, HookContext, HookWhen, Status, Step, UserAccountCreate,
+};
+use crate::{
+    disks::Disks,
+    logging::{InstallLog, LogSink},
+    timezones::Region,
+};
 use libc;
-use std::{io, sync::atomic::Ordering};
-use crate::KILL_SWITCH;
+use std::{
+    io,
+    path::Path,
+    sync::{
+        mpsc::{self, RecvTimeoutError, Sender},
+        Arc, Mutex, PoisonError,
+    },
+    time::{Duration, Instant},
+};
+
+/// How often a prompt which is waiting for its answer checks whether the install was
+/// cancelled.
+const CANCEL_POLL: Duration = Duration::from_millis(100);
+
+/// The settings and hooks of the installer, which are given to the thread that runs an
+/// install, with the channel that the events of the install are sent to.
+pub struct Worker {
+    pub hooks:           Arc<Mutex<Vec<Hook>>>,
+    pub extract_threads: usize,
+    pub space_margin:    u8,
+    pub trim:            bool,
+    pub simulate:        bool,
+    pub cancel:          CancellationToken,
+    pub prompt_timeout:  Duration,
+    pub events:          Sender<InstallerEvent>,
+}
+
+impl Worker {
+    /// Runs an install, and sends its result, with the report of a successful install, as
+    /// the last event. Returns where the log of the install was kept.
+    ///
+    /// If the install was cancelled, a final status reports it once everything has been
+    /// torn down.
+    pub fn run<F>(self, install: F) -> Option<InstallLog>
+    where
+        F: FnOnce(&mut InstallerState, &LogSink) -> io::Result<()>,
+    {
+        let _watch = self.cancel.follow_kill_switch();
+        let log = LogSink::start();
+        log.forward(self.events.clone());
+
+        let mut steps = InstallerState::new(&self);
+        let result = install(&mut steps, &log);
+
+        // Everything has been torn down, so progress may be reset.
+        if let Some(step) = result.as_ref().err().and_then(Error::cancelled_at) {
+            steps.emit_status(Status { cancelled: true, ..Status::new(step, 0) });
+        }
+
+        let log = log.finish();
+        self.emit(InstallerEvent::Finished(result.map(|()| steps.recorder.finish())));
+        log
+    }
+
+    fn emit(&self, event: InstallerEvent) { let _ = self.events.send(event); }
+
+    /// Sends the prompt, and waits for it to be answered. The prompt is not answered if the
+    /// frontend dropped the responder, or once the install is cancelled.
+    fn ask<T>(&self, prompt: fn(Responder<T>) -> Prompt) -> Option<T> {
+        let (responder, answer) = mpsc::channel();
+        self.emit(InstallerEvent::Prompt(prompt(Responder(responder))));
+
+        loop {
+            match answer.recv_timeout(CANCEL_POLL) {
+                Ok(answer) => return Some(answer),
+                Err(RecvTimeoutError::Disconnected) => return None,
+                Err(RecvTimeoutError::Timeout) if self.cancel.is_cancelled() => return None,
+                Err(RecvTimeoutError::Timeout) => (),
+            }
+        }
+    }
+
+    /// Asks the question, and waits for as long as the prompt timeout for it to be
+    /// answered. The question is not answered if the frontend dropped the responder.
+    ///
+    /// Waiting stops with an `Interrupted` error if the install is cancelled.
+    fn question(&self, question: Question) -> io::Result<Option<Answer>> {
+        let (responder, answer) = mpsc::channel();
+        let id = question.id;
+        self.emit(InstallerEvent::Prompt(Prompt::Question(question, Responder(responder))));
+
+        let deadline = Instant::now() + self.prompt_timeout;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            match answer.recv_timeout(remaining.min(CANCEL_POLL)) {
+                Ok(answer) => return Ok(Some(answer)),
+                Err(RecvTimeoutError::Disconnected) => return Ok(None),
+                Err(RecvTimeoutError::Timeout) if self.cancel.is_cancelled() => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Interrupted,
+                        format!("the {:?} question was cancelled", id),
+                    ));
+                }
+                Err(RecvTimeoutError::Timeout) if remaining <= CANCEL_POLL => {
+                    return Err(Unanswered { id, timeout: self.prompt_timeout }.into());
+                }
+                Err(RecvTimeoutError::Timeout) => (),
+            }
+        }
+    }
+}
 
 pub struct InstallerState<'a> {
-    pub installer: &'a mut Installer,
-    pub status:    Status,
+    pub worker:   &'a Worker,
+    pub status:   Status,
+    /// The timings and counts of the install, which a successful install returns.
+    pub recorder: Recorder,
+    transfer:     RateEstimator,
 }
 
 impl<'a> InstallerState<'a> {
-    pub fn new(installer: &'a mut Installer) -> Self {
-        Self { installer, status: Status { step: Step::Init, percent: 0 } }
+    pub fn new(worker: &'a Worker) -> Self {
+        Self {
+            worker,
+            status: Status::new(Step::Init, 0),
+            recorder: Recorder::new(),
+            transfer: RateEstimator::default(),
+        }
     }
 
     pub fn apply<T, F>(&mut self, step: Step, msg: &str, mut action: F) -> io::Result<T>
@@ -21,28 +139,348 @@ impl<'a> InstallerState<'a> {
             libc::sync();
         }
 
-        if KILL_SWITCH.load(Ordering::SeqCst) {
-            return Err(io::Error::new(io::ErrorKind::Interrupted, "process killed"));
+        if self.worker.cancel.is_cancelled() {
+            return Err(self.cancel(step));
         }
 
         self.status.step = step;
         self.status.percent = 0;
-        let status = self.status;
+        self.status.substep = None;
+        self.transfer = RateEstimator::default();
+        let status = self.status.clone();
         self.emit_status(status);
 
         info!("starting {} step", msg);
-        match action(self) {
+        self.recorder.start_step(step);
+        let result = action(self);
+        self.recorder.finish_step();
+
+        // A step which failed did not finish its sub-task, which belongs to no later status.
+        self.status.substep = None;
+
+        match result {
             Ok(value) => Ok(value),
+            // The step is stopped by whichever operation noticed the cancellation first.
+            Err(_) if self.worker.cancel.is_cancelled() => Err(self.cancel(step)),
+            // A hook of the step has already reported its failure.
+            Err(err) if Error::from_io(&err).is_some() => Err(err),
             Err(err) => {
                 error!("{} error: {}", msg, err);
-                let error = Error { step: self.status.step, err };
+                let error = Error::new(self.status.step, err);
                 self.emit_error(&error);
-                Err(error.err)
+                Err(error.into())
             }
         }
     }
 
-    pub fn emit_status(&mut self, status: Status) { self.installer.emit_status(status); }
+    /// Runs the hooks registered for the given step, in the order that they were registered.
+    ///
+    /// The error of a failed hook states its position among all registered hooks.
+    pub fn run_hooks(
+        &mut self,
+        step: Step,
+        when: HookWhen,
+        disks: &Disks,
+        target: Option<&Path>,
+    ) -> io::Result<()> {
+        let context = HookContext { step, when, target, disks };
+        let mut failure = None;
+
+        let hooks = Arc::clone(&self.worker.hooks);
+        let mut hooks = hooks.lock().unwrap_or_else(PoisonError::into_inner);
+        for (id, hook) in hooks.iter_mut().enumerate() {
+            if hook.step != step || hook.when != when {
+                continue;
+            }
+
+            if self.worker.cancel.is_cancelled() {
+                return Err(self.cancel(step));
+            }
+
+            info!("running hook #{} {} the {:?} step", id + 1, when, step);
+            if let Err(why) = (hook.callback)(&context) {
+                failure = Some(io::Error::new(
+                    why.kind(),
+                    format!("hook #{} {} the {:?} step failed: {}", id + 1, when, step, why),
+                ));
+                break;
+            }
+        }
+
+        match failure {
+            Some(_) if self.worker.cancel.is_cancelled() => Err(self.cancel(step)),
+            Some(err) => {
+                error!("{}", err);
+                let error = Error::new(step, err);
+                self.emit_error(&error);
+                Err(error.into())
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Reports that the install was cancelled during the step, and returns the error which
+    /// unwinds the install.
+    fn cancel(&self, step: Step) -> io::Error {
+        warn!("install was cancelled during the {:?} step", step);
+        let error = Error::Cancelled { step };
+        self.worker.emit(InstallerEvent::Error(error.duplicate()));
+        error.into()
+    }
+
+    /// Emits the status of a step which may be copying data, with the rate at which it is
+    /// copied, and the time remaining.
+    pub fn emit_transfer(
+        &mut self,
+        percent: i32,
+        message: Option<String>,
+        bytes: Option<(u64, u64)>,
+    ) {
+        self.status.percent = percent;
+        let mut status = self.status.clone();
+        status.message = message;
+        status.transfer = bytes.map(|(done, total)| self.transfer.sample(done, total));
+        if let Some((done, _)) = bytes {
+            self.recorder.bytes(done);
+        }
+        self.emit_status(status);
+    }
+
+    /// Emits the status of a step which is made up of several sub-tasks, with the sub-task
+    /// that was started.
+    pub fn emit_substep(&mut self, percent: i32, substep: Option<&'static str>) {
+        self.status.percent = percent;
+        self.status.substep = substep;
+        self.recorder.substep(substep);
+        let status = self.status.clone();
+        self.emit_status(status);
+    }
 
-    pub fn emit_error(&mut self, error: &Error) { self.installer.emit_error(&error); }
+    /// Asks for the time zone of the install.
+    pub fn timezone(&self) -> Option<Region> { self.worker.ask(Prompt::Timezone) }
+
+    /// Asks for the user account to create. The account of the owner of the machine is
+    /// created by them on the first boot, and the accounts of the config take precedence.
+    ///
+    /// The Configure step fails if the frontend could not give the account.
+    pub fn user(&mut self, config: &Config) -> io::Result<Option<UserAccountCreate>> {
+        if config.oem_mode || !config.users.is_empty() {
+            return Ok(None);
+        }
+
+        self.worker.ask(Prompt::User).transpose().map_err(|why| {
+            let err = io::Error::new(why.kind(), format!("user callback: {}", why));
+            error!("{}", err);
+            let error = Error::new(Step::Configure, err);
+            self.emit_error(&error);
+            error.into()
+        })
+    }
+
+    /// Asks the frontend a question which the config did not answer. The install fails
+    /// with an `Unanswered` error if it is not answered before the prompt timeout, and is
+    /// cancelled if it is cancelled while the question is waiting for its answer.
+    pub fn ask(&self, question: Question) -> io::Result<Option<Answer>> {
+        info!("asking: {}", question.message);
+        match self.worker.question(question) {
+            Err(_) if self.worker.cancel.is_cancelled() => Err(self.cancel(self.status.step)),
+            result => result,
+        }
+    }
+
+    pub fn emit_status(&mut self, status: Status) {
+        self.worker.emit(InstallerEvent::Status(status));
+    }
+
+    pub fn emit_error(&mut self, error: &Error) {
+        self.worker.emit(InstallerEvent::Error(error.duplicate()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::installer::{QuestionId, QuestionKind};
+    use std::thread;
+
+    fn worker(sender: Sender<InstallerEvent>, timeout: Duration) -> Worker {
+        Worker {
+            hooks:           Arc::default(),
+            extract_threads: 0,
+            space_margin:    0,
+            trim:            false,
+            simulate:        false,
+            cancel:          CancellationToken::new(),
+            prompt_timeout:  timeout,
+            events:          sender,
+        }
+    }
+
+    #[test]
+    fn prompts() {
+        let (events, received) = mpsc::channel();
+        let worker = worker(events, Duration::from_secs(60));
+
+        let frontend = thread::spawn(move || {
+            for event in received {
+                match event {
+                    InstallerEvent::Prompt(Prompt::User(responder)) => {
+                        responder.respond(Ok(UserAccountCreate {
+                            username: "oem".into(),
+                            ..Default::default()
+                        }))
+                    }
+                    // The install continues without a time zone when it is not answered.
+                    InstallerEvent::Prompt(Prompt::Timezone(_)) => (),
+                    event => panic!("unexpected event: {:?}", event),
+                }
+            }
+        });
+
+        assert!(worker.ask(Prompt::Timezone).is_none());
+        let user = worker.ask(Prompt::User).map(|user| user.unwrap().username);
+        assert_eq!(user, Some("oem".into()));
+        drop(worker);
+        frontend.join().unwrap();
+    }
+
+    #[test]
+    fn prompts_of_cancelled_installs() {
+        let (events, received) = mpsc::channel();
+        let worker = worker(events, Duration::from_secs(60));
+        let cancel = worker.cancel.clone();
+
+        // The frontend never answers, but the install is cancelled.
+        let frontend = thread::spawn(move || {
+            let mut unanswered = Vec::new();
+            for event in received {
+                match event {
+                    InstallerEvent::Prompt(Prompt::Timezone(responder)) => {
+                        unanswered.push(responder);
+                        cancel.cancel();
+                    }
+                    event => panic!("unexpected event: {:?}", event),
+                }
+            }
+        });
+
+        assert!(worker.ask(Prompt::Timezone).is_none());
+        drop(worker);
+        frontend.join().unwrap();
+    }
+
+    #[test]
+    fn questions() {
+        let (events, received) = mpsc::channel();
+        let worker = worker(events, Duration::from_millis(50));
+        let question = || Question {
+            id:      QuestionId::FormatEsp,
+            message: "format the existing EFI partition /dev/sda1?".into(),
+            kind:    QuestionKind::YesNo,
+        };
+
+        let frontend = thread::spawn(move || {
+            let mut unanswered = Vec::new();
+            for (asked, event) in received.into_iter().enumerate() {
+                let responder = match event {
+                    InstallerEvent::Prompt(Prompt::Question(_, responder)) => responder,
+                    event => panic!("unexpected event: {:?}", event),
+                };
+
+                match asked {
+                    0 => responder.respond(Answer::YesNo(true)),
+                    1 => drop(responder),
+                    // Holds on to the responder until the install gives up on it.
+                    _ => unanswered.push(responder),
+                }
+            }
+        });
+
+        assert_eq!(worker.question(question()).unwrap(), Some(Answer::YesNo(true)));
+        assert_eq!(worker.question(question()).unwrap(), None);
+
+        let why = worker.question(question()).unwrap_err();
+        assert_eq!(why.kind(), io::ErrorKind::TimedOut);
+        let unanswered = why.get_ref().and_then(|why| why.downcast_ref::<Unanswered>());
+        assert_eq!(unanswered.map(|why| why.id), Some(QuestionId::FormatEsp));
+
+        drop(worker);
+        frontend.join().unwrap();
+    }
+
+    #[test]
+    fn substeps_of_failed_steps() {
+        let (events, received) = mpsc::channel();
+        let worker = worker(events, Duration::from_secs(60));
+        let mut steps = InstallerState::new(&worker);
+
+        let result = steps.apply(Step::Configure, "configuring", |steps| -> io::Result<()> {
+            steps.emit_substep(50, Some("Installing packages"));
+            Err(io::Error::new(io::ErrorKind::Other, "apt failed"))
+        });
+        assert!(result.is_err());
+        assert_eq!(steps.status.substep, None);
+
+        steps
+            .apply(Step::Bootloader, "configuring bootloader", |steps| {
+                steps.status.percent = 100;
+                let status = steps.status.clone();
+                steps.emit_status(status);
+                Ok(())
+            })
+            .unwrap();
+
+        let report = steps.recorder.finish();
+        let substeps =
+            |id: usize| -> Vec<&str> { report.steps[id].substeps.iter().map(|s| s.name).collect() };
+        assert_eq!(substeps(0), vec!["Installing packages"]);
+        assert!(substeps(1).is_empty());
+
+        drop(worker);
+        let statuses = received
+            .into_iter()
+            .filter_map(|event| match event {
+                InstallerEvent::Status(status) => Some((status.step, status.substep)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            statuses,
+            vec![
+                (Step::Configure, None),
+                (Step::Configure, Some("Installing packages")),
+                (Step::Bootloader, None),
+                (Step::Bootloader, None),
+            ]
+        );
+    }
+
+    #[test]
+    fn cancelled_questions() {
+        let (events, received) = mpsc::channel();
+        let worker = worker(events, Duration::from_secs(600));
+        let cancel = worker.cancel.clone();
+
+        // Holds on to the responder, as a frontend which is waiting for the user would.
+        let frontend = thread::spawn(move || {
+            let responder = received.recv().unwrap();
+            cancel.cancel();
+            received.into_iter().for_each(drop);
+            drop(responder);
+        });
+
+        let started = Instant::now();
+        let question = Question {
+            id:      QuestionId::Passphrase,
+            message: "passphrase of the encrypted volume data".into(),
+            kind:    QuestionKind::Secret,
+        };
+
+        let why = worker.question(question).unwrap_err();
+        assert_eq!(why.kind(), io::ErrorKind::Interrupted);
+        assert!(started.elapsed() < Duration::from_secs(10));
+
+        drop(worker);
+        frontend.join().unwrap();
+    }
 }
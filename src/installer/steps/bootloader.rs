@@ -1,38 +1,135 @@
 use crate::chroot::Chroot;
-use crate::disks::{Bootloader, Disks};
-use crate::errors::IoContext;
+use crate::disks::Bootloader;
+use crate::errors::{IntoIoResult, IoContext};
+use crate::installer::{
+    boot_check::{self, RootIds},
+    efi_fallback,
+    efivars::{self, EfiVars, EFIVARS},
+    secure_boot::{self, SignedChain},
+    traits::InstallerDiskOps,
+};
+use fstab_generate::FstabId;
 use libc;
 use os_release::OsRelease;
+use partition_identity::PartitionID;
 use std::{
-    ffi::{OsStr, OsString},
+    env,
+    ffi::OsString,
     fs, io,
     os::unix::ffi::{OsStrExt, OsStringExt},
     path::{Path, PathBuf},
 };
 use crate::Config;
-use crate::MODIFY_BOOT_ORDER;
+use crate::{FORCE_FALLBACK, MODIFY_BOOT_ORDER, WINDOWS_SECOND};
 
 use super::mount_efivars;
 
-pub fn bootloader<F: FnMut(i32)>(
-    disks: &Disks,
+/// The bootloader which is installed, which is otherwise chosen by the firmware and the
+/// image.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum BootloaderChoice {
+    /// systemd-boot for Pop!_OS on EFI firmware, and GRUB otherwise.
+    Auto,
+    /// systemd-boot, whose entries are managed by kernelstub if the image has it, or else
+    /// are written for each kernel of the install.
+    SystemdBoot,
+    /// GRUB for EFI firmware, as for images which ship GRUB themes.
+    GrubEfi,
+    /// GRUB for BIOS firmware, which EFI firmware only boots in CSM mode.
+    GrubBios,
+    /// An `extlinux.conf` on a FAT `/boot`, which U-Boot boots on single-board computers.
+    Extlinux,
+    /// The `config.txt` and `cmdline.txt` of the firmware of the Raspberry Pi, which boots
+    /// the kernel from a FAT `/boot` along with the rest of the firmware.
+    RaspberryPi,
+    /// GRUB for the Open Firmware of POWER systems, which loads its stage1 from a PReP boot
+    /// partition.
+    GrubIeee1275,
+}
+
+impl Default for BootloaderChoice {
+    fn default() -> Self { BootloaderChoice::Auto }
+}
+
+/// Where the EFI boot entry of the install is placed in the `BootOrder` of the firmware.
+/// The other entries keep their order.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum BootOrderPolicy {
+    /// The install is booted first.
+    First,
+    /// The install is booted last, so that network boot remains first.
+    Last,
+    /// The entry takes the place of the entry of the install that it replaces, or is booted
+    /// last if there was none.
+    Preserve,
+}
+
+impl Default for BootOrderPolicy {
+    fn default() -> Self { BootOrderPolicy::First }
+}
+
+impl BootloaderChoice {
+    /// The firmware which the bootloader is installed for, on a machine whose firmware is
+    /// `detected`. The firmware of a board and Open Firmware have no ESP, so they are treated
+    /// as BIOS.
+    pub fn firmware(self, detected: Bootloader) -> Bootloader {
+        match self {
+            BootloaderChoice::Auto => detected,
+            BootloaderChoice::SystemdBoot | BootloaderChoice::GrubEfi => Bootloader::Efi,
+            BootloaderChoice::GrubBios
+            | BootloaderChoice::Extlinux
+            | BootloaderChoice::RaspberryPi
+            | BootloaderChoice::GrubIeee1275 => Bootloader::Bios,
+        }
+    }
+
+    /// Whether the firmware of a board loads the kernel from `/boot` itself, which is
+    /// configured along with the install, rather than a bootloader being installed.
+    pub fn is_board(self) -> bool {
+        self == BootloaderChoice::Extlinux || self == BootloaderChoice::RaspberryPi
+    }
+
+    /// Whether GRUB is installed for Open Firmware, which is the choice on POWER systems.
+    pub fn is_ieee1275(self) -> bool {
+        self == BootloaderChoice::GrubIeee1275
+            || (self == BootloaderChoice::Auto && cfg!(target_arch = "powerpc64"))
+    }
+
+    /// The bootloader which is installed for the `firmware` and the image named `os_name`,
+    /// which is never `Auto`.
+    pub fn resolve(self, firmware: Bootloader, os_name: &str) -> BootloaderChoice {
+        match (self, firmware) {
+            (choice, _) if choice.is_ieee1275() => BootloaderChoice::GrubIeee1275,
+            (BootloaderChoice::Auto, Bootloader::Bios) => BootloaderChoice::GrubBios,
+            (BootloaderChoice::Auto, Bootloader::Efi) if os_name == "Pop!_OS" => {
+                BootloaderChoice::SystemdBoot
+            }
+            (BootloaderChoice::Auto, Bootloader::Efi) => BootloaderChoice::GrubEfi,
+            (choice, _) => choice,
+        }
+    }
+}
+
+pub fn bootloader<D: InstallerDiskOps, F: FnMut(i32)>(
+    disks: &D,
     mount_dir: &Path,
     bootloader: Bootloader,
     config: &Config,
     iso_os_release: &OsRelease,
     mut callback: F,
 ) -> io::Result<()> {
-    // Obtain the root device & partition, with an optional EFI device & partition.
-    let ((root_dev, _root_part), boot_opt) = disks.get_base_partitions(bootloader);
-
-    let mut efi_part_num = 0;
-
-    let bootloader_dev = boot_opt.map_or(root_dev, |(dev, dev_part)| {
-        efi_part_num = dev_part.number;
-        dev
-    });
+    if config.bootloader.is_board() {
+        info!("the firmware of the board boots the install with {:?}", config.bootloader);
+        callback(99);
+        return Ok(());
+    }
 
-    info!("{}: installing bootloader for {:?}", bootloader_dev.display(), bootloader);
+    // Obtain the device of the boot partition, or of the root where there is none. Each
+    // member of a RAID1 array gets a bootloader, so that it boots after a disk has failed.
+    let devices = disks.get_boot_devices(bootloader)?;
+    for (device, _) in &devices {
+        info!("{}: installing bootloader for {:?}", device.display(), bootloader);
+    }
 
     {
         let efi_path = {
@@ -50,7 +147,7 @@ pub fn bootloader<F: FnMut(i32)>(
         };
 
         // Also ensure that the /boot/efi directory is created.
-        if bootloader == Bootloader::Efi && boot_opt.is_some() {
+        if bootloader == Bootloader::Efi {
             fs::create_dir_all(&efi_path)
                 .with_context(|err| format!("failed to create efi directory: {}", err))?;
         }
@@ -59,28 +156,79 @@ pub fn bootloader<F: FnMut(i32)>(
             let mut chroot = Chroot::new(mount_dir)?;
             let efivars_mount = mount_efivars(&mount_dir)?;
 
+            let loader = config.bootloader.resolve(bootloader, &iso_os_release.name);
+            info!("installing {:?}", loader);
+
             match bootloader {
-                Bootloader::Bios => {
+                // Open Firmware has no EFI variables, and it boots the PReP partition itself.
+                Bootloader::Bios if loader == BootloaderChoice::GrubIeee1275 => {
+                    let prep = disks.get_prep_device()?;
+                    let prep_path = prep.to_str().into_io_result(|| {
+                        format!("the path of the PReP boot partition is not UTF-8: {:?}", prep)
+                    })?;
                     chroot
                         .command(
                             "grub-install",
                             &[
-                                // Recreate device map
-                                "--recheck".into(),
-                                // Install for BIOS
-                                "--target=i386-pc".into(),
-                                // Install to the bootloader_dev device
-                                bootloader_dev.to_str().unwrap().to_owned(),
+                                "--target=powerpc-ieee1275".into(),
+                                "--no-nvram".into(),
+                                prep_path.to_owned(),
                             ],
                         )
                         .run()?;
 
-                    chroot.command("update-initramfs", &["-c", "-k", "all"]).run()?;
+                    boot_check::prep(&prep)?;
+                }
+                Bootloader::Bios => {
+                    for (bootloader_dev, _) in &devices {
+                        chroot
+                            .command(
+                                "grub-install",
+                                &[
+                                    // Recreate device map
+                                    "--recheck".into(),
+                                    // Install for BIOS
+                                    "--target=i386-pc".into(),
+                                    // Install to the bootloader_dev device
+                                    bootloader_dev.to_str().unwrap().to_owned(),
+                                ],
+                            )
+                            .run()?;
+
+                        boot_check::mbr(bootloader_dev)?;
+                        info!("{}: installed GRUB to the MBR", bootloader_dev.display());
+                    }
                 }
                 Bootloader::Efi => {
                     // Grub disallows whitespaces in the name.
                     let name = super::normalize_os_release_name(&iso_os_release.name);
-                    if &name == "Pop!_OS" {
+                    let arch = efi_fallback::target_arch(mount_dir)
+                        .or_else(|| efi_fallback::efi_arch(env::consts::ARCH))
+                        .unwrap_or("x64");
+                    let label = config.efi_label.as_deref().unwrap_or(&iso_os_release.pretty_name);
+
+                    // The variables are not mounted for a dry run.
+                    let vars =
+                        efivars_mount.as_ref().and_then(|_| EfiVars::open(Path::new(EFIVARS)));
+
+                    // Installing alongside Windows must not remove its loader from the ESP.
+                    let windows = efi_path.join("EFI/Microsoft/Boot/bootmgfw.efi");
+                    let had_windows = windows.exists();
+
+                    // The signed binaries are found before any loader is installed.
+                    let secure_boot = match vars {
+                        Some(ref vars) => vars.secure_boot()?,
+                        None => false,
+                    };
+                    let chain = if secure_boot {
+                        info!("Secure Boot is enabled, so the signed boot chain is installed");
+                        let systemd_boot = loader == BootloaderChoice::SystemdBoot;
+                        Some(SignedChain::find(mount_dir, systemd_boot, arch)?)
+                    } else {
+                        None
+                    };
+
+                    if loader == BootloaderChoice::SystemdBoot {
                         chroot
                             .command(
                                 "bootctl",
@@ -110,7 +258,7 @@ pub fn bootloader<F: FnMut(i32)>(
                             .command(
                                 "grub-install",
                                 &[
-                                    "--target=x86_64-efi",
+                                    &format!("--target={}-efi", secure_boot::grub_target(arch)),
                                     "--efi-directory=/boot/efi",
                                     &format!("--boot-directory=/boot/efi/EFI/{}", name),
                                     &format!("--bootloader={}", name),
@@ -128,34 +276,116 @@ pub fn bootloader<F: FnMut(i32)>(
                             .run()?;
                     }
 
-                    chroot.command("update-initramfs", &["-c", "-k", "all"]).run()?;
+                    let systemd_dir = Path::new("EFI/systemd");
+                    let loader = match chain {
+                        // Shim loads the loader which is beside it.
+                        Some(ref chain) if loader == BootloaderChoice::SystemdBoot => {
+                            chain.install(&efi_path, systemd_dir, label, arch)?
+                        }
+                        Some(ref chain) => {
+                            let dir = Path::new("EFI").join(&*name);
+                            chain.install(&efi_path, &dir, label, arch)?
+                        }
+                        None if loader == BootloaderChoice::SystemdBoot => {
+                            efi_fallback::systemd_boot_loader(arch)
+                        }
+                        None => efi_fallback::grub_loader(&efi_path, &name, arch),
+                    };
+
+                    // Firmware which loses its boot entries boots the fallback path instead.
+                    let force = config.flags & FORCE_FALLBACK != 0;
+                    efi_fallback::install(&efi_path, &loader, arch, force)?;
+                    boot_check::efi_loader(&efi_path, &loader)?;
 
                     if config.flags & MODIFY_BOOT_ORDER != 0 {
-                        let efi_part_num = efi_part_num.to_string();
-                        let loader = if &name == "Pop!_OS" {
-                            "\\EFI\\systemd\\systemd-bootx64.efi".into()
-                        } else {
-                            format!("\\EFI\\{}\\shimx64.efi", name)
-                        };
-
-                        let args: &[&OsStr] = &[
-                            "--create".as_ref(),
-                            "--disk".as_ref(),
-                            bootloader_dev.as_ref(),
-                            "--part".as_ref(),
-                            efi_part_num.as_ref(),
-                            "--write-signature".as_ref(),
-                            "--label".as_ref(),
-                            iso_os_release.pretty_name.as_ref(),
-                            "--loader".as_ref(),
-                            loader.as_ref(),
-                        ][..];
-
-                        chroot.command("efibootmgr", args).run()?;
+                        let loader = ["\\", &loader.to_string_lossy().replace('/', "\\")].concat();
+                        let windows_second = config.flags & WINDOWS_SECOND != 0;
+
+                        match vars {
+                            Some(ref vars) => {
+                                // The array mirrors the ESP to each member, which each get an
+                                // entry. That of the first is created last if the install is
+                                // booted first, so that it precedes the others.
+                                let policy = config.boot_order;
+                                let mut members = devices.iter().collect::<Vec<_>>();
+                                if policy == BootOrderPolicy::First {
+                                    members.reverse();
+                                }
+
+                                for (device, part_num) in members {
+                                    let (partition, disk) =
+                                        efivars::partition(device, *part_num as u32)?;
+                                    let entry = vars.replace_entry(
+                                        label,
+                                        partition,
+                                        &disk,
+                                        &loader,
+                                        windows_second,
+                                        policy,
+                                    );
+                                    let entry = entry.with_context(|err| {
+                                        format!("failed to create the boot entry: {}", err)
+                                    })?;
+                                    let number = match entry {
+                                        Some(number) => number,
+                                        // The variables are read-only.
+                                        None => continue,
+                                    };
+                                    info!(
+                                        "{}: created boot entry Boot{:04X}: {}",
+                                        device.display(),
+                                        number,
+                                        label
+                                    );
+
+                                    let entries = vars.boot_entries()?;
+                                    boot_check::efi_entry(&entries, number, &partition, &loader)?;
+                                }
+                            }
+                            None => {
+                                warn!("no EFI variables, so no boot entry was made for {}", label)
+                            }
+                        }
+                    }
+
+                    if had_windows && !windows.exists() {
+                        return Err(io::Error::new(
+                            io::ErrorKind::NotFound,
+                            "the Windows Boot Manager was removed from the ESP while installing \
+                             the bootloader",
+                        ));
+                    }
+
+                    if let Some(ref vars) = vars {
+                        let entries = vars.boot_entries()?;
+                        info!("the boot entries after installing the bootloader:");
+                        for line in efivars::report(&entries, &vars.boot_order()?) {
+                            info!("  {}", line);
+                        }
+
+                        if had_windows && !entries.iter().any(|(_, option)| option.is_windows()) {
+                            warn!("Windows is on the ESP, but the firmware has no entry for it");
+                        }
                     }
                 }
             }
 
+            // A config which boots nothing would otherwise only be found on the next boot.
+            let root = root_ids(disks)?;
+            let name = super::normalize_os_release_name(&iso_os_release.name);
+            match loader {
+                BootloaderChoice::SystemdBoot => {
+                    boot_check::systemd_boot_config(&efi_path, &name, &root)?
+                }
+                BootloaderChoice::GrubEfi => {
+                    let cfg = ["boot/efi/EFI/", &name, "/grub/grub.cfg"].concat();
+                    boot_check::grub_config(mount_dir, Path::new(&cfg), &root)?
+                }
+                _ => boot_check::grub_config(mount_dir, Path::new("boot/grub/grub.cfg"), &root)?,
+            }
+
+            info!("verified that the bootloader boots the install");
+
             // Sync to the disk before unmounting
             unsafe {
                 libc::sync();
@@ -170,3 +400,15 @@ pub fn bootloader<F: FnMut(i32)>(
 
     Ok(())
 }
+
+/// The identifiers of the root of the install, which the configs of the bootloader boot.
+fn root_ids<D: InstallerDiskOps>(disks: &D) -> io::Result<RootIds> {
+    let root = disks.get_block_info_of("/", FstabId::Uuid)?;
+    let device = root.uid.get_device_path().and_then(|path| path.canonicalize().ok());
+    let mut ids = vec![["UUID=", &root.uid.id].concat()];
+    if let Some(partuuid) = device.as_ref().and_then(PartitionID::get_partuuid) {
+        ids.push(["PARTUUID=", &partuuid.id].concat());
+    }
+
+    Ok(RootIds { ids, device })
+}
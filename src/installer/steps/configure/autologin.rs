@@ -0,0 +1,234 @@
+use std::{fs, io, path::Path};
+
+/// The display managers which automatic login may be configured for.
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum DisplayManager {
+    Gdm,
+    LightDm,
+    Sddm,
+}
+
+impl DisplayManager {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "gdm" | "gdm3" => Some(DisplayManager::Gdm),
+            "lightdm" => Some(DisplayManager::LightDm),
+            "sddm" => Some(DisplayManager::Sddm),
+            _ => None,
+        }
+    }
+
+    /// Where the configuration is written, relative to the root of the install.
+    fn config_path(self) -> &'static str {
+        match self {
+            DisplayManager::Gdm => "etc/gdm3/custom.conf",
+            DisplayManager::LightDm => "etc/lightdm/lightdm.conf.d/50-distinst-autologin.conf",
+            DisplayManager::Sddm => "etc/sddm.conf.d/50-distinst-autologin.conf",
+        }
+    }
+}
+
+/// Overrides the getty of the first console, for images without a display manager.
+const GETTY_OVERRIDE: &str = "etc/systemd/system/getty@tty1.service.d/autologin.conf";
+
+/// Configures the display manager of the install to log the user in automatically, or the
+/// console if the install has no display manager.
+///
+/// A display manager which is not supported is logged, and left as it is.
+pub fn configure(root: &Path, user: &str) -> io::Result<()> {
+    let default_dm = fs::read_to_string(root.join("etc/X11/default-display-manager")).ok();
+    let default_dm = default_dm.as_ref().map(|path| path.trim()).filter(|path| !path.is_empty());
+
+    let dm = match default_dm {
+        Some(path) => {
+            let name = Path::new(path).file_name().and_then(|name| name.to_str()).unwrap_or("");
+            match DisplayManager::from_name(name) {
+                Some(dm) => dm,
+                None => {
+                    warn!("automatic login is not supported with {}, so it was not set up", path);
+                    return Ok(());
+                }
+            }
+        }
+        None => match detect(root) {
+            Some(dm) => dm,
+            None if root.join("lib/systemd/system/getty@.service").exists() => {
+                info!("no display manager was found, so {} will log in on tty1", user);
+                return write(&root.join(GETTY_OVERRIDE), &getty_override(user));
+            }
+            None => {
+                warn!("no display manager or getty was found, so automatic login was not set up");
+                return Ok(());
+            }
+        },
+    };
+
+    info!("enabling automatic login of {} with {:?}", user, dm);
+    let path = root.join(dm.config_path());
+    let config = match dm {
+        DisplayManager::Gdm => {
+            let existing = match fs::read_to_string(&path) {
+                Ok(existing) => existing,
+                Err(ref why) if why.kind() == io::ErrorKind::NotFound => String::new(),
+                Err(why) => return Err(why),
+            };
+
+            gdm_config(&existing, user)
+        }
+        DisplayManager::LightDm => lightdm_config(user),
+        DisplayManager::Sddm => sddm_config(user),
+    };
+
+    write(&path, &config)
+}
+
+/// Finds a display manager by its binary, when none was set as the default.
+fn detect(root: &Path) -> Option<DisplayManager> {
+    [
+        ("usr/sbin/gdm3", DisplayManager::Gdm),
+        ("usr/sbin/lightdm", DisplayManager::LightDm),
+        ("usr/bin/sddm", DisplayManager::Sddm),
+    ]
+    .iter()
+    .find(|(binary, _)| root.join(binary).exists())
+    .map(|&(_, dm)| dm)
+}
+
+fn write(path: &Path, contents: &str) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    fs::write(path, contents)
+}
+
+/// Sets the automatic login keys in the `[daemon]` section of GDM's `custom.conf`, which
+/// has no drop-in directory, keeping the rest of the file as it is.
+fn gdm_config(existing: &str, user: &str) -> String {
+    let keys = ["AutomaticLoginEnable=true".to_owned(), ["AutomaticLogin=", user].concat()];
+
+    let mut output = Vec::new();
+    let mut in_daemon = false;
+    let mut found_daemon = false;
+
+    for line in existing.lines() {
+        let trimmed = line.trim();
+        if trimmed.starts_with('[') {
+            in_daemon = trimmed == "[daemon]";
+            output.push(line.to_owned());
+            if in_daemon && !found_daemon {
+                found_daemon = true;
+                output.extend(keys.iter().cloned());
+            }
+            continue;
+        }
+
+        let key = trimmed.splitn(2, '=').next().unwrap_or("").trim();
+        if in_daemon && (key == "AutomaticLoginEnable" || key == "AutomaticLogin") {
+            continue;
+        }
+
+        output.push(line.to_owned());
+    }
+
+    if !found_daemon {
+        let mut section = vec!["[daemon]".to_owned()];
+        section.extend(keys.iter().cloned());
+        if !output.is_empty() {
+            section.push(String::new());
+        }
+        output.splice(0..0, section);
+    }
+
+    let mut config = output.join("\n");
+    config.push('\n');
+    config
+}
+
+fn lightdm_config(user: &str) -> String {
+    format!("[Seat:*]\nautologin-user={}\nautologin-user-timeout=0\n", user)
+}
+
+fn sddm_config(user: &str) -> String { format!("[Autologin]\nUser={}\n", user) }
+
+fn getty_override(user: &str) -> String {
+    format!(
+        "[Service]\nExecStart=\nExecStart=-/sbin/agetty --autologin {} --noclear %I $TERM\n",
+        user
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GDM_CUSTOM_CONF: &str = "# GDM configuration storage
+#
+# See /usr/share/gdm/gdm.schemas for a list of available options.
+
+[daemon]
+AutomaticLoginEnable=false
+# Uncomment the line below to force the login screen to use Xorg
+#WaylandEnable=false
+
+[security]
+";
+
+    #[test]
+    fn gdm() {
+        assert_eq!(
+            gdm_config(GDM_CUSTOM_CONF, "jane"),
+            "# GDM configuration storage
+#
+# See /usr/share/gdm/gdm.schemas for a list of available options.
+
+[daemon]
+AutomaticLoginEnable=true
+AutomaticLogin=jane
+# Uncomment the line below to force the login screen to use Xorg
+#WaylandEnable=false
+
+[security]
+"
+        );
+
+        assert_eq!(
+            gdm_config("", "jane"),
+            "[daemon]\nAutomaticLoginEnable=true\nAutomaticLogin=jane\n"
+        );
+
+        assert_eq!(
+            gdm_config("[security]\n", "jane"),
+            "[daemon]\nAutomaticLoginEnable=true\nAutomaticLogin=jane\n\n[security]\n"
+        );
+    }
+
+    #[test]
+    fn lightdm() {
+        assert_eq!(
+            lightdm_config("jane"),
+            "[Seat:*]\nautologin-user=jane\nautologin-user-timeout=0\n"
+        );
+    }
+
+    #[test]
+    fn sddm() {
+        assert_eq!(sddm_config("jane"), "[Autologin]\nUser=jane\n");
+    }
+
+    #[test]
+    fn getty() {
+        assert_eq!(
+            getty_override("jane"),
+            "[Service]\nExecStart=\nExecStart=-/sbin/agetty --autologin jane --noclear %I $TERM\n"
+        );
+    }
+
+    #[test]
+    fn display_managers() {
+        assert_eq!(DisplayManager::from_name("gdm3"), Some(DisplayManager::Gdm));
+        assert_eq!(DisplayManager::from_name("lightdm"), Some(DisplayManager::LightDm));
+        assert_eq!(DisplayManager::from_name("sddm"), Some(DisplayManager::Sddm));
+        assert_eq!(DisplayManager::from_name("xdm"), None);
+    }
+}
@@ -0,0 +1,166 @@
+use super::systemd_boot::Kernel;
+use crate::errors::IoContext;
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+/// Where the firmware of the Raspberry Pi is installed, by Debian and by Ubuntu.
+const RASPI_FIRMWARE: &[&str] = &["usr/lib/raspi-firmware", "usr/lib/linux-firmware-raspi"];
+
+/// The `console` parameters of a kernel command line, such as that of the live system, which
+/// name the serial console of the board.
+pub fn consoles(cmdline: &str) -> Vec<&str> {
+    cmdline.split_whitespace().filter(|arg| arg.starts_with("console=")).collect()
+}
+
+/// Finds the device trees of the kernel in the image at `root`: in `/boot/dtbs`, or where
+/// Debian or Ubuntu install them.
+pub fn dtb_dir(root: &Path, version: &str) -> Option<PathBuf> {
+    dtb_dirs(version).iter().map(|dir| root.join(dir)).find(|dir| dir.is_dir())
+}
+
+/// Where the device trees of the kernel `version` may be, in the order they are searched.
+pub fn dtb_dirs(version: &str) -> [String; 3] {
+    [
+        ["boot/dtbs/", version].concat(),
+        ["usr/lib/linux-image-", version].concat(),
+        ["lib/firmware/", version, "/device-tree"].concat(),
+    ]
+}
+
+/// Finds the firmware of the Raspberry Pi in the image at `root`.
+pub fn raspi_firmware(root: &Path) -> Option<PathBuf> {
+    RASPI_FIRMWARE.iter().map(|dir| root.join(dir)).find(|dir| dir.is_dir())
+}
+
+/// The `extlinux.conf` which U-Boot boots, with a label for each kernel, and the newest as
+/// the default. A kernel which has its device trees in `/dtbs/{version}` of the boot
+/// partition has U-Boot load the tree of the board from there.
+pub fn extlinux_conf(title: &str, kernels: &[(&Kernel, bool)], options: &str) -> String {
+    let default = kernels.first().map(|(kernel, _)| kernel.version.as_str());
+    let mut conf = extlinux_header(title, default);
+    for (kernel, dtbs) in kernels {
+        conf.push_str(&extlinux_label(title, kernel, *dtbs, options));
+    }
+
+    conf
+}
+
+/// The start of `extlinux.conf`, which boots the label of the `default` kernel.
+pub fn extlinux_header(title: &str, default: Option<&str>) -> String {
+    let mut conf = String::from("# Written by distinst\n");
+    if let Some(default) = default {
+        conf.push_str(&format!("default {}\n", default));
+    }
+
+    conf.push_str(&format!("menu title {}\nprompt 0\ntimeout 30\n", title));
+    conf
+}
+
+/// The label of the `kernel` in `extlinux.conf`.
+pub fn extlinux_label(title: &str, kernel: &Kernel, dtbs: bool, options: &str) -> String {
+    let mut label = format!(
+        "\nlabel {0}\n\tmenu label {1} ({0})\n\tlinux /{2}\n\tinitrd /{3}\n",
+        kernel.version, title, kernel.kernel, kernel.initrd
+    );
+    if dtbs {
+        label.push_str(&format!("\tfdtdir /dtbs/{}\n", kernel.version));
+    }
+
+    label.push_str(&format!("\tappend {}\n", options));
+    label
+}
+
+/// The `config.txt` of the firmware of the Raspberry Pi, which boots the kernel with the
+/// command line of `cmdline.txt`.
+pub fn config_txt(kernel: &Kernel, arm_64bit: bool) -> String {
+    format!(
+        "# Written by distinst\n{}kernel={}\ninitramfs {} followkernel\ncmdline=cmdline.txt\n",
+        if arm_64bit { "arm_64bit=1\n" } else { "" },
+        kernel.kernel,
+        kernel.initrd
+    )
+}
+
+/// Copies the files of `source` into `target`, along with those of its directories.
+pub fn copy_dir(source: &Path, target: &Path) -> io::Result<()> {
+    fs::create_dir_all(target)?;
+    let entries = fs::read_dir(source)
+        .with_context(|err| format!("failed to read {}: {}", source.display(), err))?;
+
+    for entry in entries {
+        let entry = entry?;
+        let path = entry.path();
+        let target = target.join(entry.file_name());
+        if path.is_dir() {
+            copy_dir(&path, &target)?;
+        } else {
+            fs::copy(&path, &target).with_context(|err| {
+                format!("failed to copy {} to {}: {}", path.display(), target.display(), err)
+            })?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    fn kernel(version: &str) -> Kernel {
+        Kernel {
+            version: version.into(),
+            kernel:  ["vmlinuz-", version].concat(),
+            initrd:  ["initrd.img-", version].concat(),
+        }
+    }
+
+    #[test]
+    fn extlinux() {
+        let root = TempDir::new("distinst").unwrap();
+        let root = root.path();
+        let dtbs = root.join("usr/lib/linux-image-6.1.0-18-arm64/rockchip");
+        fs::create_dir_all(&dtbs).unwrap();
+        fs::write(dtbs.join("rk3399-rockpro64.dtb"), "dtb").unwrap();
+
+        let dir = dtb_dir(root, "6.1.0-18-arm64").unwrap();
+        assert_eq!(dir, root.join("usr/lib/linux-image-6.1.0-18-arm64"));
+        assert_eq!(dtb_dir(root, "6.1.0-17-arm64"), None);
+
+        copy_dir(&dir, &root.join("boot/dtbs/6.1.0-18-arm64")).unwrap();
+        let copied = root.join("boot/dtbs/6.1.0-18-arm64/rockchip/rk3399-rockpro64.dtb");
+        assert_eq!(fs::read_to_string(copied).unwrap(), "dtb");
+
+        let cmdline = "BOOT_IMAGE=/casper/vmlinuz console=ttyS2,1500000 quiet console=tty1";
+        assert_eq!(consoles(cmdline), vec!["console=ttyS2,1500000", "console=tty1"]);
+
+        let (new, old) = (kernel("6.1.0-18-arm64"), kernel("6.1.0-17-arm64"));
+        assert_eq!(
+            extlinux_conf("Debian", &[(&new, true), (&old, false)], "root=UUID=1234 ro"),
+            "# Written by distinst\ndefault 6.1.0-18-arm64\nmenu title Debian\nprompt \
+             0\ntimeout 30\n\nlabel 6.1.0-18-arm64\n\tmenu label Debian (6.1.0-18-arm64)\n\tlinux \
+             /vmlinuz-6.1.0-18-arm64\n\tinitrd /initrd.img-6.1.0-18-arm64\n\tfdtdir \
+             /dtbs/6.1.0-18-arm64\n\tappend root=UUID=1234 ro\n\nlabel 6.1.0-17-arm64\n\tmenu \
+             label Debian (6.1.0-17-arm64)\n\tlinux /vmlinuz-6.1.0-17-arm64\n\tinitrd \
+             /initrd.img-6.1.0-17-arm64\n\tappend root=UUID=1234 ro\n"
+        );
+    }
+
+    #[test]
+    fn raspberry_pi() {
+        let root = TempDir::new("distinst").unwrap();
+        let root = root.path();
+        assert_eq!(raspi_firmware(root), None);
+        fs::create_dir_all(root.join("usr/lib/raspi-firmware")).unwrap();
+        assert_eq!(raspi_firmware(root), Some(root.join("usr/lib/raspi-firmware")));
+
+        assert_eq!(
+            config_txt(&kernel("6.1.0-18-arm64"), true),
+            "# Written by distinst\narm_64bit=1\nkernel=vmlinuz-6.1.0-18-arm64\ninitramfs \
+             initrd.img-6.1.0-18-arm64 followkernel\ncmdline=cmdline.txt\n"
+        );
+    }
+}
@@ -1,17 +1,48 @@
 use crate::chroot::{Chroot, Command};
 use crate::errors::{IoContext, IntoIoResult};
 use crate::misc;
-use partition_identity::PartitionID;
+use partition_identity::{PartitionID, PartitionSource};
+use os_release::OsRelease;
 use proc_mounts::MountList;
 use std::{
-    fs,
+    env,
+    fs::{self, Permissions},
     io::{self, Write},
+    iter,
+    os::unix::fs::{symlink, PermissionsExt},
     path::Path,
     process::Stdio,
+    slice,
 };
 use sys_mount::*;
-use crate::timezones::Region;
-use crate::Config;
+use zeroize::Zeroizing;
+use crate::hardware_support::drivers;
+use crate::timezones;
+use crate::distribution::{
+    debian,
+    language::{self, Candidate},
+    target::{LocaleMechanism, TargetOs},
+};
+use crate::installer::{
+    bitflags::FileSystemSupport,
+    cmdline, efi_fallback,
+    firstboot::{self, FirstBootUnit},
+    hosts,
+    network::{self, NetworkConfig},
+    ssh,
+    user::{self, RootPolicy},
+    zram::{self, ZramMechanism},
+    steps::{normalize_os_release_name, BootloaderChoice},
+    SshConfig, ZramConfig,
+};
+use super::{
+    board,
+    grub::{self, GrubOptions},
+    hibernation, identity, initramfs::InitramfsTool, kernel_hook, kernelstub, local_packages,
+    locale, oem,
+    systemd_boot::{self, EntryIds, SystemdBootOptions},
+};
+use crate::{Config, KeyboardConfig, UserAccountCreate};
 
 const APT_OPTIONS: &[&str] = &[
     "-o",
@@ -22,6 +53,29 @@ const APT_OPTIONS: &[&str] = &[
     "APT::CDROM::NoMount=1",
 ];
 
+/// The apt sources of the archive, which are pointed at a mirror.
+const APT_SOURCES: &[&str] = &[
+    "etc/apt/sources.list",
+    "etc/apt/sources.list.d/system.sources",
+    "etc/apt/sources.list.d/ubuntu.sources",
+    "etc/apt/sources.list.d/debian.sources",
+];
+
+/// Sets the proxy and timeouts of apt.
+const APT_NETWORK_CONF: &str = "etc/apt/apt.conf.d/90distinst-network";
+
+/// Sets the name servers of systemd-resolved, if they are kept in the install.
+const RESOLVED_NETWORK_CONF: &str = "etc/systemd/resolved.conf.d/distinst-network.conf";
+
+/// Takes precedence over the `sshd_config` of the image.
+const SSHD_CONF: &str = "etc/ssh/sshd_config.d/50-distinst.conf";
+
+/// Where the swap file is created within the chroot.
+const SWAPFILE: &str = "swapfile";
+
+/// Where the directory of local packages is mounted within the chroot.
+const LOCAL_PACKAGES: &str = "tmp/distinst-local-packages";
+
 // For a clean boot by default, we hide all output and use plymouth
 const BOOT_OPTIONS: &str = "quiet loglevel=0 systemd.show_status=false splash";
 
@@ -30,10 +84,13 @@ const RECOVERY_BOOT_OPTIONS: &str = "";
 
 pub struct ChrootConfigurator<'a> {
     chroot: Chroot<'a>,
+    os:     TargetOs,
+    /// Whether apt was given a proxy by the network config.
+    proxy:  bool,
 }
 
 impl<'a> ChrootConfigurator<'a> {
-    pub fn new(chroot: Chroot<'a>) -> Self { Self { chroot } }
+    pub fn new(chroot: Chroot<'a>, os: TargetOs) -> Self { Self { chroot, os, proxy: false } }
 
     /// Install the given packages if they are not already installed.
     pub fn apt_install(&self, packages: &[&str]) -> io::Result<()> {
@@ -51,51 +108,399 @@ impl<'a> ChrootConfigurator<'a> {
         command.run()
     }
 
-    /// Remove the given packages from the system, if they are installed.
-    pub fn apt_remove(&self, packages: &[&str]) -> io::Result<()> {
-        info!("removing packages: {:?}", packages);
-        self.chroot
-            .command(
-                "apt-get",
-                &cascade! {
-                    Vec::with_capacity(packages.len() + 2);
-                    ..extend_from_slice(&["purge", "-y"]);
-                    ..extend_from_slice(packages);
-                },
-            )
-            .run()?;
-        self.chroot.command("apt-get", &["autoremove", "-y", "--purge"]).run()
+    /// Remove the given packages from the system, and purge the others along with their
+    /// configuration files, with the package manager of the install.
+    pub fn remove_packages(&self, remove: &[&str], purge: &[&str]) -> io::Result<()> {
+        info!("removing packages: {:?}", remove);
+        info!("purging packages: {:?}", purge);
+        for (program, args) in self.os.remove_commands(remove, purge) {
+            self.chroot.command(program, &args).run()?;
+        }
+
+        Ok(())
     }
 
-    /// Configure the bootloader on the system.
-    pub fn bootloader(&self) -> io::Result<()> {
+    /// Configure the bootloader on the system, adding the `options` and then the `args` of
+    /// the config to the kernel command line of kernelstub, or else of GRUB, which adds the
+    /// other systems that os-prober finds to its menu if `os_prober`, and is branded by the
+    /// `grub` options. The `root` options find the root file system, for kernelstub and the
+    /// entries of systemd-boot, whose `loader.conf` is written with the `systemd_boot`
+    /// options.
+    pub fn bootloader(
+        &self,
+        options: &str,
+        args: &[String],
+        loader: BootloaderChoice,
+        root: &str,
+        os_prober: bool,
+        systemd_boot: &SystemdBootOptions,
+        grub: &GrubOptions,
+    ) -> io::Result<()> {
         info!("configuring bootloader");
-        let result = self
-            .chroot
-            .command(
-                "kernelstub",
-                &[
-                    "--esp-path",
-                    "/boot/efi",
-                    "--add-options",
-                    BOOT_OPTIONS,
-                    "--loader",
-                    "--manage-only",
-                    "--force-update",
-                    "--verbose",
-                ],
-            )
-            .run();
+        if loader.is_board() {
+            return self.board(loader, options, args, root);
+        }
+
+        if loader != BootloaderChoice::SystemdBoot {
+            self.grub_cmdline(options, args)?;
+            self.grub_os_prober(os_prober)?;
+            self.grub_branding(grub)?;
+            let args: &[&str] = &[];
+            return self.chroot.command("update-grub", args).run();
+        }
+
+        let defaults = [BOOT_OPTIONS, options].join(" ");
+        let merged =
+            cmdline::merge(defaults.split_whitespace().chain(args.iter().map(String::as_str)));
+
+        // The defaults of kernelstub which an argument gave a new value to.
+        let replaced = cmdline::replaced(BOOT_OPTIONS, &merged);
+        if !replaced.is_empty() {
+            info!("replaced default kernel arguments: {}", replaced.join(" "));
+        }
+
+        // Another OS which shares the ESP may have configured systemd-boot, and kernelstub
+        // replaces its config, so it is read first.
+        let esp = self.chroot.path.join("boot/efi");
+        let path = esp.join("loader/loader.conf");
+        let existing = fs::read_to_string(&path).unwrap_or_default();
+
+        // If the image lacks kernelstub, write the entries of systemd-boot instead.
+        let kernelstub = self.chroot.path.join("usr/bin/kernelstub").exists();
+        let mut ids = if kernelstub {
+            self.kernelstub(&merged, root)?
+        } else {
+            self.loader_entries(&merged.join(" "), root)?
+        };
+
+        ids.recovery = systemd_boot::recovery_entry(&esp.join("loader/entries"));
+        let default = ids.resolve(systemd_boot.default.as_deref().unwrap_or("current"))?;
+        info!("systemd-boot boots {} by default", default);
+
+        // Without kernelstub, nothing would copy the kernels which are installed later.
+        if !kernelstub {
+            let position = if default == ids.current {
+                Some(1)
+            } else if ids.oldkern.as_ref() == Some(&default) {
+                Some(2)
+            } else {
+                None
+            };
+
+            let os_release = OsRelease::new_from(self.chroot.path.join("etc/os-release"))?;
+            let name = normalize_os_release_name(&os_release.name);
+            let options = [root, " ro ", &merged.join(" ")].concat();
+            let title = &os_release.pretty_name;
+            self.kernel_hook(&kernel_hook::systemd_boot("", &name, title, &options, position))?;
+        }
+
+        fs::create_dir_all(esp.join("loader"))?;
+        fs::write(&path, systemd_boot::loader_conf(&existing, &default, systemd_boot))
+            .with_context(|err| format!("failed to write {:?}: {}", path, err))
+    }
+
+    /// Writes the configuration of kernelstub, with the `root` options followed by the
+    /// `args`, and has it copy each kernel of the install to the ESP in turn, with the
+    /// newest last, as kernelstub boots the last kernel that it was given.
+    fn kernelstub(&self, args: &[&str], root: &str) -> io::Result<EntryIds> {
+        let os_release = OsRelease::new_from(self.chroot.path.join("etc/os-release"))?;
+        let name = normalize_os_release_name(&os_release.name);
+        let kernels = self.kernels()?;
+
+        let options = root.split_whitespace().chain(args.iter().cloned()).collect::<Vec<_>>();
+        let defaults = BOOT_OPTIONS.split_whitespace().collect::<Vec<_>>();
+        let dir = self.chroot.path.join("etc/kernelstub");
+        fs::create_dir_all(&dir)?;
+        let path = dir.join("configuration");
+        fs::write(&path, kernelstub::configuration("/boot/efi", &defaults, &options))
+            .with_context(|err| format!("failed to write {:?}: {}", path, err))?;
+
+        for kernel in kernels.iter().rev() {
+            let kernel_path = ["/boot/", &kernel.kernel].concat();
+            let initrd_path = ["/boot/", &kernel.initrd].concat();
+            self.chroot
+                .command(
+                    "kernelstub",
+                    &["--verbose", "--kernel-path", &kernel_path, "--initrd-path", &initrd_path],
+                )
+                .run()?;
+        }
 
-        match result {
-            Ok(()) => Ok(()),
-            // If kernelstub was not found, use grub instead.
-            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
-                let args: &[&str] = &[];
-                self.chroot.command("update-grub", args).run()
+        info!("kernel command line: {}", options.join(" "));
+
+        // kernelstub names the entries of the kernel that it was given, and of the one before.
+        Ok(EntryIds {
+            current:  [&*name, "-current"].concat(),
+            oldkern:  if kernels.len() > 1 { Some([&*name, "-oldkern"].concat()) } else { None },
+            recovery: None,
+        })
+    }
+
+    /// Copies the kernels of the install to the ESP, and writes a systemd-boot entry for
+    /// each of them.
+    fn loader_entries(&self, kernel_cmdline: &str, root: &str) -> io::Result<EntryIds> {
+        let os_release = OsRelease::new_from(self.chroot.path.join("etc/os-release"))?;
+        let name = normalize_os_release_name(&os_release.name);
+        let kernels = self.kernels()?;
+
+        let boot = self.chroot.path.join("boot");
+        let esp = boot.join("efi");
+        let kernel_dir = esp.join("EFI").join(&*name);
+        let entry_dir = esp.join("loader/entries");
+        fs::create_dir_all(&kernel_dir)?;
+        fs::create_dir_all(&entry_dir)?;
+
+        let options = [root, " ro ", kernel_cmdline].concat();
+        for kernel in &kernels {
+            for file in &[&kernel.kernel, &kernel.initrd] {
+                fs::copy(boot.join(file), kernel_dir.join(file))
+                    .with_context(|err| format!("failed to copy {} to the ESP: {}", file, err))?;
             }
-            Err(why) => Err(why),
+
+            let path = entry_dir.join([&systemd_boot::entry_id(&name, kernel), ".conf"].concat());
+            let entry = systemd_boot::entry(&name, &os_release.pretty_name, kernel, &options);
+            fs::write(&path, entry)
+                .with_context(|err| format!("failed to write {:?}: {}", path, err))?;
         }
+
+        info!("kernel command line: {}", options);
+        Ok(EntryIds {
+            current:  systemd_boot::entry_id(&name, &kernels[0]),
+            oldkern:  kernels.get(1).map(|kernel| systemd_boot::entry_id(&name, kernel)),
+            recovery: None,
+        })
+    }
+
+    /// Writes the config which the firmware of a board boots, with the `root` options, the
+    /// consoles of the live system, the `options`, and then the `args`, and copies the
+    /// device trees and firmware that it loads to `/boot`.
+    fn board(
+        &self,
+        loader: BootloaderChoice,
+        options: &str,
+        args: &[String],
+        root: &str,
+    ) -> io::Result<()> {
+        let kernels = self.kernels()?;
+        let live = fs::read_to_string("/proc/cmdline").unwrap_or_default();
+        let defaults = [root, " ro rootwait ", options].concat();
+        let merged = cmdline::merge(
+            defaults
+                .split_whitespace()
+                .chain(board::consoles(&live))
+                .chain(args.iter().map(String::as_str)),
+        );
+        let kernel_cmdline = merged.join(" ");
+
+        let boot = self.chroot.path.join("boot");
+        if loader == BootloaderChoice::RaspberryPi {
+            let firmware = board::raspi_firmware(&self.chroot.path).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::NotFound, "the image lacks the Raspberry Pi firmware")
+            })?;
+            board::copy_dir(&firmware, &boot)?;
+
+            // The firmware loads the device tree of the board from the root of `/boot`.
+            let kernel = &kernels[0];
+            if let Some(dtbs) = board::dtb_dir(&self.chroot.path, &kernel.version) {
+                let broadcom = dtbs.join("broadcom");
+                board::copy_dir(if broadcom.is_dir() { &broadcom } else { &dtbs }, &boot)?;
+                let overlays = dtbs.join("overlays");
+                if overlays.is_dir() {
+                    board::copy_dir(&overlays, &boot.join("overlays"))?;
+                }
+            }
+
+            let arm_64bit = efi_fallback::target_arch(&self.chroot.path) == Some("aa64");
+            for (file, contents) in &[
+                ("config.txt", board::config_txt(kernel, arm_64bit)),
+                ("cmdline.txt", [kernel_cmdline.as_str(), "\n"].concat()),
+            ] {
+                let path = boot.join(file);
+                fs::write(&path, contents)
+                    .with_context(|err| format!("failed to write {:?}: {}", path, err))?;
+            }
+
+            // The firmware would otherwise boot the kernel it was given until it is removed.
+            let firmware = firmware.strip_prefix(&self.chroot.path).unwrap_or(&firmware);
+            let firmware = firmware.to_string_lossy();
+            self.kernel_hook(&kernel_hook::raspberry_pi("", &firmware, arm_64bit))?;
+        } else {
+            // U-Boot loads the device trees from the boot partition.
+            let mut entries = Vec::new();
+            for kernel in &kernels {
+                let dtbs = board::dtb_dir(&self.chroot.path, &kernel.version);
+                let target = boot.join("dtbs").join(&kernel.version);
+                if let Some(ref dtbs) = dtbs {
+                    if *dtbs != target {
+                        board::copy_dir(dtbs, &target)?;
+                    }
+                }
+
+                entries.push((kernel, dtbs.is_some()));
+            }
+
+            let os_release = OsRelease::new_from(self.chroot.path.join("etc/os-release"))?;
+            let conf = board::extlinux_conf(&os_release.pretty_name, &entries, &kernel_cmdline);
+            let dir = boot.join("extlinux");
+            fs::create_dir_all(&dir)?;
+            let path = dir.join("extlinux.conf");
+            fs::write(&path, conf)
+                .with_context(|err| format!("failed to write {:?}: {}", path, err))?;
+
+            // U-Boot would otherwise lack the labels of the kernels which are installed later.
+            let hook = kernel_hook::extlinux("", &os_release.pretty_name, &kernel_cmdline);
+            self.kernel_hook(&hook)?;
+        }
+
+        info!("kernel command line: {}", kernel_cmdline);
+        Ok(())
+    }
+
+    /// Installs the `hook` which updates the boot files of the kernels, to be run as the
+    /// kernels of the install are installed and removed.
+    fn kernel_hook(&self, hook: &str) -> io::Result<()> {
+        let mut hooks = Vec::new();
+        hooks.extend(kernel_hook::PATHS.iter().map(|path| (*path, hook, 0o755)));
+        if self.chroot.path.join("etc/pacman.d").is_dir() {
+            hooks.push((kernel_hook::PACMAN_HOOK, kernel_hook::PACMAN_HOOK_CONF, 0o644));
+        }
+
+        for (path, contents, mode) in hooks {
+            let path = self.chroot.path.join(path);
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+
+            fs::write(&path, contents)
+                .and_then(|_| fs::set_permissions(&path, Permissions::from_mode(mode)))
+                .with_context(|err| format!("failed to write {:?}: {}", path, err))?;
+        }
+
+        Ok(())
+    }
+
+    /// The kernels in `/boot` of the install, with the newest first.
+    fn kernels(&self) -> io::Result<Vec<systemd_boot::Kernel>> {
+        let boot = self.chroot.path.join("boot");
+        let kernels = systemd_boot::kernels(&boot)
+            .with_context(|err| format!("failed to find kernels in {:?}: {}", boot, err))?;
+        if kernels.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "the install has no kernel with an initrd in /boot",
+            ));
+        }
+
+        Ok(kernels)
+    }
+
+    /// Merges the `args` with the default kernel command line of GRUB, which is followed by
+    /// the `options` of `hibernation`.
+    fn grub_cmdline(&self, options: &str, args: &[String]) -> io::Result<()> {
+        let grub =
+            fs::read_to_string(self.chroot.path.join("etc/default/grub")).unwrap_or_default();
+        let defaults = cmdline::grub_default(&grub);
+        let merged =
+            cmdline::merge(defaults.split_whitespace().chain(args.iter().map(String::as_str)))
+                .join(" ");
+
+        if !args.is_empty() {
+            self.grub_setting("distinst-cmdline.cfg", &cmdline::grub(&merged))?;
+        }
+
+        info!("kernel command line: {}", [merged.as_str(), options].join(" ").trim());
+        Ok(())
+    }
+
+    /// Enables os-prober in the config of GRUB, so that `update-grub` adds the other systems
+    /// to its menu, or disables it explicitly. It is only enabled if it is installed.
+    fn grub_os_prober(&self, enable: bool) -> io::Result<()> {
+        let installed = self.chroot.path.join(grub::OS_PROBER).exists();
+        if enable && !installed {
+            info!("os-prober is not installed, so other systems are not added to the GRUB menu");
+        } else if enable {
+            // Logs the systems which it finds, which update-grub finds again.
+            let args: &[&str] = &[];
+            if let Err(why) = self.chroot.command("os-prober", args).run() {
+                warn!("os-prober failed, so other systems may be missing from GRUB: {}", why);
+            }
+        }
+
+        self.grub_setting("distinst-os-prober.cfg", grub::os_prober(enable, installed))
+    }
+
+    /// Writes the `settings` of GRUB to the file `name` of `/etc/default/grub.d`, or adds them
+    /// to `/etc/default/grub` itself where `grub-mkconfig` doesn't read that directory, as on
+    /// Arch. Nothing is written if the install lacks GRUB's config.
+    fn grub_setting(&self, name: &str, settings: &str) -> io::Result<()> {
+        let default = self.chroot.path.join("etc/default/grub");
+        if !default.exists() {
+            return Ok(());
+        }
+
+        // Debian's `grub-mkconfig` sources the files of `grub.d` after `/etc/default/grub`.
+        let mkconfig = ["usr/sbin/grub-mkconfig", "usr/bin/grub-mkconfig"];
+        let sources_grub_d = mkconfig.iter().any(|path| {
+            fs::read_to_string(self.chroot.path.join(path))
+                .map_or(false, |script| script.contains("default/grub.d"))
+        });
+
+        if sources_grub_d {
+            let grub_d = self.chroot.path.join("etc/default/grub.d");
+            fs::create_dir_all(&grub_d)?;
+            let path = grub_d.join(name);
+            return fs::write(&path, settings)
+                .with_context(|err| format!("failed to write {:?}: {}", path, err));
+        }
+
+        let block = ["\n# Written by distinst as ", name, "\n", settings].concat();
+        let mut grub = fs::read_to_string(&default)?;
+        if !grub.contains(&block) {
+            grub.push_str(&block);
+            fs::write(&default, grub)
+                .with_context(|err| format!("failed to write {:?}: {}", default, err))?;
+        }
+
+        Ok(())
+    }
+
+    /// Installs the theme and the background of the `options` to `/boot/grub`, and sets them
+    /// in `/etc/default/grub` along with the rest of the `options`.
+    fn grub_branding(&self, options: &GrubOptions) -> io::Result<()> {
+        if *options == GrubOptions::default() {
+            return Ok(());
+        }
+
+        let mut theme = None;
+        if let Some(ref dir) = options.theme_dir {
+            let name = dir
+                .file_name()
+                .into_io_result(|| format!("{} is not a directory", dir.display()))?;
+            let target = Path::new(grub::THEMES).join(name);
+            board::copy_dir(dir, &self.chroot.path.join(&target))?;
+            info!("installed the GRUB theme to /{}", target.display());
+            theme = Some(Path::new("/").join(target).join("theme.txt"));
+        }
+
+        let mut background = None;
+        if let Some(ref image) = options.background {
+            let name = image
+                .file_name()
+                .into_io_result(|| format!("{} is not a file", image.display()))?;
+            let target = Path::new("boot/grub").join(name);
+            let path = self.chroot.path.join(&target);
+            fs::create_dir_all(self.chroot.path.join("boot/grub"))?;
+            fs::copy(image, &path).with_context(|err| {
+                format!("failed to copy {} to {:?}: {}", image.display(), path, err)
+            })?;
+            background = Some(Path::new("/").join(target));
+        }
+
+        let path = self.chroot.path.join("etc/default/grub");
+        let existing = fs::read_to_string(&path).unwrap_or_default();
+        let settings = options.settings(theme.as_deref(), background.as_deref());
+        fs::write(&path, grub::default_grub(&existing, &settings))
+            .with_context(|err| format!("failed to write {:?}: {}", path, err))
     }
 
     /// Add the apt repository on the image, so that packages may be installed from it.
@@ -162,40 +567,156 @@ impl<'a> ChrootConfigurator<'a> {
         }
     }
 
-    /// Create a new user account.
-    pub fn create_user(
-        &self,
-        user: &str,
-        pass: Option<&str>,
-        fullname: Option<&str>,
-        profile_icon: Option<&str>,
-    ) -> io::Result<()> {
-        // Add the user to the system.
+    /// Create a swap file of `size` MiB at `/swapfile`, which must not be copied on write on
+    /// btrfs.
+    pub fn create_swapfile(&self, size: u64, btrfs: bool) -> io::Result<()> {
+        info!("creating a swap file of {} MiB", size);
+        let path = self.chroot.path.join(SWAPFILE);
+        fs::File::create(&path)?.set_permissions(Permissions::from_mode(0o600))?;
+
+        if btrfs {
+            self.chroot.command("chattr", &["+C", "/swapfile"]).run()?;
+        }
+
+        let size = format!("{}M", size);
+        self.chroot.command("fallocate", &["-l", &size, "/swapfile"]).run()?;
+        self.chroot.command("mkswap", &["/swapfile"]).run()
+    }
+
+    /// Configure swap on a zram device, with the generator or service of the install.
+    pub fn zram(&self, zram: &ZramConfig) -> io::Result<()> {
+        let mechanism = ZramMechanism::detect(&self.chroot.path).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                "the install has neither zram-generator nor zram-tools",
+            )
+        })?;
+
+        let (conf, contents) = match mechanism {
+            ZramMechanism::Generator => (zram::GENERATOR_CONF, zram.generator_conf()),
+            ZramMechanism::Zramswap => (zram::ZRAMSWAP_CONF, zram.zramswap_conf()),
+        };
+
+        info!("writing /{}", conf);
+        let path = self.chroot.path.join(conf);
+        fs::write(&path, contents)
+            .with_context(|err| format!("failed to write {:?}: {}", path, err))?;
+
+        // The generator creates the device from its config on each boot.
+        if mechanism == ZramMechanism::Zramswap {
+            self.chroot.command("systemctl", &["enable", "zramswap.service"]).run()?;
+        }
+
+        Ok(())
+    }
+
+    /// Create the user accounts, in an order which gives them the same IDs on every install.
+    pub fn create_users(&self, accounts: &[UserAccountCreate]) -> io::Result<()> {
+        user::validate_all(accounts)?;
+        for account in user::creation_order(accounts) {
+            self.create_user(account)
+                .with_context(|err| format!("{}: {}", account.username, err))?;
+        }
+
+        Ok(())
+    }
+
+    /// Create a new user account. An account of the image with the same name and the fixed
+    /// user ID of the account is updated instead.
+    pub fn create_user(&self, account: &UserAccountCreate) -> io::Result<()> {
+        user::validate(account)?;
+        let user = account.username.as_str();
+
+        let passwd = fs::read_to_string(self.chroot.path.join("etc/passwd"))?;
+        let existing = fs::read_to_string(self.chroot.path.join("etc/group"))?;
+        user::verify_image(slice::from_ref(account), &passwd, &existing)?;
+        let adopted = user::existing_uid(&passwd, user).is_some();
+
+        // The primary group is created first, so that it has the fixed ID.
+        if let Some(gid) = account.gid.filter(|_| !adopted) {
+            if !existing.lines().any(|line| line.split(':').next() == Some(user)) {
+                self.chroot.command("groupadd", &["-g", &gid.to_string(), user]).run()?;
+            }
+        }
+
+        // Add the user to the system, with a home copied from `/etc/skel`.
         {
             const DEFAULT_USERADD_FLAGS: &[&str] = &[
                 "-m",
-                "-G", "adm,sudo,lpadmin",
-                "-s", "/bin/bash"
+                "-k", "/etc/skel",
             ];
 
-            let mut command = self.chroot.command("useradd", DEFAULT_USERADD_FLAGS);
+            let mut command = if adopted {
+                info!("updating {}, which the image already has", user);
+                self.chroot.command("usermod", iter::empty::<&str>())
+            } else {
+                let mut command = self.chroot.command("useradd", DEFAULT_USERADD_FLAGS);
+                command.args(&["-s", account.shell.as_deref().unwrap_or("/bin/bash")]);
+                if let Some(uid) = account.uid {
+                    command.args(&["-u", &uid.to_string()]);
+                }
 
-            if let Some(name) = fullname {
+                if account.gid.is_some() {
+                    command.args(&["-g", user]);
+                }
+
+                command
+            };
+
+            let groups = match account.groups {
+                Some(ref groups) => groups.join(","),
+                // Default groups which the image lacks, such as `lpadmin`, are skipped.
+                None => {
+                    user::DEFAULT_GROUPS
+                        .iter()
+                        .filter(|group| {
+                            existing.lines().any(|line| line.split(':').next() == Some(group))
+                        })
+                        .cloned()
+                        .collect::<Vec<&str>>()
+                        .join(",")
+                }
+            };
+
+            if !groups.is_empty() {
+                command.args(&["-G", &groups]);
+            }
+
+            if let (true, Some(shell)) = (adopted, account.shell.as_ref()) {
+                command.args(&["-s", shell]);
+            }
+
+            if let Some(ref name) = account.realname {
                 command.args(&["-c", name]);
             }
 
             command.arg(user).run()?;
         }
 
-        // Set the password for the newly-created user.
-        if let Some(pass) = pass {
-            let pass = &[pass, "\n", pass, "\n"].concat();
-            self.chroot.command("passwd", &[user]).stdin_input(pass).run()?;
+        // Set the password for the newly-created user, which is only given to the install
+        // hashed.
+        let hash = match (account.password.as_ref(), account.password_hash.as_ref()) {
+            (Some(password), _) => Some(user::hash_password(password)?),
+            (None, Some(hash)) => Some(Zeroizing::new(hash.clone())),
+            (None, None) => None,
+        };
+
+        if let Some(hash) = hash {
+            let input = Zeroizing::new([user, ":", &hash, "\n"].concat());
+            self.chroot.command("chpasswd", &["-e"]).stdin_input(&input).run()?;
+        } else if account.no_password {
+            // The password is set by the user when they first log in.
+            self.chroot.command("passwd", &["--delete", user]).run()?;
+            self.chroot.command("passwd", &["--expire", user]).run()?;
+        }
+
+        if account.autologin {
+            super::autologin::configure(&self.chroot.path, user)?;
         }
 
         // Copy the profile icon to `/var/lib/AccountsService/icons/{user}` and assign that in
         // the config file at `/var/lib/AccountsService/users/{user}`.
-        if let Some(path) = profile_icon {
+        if let Some(ref path) = account.profile_icon {
             let mut dest = self.chroot.path.join(&["var/lib/AccountsService/icons/", user].concat());
 
             if fs::copy(&path, &dest).is_err() {
@@ -217,6 +738,28 @@ impl<'a> ChrootConfigurator<'a> {
         Ok(())
     }
 
+    /// Have the initramfs unlock the devices in `/etc/crypttab`, activate logical volumes,
+    /// and resume from the swap with the UUID of `resume`.
+    pub fn initramfs_config(
+        &self,
+        tool: InitramfsTool,
+        support: FileSystemSupport,
+        resume: Option<&str>,
+    ) -> io::Result<()> {
+        info!("configuring the initramfs for {:?}", tool);
+        for (path, contents) in tool.config_files(&self.chroot.path, support, resume)? {
+            let path = self.chroot.path.join(path);
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+
+            fs::write(&path, contents)
+                .with_context(|err| format!("failed to write {:?}: {}", path, err))?;
+        }
+
+        Ok(())
+    }
+
     /// Disable the nvidia fallback service.
     pub fn disable_nvidia_fallback(&self) {
         info!("attempting to disable nvidia-fallback.service");
@@ -226,18 +769,189 @@ impl<'a> ChrootConfigurator<'a> {
         }
     }
 
-    /// Use locale-gen and update-locale to set the locale of the machine.
-    pub fn generate_locale(&self, locale: &str) -> io::Result<()> {
-        info!("generating locales via `locale-gen` and `update-locale`");
-        self.chroot.command("locale-gen", &["--purge", locale]).run()?;
-        self.chroot.command("update-locale", &["--reset", &["LANG=", locale].concat()]).run()
+    /// Writes the units which run on the first boot, along with their scripts, and enables
+    /// them. Units of the image are never overwritten.
+    pub fn firstboot_units(&self, units: &[FirstBootUnit]) -> io::Result<()> {
+        firstboot::validate(units)?;
+        for unit in units {
+            unit.verify_unique(&self.chroot.path)?;
+        }
+
+        let dir = self.chroot.path.join(firstboot::FIRSTBOOT_DIR);
+        fs::create_dir_all(&dir)
+            .with_context(|err| format!("failed to create {:?}: {}", dir, err))?;
+
+        let wants = self.chroot.path.join(firstboot::UNIT_DIR).join("multi-user.target.wants");
+        fs::create_dir_all(&wants)
+            .with_context(|err| format!("failed to create {:?}: {}", wants, err))?;
+
+        for unit in units {
+            let name = unit.unit_name();
+            info!("adding {} to run on the first boot", name);
+
+            if let Some(ref script) = unit.script {
+                let dest = self.chroot.path.join(unit.script_path());
+                if let Some(parent) = dest.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+
+                fs::copy(script, &dest)
+                    .and_then(|_| fs::set_permissions(&dest, Permissions::from_mode(0o755)))
+                    .with_context(|err| format!("failed to copy {:?}: {}", script, err))?;
+            }
+
+            let path = self.chroot.path.join(firstboot::UNIT_DIR).join(&name);
+            fs::write(&path, unit.unit())
+                .with_context(|err| format!("failed to write {:?}: {}", path, err))?;
+
+            // Enabled as `systemctl enable` would, which needs no commands to be run.
+            symlink(["../", &name].concat(), wants.join(&name))?;
+        }
+
+        Ok(())
+    }
+
+    /// Enable the locales in `/etc/locale.gen`, generate them with `locale-gen`, and set the
+    /// locale of the machine. If they could not be generated, `C.UTF-8` is used instead.
+    pub fn generate_locale(&self, lang: &str, additional: &[String]) -> io::Result<()> {
+        if self.os.locale_mechanism() == LocaleMechanism::Precompiled {
+            let lang = if locale::is_builtin(lang) || self.locale_installed(lang) {
+                lang
+            } else {
+                warn!("{} is not installed, using {}", lang, locale::FALLBACK);
+                locale::FALLBACK
+            };
+
+            return self.default_locale(lang);
+        }
+
+        info!("generating locales via `locale-gen`");
+        let locales = iter::once(lang)
+            .chain(additional.iter().map(String::as_str))
+            .filter(|locale| !locale::is_builtin(locale))
+            .collect::<Vec<&str>>();
+
+        let lang = if locales.is_empty() {
+            lang
+        } else {
+            match self.enable_locales(lang, &locales).and_then(|_| self.locale_gen(&locales)) {
+                Ok(()) => lang,
+                Err(why) => {
+                    warn!("failed to generate locales, using {}: {}", locale::FALLBACK, why);
+                    locale::FALLBACK
+                }
+            }
+        };
+
+        self.default_locale(lang)
+    }
+
+    fn enable_locales(&self, lang: &str, locales: &[&str]) -> io::Result<()> {
+        let supported = fs::read_to_string(self.chroot.path.join("usr/share/i18n/SUPPORTED")).ok();
+
+        let mut entries = Vec::new();
+        for &name in locales {
+            match locale::entry(name, supported.as_deref()) {
+                Some(entry) => entries.push(entry),
+                None if name == lang => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("{} is not a supported locale", name),
+                    ));
+                }
+                None => warn!("{} is not a supported locale, so it will not be generated", name),
+            }
+        }
+
+        let path = self.chroot.path.join("etc/locale.gen");
+        let existing = match fs::read_to_string(&path) {
+            Ok(existing) => existing,
+            Err(ref why) if why.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(why) => return Err(why),
+        };
+
+        fs::write(&path, locale::enable(&existing, &entries))
+            .with_context(|err| format!("failed to write locales to {:?}: {}", path, err))
+    }
+
+    /// Whether a locale which is installed precompiled is available, by `locale -a`.
+    fn locale_installed(&self, lang: &str) -> bool {
+        let wanted = locale::normalize(lang);
+        self.chroot.command("locale", &["-a"]).run_with_stdout().ok().map_or(false, |output| {
+            output.lines().any(|installed| locale::normalize(installed) == wanted)
+        })
+    }
+
+    fn locale_gen(&self, locales: &[&str]) -> io::Result<()> {
+        // Ubuntu's locale-gen takes the locales to generate as arguments, and ignores
+        // `/etc/locale.gen`, whereas Debian's only generates those enabled there.
+        let mut args = Vec::new();
+        if self.chroot.path.join("var/lib/locales/supported.d").is_dir() {
+            args.push("--purge");
+            args.extend_from_slice(locales);
+        }
+
+        self.chroot.command("locale-gen", &args).run()
+    }
+
+    /// Writes `/etc/default/locale` for Debian and Ubuntu, and `/etc/locale.conf` for
+    /// distributions that use it instead.
+    pub fn default_locale(&self, lang: &str) -> io::Result<()> {
+        info!("setting the locale to {}", lang);
+        let contents = ["LANG=", lang, "\n"].concat();
+        let default_dir = self.chroot.path.join("etc/default");
+        let locale_conf = self.chroot.path.join("etc/locale.conf");
+
+        let mut paths = Vec::new();
+        if default_dir.is_dir() {
+            paths.push(default_dir.join("locale"));
+        }
+        if paths.is_empty() || locale_conf.symlink_metadata().is_ok() {
+            paths.push(locale_conf);
+        }
+
+        for path in paths {
+            // A link may be absolute, and so point outside of the chroot. Debian links
+            // `/etc/locale.conf` to `/etc/default/locale`, which is written in its place.
+            if path.symlink_metadata().map_or(false, |meta| meta.file_type().is_symlink()) {
+                continue;
+            }
+
+            fs::write(&path, &contents)
+                .with_context(|err| format!("failed to write locale to {:?}: {}", path, err))?;
+        }
+
+        Ok(())
+    }
+
+    /// Configure GRUB to identify the root device on the kernel command line as the fstab
+    /// does. GRUB has no support for labels, which are left to the UUID. The options of
+    /// kernelstub and of systemd-boot are given the root device as the fstab identifies it.
+    pub fn grub_root_id(&self, variant: PartitionSource) -> io::Result<()> {
+        let settings = match variant {
+            PartitionSource::PartUUID => {
+                "GRUB_DISABLE_LINUX_UUID=true\nGRUB_DISABLE_LINUX_PARTUUID=false\n"
+            }
+            PartitionSource::Path => "GRUB_DISABLE_LINUX_UUID=true\n",
+            _ => return Ok(()),
+        };
+
+        info!("identifying the root device on the GRUB command line by {:?}", variant);
+        self.grub_setting("distinst-root.cfg", settings)
+    }
+
+    /// Configure GRUB to resume from hibernation, with the kernel `options` which name the
+    /// swap. The initramfs is configured with `initramfs_config`.
+    pub fn hibernation(&self, options: &str) -> io::Result<()> {
+        info!("resuming from hibernation with {}", options);
+        self.grub_setting("distinst-resume.cfg", &hibernation::grub(options))
     }
 
-    /// Generate a new machine ID for /var/lib/dbus/machine-id
-    pub fn generate_machine_id(&self) -> io::Result<()> {
-        info!("generating machine id via `dbus-uuidgen`");
-        self.chroot.command("sh", &["-c", "dbus-uuidgen > /etc/machine-id"]).run()?;
-        self.chroot.command("ln", &["-sf", "/etc/machine-id", "/var/lib/dbus/machine-id"]).run()
+    /// Configure GRUB to unlock the root with the `cryptdevice` of mkinitcpio's `encrypt`
+    /// hook, which reads it from the kernel command line rather than the crypttab.
+    pub fn grub_cryptdevice(&self, option: &str) -> io::Result<()> {
+        info!("unlocking the root with {}", option);
+        self.grub_setting("distinst-crypt.cfg", &kernelstub::grub_crypt_option(option))
     }
 
     /// Set the hostname of the new install.
@@ -249,72 +963,205 @@ impl<'a> ChrootConfigurator<'a> {
             .with_context(|err| format!("failed to write hostname to {:?}: {}", hostfile, err))
     }
 
-    /// Create a default hosts file for the new install.
-    pub fn hosts(&self, hostname: &str) -> io::Result<()> {
+    /// Create the hosts file for the new install, from the one in the image.
+    pub fn hosts(&self, hostname: &str, append: &[String]) -> io::Result<()> {
         info!("setting hosts file");
-        let hosts = self.chroot.path.join("etc/hosts");
-        let mut file = misc::create(&hosts)?;
-        writeln!(
-            &mut file,
-            "# See `man hosts` for details.\n#
-# By default, systemd-resolved or libnss-myhostname will resolve
-# localhost and the system hostname if they're not specified here.
-127.0.0.1\tlocalhost\n::1\t\tlocalhost"
-        )
-        .with_context(|err| format!("failed to write hosts to {:?}: {}", hosts, err))
-    }
-
-    pub fn initramfs_disable(&self) -> io::Result<()> {
-        info!("symlinking update-initramfs to true for duration of initial setup");
-
-        self.chroot.command("sh", &["-c", "mv /usr/sbin/update-initramfs /usr/sbin/update-initramfs.bak"])
-            .run()
-            .with_context(|err| format!("failed to migrate `update-initramfs`: {}", err))?;
+        let path = self.chroot.path.join("etc/hosts");
+        let existing = match fs::read_to_string(&path) {
+            Ok(existing) => Some(existing),
+            Err(ref why) if why.kind() == io::ErrorKind::NotFound => None,
+            Err(why) => return Err(why),
+        };
 
-        self.chroot.command("sh", &["-c", "ln -s /usr/bin/true /usr/sbin/update-initramfs"])
-            .run()
-            .with_context(|err| format!("failed to link `true` to `update-initramfs`: {}", err))
+        let hosts = hosts::generate(existing.as_deref(), hostname, append);
+        fs::write(&path, hosts)
+            .with_context(|err| format!("failed to write hosts to {:?}: {}", path, err))
     }
 
-    pub fn initramfs_reenable(&self) -> io::Result<()> {
-        info!("re-enabling update-initramfs");
-        self.chroot.command("sh", &["-c", "rm /usr/sbin/update-initramfs"])
-            .run()
-            .with_context(|err| format!("failed to remove update-initramfs symlink: {}", err))?;
+    /// Replaces the initramfs tool with a script that does nothing, so that the initramfs
+    /// is generated once, after every package has been installed.
+    pub fn initramfs_disable(&mut self, tool: InitramfsTool) -> io::Result<()> {
+        info!("replacing /{} for the duration of initial setup", tool.binary());
+        self.chroot
+            .replace_file(tool.binary(), b"#!/bin/sh\nexit 0\n", 0o755)
+            .with_context(|err| format!("failed to replace /{}: {}", tool.binary(), err))
+    }
 
-        self.chroot.command("sh", &["-c", "mv /usr/sbin/update-initramfs.bak /usr/sbin/update-initramfs"])
-            .run()
-            .with_context(|err| format!("failed to restore backup of update-initramfs: {}", err))
+    pub fn initramfs_reenable(&mut self, tool: InitramfsTool) -> io::Result<()> {
+        info!("re-enabling /{}", tool.binary());
+        self.chroot
+            .restore_file(tool.binary())
+            .with_context(|err| format!("failed to restore /{}: {}", tool.binary(), err))
     }
 
-    /// Set the keyboard layout so that the layout will function, even within the decryption screen.
-    pub fn keyboard_layout(&self, config: &Config) -> io::Result<()> {
-        info!("configuring keyboard layout");
+    /// Install the language packs, fonts, and input methods of the locale. Packages which are
+    /// not available, or which would be downloaded without network access, are skipped.
+    pub fn install_language_packages(
+        &self,
+        packages: &[String],
+        allow_network: bool,
+    ) -> io::Result<()> {
+        let packages = packages.iter().map(String::as_str).collect::<Vec<_>>();
+        self.install_optional("language", &packages, allow_network)
+    }
 
-        // This used to use localectl set-x11-keymap, but that doesn't work on some
-        // versions of Ubuntu/Debian.
-        //
-        // See https://bugs.launchpad.net/ubuntu/+source/cloud-init/+bug/2030788
-        //
-        // So, write the keyboard layout to /etc/default/keyboard in the chroot
+    /// Install the packages of the drivers which were found for the devices of the system.
+    /// Packages which are not on the ISO are skipped, unless the network is allowed.
+    pub fn install_driver_packages(
+        &self,
+        packages: &[&str],
+        allow_network: bool,
+    ) -> io::Result<()> {
+        // A family of versioned packages is resolved from the package metadata of the
+        // image, which includes the ISO once it has been added as a source.
+        let mut resolved = Vec::with_capacity(packages.len());
+        for &package in packages {
+            let prefix = match package.strip_suffix('*') {
+                Some(prefix) => prefix,
+                None => {
+                    resolved.push(package.to_owned());
+                    continue;
+                }
+            };
+
+            let names = self.chroot.command("apt-cache", &["pkgnames", prefix]).run_with_stdout();
+            let names = match names {
+                Ok(names) => names,
+                Err(why) if why.kind() == io::ErrorKind::Interrupted => return Err(why),
+                Err(why) => {
+                    warn!("skipping {}, as its versions could not be found: {}", package, why);
+                    continue;
+                }
+            };
 
-        let keyboard_file = self.chroot.path.join("etc/default/keyboard");
-        let mut file = misc::create(&keyboard_file)?;
-        writeln!(&mut file, "XKBLAYOUT={}\nBACKSPACE=guess", config.keyboard_layout)
-            .with_context(|err| {
-                format!("failed to write keyboard layout to /etc/default/keyboard: {}", err)
-            })?;
+            match drivers::newest_version(package, names.lines().map(str::trim)) {
+                Some(version) => {
+                    info!("resolved {} to {}", package, version);
+                    resolved.push(version.to_owned());
+                }
+                None => warn!("skipping {}, as no version of it is available", package),
+            }
+        }
 
-        if let Some(model) = config.keyboard_model.as_deref() {
-            writeln!(&mut file, "XKBMODEL={}", model).with_context(|err| {
-                format!("failed to write keyboard layout to /etc/default/keyboard: {}", err)
-            })?;
+        let resolved = resolved.iter().map(String::as_str).collect::<Vec<_>>();
+        self.install_optional("driver", &resolved, allow_network)
+    }
+
+    /// Install the packages which are available, and skip the others, as the install may
+    /// continue without them.
+    fn install_optional(
+        &self,
+        kind: &str,
+        packages: &[&str],
+        allow_network: bool,
+    ) -> io::Result<()> {
+        let mut install = Vec::new();
+        for &package in packages {
+            let policy = self.chroot.command("apt-cache", &["policy", package]).run_with_stdout();
+            let policy = match policy {
+                Ok(policy) => policy,
+                Err(why) if why.kind() == io::ErrorKind::Interrupted => return Err(why),
+                Err(why) => {
+                    warn!("skipping {}, as its policy could not be found: {}", package, why);
+                    continue;
+                }
+            };
+
+            match language::candidate(&policy) {
+                Candidate::Installed => (),
+                Candidate::Local => install.push(package),
+                Candidate::Network if allow_network => install.push(package),
+                Candidate::Network => warn!("skipping {}, as it must be downloaded", package),
+                Candidate::Missing => warn!("skipping {}, as it is not available", package),
+            }
         }
 
-        if let Some(variant) = config.keyboard_variant.as_deref() {
-            writeln!(&mut file, "XKBVARIANT={}", variant).with_context(|err| {
-                format!("failed to write keyboard layout to /etc/default/keyboard: {}", err)
-            })?;
+        if install.is_empty() {
+            return Ok(());
+        }
+
+        info!("installing {} packages: {:?}", kind, install);
+        let proxies = if allow_network && !self.proxy {
+            language::proxy_options(env::vars())
+        } else {
+            Vec::new()
+        };
+        let apt_install = |packages: &[&str]| {
+            let mut args = vec!["install", "-y"];
+            args.extend_from_slice(APT_OPTIONS);
+            args.extend(proxies.iter().map(String::as_str));
+            args.extend_from_slice(packages);
+            self.chroot.command("apt-get", &args).run()
+        };
+
+        // If one package fails, the others are installed without it.
+        match apt_install(&install) {
+            Err(why) if why.kind() != io::ErrorKind::Interrupted => {
+                warn!("failed to install {} packages, retrying one at a time: {}", kind, why);
+                for package in install {
+                    match apt_install(&[package]) {
+                        Err(why) if why.kind() == io::ErrorKind::Interrupted => return Err(why),
+                        Err(why) => warn!("failed to install {}: {}", package, why),
+                        Ok(()) => (),
+                    }
+                }
+
+                Ok(())
+            }
+            result => result,
+        }
+    }
+
+    /// Install the packages in a directory outside of the image, such as a pool on the ISO.
+    /// apt installs them in the order of their dependencies, fetching any that are missing.
+    pub fn install_local_packages(&self, dir: &Path) -> io::Result<()> {
+        let debs = local_packages::debs(dir)?;
+        if debs.is_empty() {
+            info!("skipping local packages, as {} has none", dir.display());
+            return Ok(());
+        }
+
+        info!("installing local packages from {}: {:?}", dir.display(), debs);
+
+        let target = self.chroot.path.join(LOCAL_PACKAGES);
+        fs::create_dir_all(&target)?;
+        let mount = Mount::new(dir, &target, "none", MountFlags::BIND, None)
+            .with_context(|err| format!("failed to mount local packages: {}", err))?
+            .into_unmount_drop(UnmountFlags::DETACH);
+
+        // Paths are installed as files by apt, rather than looked up in its sources.
+        let paths = debs.iter().map(|deb| ["/", LOCAL_PACKAGES, "/", deb].concat());
+        let mut args = vec!["install".to_owned(), "-y".to_owned()];
+        args.extend(APT_OPTIONS.iter().map(|&option| option.to_owned()));
+        args.extend(paths);
+
+        let result = self.chroot.command("apt-get", &args).run().map_err(|why| {
+            let installed = debian::installed_packages(&self.chroot).unwrap_or_default();
+            let failed = local_packages::failed(&debs, &installed);
+
+            io::Error::new(
+                why.kind(),
+                format!("failed to install local packages ({}): {}", failed.join(", "), why),
+            )
+        });
+
+        drop(mount);
+        let _ = fs::remove_dir(&target);
+        result
+    }
+
+    /// Set the keyboard layout so that the layout will function, even within the decryption screen.
+    pub fn keyboard_layout(&self, keyboard: &KeyboardConfig) -> io::Result<()> {
+        self.keyboard_files(keyboard)?;
+
+        // Without the init script of console-setup, the console is set up at boot from the
+        // keymap cached by setupcon, or from vconsole.conf.
+        if !self.chroot.path.join("etc/init.d/console-setup.sh").exists() {
+            if self.chroot.path.join("bin/setupcon").exists() {
+                return self.chroot.command("setupcon", &["--save-only"]).run();
+            }
+
+            return Ok(());
         }
 
         self.chroot
@@ -350,6 +1197,43 @@ impl<'a> ChrootConfigurator<'a> {
             .run()
     }
 
+    /// Writes the keyboard layout to the files which configure it, without running any
+    /// commands in the chroot.
+    pub fn keyboard_files(&self, keyboard: &KeyboardConfig) -> io::Result<()> {
+        info!("configuring keyboard layout");
+
+        // This used to use localectl set-x11-keymap, but that doesn't work on some
+        // versions of Ubuntu/Debian.
+        //
+        // See https://bugs.launchpad.net/ubuntu/+source/cloud-init/+bug/2030788
+        //
+        // So, write the keyboard layout to /etc/default/keyboard in the chroot, and to
+        // /etc/vconsole.conf on distributions which use it instead.
+
+        let default_dir = self.chroot.path.join("etc/default");
+        if default_dir.is_dir() {
+            fs::write(default_dir.join("keyboard"), keyboard.default_keyboard()).with_context(
+                |err| format!("failed to write keyboard layout to /etc/default/keyboard: {}", err),
+            )?;
+        }
+
+        let vconsole_file = self.chroot.path.join("etc/vconsole.conf");
+        let vconsole = match vconsole_file.symlink_metadata() {
+            Ok(ref meta) if meta.file_type().is_symlink() => None,
+            Ok(_) => Some(fs::read_to_string(&vconsole_file)?),
+            Err(_) if !default_dir.is_dir() => Some(String::new()),
+            Err(_) => None,
+        };
+
+        if let Some(existing) = vconsole {
+            fs::write(&vconsole_file, keyboard.vconsole(&existing)).with_context(|err| {
+                format!("failed to write keyboard layout to /etc/vconsole.conf: {}", err)
+            })?;
+        }
+
+        Ok(())
+    }
+
     /// In case the kernel is located outside of the squashfs image, find it.
     pub fn kernel_copy(&self) -> io::Result<()> {
         let cdrom_kernel = Path::new("/cdrom/casper/vmlinuz");
@@ -365,11 +1249,135 @@ impl<'a> ChrootConfigurator<'a> {
         }
     }
 
+    /// Fetches packages through the proxy, mirror, and name servers of the network config,
+    /// which are removed once the chroot is dropped unless they are persisted. The network
+    /// is verified to be reachable before the disks are modified.
+    ///
+    /// This must follow `netresolve`, which replaces the `resolv.conf`.
+    pub fn network(&mut self, network: &NetworkConfig) -> io::Result<()> {
+        let mut files = vec![(APT_NETWORK_CONF, network.apt_conf())];
+        if let Some(ref mirror) = network.apt_mirror {
+            for &path in APT_SOURCES {
+                if let Ok(sources) = fs::read_to_string(self.chroot.path.join(path)) {
+                    files.push((path, network::mirror_sources(&sources, mirror)));
+                }
+            }
+        }
+
+        for (path, contents) in files {
+            if network.persist {
+                info!("writing /{}", path);
+                let path = self.chroot.path.join(path);
+                fs::write(&path, contents)
+                    .with_context(|err| format!("failed to write {:?}: {}", path, err))?;
+            } else {
+                self.chroot.replace_file(path, contents.as_bytes(), 0o644)?;
+            }
+        }
+
+        // The `resolv.conf` may link outside of the chroot, and so is always replaced.
+        if !network.dns.is_empty() {
+            self.chroot.replace_file("etc/resolv.conf", network.resolv_conf().as_bytes(), 0o644)?;
+            if network.persist {
+                info!("writing /{}", RESOLVED_NETWORK_CONF);
+                let path = self.chroot.path.join(RESOLVED_NETWORK_CONF);
+                fs::create_dir_all(path.parent().expect("resolved.conf.d has a parent"))?;
+                fs::write(&path, network.resolved_conf())
+                    .with_context(|err| format!("failed to write {:?}: {}", path, err))?;
+            }
+        }
+
+        self.proxy = network.proxy.is_some();
+        Ok(())
+    }
+
     pub fn netresolve(&self) -> io::Result<()> {
+        let resolvconf = match self.os.resolv_conf() {
+            Some(resolvconf) => resolvconf,
+            None => {
+                info!("keeping the /etc/resolv.conf of the image");
+                return Ok(());
+            }
+        };
+
         info!("creating /etc/resolv.conf");
+        let path = self.chroot.path.join("etc/resolv.conf");
+        if path.symlink_metadata().is_ok() {
+            fs::remove_file(&path)?;
+        }
+
+        symlink(resolvconf, &path)
+    }
+
+    /// Prepare the install for an OEM, who customizes it from the temporary `oem` account
+    /// before shipping it. The owner of the machine creates their account on the first boot.
+    pub fn oem_mode(&self) -> io::Result<()> {
+        info!("creating the temporary {} account for OEM mode", oem::USER);
+        self.create_user(&UserAccountCreate {
+            username: oem::USER.into(),
+            realname: Some("OEM Configuration (temporary user)".into()),
+            uid: Some(oem::UID),
+            no_password: true,
+            ..UserAccountCreate::default()
+        })?;
+
+        let flag = self.chroot.path.join(oem::FLAG);
+        if let Some(parent) = flag.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        fs::write(&flag, [oem::USER, "\n"].concat())
+            .with_context(|err| format!("failed to write {:?}: {}", flag, err))?;
+
+        // Reads the flag on each boot, to remove the account once the owner has theirs.
+        let units = self.chroot.path.join(firstboot::UNIT_DIR);
+        let wants = units.join("multi-user.target.wants");
+        let script = self.chroot.path.join(oem::CLEANUP_SCRIPT);
+        fs::create_dir_all(&wants)?;
+        if let Some(parent) = script.parent() {
+            fs::create_dir_all(parent)?;
+        }
 
-        let resolvconf = "../run/systemd/resolve/stub-resolv.conf";
-        self.chroot.command("ln", &["-sf", resolvconf, "/etc/resolv.conf"]).run()
+        fs::write(&script, oem::cleanup_script())
+            .and_then(|_| fs::set_permissions(&script, Permissions::from_mode(0o755)))
+            .with_context(|err| format!("failed to write {:?}: {}", script, err))?;
+        let unit = units.join(oem::CLEANUP_SERVICE);
+        fs::write(&unit, oem::cleanup_unit())
+            .with_context(|err| format!("failed to write {:?}: {}", unit, err))?;
+        symlink(["../", oem::CLEANUP_SERVICE].concat(), wants.join(oem::CLEANUP_SERVICE))?;
+
+        let home = self.chroot.path.join("home").join(oem::USER);
+        match oem::FirstBoot::detect(&self.chroot.path) {
+            Some(oem::FirstBoot::OemConfig) => {
+                info!("enabling oem-config for the first boot");
+                self.chroot.command("systemctl", &["enable", "oem-config.service"]).run()?;
+
+                // The OEM prepares the install for shipping from the desktop of the account.
+                let launcher = "usr/share/applications/oem-config-prepare-gtk.desktop";
+                if self.chroot.path.join(launcher).exists() {
+                    let desktop = home.join("Desktop");
+                    fs::create_dir_all(&desktop)?;
+                    misc::cp(
+                        &self.chroot.path.join(launcher),
+                        &desktop.join("oem-config-prepare-gtk.desktop"),
+                    )?;
+                    let owner = [oem::USER, ":", oem::USER].concat();
+                    let home = ["/home/", oem::USER].concat();
+                    self.chroot.command("chown", &["-R", &owner, &home]).run()?;
+                }
+            }
+            Some(oem::FirstBoot::GnomeInitialSetup) => {
+                // GNOME Initial Setup runs with `--existing-user` until it has been done.
+                info!("enabling gnome-initial-setup for the first login of {}", oem::USER);
+                let done = home.join(".config/gnome-initial-setup-done");
+                if done.exists() {
+                    fs::remove_file(&done)?;
+                }
+            }
+            None => warn!("no first-boot setup service was found in the image"),
+        }
+
+        Ok(())
     }
 
     pub fn recovery(
@@ -385,7 +1393,7 @@ impl<'a> ChrootConfigurator<'a> {
 
         let result = if recovery_path.exists() { 0 } else { 1 }
             | if efi_path.is_dir() { 0 } else { 2 }
-            | if Path::new("/cdrom").is_dir() { 0 } else { 4 };
+            | if config.recovery.is_some() || Path::new("/cdrom").is_dir() { 0 } else { 4 };
 
         if result != 0 {
             warn!(
@@ -420,6 +1428,12 @@ impl<'a> ChrootConfigurator<'a> {
             .or_else(|| PartitionID::get_uuid(&efi_mount.source))
             .into_io_result(|| "/recovery does not have a UUID")?;
 
+        // The recovery step has already copied the live system, and added its boot entry.
+        if config.recovery.is_some() {
+            let (efi, recovery) = (&efi_partuuid.id, &recovery_partuuid.id);
+            return self.recovery_conf(config, efi, recovery, root_uuid, luks_uuid);
+        }
+
         let cdrom_uuid =
             Command::new("findmnt").args(&["-n", "-o", "UUID", "/cdrom"]).run_with_stdout()?;
         let cdrom_uuid = cdrom_uuid.trim();
@@ -465,37 +1479,9 @@ impl<'a> ChrootConfigurator<'a> {
                 .run()?;
         }
 
-        // Create recovery file.
-        let recovery_data = format!(
-            r#"HOSTNAME={}
-LANG={}
-KBD_LAYOUT={}
-KBD_MODEL={}
-KBD_VARIANT={}
-EFI_UUID=PARTUUID={}
-RECOVERY_UUID=PARTUUID={}
-ROOT_UUID={}
-LUKS_UUID={}
-OEM_MODE=0
-"#,
-            config.hostname,
-            config.lang,
-            config.keyboard_layout,
-            config.keyboard_model.as_deref().unwrap_or(""),
-            config.keyboard_variant.as_deref().unwrap_or(""),
-            efi_partuuid.id,
-            recovery_partuuid.id,
-            root_uuid,
-            luks_uuid,
-        );
+        self.recovery_conf(config, &efi_partuuid.id, &recovery_partuuid.id, root_uuid, luks_uuid)?;
 
         // Copy initrd and vmlinuz to EFI partition
-        let recovery_path = self.chroot.path.join("recovery/recovery.conf");
-        let mut recovery_file = misc::create(&recovery_path)?;
-        recovery_file
-            .write_all(recovery_data.as_bytes())
-            .with_context(|err| format!("failed to write recovery file: {}", err))?;
-
         let efi_recovery = ["boot/efi/EFI/", recovery.as_str()].concat();
         let efi_initrd = self.chroot.path.join([&efi_recovery, "/initrd.gz"].concat());
         let efi_vmlinuz = self.chroot.path.join([&efi_recovery, "/vmlinuz.efi"].concat());
@@ -538,16 +1524,183 @@ options {2} boot=casper hostname=recovery userfullname=Recovery username=recover
         Ok(())
     }
 
-    pub fn timezone(&self, region: &Region) -> io::Result<()> {
-        self.chroot.command("rm", &["/etc/timezone"]).run()?;
+    /// Write the `recovery.conf` which the recovery partition reinstalls with.
+    fn recovery_conf(
+        &self,
+        config: &Config,
+        efi_partuuid: &str,
+        recovery_partuuid: &str,
+        root_uuid: &str,
+        luks_uuid: &str,
+    ) -> io::Result<()> {
+        let keyboard = config.keyboard_config();
+        let recovery_data = format!(
+            r#"HOSTNAME={}
+LANG={}
+KBD_LAYOUT={}
+KBD_MODEL={}
+KBD_VARIANT={}
+EFI_UUID=PARTUUID={}
+RECOVERY_UUID=PARTUUID={}
+ROOT_UUID={}
+LUKS_UUID={}
+OEM_MODE={}
+"#,
+            config.hostname,
+            config.lang,
+            keyboard.layout,
+            keyboard.model.as_deref().unwrap_or(""),
+            keyboard.variant.as_deref().unwrap_or(""),
+            efi_partuuid,
+            recovery_partuuid,
+            root_uuid,
+            luks_uuid,
+            config.oem_mode as u8,
+        );
+
+        let recovery_path = self.chroot.path.join("recovery/recovery.conf");
+        let mut recovery_file = misc::create(&recovery_path)?;
+        recovery_file
+            .write_all(recovery_data.as_bytes())
+            .with_context(|err| format!("failed to write recovery file: {}", err))
+    }
+
+    /// Reset the machine ID, SSH host keys, and random seed which the image shares with every
+    /// other install of it. The SSH host keys are generated again for this install.
+    pub fn reset_identity(&self) -> io::Result<()> {
+        info!("resetting the identity of the machine");
+        if identity::reset(&self.chroot.path)? {
+            self.chroot.command("ssh-keygen", &["-A"]).run()?;
+        }
+
+        Ok(())
+    }
+
+    /// Locks the password of root, or sets it, and authorizes the keys of a root which may
+    /// only log in with keys.
+    pub fn root_policy(&self, root: &RootPolicy) -> io::Result<()> {
+        match *root {
+            RootPolicy::Locked => {
+                info!("locking the password of root");
+                self.chroot.command("passwd", &["--lock", "root"]).run()
+            }
+            RootPolicy::Password(ref hash) => {
+                info!("setting the password of root");
+                let input = Zeroizing::new(["root:", hash, "\n"].concat());
+                self.chroot.command("chpasswd", &["-e"]).stdin_input(&input).run()
+            }
+            RootPolicy::Keys(ref keys) => {
+                info!("locking the password of root, which may only log in with keys");
+                self.chroot.command("passwd", &["--lock", "root"]).run()?;
+                self.authorize_keys("root", &ssh::authorized_keys(keys))
+            }
+        }
+    }
+
+    /// Enables the SSH server, and authorizes the keys to log in as `user`, or as root if no
+    /// user was created.
+    pub fn ssh(&self, ssh: &SshConfig, user: Option<&str>) -> io::Result<()> {
+        let keys = ssh.authorized_keys();
+        if !keys.is_empty() {
+            self.authorize_keys(user.unwrap_or("root"), &keys)?;
+        }
+
+        info!("writing /{}", SSHD_CONF);
+        let path = self.chroot.path.join(SSHD_CONF);
+        fs::create_dir_all(path.parent().expect("sshd_config.d has a parent"))?;
+        fs::write(&path, ssh.sshd_conf())
+            .with_context(|err| format!("failed to write {:?}: {}", path, err))?;
+
+        if ssh.enable_server {
+            let service = self.os.ssh_service();
+            info!("enabling {}", service);
+            self.chroot.command("systemctl", &["enable", service]).run()?;
+        }
+
+        Ok(())
+    }
+
+    /// Appends the keys to the `~/.ssh/authorized_keys` of the user, which is only
+    /// accessible to them.
+    fn authorize_keys(&self, user: &str, keys: &str) -> io::Result<()> {
+        let passwd = fs::read_to_string(self.chroot.path.join("etc/passwd"))?;
+        let home = passwd
+            .lines()
+            .map(|line| line.split(':').collect::<Vec<_>>())
+            .find(|fields| fields.len() > 5 && fields[0] == user)
+            .map(|fields| fields[5].trim_start_matches('/').to_owned())
+            .ok_or_else(|| {
+                io::Error::new(io::ErrorKind::NotFound, format!("{} has no account", user))
+            })?;
+
+        info!("authorizing keys to log in as {}", user);
+        let dir = self.chroot.path.join(&home).join(".ssh");
+        fs::create_dir_all(&dir)
+            .with_context(|err| format!("failed to create {:?}: {}", dir, err))?;
+        fs::set_permissions(&dir, Permissions::from_mode(0o700))?;
+
+        let path = dir.join("authorized_keys");
+        fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .and_then(|mut file| file.write_all(keys.as_bytes()))
+            .with_context(|err| format!("failed to write {:?}: {}", path, err))?;
+        fs::set_permissions(&path, Permissions::from_mode(0o600))?;
+
+        // The primary group of the user has the same name as the user.
+        let dir = ["/", &home, "/.ssh"].concat();
+        self.chroot.command("chown", &["-R", &[user, ":"].concat(), &dir]).run()
+    }
+
+    /// Sets the time zone by its Olson name, once validated against the target's database.
+    pub fn timezone(&self, name: &str) -> io::Result<()> {
+        info!("setting time zone to {}", name);
+        timezones::configure(&self.chroot.path, name)
+    }
+
+    /// Find the `resume_offset` of the swap file at `/swapfile`.
+    ///
+    /// On btrfs, this requires the `map-swapfile` command of btrfs-progs 6.1, as the offsets
+    /// which `filefrag` reports are not the physical offsets on the device.
+    pub fn swapfile_offset(&self, btrfs: bool) -> io::Result<u64> {
+        let offset = if btrfs {
+            let version = self.chroot.command("btrfs", &["--version"]).run_with_stdout()?;
+            match hibernation::btrfs_progs_version(&version) {
+                Some(found) if found >= hibernation::BTRFS_MAP_SWAPFILE => (),
+                found => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Other,
+                        format!(
+                            "resuming from a swap file on btrfs requires btrfs-progs {}.{} or \
+                             later, but the install has {}",
+                            hibernation::BTRFS_MAP_SWAPFILE.0,
+                            hibernation::BTRFS_MAP_SWAPFILE.1,
+                            found.map_or("an unknown version".into(), |(major, minor)| {
+                                format!("{}.{}", major, minor)
+                            })
+                        ),
+                    ));
+                }
+            }
+
+            let args = &["inspect-internal", "map-swapfile", "-r", "/swapfile"];
+            let output = self.chroot.command("btrfs", args).run_with_stdout()?;
+            output.trim().parse::<u64>().ok()
+        } else {
+            let output = self.chroot.command("filefrag", &["-v", "/swapfile"]).run_with_stdout()?;
+            hibernation::swapfile_offset(&output)
+        };
 
-        let args: &[&str] = &[];
-        self.chroot.command("ln", args).arg(region.path()).arg("/etc/timezone").run()
+        offset.into_io_result(|| "unable to find the offset of /swapfile")
     }
 
-    pub fn update_initramfs(&self) -> io::Result<()> {
+    /// Generate the initramfs of every installed kernel.
+    pub fn update_initramfs(&self, tool: InitramfsTool) -> io::Result<()> {
+        info!("generating the initramfs of every installed kernel");
+        let (command, args) = tool.command();
         self.chroot
-            .command("update-initramfs", &["-u"])
+            .command(command, args)
             .run()
             .with_context(|why| format!("failed to update initramfs: {}", why))
     }
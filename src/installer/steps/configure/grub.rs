@@ -0,0 +1,199 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+
+/// Where the themes of GRUB are installed.
+pub const THEMES: &str = "boot/grub/themes";
+
+/// Where os-prober is in the install, which `grub-mkconfig` runs unless it is disabled.
+pub const OS_PROBER: &str = "usr/bin/os-prober";
+
+/// The branding and the menu of GRUB, which are set in `/etc/default/grub`. Those which
+/// are `None` are left to the image.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct GrubOptions {
+    /// The `GRUB_DISTRIBUTOR`, which the entries of the install are named after.
+    pub distributor:    Option<String>,
+    /// A directory with a `theme.txt`, which is copied to `/boot/grub/themes/`.
+    pub theme_dir:      Option<PathBuf>,
+    /// An image which is copied to `/boot/grub/`, and shown behind the menu.
+    pub background:     Option<PathBuf>,
+    /// The seconds for which the menu is hidden, in which a key shows it.
+    pub hidden_timeout: Option<u32>,
+    /// The `GRUB_TERMINAL`, such as `console` or `serial`.
+    pub terminal:       Option<String>,
+}
+
+impl GrubOptions {
+    /// Verifies that the theme and the background exist on the live system.
+    pub fn validate(&self) -> io::Result<()> {
+        if let Some(ref dir) = self.theme_dir {
+            if !dir.join("theme.txt").is_file() {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("the theme at {} has no theme.txt", dir.display()),
+                ));
+            }
+        }
+
+        if let Some(ref background) = self.background {
+            if !background.is_file() {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("the background {} does not exist", background.display()),
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The settings of `/etc/default/grub`, with the `theme` and the `background` at their
+    /// paths in the install.
+    pub fn settings(
+        &self,
+        theme: Option<&Path>,
+        background: Option<&Path>,
+    ) -> Vec<(&'static str, String)> {
+        let mut settings = Vec::new();
+        if let Some(ref distributor) = self.distributor {
+            settings.push(("GRUB_DISTRIBUTOR", quote(distributor)));
+        }
+
+        if let Some(theme) = theme {
+            settings.push(("GRUB_THEME", quote(&theme.to_string_lossy())));
+        }
+
+        if let Some(background) = background {
+            settings.push(("GRUB_BACKGROUND", quote(&background.to_string_lossy())));
+        }
+
+        if let Some(timeout) = self.hidden_timeout {
+            settings.push(("GRUB_TIMEOUT_STYLE", "hidden".into()));
+            settings.push(("GRUB_TIMEOUT", timeout.to_string()));
+        }
+
+        if let Some(ref terminal) = self.terminal {
+            settings.push(("GRUB_TERMINAL", quote(terminal)));
+        }
+
+        settings
+    }
+}
+
+/// Sets the `settings` in the `existing` `/etc/default/grub` of the image. Each replaces
+/// the first assignment of its key, whose later assignments are removed, or else is
+/// appended. Other lines are kept, so setting them again changes nothing.
+pub fn default_grub(existing: &str, settings: &[(&str, String)]) -> String {
+    let mut set = Vec::new();
+    let mut grub = String::with_capacity(existing.len());
+    for line in existing.lines() {
+        match key(line).and_then(|key| settings.iter().find(|&&(name, _)| name == key)) {
+            Some(&(name, _)) if set.contains(&name) => continue,
+            Some(&(name, ref value)) => {
+                set.push(name);
+                grub.push_str(&[name, "=", value].concat());
+            }
+            None => grub.push_str(line),
+        }
+
+        grub.push('\n');
+    }
+
+    for &(name, ref value) in settings.iter().filter(|(name, _)| !set.contains(name)) {
+        grub.push_str(&[name, "=", value, "\n"].concat());
+    }
+
+    grub
+}
+
+/// The setting of `/etc/default/grub` which has `grub-mkconfig` run os-prober if it is to be
+/// `enabled` and is `installed`. It is otherwise disabled, rather than left to the default of
+/// the version of GRUB in the image.
+pub fn os_prober(enable: bool, installed: bool) -> &'static str {
+    if enable && installed {
+        "GRUB_DISABLE_OS_PROBER=false\n"
+    } else {
+        "GRUB_DISABLE_OS_PROBER=true\n"
+    }
+}
+
+/// The variable which a line of shell assigns to.
+fn key(line: &str) -> Option<&str> {
+    let (key, _) = line.trim_start().split_once('=')?;
+    Some(key).filter(|key| key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'))
+}
+
+/// Quotes a value for the shell, which `grub-mkconfig` sources the file with.
+fn quote(value: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in value.chars() {
+        if let '"' | '\\' | '$' | '`' = c {
+            quoted.push('\\');
+        }
+
+        quoted.push(c);
+    }
+
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DEFAULT_GRUB: &str = r#"# If you change this file, run 'update-grub' afterwards to update
+GRUB_DEFAULT=0
+GRUB_TIMEOUT_STYLE=menu
+GRUB_TIMEOUT=5
+GRUB_DISTRIBUTOR=`lsb_release -i -s 2> /dev/null || echo Debian`
+GRUB_CMDLINE_LINUX_DEFAULT="quiet"
+#GRUB_TERMINAL=console
+GRUB_TIMEOUT=10
+"#;
+
+    #[test]
+    fn branding() {
+        let options = GrubOptions {
+            distributor:    Some("Pop!_OS".into()),
+            theme_dir:      Some("/usr/share/pop/grub".into()),
+            background:     None,
+            hidden_timeout: Some(0),
+            terminal:       Some("gfxterm".into()),
+        };
+
+        let theme = Path::new("/boot/grub/themes/pop/theme.txt");
+        let settings = options.settings(Some(theme), None);
+        let grub = default_grub(DEFAULT_GRUB, &settings);
+        assert_eq!(
+            grub,
+            r#"# If you change this file, run 'update-grub' afterwards to update
+GRUB_DEFAULT=0
+GRUB_TIMEOUT_STYLE=hidden
+GRUB_TIMEOUT=0
+GRUB_DISTRIBUTOR="Pop!_OS"
+GRUB_CMDLINE_LINUX_DEFAULT="quiet"
+#GRUB_TERMINAL=console
+GRUB_THEME="/boot/grub/themes/pop/theme.txt"
+GRUB_TERMINAL="gfxterm"
+"#
+        );
+
+        // A refresh sets them again.
+        assert_eq!(default_grub(&grub, &settings), grub);
+        assert_eq!(default_grub(DEFAULT_GRUB, &[]), DEFAULT_GRUB);
+        assert_eq!(quote("a \"$b\""), r#""a \"\$b\"""#);
+    }
+
+    #[test]
+    fn os_prober_setting() {
+        assert_eq!(os_prober(true, true), "GRUB_DISABLE_OS_PROBER=false\n");
+
+        // Disabled by the config, or missing from the install.
+        assert_eq!(os_prober(false, true), "GRUB_DISABLE_OS_PROBER=true\n");
+        assert_eq!(os_prober(true, false), "GRUB_DISABLE_OS_PROBER=true\n");
+        assert_eq!(os_prober(false, false), "GRUB_DISABLE_OS_PROBER=true\n");
+    }
+}
@@ -0,0 +1,93 @@
+/// The size of the pages which `resume_offset` is counted in.
+const PAGE_SIZE: u64 = 4096;
+
+/// The version of btrfs-progs which added `btrfs inspect-internal map-swapfile`.
+pub const BTRFS_MAP_SWAPFILE: (u32, u32) = (6, 1);
+
+/// The kernel parameters which resume from the swap with the given UUID, which is the root
+/// file system when resuming from a swap file at `offset`.
+pub fn options(uuid: &str, offset: Option<u64>) -> String {
+    match offset {
+        Some(offset) => format!("resume=UUID={} resume_offset={}", uuid, offset),
+        None => format!("resume=UUID={}", uuid),
+    }
+}
+
+/// Finds the `resume_offset` of a swap file from the output of `filefrag -v`, which is the
+/// first physical block of the file, counted in pages.
+pub fn swapfile_offset(filefrag: &str) -> Option<u64> {
+    // File size of /swapfile is 2147483648 (524288 blocks of 4096 bytes)
+    let block_size = filefrag
+        .lines()
+        .find(|line| line.starts_with("File size of"))
+        .and_then(|line| line.split(" blocks of ").nth(1))
+        .and_then(|size| size.split_whitespace().next())
+        .and_then(|size| size.parse::<u64>().ok())?;
+
+    //    0:        0..   32767:      34816..     67583:  32768:
+    let physical = filefrag
+        .lines()
+        .find(|line| line.trim_start().starts_with("0:"))
+        .and_then(|line| line.split(':').nth(2))
+        .and_then(|offset| offset.split("..").next())
+        .and_then(|offset| offset.trim().parse::<u64>().ok())?;
+
+    Some(physical * block_size / PAGE_SIZE)
+}
+
+/// Finds the major and minor version of btrfs-progs from the output of `btrfs --version`.
+pub fn btrfs_progs_version(output: &str) -> Option<(u32, u32)> {
+    // btrfs-progs v6.1.3
+    let version = output.lines().next()?.split_whitespace().nth(1)?;
+    let mut numbers = version.trim_start_matches('v').split('.').map(|n| n.parse::<u32>().ok());
+    Some((numbers.next()??, numbers.next().unwrap_or(Some(0))?))
+}
+
+/// Adds the parameters to the kernel command line which GRUB generates, in a file for
+/// `/etc/default/grub.d`.
+pub fn grub(options: &str) -> String {
+    format!("GRUB_CMDLINE_LINUX_DEFAULT=\"$GRUB_CMDLINE_LINUX_DEFAULT {}\"\n", options)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FILEFRAG: &str = "Filesystem type is: ef53
+File size of /swapfile is 2147483648 (524288 blocks of 4096 bytes)
+ ext:     logical_offset:        physical_offset: length:   expected: flags:
+   0:        0..   32767:      34816..     67583:  32768:
+   1:    32768..   63487:      67584..     98303:  30720:
+/swapfile: 2 extents found
+";
+
+    #[test]
+    fn offsets() {
+        assert_eq!(swapfile_offset(FILEFRAG), Some(34816));
+        assert_eq!(
+            swapfile_offset(&FILEFRAG.replace("blocks of 4096", "blocks of 1024")),
+            Some(8704)
+        );
+        assert_eq!(swapfile_offset("/swapfile: 0 extents found\n"), None);
+    }
+
+    #[test]
+    fn btrfs_versions() {
+        assert_eq!(btrfs_progs_version("btrfs-progs v6.1.3\n"), Some((6, 1)));
+        assert_eq!(btrfs_progs_version("btrfs-progs v5.16.2\n-EXPERIMENTAL\n"), Some((5, 16)));
+        assert_eq!(btrfs_progs_version("btrfs-progs v6\n"), Some((6, 0)));
+        assert_eq!(btrfs_progs_version("btrfs: command not found\n"), None);
+        assert!(Some((5, 16)) < Some(BTRFS_MAP_SWAPFILE));
+        assert!(Some((6, 6)) >= Some(BTRFS_MAP_SWAPFILE));
+    }
+
+    #[test]
+    fn parameters() {
+        assert_eq!(options("1234", None), "resume=UUID=1234");
+        assert_eq!(options("1234", Some(34816)), "resume=UUID=1234 resume_offset=34816");
+        assert_eq!(
+            grub("resume=UUID=1234"),
+            "GRUB_CMDLINE_LINUX_DEFAULT=\"$GRUB_CMDLINE_LINUX_DEFAULT resume=UUID=1234\"\n"
+        );
+    }
+}
@@ -0,0 +1,89 @@
+use std::{fs, io, os::unix::fs::symlink, path::Path};
+
+/// Files which are generated again on the first boot, once they are removed.
+const FIRST_BOOT_FILES: &[&str] = &["var/lib/systemd/random-seed"];
+
+/// Removes the identity which the install shares with every other install of the image,
+/// returning whether SSH host keys were removed, and so must be generated again.
+///
+/// The machine ID is truncated rather than removed, so that systemd generates it on the
+/// first boot, and dbus links to it.
+pub fn reset(root: &Path) -> io::Result<bool> {
+    let machine_id = root.join("etc/machine-id");
+    if machine_id.symlink_metadata().is_ok() {
+        fs::remove_file(&machine_id)?;
+    }
+    fs::write(&machine_id, "")?;
+
+    let dbus_dir = root.join("var/lib/dbus");
+    if dbus_dir.is_dir() {
+        let dbus_id = dbus_dir.join("machine-id");
+        if dbus_id.symlink_metadata().is_ok() {
+            fs::remove_file(&dbus_id)?;
+        }
+        symlink("/etc/machine-id", &dbus_id)?;
+    }
+
+    for file in FIRST_BOOT_FILES {
+        remove_if_exists(&root.join(file))?;
+    }
+
+    let mut removed_keys = false;
+    if let Ok(entries) = fs::read_dir(root.join("etc/ssh")) {
+        for entry in entries {
+            let entry = entry?;
+            if entry.file_name().to_string_lossy().starts_with("ssh_host_") {
+                fs::remove_file(entry.path())?;
+                removed_keys = true;
+            }
+        }
+    }
+
+    Ok(removed_keys)
+}
+
+fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(ref why) if why.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn identity() {
+        let root = TempDir::new("distinst").unwrap();
+        let root = root.path();
+        for dir in &["etc/ssh", "var/lib/dbus", "var/lib/systemd"] {
+            fs::create_dir_all(root.join(dir)).unwrap();
+        }
+
+        for file in &[
+            "etc/machine-id",
+            "etc/ssh/ssh_host_ed25519_key",
+            "etc/ssh/ssh_host_ed25519_key.pub",
+            "etc/ssh/sshd_config",
+            "var/lib/systemd/random-seed",
+        ] {
+            fs::write(root.join(file), "image").unwrap();
+        }
+        symlink("/etc/machine-id", root.join("var/lib/dbus/machine-id")).unwrap();
+
+        assert!(reset(root).unwrap());
+        assert_eq!(fs::read_to_string(root.join("etc/machine-id")).unwrap(), "");
+        assert_eq!(
+            fs::read_link(root.join("var/lib/dbus/machine-id")).unwrap(),
+            Path::new("/etc/machine-id")
+        );
+        assert!(!root.join("etc/ssh/ssh_host_ed25519_key").exists());
+        assert!(!root.join("etc/ssh/ssh_host_ed25519_key.pub").exists());
+        assert!(root.join("etc/ssh/sshd_config").exists());
+        assert!(!root.join("var/lib/systemd/random-seed").exists());
+
+        assert!(!reset(root).unwrap());
+    }
+}
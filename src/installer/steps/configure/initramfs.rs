@@ -0,0 +1,378 @@
+use crate::installer::bitflags::FileSystemSupport;
+use std::{fs, io, path::Path};
+
+/// The tool which generates the initramfs of the install.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum InitramfsTool {
+    /// `update-initramfs`, from initramfs-tools, of Debian and Ubuntu.
+    InitramfsTools,
+    /// `dracut`, of Fedora.
+    Dracut,
+    /// `mkinitcpio`, of Arch.
+    Mkinitcpio,
+}
+
+impl InitramfsTool {
+    /// Finds the tool which is installed in the target at `root`, preferring initramfs-tools
+    /// where an image has more than one.
+    pub fn detect(root: &Path) -> Option<Self> {
+        Self::detect_with(|path| Ok(root.join(path).exists())).unwrap_or(None)
+    }
+
+    /// Finds the tool which is installed in an image, where `exists` tells whether the image
+    /// has a file, so that an image may be checked before it is extracted.
+    pub fn detect_with<F>(mut exists: F) -> io::Result<Option<Self>>
+    where
+        F: FnMut(&str) -> io::Result<bool>,
+    {
+        let tools = [Self::InitramfsTools, Self::Dracut, Self::Mkinitcpio];
+        for &tool in &tools {
+            if exists(tool.binary())? {
+                return Ok(Some(tool));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// The path of the tool within the target.
+    pub fn binary(self) -> &'static str {
+        match self {
+            InitramfsTool::InitramfsTools => "usr/sbin/update-initramfs",
+            InitramfsTool::Dracut => "usr/bin/dracut",
+            InitramfsTool::Mkinitcpio => "usr/bin/mkinitcpio",
+        }
+    }
+
+    /// The command which generates the initramfs of every installed kernel, with its
+    /// arguments.
+    pub fn command(self) -> (&'static str, &'static [&'static str]) {
+        match self {
+            InitramfsTool::InitramfsTools => ("update-initramfs", &["-c", "-k", "all"]),
+            InitramfsTool::Dracut => ("dracut", &["--force", "--regenerate-all"]),
+            InitramfsTool::Mkinitcpio => ("mkinitcpio", &["-P"]),
+        }
+    }
+
+    /// Files in the target which the initramfs must be built with, for the root file system
+    /// to be found at boot.
+    pub fn required_files(self, support: FileSystemSupport) -> Vec<&'static str> {
+        let (cryptsetup, lvm) = match self {
+            InitramfsTool::InitramfsTools => (
+                "usr/share/initramfs-tools/hooks/cryptroot",
+                "usr/share/initramfs-tools/hooks/lvm2",
+            ),
+            InitramfsTool::Dracut => {
+                ("usr/lib/dracut/modules.d/90crypt", "usr/lib/dracut/modules.d/90lvm")
+            }
+            InitramfsTool::Mkinitcpio => {
+                ("usr/lib/initcpio/install/encrypt", "usr/lib/initcpio/install/lvm2")
+            }
+        };
+
+        let mut files = Vec::new();
+
+        if support.contains(FileSystemSupport::LUKS) {
+            files.push(cryptsetup);
+        }
+
+        if support.intersects(FileSystemSupport::LVM | FileSystemSupport::LVM_CACHE) {
+            files.push(lvm);
+        }
+
+        // Cached logical volumes can't be activated at boot without LVM's initramfs hook.
+        if support.contains(FileSystemSupport::LVM_CACHE) {
+            files.push("usr/sbin/cache_check");
+        }
+
+        files
+    }
+
+    /// Generates the files, relative to `root`, which have the initramfs unlock the devices
+    /// of `/etc/crypttab`, activate logical volumes, and resume from the swap with the UUID
+    /// of `resume`. The existing configuration of the target is retained.
+    pub fn config_files(
+        self,
+        root: &Path,
+        support: FileSystemSupport,
+        resume: Option<&str>,
+    ) -> io::Result<Vec<(&'static str, String)>> {
+        let luks = support.contains(FileSystemSupport::LUKS);
+        let lvm = support.intersects(FileSystemSupport::LVM | FileSystemSupport::LVM_CACHE);
+        let mut files = Vec::new();
+
+        match self {
+            InitramfsTool::InitramfsTools => {
+                if luks {
+                    let path = "etc/cryptsetup-initramfs/conf-hook";
+                    files.push((path, enable_cryptsetup(&read_existing(root, path)?)));
+                }
+
+                if let Some(uuid) = resume {
+                    files.push((
+                        "etc/initramfs-tools/conf.d/resume",
+                        format!("RESUME=UUID={}\n", uuid),
+                    ));
+                }
+            }
+            InitramfsTool::Dracut => {
+                let modules = [(luks, "crypt"), (lvm, "lvm"), (resume.is_some(), "resume")]
+                    .iter()
+                    .filter(|&&(enabled, _)| enabled)
+                    .map(|&(_, module)| module)
+                    .collect::<Vec<_>>();
+
+                if !modules.is_empty() {
+                    let mut conf = format!("add_dracutmodules+=\" {} \"\n", modules.join(" "));
+                    if luks {
+                        conf.push_str("install_items+=\" /etc/crypttab \"\n");
+                    }
+
+                    files.push(("etc/dracut.conf.d/90-distinst.conf", conf));
+                }
+            }
+            InitramfsTool::Mkinitcpio => {
+                let path = "etc/mkinitcpio.conf";
+                let conf = read_existing(root, path)?;
+                let systemd = hooks(&conf).map_or(false, |hooks| hooks.contains(&"systemd"));
+
+                // The hooks of a systemd initramfs read `/etc/crypttab.initramfs`, and resume
+                // without a hook of their own.
+                let hooks = [
+                    (luks && systemd, "sd-encrypt"),
+                    (luks && !systemd, "encrypt"),
+                    (lvm, "lvm2"),
+                    (resume.is_some() && !systemd, "resume"),
+                ]
+                .iter()
+                .filter(|&&(enabled, _)| enabled)
+                .map(|&(_, hook)| hook)
+                .collect::<Vec<_>>();
+
+                if !hooks.is_empty() {
+                    files.push((path, add_hooks(&conf, &hooks)));
+                }
+
+                if luks && systemd {
+                    files.push(("etc/crypttab.initramfs", read_existing(root, "etc/crypttab")?));
+                }
+            }
+        }
+
+        Ok(files)
+    }
+}
+
+fn read_existing(root: &Path, path: &str) -> io::Result<String> {
+    match fs::read_to_string(root.join(path)) {
+        Ok(contents) => Ok(contents),
+        Err(ref why) if why.kind() == io::ErrorKind::NotFound => Ok(String::new()),
+        Err(why) => Err(why),
+    }
+}
+
+/// Sets `CRYPTSETUP=y` in `/etc/cryptsetup-initramfs/conf-hook`, so that the cryptsetup hook
+/// includes its binaries and the entries of `/etc/crypttab` even when it cannot find the
+/// root device from within the chroot.
+pub fn enable_cryptsetup(conf_hook: &str) -> String {
+    let mut lines = conf_hook
+        .lines()
+        .filter(|line| !line.trim_start_matches('#').trim_start().starts_with("CRYPTSETUP="))
+        .map(String::from)
+        .collect::<Vec<String>>();
+
+    lines.push("CRYPTSETUP=y".into());
+
+    let mut conf_hook = lines.join("\n");
+    conf_hook.push('\n');
+    conf_hook
+}
+
+/// The hooks of the `HOOKS=(...)` line of `mkinitcpio.conf`.
+fn hooks(conf: &str) -> Option<Vec<&str>> {
+    conf.lines()
+        .filter_map(|line| line.strip_prefix("HOOKS=("))
+        .last()
+        .map(|hooks| hooks.trim_end().trim_end_matches(')').split_whitespace().collect())
+}
+
+/// Adds the hooks which are missing from `mkinitcpio.conf` before the `filesystems` hook,
+/// which mounts the root, or sets the hooks to the defaults of Arch along with them.
+fn add_hooks(conf: &str, required: &[&str]) -> String {
+    let mut hooks = hooks(conf).unwrap_or_else(|| {
+        vec![
+            "base",
+            "udev",
+            "autodetect",
+            "modconf",
+            "keyboard",
+            "keymap",
+            "block",
+            "filesystems",
+            "fsck",
+        ]
+    });
+
+    for hook in required {
+        if !hooks.contains(hook) {
+            let position = hooks.iter().position(|&hook| hook == "filesystems");
+            hooks.insert(position.unwrap_or(hooks.len()), hook);
+        }
+    }
+
+    let line = format!("HOOKS=({})", hooks.join(" "));
+    let mut replaced = false;
+    let mut lines = conf
+        .lines()
+        .map(|existing| {
+            if existing.starts_with("HOOKS=(") {
+                replaced = true;
+                line.clone()
+            } else {
+                existing.to_owned()
+            }
+        })
+        .collect::<Vec<String>>();
+
+    if !replaced {
+        lines.push(line);
+    }
+
+    let mut conf = lines.join("\n");
+    conf.push('\n');
+    conf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    /// Creates the skeleton of an image with the tool, and with the given files.
+    fn skeleton(tool: InitramfsTool, files: &[(&str, &str)]) -> TempDir {
+        let root = TempDir::new("distinst").unwrap();
+        for &(path, contents) in [(tool.binary(), "")].iter().chain(files) {
+            let path = root.path().join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        }
+
+        root
+    }
+
+    const CRYPTTAB: &str = "cryptdata UUID=1234 none luks\n";
+
+    #[test]
+    fn requirements() {
+        let tool = InitramfsTool::InitramfsTools;
+        assert!(tool.required_files(FileSystemSupport::EXT4).is_empty());
+        assert_eq!(
+            tool.required_files(FileSystemSupport::LUKS | FileSystemSupport::LVM),
+            vec![
+                "usr/share/initramfs-tools/hooks/cryptroot",
+                "usr/share/initramfs-tools/hooks/lvm2"
+            ]
+        );
+        assert_eq!(
+            tool.required_files(FileSystemSupport::LVM_CACHE),
+            vec!["usr/share/initramfs-tools/hooks/lvm2", "usr/sbin/cache_check"]
+        );
+        assert_eq!(
+            InitramfsTool::Dracut.required_files(FileSystemSupport::LUKS),
+            vec!["usr/lib/dracut/modules.d/90crypt"]
+        );
+    }
+
+    #[test]
+    fn conf_hook() {
+        let conf_hook = "#\n# CRYPTSETUP: [ y | n ]\n#\n\n#CRYPTSETUP=\n\n#KEYFILE_PATTERN=\n";
+        assert_eq!(
+            enable_cryptsetup(conf_hook),
+            "#\n# CRYPTSETUP: [ y | n ]\n#\n\n\n#KEYFILE_PATTERN=\nCRYPTSETUP=y\n"
+        );
+        assert_eq!(enable_cryptsetup(""), "CRYPTSETUP=y\n");
+    }
+
+    #[test]
+    fn initramfs_tools() {
+        let tool = InitramfsTool::InitramfsTools;
+        let root = skeleton(tool, &[("etc/cryptsetup-initramfs/conf-hook", "#CRYPTSETUP=\n")]);
+        assert_eq!(InitramfsTool::detect(root.path()), Some(tool));
+        assert_eq!(tool.command(), ("update-initramfs", &["-c", "-k", "all"][..]));
+
+        let support = FileSystemSupport::LUKS | FileSystemSupport::LVM;
+        assert_eq!(
+            tool.config_files(root.path(), support, Some("5678")).unwrap(),
+            vec![
+                ("etc/cryptsetup-initramfs/conf-hook", "CRYPTSETUP=y\n".into()),
+                ("etc/initramfs-tools/conf.d/resume", "RESUME=UUID=5678\n".into()),
+            ]
+        );
+        assert!(tool.config_files(root.path(), FileSystemSupport::EXT4, None).unwrap().is_empty());
+    }
+
+    #[test]
+    fn dracut() {
+        let tool = InitramfsTool::Dracut;
+        let root = skeleton(tool, &[]);
+        assert_eq!(InitramfsTool::detect(root.path()), Some(tool));
+        assert_eq!(tool.command(), ("dracut", &["--force", "--regenerate-all"][..]));
+
+        let support = FileSystemSupport::LUKS | FileSystemSupport::LVM;
+        assert_eq!(
+            tool.config_files(root.path(), support, Some("5678")).unwrap(),
+            vec![(
+                "etc/dracut.conf.d/90-distinst.conf",
+                "add_dracutmodules+=\" crypt lvm resume \"\ninstall_items+=\" /etc/crypttab \"\n"
+                    .into()
+            )]
+        );
+        assert!(tool.config_files(root.path(), FileSystemSupport::EXT4, None).unwrap().is_empty());
+    }
+
+    #[test]
+    fn mkinitcpio() {
+        let tool = InitramfsTool::Mkinitcpio;
+        let conf = "MODULES=()\n#HOOKS=(base)\nHOOKS=(base udev autodetect modconf block \
+                    filesystems fsck)\n";
+        let root = skeleton(tool, &[("etc/mkinitcpio.conf", conf), ("etc/crypttab", CRYPTTAB)]);
+        assert_eq!(InitramfsTool::detect(root.path()), Some(tool));
+        assert_eq!(tool.command(), ("mkinitcpio", &["-P"][..]));
+
+        let support = FileSystemSupport::LUKS | FileSystemSupport::LVM;
+        assert_eq!(
+            tool.config_files(root.path(), support, Some("5678")).unwrap(),
+            vec![(
+                "etc/mkinitcpio.conf",
+                "MODULES=()\n#HOOKS=(base)\nHOOKS=(base udev autodetect modconf block encrypt \
+                 lvm2 resume filesystems fsck)\n"
+                    .into()
+            )]
+        );
+    }
+
+    #[test]
+    fn mkinitcpio_systemd() {
+        let tool = InitramfsTool::Mkinitcpio;
+        let conf = "HOOKS=(base systemd autodetect modconf block filesystems fsck)\n";
+        let root = skeleton(tool, &[("etc/mkinitcpio.conf", conf), ("etc/crypttab", CRYPTTAB)]);
+
+        assert_eq!(
+            tool.config_files(root.path(), FileSystemSupport::LUKS, Some("5678")).unwrap(),
+            vec![
+                (
+                    "etc/mkinitcpio.conf",
+                    "HOOKS=(base systemd autodetect modconf block sd-encrypt filesystems fsck)\n"
+                        .into()
+                ),
+                ("etc/crypttab.initramfs", CRYPTTAB.into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn undetected() {
+        let root = TempDir::new("distinst").unwrap();
+        assert_eq!(InitramfsTool::detect(root.path()), None);
+    }
+}
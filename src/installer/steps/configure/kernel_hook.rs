@@ -0,0 +1,369 @@
+//! The hook which keeps the copies of the kernels and device trees that distinst wrote for a
+//! bootloader which no package of the install manages, and the configs which boot them, up
+//! to date as the kernels of the install are installed, updated, and removed.
+
+use super::{
+    board,
+    systemd_boot::{self, Kernel},
+};
+
+/// Where the hook is installed in the install. The kernel packages of Debian and Ubuntu run
+/// the hooks of `/etc/kernel` after the initramfs of a kernel is generated, and after a
+/// kernel is removed, `update-initramfs` runs those of `/etc/initramfs` after it updates an
+/// initramfs, and `kernel-install` runs those of `/etc/kernel/install.d` on Fedora.
+pub const PATHS: &[&str] = &[
+    "etc/kernel/postinst.d/zz-distinst",
+    "etc/kernel/postrm.d/zz-distinst",
+    "etc/initramfs/post-update.d/zz-distinst",
+    "etc/kernel/install.d/99-distinst.install",
+];
+
+/// The hook of pacman which runs the hook on Arch, after mkinitcpio has generated the
+/// initramfs of the kernels.
+pub const PACMAN_HOOK: &str = "etc/pacman.d/hooks/zz-distinst.hook";
+
+/// The hook of pacman, which runs after those of the kernel packages.
+pub const PACMAN_HOOK_CONF: &str = r#"# Written by distinst
+[Trigger]
+Type = Path
+Operation = Install
+Operation = Upgrade
+Operation = Remove
+Target = usr/lib/modules/*/vmlinuz
+Target = boot/*
+
+[Action]
+Description = Updating the boot files of the kernels...
+When = PostTransaction
+Exec = /etc/kernel/postinst.d/zz-distinst
+"#;
+
+/// The hook of systemd-boot, which copies each kernel in `/boot` of the install at `root`
+/// to `/EFI/{name}` of the ESP, writes its entry with the `title` and `options`, and then
+/// removes the copies and entries of the kernels which are gone. If the default entry of
+/// `loader.conf` is that of the kernel at `position`, counting from the newest, the default
+/// follows that position.
+pub fn systemd_boot(
+    root: &str,
+    name: &str,
+    title: &str,
+    options: &str,
+    position: Option<usize>,
+) -> String {
+    let entry = systemd_boot::entry("${name}", &escape(title), &placeholder(), &escape(options));
+
+    let mut hook = prelude(root, "the kernels and entries of systemd-boot on the ESP");
+    hook.push_str(&format!(
+        r#"name={}
+esp="$root/boot/efi"
+mkdir -p "$esp/EFI/$name" "$esp/loader/entries"
+kernels | while read -r version kernel initrd; do
+    cp "$boot/$kernel" "$boot/$initrd" "$esp/EFI/$name/"
+    cat > "$esp/loader/entries/$name-$version.conf" <<EOF
+{}EOF
+done
+
+versions=$(kernels | cut -d ' ' -f 1)
+for entry in "$esp/loader/entries/$name-"*.conf; do
+    version=${{entry#"$esp/loader/entries/$name-"}}
+    version=${{version%.conf}}
+    if ! echo "$versions" | grep -qxF "$version"; then
+        rm -f "$entry" "$esp/EFI/$name/vmlinuz-$version" \
+            "$esp/EFI/$name/initrd.img-$version" "$esp/EFI/$name/initramfs-$version.img"
+    fi
+done
+"#,
+        quote(name),
+        entry
+    ));
+
+    if let Some(position) = position {
+        hook.push_str(&format!(
+            r#"
+default=$(kernels | sed -n '{}p' | cut -d ' ' -f 1)
+if [ -n "$default" ] && [ -f "$esp/loader/loader.conf" ]; then
+    awk -v default="default $name-$default" \
+        '$1 == "default" {{ print default; next }} {{ print }}' \
+        "$esp/loader/loader.conf" > "$esp/loader/loader.conf.new"
+    mv "$esp/loader/loader.conf.new" "$esp/loader/loader.conf"
+fi
+"#,
+            position
+        ));
+    }
+
+    hook
+}
+
+/// The hook of the firmware of the Raspberry Pi, which copies the `firmware` of the install
+/// at `root`, and the device trees of the newest kernel, to `/boot`, and has `config.txt`
+/// boot that kernel.
+pub fn raspberry_pi(root: &str, firmware: &str, arm_64bit: bool) -> String {
+    let config = board::config_txt(&placeholder(), arm_64bit);
+
+    let mut hook = prelude(root, "the firmware, device trees, and config.txt in /boot");
+    hook.push_str(&format!(
+        r#"firmware={}
+set -- $(kernels | head -n 1)
+version=$1 kernel=$2 initrd=$3
+cp -r "$root/$firmware/." "$boot/"
+for dtbs in {}; do
+    if [ -d "$dtbs" ]; then
+        if [ -d "$dtbs/broadcom" ]; then
+            cp -r "$dtbs/broadcom/." "$boot/"
+        else
+            cp -r "$dtbs/." "$boot/"
+        fi
+
+        if [ -d "$dtbs/overlays" ]; then
+            mkdir -p "$boot/overlays"
+            cp -r "$dtbs/overlays/." "$boot/overlays/"
+        fi
+
+        break
+    fi
+done
+
+cat > "$boot/config.txt" <<EOF
+{}EOF
+"#,
+        quote(firmware),
+        dtb_dirs(),
+        config
+    ));
+
+    hook
+}
+
+/// The hook of U-Boot, which copies the device trees of each kernel in `/boot` of the
+/// install at `root` to `/boot/dtbs/{version}`, and writes `extlinux.conf` with a label for
+/// each kernel, with the `title` and `options`.
+pub fn extlinux(root: &str, title: &str, options: &str) -> String {
+    let (title, options) = (escape(title), escape(options));
+    let header = board::extlinux_header(&title, Some("${default}"));
+    let kernel = placeholder();
+    let with_dtbs = board::extlinux_label(&title, &kernel, true, &options);
+    let without_dtbs = board::extlinux_label(&title, &kernel, false, &options);
+
+    let mut hook = prelude(root, "the device trees and extlinux.conf in /boot");
+    hook.push_str(&format!(
+        r#"default=$(kernels | head -n 1 | cut -d ' ' -f 1)
+mkdir -p "$boot/extlinux"
+{{
+    cat <<EOF
+{}EOF
+    kernels | while read -r version kernel initrd; do
+        dtbs=
+        for dir in {}; do
+            if [ -d "$dir" ]; then
+                dtbs=$dir
+                break
+            fi
+        done
+
+        if [ -z "$dtbs" ]; then
+            cat <<EOF
+{}EOF
+            continue
+        fi
+
+        if [ "$dtbs" != "$boot/dtbs/$version" ]; then
+            mkdir -p "$boot/dtbs/$version"
+            cp -r "$dtbs/." "$boot/dtbs/$version/"
+        fi
+
+        cat <<EOF
+{}EOF
+    done
+}} > "$boot/extlinux/extlinux.conf.new"
+mv "$boot/extlinux/extlinux.conf.new" "$boot/extlinux/extlinux.conf"
+"#,
+        header,
+        dtb_dirs(),
+        without_dtbs,
+        with_dtbs
+    ));
+
+    hook
+}
+
+/// The directories which `board::dtb_dir` searches for the device trees of the kernel
+/// `$version`, as words of the hook.
+fn dtb_dirs() -> String {
+    let dirs = board::dtb_dirs("${version}");
+    dirs.iter().map(|dir| ["\"$root/", dir, "\""].concat()).collect::<Vec<_>>().join(" ")
+}
+
+/// The start of each hook, which does nothing if the install at `root` has no kernels, and
+/// defines `kernels`, which lists the version, kernel, and initrd of each kernel in `/boot`,
+/// with the newest first, as `systemd_boot::kernels` finds them.
+fn prelude(root: &str, updates: &str) -> String {
+    format!(
+        r#"#!/bin/sh
+# Written by distinst, to update {} as kernels are installed and removed.
+set -e
+root={}
+boot="$root/boot"
+
+kernels() {{
+    for kernel in "$boot"/vmlinuz-*; do
+        version=${{kernel##*/vmlinuz-}}
+        if [ -e "$boot/initrd.img-$version" ]; then
+            echo "$version vmlinuz-$version initrd.img-$version"
+        elif [ -e "$boot/initramfs-$version.img" ]; then
+            echo "$version vmlinuz-$version initramfs-$version.img"
+        fi
+    done | sort -rV
+}}
+
+if [ -z "$(kernels)" ]; then
+    exit 0
+fi
+
+"#,
+        updates,
+        quote(root)
+    )
+}
+
+/// A kernel whose fields are the variables of the hook, for a template of a here-document.
+fn placeholder() -> Kernel {
+    Kernel {
+        version: "${version}".into(),
+        kernel:  "${kernel}".into(),
+        initrd:  "${initrd}".into(),
+    }
+}
+
+/// Quotes the `value` as a word of the shell.
+fn quote(value: &str) -> String { ["'", &value.replace('\'', r#"'\''"#), "'"].concat() }
+
+/// Escapes the `value` for a here-document, which expands variables and commands.
+fn escape(value: &str) -> String {
+    value.replace('\\', r"\\").replace('$', r"\$").replace('`', r"\`")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fs, path::Path, process::Command};
+    use tempdir::TempDir;
+
+    fn run(hook: &str) {
+        let status = Command::new("sh").args(&["-c", hook]).status().unwrap();
+        assert!(status.success());
+    }
+
+    fn touch(root: &Path, files: &[&str]) {
+        for file in files {
+            let path = root.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, file).unwrap();
+        }
+    }
+
+    #[test]
+    fn quoting() {
+        assert_eq!(quote("Pop!_OS"), "'Pop!_OS'");
+        assert_eq!(quote("it's"), r#"'it'\''s'"#);
+        assert_eq!(escape(r"a $HOME `b` \c"), r"a \$HOME \`b\` \\c");
+    }
+
+    #[test]
+    fn systemd_boot_entries() {
+        let dir = TempDir::new("distinst").unwrap();
+        let root = dir.path();
+        touch(
+            root,
+            &[
+                "boot/vmlinuz-6.2.0-39-generic",
+                "boot/initrd.img-6.2.0-39-generic",
+                "boot/vmlinuz-6.5.0-14-generic",
+                "boot/initrd.img-6.5.0-14-generic",
+                "boot/efi/EFI/Ubuntu/vmlinuz-6.1.0-1-generic",
+                "boot/efi/EFI/Ubuntu/initrd.img-6.1.0-1-generic",
+                "boot/efi/loader/entries/Ubuntu-6.1.0-1-generic.conf",
+                "boot/efi/loader/entries/Recovery-ABCD.conf",
+            ],
+        );
+        let loader = root.join("boot/efi/loader/loader.conf");
+        fs::write(&loader, "# Written by distinst\ndefault Ubuntu-6.1.0-1-generic\ntimeout 3\n")
+            .unwrap();
+
+        let options = "root=UUID=1234 ro quiet $HOME";
+        let root_path = root.to_str().unwrap();
+        run(&systemd_boot(root_path, "Ubuntu", "Ubuntu 23.10", options, Some(1)));
+
+        let esp = root.join("boot/efi");
+        let entries = esp.join("loader/entries");
+        assert!(!entries.join("Ubuntu-6.1.0-1-generic.conf").exists());
+        assert!(!esp.join("EFI/Ubuntu/vmlinuz-6.1.0-1-generic").exists());
+        assert!(entries.join("Recovery-ABCD.conf").exists());
+        assert_eq!(
+            fs::read_to_string(esp.join("EFI/Ubuntu/initrd.img-6.5.0-14-generic")).unwrap(),
+            "boot/initrd.img-6.5.0-14-generic"
+        );
+
+        let kernel = Kernel {
+            version: "6.2.0-39-generic".into(),
+            kernel:  "vmlinuz-6.2.0-39-generic".into(),
+            initrd:  "initrd.img-6.2.0-39-generic".into(),
+        };
+        assert_eq!(
+            fs::read_to_string(entries.join("Ubuntu-6.2.0-39-generic.conf")).unwrap(),
+            systemd_boot::entry("Ubuntu", "Ubuntu 23.10", &kernel, options)
+        );
+
+        assert_eq!(
+            fs::read_to_string(&loader).unwrap(),
+            "# Written by distinst\ndefault Ubuntu-6.5.0-14-generic\ntimeout 3\n"
+        );
+    }
+
+    fn kernel(version: &str) -> Kernel {
+        Kernel {
+            version: version.into(),
+            kernel:  ["vmlinuz-", version].concat(),
+            initrd:  ["initrd.img-", version].concat(),
+        }
+    }
+
+    #[test]
+    fn board_files() {
+        let dir = TempDir::new("distinst").unwrap();
+        let root = dir.path();
+        touch(
+            root,
+            &[
+                "boot/vmlinuz-6.1.0-17-arm64",
+                "boot/initrd.img-6.1.0-17-arm64",
+                "boot/vmlinuz-6.1.0-18-arm64",
+                "boot/initrd.img-6.1.0-18-arm64",
+                "usr/lib/linux-image-6.1.0-18-arm64/broadcom/bcm2711-rpi-4-b.dtb",
+                "usr/lib/linux-image-6.1.0-18-arm64/overlays/vc4-kms-v3d.dtbo",
+                "usr/lib/raspi-firmware/start4.elf",
+            ],
+        );
+
+        let options = "root=UUID=1234 ro $HOME";
+        let root_path = root.to_str().unwrap();
+        run(&extlinux(root_path, "Debian 12", options));
+
+        let boot = root.join("boot");
+        assert!(boot.join("dtbs/6.1.0-18-arm64/broadcom/bcm2711-rpi-4-b.dtb").exists());
+        let (new, old) = (kernel("6.1.0-18-arm64"), kernel("6.1.0-17-arm64"));
+        assert_eq!(
+            fs::read_to_string(boot.join("extlinux/extlinux.conf")).unwrap(),
+            board::extlinux_conf("Debian 12", &[(&new, true), (&old, false)], options)
+        );
+
+        run(&raspberry_pi(root_path, "usr/lib/raspi-firmware", true));
+        assert!(boot.join("start4.elf").exists());
+        assert!(boot.join("bcm2711-rpi-4-b.dtb").exists());
+        assert!(boot.join("overlays/vc4-kms-v3d.dtbo").exists());
+        assert_eq!(
+            fs::read_to_string(boot.join("config.txt")).unwrap(),
+            board::config_txt(&new, true)
+        );
+    }
+}
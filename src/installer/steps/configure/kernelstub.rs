@@ -0,0 +1,171 @@
+use partition_identity::{PartitionID, PartitionSource};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// The revision of the configuration of kernelstub which is written.
+const CONFIG_REV: u32 = 3;
+
+/// The path of a device mapper device by its name, such as `/dev/mapper/data-root` rather
+/// than `/dev/dm-2`, which may be numbered differently on the next boot.
+pub fn mapper_path(device: &Path) -> Option<PathBuf> {
+    let device = device.canonicalize().ok()?;
+    let name = Path::new("/sys/class/block").join(device.file_name()?).join("dm/name");
+    let name = fs::read_to_string(name).ok()?;
+    Some(Path::new("/dev/mapper").join(name.trim()))
+}
+
+/// The name which the LUKS device with the UUID is unlocked as in the `crypttab`.
+pub fn crypt_name<'a>(crypttab: &'a str, uuid: &str) -> Option<&'a str> {
+    let source = ["UUID=", uuid].concat();
+    crypttab.lines().find_map(|line| {
+        let mut fields = line.split_whitespace();
+        let name = fields.next()?;
+        if fields.next()? == source {
+            Some(name)
+        } else {
+            None
+        }
+    })
+}
+
+/// The kernel parameters which find the root file system: by the device mapper path of a
+/// logical or encrypted root, or else by the `root` ID that the fstab identifies it with, and
+/// the LUKS device which it is unlocked from as the `cryptdevice`, which is only given for
+/// mkinitcpio.
+pub fn root_options(
+    mapper: Option<&Path>,
+    root: &PartitionID,
+    crypt: Option<(&str, &str)>,
+) -> String {
+    let mut options = match (mapper, root.variant) {
+        (Some(path), _) => ["root=", &path.to_string_lossy()].concat(),
+        (None, PartitionSource::PartUUID) => ["root=PARTUUID=", &root.id].concat(),
+        (None, PartitionSource::Label) => ["root=LABEL=", &root.id].concat(),
+        (None, PartitionSource::Path) => ["root=", &root.id].concat(),
+        (None, _) => ["root=UUID=", &root.id].concat(),
+    };
+
+    if let Some((luks_uuid, name)) = crypt {
+        options.push(' ');
+        options.push_str(&crypt_option(luks_uuid, name));
+    }
+
+    options
+}
+
+/// The `cryptdevice` parameter of mkinitcpio's `encrypt` hook, which unlocks the LUKS device
+/// with the UUID as `name`.
+pub fn crypt_option(luks_uuid: &str, name: &str) -> String {
+    ["cryptdevice=UUID=", luks_uuid, ":", name].concat()
+}
+
+/// Adds the `cryptdevice` to `GRUB_CMDLINE_LINUX`, in a file for `/etc/default/grub.d`. GRUB
+/// leaves `GRUB_CMDLINE_LINUX_DEFAULT` out of its recovery entries, which must also unlock
+/// the root.
+pub fn grub_crypt_option(option: &str) -> String {
+    format!("GRUB_CMDLINE_LINUX=\"$GRUB_CMDLINE_LINUX {}\"\n", option)
+}
+
+/// The `/etc/kernelstub/configuration` of the install, which has kernelstub manage the
+/// systemd-boot entry of the ESP at `esp`, with the `options` replacing the `defaults`.
+pub fn configuration(esp: &str, defaults: &[&str], options: &[&str]) -> String {
+    let section = |options: &[&str], user: bool| {
+        let options = options.iter().map(|option| json_string(option)).collect::<Vec<_>>();
+        format!(
+            r#"{{
+    "kernel_options": [{}],
+    "esp_path": {},
+    "setup_loader": {},
+    "manage_mode": {},
+    "force_update": {},
+    "live_mode": false,
+    "config_rev": {}
+  }}"#,
+            options.join(", "),
+            json_string(esp),
+            user,
+            user,
+            user,
+            CONFIG_REV
+        )
+    };
+
+    format!(
+        "{{\n  \"default\": {},\n  \"user\": {}\n}}\n",
+        section(defaults, false),
+        section(options, true)
+    )
+}
+
+fn json_string(value: &str) -> String {
+    let mut string = String::with_capacity(value.len() + 2);
+    string.push('"');
+    for c in value.chars() {
+        match c {
+            '"' | '\\' => {
+                string.push('\\');
+                string.push(c);
+            }
+            c if c.is_control() => string.push_str(&format!("\\u{:04x}", c as u32)),
+            c => string.push(c),
+        }
+    }
+
+    string.push('"');
+    string
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{super::systemd_boot, *};
+
+    #[test]
+    fn encrypted_lvm() {
+        let crypttab = "cryptswap UUID=5678 /dev/urandom swap\ncryptdata UUID=abcd none luks\n";
+        let name = crypt_name(crypttab, "abcd");
+        assert_eq!(name, Some("cryptdata"));
+        assert_eq!(crypt_name(crypttab, "1234"), None);
+
+        let id = |variant, id: &str| PartitionID { variant, id: id.into() };
+        let uuid = id(PartitionSource::UUID, "1234");
+        let mapper = Path::new("/dev/mapper/data-root");
+        let root = root_options(Some(mapper), &uuid, name.map(|name| ("abcd", name)));
+        assert_eq!(root, "root=/dev/mapper/data-root cryptdevice=UUID=abcd:cryptdata");
+        assert_eq!(root_options(None, &uuid, None), "root=UUID=1234");
+        assert_eq!(
+            grub_crypt_option(&crypt_option("abcd", "cryptdata")),
+            "GRUB_CMDLINE_LINUX=\"$GRUB_CMDLINE_LINUX cryptdevice=UUID=abcd:cryptdata\"\n"
+        );
+
+        // The root is found as the fstab identifies it.
+        let partuuid = id(PartitionSource::PartUUID, "e0f9a1c2-02");
+        assert_eq!(root_options(None, &partuuid, None), "root=PARTUUID=e0f9a1c2-02");
+        let label = id(PartitionSource::Label, "Pop_OS");
+        assert_eq!(root_options(None, &label, None), "root=LABEL=Pop_OS");
+
+        // kernelstub and the entries of systemd-boot boot the same root.
+        let options = root.split_whitespace().chain(vec!["quiet", "resume=UUID=5678"]);
+        let config = configuration("/boot/efi", &["quiet", "splash"], &options.collect::<Vec<_>>());
+        let root_config = r#"["root=/dev/mapper/data-root", "cryptdevice=UUID=abcd:cryptdata", "#;
+        assert!(config.contains(&[r#""kernel_options": "#, root_config].concat()));
+        assert!(config.contains(r#""kernel_options": ["quiet", "splash"]"#));
+        assert!(config.contains(r#""esp_path": "/boot/efi""#));
+
+        let kernel = systemd_boot::Kernel {
+            version: "6.2.0-39-generic".into(),
+            kernel:  "vmlinuz-6.2.0-39-generic".into(),
+            initrd:  "initrd.img-6.2.0-39-generic".into(),
+        };
+        let options = [&root, " ro quiet resume=UUID=5678"].concat();
+        let entry = systemd_boot::entry("Pop_OS", "Pop!_OS", &kernel, &options);
+        assert!(entry.contains(&["\noptions ", &root, " ro quiet"].concat()));
+    }
+
+    #[test]
+    fn json_strings() {
+        assert_eq!(json_string(r#"a"b\c"#), r#""a\"b\\c""#);
+        assert_eq!(json_string("tab\t"), r#""tab\u0009""#);
+    }
+}
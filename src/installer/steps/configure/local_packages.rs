@@ -0,0 +1,56 @@
+use crate::errors::IoContext;
+use std::{fs, io, path::Path};
+
+/// The `.deb` files of the directory of local packages, in order.
+pub fn debs(dir: &Path) -> io::Result<Vec<String>> {
+    let mut debs = fs::read_dir(dir)
+        .with_context(|err| format!("failed to read local packages at {:?}: {}", dir, err))?
+        .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+        .filter(|name| name.ends_with(".deb"))
+        .collect::<Vec<String>>();
+
+    debs.sort();
+    Ok(debs)
+}
+
+/// The name of the package of a `.deb`, which is named `name_version_arch.deb`.
+pub fn package(deb: &str) -> &str { &deb[..deb.find('_').unwrap_or(deb.len())] }
+
+/// The packages of the `debs` which are not `installed`, after apt failed to install them.
+pub fn failed<'a>(debs: &'a [String], installed: &[String]) -> Vec<&'a str> {
+    debs.iter()
+        .map(|deb| package(deb))
+        .filter(|name| !installed.iter().any(|package| package == name))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn debs_of_directory() {
+        let dir = TempDir::new("distinst").unwrap();
+        assert!(debs(dir.path()).unwrap().is_empty());
+
+        for file in &["zstd_1.4.8_amd64.deb", "Packages.gz", "hello_2.10-2_amd64.deb", "deb"] {
+            fs::write(dir.path().join(file), "").unwrap();
+        }
+
+        let found = debs(dir.path()).unwrap();
+        assert_eq!(found, vec!["hello_2.10-2_amd64.deb", "zstd_1.4.8_amd64.deb"]);
+        assert!(debs(&dir.path().join("pool")).is_err());
+    }
+
+    #[test]
+    fn packages_of_debs() {
+        assert_eq!(package("hello_2.10-2_amd64.deb"), "hello");
+        assert_eq!(package("libc6-dev_2.35-0ubuntu3_amd64.deb"), "libc6-dev");
+
+        // Only the packages which apt did not install are reported.
+        let debs = vec!["hello_2.10-2_amd64.deb".to_owned(), "zstd_1.4.8_amd64.deb".to_owned()];
+        assert_eq!(failed(&debs, &["hello".into(), "zstd-dev".into()]), vec!["zstd"]);
+        assert!(failed(&debs, &["hello".into(), "zstd".into()]).is_empty());
+    }
+}
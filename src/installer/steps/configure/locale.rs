@@ -0,0 +1,88 @@
+/// The locale which is used when the requested locales could not be generated.
+pub const FALLBACK: &str = "C.UTF-8";
+
+/// Whether the locale is built into the C library, and so need not be generated.
+pub fn is_builtin(locale: &str) -> bool {
+    match locale {
+        "C" | "POSIX" | "C.UTF-8" | "C.utf8" => true,
+        _ => false,
+    }
+}
+
+/// Finds the `/etc/locale.gen` entry of the locale, such as `cs_CZ.UTF-8 UTF-8`, from the
+/// `SUPPORTED` list of the C library. Without the list, the charset is taken from the name.
+pub fn entry(locale: &str, supported: Option<&str>) -> Option<String> {
+    let locale = normalize(locale);
+    match supported {
+        Some(supported) => supported
+            .lines()
+            .find(|line| line.split_whitespace().next() == Some(locale.as_str()))
+            .map(|line| line.split_whitespace().collect::<Vec<_>>().join(" ")),
+        None => {
+            let charset = locale.splitn(2, '.').nth(1)?.to_owned();
+            Some([&locale, " ", &charset].concat())
+        }
+    }
+}
+
+/// Enables the entries in `/etc/locale.gen`, uncommenting them where they are listed, and
+/// appending them where they are not.
+pub fn enable(locale_gen: &str, entries: &[String]) -> String {
+    let mut enabled = Vec::new();
+    let mut lines = Vec::new();
+
+    for line in locale_gen.lines() {
+        let uncommented = line.trim_start_matches('#').trim();
+        match entries.iter().find(|entry| entry.as_str() == uncommented) {
+            Some(entry) if !enabled.contains(&entry) => {
+                enabled.push(entry);
+                lines.push(entry.clone());
+            }
+            _ => lines.push(line.to_owned()),
+        }
+    }
+
+    lines.extend(entries.iter().filter(|entry| !enabled.contains(entry)).cloned());
+
+    let mut locale_gen = lines.join("\n");
+    locale_gen.push('\n');
+    locale_gen
+}
+
+/// Names the charset of the locale as the C library lists it, such as `UTF-8` for `utf8`.
+pub fn normalize(locale: &str) -> String {
+    let mut parts = locale.splitn(2, '.');
+    let name = parts.next().unwrap_or("");
+    match parts.next() {
+        Some(charset) if charset.eq_ignore_ascii_case("utf8") => [name, ".UTF-8"].concat(),
+        _ => locale.to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SUPPORTED: &str = "cs_CZ.UTF-8 UTF-8\ncs_CZ ISO-8859-2\nen_US.UTF-8 UTF-8\n";
+
+    #[test]
+    fn entries() {
+        assert_eq!(entry("cs_CZ.utf8", Some(SUPPORTED)), Some("cs_CZ.UTF-8 UTF-8".into()));
+        assert_eq!(entry("cs_CZ", Some(SUPPORTED)), Some("cs_CZ ISO-8859-2".into()));
+        assert_eq!(entry("xx_XX.UTF-8", Some(SUPPORTED)), None);
+        assert_eq!(entry("de_DE.UTF-8", None), Some("de_DE.UTF-8 UTF-8".into()));
+        assert_eq!(entry("de_DE", None), None);
+    }
+
+    #[test]
+    fn locale_gen() {
+        let locale_gen = "# This file lists locales that you wish to have built.\n\
+                          #\n# cs_CZ ISO-8859-2\n# cs_CZ.UTF-8 UTF-8\n# en_US.UTF-8 UTF-8\n";
+        let entries = ["cs_CZ.UTF-8 UTF-8".to_owned(), "de_DE.UTF-8 UTF-8".to_owned()];
+        assert_eq!(
+            enable(locale_gen, &entries),
+            "# This file lists locales that you wish to have built.\n\
+             #\n# cs_CZ ISO-8859-2\ncs_CZ.UTF-8 UTF-8\n# en_US.UTF-8 UTF-8\nde_DE.UTF-8 UTF-8\n"
+        );
+    }
+}
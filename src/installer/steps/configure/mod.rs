@@ -1,13 +1,33 @@
 use crate::bootloader::Bootloader;
+mod autologin;
+mod board;
 mod chroot_conf;
-use self::chroot_conf::ChrootConfigurator;
-use super::{mount_cdrom, mount_efivars};
-use crate::installer::{conf::RecoveryEnv, steps::normalize_os_release_name};
+mod grub;
+mod hibernation;
+mod identity;
+mod initramfs;
+mod kernel_hook;
+mod kernelstub;
+mod local_packages;
+mod locale;
+mod oem;
+mod substeps;
+mod systemd_boot;
+pub use self::{
+    grub::GrubOptions,
+    initramfs::InitramfsTool,
+    systemd_boot::{verify_default, SystemdBootOptions},
+};
+use self::{chroot_conf::ChrootConfigurator, substeps::Substeps};
+use super::{mount_cdrom, mount_efivars, BootloaderChoice, ManifestFacts, Removals, RemoveEntry};
+use crate::installer::{conf::RecoveryEnv, efi_fallback, steps::normalize_os_release_name};
 use crate::chroot::Chroot;
-use crate::distribution;
+use crate::distribution::{self, target::TargetOs};
 use crate::errors::*;
+use fstab_generate::FstabId;
 use crate::external::remount_rw;
-use crate::hardware_support;
+use crate::hardware_support::{self, drivers};
+use crate::logging;
 use crate::installer::traits::InstallerDiskOps;
 use libc;
 use crate::misc;
@@ -15,6 +35,7 @@ use os_release::OsRelease;
 use partition_identity::PartitionID;
 use rayon;
 use std::{
+    env,
     fs::{self, Permissions},
     io::{self, Write},
     os::unix::{ffi::OsStrExt, fs::PermissionsExt},
@@ -22,9 +43,12 @@ use std::{
 };
 use tempdir::TempDir;
 use crate::timezones::Region;
+use crate::CancellationToken;
 use crate::Config;
 use crate::UserAccountCreate;
+use crate::installer::{user, zram::ZramMechanism};
 use crate::INSTALL_HARDWARE_SUPPORT;
+use crate::KEEP_IDENTITY;
 use crate::RUN_UBUNTU_DRIVERS;
 
 /// Self-explanatory -- the fstab file will be generated with this header.
@@ -64,29 +88,52 @@ macro_rules! map_errors {
     }
 }
 
-pub fn configure<D: InstallerDiskOps, P: AsRef<Path>, S: AsRef<str>, F: FnMut(i32)>(
+/// The callback is given the percent of the step, and the sub-task which is being run.
+pub fn configure<D: InstallerDiskOps, P: AsRef<Path>, F: FnMut(i32, Option<&'static str>)>(
     recovery_conf: Option<&mut RecoveryEnv>,
     disks: &D,
     mount_dir: P,
     config: &Config,
     iso_os_release: &OsRelease,
     region: Option<&Region>,
-    user: Option<&UserAccountCreate>,
-    remove_pkgs: &[S],
-    mut callback: F,
+    users: &[UserAccountCreate],
+    remove_pkgs: &[RemoveEntry],
+    cancel: &CancellationToken,
+    callback: F,
 ) -> io::Result<()> {
     let mount_dir = mount_dir.as_ref().canonicalize().unwrap();
     info!("Configuring on {}", mount_dir.display());
+    let mut substeps = Substeps::new(callback);
+    substeps.start(0, "Preparing the configuration");
+
+    let initramfs_tool = match config.initramfs.or_else(|| InitramfsTool::detect(&mount_dir)) {
+        Some(tool) => tool,
+        None => {
+            warn!("no initramfs tool was found in the image: assuming initramfs-tools");
+            InitramfsTool::InitramfsTools
+        }
+    };
+    info!("the initramfs is generated with {:?}", initramfs_tool);
+
+    let target_os = TargetOs::detect(&mount_dir);
+    info!("the install is configured as {:?}", target_os);
     let tpath = mount_dir.join("tmp");
     let configure_dir = TempDir::new_in(&tpath, "distinst")
         .with_context(|err| format!("creating tempdir at {:?}: {}", tpath, err))?;
 
+    let firmware = config.bootloader.firmware(Bootloader::detect());
+    let loader = config.bootloader.resolve(firmware, &iso_os_release.name);
+    let arch = efi_fallback::target_arch(&mount_dir)
+        .or_else(|| efi_fallback::efi_arch(env::consts::ARCH))
+        .unwrap_or("x64");
+    let bootloader_pkgs =
+        distribution::debian::get_bootloader_packages(&iso_os_release, loader, arch);
     let install_pkgs = &mut cascade! {
         Vec::with_capacity(32);
-        ..extend_from_slice(distribution::debian::get_bootloader_packages(&iso_os_release));
+        ..extend_from_slice(bootloader_pkgs);
     };
 
-    callback(5);
+    substeps.start(5, "Writing fstab and crypttab");
 
     let lvm_autodetection = || {
         // Ubuntu's LVM auto-detection doesn't seem to work for activating root volumes.
@@ -98,7 +145,10 @@ pub fn configure<D: InstallerDiskOps, P: AsRef<Path>, S: AsRef<str>, F: FnMut(i3
     };
 
     let generate_fstabs = || {
-        let (crypttab, fstab) = disks.generate_fstabs();
+        let (crypttab, mut fstab) = disks.generate_fstabs(config.fstab_id);
+        if config.swapfile.is_some() {
+            fstab.push("/swapfile  none  swap  sw  0  0\n");
+        }
 
         let (a, b) = rayon::join(
             || {
@@ -121,10 +171,16 @@ pub fn configure<D: InstallerDiskOps, P: AsRef<Path>, S: AsRef<str>, F: FnMut(i3
         let mut c: io::Result<()> = Ok(());
         let mut configure_graphics = Ok(false);
 
+        // The threads log to the install which spawned them.
+        let sink = logging::current();
         rayon::scope(|s| {
-            s.spawn(|_| b = lvm_autodetection());
-            s.spawn(|_| c = generate_fstabs());
+            // The workaround is a script of initramfs-tools.
+            if initramfs_tool == InitramfsTool::InitramfsTools {
+                s.spawn(|_| b = logging::within(sink, lvm_autodetection));
+            }
+            s.spawn(|_| c = logging::within(sink, generate_fstabs));
             s.spawn(|_| {
+                let _log = logging::adopt(sink);
                 if config.flags & INSTALL_HARDWARE_SUPPORT != 0 {
                     hardware_support::append_packages(install_pkgs, &iso_os_release);
                 }
@@ -133,7 +189,6 @@ pub fn configure<D: InstallerDiskOps, P: AsRef<Path>, S: AsRef<str>, F: FnMut(i3
             });
         });
 
-        callback(10);
         map_errors! {
             b => "lvm autodetection error";
             c => "failed to generate fstab / crypttab"
@@ -143,26 +198,38 @@ pub fn configure<D: InstallerDiskOps, P: AsRef<Path>, S: AsRef<str>, F: FnMut(i3
     };
 
     {
+        substeps.start(10, "Mounting the install");
         info!("chrooting into target on {}", mount_dir.display());
 
-        let chroot = cascade! {
+        let mut chroot = cascade! {
             Chroot::new(&mount_dir)?;
             ..clear_envs(true);
             ..env("DEBIAN_FRONTEND", "noninteractive");
             ..env("HOME", "/root");
             ..env("LC_ALL", &config.lang);
             ..env("PATH", "/usr/sbin:/usr/bin:/sbin:/bin");
+            ..cancel_on(cancel.flag());
         };
 
+        if let Some(proxy) = config.network.as_ref().and_then(|network| network.proxy.as_deref()) {
+            for key in &["http_proxy", "https_proxy", "HTTP_PROXY", "HTTPS_PROXY"] {
+                chroot.env(key, proxy);
+            }
+        }
+
+        // Packages which are installed must not start their services in the live system.
+        chroot.block_services()?;
+
         let efivars_mount = mount_efivars(&mount_dir)?;
         let cdrom_mount = mount_cdrom(&mount_dir)?;
 
-        callback(15);
+        substeps.start(15, "Finding the root device");
 
-        let root_entry = disks.get_block_info_of("/")?;
-        let _recovery_entry = disks.get_block_info_of("/recovery");
-
-        callback(20);
+        // The recovery partition and LUKS unlocking find the root partition by its UUID, and
+        // the kernel command line finds it as the fstab does.
+        let root_entry = disks.get_block_info_of("/", FstabId::Uuid)?;
+        let root_id = disks.get_block_info_of("/", config.fstab_id)?.uid;
+        let _recovery_entry = disks.get_block_info_of("/recovery", FstabId::Uuid);
 
         let luks_uuid = root_entry
             .uid
@@ -173,8 +240,6 @@ pub fn configure<D: InstallerDiskOps, P: AsRef<Path>, S: AsRef<str>, F: FnMut(i3
             .and_then(PartitionID::get_uuid)
             .and_then(|uuid| if uuid == root_entry.uid { None } else { Some(uuid) });
 
-        callback(25);
-
         let root_uuid = &root_entry.uid;
         if let Some(conf) = recovery_conf {
             update_recovery_config(
@@ -185,13 +250,18 @@ pub fn configure<D: InstallerDiskOps, P: AsRef<Path>, S: AsRef<str>, F: FnMut(i3
             )?;
         }
 
-        callback(30);
+        substeps.start(30, "Resolving packages");
 
         let (retain, lang_output) = rayon::join(
             // Get packages required by this disk configuration.
             || distribution::debian::get_required_packages(disks, iso_os_release),
             // Attempt to run the check-language-support external command.
-            || distribution::debian::check_language_support(&config.lang, &chroot),
+            || match target_os {
+                TargetOs::Debian => {
+                    distribution::debian::check_language_support(&config.lang, &chroot)
+                }
+                _ => Ok(None),
+            },
         );
 
         let lang_output = lang_output?;
@@ -218,80 +288,209 @@ pub fn configure<D: InstallerDiskOps, P: AsRef<Path>, S: AsRef<str>, F: FnMut(i3
             None => &[],
         };
 
+        // The devices which need drivers are logged for support, even if none are installed.
+        let found_drivers = drivers::detect();
+        for found in &found_drivers {
+            let device = &found.device;
+            info!(
+                "found {} ({:?} {:04x}:{:04x}), which is driven by {:?}",
+                found.description, device.bus, device.vendor, device.device, found.packages
+            );
+        }
+
+        let mut driver_pkgs = Vec::new();
+        if config.install_drivers {
+            for package in found_drivers.iter().flat_map(|found| found.packages.iter()) {
+                if !driver_pkgs.contains(package) {
+                    driver_pkgs.push(*package);
+                }
+            }
+        }
+
         // Add the retained packages to the list of packages to be installed.
         // There are some packages that Ubuntu will still remove even if they've been removed from
         // the removal list.
         install_pkgs.extend_from_slice(&retain);
 
-        // Filter the discovered language packs and installed packages from the remove list.
-        let mut remove = remove_pkgs
-            .iter()
-            .map(AsRef::as_ref)
-            .filter(|pkg| !lang_packs.iter().any(|x| pkg == x) && !install_pkgs.contains(&pkg))
-            .collect::<Vec<&str>>();
-
-        // Remove incompatible bootloader packages
-        match Bootloader::detect() {
-            Bootloader::Bios => {
-                if iso_os_release.name == "Pop!_OS" {
-                    remove.push("kernelstub");
-                }
+        if target_os == TargetOs::Debian && config.ssh.as_ref().map_or(false, |s| s.enable_server) {
+            install_pkgs.push("openssh-server");
+        }
+
+        if target_os == TargetOs::Debian
+            && config.zram.is_some()
+            && ZramMechanism::detect(&mount_dir).is_none()
+        {
+            install_pkgs.push("systemd-zram-generator");
+        }
+
+        // Apply the remove manifest to the packages that are installed.
+        let facts = ManifestFacts {
+            arch:       &target_os.architecture(&chroot)?,
+            bootloader: firmware,
+            flags:      config.flags,
+            nvidia:     hardware_support::nvidia_loaded(),
+        };
+
+        // An install whose packages can't be managed is kept as it was extracted.
+        let remove_pkgs = match target_os {
+            TargetOs::Unsupported if !remove_pkgs.is_empty() => {
+                warn!("the packages of this distribution are not managed: none will be removed");
+                &[]
             }
-            Bootloader::Efi => (),
+            _ => remove_pkgs,
+        };
+
+        let installed = target_os.installed_packages(&chroot)?;
+        let removals = Removals::resolve(remove_pkgs, &facts, &installed);
+        for pattern in &removals.missing {
+            warn!("{} is in the remove manifest, but is not installed", pattern);
         }
 
-        callback(35);
+        let language_packs = match config.language_packages {
+            Some(ref packages) => packages.clone(),
+            None => distribution::language::packages(&config.lang),
+        };
+
+        let local_debs = match config.local_packages {
+            Some(ref dir) => local_packages::debs(dir)?,
+            None => Vec::new(),
+        };
+
+        // Filter the language packs, and the packages which are installed, from the remove
+        // list, as the manifest would otherwise remove what the config asked for.
+        let keep = |pkg: &&str| {
+            lang_packs.iter().any(|x| pkg == x)
+                || language_packs.iter().any(|x| pkg == x)
+                || local_debs.iter().any(|deb| local_packages::package(deb) == *pkg)
+                || install_pkgs.contains(pkg)
+                || driver_pkgs.iter().any(|driver| drivers::is_version_of(driver, pkg))
+        };
+        let remove = removals.remove.iter().map(String::as_str).filter(|pkg| !keep(pkg));
+        let remove = remove.collect::<Vec<&str>>();
+        let purge = removals.purge.iter().map(String::as_str).filter(|pkg| !keep(pkg));
+        let mut purge = purge.collect::<Vec<&str>>();
+
+        // Remove incompatible bootloader packages, as kernelstub manages systemd-boot.
+        if loader != BootloaderChoice::SystemdBoot
+            && iso_os_release.name == "Pop!_OS"
+            && installed.iter().any(|x| x == "kernelstub")
+        {
+            purge.push("kernelstub");
+        }
+
+        substeps.start(35, "Writing the system configuration");
 
         // TODO: use a macro to make this more manageable.
-        let chroot = ChrootConfigurator::new(chroot);
+        let mut chroot = ChrootConfigurator::new(chroot, target_os);
 
-        chroot.initramfs_disable()?;
+        chroot.initramfs_disable(initramfs_tool)?;
 
         let hostname = chroot.hostname(&config.hostname);
-        let hosts = chroot.hosts(&config.hostname);
-        let machine_id = chroot.generate_machine_id();
+        let hosts = chroot.hosts(&config.hostname, &config.hosts_append);
         let netresolv = chroot.netresolve();
-        let locale = chroot.generate_locale(&config.lang);
+
+        substeps.start(38, "Generating locales");
+        let locale = chroot.generate_locale(&config.lang, &config.additional_locales);
+
+        substeps.start(42, "Copying the kernel");
         let kernel_copy = chroot.kernel_copy();
 
-        let timezone = if let Some(tz) = region {
-            chroot.timezone(tz)
+        substeps.start(44, "Setting the time zone");
+        let timezone = set_timezone(&chroot, config, region);
+
+        substeps.start(46, "Creating the users");
+        let useradd = if config.oem_mode { chroot.oem_mode() } else { chroot.create_users(users) };
+
+        // The user may have been given by the user callback, rather than the config.
+        let root = user::validate_administrator(&config.root, users, config.oem_mode)
+            .and_then(|_| chroot.root_policy(&config.root));
+
+        substeps.start(50, "Installing packages");
+
+        if let (Some(network), TargetOs::Debian) = (config.network.as_ref(), target_os) {
+            chroot
+                .network(network)
+                .with_context(|why| format!("error configuring the network: {}", why))?;
+        }
+
+        // The packages are named as Debian names them, and installed from the apt repository
+        // of the image.
+        let apt_install = if target_os == TargetOs::Debian {
+            chroot
+                .cdrom_add()
+                .and_then(|_| chroot.apt_install(&install_pkgs))
+                .and_then(|_| chroot.install_drivers(config.flags & RUN_UBUNTU_DRIVERS != 0))
+                .and_then(|_| match config.local_packages {
+                    Some(ref dir) => chroot.install_local_packages(dir),
+                    None => Ok(()),
+                })
+                .and_then(|_| chroot.install_driver_packages(&driver_pkgs, config.allow_network))
+                .and_then(|_| {
+                    chroot.install_language_packages(&language_packs, config.allow_network)
+                })
+                .and_then(|_| chroot.cdrom_disable())
         } else {
+            warn!("packages are only installed in Debian and its derivatives: skipping");
             Ok(())
         };
 
-        let useradd = if let Some(ref user) = user {
-            chroot.create_user(
-                &user.username,
-                user.password.as_deref(),
-                user.realname.as_deref(),
-                user.profile_icon.as_deref(),
-            )
-        } else {
+        substeps.start(64, "Configuring SSH");
+        // The keys are given to the first user that was created, whose error is reported
+        // instead.
+        let ssh = match config.ssh {
+            Some(ref ssh) if useradd.is_ok() => {
+                chroot.ssh(ssh, users.first().map(|user| user.username.as_str()))
+            }
+            _ => Ok(()),
+        };
+
+        let firstboot = if config.firstboot_units.is_empty() {
             Ok(())
+        } else {
+            chroot.firstboot_units(&config.firstboot_units)
         };
 
-        let apt_install = chroot
-            .cdrom_add()
-            .and_then(|_| chroot.apt_install(&install_pkgs))
-            .and_then(|_| chroot.install_drivers(config.flags & RUN_UBUNTU_DRIVERS != 0))
-            .and_then(|_| chroot.cdrom_disable());
+        substeps.start(66, "Resetting the machine identity");
+        let grub_root = chroot.grub_root_id(root_id.variant);
+
+        // Packages which were installed may have generated an identity of their own.
+        let identity = if config.flags & KEEP_IDENTITY == 0 {
+            chroot.reset_identity()
+        } else {
+            Ok(())
+        };
 
         map_errors! {
             hostname => "error writing hostname";
             hosts => "error writing hosts";
-            machine_id => "error writing unique machine id";
             netresolv => "error linking netresolve";
             locale => "error generating locales";
             apt_install => "error installing packages";
+            ssh => "error configuring ssh";
+            firstboot => "error adding first boot services";
+            grub_root => "error configuring the root device of GRUB";
+            identity => "error resetting machine identity";
             kernel_copy => "error copying kernel from casper to chroot";
             timezone => "error setting timezone";
-            useradd => "error creating user account"
+            useradd => "error creating user accounts";
+            root => "error applying the root policy"
+        }
+
+        // The initramfs can't find the root file system without the hooks for its devices.
+        let support = disks.get_support_flags();
+        for required in initramfs_tool.required_files(support) {
+            if !mount_dir.join(required).exists() {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("the initramfs of this disk configuration requires /{}", required),
+                ));
+            }
         }
 
-        callback(70);
+        substeps.start(70, "Removing packages");
+        let package_remove = chroot.remove_packages(&remove, &purge);
 
-        let apt_remove = chroot.apt_remove(&remove);
+        substeps.start(73, "Configuring the recovery partition");
         let recovery = chroot.recovery(
             config,
             &normalize_os_release_name(&iso_os_release.name),
@@ -300,28 +499,99 @@ pub fn configure<D: InstallerDiskOps, P: AsRef<Path>, S: AsRef<str>, F: FnMut(i3
         );
 
         map_errors! {
-            apt_remove => "error removing packages";
+            package_remove => "error removing packages";
             recovery => "error creating recovery partition"
         }
 
-        callback(75);
+        substeps.start(75, "Setting the keyboard layout");
 
-        chroot.bootloader().with_context(|why| format!("error installing bootloader: {}", why))?;
+        // The console keymap is included in the initramfs.
+        chroot
+            .keyboard_layout(&config.keyboard_config())
+            .with_context(|why| format!("error setting keyboard layout: {}", why))?;
 
-        callback(80);
+        let btrfs = root_entry.fs == "btrfs";
+        if let Some(size) = config.swapfile {
+            substeps.start(76, "Creating the swap file");
+            chroot
+                .create_swapfile(size, btrfs)
+                .with_context(|why| format!("error creating swap file: {}", why))?;
+        }
 
-        if configure_graphics {
-            chroot.disable_nvidia_fallback();
+        if let Some(ref zram) = config.zram {
+            substeps.start(76, "Configuring zram");
+            chroot.zram(zram).with_context(|why| format!("error configuring zram: {}", why))?;
         }
 
+        // Resumes from the swap file if there is one, or else the largest swap partition.
+        let mut boot_options = String::new();
+        let mut resume = None;
+        if config.hibernation {
+            substeps.start(77, "Configuring hibernation");
+            let (uuid, offset) = match config.swapfile {
+                Some(_) => (root_uuid.id.clone(), Some(chroot.swapfile_offset(btrfs)?)),
+                None => {
+                    let uuid = disks.get_resume_uuid().ok_or_else(|| {
+                        io::Error::new(io::ErrorKind::NotFound, "no swap to resume from")
+                    })?;
+                    (uuid, None)
+                }
+            };
+
+            boot_options = hibernation::options(&uuid, offset);
+            chroot
+                .hibernation(&boot_options)
+                .with_context(|why| format!("error configuring hibernation: {}", why))?;
+            resume = Some(uuid);
+        }
+
+        // The initramfs is generated once the fstab, crypttab, and hooks are in place, and
+        // before the bootloader copies it to the ESP.
+        substeps.start(78, "Generating the initramfs");
         chroot
-            .keyboard_layout(config)
-            .with_context(|why| format!("error setting keyboard layout: {}", why))?;
-        callback(85);
+            .initramfs_config(initramfs_tool, support, resume.as_deref())
+            .with_context(|why| format!("error configuring the initramfs: {}", why))?;
+        chroot.initramfs_reenable(initramfs_tool)?;
+        chroot.update_initramfs(initramfs_tool)?;
+
+        // The root is found by the device mapper name of an encrypted or logical root, which
+        // systemd-boot entries need. Only the `encrypt` hook of mkinitcpio is given the LUKS
+        // device that it is unlocked from, as initramfs-tools and dracut read the crypttab.
+        let crypttab = fs::read_to_string(mount_dir.join("etc/crypttab")).unwrap_or_default();
+        let crypt = luks_uuid.as_ref().and_then(|luks| {
+            kernelstub::crypt_name(&crypttab, &luks.id).map(|name| (luks.id.as_str(), name))
+        });
+        let crypt = crypt.filter(|_| initramfs_tool == InitramfsTool::Mkinitcpio);
+        let mapper = root_uuid.get_device_path().and_then(|path| kernelstub::mapper_path(&path));
+        let root_options = kernelstub::root_options(mapper.as_deref(), &root_id, crypt);
+
+        // GRUB generates the root of the command line, but not the `cryptdevice`.
+        if let Some((luks_uuid, name)) = crypt {
+            if loader != BootloaderChoice::SystemdBoot && !loader.is_board() {
+                chroot
+                    .grub_cryptdevice(&kernelstub::crypt_option(luks_uuid, name))
+                    .with_context(|why| format!("error configuring the LUKS device: {}", why))?;
+            }
+        }
 
-        chroot.initramfs_reenable()?;
+        substeps.start(85, "Installing the bootloader");
+        chroot
+            .bootloader(
+                &boot_options,
+                &config.kernel_cmdline,
+                loader,
+                &root_options,
+                config.os_prober,
+                &config.systemd_boot,
+                &config.grub,
+            )
+            .with_context(|why| format!("error installing bootloader: {}", why))?;
 
-        callback(90);
+        if configure_graphics {
+            chroot.disable_nvidia_fallback();
+        }
+
+        substeps.start(90, "Unmounting the install");
 
         // Sync to the disk before unmounting
         unsafe {
@@ -335,15 +605,126 @@ pub fn configure<D: InstallerDiskOps, P: AsRef<Path>, S: AsRef<str>, F: FnMut(i3
         }
 
         drop(efivars_mount);
-        callback(95);
     }
 
     configure_dir.close()?;
-    callback(100);
+    substeps.complete();
 
     Ok(())
 }
 
+/// Configures an install which was extracted to a directory, rather than to disks, by
+/// writing the files of its configuration without mounting anything.
+///
+/// Commands are only run if `commands` is set, as root within a user namespace. Otherwise,
+/// the locales are not generated, and the users are not created.
+pub fn configure_dir<F: FnMut(i32, Option<&'static str>)>(
+    target: &Path,
+    config: &Config,
+    region: Option<&Region>,
+    users: &[UserAccountCreate],
+    commands: bool,
+    cancel: &CancellationToken,
+    callback: F,
+) -> io::Result<()> {
+    info!("configuring the directory at {}", target.display());
+    let mut substeps = Substeps::new(callback);
+    substeps.start(0, "Writing the system configuration");
+
+    let chroot = cascade! {
+        Chroot::unprivileged(target)?;
+        ..clear_envs(true);
+        ..env("DEBIAN_FRONTEND", "noninteractive");
+        ..env("HOME", "/root");
+        ..env("LC_ALL", &config.lang);
+        ..env("PATH", "/usr/sbin:/usr/bin:/sbin:/bin");
+        ..cancel_on(cancel.flag());
+    };
+
+    let chroot = ChrootConfigurator::new(chroot, TargetOs::detect(target));
+
+    map_errors! {
+        chroot.hostname(&config.hostname) => "error writing hostname";
+        chroot.hosts(&config.hostname, &config.hosts_append) => "error writing hosts";
+        chroot.netresolve() => "error linking netresolve";
+        set_timezone(&chroot, config, region) => "error setting timezone"
+    }
+
+    if !config.firstboot_units.is_empty() {
+        map_errors! {
+            chroot.firstboot_units(&config.firstboot_units) => "error adding first boot services"
+        }
+    }
+
+    if commands {
+        substeps.start(50, "Creating the users");
+        let useradd = if config.oem_mode { chroot.oem_mode() } else { chroot.create_users(users) };
+
+        let ssh = match config.ssh {
+            Some(ref ssh) if useradd.is_ok() => {
+                chroot.ssh(ssh, users.first().map(|user| user.username.as_str()))
+            }
+            _ => Ok(()),
+        };
+
+        let root = user::validate_administrator(&config.root, users, config.oem_mode)
+            .and_then(|_| chroot.root_policy(&config.root));
+
+        let zram = config.zram.as_ref().map_or(Ok(()), |zram| chroot.zram(zram));
+
+        substeps.start(60, "Generating locales");
+        let locale = chroot.generate_locale(&config.lang, &config.additional_locales);
+        let keyboard = chroot.keyboard_layout(&config.keyboard_config());
+        map_errors! {
+            locale => "error generating locales";
+            keyboard => "error setting keyboard layout";
+            useradd => "error creating user accounts";
+            root => "error applying the root policy";
+            ssh => "error configuring ssh";
+            zram => "error configuring zram"
+        }
+    } else {
+        info!(
+            "not generating locales, creating the users, applying the root policy, or caching the \
+             keymap, as commands may not be run"
+        );
+        substeps.start(50, "Setting the locale");
+        map_errors! {
+            chroot.default_locale(&config.lang) => "error setting the locale";
+            chroot.keyboard_files(&config.keyboard_config()) => "error setting keyboard layout"
+        }
+    }
+
+    substeps.start(75, "Resetting the machine identity");
+    if config.flags & KEEP_IDENTITY == 0 {
+        let identity =
+            if commands { chroot.reset_identity() } else { identity::reset(target).map(|_| ()) };
+        identity.with_context(|why| format!("error resetting machine identity: {}", why))?;
+    }
+
+    substeps.complete();
+    Ok(())
+}
+
+/// Sets the time zone of the config, or else that of the region from the timezone callback.
+fn set_timezone(
+    chroot: &ChrootConfigurator,
+    config: &Config,
+    region: Option<&Region>,
+) -> io::Result<()> {
+    match (config.timezone.as_ref(), region) {
+        (Some(timezone), _) => chroot.timezone(timezone),
+        (None, Some(region)) => match region.olson_name() {
+            Some(timezone) => chroot.timezone(timezone),
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is not in the time zone database", region.path().display()),
+            )),
+        },
+        (None, None) => Ok(()),
+    }
+}
+
 fn update_recovery_config(
     recovery_conf: &mut RecoveryEnv,
     mount: &Path,
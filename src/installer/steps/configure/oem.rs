@@ -0,0 +1,112 @@
+use std::path::Path;
+
+/// The temporary account which the OEM prepares the install with.
+pub const USER: &str = "oem";
+
+/// The user ID which `oem-config` expects of the temporary account.
+pub const UID: u32 = 29999;
+
+/// Marks the temporary account, so that it is removed once the owner of the machine has
+/// created their own.
+pub const FLAG: &str = "var/lib/distinst/oem";
+
+/// Removes the temporary account, named in the `FLAG`, once another account exists.
+pub const CLEANUP_SCRIPT: &str = "usr/lib/distinst/oem-cleanup";
+
+/// Runs the `CLEANUP_SCRIPT` on each boot until the `FLAG` is gone.
+pub const CLEANUP_SERVICE: &str = "distinst-oem-cleanup.service";
+
+/// The contents of the `CLEANUP_SCRIPT`. `oem-config` may have removed the account
+/// already, in which case only the flag is removed.
+pub fn cleanup_script() -> String {
+    fomat!(
+        "#!/bin/sh\n"
+        "# Written by distinst\n"
+        "set -e\n"
+        "flag=/" (FLAG) "\n"
+        "oem=\"$(cat \"$flag\")\"\n"
+        "getent passwd | awk -F: -v oem=\"$oem\" \\\n"
+        "    '$3 >= 1000 && $3 < 60000 && $1 != oem { found = 1 } END { exit !found }' \\\n"
+        "    || exit 0\n"
+        "if id \"$oem\" >/dev/null 2>&1; then\n"
+        "    userdel --remove \"$oem\"\n"
+        "fi\n"
+        "rm -f \"$flag\"\n"
+    )
+}
+
+/// The contents of the `CLEANUP_SERVICE`, which runs before anyone can log in.
+pub fn cleanup_unit() -> String {
+    fomat!(
+        "# Written by distinst\n"
+        "[Unit]\n"
+        "Description=Remove the temporary OEM account\n"
+        "ConditionPathExists=/" (FLAG) "\n"
+        "Before=display-manager.service systemd-user-sessions.service\n"
+        "\n[Service]\n"
+        "Type=oneshot\n"
+        "ExecStart=/" (CLEANUP_SCRIPT) "\n"
+        "\n[Install]\n"
+        "WantedBy=multi-user.target\n"
+    )
+}
+
+/// The services which set up the account of the owner of the machine on the first boot.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum FirstBoot {
+    /// Ubuntu's `oem-config`, which runs once the OEM prepares the install for shipping.
+    OemConfig,
+    /// GNOME Initial Setup, which runs in `--existing-user` mode on the first login of the
+    /// temporary account.
+    GnomeInitialSetup,
+}
+
+impl FirstBoot {
+    /// Finds the first-boot setup service which the image contains, if any.
+    pub fn detect(root: &Path) -> Option<Self> {
+        if root.join("usr/sbin/oem-config-firstboot").exists() {
+            Some(FirstBoot::OemConfig)
+        } else if root.join("usr/libexec/gnome-initial-setup").exists() {
+            Some(FirstBoot::GnomeInitialSetup)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempdir::TempDir;
+
+    #[test]
+    fn first_boot() {
+        let root = TempDir::new("distinst").unwrap();
+        let root = root.path();
+        assert_eq!(FirstBoot::detect(root), None);
+
+        fs::create_dir_all(root.join("usr/libexec")).unwrap();
+        fs::write(root.join("usr/libexec/gnome-initial-setup"), "").unwrap();
+        assert_eq!(FirstBoot::detect(root), Some(FirstBoot::GnomeInitialSetup));
+
+        fs::create_dir_all(root.join("usr/sbin")).unwrap();
+        fs::write(root.join("usr/sbin/oem-config-firstboot"), "").unwrap();
+        assert_eq!(FirstBoot::detect(root), Some(FirstBoot::OemConfig));
+    }
+
+    #[test]
+    fn cleanup() {
+        let unit = cleanup_unit();
+        assert!(unit.contains("ConditionPathExists=/var/lib/distinst/oem\n"));
+        assert!(unit.contains("ExecStart=/usr/lib/distinst/oem-cleanup\n"));
+
+        // The account to remove is the one named in the flag, which is removed with it.
+        let script = cleanup_script();
+        assert!(script.starts_with("#!/bin/sh\n"));
+        assert!(script.contains("flag=/var/lib/distinst/oem\n"));
+        assert!(script.contains("oem=\"$(cat \"$flag\")\"\n"));
+        assert!(script.contains("userdel --remove \"$oem\"\n"));
+        assert!(script.ends_with("rm -f \"$flag\"\n"));
+    }
+}
@@ -0,0 +1,53 @@
+use std::time::Instant;
+
+/// Reports the sub-tasks of the Configure step as each is started, with the percent of the
+/// step at which it starts, and logs how long each took.
+pub struct Substeps<F: FnMut(i32, Option<&'static str>)> {
+    callback: F,
+    current:  Option<(&'static str, Instant)>,
+}
+
+impl<F: FnMut(i32, Option<&'static str>)> Substeps<F> {
+    pub fn new(callback: F) -> Self { Self { callback, current: None } }
+
+    /// Finishes the current sub-task, and starts the next.
+    pub fn start(&mut self, percent: i32, substep: &'static str) {
+        self.finish();
+        info!("{}: started", substep);
+        self.current = Some((substep, Instant::now()));
+        (self.callback)(percent, Some(substep));
+    }
+
+    /// Finishes the last sub-task, once the step has completed.
+    pub fn complete(mut self) {
+        self.finish();
+        (self.callback)(100, None);
+    }
+
+    fn finish(&mut self) {
+        if let Some((substep, started)) = self.current.take() {
+            info!("{}: finished in {:.1} seconds", substep, started.elapsed().as_secs_f64());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn substeps() {
+        let mut reported = Vec::new();
+        {
+            let mut substeps = Substeps::new(|percent, substep| reported.push((percent, substep)));
+            substeps.start(0, "Writing fstab");
+            substeps.start(50, "Installing packages");
+            substeps.complete();
+        }
+
+        assert_eq!(
+            reported,
+            vec![(0, Some("Writing fstab")), (50, Some("Installing packages")), (100, None)]
+        );
+    }
+}
@@ -0,0 +1,286 @@
+use std::{cmp::Ordering, fs, io, path::Path};
+
+/// The settings of the `loader.conf` of systemd-boot. Those which are `None` are left to
+/// the existing config of the ESP, or else to distinst.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SystemdBootOptions {
+    /// The seconds for which the menu is shown, where 0 boots the default entry at once,
+    /// unless a key is held.
+    pub timeout:      Option<u32>,
+    /// The entry which is booted by default: `current`, `oldkern`, or `recovery`, which
+    /// name the entries that are generated for the install, or else the ID of an entry,
+    /// which may be a glob. The newest kernel of the install is the default otherwise.
+    pub default:      Option<String>,
+    /// The `console-mode` of the menu, such as `auto`, `max`, or `keep`.
+    pub console_mode: Option<String>,
+    /// Whether the kernel command line of an entry may be edited from the menu.
+    pub editor:       Option<bool>,
+}
+
+/// The IDs of the entries which are generated for the install, as the `default` of the
+/// options names them.
+#[derive(Debug, Default, PartialEq)]
+pub struct EntryIds {
+    /// The entry of the newest kernel.
+    pub current:  String,
+    /// The entry of the previous kernel, if the install has more than one.
+    pub oldkern:  Option<String>,
+    /// The entry of the recovery partition.
+    pub recovery: Option<String>,
+}
+
+impl EntryIds {
+    /// The pattern of `loader.conf` which boots the entry that `default` names.
+    pub fn resolve(&self, default: &str) -> io::Result<String> {
+        let id = match default {
+            "current" => &self.current,
+            "oldkern" => self.oldkern.as_ref().ok_or_else(|| missing("an older kernel"))?,
+            "recovery" => self.recovery.as_ref().ok_or_else(|| missing("a recovery partition"))?,
+            id if id.is_empty() || id.contains(char::is_whitespace) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("systemd-boot default entry {:?} is not a valid entry ID", id),
+                ));
+            }
+            id => return Ok(id.to_owned()),
+        };
+
+        Ok([id, ".conf"].concat())
+    }
+}
+
+/// Checks that the `default` names an entry which will be generated for the install,
+/// before any disks are modified. The image has an older kernel if the files of its
+/// `boot`, when they could be listed, pair more than one kernel with an initrd.
+pub fn verify_default(default: &str, boot: Option<&[String]>, recovery: bool) -> io::Result<()> {
+    let oldkern = boot.map_or(true, |files| find_kernels(files).len() > 1);
+    let ids = EntryIds {
+        current:  String::new(),
+        oldkern:  if oldkern { Some(String::new()) } else { None },
+        recovery: if recovery { Some(String::new()) } else { None },
+    };
+
+    ids.resolve(default).map(|_| ())
+}
+
+fn missing(what: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("systemd-boot has no default entry, as the install does not have {}", what),
+    )
+}
+
+/// A kernel of the install, and the initrd which was generated for it, in `/boot`.
+#[derive(Debug, PartialEq)]
+pub struct Kernel {
+    pub version: String,
+    pub kernel:  String,
+    pub initrd:  String,
+}
+
+/// Finds the kernels in `boot` which have an initrd, with the newest first.
+pub fn kernels(boot: &Path) -> io::Result<Vec<Kernel>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(boot)? {
+        if let Ok(name) = entry?.file_name().into_string() {
+            files.push(name);
+        }
+    }
+
+    Ok(find_kernels(&files))
+}
+
+/// Pairs each `vmlinuz-{version}` with its `initrd.img-{version}`, as Debian names them, or
+/// its `initramfs-{version}.img`, as dracut and mkinitcpio name them.
+fn find_kernels(files: &[String]) -> Vec<Kernel> {
+    let mut kernels = files
+        .iter()
+        .filter_map(|file| {
+            let version = file.strip_prefix("vmlinuz-")?;
+            let initrds =
+                [["initrd.img-", version].concat(), ["initramfs-", version, ".img"].concat()];
+            let initrd = initrds.iter().find(|initrd| files.contains(initrd))?;
+            Some(Kernel {
+                version: version.to_owned(),
+                kernel:  file.clone(),
+                initrd:  initrd.clone(),
+            })
+        })
+        .collect::<Vec<_>>();
+
+    kernels.sort_by(|a, b| compare_versions(&b.version, &a.version));
+    kernels
+}
+
+/// Compares versions such as `6.2.0-39-generic`, with their numbers compared as numbers.
+fn compare_versions(a: &str, b: &str) -> Ordering {
+    let parts = |version: &str| {
+        version
+            .split(|c: char| !c.is_ascii_alphanumeric())
+            .map(|part| part.parse::<u64>().map_err(|_| part.to_owned()))
+            .collect::<Vec<_>>()
+    };
+
+    parts(a).cmp(&parts(b))
+}
+
+/// The ID of the entry of a kernel, which names its file in `/loader/entries`.
+pub fn entry_id(name: &str, kernel: &Kernel) -> String { [name, "-", &kernel.version].concat() }
+
+/// A systemd-boot entry, which boots the copies of the kernel and initrd in `/EFI/{name}` on
+/// the ESP.
+pub fn entry(name: &str, title: &str, kernel: &Kernel, options: &str) -> String {
+    format!(
+        r#"title {0}
+version {1}
+linux /EFI/{2}/{3}
+initrd /EFI/{2}/{4}
+options {5}
+"#,
+        title, kernel.version, name, kernel.kernel, kernel.initrd, options
+    )
+}
+
+/// The ID of the recovery entry in the `entries` of the ESP.
+pub fn recovery_entry(entries: &Path) -> Option<String> {
+    fs::read_dir(entries).ok()?.filter_map(Result::ok).find_map(|entry| {
+        let name = entry.file_name().into_string().ok()?;
+        let id = name.strip_suffix(".conf")?;
+        if id.starts_with("Recovery-") {
+            Some(id.to_owned())
+        } else {
+            None
+        }
+    })
+}
+
+/// The `loader.conf` of systemd-boot, which boots the `default` entry, with the settings
+/// of the `options`. Those of the `existing` config, which another OS may have written,
+/// are kept unless they are replaced.
+pub fn loader_conf(existing: &str, default: &str, options: &SystemdBootOptions) -> String {
+    fn key(line: &str) -> Option<&str> { line.split_whitespace().next() }
+
+    let has_timeout = existing.lines().any(|line| key(line) == Some("timeout"));
+
+    let mut settings = vec![("default", default.to_owned())];
+    match options.timeout {
+        Some(timeout) => settings.push(("timeout", timeout.to_string())),
+        None if !has_timeout => settings.push(("timeout", "3".into())),
+        None => (),
+    }
+
+    if let Some(ref mode) = options.console_mode {
+        settings.push(("console-mode", mode.clone()));
+    }
+
+    if let Some(editor) = options.editor {
+        settings.push(("editor", if editor { "yes" } else { "no" }.into()));
+    }
+
+    let mut conf = String::from("# Written by distinst\n");
+    for line in existing.lines() {
+        let replaced = key(line).map_or(false, |key| settings.iter().any(|&(name, _)| name == key));
+        if !replaced && line != "# Written by distinst" {
+            conf.push_str(line);
+            conf.push('\n');
+        }
+    }
+
+    for (name, value) in settings {
+        conf.push_str(&[name, " ", &value, "\n"].concat());
+    }
+
+    conf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pairs() {
+        let files = [
+            "config-6.2.0-39-generic",
+            "initrd.img-6.2.0-39-generic",
+            "initrd.img-6.10.3-generic",
+            "vmlinuz-6.2.0-39-generic",
+            "vmlinuz-6.10.3-generic",
+            "vmlinuz-6.11.0-generic",
+            "vmlinuz",
+        ];
+        let files = files.iter().map(|&file| String::from(file)).collect::<Vec<_>>();
+
+        let found = find_kernels(&files);
+        let versions = found.iter().map(|kernel| kernel.version.as_str()).collect::<Vec<_>>();
+        assert_eq!(versions, vec!["6.10.3-generic", "6.2.0-39-generic"]);
+        assert_eq!(found[1].initrd, "initrd.img-6.2.0-39-generic");
+
+        let arch = ["vmlinuz-linux".into(), "initramfs-linux.img".into()];
+        assert_eq!(find_kernels(&arch)[0].initrd, "initramfs-linux.img");
+    }
+
+    #[test]
+    fn defaults() {
+        let boot = ["vmlinuz-6.2.0-39-generic".into(), "initrd.img-6.2.0-39-generic".into()];
+        assert!(verify_default("current", Some(&boot), false).is_ok());
+        assert!(verify_default("oldkern", Some(&boot), false).is_err());
+        assert!(verify_default("recovery", Some(&boot), false).is_err());
+        assert!(verify_default("recovery", Some(&boot), true).is_ok());
+
+        // The kernels of an image which can't be listed are only found after extraction.
+        assert!(verify_default("oldkern", None, false).is_ok());
+    }
+
+    #[test]
+    fn entries() {
+        let kernel = Kernel {
+            version: "6.2.0-39-generic".into(),
+            kernel:  "vmlinuz-6.2.0-39-generic".into(),
+            initrd:  "initrd.img-6.2.0-39-generic".into(),
+        };
+
+        assert_eq!(entry_id("ubuntu", &kernel), "ubuntu-6.2.0-39-generic");
+        assert_eq!(
+            entry("ubuntu", "Ubuntu 22.04 LTS", &kernel, "root=UUID=1234 ro quiet"),
+            "title Ubuntu 22.04 LTS\nversion 6.2.0-39-generic\nlinux \
+             /EFI/ubuntu/vmlinuz-6.2.0-39-generic\ninitrd \
+             /EFI/ubuntu/initrd.img-6.2.0-39-generic\noptions root=UUID=1234 ro quiet\n"
+        );
+        let default = EntryIds { current: entry_id("ubuntu", &kernel), ..EntryIds::default() };
+        assert_eq!(
+            loader_conf("", &default.resolve("current").unwrap(), &SystemdBootOptions::default()),
+            "# Written by distinst\ndefault ubuntu-6.2.0-39-generic.conf\ntimeout 3\n"
+        );
+    }
+
+    #[test]
+    fn loader_options() {
+        let ids = EntryIds {
+            current:  "Pop_OS-current".into(),
+            oldkern:  None,
+            recovery: Some("Recovery-1234".into()),
+        };
+        assert_eq!(ids.resolve("recovery").unwrap(), "Recovery-1234.conf");
+        assert_eq!(ids.resolve("arch-*").unwrap(), "arch-*");
+        assert!(ids.resolve("oldkern").is_err());
+        assert!(ids.resolve("arch current").is_err());
+        assert!(ids.resolve("").is_err());
+
+        // The settings of another OS are merged, and its timeout is kept.
+        let existing = "#timeout 3\ndefault arch.conf\ntimeout 5\nbeep yes\n";
+        let options = SystemdBootOptions {
+            console_mode: Some("max".into()),
+            editor: Some(false),
+            ..SystemdBootOptions::default()
+        };
+        assert_eq!(
+            loader_conf(existing, "Recovery-1234.conf", &options),
+            "# Written by distinst\n#timeout 3\ntimeout 5\nbeep yes\ndefault \
+             Recovery-1234.conf\nconsole-mode max\neditor no\n"
+        );
+
+        let options = SystemdBootOptions { timeout: Some(0), ..SystemdBootOptions::default() };
+        let conf = loader_conf(existing, "Pop_OS-current.conf", &options);
+        assert!(conf.ends_with("beep yes\ndefault Pop_OS-current.conf\ntimeout 0\n"), "{}", conf);
+    }
+}
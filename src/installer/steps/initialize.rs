@@ -1,61 +1,24 @@
 use crate::disks::*;
+use crate::logging;
 use crate::misc;
+use crate::squashfs::{self, Source};
 use rayon;
 use std::{
-    io::{self, BufRead},
-    path::{Path, PathBuf},
+    io::{self, Read},
+    path::PathBuf,
 };
-use crate::Config;
+use super::{parse_manifest, RemoveEntry};
+use crate::{Config, DELETE_EXTRANEOUS};
 
 pub fn initialize<F: FnMut(i32)>(
     disks: &mut Disks,
     config: &Config,
     mut callback: F,
-) -> io::Result<(PathBuf, Vec<String>)> {
+) -> io::Result<(Source, Vec<RemoveEntry>)> {
     info!("Initializing");
 
-    let fetch_squashfs = || match Path::new(&config.squashfs).canonicalize() {
-        Ok(squashfs) => {
-            if squashfs.exists() {
-                info!("config.squashfs: found at {}", squashfs.display());
-                Ok(squashfs)
-            } else {
-                error!("config.squashfs: supplied file does not exist");
-                Err(io::Error::new(io::ErrorKind::NotFound, "invalid squashfs path"))
-            }
-        }
-        Err(err) => {
-            error!("config.squashfs: {}", err);
-            Err(err)
-        }
-    };
-
-    let fetch_packages = || {
-        let mut remove_pkgs = Vec::new();
-        {
-            let file = match misc::open(&config.remove) {
-                Ok(file) => file,
-                Err(err) => {
-                    error!("config.remove: {}", err);
-                    return Err(err);
-                }
-            };
-
-            // Collects the packages that are to be removed from the install.
-            for line_res in io::BufReader::new(file).lines() {
-                match line_res {
-                    // Only add package if it is not contained within lang_packs.
-                    Ok(line) => remove_pkgs.push(line),
-                    Err(err) => {
-                        error!("config.remove: {}", err);
-                        return Err(err);
-                    }
-                }
-            }
-        }
-
-        Ok(remove_pkgs)
-    };
+    let fetch_squashfs = || find_source(config);
+    let fetch_packages = || read_remove_manifest(config);
 
     let verify_disks = |disks: &Disks| {
         disks.verify_keyfile_paths()?;
@@ -65,10 +28,14 @@ pub fn initialize<F: FnMut(i32)>(
     let mut res_a = Ok(());
     let mut res_b = Ok(Vec::new());
     let mut res_c = Ok(());
-    let mut res_d = Ok(PathBuf::new());
+    let mut res_d = Err(io::Error::new(io::ErrorKind::NotFound, "no source was found"));
 
+    // The threads log to the install which spawned them.
+    let sink = logging::current();
     rayon::scope(|s| {
         s.spawn(|_| {
+            let _log = logging::adopt(sink);
+
             // Deactivate any open logical volumes & close any encrypted partitions.
             if let Err(why) = disks.deactivate_device_maps() {
                 error!("device map deactivation error: {}", why);
@@ -92,9 +59,9 @@ pub fn initialize<F: FnMut(i32)>(
             res_a = Ok(());
         });
 
-        s.spawn(|_| res_b = fetch_packages());
-        s.spawn(|_| res_c = verify_disks(disks));
-        s.spawn(|_| res_d = fetch_squashfs());
+        s.spawn(|_| res_b = logging::within(sink, fetch_packages));
+        s.spawn(|_| res_c = logging::within(sink, || verify_disks(disks)));
+        s.spawn(|_| res_d = logging::within(sink, fetch_squashfs));
     });
 
     let (remove_pkgs, squashfs) =
@@ -125,3 +92,43 @@ pub fn initialize<F: FnMut(i32)>(
 
     Ok((squashfs, remove_pkgs))
 }
+
+/// Collects the packages that are to be removed from the install.
+pub fn read_remove_manifest(config: &Config) -> io::Result<Vec<RemoveEntry>> {
+    let mut manifest = String::new();
+    misc::open(&config.remove)
+        .and_then(|mut file| file.read_to_string(&mut manifest))
+        .and_then(|_| parse_manifest(&manifest))
+        .map_err(|err| {
+            error!("config.remove: {}", err);
+            io::Error::new(err.kind(), format!("remove manifest: {}", err))
+        })
+}
+
+/// Finds the source image of the install, along with the layers that are extracted over it.
+pub fn find_source(config: &Config) -> io::Result<Source> {
+    let detected = if squashfs::is_url(&config.squashfs) {
+        Ok(Source::Remote(config.squashfs.clone()))
+    } else {
+        Source::detect(&config.squashfs)
+    };
+
+    match detected {
+        Ok(Source::Directory { path, .. }) => {
+            let delete = config.flags & DELETE_EXTRANEOUS != 0;
+            info!("config.squashfs: copying from {} (delete: {})", path.display(), delete);
+            Ok(Source::Directory { path, delete })
+        }
+        Ok(source) => {
+            info!("config.squashfs: found {:?}", source);
+            source.with_layers(config.layers.iter().map(PathBuf::from).collect()).map_err(|err| {
+                error!("config.layers: {}", err);
+                err
+            })
+        }
+        Err(err) => {
+            error!("config.squashfs: {}", err);
+            Err(err)
+        }
+    }
+}
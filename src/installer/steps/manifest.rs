@@ -0,0 +1,281 @@
+//! The manifest of packages to remove from the install, such as
+//! `/cdrom/casper/filesystem.manifest-remove`.
+//!
+//! Each line names a package, or a glob of packages, followed by optional qualifiers:
+//!
+//! ```text
+//! # Comments and empty lines are ignored.
+//! ubiquity
+//! casper remove
+//! nvidia-* if=!nvidia
+//! grub-pc arch=amd64,i386 if=efi
+//! ```
+//!
+//! - `remove` or `purge` chooses whether configuration files are kept. Packages are purged by
+//!   default, as they were when the manifest was a plain list of packages.
+//! - `arch=` lists the architectures, as named by dpkg, which the entry applies to.
+//! - `if=` lists the conditions which must all hold for the entry to apply. Each may be negated
+//!   with `!`.
+
+use crate::{bootloader::Bootloader, INSTALL_HARDWARE_SUPPORT, RUN_UBUNTU_DRIVERS};
+use std::io;
+
+/// Whether the configuration files of a package are kept when it is removed.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum RemoveAction {
+    Remove,
+    Purge,
+}
+
+/// A condition which an entry of the manifest may depend upon.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum RemoveCondition {
+    /// The system was booted with EFI.
+    Efi,
+    /// The system was booted with a BIOS.
+    Bios,
+    /// The NVIDIA driver is loaded on the live system.
+    Nvidia,
+    /// Hardware support packages are installed.
+    HardwareSupport,
+    /// Drivers are installed with ubuntu-drivers.
+    UbuntuDrivers,
+}
+
+impl RemoveCondition {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "efi" => Some(RemoveCondition::Efi),
+            "bios" => Some(RemoveCondition::Bios),
+            "nvidia" => Some(RemoveCondition::Nvidia),
+            "hardware-support" => Some(RemoveCondition::HardwareSupport),
+            "ubuntu-drivers" => Some(RemoveCondition::UbuntuDrivers),
+            _ => None,
+        }
+    }
+
+    fn holds(self, facts: &ManifestFacts) -> bool {
+        match self {
+            RemoveCondition::Efi => facts.bootloader == Bootloader::Efi,
+            RemoveCondition::Bios => facts.bootloader == Bootloader::Bios,
+            RemoveCondition::Nvidia => facts.nvidia,
+            RemoveCondition::HardwareSupport => facts.flags & INSTALL_HARDWARE_SUPPORT != 0,
+            RemoveCondition::UbuntuDrivers => facts.flags & RUN_UBUNTU_DRIVERS != 0,
+        }
+    }
+}
+
+/// What is known about the install, which the entries of the manifest are applied to.
+pub struct ManifestFacts<'a> {
+    /// The architecture of the install, as named by dpkg.
+    pub arch:       &'a str,
+    pub bootloader: Bootloader,
+    /// The flags of the install's config.
+    pub flags:      u8,
+    pub nvidia:     bool,
+}
+
+/// An entry of the manifest of packages to remove.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RemoveEntry {
+    /// The name of a package, which may contain `*` and `?` wildcards.
+    pub pattern:    String,
+    pub action:     RemoveAction,
+    /// The architectures that the entry applies to, or all of them if empty.
+    pub arches:     Vec<String>,
+    /// The conditions that must hold, and whether each is negated.
+    pub conditions: Vec<(bool, RemoveCondition)>,
+}
+
+impl RemoveEntry {
+    /// Whether the entry applies to the install.
+    pub fn applies(&self, facts: &ManifestFacts) -> bool {
+        (self.arches.is_empty() || self.arches.iter().any(|arch| arch == facts.arch))
+            && self.conditions.iter().all(|&(negated, condition)| condition.holds(facts) != negated)
+    }
+
+    /// Whether the package is matched by the entry's pattern.
+    pub fn matches(&self, package: &str) -> bool {
+        glob_matches(self.pattern.as_bytes(), package.as_bytes())
+    }
+}
+
+/// Parses a manifest of packages to remove, which may be a plain list of packages.
+pub fn parse_manifest(manifest: &str) -> io::Result<Vec<RemoveEntry>> {
+    let mut entries = Vec::new();
+
+    for (number, line) in manifest.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let invalid = |why: String| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("line {}: {}", number + 1, why))
+        };
+
+        let mut fields = line.split_whitespace();
+        let mut entry = RemoveEntry {
+            pattern:    fields.next().unwrap_or_default().to_owned(),
+            action:     RemoveAction::Purge,
+            arches:     Vec::new(),
+            conditions: Vec::new(),
+        };
+
+        for field in fields {
+            if field == "remove" {
+                entry.action = RemoveAction::Remove;
+            } else if field == "purge" {
+                entry.action = RemoveAction::Purge;
+            } else if field.starts_with("arch=") {
+                entry.arches.extend(field[5..].split(',').map(String::from));
+            } else if field.starts_with("if=") {
+                for condition in field[3..].split(',') {
+                    let negated = condition.starts_with('!');
+                    let name = condition.trim_start_matches('!');
+                    let condition = RemoveCondition::from_name(name)
+                        .ok_or_else(|| invalid(format!("unknown condition '{}'", name)))?;
+                    entry.conditions.push((negated, condition));
+                }
+            } else {
+                return Err(invalid(format!("unknown qualifier '{}'", field)));
+            }
+        }
+
+        entries.push(entry);
+    }
+
+    Ok(entries)
+}
+
+/// The packages to remove from the install, once the manifest is applied to what is
+/// installed.
+#[derive(Debug, Default, PartialEq)]
+pub struct Removals {
+    pub remove:  Vec<String>,
+    pub purge:   Vec<String>,
+    /// Entries which apply to the install, but matched no installed packages.
+    pub missing: Vec<String>,
+}
+
+impl Removals {
+    pub fn resolve(entries: &[RemoveEntry], facts: &ManifestFacts, installed: &[String]) -> Self {
+        let mut removals = Removals::default();
+
+        for entry in entries.iter().filter(|entry| entry.applies(facts)) {
+            let mut matched = false;
+            for package in installed.iter().filter(|package| entry.matches(package)) {
+                matched = true;
+                match entry.action {
+                    RemoveAction::Remove => removals.remove.push(package.clone()),
+                    RemoveAction::Purge => removals.purge.push(package.clone()),
+                }
+            }
+
+            if !matched {
+                removals.missing.push(entry.pattern.clone());
+            }
+        }
+
+        // A package which is listed to be purged and removed is only purged.
+        let purge = &removals.purge;
+        removals.remove.retain(|package| !purge.contains(package));
+        removals.remove.sort();
+        removals.remove.dedup();
+        removals.purge.sort();
+        removals.purge.dedup();
+        removals
+    }
+}
+
+fn glob_matches(pattern: &[u8], name: &[u8]) -> bool {
+    match (pattern.split_first(), name.split_first()) {
+        (None, None) => true,
+        (Some((b'*', rest)), _) => {
+            glob_matches(rest, name) || (!name.is_empty() && glob_matches(pattern, &name[1..]))
+        }
+        (Some((&p, rest)), Some((&n, name))) if p == b'?' || p == n => glob_matches(rest, name),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MANIFEST: &str = "# Removed from every install
+casper
+ubiquity*  remove
+
+nvidia-* if=!nvidia
+grub-pc arch=amd64,i386 if=efi,!hardware-support purge
+";
+
+    fn facts(bootloader: Bootloader, nvidia: bool) -> ManifestFacts<'static> {
+        ManifestFacts { arch: "amd64", bootloader, flags: 0, nvidia }
+    }
+
+    #[test]
+    fn legacy() {
+        let entries = parse_manifest("casper\nubiquity\n").unwrap();
+        assert_eq!(entries.len(), 2);
+        assert!(entries.iter().all(|entry| entry.action == RemoveAction::Purge));
+        assert!(entries.iter().all(|entry| entry.applies(&facts(Bootloader::Bios, false))));
+    }
+
+    #[test]
+    fn qualifiers() {
+        let entries = parse_manifest(MANIFEST).unwrap();
+        assert_eq!(
+            entries[3],
+            RemoveEntry {
+                pattern:    "grub-pc".into(),
+                action:     RemoveAction::Purge,
+                arches:     vec!["amd64".into(), "i386".into()],
+                conditions: vec![
+                    (false, RemoveCondition::Efi),
+                    (true, RemoveCondition::HardwareSupport)
+                ],
+            }
+        );
+
+        assert!(parse_manifest("casper if=wayland\n").is_err());
+        assert!(parse_manifest("casper keep\n").is_err());
+    }
+
+    #[test]
+    fn resolve() {
+        let entries = parse_manifest(MANIFEST).unwrap();
+        let installed = ["casper", "grub-pc", "nvidia-driver-470", "ubiquity", "ubiquity-casper"]
+            .iter()
+            .map(|&package| String::from(package))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            Removals::resolve(&entries, &facts(Bootloader::Efi, false), &installed),
+            Removals {
+                remove:  vec!["ubiquity".into(), "ubiquity-casper".into()],
+                purge:   vec!["casper".into(), "grub-pc".into(), "nvidia-driver-470".into()],
+                missing: Vec::new(),
+            }
+        );
+
+        assert_eq!(
+            Removals::resolve(&entries, &facts(Bootloader::Bios, true), &installed[..1]),
+            Removals {
+                remove:  Vec::new(),
+                purge:   vec!["casper".into()],
+                missing: vec!["ubiquity*".into()],
+            }
+        );
+    }
+
+    #[test]
+    fn globs() {
+        assert!(glob_matches(b"nvidia-*", b"nvidia-driver-470"));
+        assert!(glob_matches(b"*-dbg", b"libc6-dbg"));
+        assert!(glob_matches(b"linux-image-?.*", b"linux-image-6.2"));
+        assert!(!glob_matches(b"nvidia-*", b"libnvidia-gl"));
+        assert!(!glob_matches(b"casper", b"casper-md5check"));
+    }
+}
@@ -1,9 +1,14 @@
 mod bootloader;
 mod configure;
 mod initialize;
+mod manifest;
 mod partition;
+mod recovery;
+mod trim;
 
-pub use self::{bootloader::*, configure::*, initialize::*, partition::*};
+pub use self::{
+    bootloader::*, configure::*, initialize::*, manifest::*, partition::*, recovery::*, trim::*,
+};
 
 use std::{
     borrow::Cow,
@@ -13,15 +18,21 @@ use std::{
 };
 
 use sys_mount::*;
+use crate::errors::IoContext;
+use crate::installer::efivars::{EfiVars, EFIVARS};
 use crate::NO_EFI_VARIABLES;
 
 /// Installation step
-#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize)]
 pub enum Step {
     Backup,
     Init,
+    /// Verifies the integrity of the source, before the disks are modified.
+    Verify,
     Partition,
     Extract,
+    /// Copies the live system to the recovery partition.
+    Recovery,
     Configure,
     Bootloader,
 }
@@ -32,15 +43,70 @@ fn mount_cdrom(mount_dir: &Path) -> io::Result<Option<(UnmountDrop<Mount>, PathB
     mount_bind_if_exists(&cdrom_source, &cdrom_target).map(|res| res.map(|m| (m, cdrom_target)))
 }
 
-pub fn mount_efivars(mount_dir: &Path) -> io::Result<Option<UnmountDrop<Mount>>> {
+/// The EFI variables, bind-mounted into a chroot.
+///
+/// The variables are unmounted when this is dropped, as on failure. If efivarfs was
+/// remounted read-write for the boot entries to be written, it is remounted with its
+/// original flags first.
+pub struct EfivarsMount {
+    _mount:  UnmountDrop<Mount>,
+    restore: Option<MountFlags>,
+}
+
+impl Drop for EfivarsMount {
+    fn drop(&mut self) {
+        if let Some(flags) = self.restore {
+            info!("remounting efivars with its original flags");
+            let flags = MountFlags::REMOUNT | flags;
+            if let Err(why) = Mount::new(EFIVARS, EFIVARS, "efivarfs", flags, None) {
+                warn!("failed to restore the flags of efivars: {}", why);
+            }
+        }
+    }
+}
+
+/// Bind-mounts the EFI variables into the chroot at `mount_dir`, read-write, as the boot
+/// entries are written through them. Nothing is mounted if the system booted in BIOS mode.
+pub fn mount_efivars(mount_dir: &Path) -> io::Result<Option<EfivarsMount>> {
     if NO_EFI_VARIABLES.load(Ordering::Relaxed) {
         info!("was ordered to not mount the efivars directory");
-        Ok(None)
-    } else {
-        let efivars_source = Path::new("/sys/firmware/efi/efivars");
-        let efivars_target = mount_dir.join("sys/firmware/efi/efivars");
-        mount_bind_if_exists(&efivars_source, &efivars_target)
+        return Ok(None);
     }
+
+    let source = Path::new(EFIVARS);
+    if !source.exists() {
+        return Ok(None);
+    }
+
+    let target = mount_dir.join("sys/firmware/efi/efivars");
+    fs::create_dir_all(&target)
+        .with_context(|err| format!("failed to create {}: {}", target.display(), err))?;
+    let mount = Mount::new(source, &target, "none", MountFlags::BIND, None)
+        .with_context(|err| format!("failed to mount efivars in the chroot: {}", err))?
+        .into_unmount_drop(UnmountFlags::empty());
+
+    // The bind has the flags of the mount of the live system, which may be read-only. Only
+    // the read-only flag is cleared, and it is set again once the variables are unmounted.
+    let mut restore = None;
+    if EfiVars::open(&target).map_or(false, |vars| vars.read_only()) {
+        info!("remounting efivars read-write");
+        let original = EfiVars::open(source).map_or(MountFlags::RDONLY, |vars| {
+            MountFlags::from_bits_truncate(vars.mount_flags() as _)
+        });
+
+        let writable = MountFlags::REMOUNT | (original - MountFlags::RDONLY);
+        let remounted = Mount::new(source, source, "efivarfs", writable, None).and_then(|_| {
+            restore = Some(original);
+            let flags = MountFlags::BIND | MountFlags::REMOUNT;
+            Mount::new(&target, &target, "none", flags, None)
+        });
+
+        if let Err(why) = remounted {
+            warn!("failed to remount efivars read-write: {}", why);
+        }
+    }
+
+    Ok(Some(EfivarsMount { _mount: mount, restore }))
 }
 
 fn mount_bind_if_exists(source: &Path, target: &Path) -> io::Result<Option<UnmountDrop<Mount>>> {
@@ -1,44 +1,59 @@
-use crate::disks::{operations::FormatPartitions, Disks};
+use crate::disks::{
+    operations::{report_move_progress, FormatPartitions},
+    Disks, EncryptionProgress, REMOVE, SOURCE,
+};
 use crate::errors::IoContext;
-use crate::external::{blockdev, pvs, vgactivate, vgdeactivate};
+use crate::external::{pvs, reread_partition_table, settle_nodes, vgactivate, vgdeactivate};
+use crate::installer::CancellationToken;
+use crate::logging;
 use itertools::Itertools;
 use rayon::{self, prelude::*};
-use std::{collections::BTreeMap, io, path::PathBuf, thread::sleep, time::Duration};
+use std::{
+    collections::BTreeMap,
+    io, iter, panic,
+    path::{Path, PathBuf},
+    sync::mpsc,
+    thread,
+    time::Duration,
+};
 
-pub fn partition<F: FnMut(i32)>(disks: &mut Disks, mut callback: F) -> io::Result<()> {
-    let (pvs_result, commit_result): (
-        io::Result<BTreeMap<PathBuf, Option<String>>>,
-        io::Result<()>,
-    ) = rayon::join(
-        || {
-            // This collection of physical volumes and their optional volume groups
-            // will be used to obtain a list of volume groups associated with our
-            // modified partitions.
-            pvs().with_context(|why| format!("failed to get PVS map: {}", why))
-        },
-        || {
-            // Perform layout changes serially, due to libparted thread safety issues,
-            // and collect a list of partitions to format which can be done in parallel.
-            // Once partitions have been formatted in parallel, reload the disk configuration.
-            let mut partitions_to_format = FormatPartitions(Vec::new());
-            for disk in disks.get_physical_devices_mut() {
-                info!("{}: Committing changes to disk", disk.path().display());
-                if let Some(partitions) =
-                    disk.commit().with_context(|why| format!("disk commit error: {}", why))?
-                {
-                    partitions_to_format.0.extend_from_slice(&partitions.0);
-                }
-            }
+/// How long udev is given to create the device nodes of the new layout.
+const SETTLE_TIMEOUT: Duration = Duration::from_secs(30);
 
-            partitions_to_format.format()?;
+/// Reports the percent of the step, an operation that may take a while, and the bytes which
+/// partitions that are being moved have copied.
+pub fn partition<F: FnMut(i32, Option<String>, Option<(u64, u64)>)>(
+    disks: &mut Disks,
+    cancel: &CancellationToken,
+    mut callback: F,
+) -> io::Result<()> {
+    // Once the disks are committed, their devices are settled rather than left half-way.
+    if cancel.is_cancelled() {
+        return Err(io::Error::new(io::ErrorKind::Interrupted, "partitioning was cancelled"));
+    }
 
-            disks.physical.iter_mut().map(|disk| disk.reload().map_err(io::Error::from)).collect()
-        },
-    );
+    // Moving a partition copies all of its data, so the progress of each move is reported.
+    let (sender, moves) = mpsc::channel();
+    report_move_progress(Some(sender));
 
+    let sink = logging::current();
+    let (pvs_result, commit_result) = thread::scope(|scope| {
+        let job = scope.spawn(|| logging::within(sink, || commit(disks)));
+
+        // The sender is kept until it is unset, so the job is checked for completion instead.
+        while !job.is_finished() {
+            if let Ok(bytes) = moves.recv_timeout(Duration::from_millis(100)) {
+                callback(0, Some("Moving partitions".into()), Some(bytes));
+            }
+        }
+
+        job.join().unwrap_or_else(|why| panic::resume_unwind(why))
+    });
+
+    report_move_progress(None);
     let pvs = commit_result.and(pvs_result)?;
 
-    callback(25);
+    callback(25, None, None);
 
     // Utilizes the physical volume collection to generate a vector of volume
     // groups which we will need to deactivate pre-`blockdev`, and will be
@@ -55,27 +70,84 @@ pub fn partition<F: FnMut(i32)>(disks: &mut Disks, mut callback: F) -> io::Resul
     // Deactivate logical volumes so that blockdev will not fail.
     vgs.iter().map(|vg| vgdeactivate(vg)).collect::<io::Result<()>>()?;
 
-    // Ensure that the logical volumes have had time to deactivate.
-    sleep(Duration::from_secs(1));
-    callback(50);
+    // Ensure that the device-mapper nodes of the logical volumes have been removed.
+    settle(iter::empty())?;
+    callback(50, None, None);
 
     // This is to ensure that everything's been written and the OS is ready to
     // proceed.
+    let sink = logging::current();
     disks.physical.par_iter().for_each(|disk| {
-        let _ = blockdev(&disk.path(), &["--flushbufs", "--rereadpt"]);
+        let _log = logging::adopt(sink);
+        if let Err(why) = reread_partition_table(disk.path()) {
+            warn!("{}: failed to reread partition table: {}", disk.path().display(), why);
+        }
     });
 
-    // Give a bit of time to ensure that logical volumes can be re-activated.
-    sleep(Duration::from_secs(1));
-    callback(75);
+    // The partitions must exist before their logical volumes can be re-activated.
+    settle(disks.get_physical_partitions().map(|part| part.device_path.as_path()))?;
+    callback(75, None, None);
 
     // Reactivate the logical volumes.
     vgs.iter().map(|vg| vgactivate(vg)).collect::<io::Result<()>>()?;
 
+    // Formatting LUKS volumes is slow, so each volume is reported as it is encrypted.
     let res = disks
-        .commit_logical_partitions()
-        .with_context(|why| format!("failed to commit logical partitions: {}", why));
+        .commit_logical_partitions(|progress| match progress {
+            EncryptionProgress::Encrypting(volume) => {
+                info!("encrypting volume {}", volume);
+                callback(75, Some(format!("Encrypting volume {}", volume)), None);
+            }
+            EncryptionProgress::Encrypted(volume) => {
+                info!("encrypted volume {}", volume);
+                callback(75, Some(format!("Encrypted volume {}", volume)), None);
+            }
+        })
+        .with_context(|why| format!("failed to commit logical partitions: {}", why))
+        .and_then(|_| {
+            // Volumes which existed before may belong to groups that were never activated.
+            let created = disks
+                .get_logical_partitions()
+                .filter(|part| !part.flag_is_enabled(SOURCE) && !part.flag_is_enabled(REMOVE));
+            settle(created.map(|part| part.device_path.as_path()))
+        });
 
-    callback(100);
+    callback(100, None, None);
     res
 }
+
+/// Waits for udev to handle the changes to the disks, and then for each of the given device
+/// nodes to exist, for at most `SETTLE_TIMEOUT`.
+fn settle<'a, I: Iterator<Item = &'a Path>>(nodes: I) -> io::Result<()> {
+    settle_nodes(nodes, SETTLE_TIMEOUT)
+}
+
+/// Commits the layout of each disk, while the physical volumes are collected.
+fn commit(disks: &mut Disks) -> (io::Result<BTreeMap<PathBuf, Option<String>>>, io::Result<()>) {
+    rayon::join(
+        || {
+            // This collection of physical volumes and their optional volume groups
+            // will be used to obtain a list of volume groups associated with our
+            // modified partitions.
+            pvs().with_context(|why| format!("failed to get PVS map: {}", why))
+        },
+        || {
+            // Perform layout changes serially, due to libparted thread safety issues,
+            // and collect a list of partitions to format which can be done in parallel.
+            // Once partitions have been formatted in parallel, reload the disk configuration.
+            let mut partitions_to_format = FormatPartitions(Vec::new());
+            for disk in disks.get_physical_devices_mut() {
+                info!("{}: Committing changes to disk", disk.path().display());
+                if let Some(partitions) =
+                    disk.commit().with_context(|why| format!("disk commit error: {}", why))?
+                {
+                    partitions_to_format.0.extend_from_slice(&partitions.0);
+                }
+            }
+
+            partitions_to_format.format()?;
+
+            disks.physical.iter_mut().map(|disk| disk.reload().map_err(io::Error::from)).collect()
+        },
+    )
+}
@@ -0,0 +1,465 @@
+use super::BootloaderChoice;
+use crate::errors::{IntoIoResult, IoContext};
+use crate::installer::CancellationToken;
+use crate::squashfs::{self, Progress};
+use os_release::OsRelease;
+use partition_identity::PartitionID;
+use proc_mounts::{MountInfo, MountIter};
+use std::{
+    fs::{self, Permissions},
+    io,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+};
+use sys_mount::*;
+use tempdir::TempDir;
+
+/// The manifest of the recovery partition, which records the release that it reinstalls,
+/// and the checksum of each file copied to it.
+const MANIFEST: &str = "recovery.manifest";
+
+/// Directories of the live medium which are copied beside its `casper` directory.
+const MEDIUM_DIRS: &[&str] = &[".disk", "dists", "pool"];
+
+/// The kernels and initrds which a live medium may have, in the order of preference.
+const KERNELS: &[&str] = &["vmlinuz.efi", "vmlinuz"];
+const INITRDS: &[&str] = &["initrd.gz", "initrd.lz", "initrd"];
+
+/// The size of the largest file which FAT32 can hold.
+const FAT32_MAX_FILE: u64 = 0xFFFF_FFFF;
+
+/// Where the GRUB menu entry of the recovery partition is written, within the install.
+const GRUB_SCRIPT: &str = "etc/grub.d/42_distinst_recovery";
+
+/// A partition which holds a copy of the live system, from which the install may be
+/// reinstalled.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecoveryConfig {
+    /// The FAT32 partition to copy the live system to, which is mounted at `/recovery`.
+    pub partition: PathBuf,
+    /// Where the live system is copied from.
+    pub source:    RecoverySource,
+}
+
+/// Where the files of a recovery partition are copied from.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RecoverySource {
+    /// An ISO of the live system, which is mounted while it is copied.
+    Iso(PathBuf),
+    /// The `casper` directory of a live medium, such as `/cdrom/casper`. The `.disk`,
+    /// `dists`, and `pool` directories beside it are copied as well.
+    Casper(PathBuf),
+}
+
+impl RecoverySource {
+    /// Copies from the `casper` directory if the path is a directory, or else from an ISO.
+    pub fn from_path(path: PathBuf) -> Self {
+        if path.is_dir() {
+            RecoverySource::Casper(path)
+        } else {
+            RecoverySource::Iso(path)
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        match *self {
+            RecoverySource::Iso(ref path) | RecoverySource::Casper(ref path) => path,
+        }
+    }
+
+    /// The number of bytes which the recovery partition must hold.
+    pub fn size(&self) -> io::Result<u64> {
+        match *self {
+            RecoverySource::Iso(ref iso) => Ok(iso.metadata()?.len()),
+            RecoverySource::Casper(ref casper) => {
+                Ok(artifacts(casper, "casper")?.iter().map(|artifact| artifact.size).sum())
+            }
+        }
+    }
+
+    /// Validates that each file fits within the FAT32 file system of the recovery partition,
+    /// mounting an ISO to find its files.
+    pub fn verify_files(&self) -> io::Result<()> {
+        let iso = match *self {
+            RecoverySource::Iso(ref iso) => Some(mount_iso(iso)?),
+            RecoverySource::Casper(_) => None,
+        };
+
+        let casper = match iso {
+            Some((_, ref dir)) => dir.path().join("casper"),
+            None => self.path().to_path_buf(),
+        };
+
+        verify_sizes(&artifacts(&casper, "casper")?)
+    }
+
+    /// The device of the file system which holds the source, followed by the disk of the
+    /// device if it is a partition, as found from the mounts of the live system.
+    pub fn devices(&self) -> io::Result<Vec<PathBuf>> {
+        let path = self.path().canonicalize()?;
+        let depth = |info: &MountInfo| info.dest.components().count();
+        let mut mount: Option<MountInfo> = None;
+        for info in MountIter::new()? {
+            let info = info?;
+            // The last of the mounts at the deepest directory is the one which is visible.
+            let deeper = mount.as_ref().map_or(true, |mount| depth(&info) >= depth(mount));
+            if path.starts_with(&info.dest) && deeper {
+                mount = Some(info);
+            }
+        }
+
+        let device = match mount {
+            Some(mount) => mount.source,
+            None => return Ok(Vec::new()),
+        };
+
+        let mut devices = vec![device.clone()];
+        if let Some(name) = device.file_name() {
+            let sys = Path::new("/sys/class/block").join(name);
+            if sys.join("partition").exists() {
+                let disk = sys.canonicalize()?;
+                if let Some(disk) = disk.parent().and_then(Path::file_name) {
+                    devices.push(Path::new("/dev").join(disk));
+                }
+            }
+        }
+
+        Ok(devices)
+    }
+}
+
+/// Fails on the first file which is larger than FAT32 can hold.
+fn verify_sizes(artifacts: &[Artifact]) -> io::Result<()> {
+    match artifacts.iter().find(|artifact| artifact.size > FAT32_MAX_FILE) {
+        Some(artifact) => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "{} is {} bytes, which is more than the FAT32 recovery partition can hold in a \
+                 file",
+                artifact.source.display(),
+                artifact.size
+            ),
+        )),
+        None => Ok(()),
+    }
+}
+
+/// A file which is copied to the recovery partition.
+#[derive(Debug, PartialEq)]
+struct Artifact {
+    source: PathBuf,
+    /// The path of the copy, relative to the root of the recovery partition.
+    dest:   PathBuf,
+    size:   u64,
+}
+
+/// Copies the live system to the recovery partition, which is mounted at `/recovery` in the
+/// target, records it in a manifest, and adds a boot entry for it.
+///
+/// The callback is given the percent copied, and the path of the file being copied.
+pub fn recovery<F: FnMut(i32, Option<String>)>(
+    recovery: &RecoveryConfig,
+    mount_dir: &Path,
+    loader: BootloaderChoice,
+    iso_os_release: &OsRelease,
+    cancel: &CancellationToken,
+    mut callback: F,
+) -> io::Result<()> {
+    let target = mount_dir.join("recovery");
+    let device = &recovery.partition;
+    let uuid = PartitionID::get_uuid(device)
+        .into_io_result(|| format!("recovery partition {} has no UUID", device.display()))?;
+    let partuuid = PartitionID::get_partuuid(device)
+        .into_io_result(|| format!("recovery partition {} has no PartUUID", device.display()))?;
+
+    // An ISO is mounted until its files have been copied.
+    let iso = match recovery.source {
+        RecoverySource::Iso(ref iso) => Some(mount_iso(iso)?),
+        RecoverySource::Casper(_) => None,
+    };
+
+    let casper = match iso {
+        Some((_, ref dir)) => dir.path().join("casper"),
+        None => recovery.source.path().to_path_buf(),
+    };
+
+    let casper_name = ["casper-", &uuid.id].concat();
+    let artifacts = artifacts(&casper, &casper_name)?;
+    let total = artifacts.iter().map(|artifact| artifact.size).sum();
+
+    info!("copying {} bytes from {} to the recovery partition", total, casper.display());
+    erase(&target).with_context(|err| format!("failed to erase {:?}: {}", target, err))?;
+
+    let mut bytes = 0;
+    let mut checksums = Vec::with_capacity(artifacts.len());
+    for artifact in &artifacts {
+        let dest = target.join(&artifact.dest);
+        if let Some(parent) = dest.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let message = artifact.dest.display().to_string();
+        let checksum =
+            squashfs::copy_with_checksum(&artifact.source, &dest, cancel.flag(), |progress| {
+                let progress = Progress { bytes: bytes + progress.bytes, total, path: None };
+                callback(progress.percent(), Some(message.clone()));
+            })
+            .with_context(|err| format!("failed to copy {:?}: {}", artifact.source, err))?;
+
+        bytes += artifact.size;
+        checksums.push((artifact.dest.display().to_string(), checksum));
+    }
+
+    drop(iso);
+    let casper = target.join(&casper_name);
+
+    let manifest = manifest(iso_os_release, recovery.source.path(), &checksums);
+    fs::write(target.join(MANIFEST), manifest)
+        .with_context(|err| format!("failed to write the recovery manifest: {}", err))?;
+
+    let kernel = find(&casper, KERNELS).into_io_result(|| "no kernel was found in casper")?;
+    let initrd = find(&casper, INITRDS).into_io_result(|| "no initrd was found in casper")?;
+
+    let name = super::normalize_os_release_name(&iso_os_release.name);
+    let boot = Boot { casper: &casper_name, kernel, initrd, partuuid: &partuuid.id };
+    if loader == BootloaderChoice::SystemdBoot {
+        // systemd-boot can only load the kernel and initrd from the ESP.
+        let recovery = ["Recovery-", &uuid.id].concat();
+        let efi_dir = mount_dir.join("boot/efi/EFI").join(&recovery);
+        fs::create_dir_all(&efi_dir)?;
+        fs::copy(casper.join(kernel), efi_dir.join(kernel))?;
+        fs::copy(casper.join(initrd), efi_dir.join(initrd))?;
+
+        let entries = mount_dir.join("boot/efi/loader/entries");
+        fs::create_dir_all(&entries)?;
+        let entry = entries.join([&recovery, ".conf"].concat());
+        fs::write(entry, boot.systemd_boot(&name, &recovery))
+            .with_context(|err| format!("failed to write recovery EFI entry: {}", err))?;
+    } else {
+        let script = mount_dir.join(GRUB_SCRIPT);
+        fs::write(&script, boot.grub(&iso_os_release.pretty_name, &uuid.id))
+            .and_then(|_| fs::set_permissions(&script, Permissions::from_mode(0o755)))
+            .with_context(|err| format!("failed to write recovery GRUB entry: {}", err))?;
+    }
+
+    callback(100, None);
+    Ok(())
+}
+
+/// The files of the live medium which the `casper` directory belongs to, with the contents
+/// of that directory copied to `casper_name`.
+fn artifacts(casper: &Path, casper_name: &str) -> io::Result<Vec<Artifact>> {
+    let mut artifacts = Vec::new();
+    if let Some(medium) = casper.parent() {
+        for dir in MEDIUM_DIRS {
+            let path = medium.join(dir);
+            if path.exists() {
+                walk(&path, Path::new(dir), &mut artifacts)?;
+            }
+        }
+    }
+
+    walk(casper, Path::new(casper_name), &mut artifacts)?;
+    Ok(artifacts)
+}
+
+/// Collects the files within `dir`, following links to files, but not to directories, which
+/// a live medium links to itself with.
+fn walk(dir: &Path, dest: &Path, artifacts: &mut Vec<Artifact>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        let dest = dest.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            walk(&path, &dest, artifacts)?;
+        } else {
+            let metadata = path.metadata()?;
+            if metadata.is_file() {
+                artifacts.push(Artifact { source: path, dest, size: metadata.len() });
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Removes everything on the recovery partition, so that only the new copy remains.
+fn erase(target: &Path) -> io::Result<()> {
+    for entry in fs::read_dir(target)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            fs::remove_dir_all(entry.path())?;
+        } else {
+            fs::remove_file(entry.path())?;
+        }
+    }
+
+    Ok(())
+}
+
+fn find(dir: &Path, names: &[&'static str]) -> Option<&'static str> {
+    names.iter().cloned().find(|name| dir.join(name).exists())
+}
+
+/// Mounts the ISO read-only to a temporary directory, which is removed once it has been
+/// unmounted.
+fn mount_iso(iso: &Path) -> io::Result<(UnmountDrop<Mount>, TempDir)> {
+    let dir = TempDir::new("distinst-recovery")?;
+    let mount = Mount::new(iso, dir.path(), "iso9660", MountFlags::RDONLY, None)
+        .with_context(|err| format!("failed to mount {:?}: {}", iso, err))?
+        .into_unmount_drop(UnmountFlags::DETACH);
+
+    Ok((mount, dir))
+}
+
+/// The release on the recovery partition, followed by the SHA-256 checksum of each file in
+/// the format of `sha256sum`, which may verify them from the root of the partition.
+fn manifest(release: &OsRelease, source: &Path, checksums: &[(String, String)]) -> String {
+    let mut manifest = format!(
+        "# NAME={}\n# VERSION={}\n# SOURCE={}\n",
+        release.name,
+        release.version,
+        source.display()
+    );
+
+    for &(ref path, ref checksum) in checksums {
+        manifest.push_str(&format!("{}  {}\n", checksum, path));
+    }
+
+    manifest
+}
+
+/// How the live system on the recovery partition is booted.
+struct Boot<'a> {
+    casper:   &'a str,
+    kernel:   &'a str,
+    initrd:   &'a str,
+    partuuid: &'a str,
+}
+
+impl<'a> Boot<'a> {
+    fn options(&self) -> String {
+        format!(
+            "boot=casper hostname=recovery userfullname=Recovery username=recovery \
+             live-media-path=/{} live-media=/dev/disk/by-partuuid/{} noprompt",
+            self.casper, self.partuuid
+        )
+    }
+
+    /// A systemd-boot entry, which boots the copies of the kernel and initrd in
+    /// `/EFI/{recovery}` on the ESP.
+    fn systemd_boot(&self, name: &str, recovery: &str) -> String {
+        format!(
+            r#"title {0} recovery
+linux /EFI/{1}/{2}
+initrd /EFI/{1}/{3}
+options {4}
+"#,
+            name,
+            recovery,
+            self.kernel,
+            self.initrd,
+            self.options()
+        )
+    }
+
+    /// A script for `/etc/grub.d`, which boots the kernel and initrd directly from the
+    /// recovery partition with the given UUID.
+    fn grub(&self, name: &str, uuid: &str) -> String {
+        format!(
+            r#"#!/bin/sh
+exec tail -n +3 $0
+menuentry '{0} recovery' {{
+    search --no-floppy --fs-uuid --set=root {1}
+    linux /{2}/{3} {4}
+    initrd /{2}/{5}
+}}
+"#,
+            name,
+            uuid,
+            self.casper,
+            self.kernel,
+            self.options(),
+            self.initrd
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BOOT: Boot = Boot {
+        casper:   "casper-1234-ABCD",
+        kernel:   "vmlinuz.efi",
+        initrd:   "initrd.gz",
+        partuuid: "5678",
+    };
+
+    #[test]
+    fn entries() {
+        let options = "boot=casper hostname=recovery userfullname=Recovery username=recovery \
+                       live-media-path=/casper-1234-ABCD live-media=/dev/disk/by-partuuid/5678 \
+                       noprompt";
+
+        assert_eq!(
+            BOOT.systemd_boot("Pop!_OS", "Recovery-1234-ABCD"),
+            format!(
+                r#"title Pop!_OS recovery
+linux /EFI/Recovery-1234-ABCD/vmlinuz.efi
+initrd /EFI/Recovery-1234-ABCD/initrd.gz
+options {}
+"#,
+                options
+            )
+        );
+
+        assert_eq!(
+            BOOT.grub("Ubuntu 22.04 LTS", "1234-ABCD"),
+            format!(
+                r#"#!/bin/sh
+exec tail -n +3 $0
+menuentry 'Ubuntu 22.04 LTS recovery' {{
+    search --no-floppy --fs-uuid --set=root 1234-ABCD
+    linux /casper-1234-ABCD/vmlinuz.efi {}
+    initrd /casper-1234-ABCD/initrd.gz
+}}
+"#,
+                options
+            )
+        );
+    }
+
+    #[test]
+    fn files() {
+        let medium = TempDir::new("distinst").unwrap();
+        let medium = medium.path();
+        for dir in &[".disk", "casper", "dists/jammy", "isolinux"] {
+            fs::create_dir_all(medium.join(dir)).unwrap();
+        }
+
+        for file in &[".disk/info", "casper/filesystem.squashfs", "dists/jammy/Release"] {
+            fs::write(medium.join(file), "data").unwrap();
+        }
+
+        std::os::unix::fs::symlink(".", medium.join("dists/stable")).unwrap();
+
+        let mut artifacts = artifacts(&medium.join("casper"), "casper-1234").unwrap();
+        artifacts.sort_by(|a, b| a.dest.cmp(&b.dest));
+
+        let dests = artifacts.iter().map(|a| a.dest.to_str().unwrap()).collect::<Vec<_>>();
+        assert_eq!(
+            dests,
+            vec![".disk/info", "casper-1234/filesystem.squashfs", "dists/jammy/Release"]
+        );
+        assert_eq!(RecoverySource::Casper(medium.join("casper")).size().unwrap(), 12);
+        assert!(RecoverySource::Casper(medium.join("casper")).verify_files().is_ok());
+
+        let squashfs = Artifact {
+            source: medium.join("casper/filesystem.squashfs"),
+            dest:   "casper/filesystem.squashfs".into(),
+            size:   FAT32_MAX_FILE + 1,
+        };
+        assert!(verify_sizes(&[squashfs]).is_err());
+    }
+}
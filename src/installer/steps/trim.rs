@@ -0,0 +1,38 @@
+use crate::disks::is_rotational;
+use crate::external::fstrim;
+use proc_mounts::MountList;
+use std::path::Path;
+
+/// Discards the unused blocks of each file system mounted within the install at `root`, as
+/// the extraction overwrites large regions which the drive would otherwise consider in use.
+///
+/// File systems on rotational disks, and those which are mounted with `discard`, are
+/// skipped. The install does not fail if a file system can't be trimmed.
+pub fn trim(root: &Path) {
+    let mounts = match MountList::new() {
+        Ok(mounts) => mounts,
+        Err(why) => {
+            warn!("unable to trim the install, as the mounts could not be read: {}", why);
+            return;
+        }
+    };
+
+    let targets = mounts
+        .0
+        .iter()
+        .filter(|mount| mount.dest.starts_with(root) && mount.source.starts_with("/dev"));
+
+    for mount in targets {
+        let dest = mount.dest.display();
+        if mount.options.iter().any(|option| option == "discard") {
+            info!("{}: not trimming, as it is mounted with discard", dest);
+        } else if is_rotational(&mount.source) {
+            info!("{}: not trimming, as {} is rotational", dest, mount.source.display());
+        } else {
+            match fstrim(&mount.dest) {
+                Ok(bytes) => info!("{}: trimmed {} bytes", dest, bytes),
+                Err(why) => warn!("{}: unable to trim: {}", dest, why),
+            }
+        }
+    }
+}
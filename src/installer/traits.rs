@@ -1,32 +1,56 @@
 use self::FileSystem::*;
-use super::bitflags::FileSystemSupport;
-use disk_types::{BlockDeviceExt, FileSystem, PartitionExt};
-use crate::disks::{Disks};
+use super::{bitflags::FileSystemSupport, raid};
+use disk_types::{BlockDeviceExt, FileSystem, PartitionExt, SectorExt};
+use crate::disks::{is_rotational, Bootloader, Disks, REMOVE};
 use crate::errors::IntoIoResult;
 use crate::external::generate_unique_id;
-use fstab_generate::BlockInfo;
+use fstab_generate::{BlockInfo, FstabId};
 use crate::misc::hasher;
 use partition_identity::PartitionID;
 use std::{
-    borrow::Cow,
-    ffi::{OsStr, OsString},
+    ffi::OsString,
     io,
+    path::{Path, PathBuf},
 };
 
 pub trait InstallerDiskOps: Sync {
-    /// Generates the crypttab and fstab files in memory.
-    fn generate_fstabs(&self) -> (OsString, OsString);
+    /// Generates the crypttab and fstab files in memory, identifying file systems in the
+    /// fstab with the given strategy.
+    fn generate_fstabs(&self, id: FstabId) -> (OsString, OsString);
+
+    /// Find the block info of the partition mounted at `mount` from this disks object,
+    /// identifying it with the given strategy.
+    fn get_block_info_of(&self, mount: &str, id: FstabId) -> io::Result<BlockInfo>;
 
-    /// Find the root partition's block info from this disks object.
-    fn get_block_info_of(&self, mount: &str) -> io::Result<BlockInfo>;
+    /// The UUID of the largest swap partition, which the install resumes from after
+    /// hibernating.
+    fn get_resume_uuid(&self) -> Option<String>;
+
+    /// The passphrases of the encrypted partitions, which must not be written to logs.
+    fn get_passphrases(&self) -> Vec<&str>;
 
     /// Reports file systems that need to be supported in the install.
     fn get_support_flags(&self) -> FileSystemSupport;
+
+    /// The mount targets of the install, with the bytes that each may hold, which is not
+    /// known for file systems that will not be formatted.
+    fn get_target_capacities(&self) -> Vec<(&Path, Option<u64>)>;
+
+    /// The disk which the bootloader is installed to, with the number of the partition that
+    /// it is installed in, which is 0 when it is not installed in a partition.
+    fn get_boot_device(&self, bootloader: Bootloader) -> io::Result<(PathBuf, i32)>;
+
+    /// The devices which the bootloader is installed to, as with `get_boot_device`: each
+    /// member of the RAID1 array that boots the install, or else the boot device alone.
+    fn get_boot_devices(&self, bootloader: Bootloader) -> io::Result<Vec<(PathBuf, i32)>>;
+
+    /// The PReP boot partition which GRUB for Open Firmware is installed to.
+    fn get_prep_device(&self) -> io::Result<PathBuf>;
 }
 
 impl InstallerDiskOps for Disks {
     /// Generates the crypttab and fstab files in memory.
-    fn generate_fstabs(&self) -> (OsString, OsString) {
+    fn generate_fstabs(&self, id: FstabId) -> (OsString, OsString) {
         let &Disks { ref logical, ref physical, .. } = self;
 
         info!("generating /etc/crypttab & /etc/fstab in memory");
@@ -40,7 +64,7 @@ impl InstallerDiskOps for Disks {
                     .as_ref()
                     .into_iter()
                     .chain(x.partitions.iter())
-                    .map(|p| (true, &None, p))
+                    .map(|p| (true, &None, None, p))
             })
             .chain(logical.iter().flat_map(|x| {
                 let luks_parent = &x.luks_parent;
@@ -49,48 +73,65 @@ impl InstallerDiskOps for Disks {
                     .as_ref()
                     .into_iter()
                     .chain(x.partitions.iter())
-                    .map(move |p| (is_unencrypted, luks_parent, p))
+                    .map(move |p| {
+                        (is_unencrypted && p.encryption.is_none(), luks_parent, Some(x), p)
+                    })
             }));
 
         let mut swap_uuids: Vec<u64> = Vec::new();
         let mut crypt_ids: Vec<u64> = Vec::new();
 
-        for (is_unencrypted, luks_parent, partition) in partitions {
-            if let Some(&(_, Some(ref enc))) = partition.volume_group.as_ref() {
-                let password: Cow<'static, OsStr> =
-                    match (enc.password.is_some(), enc.keydata.as_ref()) {
-                        (true, None) => Cow::Borrowed(OsStr::new("none")),
-                        (false, None) => Cow::Borrowed(OsStr::new("/dev/urandom")),
-                        (true, Some(_key)) => unimplemented!(),
-                        (false, Some(&(_, ref key))) => {
-                            let path = key
-                                .clone()
-                                .expect("should have been populated")
-                                .1
-                                .join(&enc.physical_volume);
-                            Cow::Owned(path.into_os_string())
+        for (is_unencrypted, luks_parent, lvm_device, partition) in partitions {
+            // Logical volumes with an encrypted file system are unlocked by their own entry.
+            if let (Some(enc), Some(lvm_device)) = (partition.encryption.as_ref(), lvm_device) {
+                let name = partition.name.as_ref().expect("logical partitions should have names");
+                let volume = lvm_device.get_volume_path(name);
+                match enc.crypttab_source(&volume) {
+                    Some(source) => {
+                        let id = hasher(&enc.physical_volume);
+                        if !crypt_ids.contains(&id) {
+                            crypt_ids.push(id);
+                            crypttab.push(enc.crypttab_entry(&source, is_rotational(&volume)));
                         }
-                    };
+                    }
+                    None => warn!("unable to find UUID for {} -- skipping", volume.display()),
+                }
+            }
 
+            if let Some(&(_, Some(ref enc))) = partition.volume_group.as_ref() {
                 let ppath = partition.get_device_path();
                 let luks_path = luks_parent.as_ref().map_or(ppath, |x| &x);
 
+                // A file system directly within a reused LUKS container.
+                let fs_on_luks = lvm_device.map_or(false, |d| d.file_system.is_some());
+                if fs_on_luks && luks_parent.is_some() && partition.target.is_some() {
+                    match enc.crypttab_source(luks_path) {
+                        Some(source) => {
+                            let id = hasher(&enc.physical_volume);
+                            if !crypt_ids.contains(&id) {
+                                crypt_ids.push(id);
+                                crypttab.push(
+                                    enc.crypttab_entry(&source, is_rotational(luks_path)),
+                                );
+                            }
+                        }
+                        None => warn!("unable to find UUID for {} -- skipping", ppath.display()),
+                    }
+                }
+
                 for logical in logical {
                     if let Some(ref parent) = logical.luks_parent {
                         if parent == ppath {
                             if logical.partitions.iter().any(|p| p.target.is_some()) {
-                                match PartitionID::get_uuid(luks_path) {
-                                    Some(uuid) => {
+                                match enc.crypttab_source(luks_path) {
+                                    Some(source) => {
                                         let id = hasher(&enc.physical_volume);
                                         if !crypt_ids.contains(&id) {
                                             crypt_ids.push(id);
-
-                                            crypttab.push(&enc.physical_volume);
-                                            crypttab.push(" UUID=");
-                                            crypttab.push(&uuid.id);
-                                            crypttab.push(" ");
-                                            crypttab.push(&password);
-                                            crypttab.push(" luks\n");
+                                            crypttab.push(enc.crypttab_entry(
+                                                &source,
+                                                is_rotational(luks_path),
+                                            ));
                                         }
                                     }
                                     None => warn!(
@@ -103,21 +144,40 @@ impl InstallerDiskOps for Disks {
                         }
                     }
                 }
-                if let Some(blockinfo) = partition.get_block_info() {
+                if let Some(blockinfo) = partition.get_block_info_with(id) {
                     blockinfo.write_entry(&mut fstab);
                 }
             } else if partition.is_swap() {
                 if is_unencrypted {
-                    match PartitionID::get_uuid(&partition.get_device_path()) {
-                        Some(uuid) => {
+                    let path = partition.get_device_path();
+                    let source = if partition.is_random_swap() {
+                        // Swap that is created at boot has no signature to obtain a UUID from.
+                        match lvm_device {
+                            Some(device) => {
+                                let name = partition
+                                    .name
+                                    .as_ref()
+                                    .expect("logical partitions should have names");
+                                Some(device.get_volume_path(name).into_os_string())
+                            }
+                            None => PartitionID::get_partuuid(path)
+                                .map(|id| OsString::from(["PARTUUID=", &id.id].concat())),
+                        }
+                    } else {
+                        PartitionID::get_uuid(path)
+                            .map(|id| OsString::from(["UUID=", &id.id].concat()))
+                    };
+
+                    match source {
+                        Some(source) => {
                             let unique_id = generate_unique_id("cryptswap", &swap_uuids)
                                 .unwrap_or_else(|_| "cryptswap".into());
 
                             swap_uuids.push(hasher(&unique_id));
 
                             crypttab.push(&unique_id);
-                            crypttab.push(" UUID=");
-                            crypttab.push(&uuid.id);
+                            crypttab.push(" ");
+                            crypttab.push(&source);
                             crypttab.push(
                                 " /dev/urandom swap,plain,offset=1024,cipher=aes-xts-plain64,size=512\n",
                             );
@@ -127,16 +187,13 @@ impl InstallerDiskOps for Disks {
                                     .concat(),
                             );
                         }
-                        None => warn!(
-                            "unable to find UUID for {} -- skipping",
-                            partition.get_device_path().display()
-                        ),
+                        None => warn!("unable to find UUID for {} -- skipping", path.display()),
                     }
                 } else {
                     fstab.push(partition.get_device_path());
                     fstab.push("  none  swap  defaults  0  0\n");
                 }
-            } else if let Some(blockinfo) = partition.get_block_info() {
+            } else if let Some(blockinfo) = partition.get_block_info_with(id) {
                 blockinfo.write_entry(&mut fstab);
             }
         }
@@ -150,17 +207,41 @@ impl InstallerDiskOps for Disks {
         (crypttab, fstab)
     }
 
-    fn get_block_info_of(&self, path: &str) -> io::Result<BlockInfo> {
+    fn get_block_info_of(&self, path: &str, id: FstabId) -> io::Result<BlockInfo> {
         self.get_partitions()
-            .filter_map(|part| part.get_block_info())
+            .filter_map(|part| part.get_block_info_with(id))
             .find(|entry| entry.mount() == path)
             .into_io_result(|| "root partition not found")
     }
 
+    fn get_resume_uuid(&self) -> Option<String> {
+        self.get_partitions()
+            .filter(|part| part.is_swap() && !part.is_random_swap())
+            .filter(|part| !part.flag_is_enabled(REMOVE))
+            .max_by_key(|part| part.get_sectors())
+            .and_then(|part| PartitionID::get_uuid(part.get_device_path()))
+            .map(|id| id.id)
+    }
+
+    fn get_passphrases(&self) -> Vec<&str> {
+        self.get_partitions()
+            .filter_map(|part| part.encryption.as_ref())
+            .filter_map(|enc| enc.password.as_ref().map(|password| password.as_str()))
+            .collect()
+    }
+
     fn get_support_flags(&self) -> FileSystemSupport {
         let mut flags = FileSystemSupport::empty();
 
+        if self.get_logical_devices().iter().any(|d| !d.caches.is_empty()) {
+            flags |= FileSystemSupport::LVM_CACHE;
+        }
+
         for partition in self.get_partitions() {
+            if partition.encryption.is_some() {
+                flags |= FileSystemSupport::LUKS;
+            }
+
             match partition.filesystem {
                 Some(Btrfs) => flags |= FileSystemSupport::BTRFS,
                 Some(Ext2) | Some(Ext3) | Some(Ext4) => flags |= FileSystemSupport::EXT4,
@@ -176,4 +257,83 @@ impl InstallerDiskOps for Disks {
 
         flags
     }
+
+    fn get_target_capacities(&self) -> Vec<(&Path, Option<u64>)> {
+        let physical = self.physical.iter().flat_map(|disk| {
+            let sector_size = disk.get_logical_block_size();
+            disk.file_system.iter().chain(disk.partitions.iter()).map(move |p| (sector_size, p))
+        });
+
+        let logical = self.logical.iter().flat_map(|device| {
+            let sector_size = device.sector_size;
+            device.file_system.iter().chain(device.partitions.iter()).map(move |p| (sector_size, p))
+        });
+
+        physical
+            .chain(logical)
+            .filter(|&(_, partition)| !partition.flag_is_enabled(REMOVE))
+            .filter_map(|(sector_size, partition)| {
+                let target = partition.target.as_ref()?;
+                let capacity = if partition.will_format() {
+                    Some(partition.get_sectors() * sector_size)
+                } else {
+                    None
+                };
+
+                Some((target.as_path(), capacity))
+            })
+            .collect()
+    }
+
+    fn get_boot_device(&self, bootloader: Bootloader) -> io::Result<(PathBuf, i32)> {
+        if let (Bootloader::Bios, Some(disk)) = (bootloader, self.get_bootloader_disk()) {
+            return Ok((disk.to_path_buf(), 0));
+        }
+
+        if bootloader == Bootloader::Efi {
+            let (disk, esp) = self.get_esp()?;
+            return Ok((disk.to_path_buf(), esp.number));
+        }
+
+        let ((root_dev, _root_part), boot_opt) = self.get_base_partitions(bootloader);
+        Ok(boot_opt.map_or((root_dev.to_path_buf(), 0), |(dev, dev_part)| {
+            (dev.to_path_buf(), dev_part.number)
+        }))
+    }
+
+    /// Arrays are not created by distinst, but the boot partition may be on an existing one.
+    fn get_boot_devices(&self, bootloader: Bootloader) -> io::Result<Vec<(PathBuf, i32)>> {
+        let boot = match bootloader {
+            Bootloader::Bios if self.get_bootloader_disk().is_some() => None,
+            Bootloader::Bios => {
+                let ((_, root), boot) = self.get_base_partitions(bootloader);
+                Some(boot.map_or(root, |(_, boot)| boot))
+            }
+            Bootloader::Efi => Some(self.get_esp()?.1),
+        };
+
+        let devices = match boot {
+            Some(boot) => raid::boot_devices(boot.get_device_path(), bootloader)?,
+            None => None,
+        };
+
+        match devices {
+            Some(devices) => Ok(devices),
+            None => Ok(vec![self.get_boot_device(bootloader)?]),
+        }
+    }
+
+    fn get_prep_device(&self) -> io::Result<PathBuf> {
+        let (_, prep) = self.get_prep()?;
+        let path = prep.get_device_path();
+        if path.as_os_str().is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "the PReP boot partition has no device path, as it has not been created",
+            ));
+        }
+
+        Ok(path.to_path_buf())
+    }
 }
+
@@ -0,0 +1,106 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+/// The rate is averaged over the samples taken within this window.
+const WINDOW: Duration = Duration::from_secs(10);
+
+/// A rate is not given until the samples span at least this long.
+const MIN_ELAPSED: Duration = Duration::from_secs(1);
+
+/// Samples closer together than this are not kept.
+const SAMPLE_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The progress of a step which is copying data, such as extracting the image or moving a
+/// partition.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Transfer {
+    pub bytes_done:  u64,
+    pub bytes_total: u64,
+    /// Bytes per second, averaged over the last few seconds.
+    pub rate:        Option<f64>,
+    /// Seconds until `bytes_done` reaches `bytes_total`, at the current rate.
+    pub eta_seconds: Option<u64>,
+}
+
+/// Smooths the rate of a transfer over a sliding window, so that the time remaining
+/// doesn't jump between small and large files.
+#[derive(Default)]
+pub(crate) struct RateEstimator {
+    samples: VecDeque<(Instant, u64)>,
+    total:   u64,
+}
+
+impl RateEstimator {
+    pub fn sample(&mut self, done: u64, total: u64) -> Transfer {
+        self.sample_at(Instant::now(), done, total)
+    }
+
+    /// A new transfer is started whenever the total changes, or the bytes go backwards.
+    fn sample_at(&mut self, now: Instant, done: u64, total: u64) -> Transfer {
+        let restarted = self.samples.back().map_or(false, |&(_, last)| done < last);
+        if restarted || total != self.total {
+            self.samples.clear();
+            self.total = total;
+        }
+
+        let recent = self
+            .samples
+            .back()
+            .map_or(false, |&(last, _)| now.duration_since(last) < SAMPLE_INTERVAL);
+        if !recent {
+            self.samples.push_back((now, done));
+        }
+
+        while self.samples.len() > 2 && now.duration_since(self.samples[1].0) >= WINDOW {
+            self.samples.pop_front();
+        }
+
+        let (start, start_bytes) = self.samples[0];
+        let elapsed = now.duration_since(start);
+        let rate = if elapsed >= MIN_ELAPSED {
+            Some((done - start_bytes) as f64 / elapsed.as_secs_f64())
+        } else {
+            None
+        };
+
+        let eta_seconds = rate
+            .filter(|&rate| rate > 0.0)
+            .map(|rate| (total.saturating_sub(done) as f64 / rate).ceil() as u64);
+
+        Transfer { bytes_done: done, bytes_total: total, rate, eta_seconds }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn estimate() {
+        let start = Instant::now();
+        let at = |secs: u64| start + Duration::from_secs(secs);
+        let mut estimator = RateEstimator::default();
+
+        let transfer = estimator.sample_at(at(0), 0, 1000);
+        assert_eq!(transfer.rate, None);
+        assert_eq!(transfer.eta_seconds, None);
+
+        estimator.sample_at(at(1), 10, 1000);
+        let transfer = estimator.sample_at(at(2), 20, 1000);
+        assert_eq!(transfer.rate, Some(10.0));
+        assert_eq!(transfer.eta_seconds, Some(98));
+
+        // Samples older than the window no longer count towards the rate.
+        estimator.sample_at(at(12), 120, 1000);
+        let transfer = estimator.sample_at(at(13), 240, 1000);
+        assert_eq!(transfer.rate, Some(20.0));
+        assert_eq!(transfer.eta_seconds, Some(38));
+
+        // A new total starts a new transfer.
+        let transfer = estimator.sample_at(at(14), 0, 500);
+        assert_eq!(transfer.bytes_total, 500);
+        assert_eq!(transfer.rate, None);
+    }
+}
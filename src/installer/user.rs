@@ -0,0 +1,480 @@
+use super::UserAccountCreate;
+use std::{
+    ffi::{CStr, CString},
+    fs::File,
+    io::{self, Read},
+    slice,
+    sync::{Mutex, PoisonError},
+};
+use zeroize::{Zeroize, Zeroizing};
+
+/// Groups that the user is added to when none were requested, which grant administration.
+pub const DEFAULT_GROUPS: &[&str] = &["adm", "sudo", "lpadmin"];
+
+/// Groups whose members may administer the system with `sudo`.
+const ADMIN_GROUPS: &[&str] = &["sudo", "wheel", "admin"];
+
+/// Names which belong to system accounts and groups of Debian and Ubuntu.
+#[rustfmt::skip]
+const RESERVED_NAMES: &[&str] = &[
+    "adm", "admin", "audio", "backup", "bin", "cdrom", "daemon", "dialout", "dip", "disk",
+    "floppy", "games", "gnats", "input", "irc", "kmem", "list", "lp", "lpadmin", "mail", "man",
+    "messagebus", "netdev", "news", "nogroup", "nobody", "operator", "plugdev", "proxy",
+    "render", "root", "sambashare", "shadow", "src", "staff", "sudo", "sync", "sys", "syslog",
+    "tape", "tty", "users", "utmp", "uucp", "video", "voice", "www-data",
+];
+
+/// The maximum length of a user name, as accepted by `useradd`.
+const NAME_MAX: usize = 32;
+
+/// The range of IDs which are given to the accounts of people, rather than the system.
+const UID_MIN: u32 = 1000;
+const UID_MAX: u32 = 59999;
+
+/// The characters which may be used in the salt of a crypt(3) hash.
+const SALT_CHARS: &[u8] = b"./0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+
+/// SHA-512 crypt, with more rounds than the default of 5000 to slow down cracking.
+const HASH_PREFIX: &str = "$6$rounds=656000$";
+
+#[link(name = "crypt")]
+extern "C" {
+    fn crypt(key: *const libc::c_char, salt: *const libc::c_char) -> *mut libc::c_char;
+}
+
+/// crypt(3) returns its hash in a static buffer, so concurrent installs take turns with it.
+static CRYPT: Mutex<()> = Mutex::new(());
+
+/// How the root account of the install may be logged in to.
+#[derive(Clone, Debug, PartialEq)]
+pub enum RootPolicy {
+    /// The password of root is locked, and the system is administered with `sudo`, by an
+    /// account of the install, or that is created on the first boot in OEM mode.
+    Locked,
+    /// Root logs in with a password, which was already hashed by crypt(3).
+    Password(String),
+    /// The password of root is locked, and root logs in over SSH with the authorized keys.
+    Keys(Vec<String>),
+}
+
+impl Default for RootPolicy {
+    fn default() -> Self { RootPolicy::Locked }
+}
+
+/// Validates the account before any disks are modified.
+pub fn validate(user: &UserAccountCreate) -> io::Result<()> {
+    let invalid = |why: String| io::Error::new(io::ErrorKind::InvalidInput, why);
+
+    validate_name(&user.username)
+        .map_err(|why| invalid(format!("invalid user name '{}': {}", user.username, why)))?;
+
+    if let Some(ref groups) = user.groups {
+        for group in groups {
+            if !is_posix_name(group) {
+                return Err(invalid(format!("invalid group name '{}'", group)));
+            }
+        }
+    }
+
+    if let Some(uid) = user.uid {
+        if uid < UID_MIN || uid > UID_MAX {
+            return Err(invalid(format!(
+                "user ID {} is outside of the range of {} to {}",
+                uid, UID_MIN, UID_MAX
+            )));
+        }
+    }
+
+    if let Some(gid) = user.gid {
+        if gid < UID_MIN || gid > UID_MAX {
+            return Err(invalid(format!(
+                "group ID {} is outside of the range of {} to {}",
+                gid, UID_MIN, UID_MAX
+            )));
+        }
+    }
+
+    if let Some(ref shell) = user.shell {
+        if !shell.starts_with('/') || shell.contains(|c| c == ':' || c == '\n') {
+            return Err(invalid(format!("the shell '{}' is not an absolute path", shell)));
+        }
+    }
+
+    match (user.password.is_some(), user.password_hash.as_ref(), user.no_password) {
+        (true, Some(_), _) => Err(invalid("both a password and a password hash were given".into())),
+        (true, None, true) | (false, Some(_), true) => {
+            Err(invalid("a password was given for an account without a password".into()))
+        }
+        (_, Some(hash), _) if !is_crypt_hash(hash) => {
+            Err(invalid("the password hash is not a crypt(3) hash".into()))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Validates the password hash or keys of the root policy.
+pub fn validate_root(root: &RootPolicy) -> io::Result<()> {
+    let invalid = |why: &str| io::Error::new(io::ErrorKind::InvalidInput, why.to_owned());
+
+    match *root {
+        RootPolicy::Password(ref hash) if !is_crypt_hash(hash) => {
+            Err(invalid("the password hash of root is not a crypt(3) hash"))
+        }
+        RootPolicy::Keys(ref keys) if keys.iter().all(|keys| keys.trim().is_empty()) => {
+            Err(invalid("root may only log in with keys, but none were given"))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Validates that the system may be administered with the root policy and the accounts which
+/// are created. A locked root requires that one of the accounts may administer the system,
+/// unless the owner of the machine creates an administrator on the first boot, in OEM mode.
+pub fn validate_administrator(
+    root: &RootPolicy,
+    users: &[UserAccountCreate],
+    oem: bool,
+) -> io::Result<()> {
+    let invalid = |why: &str| io::Error::new(io::ErrorKind::InvalidInput, why.to_owned());
+
+    match *root {
+        RootPolicy::Locked if users.is_empty() && !oem => {
+            Err(invalid("root is locked, but no user is created who may administer the system"))
+        }
+        RootPolicy::Locked if !users.is_empty() && !users.iter().any(is_administrator) => Err(
+            invalid("root is locked, but no user is in a group which may administer the system"),
+        ),
+        _ => Ok(()),
+    }
+}
+
+/// Whether the account is in a group which may administer the system, as it is by default.
+fn is_administrator(user: &UserAccountCreate) -> bool {
+    user.groups
+        .as_ref()
+        .map_or(true, |groups| groups.iter().any(|group| ADMIN_GROUPS.contains(&group.as_str())))
+}
+
+/// Validates each account, which must not share a name or an ID with another.
+pub fn validate_all(users: &[UserAccountCreate]) -> io::Result<()> {
+    let invalid = |why: String| io::Error::new(io::ErrorKind::InvalidInput, why);
+
+    for (index, user) in users.iter().enumerate() {
+        validate(user)?;
+
+        for other in &users[..index] {
+            if other.username == user.username {
+                return Err(invalid(format!("{} is given more than once", user.username)));
+            }
+
+            if user.uid.is_some() && other.uid == user.uid {
+                return Err(invalid(format!(
+                    "{} and {} have the same user ID",
+                    other.username, user.username
+                )));
+            }
+
+            if user.gid.is_some() && other.gid == user.gid {
+                return Err(invalid(format!(
+                    "{} and {} have the same group ID",
+                    other.username, user.username
+                )));
+            }
+        }
+    }
+
+    if users.iter().filter(|user| user.autologin).count() > 1 {
+        return Err(invalid("only one user may be logged in automatically".into()));
+    }
+
+    if let (_, Some(user)) = resolve_order(users) {
+        return Err(invalid(format!(
+            "{} is a member of the group of an account which is a member of its group",
+            user
+        )));
+    }
+
+    Ok(())
+}
+
+/// The order which the accounts are created in, so that the IDs which `useradd` gives are
+/// the same on every install: accounts with fixed user IDs are created first, and then those
+/// with fixed group IDs, so that their IDs are not given to others, followed by the rest in
+/// the order that they were given. An account which is a member of the primary group of
+/// another is created after it, as `useradd` requires its groups to exist.
+pub fn creation_order(users: &[UserAccountCreate]) -> Vec<&UserAccountCreate> {
+    resolve_order(users).0
+}
+
+/// The order of `creation_order`, and the first of the accounts which are members of the
+/// primary groups of each other, if there are any, which are left in the order of their IDs.
+fn resolve_order(users: &[UserAccountCreate]) -> (Vec<&UserAccountCreate>, Option<&str>) {
+    let mut pending = users.iter().collect::<Vec<_>>();
+    // The sort is stable, and accounts without fixed IDs are ordered last.
+    pending.sort_by_key(|user| match (user.uid, user.gid) {
+        (Some(uid), _) => (0, uid),
+        (None, Some(gid)) => (1, gid),
+        (None, None) => (2, 0),
+    });
+
+    let mut ordered = Vec::with_capacity(pending.len());
+    while !pending.is_empty() {
+        let ready = pending.iter().position(|user| {
+            !pending.iter().any(|other| other.username != user.username && is_member(user, other))
+        });
+
+        match ready {
+            Some(index) => ordered.push(pending.remove(index)),
+            None => {
+                let cycle = pending[0].username.as_str();
+                ordered.extend(pending);
+                return (ordered, Some(cycle));
+            }
+        }
+    }
+
+    (ordered, None)
+}
+
+/// Whether the `user` is a member of the primary group of the `other` account.
+fn is_member(user: &UserAccountCreate, other: &UserAccountCreate) -> bool {
+    user.groups.as_ref().map_or(false, |groups| groups.iter().any(|group| *group == other.username))
+}
+
+/// Finds the accounts which conflict with those in the `passwd` and `group` files of the
+/// image, which would otherwise only fail once the disks have been modified.
+///
+/// An account of the image with the same name conflicts unless it has the fixed user ID of
+/// the account, in which case it is reused.
+pub fn verify_image(users: &[UserAccountCreate], passwd: &str, group: &str) -> io::Result<()> {
+    let conflict = |why: String| io::Error::new(io::ErrorKind::AlreadyExists, why);
+
+    for user in users {
+        for (name, uid) in entries(passwd) {
+            if name == user.username && uid != user.uid {
+                return Err(conflict(format!(
+                    "{} is already a user of the image, with the user ID {}",
+                    name,
+                    uid.map_or_else(|| "?".into(), |uid| uid.to_string())
+                )));
+            }
+
+            if name != user.username && uid.is_some() && uid == user.uid {
+                return Err(conflict(format!(
+                    "the user ID of {} is already given to {} in the image",
+                    user.username, name
+                )));
+            }
+        }
+
+        let adopted = existing_uid(passwd, &user.username).is_some();
+        for (name, gid) in entries(group) {
+            if name == user.username && gid != user.gid && !adopted {
+                return Err(conflict(format!("{} is already a group of the image", name)));
+            }
+
+            if name != user.username && gid.is_some() && gid == user.gid {
+                return Err(conflict(format!(
+                    "the group ID of {} is already given to the group {} in the image",
+                    user.username, name
+                )));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// The user ID of the account in the `passwd` file, if it has one.
+pub fn existing_uid(passwd: &str, username: &str) -> Option<u32> {
+    entries(passwd).find(|&(name, _)| name == username).and_then(|(_, uid)| uid)
+}
+
+/// The names and IDs of the entries of a `passwd` or `group` file.
+fn entries(file: &str) -> impl Iterator<Item = (&str, Option<u32>)> {
+    file.lines().filter_map(|line| {
+        let mut fields = line.split(':');
+        let name = fields.next().filter(|name| !name.is_empty())?;
+        Some((name, fields.nth(1).and_then(|id| id.parse().ok())))
+    })
+}
+
+fn validate_name(name: &str) -> Result<(), &'static str> {
+    if !is_posix_name(name) {
+        Err("user names must begin with a lowercase letter or underscore, and may only contain \
+             lowercase letters, digits, underscores, and dashes")
+    } else if RESERVED_NAMES.contains(&name) || name.starts_with("systemd-") {
+        Err("the name is reserved for a system account")
+    } else {
+        Ok(())
+    }
+}
+
+/// Whether the hash is in the format of crypt(3), and may be given to `chpasswd -e`.
+fn is_crypt_hash(hash: &str) -> bool {
+    hash.starts_with('$') && !hash.contains(|c| c == ':' || c == '\n')
+}
+
+/// Whether the name is portable, and acceptable to `useradd` and `groupadd`.
+fn is_posix_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    let first_is_valid = chars.next().map_or(false, |c| c.is_ascii_lowercase() || c == '_');
+    first_is_valid
+        && name.len() <= NAME_MAX
+        && chars.all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_' || c == '-')
+}
+
+/// Hashes the password with SHA-512 crypt and a random salt, for `chpasswd -e`.
+pub fn hash_password(password: &str) -> io::Result<Zeroizing<String>> {
+    let mut random = [0u8; 16];
+    File::open("/dev/urandom")?.read_exact(&mut random)?;
+    let salt = random.iter().map(|&b| SALT_CHARS[b as usize % SALT_CHARS.len()] as char);
+    let salt = [HASH_PREFIX, &salt.collect::<String>()].concat();
+
+    let mut key = CString::new(password)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "password contains a NUL byte"))?
+        .into_bytes_with_nul();
+    let salt = CString::new(salt).expect("salt does not contain a NUL byte");
+
+    let hash = unsafe {
+        let _crypt = CRYPT.lock().unwrap_or_else(PoisonError::into_inner);
+        let hash = crypt(key.as_ptr() as *const libc::c_char, salt.as_ptr());
+        key.zeroize();
+        if hash.is_null() {
+            return Err(io::Error::last_os_error());
+        }
+
+        let copy = Zeroizing::new(CStr::from_ptr(hash).to_string_lossy().into_owned());
+        slice::from_raw_parts_mut(hash as *mut u8, libc::strlen(hash)).zeroize();
+        copy
+    };
+
+    // Failures are indicated by a hash which begins with `*`, rather than the prefix.
+    if !hash.starts_with("$6$") {
+        return Err(io::Error::new(io::ErrorKind::Other, "crypt does not support SHA-512"));
+    }
+
+    Ok(hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names() {
+        assert!(validate_name("jane").is_ok());
+        assert!(validate_name("_jane-doe2").is_ok());
+        assert!(validate_name("Jane").is_err());
+        assert!(validate_name("2jane").is_err());
+        assert!(validate_name("jane doe").is_err());
+        assert!(validate_name("").is_err());
+        assert!(validate_name(&"j".repeat(33)).is_err());
+        assert!(validate_name("root").is_err());
+        assert!(validate_name("systemd-network").is_err());
+    }
+
+    #[test]
+    fn root() {
+        let user = |groups: Option<&[&str]>| UserAccountCreate {
+            username: "jane".into(),
+            groups: groups.map(|groups| groups.iter().map(|&g| g.into()).collect()),
+            ..UserAccountCreate::default()
+        };
+
+        let locked = |users: &[UserAccountCreate]| {
+            validate_administrator(&RootPolicy::Locked, users, false).is_ok()
+        };
+        assert!(!locked(&[]));
+        assert!(validate_administrator(&RootPolicy::Locked, &[], true).is_ok());
+        assert!(locked(&[user(None)]));
+        assert!(locked(&[user(Some(&["adm", "wheel"]))]));
+        assert!(!locked(&[user(Some(&["adm"]))]));
+        assert!(locked(&[user(Some(&[])), user(None)]));
+
+        let hash = RootPolicy::Password("$6$salt$hash".into());
+        assert!(validate_root(&hash).is_ok());
+        assert!(validate_administrator(&hash, &[user(Some(&[]))], false).is_ok());
+        assert!(validate_administrator(&hash, &[], false).is_ok());
+        assert!(validate_root(&RootPolicy::Password("hunter2".into())).is_err());
+        assert!(validate_root(&RootPolicy::Keys(vec!["\n".into()])).is_err());
+    }
+
+    fn account(name: &str, uid: Option<u32>) -> UserAccountCreate {
+        UserAccountCreate { username: name.into(), uid, ..UserAccountCreate::default() }
+    }
+
+    #[test]
+    fn accounts() {
+        let users = [account("c", None), account("b", Some(1002)), account("a", Some(1001))];
+        assert!(validate_all(&users).is_ok());
+
+        let order = creation_order(&users).iter().map(|u| u.username.as_str()).collect::<Vec<_>>();
+        assert_eq!(order, ["a", "b", "c"]);
+
+        assert!(validate_all(&[account("a", None), account("a", None)]).is_err());
+        assert!(validate_all(&[account("a", Some(1001)), account("b", Some(1001))]).is_err());
+
+        // Fixed group IDs are taken before the groups of the others are made, and a member of
+        // the primary group of another account is created after it.
+        let member = |name: &str, groups: &[&str]| UserAccountCreate {
+            groups: Some(groups.iter().map(|&group| group.into()).collect()),
+            ..account(name, None)
+        };
+        let users = [
+            member("d", &["e", "sudo"]),
+            account("e", None),
+            UserAccountCreate { gid: Some(2000), ..account("f", None) },
+            member("g", &["a"]),
+            account("a", Some(1001)),
+        ];
+        assert!(validate_all(&users).is_ok());
+        let order = creation_order(&users).iter().map(|u| u.username.as_str()).collect::<Vec<_>>();
+        assert_eq!(order, ["a", "f", "e", "d", "g"]);
+
+        let cycle = [member("d", &["e"]), member("e", &["d"])];
+        assert!(validate_all(&cycle).is_err());
+    }
+
+    #[test]
+    fn image_conflicts() {
+        let passwd = "root:x:0:0:root:/root:/bin/bash\nlab:x:1000:1000::/home/lab:/bin/bash\n";
+        let group = "root:x:0:\nlab:x:1000:\nstaff:x:1005:\n";
+
+        assert!(verify_image(&[account("jane", None)], passwd, group).is_ok());
+        assert!(verify_image(&[account("lab", Some(1000))], passwd, group).is_ok());
+        assert!(verify_image(&[account("lab", None)], passwd, group).is_err());
+        assert!(verify_image(&[account("jane", Some(1000))], passwd, group).is_err());
+
+        let jane = UserAccountCreate { gid: Some(1005), ..account("jane", None) };
+        assert!(verify_image(&[jane], passwd, group).is_err());
+        assert!(verify_image(&[account("staff", None)], passwd, group).is_err());
+        assert_eq!(existing_uid(passwd, "lab"), Some(1000));
+    }
+
+    #[test]
+    fn password() {
+        let hash = hash_password("hunter2").unwrap();
+        assert!(hash.starts_with(HASH_PREFIX));
+        assert_ne!(&*hash, &*hash_password("hunter2").unwrap());
+    }
+
+    #[test]
+    fn concurrent_passwords() {
+        let hashes = ["hunter2", "correct horse"]
+            .iter()
+            .map(|&password| std::thread::spawn(move || (password, hash_password(password))))
+            .collect::<Vec<_>>();
+
+        // Each hash is of its own password, which hashes to it again with it as the salt.
+        for thread in hashes {
+            let (password, hash) = thread.join().unwrap();
+            let hash = hash.unwrap();
+            let key = CString::new(password).unwrap();
+            let salt = CString::new(hash.as_str()).unwrap();
+            let _crypt = CRYPT.lock().unwrap();
+            let again = unsafe { CStr::from_ptr(crypt(key.as_ptr(), salt.as_ptr())) };
+            assert_eq!(again.to_str().unwrap(), hash.as_str());
+        }
+    }
+}
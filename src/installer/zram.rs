@@ -0,0 +1,129 @@
+use std::{io, path::Path};
+
+/// The config of `zram-generator`, which creates the device on each boot.
+pub(crate) const GENERATOR_CONF: &str = "etc/systemd/zram-generator.conf";
+
+/// The config of `zramswap`, from the `zram-tools` package of Debian.
+pub(crate) const ZRAMSWAP_CONF: &str = "etc/default/zramswap";
+
+/// Where `zram-generator` may be installed.
+const GENERATORS: &[&str] = &[
+    "usr/lib/systemd/system-generators/zram-generator",
+    "lib/systemd/system-generators/zram-generator",
+];
+
+/// Where `zramswap` may be installed.
+const ZRAMSWAPS: &[&str] = &["usr/sbin/zramswap", "sbin/zramswap"];
+
+/// The compression algorithms of the kernel which zram may use.
+const ALGORITHMS: &[&str] = &["lzo", "lzo-rle", "lz4", "lz4hc", "zstd", "842", "deflate"];
+
+/// Compressed swap in memory, which is preferred over swap on disk when memory is ample.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ZramConfig {
+    /// The size of the device, as a fraction of the memory of the machine that it boots on.
+    /// It may exceed one, as the pages which are swapped to it are compressed.
+    pub fraction_of_ram: f32,
+    /// The compression algorithm, such as `zstd` or `lz4`, or `None` for the default.
+    pub algorithm:       Option<String>,
+}
+
+impl Default for ZramConfig {
+    fn default() -> Self { ZramConfig { fraction_of_ram: 0.5, algorithm: None } }
+}
+
+/// How the install creates its zram device when it boots.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum ZramMechanism {
+    /// `systemd-zram-generator`, which Fedora and Arch ship, and which Debian packages.
+    Generator,
+    /// The `zramswap` service of `zram-tools`.
+    Zramswap,
+}
+
+impl ZramMechanism {
+    /// Finds the mechanism of the install at `root`, preferring the generator.
+    pub(crate) fn detect(root: &Path) -> Option<Self> {
+        let has = |paths: &[&str]| paths.iter().any(|path| root.join(path).exists());
+        if has(GENERATORS) {
+            Some(ZramMechanism::Generator)
+        } else if has(ZRAMSWAPS) {
+            Some(ZramMechanism::Zramswap)
+        } else {
+            None
+        }
+    }
+}
+
+impl ZramConfig {
+    /// Validates the size and algorithm before any disks are modified.
+    pub(crate) fn validate(&self) -> io::Result<()> {
+        let invalid = |why: String| io::Error::new(io::ErrorKind::InvalidInput, why);
+
+        if !(self.fraction_of_ram > 0.0 && self.fraction_of_ram <= 2.0) {
+            return Err(invalid(format!(
+                "the fraction of memory, {}, is not greater than 0 and at most 2",
+                self.fraction_of_ram
+            )));
+        }
+
+        if let Some(ref algorithm) = self.algorithm {
+            if !ALGORITHMS.contains(&algorithm.as_str()) {
+                return Err(invalid(format!("unknown compression algorithm '{}'", algorithm)));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The size of the device in MiB, on a machine with `memory` KiB of memory.
+    pub(crate) fn size(&self, memory: u64) -> u64 {
+        (memory as f64 * f64::from(self.fraction_of_ram) / 1024.0) as u64
+    }
+
+    /// The contents of `zram-generator.conf`.
+    pub(crate) fn generator_conf(&self) -> String {
+        let mut conf =
+            format!("# Written by distinst\n[zram0]\nzram-size = ram * {}\n", self.fraction_of_ram);
+
+        if let Some(ref algorithm) = self.algorithm {
+            conf.push_str(&["compression-algorithm = ", algorithm, "\n"].concat());
+        }
+
+        conf
+    }
+
+    /// The contents of `/etc/default/zramswap`, whose size is a percent of the memory.
+    pub(crate) fn zramswap_conf(&self) -> String {
+        let percent = (self.fraction_of_ram * 100.0).round() as u32;
+        let mut conf = format!("# Written by distinst\nPERCENT={}\n", percent);
+        if let Some(ref algorithm) = self.algorithm {
+            conf.push_str(&["ALGO=", algorithm, "\n"].concat());
+        }
+
+        conf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config() {
+        let zram = ZramConfig { fraction_of_ram: 0.25, algorithm: Some("zstd".into()) };
+        assert!(zram.validate().is_ok());
+        assert_eq!(zram.size(16 * 1024 * 1024), 4096);
+        assert_eq!(
+            zram.generator_conf(),
+            "# Written by distinst\n[zram0]\nzram-size = ram * 0.25\ncompression-algorithm = \
+             zstd\n"
+        );
+        assert_eq!(zram.zramswap_conf(), "# Written by distinst\nPERCENT=25\nALGO=zstd\n");
+
+        assert!(ZramConfig { fraction_of_ram: 0.0, ..zram.clone() }.validate().is_err());
+        assert!(ZramConfig { fraction_of_ram: f32::NAN, ..zram.clone() }.validate().is_err());
+        assert!(ZramConfig { algorithm: Some("gzip".into()), ..zram }.validate().is_err());
+        assert!(ZramConfig::default().generator_conf().ends_with("zram-size = ram * 0.5\n"));
+    }
+}
@@ -18,6 +18,7 @@ pub extern crate os_release;
 pub extern crate partition_identity;
 pub extern crate proc_mounts;
 pub extern crate sys_mount;
+pub extern crate zeroize;
 
 extern crate anyhow;
 extern crate apt_cli_wrappers;
@@ -46,13 +47,21 @@ extern crate libparted;
 extern crate log;
 extern crate logind_dbus;
 extern crate rayon;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
+extern crate serde_path_to_error;
 extern crate systemd_boot_conf;
 extern crate tempdir;
+extern crate toml;
 
 pub use crate::bootloader::*;
+pub use crate::chroot::Chroot;
 pub use disk_types::*;
 pub use crate::disks::*;
-pub use crate::misc::device_layout_hash;
+pub use crate::misc::{device_layout_hash, ErrorContext};
+pub use crate::recipe::RecipeFile;
 pub use crate::upgrade::*;
 
 pub use self::installer::RecoveryEnv;
@@ -60,10 +69,13 @@ pub use self::installer::RecoveryEnv;
 mod distribution;
 mod installer;
 mod logging;
+mod preflight;
+mod requirements;
 mod upgrade;
 
 pub mod auto;
 pub(crate) mod errors;
+pub mod recipe;
 
 /// Useful DBus interfaces for installers to implement.
 pub mod dbus_interfaces {
@@ -82,14 +94,29 @@ use partition_identity::PartitionID;
 use sys_mount::*;
 use systemd_boot_conf::SystemdBootConf;
 
-pub use self::{installer::*, logging::log};
+pub use self::{
+    installer::*,
+    logging::{log, log_subscribe, log_to_file, InstallLog, LogMessage, TARGETS},
+    preflight::{preflight, PreflightProblem},
+    requirements::*,
+};
+pub use log::{Level, LevelFilter};
 
 /// When set to true, this will stop the installation process.
+///
+/// Every install in the process is cancelled by it, so it cannot stop one install without
+/// the others.
+#[deprecated(note = "use the 'Installer::cancel_handle()' method instead")]
 pub static KILL_SWITCH: AtomicBool = AtomicBool::new(false);
 
 pub use crate::bootloader::FORCE_BOOTLOADER;
 
+pub use crate::hardware_support::drivers::DriverMatch;
+
 /// Exits before the unsquashfs step
+///
+/// Every install in the process is simulated while it is set.
+#[deprecated(note = "use the 'Installer::set_simulate()' method instead")]
 pub static PARTITIONING_TEST: AtomicBool = AtomicBool::new(false);
 
 /// Even if the system is EFI, the efivars directory will not be mounted in the chroot.
@@ -126,6 +153,34 @@ pub fn minimum_disk_size(default: u64) -> u64 {
         // Convert bytes read into sectors required + 1
         .map_or(default, |size| ((size / 512) + 1).max(default));
 
+    casper + default_partition_sectors()
+}
+
+/// Gets the minimum size of a disk, in bytes, which the image, archive, or directory at the
+/// given path may be installed to.
+///
+/// This is the space which the files of the source will take once extracted, with the
+/// `DEFAULT_SPACE_MARGIN`, and the sizes of a default boot / esp, swap, and recovery
+/// partition.
+pub fn minimum_disk_size_of_source<P: AsRef<Path>>(source: P) -> io::Result<u64> {
+    minimum_disk_size_of(&squashfs::Source::detect(source)?)
+}
+
+/// The `minimum_disk_size_of_source` of a source which was already detected.
+pub(crate) fn minimum_disk_size_of(source: &squashfs::Source) -> io::Result<u64> {
+    let files = squashfs::file_sizes(source, CancellationToken::new().flag())?;
+    let root = [Path::new("/")];
+    let (_, required) = installer::space::required_space(&files, &root, DEFAULT_SPACE_MARGIN)[0];
+
+    Ok(required + default_partition_sectors() * 512)
+}
+
+/// The devices of the system which need drivers that are not in the image, with the
+/// packages that are installed for them if `Config::install_drivers` is set.
+pub fn driver_support() -> Vec<DriverMatch> { hardware_support::drivers::detect() }
+
+/// The sectors of the partitions which are created alongside the root by default.
+fn default_partition_sectors() -> u64 {
     // EFI installs will contain an EFI partition with a recovery partition.
     let bootloader = if Bootloader::detect() == Bootloader::Efi {
         DEFAULT_ESP_SECTORS + DEFAULT_RECOVER_SECTORS
@@ -133,7 +188,7 @@ pub fn minimum_disk_size(default: u64) -> u64 {
         0
     };
 
-    casper + bootloader + DEFAULT_SWAP_SECTORS
+    bootloader + DEFAULT_SWAP_SECTORS
 }
 
 pub fn unset_mode() -> anyhow::Result<()> {
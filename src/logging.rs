@@ -1,26 +1,408 @@
+use crate::{installer::InstallerEvent, misc};
 use dirs;
 use fern;
-use log::{Level, LevelFilter};
-use std::io;
+use log::{Level, LevelFilter, Record};
+use std::{
+    cell::{Cell, RefCell},
+    fmt::Write as FmtWrite,
+    fs::{self, Permissions},
+    io::{self, Write},
+    marker::PhantomData,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::Sender,
+        Mutex, RwLock,
+    },
+    time::Instant,
+};
+use zeroize::Zeroizing;
 
-/// Initialize logging with the fern logger
+/// Where the log of the installer is written.
+pub const LOG_FILE: &str = "/tmp/installer.log";
+
+/// Where the log of an install is kept, within the installed system.
+pub const TARGET_LOG: &str = "var/log/installer/distinst.log";
+
+/// Where the log of an install is kept when it failed before its target was mounted.
+pub const LIVE_LOG: &str = "/run/distinst/installer.log";
+
+/// Replaces secrets in the log of an install.
+const HIDDEN: &str = "[hidden]";
+
+/// The logs of the installs in progress, each of which runs on its own worker thread.
+static SINKS: Mutex<Vec<Sink>> = Mutex::new(Vec::new());
+
+/// The ID of the next install to be logged.
+static NEXT_SINK: AtomicU64 = AtomicU64::new(0);
+
+thread_local! {
+    /// The install which the records of this thread are logged to.
+    static CURRENT: Cell<Option<SinkId>> = Cell::new(None);
+}
+
+/// Identifies the log of an install, so that the threads which work on the install may log
+/// to it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct SinkId(u64);
+
+/// The log of an install in progress, when it was started, and where its records are sent.
+struct Sink {
+    id:      SinkId,
+    started: Instant,
+    log:     String,
+    forward: Option<Sender<InstallerEvent>>,
+}
+
+/// Applies `func` to the log which the records of this thread belong to.
+///
+/// Threads which aren't part of an install, such as those spawned by the crates of the
+/// installer, log to the install in progress if there is only one. Otherwise, it can't be
+/// known which install they belong to, so their records are only given to the subscribers.
+fn with_sink<F: FnOnce(&mut Sink)>(func: F) {
+    if let Ok(mut sinks) = SINKS.lock() {
+        let sink = match CURRENT.with(Cell::get) {
+            Some(id) => sinks.iter_mut().find(|sink| sink.id == id),
+            None if sinks.len() == 1 => sinks.first_mut(),
+            None => None,
+        };
+
+        if let Some(sink) = sink {
+            func(sink);
+        }
+    }
+}
+
+/// The install which this thread logs to, which threads that it spawns may adopt.
+pub(crate) fn current() -> Option<SinkId> { CURRENT.with(Cell::get) }
+
+/// Logs the records of this thread to the install of `sink`, until the guard is dropped.
+pub(crate) fn adopt(sink: Option<SinkId>) -> Adopted {
+    Adopted { previous: CURRENT.with(|current| current.replace(sink)), _thread: PhantomData }
+}
+
+/// Runs `func`, on a thread spawned by an install, with the records of the thread logged to
+/// the install of `sink`.
+pub(crate) fn within<T, F: FnOnce() -> T>(sink: Option<SinkId>, func: F) -> T {
+    let _adopted = adopt(sink);
+    func()
+}
+
+/// Restores the install which the thread logged to before it adopted another. It must be
+/// dropped on the thread which adopted the install.
+pub(crate) struct Adopted {
+    previous: Option<SinkId>,
+    _thread:  PhantomData<*const ()>,
+}
+
+impl Drop for Adopted {
+    fn drop(&mut self) { CURRENT.with(|current| current.set(self.previous)); }
+}
+
+/// Where the log of an install was kept.
+#[derive(Clone, Debug, PartialEq)]
+pub enum InstallLog {
+    /// At this path within the installed system.
+    Target(PathBuf),
+    /// At this path of the live system, when it could not be kept on the target.
+    Live(PathBuf),
+}
+
+/// Buffers everything which is logged during an install, including the output of the
+/// commands that it runs, so that it may be kept on the installed system.
+///
+/// Only the records of the thread which started the sink, and of the threads which adopt it,
+/// are kept, so that installs on other threads have logs of their own.
+pub(crate) struct LogSink {
+    id:      SinkId,
+    _thread: Adopted,
+    secrets: RefCell<Vec<Zeroizing<String>>>,
+    written: RefCell<Option<InstallLog>>,
+}
+
+impl LogSink {
+    /// Starts buffering the log of this thread, until the sink is dropped.
+    pub fn start() -> Self {
+        let id = SinkId(NEXT_SINK.fetch_add(1, Ordering::SeqCst));
+        if let Ok(mut sinks) = SINKS.lock() {
+            sinks.push(Sink { id, started: Instant::now(), log: String::new(), forward: None });
+        }
+
+        LogSink {
+            id,
+            _thread: adopt(Some(id)),
+            secrets: RefCell::new(Vec::new()),
+            written: RefCell::new(None),
+        }
+    }
+
+    /// Identifies the log, for the threads of the install to adopt.
+    pub fn id(&self) -> SinkId { self.id }
+
+    /// Keeps the log on the target mounted at `root` once the guard is dropped, which must be
+    /// before the target is unmounted. The `secrets` are removed from the log.
+    pub fn target(&self, root: &Path, secrets: Vec<Zeroizing<String>>) -> TargetLog {
+        *self.secrets.borrow_mut() = secrets;
+        TargetLog { sink: self, root: root.to_path_buf() }
+    }
+
+    /// Sends everything which is logged to this sink as events of the install, until the
+    /// sink is dropped.
+    pub fn forward(&self, events: Sender<InstallerEvent>) {
+        if let Ok(mut sinks) = SINKS.lock() {
+            if let Some(sink) = sinks.iter_mut().find(|sink| sink.id == self.id) {
+                sink.forward = Some(events);
+            }
+        }
+    }
+
+    /// Keeps the log on the live system if it could not be kept on the target, and returns
+    /// where it was kept.
+    pub fn finish(self) -> Option<InstallLog> {
+        if self.written.borrow().is_none() {
+            match self.write(Path::new(LIVE_LOG)) {
+                Ok(()) => *self.written.borrow_mut() = Some(InstallLog::Live(LIVE_LOG.into())),
+                Err(why) => error!("failed to write the install log to {}: {}", LIVE_LOG, why),
+            }
+        }
+
+        self.written.borrow_mut().take()
+    }
+
+    fn write(&self, path: &Path) -> io::Result<()> {
+        // Logging while the buffer is locked would deadlock, so it is copied out first.
+        let log = match SINKS.lock() {
+            Ok(sinks) => sinks
+                .iter()
+                .find(|sink| sink.id == self.id)
+                .map_or_else(String::new, |sink| sink.log.clone()),
+            Err(_) => return Err(io::Error::new(io::ErrorKind::Other, "log buffer poisoned")),
+        };
+
+        let secrets = self.secrets.borrow();
+        let secrets = secrets.iter().map(|secret| secret.as_str()).collect::<Vec<&str>>();
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        fs::write(path, scrub(&log, &secrets))
+            .and_then(|_| fs::set_permissions(path, Permissions::from_mode(0o600)))
+    }
+}
+
+impl Drop for LogSink {
+    fn drop(&mut self) {
+        if let Ok(mut sinks) = SINKS.lock() {
+            sinks.retain(|sink| sink.id != self.id);
+        }
+    }
+}
+
+/// Keeps the log of the install on the target when it is dropped.
+pub(crate) struct TargetLog<'a> {
+    sink: &'a LogSink,
+    root: PathBuf,
+}
+
+impl<'a> Drop for TargetLog<'a> {
+    fn drop(&mut self) {
+        info!("keeping the install log at /{}", TARGET_LOG);
+        match self.sink.write(&self.root.join(TARGET_LOG)) {
+            Ok(()) => {
+                let path = Path::new("/").join(TARGET_LOG);
+                *self.sink.written.borrow_mut() = Some(InstallLog::Target(path));
+            }
+            Err(why) => error!("failed to write the install log to the target: {}", why),
+        }
+    }
+}
+
+/// Appends a record to the log of the install that it belongs to, with the time since the
+/// install started.
+fn buffer(record: &Record) {
+    with_sink(|sink| {
+        let elapsed = sink.started.elapsed();
+        let _ = writeln!(
+            sink.log,
+            "[{:>4}.{:03} {} {}] {}",
+            elapsed.as_secs(),
+            elapsed.subsec_millis(),
+            record.level(),
+            target(record.target()),
+            record.args()
+        );
+    });
+}
+
+/// Removes the secrets from the log, along with the passphrase of any partition that was
+/// given on the command line as `pass=`.
+fn scrub(log: &str, secrets: &[&str]) -> String {
+    let log = secrets
+        .iter()
+        .filter(|secret| !secret.is_empty())
+        .fold(log.to_owned(), |log, secret| log.replace(secret, HIDDEN));
+
+    let mut scrubbed = String::with_capacity(log.len());
+    let mut rest = log.as_str();
+    while let Some(pos) = rest.find("pass=") {
+        let (before, after) = rest.split_at(pos + 5);
+        scrubbed.push_str(before);
+
+        let end = after
+            .find(|c: char| c == ',' || c == ':' || c == '"' || c.is_whitespace())
+            .unwrap_or_else(|| after.len());
+
+        if end != 0 {
+            scrubbed.push_str(HIDDEN);
+        }
+
+        rest = &after[end..];
+    }
+
+    scrubbed.push_str(rest);
+    scrubbed
+}
+
+/// The subsystems of the installer, which messages are logged under as their target.
+pub const TARGETS: &[&str] = &["disk", "lvm", "extract", "chroot", "bootloader", "distinst"];
+
+/// Modules of the installer and of its crates, and the targets that they log under. The
+/// first prefix which matches the module of a message is used.
+const MODULE_TARGETS: &[(&str, &str)] = &[
+    ("distinst_external_commands::lvm", "lvm"),
+    ("distinst_disks::config::lvm", "lvm"),
+    ("distinst::installer::steps::partition", "disk"),
+    ("distinst_disks", "disk"),
+    ("distinst_disk_ops", "disk"),
+    ("distinst_external_commands", "disk"),
+    ("disk_types", "disk"),
+    ("distinst_squashfs", "extract"),
+    ("distinst::installer::steps::configure", "chroot"),
+    ("distinst_chroot", "chroot"),
+    ("distinst::installer::steps::bootloader", "bootloader"),
+    ("distinst_bootloader", "bootloader"),
+];
+
+/// A message which was logged by the installer.
+pub struct LogMessage<'a> {
+    pub level:   Level,
+    /// The subsystem which logged the message, which is one of `TARGETS`.
+    pub target:  &'static str,
+    pub message: &'a str,
+}
+
+type Subscriber = (LevelFilter, Box<dyn Fn(&LogMessage) + Send + Sync>);
+
+/// Everything which receives the messages of the installer.
+static SUBSCRIBERS: RwLock<Vec<Subscriber>> = RwLock::new(Vec::new());
+
+/// Set once the logger of the installer is the global logger.
+static INITIALIZED: Mutex<bool> = Mutex::new(false);
+
+/// Initialize logging with the fern logger, passing every message to the `callback`.
+///
+/// This is a shim over `log_subscribe`, which also gives the target of each message.
 pub fn log<F: Fn(Level, &str) + Send + Sync + 'static>(callback: F) -> Result<(), fern::InitError> {
+    log_subscribe(LevelFilter::Debug, move |message| callback(message.level, message.message))
+}
+
+/// Passes each message at or above the `level` to the `callback`, which may filter or route
+/// them by their target. Any number of subscribers may be added.
+pub fn log_subscribe<F: Fn(&LogMessage) + Send + Sync + 'static>(
+    level: LevelFilter,
+    callback: F,
+) -> Result<(), fern::InitError> {
+    init()?;
+
+    if let Ok(mut subscribers) = SUBSCRIBERS.write() {
+        subscribers.push((level, Box::new(callback)));
+    }
+
+    Ok(())
+}
+
+/// Appends each message at or above the `level` to the file at `path`, with the time that
+/// it was logged at, in UTC.
+pub fn log_to_file<P: AsRef<Path>>(path: P, level: LevelFilter) -> Result<(), fern::InitError> {
+    let file = Mutex::new(fern::log_file(path)?);
+    log_subscribe(level, move |message| {
+        if let Ok(mut file) = file.lock() {
+            let _ = writeln!(
+                file,
+                "{} {:<5} {}: {}",
+                misc::timestamp(),
+                message.level,
+                message.target,
+                message.message
+            );
+        }
+    })
+}
+
+/// The target which messages of the given module are logged under.
+fn target(module: &str) -> &'static str {
+    if let Some(&target) = TARGETS.iter().find(|&&target| target == module) {
+        return target;
+    }
+
+    MODULE_TARGETS
+        .iter()
+        .find(|&&(prefix, _)| module.starts_with(prefix))
+        .map_or("distinst", |&(_, target)| target)
+}
+
+/// Passes a record to each subscriber which accepts its level, and to the install that it
+/// belongs to.
+fn dispatch(record: &Record) {
+    let message = record.args().to_string();
+    let message =
+        LogMessage { level: record.level(), target: target(record.target()), message: &message };
+
+    if let Ok(subscribers) = SUBSCRIBERS.read() {
+        for &(level, ref callback) in subscribers.iter() {
+            if message.level <= level {
+                callback(&message);
+            }
+        }
+    }
+
+    with_sink(|sink| {
+        if let Some(ref events) = sink.forward {
+            let _ = events.send(InstallerEvent::Log {
+                level:   message.level,
+                target:  message.target,
+                message: message.message.to_owned(),
+            });
+        }
+    });
+}
+
+/// Makes the logger of the installer the global logger, if it is not already.
+fn init() -> Result<(), fern::InitError> {
+    let mut initialized = INITIALIZED.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    if *initialized {
+        return Ok(());
+    }
+
     fern::Dispatch::new()
         // Include logs for crates that we use.
         .level(LevelFilter::Debug)
         // Exclude logs for crates which we don't care to log.
         .level_for("serde_xml_rs", LevelFilter::Off)
-        // This will be used by the front end for display logs in a UI
-        .chain(fern::Output::call(move |record| {
-            callback(record.level(), &format!("{}", record.args()))
-        }))
+        // This will be used by the front ends, to display and route logs.
+        .chain(fern::Output::call(dispatch))
+        // The log of each install, which is kept on the installed system.
+        .chain(fern::Output::call(buffer))
         // Whereas this will handle displaying the logs to the terminal & a log file
         .chain({
             let mut logger = fern::Dispatch::new()
                 .format(|out, message, record| {
                     out.finish(format_args!(
-                        "[{} distinst{}] {}",
+                        "[{} {}{}] {}",
                         record.level(),
+                        target(record.target()),
                         match (record.file(), record.line()) {
                             (Some(file), Some(line)) => format!(":{}:{}", file, line),
                             _ => "".into(),
@@ -30,10 +412,10 @@ pub fn log<F: Fn(Level, &str) + Send + Sync + 'static>(callback: F) -> Result<()
                 })
                 .chain(io::stderr());
 
-            match fern::log_file("/tmp/installer.log") {
+            match fern::log_file(LOG_FILE) {
                 Ok(log) => logger = logger.chain(log),
                 Err(why) => {
-                    eprintln!("failed to create log file at /tmp/installer.log: {}", why);
+                    eprintln!("failed to create log file at {}: {}", LOG_FILE, why);
                 }
             };
 
@@ -59,5 +441,78 @@ pub fn log<F: Fn(Level, &str) + Send + Sync + 'static>(callback: F) -> Result<()
         })
         .apply()?;
 
+    *initialized = true;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    const LOG: &str = r#"[INFO distinst] setting passphrase hunter2 on /dev/sda3
+-n "/dev/sda:primary:start:end:enc=cryptdata,data,pass=secret"
+[INFO distinst] pass=
+"#;
+
+    #[test]
+    fn targets() {
+        assert_eq!(target("distinst_external_commands::lvm"), "lvm");
+        assert_eq!(target("distinst_external_commands::luks"), "disk");
+        assert_eq!(target("distinst::installer::steps::configure::chroot_conf"), "chroot");
+        assert_eq!(target("distinst_squashfs::unsquashfs"), "extract");
+        assert_eq!(target("distinst_bootloader"), "bootloader");
+        assert_eq!(target("extract"), "extract");
+        assert_eq!(target("distinst::installer"), "distinst");
+    }
+
+    #[test]
+    fn sinks() {
+        let log = |message: &str| {
+            buffer(&Record::builder().args(format_args!("{}", message)).target("disk").build())
+        };
+
+        let log_of = |id: SinkId| {
+            let sinks = SINKS.lock().unwrap();
+            sinks.iter().find(|sink| sink.id == id).map(|sink| sink.log.clone()).unwrap()
+        };
+
+        let first = LogSink::start();
+        let id = first.id();
+        log("first");
+
+        // An install on another thread keeps a log of its own, and the threads which adopt
+        // the first install log to it instead.
+        let second = thread::spawn(move || {
+            let sink = LogSink::start();
+            log("second");
+            thread::spawn(move || {
+                let _adopted = adopt(Some(id));
+                log("adopted");
+            })
+            .join()
+            .unwrap();
+
+            log_of(sink.id())
+        })
+        .join()
+        .unwrap();
+
+        let first = log_of(first.id());
+        assert!(first.contains("first") && first.contains("adopted"));
+        assert!(!first.contains("second"));
+        assert!(second.contains("second"));
+        assert!(!second.contains("first") && !second.contains("adopted"));
+    }
+
+    #[test]
+    fn scrubbing() {
+        assert_eq!(
+            scrub(LOG, &["hunter2", ""]),
+            r#"[INFO distinst] setting passphrase [hidden] on /dev/sda3
+-n "/dev/sda:primary:start:end:enc=cryptdata,data,pass=[hidden]"
+[INFO distinst] pass=
+"#
+        );
+    }
+}
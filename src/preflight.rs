@@ -0,0 +1,316 @@
+//! Checks that the installer may run on this host at all, before any disks are touched: that
+//! it is privileged, that the commands which it runs are installed, and that the kernel's file
+//! systems are mounted.
+//!
+//! Without these, an install fails partway through with the `EPERM` or `ENOENT` of whichever
+//! step happened to need them first.
+
+use crate::{
+    disks::{Disks, PartitionInfo},
+    external,
+    installer::Config,
+    squashfs::{self, Source},
+};
+use proc_mounts::MountList;
+use std::{env, ffi::OsStr, fmt, fs, io, os::unix::fs::PermissionsExt, path::Path};
+
+/// The capabilities which partitioning, mounting, and configuring the install within a
+/// chroot require, by their number and name.
+const CAPABILITIES: &[(u32, &str)] = &[
+    (0, "CAP_CHOWN"),
+    (1, "CAP_DAC_OVERRIDE"),
+    (3, "CAP_FOWNER"),
+    (6, "CAP_SETGID"),
+    (7, "CAP_SETUID"),
+    (18, "CAP_SYS_CHROOT"),
+    (21, "CAP_SYS_ADMIN"),
+    (27, "CAP_MKNOD"),
+];
+
+/// The kernel's file systems, which the installer reads devices and mounts from, and binds
+/// into the chroot of the install.
+const KERNEL_MOUNTS: &[&str] = &["/dev", "/proc", "/sys"];
+
+/// The search path of root, for hosts whose environment lacks one.
+const DEFAULT_PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+
+/// A reason that an install can't run on this host.
+#[derive(Clone, Debug, PartialEq)]
+pub enum PreflightProblem {
+    /// The process lacks capabilities which the install needs, as it is not run as root, or
+    /// is run as root within a container which drops them.
+    Unprivileged { uid: u32, missing: Vec<&'static str> },
+    /// A command which the install runs on the host is not in the `PATH`.
+    MissingCommand { command: &'static str, needed_for: String },
+    /// One of `/dev`, `/proc`, or `/sys` is not mounted.
+    NotMounted(&'static str),
+}
+
+impl fmt::Display for PreflightProblem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PreflightProblem::Unprivileged { uid: 0, ref missing } => {
+                write!(f, "distinst is run as root, but lacks {}", missing.join(", "))
+            }
+            PreflightProblem::Unprivileged { uid, ref missing } => write!(
+                f,
+                "distinst must be run as root, but is run by uid {}, which lacks {}",
+                uid,
+                missing.join(", ")
+            ),
+            PreflightProblem::MissingCommand { command, ref needed_for } => {
+                write!(f, "{} is needed for {}, but was not found in PATH", command, needed_for)
+            }
+            PreflightProblem::NotMounted(path) => write!(f, "{} is not mounted", path),
+        }
+    }
+}
+
+impl std::error::Error for PreflightProblem {}
+
+/// Finds what would stop the disks from being installed to with the config on this host,
+/// which is nothing if the list is empty.
+///
+/// The commands which are checked for are those which are run on the host, as the disks and
+/// the source of the config require. The bootloader and other packages are configured by
+/// commands of the image, within its chroot, and so are not checked for here.
+pub fn preflight(disks: &Disks, config: &Config) -> Vec<PreflightProblem> {
+    let mut problems = Vec::new();
+
+    let uid = unsafe { libc::geteuid() };
+    let missing = match fs::read_to_string("/proc/self/status").ok().and_then(|s| cap_eff(&s)) {
+        Some(effective) => missing_capabilities(effective),
+        // Without `/proc`, which is reported below, root is assumed to be privileged.
+        None if uid == 0 => Vec::new(),
+        None => CAPABILITIES.iter().map(|&(_, name)| name).collect(),
+    };
+
+    if !missing.is_empty() {
+        problems.push(PreflightProblem::Unprivileged { uid, missing });
+    }
+
+    let search = env::var_os("PATH").unwrap_or_else(|| DEFAULT_PATH.into());
+    for (command, needed_for) in host_commands(disks, config) {
+        if !in_path(command, &search) {
+            problems.push(PreflightProblem::MissingCommand { command, needed_for });
+        }
+    }
+
+    // The mounts are listed by `/proc`, so the others can't be checked without it.
+    match MountList::new() {
+        Ok(mounts) => problems.extend(
+            unmounted(mounts.0.iter().map(|mount| mount.dest.as_path()))
+                .into_iter()
+                .map(PreflightProblem::NotMounted),
+        ),
+        Err(_) => problems.push(PreflightProblem::NotMounted("/proc")),
+    }
+
+    problems
+}
+
+/// Fails with each of the problems that `preflight` finds.
+pub(crate) fn verify(disks: &Disks, config: &Config) -> io::Result<()> {
+    let problems = preflight(disks, config);
+    if problems.is_empty() {
+        return Ok(());
+    }
+
+    let unprivileged =
+        problems.iter().any(|problem| matches!(*problem, PreflightProblem::Unprivileged { .. }));
+    let kind = if unprivileged { io::ErrorKind::PermissionDenied } else { io::ErrorKind::Other };
+    let problems = problems.iter().map(ToString::to_string).collect::<Vec<_>>();
+
+    Err(io::Error::new(kind, format!("preflight check failed: {}", problems.join("; "))))
+}
+
+/// The commands which the install runs on the host, with what each is needed for, in the
+/// order that they are first needed.
+fn host_commands(disks: &Disks, config: &Config) -> Vec<(&'static str, String)> {
+    let mut commands = disk_commands(disks.get_partitions());
+
+    // An invalid source is reported by the install itself, which has the better error.
+    let source = if squashfs::is_url(&config.squashfs) {
+        Some(Source::Remote(config.squashfs.clone()))
+    } else {
+        Source::detect(&config.squashfs).ok()
+    };
+
+    if let Some(ref source) = source {
+        let checksum = config.squashfs_checksum.is_some() || !config.layer_checksums.is_empty();
+        commands.extend(source_commands(source, checksum));
+    }
+
+    if config.overlay.is_some() {
+        commands.push(("rsync", "copying the overlay".into()));
+    }
+
+    dedup_commands(commands)
+}
+
+fn disk_commands<'a, I>(partitions: I) -> Vec<(&'static str, String)>
+where
+    I: Iterator<Item = &'a PartitionInfo>,
+{
+    let mut commands = ["wipefs", "blockdev", "partprobe", "udevadm", "blkid"]
+        .iter()
+        .map(|&command| (command, "partitioning the disks".to_owned()))
+        .collect::<Vec<_>>();
+
+    for part in partitions {
+        if part.encryption.is_some()
+            || part.volume_group.as_ref().map_or(false, |(_, encryption)| encryption.is_some())
+        {
+            commands.push(("cryptsetup", "encrypting partitions".into()));
+        }
+
+        if part.volume_group.is_some() {
+            for &command in &["pvcreate", "vgcreate", "lvcreate", "vgchange"] {
+                commands.push((command, "creating logical volumes".into()));
+            }
+        }
+
+        if let Some(fs) = part.filesystem.filter(|_| part.will_format()) {
+            if let Some(command) = external::mkfs_command(fs) {
+                let fs: &str = fs.into();
+                commands.push((command, format!("formatting {} partitions", fs)));
+            }
+        }
+    }
+
+    commands
+}
+
+fn source_commands(source: &Source, checksum: bool) -> Vec<(&'static str, String)> {
+    let mut commands = Vec::new();
+    match *source {
+        Source::Squashfs(_) | Source::Layered(_) => {
+            commands.push(("unsquashfs", "extracting squashfs images".into()))
+        }
+        Source::Tar(..) => commands.push(("tar", "extracting tar archives".into())),
+        // Whether the download is a squashfs image or a tar archive isn't known until then.
+        Source::Remote(_) => commands.push(("curl", "downloading remote images".into())),
+        Source::Directory { .. } => commands.push(("rsync", "copying directories".into())),
+    }
+
+    if checksum {
+        commands.push(("sha256sum", "verifying the checksum of the image".into()));
+    }
+
+    commands
+}
+
+/// Keeps the first reason that each command is needed for.
+fn dedup_commands(commands: Vec<(&'static str, String)>) -> Vec<(&'static str, String)> {
+    let mut unique: Vec<(&'static str, String)> = Vec::new();
+    for (command, needed_for) in commands {
+        if unique.iter().all(|&(existing, _)| existing != command) {
+            unique.push((command, needed_for));
+        }
+    }
+
+    unique
+}
+
+/// Whether an executable file of the name is in a directory of the search path.
+fn in_path(command: &str, search: &OsStr) -> bool {
+    env::split_paths(search).any(|dir| {
+        fs::metadata(dir.join(command))
+            .map(|meta| meta.is_file() && meta.permissions().mode() & 0o111 != 0)
+            .unwrap_or(false)
+    })
+}
+
+/// The mask of effective capabilities from `/proc/self/status`, as in `CapEff: 000001ffffffffff`.
+fn cap_eff(status: &str) -> Option<u64> {
+    let mask = status.lines().find(|line| line.starts_with("CapEff:"))?["CapEff:".len()..].trim();
+    u64::from_str_radix(mask, 16).ok()
+}
+
+fn missing_capabilities(effective: u64) -> Vec<&'static str> {
+    CAPABILITIES
+        .iter()
+        .filter(|&&(number, _)| effective & (1 << number) == 0)
+        .map(|&(_, name)| name)
+        .collect()
+}
+
+fn unmounted<'a, I: IntoIterator<Item = &'a Path>>(mounted: I) -> Vec<&'static str> {
+    let mounted = mounted.into_iter().collect::<Vec<_>>();
+    KERNEL_MOUNTS.iter().cloned().filter(|&path| !mounted.contains(&Path::new(path))).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disks::PartitionBuilder;
+    use disk_types::FileSystem;
+    use std::{ffi::OsString, path::PathBuf};
+    use tempdir::TempDir;
+
+    #[test]
+    fn capabilities() {
+        let status = "Name:\tdistinst\nCapInh:\t0000000000000000\nCapEff:\t00000000a80425fb\n";
+        let effective = cap_eff(status).unwrap();
+        assert_eq!(missing_capabilities(effective), vec!["CAP_SYS_ADMIN"]);
+        assert!(missing_capabilities(0x1ff_ffff_ffff).is_empty());
+        assert_eq!(missing_capabilities(0).len(), CAPABILITIES.len());
+        assert_eq!(cap_eff("Name:\tdistinst\n"), None);
+    }
+
+    #[test]
+    fn commands() {
+        let names = |commands: Vec<(&'static str, String)>| {
+            commands.into_iter().map(|(command, _)| command).collect::<Vec<_>>()
+        };
+
+        let partitions = [
+            PartitionBuilder::new(0, 1, FileSystem::Fat32).build(),
+            PartitionBuilder::new(1, 2, FileSystem::Lvm)
+                .logical_volume("data".into(), None)
+                .build(),
+            PartitionBuilder::new(2, 3, FileSystem::Fat16).build(),
+        ];
+        let commands = dedup_commands(disk_commands(partitions.iter()));
+        assert_eq!(
+            &names(commands)[5..],
+            &["mkfs.fat", "pvcreate", "vgcreate", "lvcreate", "vgchange"]
+        );
+
+        let commands = source_commands(&Source::Remote("https://example.com".into()), true);
+        assert_eq!(names(commands), vec!["curl", "sha256sum"]);
+
+        let directory = Source::Directory { path: PathBuf::from("/"), delete: false };
+        assert_eq!(source_commands(&directory, false)[0].0, "rsync");
+
+        let commands = dedup_commands(vec![
+            ("rsync", "copying directories".into()),
+            ("rsync", "copying the overlay".into()),
+        ]);
+        assert_eq!(commands, vec![("rsync", "copying directories".into())]);
+    }
+
+    #[test]
+    fn search_path() {
+        let dirs = [TempDir::new("distinst").unwrap(), TempDir::new("distinst").unwrap()];
+        for (dir, mode) in dirs.iter().zip(&[0o644, 0o755]) {
+            let path = dir.path().join("unsquashfs");
+            fs::write(&path, "").unwrap();
+            fs::set_permissions(&path, fs::Permissions::from_mode(*mode)).unwrap();
+        }
+
+        let search = env::join_paths(dirs.iter().map(|dir| dir.path())).unwrap();
+        assert!(in_path("unsquashfs", &search));
+        assert!(!in_path("unsquashfs", &env::join_paths(&[dirs[0].path()]).unwrap()));
+        assert!(!in_path("cryptsetup", &search));
+        assert!(!in_path("unsquashfs", &OsString::new()));
+    }
+
+    #[test]
+    fn mounts() {
+        let mounted = [Path::new("/"), Path::new("/proc"), Path::new("/sys")];
+        assert_eq!(unmounted(mounted.iter().cloned()), vec!["/dev"]);
+        assert!(unmounted([Path::new("/dev")].iter().cloned().chain(mounted[1..].iter().cloned()))
+            .is_empty());
+    }
+}
@@ -0,0 +1,524 @@
+use super::{invalid, RecipeFile};
+use crate::{
+    disks::{
+        validate_volume_group, CrypttabOptions, Disk, DiskExt, Disks, LvmEncryption,
+        PartitionBuilder, PartitionFlag, PartitionInfo, SwapEncryption,
+    },
+    FileSystem, PartitionTable, PartitionType, Sector, SectorExt,
+};
+use std::{io, path::PathBuf};
+
+/// A disk, whose partitions are removed, moved, reused, and then created, in that order.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DiskRecipe {
+    pub device:     PathBuf,
+    /// A new partition table, which clobbers the partitions of the disk.
+    pub table:      Option<TableRecipe>,
+    /// The numbers of the partitions to remove.
+    pub remove:     Vec<i32>,
+    #[serde(rename = "move")]
+    pub moves:      Vec<MoveRecipe>,
+    pub reuse:      Vec<ReuseRecipe>,
+    /// New partitions, which are created in order.
+    pub partitions: Vec<PartitionRecipe>,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum TableRecipe {
+    Gpt,
+    Msdos,
+}
+
+/// Moves the start of a partition, and resizes it to its end. Sectors are given as they
+/// are to the CLI, such as `start`, `-512M`, `50%`, or `2048MiB`, where `M` are megabytes
+/// and `MiB` are mebibytes.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MoveRecipe {
+    pub number: i32,
+    pub start:  Option<String>,
+    pub end:    Option<String>,
+}
+
+/// An existing partition, which is formatted if `fs` or `lvm` is set.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReuseRecipe {
+    pub number:      i32,
+    pub fs:          Option<String>,
+    pub lvm:         Option<LvmRecipe>,
+    pub mount:       Option<PathBuf>,
+    pub flags:       Option<Vec<String>>,
+    /// The ID of a keyfile which is stored on this partition, which requires `mount`.
+    pub keyid:       Option<String>,
+    /// See `PartitionRecipe::random_swap`.
+    pub random_swap: bool,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PartitionRecipe {
+    pub kind:        PartitionKindRecipe,
+    pub start:       String,
+    pub end:         String,
+    /// The file system, or none if `lvm` is set, or the partition is left unformatted.
+    pub fs:          Option<String>,
+    pub lvm:         Option<LvmRecipe>,
+    pub mount:       Option<PathBuf>,
+    pub flags:       Vec<String>,
+    pub keyid:       Option<String>,
+    /// Encrypts swap with a random key on every boot, instead of formatting it.
+    pub random_swap: bool,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum PartitionKindRecipe {
+    Primary,
+    Logical,
+    Extended,
+}
+
+impl Default for PartitionKindRecipe {
+    fn default() -> Self { PartitionKindRecipe::Primary }
+}
+
+/// Assigns a partition to a volume group, optionally within a LUKS device.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LvmRecipe {
+    pub group:      String,
+    #[serde(default)]
+    pub encryption: Option<EncryptionRecipe>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EncryptionRecipe {
+    /// The name of the LUKS device, which is the physical volume of the group.
+    pub physical_volume: String,
+    pub password:        Option<String>,
+    /// The ID of the partition which stores the keyfile, given as its `keyid`.
+    pub keyfile:         Option<String>,
+    /// A path in the install to store the LUKS header at, instead of on the device.
+    pub header:          Option<PathBuf>,
+    pub discard:         Option<bool>,
+    pub tries:           Option<u32>,
+    pub keyscript:       Option<PathBuf>,
+    pub tpm2:            bool,
+}
+
+/// A volume group, which is configured once the disks are partitioned and existing LUKS
+/// partitions are decrypted.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct VolumeGroupRecipe {
+    pub group:      String,
+    /// Removes every existing volume of the group.
+    pub remove_all: bool,
+    /// The names of existing volumes to remove.
+    pub remove:     Vec<String>,
+    pub modify:     Vec<VolumeModifyRecipe>,
+    /// New volumes, which are created in order.
+    pub volumes:    Vec<VolumeRecipe>,
+}
+
+/// An existing volume, which is formatted if `fs` is set.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct VolumeModifyRecipe {
+    pub name:  String,
+    pub fs:    Option<String>,
+    pub mount: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct VolumeRecipe {
+    pub name:        String,
+    /// The size of the volume, or none if it takes the rest of the group.
+    pub size:        Option<String>,
+    pub fs:          Option<String>,
+    pub mount:       Option<PathBuf>,
+    pub flags:       Vec<String>,
+    pub encryption:  Option<EncryptionRecipe>,
+    /// See `PartitionRecipe::random_swap`.
+    pub random_swap: bool,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DecryptRecipe {
+    pub device:          PathBuf,
+    pub physical_volume: String,
+    pub password:        Option<String>,
+    pub keyfile:         Option<String>,
+}
+
+impl RecipeFile {
+    /// Probes the disks of the recipe, and applies its partitioning to them. Nothing is
+    /// written to the disks until they are installed to.
+    pub fn disks(&self) -> io::Result<Disks> {
+        let mut disks = Disks::default();
+        for (id, recipe) in self.disks.iter().enumerate() {
+            let disk = Disk::from_name(&recipe.device)
+                .map_err(|why| invalid(format_args!("disks[{}].device", id), why))?;
+            disks.add(disk);
+        }
+
+        for (id, recipe) in self.disks.iter().enumerate() {
+            let disk = disks.find_disk_mut(&recipe.device).expect("disk was added");
+            configure_disk(disk, recipe, &format!("disks[{}]", id))?;
+        }
+
+        disks
+            .initialize_volume_groups()
+            .map_err(|why| invalid("lvm", format_args!("failed to initialize: {}", why)))?;
+
+        for (id, decrypt) in self.decrypt.iter().enumerate() {
+            let encryption = LvmEncryption::new(
+                decrypt.physical_volume.clone(),
+                decrypt.password.clone(),
+                decrypt.keyfile.clone(),
+            );
+
+            disks
+                .decrypt_partition(&decrypt.device, &encryption)
+                .map_err(|why| invalid(format_args!("decrypt[{}]", id), why))?;
+        }
+
+        for (id, recipe) in self.lvm.iter().enumerate() {
+            let path = format!("lvm[{}]", id);
+            let device = disks.get_logical_device_mut(&recipe.group).ok_or_else(|| {
+                invalid(format_args!("{}.group", path), "no partition is assigned to the group")
+            })?;
+
+            if recipe.remove_all {
+                device.clear_partitions();
+            }
+
+            for (id, volume) in recipe.remove.iter().enumerate() {
+                device
+                    .remove_partition(volume)
+                    .map_err(|why| invalid(format_args!("{}.remove[{}]", path, id), why))?;
+            }
+
+            for (id, modify) in recipe.modify.iter().enumerate() {
+                let path = format!("{}.modify[{}]", path, id);
+                let partition = device.get_partition_mut(&modify.name).ok_or_else(|| {
+                    invalid(format_args!("{}.name", path), "the volume does not exist")
+                })?;
+
+                if let Some(ref fs) = modify.fs {
+                    partition.format_and_keep_name(file_system(fs, &path)?);
+                }
+
+                if let Some(ref mount) = modify.mount {
+                    partition.set_mount(mount.clone());
+                }
+            }
+
+            for (id, volume) in recipe.volumes.iter().enumerate() {
+                let path = format!("{}.volumes[{}]", path, id);
+                let fs = match volume.fs {
+                    Some(ref fs) => Some(file_system(fs, &path)?),
+                    None => None,
+                };
+
+                // The placement of the volume is calculated when it is added.
+                let mut builder = match volume.size {
+                    Some(ref size) => {
+                        let size = sector(&*device, size, &format!("{}.size", path))?;
+                        PartitionBuilder::new(0, size, fs)
+                    }
+                    None => PartitionBuilder::new(0, 1, fs).remainder(),
+                }
+                .name(volume.name.clone())
+                .flags(flags(&volume.flags, &path)?);
+
+                if let Some(ref mount) = volume.mount {
+                    builder = builder.mount(mount.clone());
+                }
+
+                if let Some(ref encryption) = volume.encryption {
+                    builder = builder.encryption(encryption.to_encryption());
+                }
+
+                if volume.random_swap {
+                    builder = builder.swap_encryption(SwapEncryption::Random);
+                }
+
+                device.add_partition(builder).map_err(|why| invalid(&path, why))?;
+            }
+        }
+
+        if let Some(ref disk) = self.bootloader.disk {
+            disks.set_bootloader_disk(disk).map_err(|why| invalid("bootloader.disk", why))?;
+        }
+
+        Ok(disks)
+    }
+}
+
+fn configure_disk(disk: &mut Disk, recipe: &DiskRecipe, path: &str) -> io::Result<()> {
+    match recipe.table {
+        Some(TableRecipe::Gpt) => disk.mklabel(PartitionTable::Gpt),
+        Some(TableRecipe::Msdos) => disk.mklabel(PartitionTable::Msdos),
+        None => Ok(()),
+    }
+    .map_err(|why| invalid(format_args!("{}.table", path), why))?;
+
+    for (id, &number) in recipe.remove.iter().enumerate() {
+        disk.remove_partition(number)
+            .map_err(|why| invalid(format_args!("{}.remove[{}]", path, id), why))?;
+    }
+
+    for (id, moved) in recipe.moves.iter().enumerate() {
+        let path = format!("{}.move[{}]", path, id);
+        if let Some(ref start) = moved.start {
+            let start = sector(&*disk, start, &format!("{}.start", path))?;
+            disk.move_partition(moved.number, start).map_err(|why| invalid(&path, why))?;
+        }
+
+        if let Some(ref end) = moved.end {
+            let end = sector(&*disk, end, &format!("{}.end", path))?;
+            disk.resize_partition(moved.number, end).map_err(|why| invalid(&path, why))?;
+        }
+    }
+
+    for (id, reuse) in recipe.reuse.iter().enumerate() {
+        let path = format!("{}.reuse[{}]", path, id);
+        let partition = disk.get_partition_mut(reuse.number).ok_or_else(|| {
+            invalid(format_args!("{}.number", path), "the partition does not exist")
+        })?;
+
+        reuse_partition(partition, reuse, &path)?;
+    }
+
+    for (id, recipe) in recipe.partitions.iter().enumerate() {
+        let path = format!("{}.partitions[{}]", path, id);
+        let start = sector(&*disk, &recipe.start, &format!("{}.start", path))?;
+        let end = sector(&*disk, &recipe.end, &format!("{}.end", path))?;
+        let kind = match recipe.kind {
+            PartitionKindRecipe::Primary => PartitionType::Primary,
+            PartitionKindRecipe::Logical => PartitionType::Logical,
+            PartitionKindRecipe::Extended => PartitionType::Extended,
+        };
+
+        let mut builder = match (&recipe.fs, &recipe.lvm) {
+            (Some(_), Some(_)) => return Err(invalid(&path, "fs and lvm are exclusive")),
+            (Some(fs), None) => PartitionBuilder::new(start, end, file_system(fs, &path)?),
+            (None, Some(lvm)) => {
+                let (group, encryption) = lvm.to_volume_group(&path)?;
+                PartitionBuilder::new(start, end, FileSystem::Lvm).logical_volume(group, encryption)
+            }
+            (None, None) => PartitionBuilder::new(start, end, None),
+        }
+        .partition_type(kind)
+        .flags(flags(&recipe.flags, &path)?);
+
+        if let Some(ref mount) = recipe.mount {
+            builder = builder.mount(mount.clone());
+        }
+
+        if let Some(ref keyid) = recipe.keyid {
+            if recipe.mount.is_none() {
+                return Err(invalid(format_args!("{}.keyid", path), "a mount is required"));
+            }
+
+            builder = builder.associate_keyfile(keyid.clone());
+        }
+
+        if recipe.random_swap {
+            builder = builder.swap_encryption(SwapEncryption::Random);
+        }
+
+        disk.add_partition(builder).map_err(|why| invalid(&path, why))?;
+    }
+
+    Ok(())
+}
+
+fn reuse_partition(
+    partition: &mut PartitionInfo,
+    reuse: &ReuseRecipe,
+    path: &str,
+) -> io::Result<()> {
+    if let Some(ref keyid) = reuse.keyid {
+        if reuse.mount.is_none() {
+            return Err(invalid(format_args!("{}.keyid", path), "a mount is required"));
+        }
+
+        partition.associate_keyfile(keyid.clone());
+    }
+
+    if let Some(ref mount) = reuse.mount {
+        partition.set_mount(mount.clone());
+    }
+
+    match (&reuse.fs, &reuse.lvm) {
+        (Some(_), Some(_)) => return Err(invalid(path, "fs and lvm are exclusive")),
+        (Some(fs), None) => partition.format_with(file_system(fs, path)?),
+        (None, Some(lvm)) => {
+            let (group, encryption) = lvm.to_volume_group(path)?;
+            partition
+                .set_volume_group(group, encryption)
+                .map_err(|why| invalid(format_args!("{}.lvm", path), why))?;
+            partition.format_with(FileSystem::Lvm);
+        }
+        (None, None) => (),
+    }
+
+    if let Some(ref names) = reuse.flags {
+        partition.flags = flags(names, path)?;
+    }
+
+    if reuse.random_swap {
+        partition.set_swap_encryption(SwapEncryption::Random);
+    }
+
+    Ok(())
+}
+
+impl LvmRecipe {
+    fn to_volume_group(&self, path: &str) -> io::Result<(String, Option<LvmEncryption>)> {
+        let encryption = self.encryption.as_ref().map(EncryptionRecipe::to_encryption);
+        validate_volume_group(&self.group, encryption.as_ref())
+            .map_err(|why| invalid(format_args!("{}.lvm", path), why))?;
+        Ok((self.group.clone(), encryption))
+    }
+}
+
+impl EncryptionRecipe {
+    fn to_encryption(&self) -> LvmEncryption {
+        let mut encryption = LvmEncryption::new(
+            self.physical_volume.clone(),
+            self.password.clone(),
+            self.keyfile.clone(),
+        );
+
+        encryption.crypttab = CrypttabOptions {
+            discard:   self.discard,
+            tries:     self.tries,
+            keyscript: self.keyscript.clone(),
+            tpm2:      self.tpm2,
+        };
+
+        match self.header {
+            Some(ref header) => encryption.detached_header(header.clone()),
+            None => encryption,
+        }
+    }
+}
+
+/// Parses a sector, and finds it on the device. `MiB` are converted to the logical sectors
+/// of the device, as the megabytes of `Sector` are decimal.
+fn sector<D: SectorExt>(device: &D, value: &str, path: &str) -> io::Result<u64> {
+    let sector = if value.ends_with("MiB") {
+        let mebibytes = |value: &str| {
+            let bytes = value.parse::<u64>().ok()?.checked_mul(1_048_576)?;
+            Some(bytes / device.get_logical_block_size())
+        };
+
+        let value = &value[..value.len() - 3];
+        if value.starts_with('-') {
+            mebibytes(&value[1..]).map(Sector::UnitFromEnd)
+        } else {
+            mebibytes(value).map(Sector::Unit)
+        }
+    } else {
+        value.parse::<Sector>().ok()
+    };
+
+    match sector {
+        Some(sector) => Ok(device.get_sector(sector)),
+        None => Err(invalid(path, format_args!("invalid sector value '{}'", value))),
+    }
+}
+
+fn file_system(name: &str, path: &str) -> io::Result<FileSystem> {
+    name.parse::<FileSystem>().map_err(|_| {
+        invalid(format_args!("{}.fs", path), format_args!("unknown file system '{}'", name))
+    })
+}
+
+fn flags(names: &[String], path: &str) -> io::Result<Vec<PartitionFlag>> {
+    let mut flags = Vec::with_capacity(names.len());
+    for (id, name) in names.iter().enumerate() {
+        flags.push(match name.as_str() {
+            "esp" => PartitionFlag::PED_PARTITION_ESP,
+            "boot" => PartitionFlag::PED_PARTITION_BOOT,
+            "root" => PartitionFlag::PED_PARTITION_ROOT,
+            "swap" => PartitionFlag::PED_PARTITION_SWAP,
+            "hidden" => PartitionFlag::PED_PARTITION_HIDDEN,
+            "raid" => PartitionFlag::PED_PARTITION_RAID,
+            "lvm" => PartitionFlag::PED_PARTITION_LVM,
+            "lba" => PartitionFlag::PED_PARTITION_LBA,
+            "hpservice" => PartitionFlag::PED_PARTITION_HPSERVICE,
+            "palo" => PartitionFlag::PED_PARTITION_PALO,
+            "prep" => PartitionFlag::PED_PARTITION_PREP,
+            "msft_reserved" => PartitionFlag::PED_PARTITION_MSFT_RESERVED,
+            "apple_tv_recovery" => PartitionFlag::PED_PARTITION_APPLE_TV_RECOVERY,
+            "diag" => PartitionFlag::PED_PARTITION_DIAG,
+            "legacy_boot" => PartitionFlag::PED_PARTITION_LEGACY_BOOT,
+            "msft_data" => PartitionFlag::PED_PARTITION_MSFT_DATA,
+            "irst" => PartitionFlag::PED_PARTITION_IRST,
+            _ => {
+                return Err(invalid(
+                    format_args!("{}.flags[{}]", path, id),
+                    format_args!("unknown partition flag '{}'", name),
+                ))
+            }
+        });
+    }
+
+    Ok(flags)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::BlockDeviceExt;
+    use std::path::Path;
+
+    /// A disk of 8 GiB, whose logical sectors are of the given size.
+    struct FictionalDisk(u64);
+
+    impl BlockDeviceExt for FictionalDisk {
+        fn get_device_path(&self) -> &Path { Path::new("/dev/fictional") }
+    }
+
+    impl SectorExt for FictionalDisk {
+        fn get_sectors(&self) -> u64 { 8 * 1024 * 1024 * 1024 / self.0 }
+
+        fn get_logical_block_size(&self) -> u64 { self.0 }
+    }
+
+    #[test]
+    fn values() {
+        let (disk, native) = (FictionalDisk(512), FictionalDisk(4096));
+        assert_eq!(sector(&disk, "end", "end").unwrap(), 16_773_120);
+        assert_eq!(sector(&disk, "512MiB", "end").unwrap(), 1_048_576);
+        assert_eq!(sector(&native, "512MiB", "end").unwrap(), 131_072);
+        assert_eq!(sector(&disk, "-1024MiB", "end").unwrap(), 16_773_120 - 2_097_152);
+        assert_eq!(sector(&disk, "-4096M", "end").unwrap(), 16_773_120 - 8_000_000);
+        assert_eq!(
+            sector(&disk, "half", "disks[0].partitions[1].end").unwrap_err().to_string(),
+            "disks[0].partitions[1].end: invalid sector value 'half'"
+        );
+
+        assert_eq!(file_system("ext4", "").unwrap(), FileSystem::Ext4);
+        assert_eq!(
+            file_system("ext5", "disks[0].partitions[2]").unwrap_err().to_string(),
+            "disks[0].partitions[2].fs: unknown file system 'ext5'"
+        );
+
+        let names = ["esp".to_owned(), "boot".to_owned(), "sticky".to_owned()];
+        assert_eq!(
+            flags(&names, "disks[0].partitions[0]").unwrap_err().to_string(),
+            "disks[0].partitions[0].flags[2]: unknown partition flag 'sticky'"
+        );
+    }
+}
@@ -0,0 +1,641 @@
+//! Unattended installs, which declare their disks and the configuration of the system in
+//! a TOML or JSON file, instead of in the arguments of the CLI.
+//!
+//! ```toml
+//! squashfs = "/cdrom/casper/filesystem.squashfs"
+//! remove = "/cdrom/casper/filesystem.manifest-remove"
+//! hostname = "pop-os"
+//!
+//! [[users]]
+//! username = "admin"
+//! password_hash = "$6$..."
+//!
+//! [[disks]]
+//! device = "/dev/sda"
+//! table = "gpt"
+//!
+//! [[disks.partitions]]
+//! start = "start"
+//! end = "512M"
+//! fs = "fat32"
+//! mount = "/boot/efi"
+//! flags = ["esp"]
+//!
+//! [[disks.partitions]]
+//! start = "512M"
+//! end = "end"
+//! lvm = { group = "data", encryption = { physical_volume = "cryptdata", password = "..." } }
+//!
+//! [[lvm]]
+//! group = "data"
+//! volumes = [{ name = "root", fs = "ext4", mount = "/" }]
+//! ```
+
+mod disks;
+
+pub use self::disks::*;
+
+use crate::{
+    installer::{
+        BootOrderPolicy, BootloaderChoice, Config, FirstBootUnit, FstabId, GrubOptions,
+        InitramfsTool, InstallMode, Installer, KeyboardConfig, NetworkConfig, RecoveryConfig,
+        RecoverySource, RootPolicy, SshConfig, SystemdBootOptions, UserAccountCreate, ZramConfig,
+        DELETE_EXTRANEOUS, FORCE_FALLBACK, INSTALL_HARDWARE_SUPPORT, KEEP_IDENTITY, KEEP_OLD_ROOT,
+        MODIFY_BOOT_ORDER, RUN_UBUNTU_DRIVERS, WINDOWS_SECOND,
+    },
+    Disks,
+};
+use serde::de::DeserializeOwned;
+use std::{
+    fmt, fs, io,
+    path::{Path, PathBuf},
+};
+use zeroize::Zeroizing;
+
+/// An install which is declared in a file. Files ending in `.json` are read as JSON, and
+/// others as TOML.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RecipeFile {
+    /// The image which contains the base system.
+    pub squashfs:               Option<String>,
+    /// Squashfs images which are extracted over the base system, in order.
+    pub layers:                 Vec<String>,
+    pub squashfs_checksum:      Option<String>,
+    /// The checksums of `layers`, in the same order.
+    pub layer_checksums:        Vec<String>,
+    /// The manifest of the packages to remove once installed.
+    pub remove:                 Option<String>,
+    pub hostname:               Option<String>,
+    /// The locale of the install, which is `en_US.UTF-8` by default.
+    pub lang:                   Option<String>,
+    pub additional_locales:     Vec<String>,
+    pub language_packages:      Option<Vec<String>>,
+    pub timezone:               Option<String>,
+    /// The keyboard, which is the `us` layout by default.
+    pub keyboard:               Option<KeyboardRecipe>,
+    pub users:                  Vec<UserRecipe>,
+    pub root:                   RootRecipe,
+    pub hosts_append:           Vec<String>,
+    pub local_packages:         Option<PathBuf>,
+    pub allow_network:          bool,
+    pub install_drivers:        bool,
+    pub fstab_id:               FstabIdRecipe,
+    pub hibernation:            bool,
+    /// The size, in MiB, of a swap file.
+    pub swapfile:               Option<u64>,
+    pub zram:                   Option<ZramRecipe>,
+    pub oem_mode:               bool,
+    pub recovery:               Option<RecoveryRecipe>,
+    /// The UUID of the root of an install to refresh, instead of installing a new system.
+    pub refresh:                Option<String>,
+    pub preserve:               Option<Vec<PathBuf>>,
+    pub initramfs:              Option<InitramfsRecipe>,
+    pub network:                Option<NetworkRecipe>,
+    pub ssh:                    Option<SshRecipe>,
+    pub overlay:                Option<PathBuf>,
+    pub firstboot:              Vec<FirstBootRecipe>,
+    pub snapshot_previous_root: bool,
+    /// The size of the snapshot of the previous root, which is 20 percent by default.
+    pub snapshot_percent:       Option<u8>,
+    pub flags:                  FlagsRecipe,
+    pub bootloader:             BootloaderRecipe,
+    /// The disks which are partitioned, in the order in which they are probed.
+    pub disks:                  Vec<DiskRecipe>,
+    /// Volume groups which are configured once the disks have been partitioned.
+    pub lvm:                    Vec<VolumeGroupRecipe>,
+    /// Existing LUKS partitions which are decrypted, so that their volume groups may be
+    /// reused.
+    pub decrypt:                Vec<DecryptRecipe>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct KeyboardRecipe {
+    pub layout:  String,
+    pub variant: Option<String>,
+    pub model:   Option<String>,
+    pub options: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UserRecipe {
+    pub username:      String,
+    pub realname:      Option<String>,
+    pub password:      Option<String>,
+    pub password_hash: Option<String>,
+    pub profile_icon:  Option<String>,
+    pub groups:        Option<Vec<String>>,
+    pub uid:           Option<u32>,
+    pub gid:           Option<u32>,
+    pub shell:         Option<String>,
+    pub no_password:   bool,
+    pub autologin:     bool,
+}
+
+impl UserRecipe {
+    /// The account which the recipe describes.
+    pub fn account(&self) -> UserAccountCreate {
+        UserAccountCreate {
+            username:      self.username.clone(),
+            realname:      self.realname.clone(),
+            password:      self.password.clone().map(Zeroizing::new),
+            password_hash: self.password_hash.clone(),
+            profile_icon:  self.profile_icon.clone(),
+            groups:        self.groups.clone(),
+            uid:           self.uid,
+            gid:           self.gid,
+            shell:         self.shell.clone(),
+            no_password:   self.no_password,
+            autologin:     self.autologin,
+        }
+    }
+}
+
+/// Root is locked unless a password hash or authorized keys are given.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RootRecipe {
+    pub password_hash:   Option<String>,
+    pub authorized_keys: Vec<String>,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum FstabIdRecipe {
+    Uuid,
+    Partuuid,
+    Label,
+    Path,
+}
+
+impl Default for FstabIdRecipe {
+    fn default() -> Self { FstabIdRecipe::Uuid }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ZramRecipe {
+    pub fraction_of_ram: f32,
+    #[serde(default)]
+    pub algorithm:       Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RecoveryRecipe {
+    pub partition: PathBuf,
+    /// The `casper` directory or ISO of the live system, which is `/cdrom/casper` by
+    /// default.
+    #[serde(default)]
+    pub source:    Option<PathBuf>,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum InitramfsRecipe {
+    InitramfsTools,
+    Dracut,
+    Mkinitcpio,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NetworkRecipe {
+    pub proxy:      Option<String>,
+    pub apt_mirror: Option<String>,
+    pub dns:        Vec<String>,
+    pub persist:    bool,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SshRecipe {
+    pub enable_server:         bool,
+    pub authorized_keys:       Vec<String>,
+    pub permit_root_login:     bool,
+    pub disable_password_auth: bool,
+}
+
+/// A service which is run on the first boot: either a `script` of the live system, or the
+/// command line of `exec`.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FirstBootRecipe {
+    pub name:     String,
+    pub script:   Option<PathBuf>,
+    pub exec:     Option<String>,
+    pub run_once: bool,
+}
+
+/// The flags of `Config::flags`.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FlagsRecipe {
+    pub modify_boot_order:        bool,
+    pub install_hardware_support: bool,
+    pub keep_old_root:            bool,
+    pub run_ubuntu_drivers:       bool,
+    pub delete_extraneous:        bool,
+    pub keep_identity:            bool,
+    pub force_fallback:           bool,
+    pub windows_second:           bool,
+}
+
+impl FlagsRecipe {
+    pub fn bits(&self) -> u8 {
+        [
+            (self.modify_boot_order, MODIFY_BOOT_ORDER),
+            (self.install_hardware_support, INSTALL_HARDWARE_SUPPORT),
+            (self.keep_old_root, KEEP_OLD_ROOT),
+            (self.run_ubuntu_drivers, RUN_UBUNTU_DRIVERS),
+            (self.delete_extraneous, DELETE_EXTRANEOUS),
+            (self.keep_identity, KEEP_IDENTITY),
+            (self.force_fallback, FORCE_FALLBACK),
+            (self.windows_second, WINDOWS_SECOND),
+        ]
+        .iter()
+        .filter(|&&(set, _)| set)
+        .fold(0, |flags, &(_, flag)| flags | flag)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BootloaderRecipe {
+    pub kind:           BootloaderKindRecipe,
+    /// The disk whose ESP or MBR the bootloader is installed to.
+    pub disk:           Option<PathBuf>,
+    pub efi_label:      Option<String>,
+    pub boot_order:     BootOrderRecipe,
+    pub os_prober:      bool,
+    pub kernel_cmdline: Vec<String>,
+    pub systemd_boot:   SystemdBootRecipe,
+    pub grub:           GrubRecipe,
+}
+
+impl Default for BootloaderRecipe {
+    fn default() -> Self {
+        BootloaderRecipe {
+            kind:           BootloaderKindRecipe::Auto,
+            disk:           None,
+            efi_label:      None,
+            boot_order:     BootOrderRecipe::First,
+            os_prober:      true,
+            kernel_cmdline: Vec::new(),
+            systemd_boot:   SystemdBootRecipe::default(),
+            grub:           GrubRecipe::default(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum BootloaderKindRecipe {
+    Auto,
+    SystemdBoot,
+    GrubEfi,
+    GrubBios,
+    GrubIeee1275,
+    Extlinux,
+    RaspberryPi,
+}
+
+impl Default for BootloaderKindRecipe {
+    fn default() -> Self { BootloaderKindRecipe::Auto }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum BootOrderRecipe {
+    First,
+    Last,
+    Preserve,
+}
+
+impl Default for BootOrderRecipe {
+    fn default() -> Self { BootOrderRecipe::First }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SystemdBootRecipe {
+    pub timeout:      Option<u32>,
+    pub default:      Option<String>,
+    pub console_mode: Option<String>,
+    pub editor:       Option<bool>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GrubRecipe {
+    pub distributor:    Option<String>,
+    pub theme_dir:      Option<PathBuf>,
+    pub background:     Option<PathBuf>,
+    pub hidden_timeout: Option<u32>,
+    pub terminal:       Option<String>,
+}
+
+impl RecipeFile {
+    /// Reads the recipe at `path`, and produces the disks and the configuration which it
+    /// declares, once both have been validated.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<(Disks, Config)> {
+        let recipe = Self::open(path)?;
+        let config = recipe.config()?;
+
+        for &(field, value) in &[("squashfs", &config.squashfs), ("remove", &config.remove)] {
+            if value.is_empty() {
+                return Err(invalid(field, "is required"));
+            }
+        }
+
+        Installer::validate(&config)?;
+        let disks = recipe.disks()?;
+        Ok((disks, config))
+    }
+
+    /// Reads the recipe at `path`, without validating it.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref();
+        let contents = fs::read_to_string(path).map_err(|why| {
+            io::Error::new(why.kind(), format!("failed to read {}: {}", path.display(), why))
+        })?;
+
+        let recipe = if path.extension().map_or(false, |ext| ext == "json") {
+            Self::from_json(&contents)
+        } else {
+            Self::from_toml(&contents)
+        };
+
+        recipe.map_err(|why| io::Error::new(why.kind(), format!("{}: {}", path.display(), why)))
+    }
+
+    pub fn from_toml(contents: &str) -> io::Result<Self> {
+        let mut deserializer = toml::Deserializer::new(contents);
+        let recipe = deserialize(&mut deserializer)?;
+        deserializer.end().map_err(|why| invalid("recipe", why))?;
+        Ok(recipe)
+    }
+
+    pub fn from_json(contents: &str) -> io::Result<Self> {
+        let mut deserializer = serde_json::Deserializer::from_str(contents);
+        let recipe = deserialize(&mut deserializer)?;
+        deserializer.end().map_err(|why| invalid("recipe", why))?;
+        Ok(recipe)
+    }
+
+    /// The configuration of the install, whose fields are left empty or at their defaults
+    /// where the recipe does not set them.
+    pub fn config(&self) -> io::Result<Config> {
+        let keyboard = match self.keyboard {
+            Some(ref keyboard) => KeyboardConfig {
+                layout:  keyboard.layout.clone(),
+                variant: keyboard.variant.clone(),
+                model:   keyboard.model.clone(),
+                options: keyboard.options.clone(),
+            },
+            None => KeyboardConfig::new("us"),
+        };
+
+        let users = self.users.iter().map(UserRecipe::account).collect();
+
+        let root = match (&self.root.password_hash, self.root.authorized_keys.is_empty()) {
+            (Some(_), false) => {
+                return Err(invalid("root", "a password hash and authorized keys are exclusive"))
+            }
+            (Some(hash), true) => RootPolicy::Password(hash.clone()),
+            (None, false) => RootPolicy::Keys(self.root.authorized_keys.clone()),
+            (None, true) => RootPolicy::Locked,
+        };
+
+        let mut firstboot_units = Vec::with_capacity(self.firstboot.len());
+        for (id, unit) in self.firstboot.iter().enumerate() {
+            firstboot_units.push(match (&unit.script, &unit.exec) {
+                (Some(script), None) => {
+                    FirstBootUnit::from_script(&unit.name, script.clone(), unit.run_once)
+                }
+                (None, Some(exec)) => FirstBootUnit {
+                    name:     unit.name.clone(),
+                    exec:     exec.clone(),
+                    script:   None,
+                    run_once: unit.run_once,
+                },
+                _ => {
+                    return Err(invalid(
+                        format_args!("firstboot[{}]", id),
+                        "either a script or an exec is required",
+                    ))
+                }
+            });
+        }
+
+        let snapshot_percent = match self.snapshot_percent {
+            Some(percent) if percent == 0 || percent > 100 => {
+                return Err(invalid("snapshot_percent", "must be from 1 to 100"))
+            }
+            percent => percent.unwrap_or(20),
+        };
+
+        let bootloader = &self.bootloader;
+        Ok(Config {
+            hostname: self.hostname.clone().unwrap_or_default(),
+            keyboard,
+            old_root: self.refresh.clone(),
+            lang: self.lang.clone().unwrap_or_else(|| "en_US.UTF-8".into()),
+            additional_locales: self.additional_locales.clone(),
+            remove: self.remove.clone().unwrap_or_default(),
+            squashfs: self.squashfs.clone().unwrap_or_default(),
+            layers: self.layers.clone(),
+            squashfs_checksum: self.squashfs_checksum.clone(),
+            layer_checksums: self.layer_checksums.clone(),
+            flags: self.flags.bits(),
+            snapshot_previous_root: self.snapshot_previous_root,
+            snapshot_percent,
+            timezone: self.timezone.clone(),
+            users,
+            hosts_append: self.hosts_append.clone(),
+            local_packages: self.local_packages.clone(),
+            language_packages: self.language_packages.clone(),
+            allow_network: self.allow_network,
+            fstab_id: match self.fstab_id {
+                FstabIdRecipe::Uuid => FstabId::Uuid,
+                FstabIdRecipe::Partuuid => FstabId::PartUuid,
+                FstabIdRecipe::Label => FstabId::Label,
+                FstabIdRecipe::Path => FstabId::Path,
+            },
+            hibernation: self.hibernation,
+            swapfile: self.swapfile,
+            zram: self.zram.as_ref().map(|zram| ZramConfig {
+                fraction_of_ram: zram.fraction_of_ram,
+                algorithm:       zram.algorithm.clone(),
+            }),
+            bootloader: match bootloader.kind {
+                BootloaderKindRecipe::Auto => BootloaderChoice::Auto,
+                BootloaderKindRecipe::SystemdBoot => BootloaderChoice::SystemdBoot,
+                BootloaderKindRecipe::GrubEfi => BootloaderChoice::GrubEfi,
+                BootloaderKindRecipe::GrubBios => BootloaderChoice::GrubBios,
+                BootloaderKindRecipe::GrubIeee1275 => BootloaderChoice::GrubIeee1275,
+                BootloaderKindRecipe::Extlinux => BootloaderChoice::Extlinux,
+                BootloaderKindRecipe::RaspberryPi => BootloaderChoice::RaspberryPi,
+            },
+            efi_label: bootloader.efi_label.clone(),
+            boot_order: match bootloader.boot_order {
+                BootOrderRecipe::First => BootOrderPolicy::First,
+                BootOrderRecipe::Last => BootOrderPolicy::Last,
+                BootOrderRecipe::Preserve => BootOrderPolicy::Preserve,
+            },
+            os_prober: bootloader.os_prober,
+            systemd_boot: SystemdBootOptions {
+                timeout:      bootloader.systemd_boot.timeout,
+                default:      bootloader.systemd_boot.default.clone(),
+                console_mode: bootloader.systemd_boot.console_mode.clone(),
+                editor:       bootloader.systemd_boot.editor,
+            },
+            grub: GrubOptions {
+                distributor:    bootloader.grub.distributor.clone(),
+                theme_dir:      bootloader.grub.theme_dir.clone(),
+                background:     bootloader.grub.background.clone(),
+                hidden_timeout: bootloader.grub.hidden_timeout,
+                terminal:       bootloader.grub.terminal.clone(),
+            },
+            kernel_cmdline: bootloader.kernel_cmdline.clone(),
+            oem_mode: self.oem_mode,
+            recovery: self.recovery.as_ref().map(|recovery| RecoveryConfig {
+                partition: recovery.partition.clone(),
+                source:    RecoverySource::from_path(
+                    recovery.source.clone().unwrap_or_else(|| "/cdrom/casper".into()),
+                ),
+            }),
+            mode: if self.refresh.is_some() { InstallMode::Refresh } else { InstallMode::Install },
+            preserve: self.preserve.clone(),
+            initramfs: self.initramfs.map(|tool| match tool {
+                InitramfsRecipe::InitramfsTools => InitramfsTool::InitramfsTools,
+                InitramfsRecipe::Dracut => InitramfsTool::Dracut,
+                InitramfsRecipe::Mkinitcpio => InitramfsTool::Mkinitcpio,
+            }),
+            install_drivers: self.install_drivers,
+            network: self.network.as_ref().map(|network| NetworkConfig {
+                proxy:      network.proxy.clone(),
+                apt_mirror: network.apt_mirror.clone(),
+                dns:        network.dns.clone(),
+                persist:    network.persist,
+            }),
+            ssh: self.ssh.as_ref().map(|ssh| SshConfig {
+                enable_server:         ssh.enable_server,
+                authorized_keys:       ssh.authorized_keys.clone(),
+                permit_root_login:     ssh.permit_root_login,
+                disable_password_auth: ssh.disable_password_auth,
+            }),
+            overlay: self.overlay.clone(),
+            firstboot_units,
+            root,
+            ..Config::default()
+        })
+    }
+}
+
+/// Deserializes a document, with errors of its schema prefixed with their path within it,
+/// such as `disks[0].partitions[2].fs`.
+fn deserialize<'de, D: serde::Deserializer<'de>, T: DeserializeOwned>(
+    deserializer: D,
+) -> io::Result<T> {
+    serde_path_to_error::deserialize(deserializer).map_err(|why| {
+        let path = why.path().to_string();
+        invalid(if path == "." { "recipe".into() } else { path }, why.into_inner())
+    })
+}
+
+/// An error in the recipe, at `path` within the document.
+fn invalid<P: fmt::Display, E: fmt::Display>(path: P, why: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path, why))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RECIPE: &str = r#"
+squashfs = "/cdrom/casper/filesystem.squashfs"
+remove = "/cdrom/casper/filesystem.manifest-remove"
+hostname = "pop-os"
+
+[[users]]
+username = "admin"
+password_hash = "$6$salt$hash"
+
+[flags]
+modify_boot_order = true
+
+[bootloader]
+kind = "grub-efi"
+boot_order = "last"
+
+[[disks]]
+device = "/dev/sda"
+table = "gpt"
+
+[[disks.partitions]]
+start = "start"
+end = "512MiB"
+fs = "fat32"
+mount = "/boot/efi"
+flags = ["esp"]
+
+[[disks.partitions]]
+start = "512MiB"
+end = "end"
+lvm = { group = "data", encryption = { physical_volume = "cryptdata", password = "pass" } }
+
+[[lvm]]
+group = "data"
+volumes = [
+    { name = "root", fs = "ext4", mount = "/" },
+    { name = "swap", size = "4GiB", fs = "swap", random_swap = true },
+]
+"#;
+
+    #[test]
+    fn recipe() {
+        let recipe = RecipeFile::from_toml(RECIPE).unwrap();
+        let config = recipe.config().unwrap();
+        assert_eq!(config.hostname, "pop-os");
+        assert_eq!(config.lang, "en_US.UTF-8");
+        assert_eq!(config.flags, MODIFY_BOOT_ORDER);
+        assert_eq!(config.bootloader, BootloaderChoice::GrubEfi);
+        assert_eq!(config.boot_order, BootOrderPolicy::Last);
+        assert!(config.os_prober);
+        assert_eq!(config.users[0].password_hash.as_deref(), Some("$6$salt$hash"));
+
+        let partitions = &recipe.disks[0].partitions;
+        assert_eq!(partitions[1].lvm.as_ref().unwrap().group, "data");
+        assert_eq!(recipe.lvm[0].volumes[0].size, None);
+        assert!(!recipe.lvm[0].volumes[0].random_swap && recipe.lvm[0].volumes[1].random_swap);
+
+        let json = RecipeFile::from_json(r#"{ "hostname": "pop-os", "lang": "cs_CZ.UTF-8" }"#);
+        assert_eq!(json.unwrap().config().unwrap().lang, "cs_CZ.UTF-8");
+    }
+
+    #[test]
+    fn error_paths() {
+        let error = |recipe: &str| RecipeFile::from_toml(recipe).unwrap_err().to_string();
+
+        let recipe = RECIPE.replace("fs = \"fat32\"", "fs = 32");
+        assert!(error(&recipe).starts_with("disks[0].partitions[0].fs: "));
+
+        let recipe = RECIPE.replace("[flags]", "[flags]\nmodify_everything = true");
+        let unknown = error(&recipe);
+        assert!(unknown.starts_with("flags"));
+        assert!(unknown.contains("unknown field `modify_everything`"));
+
+        let recipe = RECIPE.replace("\"grub-efi\"", "\"lilo\"");
+        assert!(error(&recipe).starts_with("bootloader.kind: unknown variant `lilo`"));
+
+        let error = RecipeFile::from_json(r#"{ "users": [{ "username": 1 }] }"#).unwrap_err();
+        assert!(error.to_string().starts_with("users[0].username: "));
+
+        let recipe = RecipeFile::from_toml("[[firstboot]]\nname = \"enroll\"").unwrap();
+        let error = recipe.config().unwrap_err().to_string();
+        assert_eq!(error, "firstboot[0]: either a script or an exec is required");
+    }
+}
@@ -0,0 +1,371 @@
+//! Answers whether this machine can install an image, before the user configures anything.
+
+use crate::{
+    auto::is_live_disk,
+    bootloader::{self, BootMode},
+    disks::{memory_total, Disks},
+    installer::efi_fallback,
+    squashfs::Source,
+};
+use disk_types::{BlockDeviceExt, SectorExt};
+use std::{ffi::CStr, fmt, fs, mem};
+
+/// The memory which the live session takes, with the installer running on its desktop.
+const LIVE_SESSION_MEMORY: u64 = 1536 * 1024 * 1024;
+
+/// The memory which extracting the image takes, for the buffers of `unsquashfs`.
+const EXTRACTION_MEMORY: u64 = 512 * 1024 * 1024;
+
+/// The live session swaps while the image is extracted, which slows the install, below this.
+const RECOMMENDED_MEMORY: u64 = 4 * 1024 * 1024 * 1024;
+
+/// Whether a requirement was met, from the least to the most severe.
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RequirementStatus {
+    Pass,
+    /// The install may proceed, but the user should be told of it.
+    Warn,
+    Fail,
+}
+
+impl fmt::Display for RequirementStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            RequirementStatus::Pass => "pass",
+            RequirementStatus::Warn => "warn",
+            RequirementStatus::Fail => "fail",
+        })
+    }
+}
+
+/// A requirement, with the values that it was checked with.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct RequirementCheck<T> {
+    pub status:   RequirementStatus,
+    /// The value of this machine, if it could be measured.
+    pub measured: Option<T>,
+    /// The value which the image requires, if it could be determined.
+    pub required: Option<T>,
+    /// What was found, which frontends may show to the user.
+    pub message:  String,
+}
+
+impl<T> RequirementCheck<T> {
+    fn new(status: RequirementStatus, measured: Option<T>, required: Option<T>) -> Self {
+        RequirementCheck { status, measured, required, message: String::new() }
+    }
+
+    fn message<S: Into<String>>(mut self, message: S) -> Self {
+        self.message = message.into();
+        self
+    }
+}
+
+/// Whether this machine meets each of the requirements of installing an image.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct RequirementsReport {
+    /// The bytes of memory, which must hold the live session while the image is extracted.
+    pub memory:       RequirementCheck<u64>,
+    /// The bytes of the largest disk, which must have the `minimum_disk_size_of_source` of the
+    /// image.
+    pub disk:         RequirementCheck<u64>,
+    /// The mode of the firmware that the live session was booted in, and those which can boot
+    /// the image, such as `Efi`.
+    pub firmware:     RequirementCheck<String>,
+    /// The architecture of the CPU of this machine, and that of the image, such as `x86_64`.
+    pub architecture: RequirementCheck<String>,
+}
+
+impl RequirementsReport {
+    /// The most severe status of the requirements.
+    pub fn status(&self) -> RequirementStatus {
+        self.memory
+            .status
+            .max(self.disk.status)
+            .max(self.firmware.status)
+            .max(self.architecture.status)
+    }
+}
+
+impl fmt::Display for RequirementsReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let checks = [
+            ("memory", self.memory.status, &self.memory.message),
+            ("disk", self.disk.status, &self.disk.message),
+            ("firmware", self.firmware.status, &self.firmware.message),
+            ("architecture", self.architecture.status, &self.architecture.message),
+        ];
+
+        for &(name, status, message) in &checks {
+            writeln!(f, "{:<12} {}: {}", name, status, message)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Checks whether this machine can install the image: that it has enough memory, a disk
+/// which is large enough, firmware which can boot the image, and the image's architecture.
+///
+/// The disks of the live system aren't counted, as they are not offered as targets.
+/// Requirements which can't be checked, such as the size of a remote image, are warnings.
+pub fn check_requirements(source: &Source, disks: &Disks) -> RequirementsReport {
+    let machine = read_cpu_arch();
+    let image = read_arch(source);
+    let arch = image.as_deref().or_else(|| machine.as_deref()).unwrap_or_default();
+
+    let memory = memory_total().map(|total| total * 1024).map_err(|why| why.to_string());
+    let disk_sizes = disks
+        .get_physical_devices()
+        .iter()
+        .filter(|disk| !disk.is_read_only() && !is_live_disk(disk, disks))
+        .map(|disk| disk.get_sectors() * disk.get_logical_block_size())
+        .collect::<Vec<_>>();
+    let required = crate::minimum_disk_size_of(source).map_err(|why| why.to_string());
+
+    RequirementsReport {
+        memory:       check_memory(memory),
+        disk:         check_disks(&disk_sizes, required),
+        firmware:     check_firmware(bootloader::detect(), arch),
+        architecture: check_architecture(machine, image),
+    }
+}
+
+/// The architecture of the CPU, which is that of the kernel, unless a 32-bit kernel runs on
+/// a 64-bit x86 CPU. The architecture which distinst was built for may be narrower than both.
+fn read_cpu_arch() -> Option<String> {
+    let mut uname: libc::utsname = unsafe { mem::zeroed() };
+    if unsafe { libc::uname(&mut uname) } != 0 {
+        return None;
+    }
+
+    let machine = unsafe { CStr::from_ptr(uname.machine.as_ptr()) }.to_string_lossy();
+    let long_mode = || {
+        fs::read_to_string("/proc/cpuinfo").ok().map_or(false, |cpuinfo| {
+            cpuinfo
+                .lines()
+                .filter(|line| line.starts_with("flags"))
+                .any(|line| line.split_whitespace().any(|flag| flag == "lm"))
+        })
+    };
+
+    Some(cpu_arch(&machine, long_mode).to_owned())
+}
+
+/// The name of the architecture of the machine which `uname` gives, such as `i686`, in the
+/// names of `read_arch`. x86 CPUs which support long mode are 64-bit.
+fn cpu_arch<'a, F: FnOnce() -> bool>(machine: &'a str, long_mode: F) -> &'a str {
+    match machine {
+        "i386" | "i486" | "i586" | "i686" if long_mode() => "x86_64",
+        "i386" | "i486" | "i586" | "i686" => "x86",
+        "arm64" => "aarch64",
+        "ppc64" | "ppc64le" => "powerpc64",
+        _ if machine.starts_with("arm") => "arm",
+        _ => machine,
+    }
+}
+
+/// The architecture of the image, from the ELF header of its `/usr/bin/env`.
+fn read_arch(source: &Source) -> Option<String> {
+    let env = source.read_file("usr/bin/env").ok()??;
+    let arch = match efi_fallback::elf_machine(&env)? {
+        0x03 => "x86",
+        0x28 => "arm",
+        0x3e => "x86_64",
+        0x15 => "powerpc64",
+        0xb7 => "aarch64",
+        0xf3 => "riscv64",
+        _ => return None,
+    };
+
+    Some(arch.to_owned())
+}
+
+fn check_memory(total: Result<u64, String>) -> RequirementCheck<u64> {
+    let required = LIVE_SESSION_MEMORY + EXTRACTION_MEMORY;
+    let total = match total {
+        Ok(total) => total,
+        Err(why) => {
+            return RequirementCheck::new(RequirementStatus::Warn, None, Some(required))
+                .message(format!("the memory could not be measured: {}", why));
+        }
+    };
+
+    let status = if total < required {
+        RequirementStatus::Fail
+    } else if total < RECOMMENDED_MEMORY {
+        RequirementStatus::Warn
+    } else {
+        RequirementStatus::Pass
+    };
+
+    let message = format!("{} MiB of memory, where {} MiB are required", mib(total), mib(required));
+    let message = match status {
+        RequirementStatus::Warn => {
+            format!("{}, and {} MiB are recommended", message, mib(RECOMMENDED_MEMORY))
+        }
+        _ => message,
+    };
+
+    RequirementCheck::new(status, Some(total), Some(required)).message(message)
+}
+
+fn check_disks(sizes: &[u64], required: Result<u64, String>) -> RequirementCheck<u64> {
+    let largest = match sizes.iter().max() {
+        Some(&largest) => largest,
+        None => {
+            return RequirementCheck::new(RequirementStatus::Fail, None, required.ok())
+                .message("no disks which may be written to were found");
+        }
+    };
+
+    match required {
+        Ok(required) if largest < required => {
+            RequirementCheck::new(RequirementStatus::Fail, Some(largest), Some(required)).message(
+                format!(
+                    "the largest disk has {} MiB, where {} MiB are required",
+                    mib(largest),
+                    mib(required)
+                ),
+            )
+        }
+        Ok(required) => {
+            let fits = sizes.iter().filter(|&&size| size >= required).count();
+            RequirementCheck::new(RequirementStatus::Pass, Some(largest), Some(required)).message(
+                format!(
+                    "{} of {} disks have the {} MiB required",
+                    fits,
+                    sizes.len(),
+                    mib(required)
+                ),
+            )
+        }
+        Err(why) => RequirementCheck::new(RequirementStatus::Warn, Some(largest), None)
+            .message(format!("the size of the image could not be measured: {}", why)),
+    }
+}
+
+/// The image can be booted by EFI firmware of its width, or by BIOS if it is for x86. Other
+/// architectures without EFI are booted by the firmware of their board.
+fn check_firmware(mode: BootMode, arch: &str) -> RequirementCheck<String> {
+    let supported: &[BootMode] = match arch {
+        "x86_64" => &[BootMode::Efi, BootMode::Bios],
+        "x86" => &[BootMode::Ia32Efi, BootMode::Bios],
+        _ => &[BootMode::Efi],
+    };
+
+    let names = supported.iter().map(|mode| format!("{:?}", mode)).collect::<Vec<_>>();
+    let (measured, required) = (Some(format!("{:?}", mode)), Some(names.join(" or ")));
+
+    if supported.contains(&mode) {
+        RequirementCheck::new(RequirementStatus::Pass, measured, required)
+            .message(format!("booted in {:?} mode", mode))
+    } else if mode == BootMode::Bios {
+        RequirementCheck::new(RequirementStatus::Warn, measured, required).message(format!(
+            "booted without EFI: {} images are booted by the firmware of the board",
+            arch
+        ))
+    } else {
+        RequirementCheck::new(RequirementStatus::Fail, measured, required).message(format!(
+            "{:?} firmware can't boot the loaders of {} images: boot the installer in {} mode \
+             instead",
+            mode,
+            arch,
+            names.join(" or ")
+        ))
+    }
+}
+
+fn check_architecture(machine: Option<String>, image: Option<String>) -> RequirementCheck<String> {
+    let measured = machine.clone();
+    let machine = match machine {
+        Some(machine) => machine,
+        None => {
+            return RequirementCheck::new(RequirementStatus::Warn, None, image)
+                .message("the architecture of the CPU could not be read");
+        }
+    };
+
+    match image {
+        Some(ref image) if image == machine => {
+            RequirementCheck::new(RequirementStatus::Pass, measured, Some(image.clone()))
+                .message(format!("{} images are installed", image))
+        }
+        // A 64-bit x86 CPU runs 32-bit x86 images.
+        Some(ref image) if machine == "x86_64" && image == "x86" => {
+            RequirementCheck::new(RequirementStatus::Warn, measured, Some(image.clone()))
+                .message("a 32-bit image is installed on a 64-bit CPU")
+        }
+        Some(image) => RequirementCheck::new(RequirementStatus::Fail, measured, Some(image))
+            .message(format!("the image is not for the {} CPU of this machine", machine)),
+        None => RequirementCheck::new(RequirementStatus::Warn, measured, None)
+            .message("the architecture of the image could not be read"),
+    }
+}
+
+fn mib(bytes: u64) -> u64 { bytes / 1024 / 1024 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GIB: u64 = 1024 * 1024 * 1024;
+
+    #[test]
+    fn memory() {
+        assert_eq!(check_memory(Ok(8 * GIB)).status, RequirementStatus::Pass);
+        assert_eq!(check_memory(Ok(3 * GIB)).status, RequirementStatus::Warn);
+        assert_eq!(check_memory(Ok(GIB)).status, RequirementStatus::Fail);
+
+        let unknown = check_memory(Err("no /proc/meminfo".into()));
+        assert_eq!((unknown.status, unknown.measured), (RequirementStatus::Warn, None));
+        assert_eq!(unknown.required, Some(2 * GIB));
+    }
+
+    #[test]
+    fn disks() {
+        let check = check_disks(&[16 * GIB, 64 * GIB], Ok(32 * GIB));
+        assert_eq!(check.status, RequirementStatus::Pass);
+        assert_eq!(check.measured, Some(64 * GIB));
+        assert_eq!(check.message, "1 of 2 disks have the 32768 MiB required");
+
+        assert_eq!(check_disks(&[16 * GIB], Ok(32 * GIB)).status, RequirementStatus::Fail);
+        assert_eq!(check_disks(&[], Ok(32 * GIB)).status, RequirementStatus::Fail);
+        assert_eq!(check_disks(&[16 * GIB], Err("remote".into())).status, RequirementStatus::Warn);
+    }
+
+    #[test]
+    fn firmware() {
+        let status = |mode, arch| check_firmware(mode, arch).status;
+        assert_eq!(status(BootMode::Efi, "x86_64"), RequirementStatus::Pass);
+        assert_eq!(status(BootMode::Bios, "x86_64"), RequirementStatus::Pass);
+        assert_eq!(status(BootMode::Ia32Efi, "x86_64"), RequirementStatus::Fail);
+        assert_eq!(status(BootMode::Ia32Efi, "x86"), RequirementStatus::Pass);
+        assert_eq!(status(BootMode::Bios, "aarch64"), RequirementStatus::Warn);
+        assert_eq!(check_firmware(BootMode::Efi, "x86").required.unwrap(), "Ia32Efi or Bios");
+    }
+
+    #[test]
+    fn architecture() {
+        let status = |machine: Option<&str>, image: Option<&str>| {
+            check_architecture(machine.map(String::from), image.map(String::from)).status
+        };
+
+        assert_eq!(status(Some("x86_64"), Some("x86_64")), RequirementStatus::Pass);
+        assert_eq!(status(Some("x86_64"), Some("x86")), RequirementStatus::Warn);
+        assert_eq!(status(Some("x86_64"), Some("aarch64")), RequirementStatus::Fail);
+        assert_eq!(status(Some("aarch64"), None), RequirementStatus::Warn);
+        assert_eq!(status(None, Some("x86_64")), RequirementStatus::Warn);
+    }
+
+    #[test]
+    fn cpus() {
+        assert_eq!(cpu_arch("x86_64", || false), "x86_64");
+        assert_eq!(cpu_arch("i686", || true), "x86_64");
+        assert_eq!(cpu_arch("i686", || false), "x86");
+        assert_eq!(cpu_arch("armv7l", || false), "arm");
+        assert_eq!(cpu_arch("aarch64", || false), "aarch64");
+        assert_eq!(cpu_arch("ppc64le", || false), "powerpc64");
+    }
+}
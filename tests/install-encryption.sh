@@ -0,0 +1,67 @@
+#!/bin/sh
+# Installs LVM on LUKS to a loopback device, and checks that the initramfs of the install
+# is able to unlock the root file system.
+FS="/cdrom/casper/filesystem.squashfs"
+REMOVE="/cdrom/casper/filesystem.manifest-remove"
+DISK="tests/loopback-encryption.bin"
+MOUNT="/tmp/distinst-encryption-test"
+
+if ! test -e "target/debug/distinst"; then
+    cargo build --manifest-path cli/Cargo.toml
+fi
+
+for file in "$FS" "$REMOVE"; do
+    if ! test -e "${file}"; then
+        echo "failed to find ${file}"
+        exit 1
+    fi
+done
+
+set -e -x
+
+if [ ! -e "$DISK" ]; then
+    dd if=/dev/zero of="$DISK" bs=1G count=16 status=progress
+fi
+
+LO="$(sudo losetup --find "$DISK" --show --partscan)"
+
+cleanup() {
+    sudo umount "$MOUNT" 2>/dev/null || true
+    sudo vgchange -an data 2>/dev/null || true
+    sudo cryptsetup close cryptdata 2>/dev/null || true
+    sudo losetup --detach "$LO"
+}
+
+trap cleanup EXIT
+
+sudo env RUST_BACKTRACE=1 target/debug/distinst \
+    -s "${FS}" \
+    -r "${REMOVE}" \
+    -h "pop-testing" \
+    -k "us" \
+    -l "en_US.UTF-8" \
+    -b "$LO" \
+    -t "$LO:gpt" \
+    -n "$LO:primary:start:512M:fat32:mount=/boot/efi:flags=esp" \
+    -n "$LO:primary:512M:end:enc=cryptdata,data,pass=password" \
+    --logical "data:root:-4096M:ext4:mount=/" \
+    --logical "data:swap:4096M:swap"
+
+echo -n "password" | sudo cryptsetup open "${LO}p2" cryptdata --key-file -
+sudo vgchange -ay data
+sudo mkdir -p "$MOUNT"
+sudo mount /dev/mapper/data-root "$MOUNT"
+
+grep -q "^cryptdata " "$MOUNT/etc/crypttab"
+
+for initrd in "$MOUNT"/boot/initrd.img-*; do
+    contents="$(lsinitramfs "$initrd")"
+    echo "$contents" | grep -q "sbin/cryptsetup$"
+    echo "$contents" | grep -q "cryptroot/crypttab$"
+    sudo unmkinitramfs "$initrd" "$MOUNT/tmp/initrd"
+    sudo grep -rq "^cryptdata " "$MOUNT"/tmp/initrd/main/cryptroot/crypttab \
+        "$MOUNT"/tmp/initrd/cryptroot/crypttab 2>/dev/null
+    sudo rm -rf "$MOUNT/tmp/initrd"
+done
+
+echo "the initramfs contains cryptsetup and the crypttab entry"